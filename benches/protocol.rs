@@ -0,0 +1,92 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use sanic::{
+    internals::make_parts_packet,
+    protocol::{GroupMember, Message},
+    MTU, PART_SIZE,
+};
+
+fn messages() -> Vec<(&'static str, Message)> {
+    vec![
+        (
+            "part",
+            Message::Part {
+                id: 1234,
+                data: vec![0xa5; PART_SIZE],
+            },
+        ),
+        (
+            "sync",
+            Message::Sync {
+                ids: (0..100).collect(),
+            },
+        ),
+        (
+            "ack_full",
+            Message::Ack {
+                ids: (0..(MTU as u32 - 5) / 4).collect(),
+            },
+        ),
+        (
+            "send",
+            Message::Send {
+                filename: "holiday-pictures-2023.tar.gz".to_string(),
+                parts: 100_000,
+                group: Some(GroupMember {
+                    name: "holidays".to_string(),
+                    index: 2,
+                    count: 5,
+                }),
+                delta: false,
+                dedup: false,
+                verify: false,
+                token: None,
+                offset: None,
+                text: false,
+                synthetic: false,
+                size: Some(149_500_000),
+            },
+        ),
+    ]
+}
+
+fn serialize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("serialize");
+    for (name, message) in messages() {
+        group.throughput(Throughput::Bytes(message.serialize().len() as u64));
+        group.bench_function(name, |b| b.iter(|| black_box(&message).serialize()));
+    }
+    group.finish();
+}
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    for (name, message) in messages() {
+        let packet = message.serialize();
+        group.throughput(Throughput::Bytes(packet.len() as u64));
+        group.bench_function(name, |b| b.iter(|| Message::parse(black_box(&packet))));
+    }
+    // Parts land in recycled buffers on the receiver.
+    let packet = messages().swap_remove(0).1.serialize();
+    group.bench_function("part_recycled", |b| {
+        b.iter_batched(
+            || Vec::with_capacity(PART_SIZE),
+            |buffer| Message::parse_with(black_box(&packet), || buffer),
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+fn parts_packet(c: &mut Criterion) {
+    let mut group = c.benchmark_group("make_parts_packet");
+    let data = vec![0xa5; PART_SIZE];
+    let mut packet = Vec::with_capacity(MTU);
+    group.throughput(Throughput::Bytes(PART_SIZE as u64));
+    group.bench_function("full", |b| {
+        b.iter(|| make_parts_packet(black_box(&data), 1234, &mut packet))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, serialize, parse, parts_packet);
+criterion_main!(benches);
@@ -0,0 +1,38 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use sanic::{internals::reassemble, PART_SIZE};
+
+/// Ids in the order they arrive: in order, or scattered like after heavy loss.
+fn order(nb_parts: u32, scattered: bool) -> Vec<u32> {
+    match scattered {
+        // 7919 is a prime, so the stride visits every id once.
+        true => (0..nb_parts)
+            .map(|i| (i as u64 * 7919 % nb_parts as u64) as u32)
+            .collect(),
+        false => (0..nb_parts).collect(),
+    }
+}
+
+fn reassembly(c: &mut Criterion) {
+    let mut group = c.benchmark_group("reassembly");
+    for nb_parts in [100, 1_000, 10_000] {
+        group.throughput(Throughput::Bytes(nb_parts as u64 * PART_SIZE as u64));
+        for (name, scattered) in [("in_order", false), ("scattered", true)] {
+            let ids = order(nb_parts, scattered);
+            group.bench_with_input(BenchmarkId::new(name, nb_parts), &ids, |b, ids| {
+                b.iter_batched(
+                    || {
+                        ids.iter()
+                            .map(|&id| (id, vec![id as u8; PART_SIZE]))
+                            .collect::<Vec<_>>()
+                    },
+                    |parts| reassemble(nb_parts, parts).unwrap(),
+                    BatchSize::LargeInput,
+                )
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, reassembly);
+criterion_main!(benches);
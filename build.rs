@@ -0,0 +1,24 @@
+//! Writes the C header of the `ffi` feature to `$OUT_DIR/sanic.h`, and to
+//! `$SANIC_HEADER_DIR/sanic.h` when set, like `SANIC_HEADER_DIR=include` to update the
+//! header checked in.
+
+fn main() {
+    println!("cargo:rerun-if-changed=src/ffi.rs");
+    println!("cargo:rerun-if-env-changed=SANIC_HEADER_DIR");
+    #[cfg(feature = "ffi")]
+    {
+        let dir = std::env::var("CARGO_MANIFEST_DIR").expect("Cargo sets CARGO_MANIFEST_DIR");
+        let out = std::env::var("OUT_DIR").expect("Cargo sets OUT_DIR");
+        let header = cbindgen::Builder::new()
+            .with_src(format!("{dir}/src/ffi.rs"))
+            .with_language(cbindgen::Language::C)
+            .with_include_guard("SANIC_H")
+            .with_autogen_warning("/* Generated by the build of the ffi feature, do not edit. */")
+            .generate()
+            .expect("Could not generate the C header");
+        header.write_to_file(format!("{out}/sanic.h"));
+        if let Ok(target) = std::env::var("SANIC_HEADER_DIR") {
+            header.write_to_file(std::path::Path::new(&dir).join(target).join("sanic.h"));
+        }
+    }
+}
@@ -0,0 +1,120 @@
+#ifndef SANIC_H
+#define SANIC_H
+
+/* Generated by the build of the ffi feature, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * The transfer went through.
+ */
+#define SANIC_OK 0
+
+/**
+ * A string argument was null or not UTF-8.
+ */
+#define SANIC_ERR_ARGUMENT -1
+
+/**
+ * Reading, writing or the network failed.
+ */
+#define SANIC_ERR_IO -2
+
+/**
+ * The transfer was cancelled with [`sanic_cancel`].
+ */
+#define SANIC_ERR_CANCELLED -3
+
+/**
+ * The other end did not answer, or went silent.
+ */
+#define SANIC_ERR_TIMEOUT -4
+
+/**
+ * The other end refused or aborted the transfer.
+ */
+#define SANIC_ERR_REFUSED -5
+
+/**
+ * Any other failure, see [`sanic_last_error`].
+ */
+#define SANIC_ERR_OTHER -6
+
+/**
+ * The library panicked, a bug, see [`sanic_last_error`].
+ */
+#define SANIC_ERR_PANIC -7
+
+/**
+ * Cancels the transfers it is given to once [`sanic_cancel`] is called.
+ */
+typedef struct SanicCancel SanicCancel;
+
+/**
+ * Called as parts go through, with the `user_data` the transfer was given.
+ */
+typedef void (*SanicProgress)(uint32_t parts_done, uint32_t parts_total, void *user_data);
+
+/**
+ * A new cancel handle, to free with [`sanic_cancel_free`].
+ */
+struct SanicCancel *sanic_cancel_new(void);
+
+/**
+ * Cancels the transfers running with `cancel`, and the ones started with it later.
+ *
+ * # Safety
+ *
+ * `cancel` must come from [`sanic_cancel_new`] and not be freed yet.
+ */
+void sanic_cancel(const struct SanicCancel *cancel);
+
+/**
+ * Frees `cancel`. Transfers still running with it can no longer be cancelled.
+ *
+ * # Safety
+ *
+ * `cancel` must come from [`sanic_cancel_new`], and is not to be used afterwards.
+ */
+void sanic_cancel_free(struct SanicCancel *cancel);
+
+/**
+ * Sends the file at `path` to the receiver at `addr`, like `sanic send`.
+ *
+ * # Safety
+ *
+ * `addr` and `path` must be null or NUL terminated strings, and `cancel` null or a live
+ * handle from [`sanic_cancel_new`].
+ */
+int sanic_send(const char *addr,
+               const char *path,
+               SanicProgress progress,
+               void *user_data,
+               const struct SanicCancel *cancel);
+
+/**
+ * Listens on `bind` (null for 0.0.0.0:6666) until a sender comes, and receives its file
+ * into `output`, a directory or the path of the file (null for the current directory),
+ * like `sanic receive --once`.
+ *
+ * # Safety
+ *
+ * `bind` and `output` must be null or NUL terminated strings, and `cancel` null or a live
+ * handle from [`sanic_cancel_new`].
+ */
+int sanic_receive(const char *bind,
+                  const char *output,
+                  SanicProgress progress,
+                  void *user_data,
+                  const struct SanicCancel *cancel);
+
+/**
+ * Message of the last error on this thread, empty if there was none. It stays valid
+ * until the next one.
+ */
+const char *sanic_last_error(void);
+
+#endif  /* SANIC_H */
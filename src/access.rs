@@ -0,0 +1,219 @@
+//! Which senders a receiver takes transfers from: address ranges to allow or deny, and
+//! tokens the senders of a range must present with their Send.
+//!
+//! Datagrams from denied or unlisted addresses are dropped unanswered, before anything
+//! is parsed. Tokens go in the clear unless the transfer is encrypted.
+
+use std::{fmt, net::IpAddr, str::FromStr};
+
+/// Range of addresses, as `10.0.0.0/8`, `fd00::/8`, or a single address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Senders of a dual stack socket show up as IPv4 mapped addresses.
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            IpAddr::V4(_) => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                masked(range.to_bits().into(), ip.to_bits().into(), 32, self.prefix)
+            }
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                masked(range.to_bits(), ip.to_bits(), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether the top `prefix` bits of the `bits` wide `range` and `ip` agree.
+fn masked(range: u128, ip: u128, bits: u32, prefix: u8) -> bool {
+    let shift = bits - u32::from(prefix);
+    (range ^ ip).checked_shr(shift).unwrap_or(0) == 0
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = s.split_once('/').unwrap_or((s, ""));
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("{s} is not an address or a range like 10.0.0.0/8"))?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            "" => bits,
+            prefix => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= bits)
+                .ok_or_else(|| format!("{s} has a prefix length over {bits}"))?,
+        };
+        // A v4 range written as mapped v6 still matches v4 senders.
+        let (addr, prefix) = match addr {
+            IpAddr::V6(v6) if prefix >= 96 => match v6.to_ipv4_mapped() {
+                Some(v4) => (IpAddr::V4(v4), prefix - 96),
+                None => (addr, prefix),
+            },
+            _ => (addr, prefix),
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+/// Range of that single address.
+impl From<IpAddr> for Cidr {
+    fn from(addr: IpAddr) -> Self {
+        let prefix = if addr.is_ipv4() { 32 } else { 128 };
+        Cidr { addr, prefix }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Range allowed to send, with the token its senders must present if it takes one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub range: Cidr,
+    pub token: Option<String>,
+}
+
+/// Takes `<range>` or `<range>=<token>`.
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (range, token) = match s.split_once('=') {
+            Some((_, "")) => return Err(format!("{s} has an empty token")),
+            Some((range, token)) => (range, Some(token.to_string())),
+            None => (s, None),
+        };
+        Ok(Rule {
+            range: range.parse()?,
+            token,
+        })
+    }
+}
+
+/// Senders a receiver takes transfers from. Everyone, unless told otherwise.
+#[derive(Debug, Clone, Default)]
+pub struct Access {
+    allow: Vec<Rule>,
+    deny: Vec<Cidr>,
+}
+
+impl Access {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes transfers from the senders of `rule`. Once a range is allowed, the senders of
+    /// no other range are.
+    pub fn allow(mut self, rule: Rule) -> Self {
+        self.allow.push(rule);
+        self
+    }
+
+    /// Never takes anything from `range`, whatever is allowed.
+    pub fn deny(mut self, range: Cidr) -> Self {
+        self.deny.push(range);
+        self
+    }
+
+    /// Whether datagrams from `ip` are worth looking at.
+    pub fn admits(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|range| range.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|rule| rule.range.contains(ip)))
+    }
+
+    /// Whether `ip` may send, presenting `token`: a range it is in has to take no token,
+    /// or that one.
+    pub fn authorizes(&self, ip: IpAddr, token: Option<&str>) -> bool {
+        if !self.admits(ip) {
+            return false;
+        }
+        let mut rules = self.allow.iter().filter(|rule| rule.range.contains(ip));
+        self.allow.is_empty()
+            || rules.any(|rule| match (&rule.token, token) {
+                (None, _) => true,
+                (Some(expected), Some(token)) => same(expected.as_bytes(), token.as_bytes()),
+                (Some(_), None) => false,
+            })
+    }
+}
+
+/// Compares in a time that only depends on the lengths, so timing does not give tokens away.
+fn same(expected: &[u8], given: &[u8]) -> bool {
+    expected.len() == given.len()
+        && expected
+            .iter()
+            .zip(given)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ranges_match_their_prefix() {
+        let lan: Cidr = "192.168.1.0/24".parse().unwrap();
+        assert!(lan.contains(ip("192.168.1.200")));
+        assert!(!lan.contains(ip("192.168.2.1")));
+        assert!(lan.contains(ip("::ffff:192.168.1.7")));
+        assert!(!lan.contains(ip("fd00::1")));
+        let host: Cidr = "10.0.0.5".parse().unwrap();
+        assert_eq!(host.to_string(), "10.0.0.5/32");
+        assert!(host.contains(ip("10.0.0.5")) && !host.contains(ip("10.0.0.6")));
+        let everyone: Cidr = "::/0".parse().unwrap();
+        assert!(everyone.contains(ip("2001:db8::1")));
+        let mapped: Cidr = "::ffff:10.0.0.0/104".parse().unwrap();
+        assert!(mapped.contains(ip("10.1.2.3")));
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("lan".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn everyone_is_allowed_by_default() {
+        let access = Access::new();
+        assert!(access.admits(ip("203.0.113.9")));
+        assert!(access.authorizes(ip("203.0.113.9"), None));
+    }
+
+    #[test]
+    fn denied_ranges_win() {
+        let access = Access::new()
+            .allow("10.0.0.0/8".parse().unwrap())
+            .deny("10.0.0.66".parse().unwrap());
+        assert!(access.admits(ip("10.1.1.1")));
+        assert!(!access.admits(ip("10.0.0.66")));
+        assert!(!access.admits(ip("192.168.1.1")));
+    }
+
+    #[test]
+    fn tokens_are_asked_of_their_range_only() {
+        let access = Access::new()
+            .allow("192.168.1.0/24".parse().unwrap())
+            .allow("0.0.0.0/0=s3cret".parse().unwrap());
+        assert!(access.authorizes(ip("192.168.1.4"), None));
+        assert!(access.authorizes(ip("198.51.100.1"), Some("s3cret")));
+        assert!(!access.authorizes(ip("198.51.100.1"), Some("guess")));
+        assert!(!access.authorizes(ip("198.51.100.1"), None));
+        assert!("10.0.0.0/8=".parse::<Rule>().is_err());
+    }
+}
@@ -0,0 +1,434 @@
+//! Transfers running in a long running receiver or queue, which the control socket lists,
+//! pauses, resumes and cancels, and the last ones that ended. Sessions go by the id of their
+//! log lines.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{stats::json_string, PART_SIZE};
+
+/// Sessions ended that are remembered, the oldest forgotten first.
+const HISTORY: usize = 32;
+
+/// Sessions running, shared by a receiver or a sender and the control socket.
+#[derive(Debug, Clone, Default)]
+pub struct Activity(Arc<Mutex<Sessions>>);
+
+#[derive(Debug, Default)]
+struct Sessions {
+    running: BTreeMap<String, Arc<Session>>,
+    /// How the last sessions looked when they ended, the latest first.
+    ended: VecDeque<Snapshot>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Send,
+    Receive,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Direction::Send => "send",
+            Direction::Receive => "receive",
+        })
+    }
+}
+
+/// A transfer running, which the operator can pause, resume or cancel.
+#[derive(Debug)]
+pub struct Session {
+    id: String,
+    direction: Direction,
+    peer: Option<SocketAddr>,
+    filename: String,
+    started: Instant,
+    parts_total: u32,
+    parts_done: AtomicU32,
+    parts_lost: AtomicU64,
+    paused: AtomicBool,
+    cancelled: AtomicBool,
+}
+
+/// What a session looks like at a point in time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub id: String,
+    pub direction: Direction,
+    pub peer: Option<SocketAddr>,
+    pub filename: String,
+    pub parts_done: u32,
+    pub parts_total: u32,
+    /// Parts the receiver reported lost, counting every time.
+    pub parts_lost: u64,
+    pub elapsed: Duration,
+    pub paused: bool,
+}
+
+/// Keeps a session listed until dropped.
+pub(crate) struct Tracked {
+    activity: Activity,
+    session: Arc<Session>,
+}
+
+impl Activity {
+    /// Lists a session until the handle returned is dropped.
+    pub(crate) fn start(
+        &self,
+        direction: Direction,
+        id: &str,
+        peer: Option<SocketAddr>,
+        filename: &str,
+        parts_total: u32,
+    ) -> Tracked {
+        let session = Arc::new(Session {
+            id: id.to_string(),
+            direction,
+            peer,
+            filename: filename.to_string(),
+            started: Instant::now(),
+            parts_total,
+            parts_done: AtomicU32::new(0),
+            parts_lost: AtomicU64::new(0),
+            paused: AtomicBool::new(false),
+            cancelled: AtomicBool::new(false),
+        });
+        self.0
+            .lock()
+            .expect("Could not lock sessions")
+            .running
+            .insert(id.to_string(), session.clone());
+        Tracked {
+            activity: self.clone(),
+            session,
+        }
+    }
+
+    /// Sessions running, by id.
+    pub fn list(&self) -> Vec<Snapshot> {
+        let sessions = self.0.lock().expect("Could not lock sessions");
+        sessions
+            .running
+            .values()
+            .map(|session| session.snapshot())
+            .collect()
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<Session>> {
+        self.0
+            .lock()
+            .expect("Could not lock sessions")
+            .running
+            .get(id)
+            .cloned()
+    }
+
+    /// How the last sessions that ended looked then, the latest first.
+    pub fn history(&self) -> Vec<Snapshot> {
+        let sessions = self.0.lock().expect("Could not lock sessions");
+        sessions.ended.iter().cloned().collect()
+    }
+}
+
+impl Session {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Holds the parts not sent yet, the transfer staying open.
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Relaxed);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Relaxed);
+    }
+
+    /// Aborts the transfer, telling the peer.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn progress(&self, parts_done: u32) {
+        self.parts_done.store(parts_done, Ordering::Relaxed);
+    }
+
+    /// The receiver reported `parts_lost` lost parts so far.
+    pub(crate) fn lost(&self, parts_lost: u64) {
+        self.parts_lost.store(parts_lost, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            id: self.id.clone(),
+            direction: self.direction,
+            peer: self.peer,
+            filename: self.filename.clone(),
+            parts_done: self.parts_done.load(Ordering::Relaxed),
+            parts_total: self.parts_total,
+            parts_lost: self.parts_lost.load(Ordering::Relaxed),
+            elapsed: self.started.elapsed(),
+            paused: self.is_paused(),
+        }
+    }
+}
+
+impl std::ops::Deref for Tracked {
+    type Target = Arc<Session>;
+
+    fn deref(&self) -> &Arc<Session> {
+        &self.session
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        let mut sessions = self.activity.0.lock().expect("Could not lock sessions");
+        sessions.running.remove(&self.session.id);
+        sessions.ended.push_front(self.session.snapshot());
+        sessions.ended.truncate(HISTORY);
+    }
+}
+
+impl Snapshot {
+    /// Bytes transferred so far, give or take the padding of the last part.
+    pub fn bytes(&self) -> u64 {
+        u64::from(self.parts_done) * PART_SIZE as u64
+    }
+
+    /// Average rate since the session started, in bytes per second.
+    pub fn rate(&self) -> u64 {
+        (self.bytes() as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)) as u64
+    }
+
+    /// Time left at the average rate, None until some parts made it.
+    pub fn eta(&self) -> Option<Duration> {
+        if self.parts_done == 0 {
+            return None;
+        }
+        let left = self.parts_total.saturating_sub(self.parts_done);
+        Some(
+            self.elapsed
+                .mul_f64(f64::from(left) / f64::from(self.parts_done)),
+        )
+    }
+
+    /// One line JSON object, durations in milliseconds.
+    pub fn to_json(&self) -> String {
+        let peer = match self.peer {
+            Some(peer) => json_string(&peer.to_string()),
+            None => "null".to_string(),
+        };
+        let eta = match self.eta() {
+            Some(eta) => eta.as_millis().to_string(),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"id\":{},\"direction\":\"{}\",\"peer\":{peer},\"filename\":{},\
+             \"parts_done\":{},\"parts_total\":{},\"parts_lost\":{},\"bytes\":{},\
+             \"elapsed_ms\":{},\"bytes_per_sec\":{},\"eta_ms\":{eta},\"paused\":{}}}",
+            json_string(&self.id),
+            self.direction,
+            json_string(&self.filename),
+            self.parts_done,
+            self.parts_total,
+            self.parts_lost,
+            self.bytes(),
+            self.elapsed.as_millis(),
+            self.rate(),
+            self.paused
+        )
+    }
+
+    /// Reads back the JSON array `list-sessions` answers with, None if it is not one.
+    pub fn parse_list(json: &str) -> Option<Vec<Snapshot>> {
+        let mut fields = Fields(json);
+        fields.punct('[')?;
+        let mut snapshots = Vec::new();
+        if fields.punct(']').is_some() {
+            return Some(snapshots);
+        }
+        loop {
+            snapshots.push(fields.snapshot()?);
+            if fields.punct(']').is_some() {
+                return Some(snapshots);
+            }
+            fields.punct(',')?;
+        }
+    }
+}
+
+/// Reads back what [`Snapshot::to_json`] wrote, the fields in the order it puts them.
+struct Fields<'a>(&'a str);
+
+impl Fields<'_> {
+    fn snapshot(&mut self) -> Option<Snapshot> {
+        self.punct('{')?;
+        let id = self.key("id")?.string()?;
+        let direction = match self.key("direction")?.string()?.as_str() {
+            "send" => Direction::Send,
+            "receive" => Direction::Receive,
+            _ => return None,
+        };
+        let peer = match self.key("peer")?.null() {
+            true => None,
+            false => Some(self.string()?.parse().ok()?),
+        };
+        let filename = self.key("filename")?.string()?;
+        let parts_done = self.key("parts_done")?.number()?.try_into().ok()?;
+        let parts_total = self.key("parts_total")?.number()?.try_into().ok()?;
+        let parts_lost = self.key("parts_lost")?.number()?;
+        self.key("bytes")?.number()?;
+        let elapsed = Duration::from_millis(self.key("elapsed_ms")?.number()?);
+        self.key("bytes_per_sec")?.number()?;
+        if !self.key("eta_ms")?.null() {
+            self.number()?;
+        }
+        let fields = self.key("paused")?;
+        let paused = if fields.word("true") {
+            true
+        } else if fields.word("false") {
+            false
+        } else {
+            return None;
+        };
+        self.punct('}')?;
+        Some(Snapshot {
+            id,
+            direction,
+            peer,
+            filename,
+            parts_done,
+            parts_total,
+            parts_lost,
+            elapsed,
+            paused,
+        })
+    }
+
+    fn punct(&mut self, c: char) -> Option<()> {
+        self.0 = self.0.trim_start().strip_prefix(c)?;
+        Some(())
+    }
+
+    /// Skips `word` if it comes next.
+    fn word(&mut self, word: &str) -> bool {
+        match self.0.trim_start().strip_prefix(word) {
+            Some(rest) => {
+                self.0 = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Skips the key `name`, and a comma before it unless it is the first.
+    fn key(&mut self, name: &str) -> Option<&mut Self> {
+        self.word(",");
+        if self.string()? != name {
+            return None;
+        }
+        self.punct(':')?;
+        Some(self)
+    }
+
+    fn null(&mut self) -> bool {
+        self.word("null")
+    }
+
+    fn number(&mut self) -> Option<u64> {
+        let rest = self.0.trim_start();
+        let end = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let number = rest[..end].parse().ok()?;
+        self.0 = &rest[end..];
+        Some(number)
+    }
+
+    /// Strings only have the escapes of `json_string`.
+    fn string(&mut self) -> Option<String> {
+        let rest = self.0.trim_start().strip_prefix('"')?;
+        let mut chars = rest.char_indices();
+        let mut string = String::new();
+        while let Some((at, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.0 = &rest[at + 1..];
+                    return Some(string);
+                }
+                '\\' => match chars.next()?.1 {
+                    'u' => {
+                        let hex: String = (0..4)
+                            .filter_map(|_| chars.next())
+                            .map(|(_, c)| c)
+                            .collect();
+                        string.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                    }
+                    escaped => string.push(escaped),
+                },
+                c => string.push(c),
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_are_listed_while_they_run() {
+        let activity = Activity::default();
+        let peer = "192.168.1.12:6667".parse().ok();
+        let first = activity.start(Direction::Receive, "0a1b2c3d", peer, "a \"b\".txt", 10);
+        let second = activity.start(Direction::Send, "ffee0011", None, "c", 4);
+        assert_eq!(activity.list().len(), 2);
+
+        first.progress(5);
+        activity.get("0a1b2c3d").unwrap().pause();
+        assert!(first.is_paused());
+        let snapshot = first.snapshot();
+        assert_eq!(snapshot.bytes(), 5 * PART_SIZE as u64);
+        let json = snapshot.to_json();
+        assert!(json.starts_with(
+            "{\"id\":\"0a1b2c3d\",\"direction\":\"receive\",\"peer\":\"192.168.1.12:6667\",\
+             \"filename\":\"a \\\"b\\\".txt\",\"parts_done\":5,\"parts_total\":10,"
+        ));
+        assert!(json.ends_with(",\"paused\":true}"));
+        assert_eq!(second.snapshot().eta(), None);
+
+        let listed = format!("[{json},{}]", second.snapshot().to_json());
+        let parsed = Snapshot::parse_list(&listed).unwrap();
+        assert_eq!(parsed[0].filename, "a \"b\".txt");
+        assert_eq!(parsed[0].peer, peer);
+        assert!(parsed[0].paused && !parsed[1].paused);
+        assert_eq!(parsed[1].direction, Direction::Send);
+        assert_eq!(Snapshot::parse_list(" [ ] "), Some(Vec::new()));
+        assert_eq!(Snapshot::parse_list("[{\"id\":1}]"), None);
+
+        drop(first);
+        let ids: Vec<_> = activity.list().into_iter().map(|s| s.id).collect();
+        assert_eq!(ids, ["ffee0011"]);
+        assert!(activity.get("0a1b2c3d").is_none());
+        assert_eq!(activity.history()[0].parts_done, 5);
+    }
+}
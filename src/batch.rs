@@ -0,0 +1,469 @@
+//! Batches: directory trees announced to the receiver with a Manifest before any of their
+//! files is sent. The receiver creates the directories and answers with the files it
+//! already has, the others then go a transfer each, so an interrupted batch resumes at
+//! file granularity.
+//!
+//! A batch can mirror a directory of the receiver instead: the files there are told apart
+//! by their size and modification time unless asked to compare digests, and the ones the
+//! batch does not have can be removed, if the receiver allows it. A batch of no files
+//! removes nothing.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{File, Metadata},
+    io::{self, ErrorKind},
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tracing::info;
+
+use crate::{
+    digest::file_digest,
+    paths,
+    protocol::{ManifestEntry, Message, Mirror},
+    MTU,
+};
+
+/// Bytes of a Manifest before its entries, mirror and token: message id, first index, total
+/// and flags.
+const MANIFEST_HEADER: usize = 1 + 4 + 4 + 1;
+
+/// Lists the files under `root`, in name order, with their entry in the manifest.
+/// Symbolic links to files are followed, the ones to directories are not.
+pub(crate) fn walk(root: &Path) -> io::Result<Vec<(PathBuf, ManifestEntry)>> {
+    walk_with(root, &mut |path, _| file_digest(path))
+}
+
+/// Like [`walk`], with the SHA-256 of each file given by `digest`, which can know it
+/// already from its metadata.
+pub(crate) fn walk_with(
+    root: &Path,
+    digest: &mut impl FnMut(&Path, &Metadata) -> io::Result<[u8; 32]>,
+) -> io::Result<Vec<(PathBuf, ManifestEntry)>> {
+    let mut files = Vec::new();
+    visit(root, root, &mut files, digest)?;
+    Ok(files)
+}
+
+fn visit(
+    root: &Path,
+    dir: &Path,
+    files: &mut Vec<(PathBuf, ManifestEntry)>,
+    digest: &mut impl FnMut(&Path, &Metadata) -> io::Result<[u8; 32]>,
+) -> io::Result<()> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            visit(root, &path, files, digest)?;
+            continue;
+        }
+        let metadata = std::fs::metadata(&path)?;
+        if !metadata.is_file() {
+            continue;
+        }
+        let relative = path
+            .strip_prefix(root)
+            .expect("Walked paths are under the root")
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let entry = ManifestEntry {
+            path: relative,
+            size: metadata.len(),
+            modified: seconds(metadata.modified()?),
+            sha256: digest(&path, &metadata)?,
+        };
+        files.push((path, entry));
+    }
+    Ok(())
+}
+
+/// Seconds since the epoch of a modification time, none before it.
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Manifest messages announcing `entries`, as many in each as fit a datagram, and at least
+/// one so that an empty batch is announced too. Each carries the `token` of the sender.
+pub(crate) fn manifests(
+    entries: &[ManifestEntry],
+    mirror: Option<&Mirror>,
+    token: Option<&str>,
+) -> Vec<Message> {
+    let total = entries.len() as u32;
+    let header = MANIFEST_HEADER
+        + mirror.map_or(0, Mirror::encoded_len)
+        + token.map_or(0, |token| 1 + token.len());
+    let mut messages = Vec::new();
+    let mut first = 0;
+    loop {
+        let count = fitting(&entries[first..], header);
+        messages.push(Message::Manifest {
+            first: first as u32,
+            total,
+            mirror: mirror.cloned(),
+            token: token.map(str::to_string),
+            entries: entries[first..first + count].to_vec(),
+        });
+        first += count;
+        if first == entries.len() {
+            return messages;
+        }
+    }
+}
+
+/// Manifest announcing `entries` from the one at index `first` on, as many as fit a
+/// datagram. It has none past the last one.
+pub(crate) fn manifest(entries: &[ManifestEntry], first: u32) -> Message {
+    let rest = entries.get(first as usize..).unwrap_or_default();
+    Message::Manifest {
+        first,
+        total: entries.len() as u32,
+        mirror: None,
+        token: None,
+        entries: rest[..fitting(rest, MANIFEST_HEADER)].to_vec(),
+    }
+}
+
+/// How many of the first `entries` fit a Manifest starting with `header` bytes, at least
+/// one if there is any.
+fn fitting(entries: &[ManifestEntry], header: usize) -> usize {
+    let mut len = header;
+    entries
+        .iter()
+        .take_while(|entry| {
+            len += entry.encoded_len();
+            len <= MTU
+        })
+        .count()
+        .max(entries.len().min(1))
+}
+
+/// Batch announced to the receiver.
+#[derive(Debug)]
+pub(crate) struct Batch {
+    /// Directory the batch goes in.
+    root: PathBuf,
+    total: u32,
+    /// Whether we had each file announced so far, by index.
+    announced: BTreeMap<u32, bool>,
+    /// Entries by path, with whether the file is still missing.
+    entries: BTreeMap<String, (ManifestEntry, bool)>,
+    /// Tell the files we have by their digest rather than by their size and modification
+    /// time.
+    checksum: bool,
+    /// Remove the files under the root the batch does not have, once it is all announced.
+    delete: bool,
+}
+
+impl Batch {
+    pub fn new(root: PathBuf, total: u32) -> Self {
+        Batch {
+            root,
+            total,
+            announced: BTreeMap::new(),
+            entries: BTreeMap::new(),
+            checksum: true,
+            delete: false,
+        }
+    }
+
+    /// Batch mirroring the directory of `output` its `mirror` names, which it creates.
+    pub fn mirror(output: PathBuf, total: u32, mirror: &Mirror) -> io::Result<Self> {
+        let root = match mirror.dir.as_str() {
+            "" => output,
+            dir => resolve(&output, dir)?,
+        };
+        std::fs::create_dir_all(&root)?;
+        Ok(Batch {
+            checksum: mirror.checksum,
+            delete: mirror.delete,
+            ..Batch::new(root, total)
+        })
+    }
+
+    pub fn total(&self) -> u32 {
+        self.total
+    }
+
+    /// Takes in the `entries` of a Manifest, the first one at index `first`: creates their
+    /// directories, and returns the indices of the files we already have.
+    pub fn announce(&mut self, first: u32, entries: Vec<ManifestEntry>) -> io::Result<Vec<u32>> {
+        let mut have = Vec::new();
+        for (index, entry) in (first..).zip(entries) {
+            if index >= self.total {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("file {index} of a batch of {}", self.total),
+                ));
+            }
+            // Announced again as our answer got lost, no need to hash the file again.
+            if let Some(&had) = self.announced.get(&index) {
+                if had {
+                    have.push(index);
+                }
+                continue;
+            }
+            let target = resolve(&self.root, &entry.path)?;
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let had = std::fs::metadata(&target).is_ok_and(|metadata| {
+                metadata.is_file()
+                    && metadata.len() == entry.size
+                    && match self.checksum {
+                        true => file_digest(&target).is_ok_and(|digest| digest == entry.sha256),
+                        false => metadata
+                            .modified()
+                            .is_ok_and(|modified| seconds(modified) == entry.modified),
+                    }
+            });
+            if had {
+                have.push(index);
+            }
+            self.announced.insert(index, had);
+            self.entries.insert(entry.path.clone(), (entry, !had));
+        }
+        // Nothing announced would be everything removed: an empty batch keeps what is there.
+        if self.delete && !self.entries.is_empty() && self.announced.len() as u32 == self.total {
+            // The names on disk, which the local filesystem may have had to change.
+            let kept = self.entries.keys().map(|path| local_path(path)).collect();
+            prune(&kept, &self.root, "")?;
+        }
+        Ok(have)
+    }
+
+    /// Where the file sent as `filename` goes, if the batch announced it.
+    pub fn target(&self, filename: &str) -> Option<PathBuf> {
+        self.entries.get(filename)?;
+        resolve(&self.root, filename).ok()
+    }
+
+    /// Digest the file sent as `filename` must have, if the batch announced it.
+    pub fn digest(&self, filename: &str) -> Option<[u8; 32]> {
+        self.entries.get(filename).map(|(entry, _)| entry.sha256)
+    }
+
+    /// The file sent as `filename` arrived at `target`, which takes the modification time
+    /// the manifest gave it.
+    pub fn received(&mut self, filename: &str, target: &Path) -> io::Result<()> {
+        if let Some((entry, missing)) = self.entries.get_mut(filename) {
+            *missing = false;
+            File::options()
+                .write(true)
+                .open(target)?
+                .set_modified(UNIX_EPOCH + Duration::from_secs(entry.modified))?;
+        }
+        Ok(())
+    }
+
+    /// Whether the whole manifest came, and we have every file of it.
+    pub fn is_complete(&self) -> bool {
+        self.announced.len() as u32 == self.total
+            && self.entries.values().all(|(_, missing)| !missing)
+    }
+}
+
+/// Removes what is under `dir`, at `relative` in the batch, and not `kept` by it.
+fn prune(kept: &BTreeSet<String>, dir: &Path, relative: &str) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let path = format!("{relative}{}", name.to_string_lossy());
+        if entry.file_type()?.is_dir() {
+            let prefix = format!("{path}/");
+            if kept
+                .range(prefix.clone()..)
+                .next()
+                .is_some_and(|kept| kept.starts_with(&prefix))
+            {
+                prune(kept, &entry.path(), &prefix)?;
+            } else {
+                info!(path, "Removing a directory the batch does not have.");
+                std::fs::remove_dir_all(entry.path())?;
+            }
+        } else if !kept.contains(&path) {
+            info!(path, "Removing a file the batch does not have.");
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// `path` under `root`, refused if it would end up anywhere else.
+fn resolve(root: &Path, path: &str) -> io::Result<PathBuf> {
+    let mut resolved = root.to_path_buf();
+    for name in path.split('/') {
+        // ".." is refused as sent: made safe for Windows, it would pass for a name.
+        let local = paths::local_name(name);
+        match Path::new(&*local).components().collect::<Vec<_>>()[..] {
+            [Component::Normal(local)] if !matches!(name, "" | "." | "..") => resolved.push(local),
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unsafe path in the manifest: {path}"),
+                ))
+            }
+        }
+    }
+    Ok(paths::long(resolved))
+}
+
+/// `path` of the batch, with its names as the local filesystem takes them.
+fn local_path(path: &str) -> String {
+    path.split('/')
+        .map(paths::local_name)
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn manifests_fill_datagrams() {
+        let entries: Vec<ManifestEntry> = (0..100)
+            .map(|i| ManifestEntry {
+                path: format!("dir/file-{i}"),
+                size: i,
+                modified: i,
+                sha256: [i as u8; 32],
+            })
+            .collect();
+        let messages = manifests(&entries, None, Some("secret"));
+        assert!(messages.len() > 1);
+        let mut announced = Vec::new();
+        for message in messages {
+            let packet = message.serialize();
+            assert!(packet.len() <= MTU);
+            let Message::Manifest {
+                first,
+                total,
+                token,
+                entries,
+                ..
+            } = Message::parse(&packet).unwrap()
+            else {
+                panic!("Not a manifest");
+            };
+            assert_eq!((first, total), (announced.len() as u32, 100));
+            assert_eq!(token.as_deref(), Some("secret"));
+            announced.extend(entries);
+        }
+        assert_eq!(announced, entries);
+    }
+
+    #[test]
+    fn batches_skip_the_files_already_there() {
+        let source = TempDir::new("batch-source");
+        source.write("a/b/same", b"same");
+        source.write("a/changed", b"new");
+        source.write("top", b"top");
+        let files = walk(source.path()).unwrap();
+        let paths: Vec<&str> = files.iter().map(|(_, entry)| entry.path.as_str()).collect();
+        assert_eq!(paths, ["a/b/same", "a/changed", "top"]);
+
+        let output = TempDir::new("batch-output");
+        output.write("a/b/same", b"same");
+        output.write("a/changed", b"old");
+        let mut batch = Batch::new(output.path().to_path_buf(), 3);
+        let entries: Vec<ManifestEntry> = files.into_iter().map(|(_, entry)| entry).collect();
+        assert_eq!(batch.announce(0, entries[..2].to_vec()).unwrap(), [0]);
+        assert!(!batch.is_complete());
+        assert_eq!(batch.announce(2, entries[2..].to_vec()).unwrap(), []);
+        assert_eq!(batch.target("a/changed"), Some(output.join("a/changed")));
+        assert_eq!(batch.target("elsewhere"), None);
+        output.write("a/changed", b"new");
+        batch
+            .received("a/changed", &output.join("a/changed"))
+            .unwrap();
+        assert!(!batch.is_complete());
+        output.write("top", b"top");
+        batch.received("top", &output.join("top")).unwrap();
+        assert!(batch.is_complete());
+    }
+
+    #[test]
+    fn mirrors_go_by_modification_time_and_remove_the_rest() {
+        let output = TempDir::new("batch-mirror");
+        output.write("copy/gone/file", b"gone");
+        output.write("copy/stale", b"stale");
+        output.write("copy/kept", b"kept");
+        output.write("outside", b"outside");
+        let modified = |path: &str| {
+            let modified = std::fs::metadata(output.join(path)).unwrap().modified();
+            seconds(modified.unwrap())
+        };
+        let entries = vec![
+            ManifestEntry {
+                path: "kept".to_string(),
+                size: 4,
+                modified: modified("copy/kept"),
+                // Not looked at without checksums.
+                sha256: [0; 32],
+            },
+            ManifestEntry {
+                path: "stale".to_string(),
+                size: 5,
+                modified: modified("copy/stale") - 60,
+                sha256: [0; 32],
+            },
+        ];
+        let mirror = Mirror {
+            dir: "copy".to_string(),
+            checksum: false,
+            delete: true,
+        };
+        let messages = manifests(&entries, Some(&mirror), None);
+        assert_eq!(messages.len(), 1);
+        let mut batch = Batch::mirror(output.path().to_path_buf(), 2, &mirror).unwrap();
+        assert_eq!(batch.announce(0, entries).unwrap(), [0]);
+        assert!(output.join("copy/stale").exists());
+        assert!(!output.join("copy/gone").exists());
+        assert!(output.join("outside").exists());
+        batch.received("stale", &output.join("copy/stale")).unwrap();
+        assert_eq!(modified("copy/stale"), modified("copy/kept") - 60);
+        assert!(batch.is_complete());
+
+        let mut empty = Batch::mirror(output.path().to_path_buf(), 0, &mirror).unwrap();
+        assert_eq!(empty.announce(0, Vec::new()).unwrap(), []);
+        assert!(output.join("copy/kept").exists());
+
+        let escaping = Mirror {
+            dir: "../elsewhere".to_string(),
+            ..mirror
+        };
+        assert!(Batch::mirror(output.path().to_path_buf(), 0, &escaping).is_err());
+    }
+
+    #[test]
+    fn batches_stay_in_their_directory() {
+        let output = TempDir::new("batch-escape");
+        for path in ["../escape", "/etc/passwd", "a//b", "a/./b", ""] {
+            let mut batch = Batch::new(output.path().to_path_buf(), 1);
+            let entry = ManifestEntry {
+                path: path.to_string(),
+                size: 0,
+                modified: 0,
+                sha256: [0; 32],
+            };
+            assert!(batch.announce(0, vec![entry]).is_err(), "{path}");
+        }
+        let mut batch = Batch::new(output.path().to_path_buf(), 1);
+        let entry = ManifestEntry {
+            path: "f".to_string(),
+            size: 0,
+            modified: 0,
+            sha256: [0; 32],
+        };
+        assert!(batch.announce(1, vec![entry]).is_err());
+    }
+}
@@ -0,0 +1,214 @@
+//! `sanic bench`: a transfer between a sender and a receiver in this process, with what it
+//! cost in time, CPU and allocations, to compare builds before and after a change.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    fs::File,
+    io::{self, BufWriter, Write},
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use sanic::{
+    stats::TransferStats, transport::MemoryNetwork, ImpairedTransport, Impairments, Receiver,
+    Sender,
+};
+
+use crate::{human_bytes, CliError};
+
+/// Counts the allocations of the whole process, which costs an atomic add on each.
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static ALLOCATED: AtomicU64 = AtomicU64::new(0);
+
+struct Counting;
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED.fetch_add(layout.size() as u64, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED.fetch_add(new_size as u64, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+/// What the process spent so far.
+#[derive(Clone, Copy)]
+struct Usage {
+    at: Instant,
+    cpu: Option<Duration>,
+    allocations: u64,
+    allocated: u64,
+}
+
+impl Usage {
+    fn now() -> Self {
+        Usage {
+            at: Instant::now(),
+            cpu: cpu_time(),
+            allocations: ALLOCATIONS.load(Ordering::Relaxed),
+            allocated: ALLOCATED.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// User and system time of the process, from `/proc/self/stat`.
+#[cfg(target_os = "linux")]
+fn cpu_time() -> Option<Duration> {
+    // Clock ticks of /proc, fixed by the kernel ABI whatever its internal frequency.
+    const USER_HZ: u64 = 100;
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    // The command name may hold spaces, the fields we want come after it.
+    let mut fields = stat.rsplit_once(')')?.1.split_whitespace();
+    let utime: u64 = fields.nth(11)?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+    Some(Duration::from_millis((utime + stime) * 1000 / USER_HZ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cpu_time() -> Option<Duration> {
+    None
+}
+
+/// Sends a file of `size` bytes over loopback, or over the simulator with `sim`, `runs`
+/// times, and prints what each transfer cost.
+pub fn bench(size: u64, sim: Option<Impairments>, runs: u32) -> Result<(), CliError> {
+    let dir = std::env::temp_dir().join(format!("sanic-bench-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("out"))?;
+    let benched = (|| {
+        let file = dir.join("bench.bin");
+        write_file(&file, size)?;
+        for run in 1..=runs {
+            if runs > 1 {
+                println!("Run {run} of {runs}");
+            }
+            transfer(&file, &dir.join("out"), sim)?;
+            std::fs::remove_file(dir.join("out").join("bench.bin"))?;
+        }
+        Ok(())
+    })();
+    std::fs::remove_dir_all(&dir)?;
+    benched
+}
+
+/// Writes `size` bytes of noise to `path`, so nothing on the way can shortcut them.
+fn write_file(path: &Path, size: u64) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    let mut state: u64 = 0x9e3779b97f4a7c15;
+    let mut chunk = vec![0; 1 << 20];
+    let mut left = size;
+    while left > 0 {
+        for word in chunk.chunks_mut(8) {
+            // xorshift64
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            word.copy_from_slice(&state.to_le_bytes()[..word.len()]);
+        }
+        let len = left.min(chunk.len() as u64) as usize;
+        file.write_all(&chunk[..len])?;
+        left -= len as u64;
+    }
+    file.flush()
+}
+
+fn transfer(file: &Path, output: &Path, sim: Option<Impairments>) -> Result<(), CliError> {
+    let (listening, addr) = mpsc::channel();
+    let mut receiver = Receiver::new()
+        .bind("127.0.0.1:0")
+        .output(output)
+        .once(true)
+        .tcp_fallback(false)
+        .on_listening(move |addr| {
+            let _ = listening.send(addr);
+        });
+    let mut sending = None;
+    if let Some(impairments) = sim {
+        let network = MemoryNetwork::new();
+        let receiving = network.bind("127.0.0.1:6666".parse().expect("Valid address"))?;
+        sending = Some(network.bind("127.0.0.1:6667".parse().expect("Valid address"))?);
+        // The answers go through a link of their own, seeded apart from the parts.
+        let answers = Impairments {
+            seed: impairments.seed.wrapping_add(1),
+            ..impairments
+        };
+        receiver = receiver.transport(ImpairedTransport::new(receiving, answers));
+    }
+    let receiving = std::thread::spawn(move || receiver.receive());
+    let addr: SocketAddr = addr
+        .recv_timeout(Duration::from_secs(5))
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "The receiver did not start"))?;
+
+    let stats = Arc::new(Mutex::new(None));
+    let mut sender = {
+        let stats = stats.clone();
+        Sender::new(addr.to_string())
+            .bind("127.0.0.1:0")
+            .tcp_fallback(None)
+            .on_stats(move |transfer| {
+                *stats.lock().expect("Could not lock stats") = Some(transfer.clone())
+            })
+    };
+    if let (Some(sending), Some(impairments)) = (sending, sim) {
+        sender = sender.transport(ImpairedTransport::new(sending, impairments));
+    }
+    let before = Usage::now();
+    sender.send(file)?;
+    let after = Usage::now();
+    // The receiver lingers a bit for the last acknowledgements, which we do not count.
+    receiving.join().expect("Receiver thread panicked")?;
+
+    let stats = stats.lock().expect("Could not lock stats").take();
+    report(stats.as_ref(), before, after);
+    Ok(())
+}
+
+fn report(stats: Option<&TransferStats>, before: Usage, after: Usage) {
+    if let Some(stats) = stats {
+        print!("{stats}");
+    }
+    let elapsed = after.at - before.at;
+    let cpu = match (before.cpu, after.cpu) {
+        (Some(before), Some(after)) => {
+            let cpu = after.saturating_sub(before);
+            format!(
+                "{cpu:.2?} ({:.0}% of a core)",
+                cpu.as_secs_f64() / elapsed.as_secs_f64().max(f64::EPSILON) * 100.0
+            )
+        }
+        _ => "-".to_string(),
+    };
+    let allocated = after.allocated - before.allocated;
+    let rows = [
+        ("CPU time", cpu),
+        (
+            "Allocations",
+            format!(
+                "{} ({})",
+                after.allocations - before.allocations,
+                human_bytes(allocated)
+            ),
+        ),
+    ];
+    // Lined up with the statistics above.
+    let width = "Buffers allocated / reused".len();
+    for (name, value) in rows {
+        println!("{name:<width$}  {value}");
+    }
+}
@@ -0,0 +1,90 @@
+/// Bloom filter over part ids, kept by the receiving thread to spot duplicates cheaply.
+///
+/// A miss means the part was never seen, so the exact set of received parts only has to
+/// be consulted when the filter reports a possible hit.
+#[derive(Debug)]
+pub(crate) struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+/// Most ids a filter is sized for, about 1.2MB at 1% false positives. Past them hits
+/// grow more likely, and more lookups go to the exact set, but the size stays bounded
+/// whatever a sender claims.
+const MAX_EXPECTED: u32 = 1 << 20;
+
+impl BloomFilter {
+    /// Sizes the filter for `expected` ids at the given false positive rate, up to
+    /// [`MAX_EXPECTED`] of them.
+    pub fn new(expected: u32, false_positive_rate: f64) -> Self {
+        let expected = expected.clamp(1, MAX_EXPECTED) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let nb_bits = (-expected * false_positive_rate.ln() / (ln2 * ln2)).ceil() as usize;
+        let hashes = ((nb_bits as f64 / expected) * ln2).round().clamp(1.0, 16.0) as u32;
+        BloomFilter {
+            bits: vec![0; nb_bits.div_ceil(64).max(1)],
+            hashes,
+        }
+    }
+
+    /// Adds `id` and returns whether it might already have been in the filter.
+    pub fn insert(&mut self, id: u32) -> bool {
+        let nb_bits = (self.bits.len() * 64) as u64;
+        let (h1, h2) = hash(id);
+        let mut present = true;
+        for i in 0..self.hashes as u64 {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % nb_bits;
+            let (word, mask) = ((bit / 64) as usize, 1u64 << (bit % 64));
+            present &= self.bits[word] & mask != 0;
+            self.bits[word] |= mask;
+        }
+        present
+    }
+}
+
+/// Two independent hashes of `id`, combined by double hashing.
+fn hash(id: u32) -> (u64, u64) {
+    let mut x = id as u64 ^ 0x9e37_79b9_7f4a_7c15;
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^= x >> 31;
+    (x, (x >> 32) | 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_inserted_are_always_reported() {
+        let mut filter = BloomFilter::new(10_000, 0.01);
+        for id in (0..30_000).step_by(3) {
+            filter.insert(id);
+        }
+        assert!((0..30_000).step_by(3).all(|id| filter.insert(id)));
+    }
+
+    #[test]
+    fn false_positives_stay_near_the_rate() {
+        let mut filter = BloomFilter::new(10_000, 0.01);
+        for id in 0..10_000 {
+            filter.insert(id);
+        }
+        // About 10 expected, a little more as the probes fill the filter too.
+        let hits = (1_000_000..1_001_000)
+            .filter(|&id| filter.insert(id))
+            .count();
+        assert!(hits < 40, "{hits} false positives out of 1000");
+    }
+
+    #[test]
+    fn filters_are_capped_whatever_the_count_claimed() {
+        let filter = BloomFilter::new(u32::MAX, 0.01);
+        assert!(filter.bits.len() * 8 <= 2 * 1024 * 1024);
+        assert_eq!(
+            filter.bits.len(),
+            BloomFilter::new(MAX_EXPECTED, 0.01).bits.len()
+        );
+        assert!(!BloomFilter::new(0, 0.01).bits.is_empty());
+    }
+}
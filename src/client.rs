@@ -1,165 +1,3499 @@
 use std::{
-    fs::File,
-    io::{Seek, SeekFrom, Write},
-    net::UdpSocket,
+    collections::{BTreeMap, HashMap},
+    fs::{File, OpenOptions},
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket},
+    ops::Range,
+    panic::AssertUnwindSafe,
+    path::{Path, PathBuf},
     sync::{
-        mpsc::{Receiver, Sender},
-        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc, Mutex,
     },
+    time::{Duration, Instant, SystemTime},
 };
 
-use tracing::{error, info, warn};
+use thiserror::Error;
+use tracing::{debug, error, field, info, info_span, warn};
 
-use crate::{protocol::Message, BUF_CAPACITY, MTU, PART_SIZE};
+#[cfg(feature = "mdns")]
+use crate::mdns::Announcer;
+use crate::{
+    access::{Access, Rule},
+    activity::{Activity, Direction, Session},
+    batch::Batch,
+    crypto::{self, SessionKey},
+    dedup::ChunkCache,
+    delta::{self, Signature},
+    digest::{file_digest, Follower},
+    dscp::Dscp,
+    hook::{self, Hook},
+    journal::{Entry, Journal, Outcome},
+    merkle,
+    metrics::Metrics,
+    paths,
+    pool::BufferPool,
+    portmap::PortMapping,
+    probe,
+    protocol::{Capabilities, ManifestEntry, Message, Mirror},
+    reassembly::Reassembler,
+    rendezvous::{self, Meeting, Route},
+    resume::{self, Checkpoint, Saved},
+    scan::{ScanError, Scanner, Verdict},
+    server::{INITIAL_BACKOFF, SYNC_INTERVAL},
+    session_id,
+    sessions::{Lane, Scheduler, Weight},
+    sim::{impair, Impairments},
+    sink::{Ordered, Sink},
+    socket::Socket,
+    spawn,
+    state::{Offer, Phase, ReceiverAction, ReceiverState},
+    trace::Tracer,
+    transport::{bind_udp, Buffer, TcpTransport, Transport, MAX_BATCH},
+    Progress, ProgressCallback, Quota, MAX_TEXT, MTU, PART_SIZE,
+};
+
+/// How long the receiver keeps answering Syncs once it has every part, so the sender
+/// gets the acknowledgements for the last parts.
+const LINGER: Duration = Duration::from_secs(1);
+/// How often an idle receiver checks for TCP fallback connections, and whether it was
+/// stopped.
+const TCP_POLL: Duration = Duration::from_millis(200);
+/// How long a TCP fallback connection has to send its Send message.
+const TCP_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often the reaper checks the session, and the reader wakes up to notice it was reaped.
+const REAPER_TICK: Duration = Duration::from_millis(500);
+/// Part buffers kept for reuse once written, a little more than the writer holds at once.
+const POOL_SIZE: usize = 1024;
+/// How often the receive buffer is grown to the rate parts arrive at.
+const TUNE_INTERVAL: Duration = Duration::from_secs(1);
+/// Largest receive buffer we grow to on our own, enough for about 1.3Gbps.
+pub(crate) const MAX_TUNED_BUFFER: usize = 32 * 1024 * 1024;
+/// Why the sender of a cancelled session is told it was aborted.
+const CANCELLED: &str = "the receiver's operator cancelled the transfer";
+/// How long a speed test request from the host we just sent one to is taken for a late
+/// copy of that one.
+const REPEATED_SPEEDTEST: Duration = Duration::from_secs(2);
+/// Most synthetic data a host gets from speed tests per [`SPEEDTEST_WINDOW`].
+const SPEEDTEST_BUDGET: u64 = 4 * crate::speedtest::MAX_ROUND;
+const SPEEDTEST_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+type EndedCallback = Arc<dyn Fn(&str) + Send + std::marker::Sync>;
+type TextCallback = Arc<dyn Fn(Option<SocketAddr>, &str) + Send + std::marker::Sync>;
+type SinkFactory =
+    Arc<dyn Fn(&str, Option<u64>) -> std::io::Result<Box<dyn Sink>> + Send + std::marker::Sync>;
+type SpeedTests = Arc<Mutex<HashMap<IpAddr, Served>>>;
+
+/// Speed tests sent to a host, which gets one at a time and [`SPEEDTEST_BUDGET`] bytes of
+/// them per window.
+struct Served {
+    /// Where the test being sent goes, if one is.
+    sending: Option<SocketAddr>,
+    /// Where the last test went and when it ended, so that the requests it repeated
+    /// meanwhile do not start another.
+    ended: Option<(SocketAddr, Instant)>,
+    window: Instant,
+    bytes: u64,
+}
+
+#[derive(Error, Debug)]
+pub enum ReceiveError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("The sender went silent for more than {0:?}, session aborted")]
+    Inactive(Duration),
+
+    #[error("The transfer took more than {0:?}, session aborted")]
+    TooLong(Duration),
+
+    #[error("The sender closed the connection before the end of the transfer")]
+    Disconnected,
+
+    #[error("{0} was rejected by the scanner: {1}")]
+    Rejected(String, String),
+
+    #[error("{0}")]
+    Scan(#[from] ScanError),
+
+    #[error("The transfer would exceed the quota of {0} bytes")]
+    Quota(u64),
+
+    #[error("The file is at least {size} bytes, over the limit of {limit} bytes per file")]
+    TooLarge { size: u64, limit: u64 },
+
+    #[error("Not enough disk space for the transfer, {needed} bytes needed and {free} free")]
+    NoSpace { needed: u64, free: u64 },
+
+    #[error("The sender aborted the transfer: {0}")]
+    Aborted(String),
+
+    #[error("The transfer was cancelled")]
+    Cancelled,
+
+    #[error("The accept hook declined the transfer: {0}")]
+    Declined(String),
+
+    #[error("{0} does not match the digest of the manifest")]
+    Mismatch(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expiry {
+    Inactive,
+    TooLong,
+    Disconnected,
+    /// The transfer went over the quota.
+    Quota,
+    Aborted(String),
+    /// One of the session threads ran into an error, which it returns.
+    Failed,
+    /// The operator cancelled the transfer.
+    Cancelled,
+}
+
+/// Where the parts of a transfer go.
+enum Output {
+    File(File),
+    Sink(Box<dyn Sink>),
+}
+
+/// Activity of the current session, shared between the reader and the reaper.
+struct Watchdog {
+    started: Instant,
+    /// Milliseconds between `started` and the last datagram from the sender.
+    last_activity: AtomicU64,
+    finished: AtomicBool,
+    /// The writer has every part on disk.
+    stored: AtomicBool,
+    expired: Mutex<Option<Expiry>>,
+}
+
+impl Watchdog {
+    fn new() -> Self {
+        Watchdog {
+            started: Instant::now(),
+            last_activity: AtomicU64::new(0),
+            finished: AtomicBool::new(false),
+            stored: AtomicBool::new(false),
+            expired: Mutex::new(None),
+        }
+    }
+
+    fn touch(&self) {
+        self.last_activity
+            .store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn idle(&self) -> Duration {
+        self.started.elapsed().saturating_sub(Duration::from_millis(
+            self.last_activity.load(Ordering::Relaxed),
+        ))
+    }
+
+    fn expired(&self) -> Option<Expiry> {
+        self.expired
+            .lock()
+            .expect("Could not lock watchdog")
+            .clone()
+    }
+
+    fn expire(&self, expiry: Expiry) {
+        *self.expired.lock().expect("Could not lock watchdog") = Some(expiry);
+    }
+
+    /// Expires the session when `result` is an error, so the reader stops too.
+    fn check<T>(&self, result: std::io::Result<T>) -> std::io::Result<T> {
+        if result.is_err() {
+            self.expire(Expiry::Failed);
+        }
+        result
+    }
+}
+
+/// Members of a transfer group received so far, waiting in the staging directory until
+/// the last one arrives.
+struct StagedGroup {
+    name: String,
+    count: u32,
+    dir: PathBuf,
+    /// Staged and final path of every member, by index.
+    members: BTreeMap<u32, (PathBuf, PathBuf)>,
+}
+
+impl StagedGroup {
+    fn is_complete(&self) -> bool {
+        self.members.len() as u32 == self.count
+    }
+
+    /// Moves every member to its final path.
+    fn publish(self) -> std::io::Result<()> {
+        for (staged, path) in self.members.values() {
+            std::fs::rename(staged, path)?;
+        }
+        std::fs::remove_dir(&self.dir)?;
+        info!(
+            group = self.name,
+            files = self.count,
+            "Transfer group published."
+        );
+        Ok(())
+    }
+
+    fn discard(self, keep_partial: bool) {
+        if keep_partial {
+            warn!(dir = %self.dir.display(), "Keeping the staged files of the aborted group.");
+        } else if let Err(err) = std::fs::remove_dir_all(&self.dir) {
+            warn!(dir = %self.dir.display(), error = ?err, "Could not remove the staged group.");
+        }
+    }
+}
+
+/// Bandwidth cap the receiver pushes to its senders, which can be changed while a
+/// transfer is running.
+///
+/// ```
+/// let cap = sanic::RateCap::new(Some(50_000_000));
+/// let receiver = sanic::Receiver::new().rate_cap(cap.clone());
+/// // Later, from another thread:
+/// cap.set(None);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct RateCap(Arc<AtomicU64>);
+
+impl RateCap {
+    pub fn new(bytes_per_sec: Option<u64>) -> Self {
+        RateCap(Arc::new(AtomicU64::new(bytes_per_sec.unwrap_or(0))))
+    }
+
+    /// Bytes per second, `None` lifting the cap.
+    pub fn set(&self, bytes_per_sec: Option<u64>) {
+        self.0.store(bytes_per_sec.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> Option<u64> {
+        Some(self.0.load(Ordering::Relaxed)).filter(|rate| *rate > 0)
+    }
+}
+
+/// Cap a session asks its sender for: the receiver's, or its share of it when several
+/// sessions run at once.
+#[derive(Clone)]
+enum Share {
+    Whole(RateCap),
+    Lane(Arc<Lane>),
+}
+
+impl Share {
+    fn get(&self) -> Option<u64> {
+        match self {
+            Share::Whole(cap) => cap.get(),
+            Share::Lane(lane) => lane.share(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Sync {
+    ack: Vec<u32>,
+    loss: Vec<u32>,
+    /// Parts that arrived since the previous Sync, most of them acknowledged unasked.
+    arrived: usize,
+}
+
+impl Sync {
+    fn new(ack: Vec<u32>, loss: Vec<u32>, arrived: usize) -> Self {
+        Sync { ack, loss, arrived }
+    }
+}
+
+/// Receiving side of a transfer.
+///
+/// ```no_run
+/// let receiver = sanic::Receiver::new().output("/srv/drop").once(true);
+/// receiver.receive()?;
+/// # Ok::<(), sanic::ReceiveError>(())
+/// ```
+pub struct Receiver {
+    bind: String,
+    output: Option<PathBuf>,
+    once: bool,
+    idle_timeout: Duration,
+    max_duration: Option<Duration>,
+    keep_partial: bool,
+    resume: bool,
+    /// Transfers we were receiving before a restart, by the peer sending them.
+    resumable: Mutex<HashMap<SocketAddr, Saved>>,
+    tcp_fallback: bool,
+    key: Option<SessionKey>,
+    transport: Option<Arc<dyn Transport>>,
+    /// Bound already, like the socket systemd passes.
+    socket: Option<UdpSocket>,
+    progress: Option<ProgressCallback>,
+    listening: Option<Arc<dyn Fn(SocketAddr) + Send + std::marker::Sync>>,
+    port_mapped: Option<Arc<dyn Fn(SocketAddr) + Send + std::marker::Sync>>,
+    scanner: Option<Scanner>,
+    quarantine: Option<PathBuf>,
+    access: Access,
+    rate_cap: RateCap,
+    quota: Option<Quota>,
+    max_file_size: Option<u64>,
+    disk_quota: Option<u64>,
+    max_sessions: usize,
+    weights: Vec<Weight>,
+    metrics: Metrics,
+    activity: Option<Activity>,
+    stop: Option<Arc<AtomicBool>>,
+    journal: Option<Journal>,
+    ended: Option<EndedCallback>,
+    text: Option<TextCallback>,
+    sink: Option<SinkFactory>,
+    speedtest: bool,
+    speedtests: SpeedTests,
+    hooks: Vec<Hook>,
+    accept_hook: Option<String>,
+    tracer: Option<Tracer>,
+    chaos: Option<Impairments>,
+    sparse: bool,
+    allow_delete: bool,
+    multicast: Option<Ipv4Addr>,
+    announce: bool,
+    upnp: bool,
+    /// Rendezvous server and the code we meet the sender under there.
+    rendezvous: Option<(String, Meeting)>,
+    relay_fallback: bool,
+    send_buffer: Option<usize>,
+    recv_buffer: Option<usize>,
+    dscp: Option<Dscp>,
+    max_in_flight: Option<u32>,
+    chunk_cache: Option<ChunkCache>,
+}
+
+impl Default for Receiver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Receiver {
+    pub fn new() -> Self {
+        Receiver {
+            bind: "0.0.0.0:6666".to_string(),
+            output: None,
+            once: false,
+            idle_timeout: Duration::from_secs(60),
+            max_duration: None,
+            keep_partial: false,
+            resume: false,
+            resumable: Mutex::new(HashMap::new()),
+            tcp_fallback: true,
+            key: None,
+            transport: None,
+            socket: None,
+            progress: None,
+            listening: None,
+            port_mapped: None,
+            scanner: None,
+            quarantine: None,
+            access: Access::new(),
+            rate_cap: RateCap::default(),
+            quota: None,
+            max_file_size: None,
+            disk_quota: None,
+            max_sessions: 1,
+            weights: Vec::new(),
+            metrics: Metrics::default(),
+            activity: None,
+            stop: None,
+            journal: None,
+            ended: None,
+            text: None,
+            sink: None,
+            speedtest: false,
+            speedtests: SpeedTests::default(),
+            hooks: Vec::new(),
+            accept_hook: None,
+            tracer: None,
+            chaos: None,
+            sparse: false,
+            allow_delete: false,
+            multicast: None,
+            announce: false,
+            upnp: false,
+            rendezvous: None,
+            relay_fallback: false,
+            send_buffer: None,
+            recv_buffer: None,
+            dscp: None,
+            max_in_flight: None,
+            chunk_cache: None,
+        }
+    }
+
+    /// Local address the socket listens on.
+    pub fn bind(mut self, addr: impl Into<String>) -> Self {
+        self.bind = addr.into();
+        self
+    }
+
+    /// Listens on `socket` instead of binding the `bind` address, like the one systemd
+    /// passes with socket activation.
+    pub fn socket(mut self, socket: UdpSocket) -> Self {
+        self.socket = Some(socket);
+        self
+    }
+
+    /// Where to store received files. A directory keeps the sender's file name.
+    pub fn output(mut self, path: impl Into<PathBuf>) -> Self {
+        self.output = Some(path.into());
+        self
+    }
+
+    /// Return after the first transfer instead of waiting for the next one.
+    pub fn once(mut self, once: bool) -> Self {
+        self.once = once;
+        self
+    }
+
+    /// Abort a session when the sender has been silent for this long.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Abort a session that is still running after this long, however active it is.
+    pub fn max_duration(mut self, duration: Duration) -> Self {
+        self.max_duration = Some(duration);
+        self
+    }
+
+    /// Skip the parts that are all zeroes, leaving holes in the output file instead. Files
+    /// are then only sized, not preallocated. Windows does not mark them sparse, so the
+    /// holes read as zeroes but take their space on disk.
+    pub fn sparse(mut self, sparse: bool) -> Self {
+        self.sparse = sparse;
+        self
+    }
+
+    /// Let the batches mirroring a directory of ours remove the files there they do not
+    /// have. Off by default: such batches are refused.
+    pub fn allow_delete(mut self, allow: bool) -> Self {
+        self.allow_delete = allow;
+        self
+    }
+
+    /// Keep what was received of an aborted session instead of deleting the partial file,
+    /// named like the file with a `.sanic-partial` suffix.
+    pub fn keep_partial(mut self, keep: bool) -> Self {
+        self.keep_partial = keep;
+        self
+    }
+
+    /// Save which parts of a transfer are on disk as it goes, next to the partial file, so
+    /// that once restarted we take it back from its sender rather than start over. Only
+    /// a receiver that died leaves it behind, sessions that fail remove it with the file.
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    /// Also accept transfers over TCP, on the same port, for senders that cannot reach us
+    /// over UDP.
+    pub fn tcp_fallback(mut self, enabled: bool) -> Self {
+        self.tcp_fallback = enabled;
+        self
+    }
+
+    /// Also take transfers sent to the multicast `group`, which the socket joins on the
+    /// default interface. They cannot be encrypted, so this does not go with a key.
+    pub fn multicast(mut self, group: Ipv4Addr) -> Self {
+        self.multicast = Some(group);
+        self
+    }
+
+    /// Register `code` with the rendezvous server at `relay` and take the transfers of the
+    /// sender registering it too, even when both are behind NATs. Waits up to
+    /// [`rendezvous::CODE_TTL`] for it. The transfers are encrypted with a key derived
+    /// from the code, in place of the one given to [`Receiver::key`].
+    pub fn rendezvous(mut self, relay: impl Into<String>, code: impl Into<String>) -> Self {
+        self.rendezvous = Some((relay.into(), Meeting::new(code)));
+        self
+    }
+
+    /// Also take the transfers the rendezvous server relays when hole punching failed.
+    pub fn relay_fallback(mut self, enabled: bool) -> Self {
+        self.relay_fallback = enabled;
+        self
+    }
+
+    /// Announce the receiver over mDNS as a `_sanic._udp` service named after the host,
+    /// so senders on the LAN can find it without knowing its address.
+    pub fn announce(mut self, enabled: bool) -> Self {
+        self.announce = enabled;
+        self
+    }
+
+    /// Ask the home router to forward our port from its public address, over NAT-PMP or
+    /// UPnP IGD, for as long as we receive. See [`Receiver::on_port_mapped`] to learn the
+    /// address to hand to senders.
+    pub fn upnp(mut self, enabled: bool) -> Self {
+        self.upnp = enabled;
+        self
+    }
+
+    /// Receive through `transport` instead of a UDP socket bound to the `bind` address.
+    pub fn transport(mut self, transport: impl Transport + 'static) -> Self {
+        self.transport = Some(Arc::new(transport));
+        self
+    }
+
+    /// Scan every received file before publishing it. Files are kept under a hidden name
+    /// until the scanner cleared them.
+    pub fn scanner(mut self, scanner: Scanner) -> Self {
+        self.scanner = Some(scanner);
+        self
+    }
+
+    /// Move rejected files to this directory instead of deleting them.
+    pub fn quarantine(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.quarantine = Some(dir.into());
+        self
+    }
+
+    /// Only take transfers from the senders `access` lets in, the others are ignored before
+    /// we allocate anything for them.
+    pub fn access(mut self, access: Access) -> Self {
+        self.access = access;
+        self
+    }
+
+    /// Ask senders to stay under this cap. Keep a clone of `cap` to change it while a
+    /// transfer is running.
+    pub fn rate_cap(mut self, cap: RateCap) -> Self {
+        self.rate_cap = cap;
+        self
+    }
+
+    /// Stop once `limit` bytes were received, over every transfer. Offers larger than what
+    /// is left are refused, and a transfer crossing the limit is aborted.
+    pub fn max_bytes(mut self, limit: u64) -> Self {
+        self.quota = Some(Quota::new(limit));
+        self
+    }
+
+    /// Run up to `max` sessions at once, each with a sender of its own. The senders past
+    /// that are told to come back later. A sender's transfers stay in order, as do the
+    /// ones of senders behind the same address. Not for multicast, rendezvous or once.
+    pub fn max_sessions(mut self, max: usize) -> Self {
+        self.max_sessions = max.max(1);
+        self
+    }
+
+    /// Give the sessions of the senders of `weight.range` that much of the rate cap,
+    /// against the others' 1, while several run at once.
+    pub fn weight(mut self, weight: Weight) -> Self {
+        self.weights.push(weight);
+        self
+    }
+
+    /// Refuse files over `limit` bytes, and discard the ones a delta rebuilds over it.
+    pub fn max_file_size(mut self, limit: u64) -> Self {
+        self.max_file_size = Some(limit);
+        self
+    }
+
+    /// Keep the output directory under `limit` bytes, counting what is already there:
+    /// offers that would not fit are refused, and transfers that did not are discarded.
+    pub fn quota(mut self, limit: u64) -> Self {
+        self.disk_quota = Some(limit);
+        self
+    }
+
+    /// Only accept transfers encrypted with this key.
+    pub fn key(mut self, key: SessionKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// Counts the transfers into `metrics`, to serve them with [`Metrics::serve`].
+    pub fn metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Lists the sessions running in `activity`, where they can be paused, resumed and
+    /// cancelled.
+    pub fn activity(mut self, activity: Activity) -> Self {
+        self.activity = Some(activity);
+        self
+    }
+
+    /// Stop receiving once `stop` is set, cancelling the running transfer and telling its
+    /// sender.
+    pub fn stop_on(mut self, stop: Arc<AtomicBool>) -> Self {
+        self.stop = Some(stop);
+        self
+    }
+
+    /// Records every transfer, taken or not, in `journal`.
+    pub fn journal(mut self, journal: Journal) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    /// Runs `hook` after every transfer, taken or not, before waiting for the next one.
+    pub fn hook(mut self, hook: Hook) -> Self {
+        self.hooks.push(hook);
+        self
+    }
+
+    /// Lets the shell `command` decide whether each transfer is taken, once the limits
+    /// set here passed. See [`hook`](crate::hook) for what it is given; a command that
+    /// cannot run refuses everything.
+    pub fn accept_hook(mut self, command: impl Into<String>) -> Self {
+        self.accept_hook = Some(command.into());
+        self
+    }
+
+    /// Records every message of the transfers in `tracer`, see [`crate::trace`].
+    pub fn trace_packets(mut self, tracer: Tracer) -> Self {
+        self.tracer = Some(tracer);
+        self
+    }
+
+    /// Puts the datagrams we send through `impairments`, to see how transfers hold up on
+    /// a bad link, see [`Impairments`].
+    pub fn chaos(mut self, impairments: Impairments) -> Self {
+        self.chaos = Some(impairments);
+        self
+    }
+
+    /// Kernel send buffer of the socket, in bytes. Capped by net.core.wmem_max on Linux.
+    pub fn send_buffer(mut self, bytes: usize) -> Self {
+        self.send_buffer = Some(bytes);
+        self
+    }
+
+    /// Kernel receive buffer of the socket, in bytes. Capped by net.core.rmem_max on Linux.
+    /// Without it, the buffer grows with the rate transfers come in at.
+    pub fn recv_buffer(mut self, bytes: usize) -> Self {
+        self.recv_buffer = Some(bytes);
+        self
+    }
+
+    /// Marks the datagrams we send with `dscp`, for QoS setups to classify the transfers,
+    /// see [`crate::dscp`].
+    pub fn dscp(mut self, dscp: Dscp) -> Self {
+        self.dscp = Some(dscp);
+        self
+    }
+
+    /// Most parts senders may have waiting for an Ack, to bound what a transfer keeps
+    /// in flight towards us. Unlimited by default.
+    pub fn max_in_flight(mut self, parts: u32) -> Self {
+        self.max_in_flight = Some(parts);
+        self
+    }
+
+    /// Keeps the chunks of chunked transfers in `dir`, so that senders skip the ones we
+    /// already got in a previous transfer.
+    pub fn chunk_cache(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.chunk_cache = Some(ChunkCache::new(dir.into()));
+        self
+    }
+
+    /// Called with the bound address every time the receiver starts waiting for a sender.
+    pub fn on_listening(
+        mut self,
+        callback: impl Fn(SocketAddr) + Send + std::marker::Sync + 'static,
+    ) -> Self {
+        self.listening = Some(Arc::new(callback));
+        self
+    }
+
+    /// Called with the public address the router forwards to us, once it mapped our port.
+    pub fn on_port_mapped(
+        mut self,
+        callback: impl Fn(SocketAddr) + Send + std::marker::Sync + 'static,
+    ) -> Self {
+        self.port_mapped = Some(Arc::new(callback));
+        self
+    }
+
+    /// Called with the JSON object a journal records for every transfer, taken or not,
+    /// whether one is kept or not. The SHA-256 is only known with a journal or hooks.
+    pub fn on_transfer_end(
+        mut self,
+        callback: impl Fn(&str) + Send + std::marker::Sync + 'static,
+    ) -> Self {
+        self.ended = Some(Arc::new(callback));
+        self
+    }
+
+    /// Called with the peer and the text of every text message, see
+    /// [`crate::Sender::send_text`], which is then not stored. Without it, text messages
+    /// are stored as files like the others.
+    pub fn on_text(
+        mut self,
+        callback: impl Fn(Option<SocketAddr>, &str) + Send + std::marker::Sync + 'static,
+    ) -> Self {
+        self.text = Some(Arc::new(callback));
+        self
+    }
+
+    /// Hands every file to the sink `open` gives for its name and size, when known,
+    /// instead of writing it to the output. Only whole files sent on their own are taken
+    /// then, and none of them is resumed, scanned or hashed.
+    pub fn sink(
+        mut self,
+        open: impl Fn(&str, Option<u64>) -> std::io::Result<Box<dyn Sink>>
+            + Send
+            + std::marker::Sync
+            + 'static,
+    ) -> Self {
+        self.sink = Some(Arc::new(open));
+        self
+    }
+
+    /// Take speed tests, see [`crate::speedtest`]: throw the synthetic data of their
+    /// senders away, and send synthetic data to the hosts asking for it, one test at a
+    /// time and at most 16GiB every 10 minutes per host.
+    pub fn speedtest(mut self, enabled: bool) -> Self {
+        self.speedtest = enabled;
+        self
+    }
+
+    /// Called every time a new part is received.
+    pub fn on_progress(
+        mut self,
+        callback: impl Fn(Progress) + Send + std::marker::Sync + 'static,
+    ) -> Self {
+        self.progress = Some(Arc::new(callback));
+        self
+    }
+
+    /// Waits for senders and receives their files, one at a time.
+    pub fn receive(&self) -> Result<(), ReceiveError> {
+        if self.multicast.is_some() && self.key.is_some() {
+            // Every receiver would seal its answers with the same key and nonces.
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "multicast transfers cannot be encrypted",
+            )
+            .into());
+        }
+        if self.max_sessions > 1
+            && (self.once || self.multicast.is_some() || self.rendezvous.is_some())
+        {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "only a receiver listening for good runs several sessions at once",
+            )
+            .into());
+        }
+        if self.announce && !cfg!(feature = "mdns") {
+            return Err(std::io::Error::new(
+                ErrorKind::Unsupported,
+                "sanic was built without mDNS (the mdns feature)",
+            )
+            .into());
+        }
+        let transport: Arc<dyn Transport> = match (&self.transport, self.multicast) {
+            (Some(transport), _) => transport.clone(),
+            (None, Some(group)) => {
+                let socket = match &self.socket {
+                    Some(socket) => socket.try_clone()?,
+                    None => UdpSocket::bind(&self.bind)?,
+                };
+                socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?;
+                info!(%group, "Joined the multicast group.");
+                Arc::new(socket)
+            }
+            (None, None) => match &self.socket {
+                Some(socket) => Arc::new(socket.try_clone()?),
+                None => bind_udp(&self.bind)?,
+            },
+        };
+        let transport = impair(transport, self.chaos, 0);
+        if self.resume {
+            *self
+                .resumable
+                .lock()
+                .expect("Could not lock resumable transfers") = resume::scan(self.output_dir())?;
+        }
+        let mut key = self.key.clone();
+        let mut punch = None;
+        let mut rendezvous_server = None;
+        if let Some((relay, meeting)) = &self.rendezvous {
+            let relay = relay.to_socket_addrs()?.next().ok_or_else(|| {
+                std::io::Error::new(ErrorKind::InvalidInput, "Relay address did not resolve")
+            })?;
+            let (route, derived) =
+                meeting.route(&*transport, relay, false, rendezvous::CODE_TTL)?;
+            if let Route::Direct(peer) = route {
+                punch = Some(peer);
+            }
+            key = Some(derived);
+            rendezvous_server = Some(relay);
+        }
+        // Senders may stripe their parts over several source ports.
+        let socket = Socket::new(transport.clone(), key.as_ref(), crypto::SPACE_RECEIVER)?
+            .any_port()
+            .trace(self.tracer.clone());
+        socket.size_buffers(self.send_buffer, self.recv_buffer);
+        socket.mark(self.dscp);
+        if let Some(peer) = punch {
+            rendezvous::punch(socket.try_clone()?, peer);
+        }
+        #[cfg(feature = "mdns")]
+        let _announcer = if self.announce {
+            let port = socket.local_addr()?.port();
+            Some(Announcer::start(&probe::hostname(), port)?)
+        } else {
+            None
+        };
+        let _mapping = if self.upnp {
+            let mapping = PortMapping::start(socket.local_addr()?.port())?;
+            if let Some(port_mapped) = &self.port_mapped {
+                port_mapped(mapping.external());
+            }
+            Some(mapping)
+        } else {
+            None
+        };
+        let tcp_streams =
+            if self.tcp_fallback && self.transport.is_none() && self.rendezvous.is_none() {
+                Some(listen_tcp(socket.local_addr()?)?)
+            } else {
+                None
+            };
+        let tcp_streams = tcp_streams.map(Mutex::new);
+        if self.max_sessions <= 1 {
+            return self.serve(socket, tcp_streams.as_ref(), rendezvous_server, None);
+        }
+        let (scheduler, lanes, overflow) = Scheduler::new(
+            transport,
+            self.max_sessions,
+            self.weights.clone(),
+            self.rate_cap.clone(),
+        );
+        let failure = Mutex::new(None);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                if let Err(err) = scheduler.dispatch() {
+                    error!(error = ?err, "Could not receive anymore.");
+                }
+            });
+            let overflow = socket.with_transport(overflow);
+            scope.spawn(move || turn_away(&overflow));
+            for lane in lanes {
+                let socket = socket.with_transport(lane.clone());
+                let (tcp_streams, scheduler, failure) =
+                    (tcp_streams.as_ref(), &scheduler, &failure);
+                scope.spawn(move || {
+                    let served = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                        self.serve(socket, tcp_streams, rendezvous_server, Some(&lane))
+                    }))
+                    .unwrap_or_else(|_| {
+                        Err(std::io::Error::other("a receiver worker panicked").into())
+                    });
+                    if let Err(err) = served {
+                        // The first failure takes the receiver down, the others follow.
+                        failure
+                            .lock()
+                            .expect("Could not lock failure")
+                            .get_or_insert(err);
+                        scheduler.stop();
+                    }
+                });
+            }
+        });
+        match failure.into_inner().expect("Could not lock failure") {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Lists the files `server` serves, as `sanic serve` does.
+    pub fn list(&self, server: &str) -> Result<Vec<ManifestEntry>, ReceiveError> {
+        let (socket, _) = self.dial(server)?;
+        let mut entries: Vec<ManifestEntry> = Vec::new();
+        let mut total = None;
+        while total.is_none_or(|total| entries.len() < total) {
+            let first = entries.len() as u32;
+            let mut more = 0;
+            self.ask(&socket, &Message::List { first }, |message| match message {
+                Message::Manifest {
+                    first: at,
+                    total: all,
+                    entries: listed,
+                    ..
+                } if at == first => {
+                    total = Some(all as usize);
+                    more = listed.len();
+                    entries.extend(listed);
+                    Ok(true)
+                }
+                _ => Ok(false),
+            })?;
+            if more == 0 && total.is_some_and(|total| entries.len() < total) {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    "the listing stopped short of its end",
+                )
+                .into());
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Asks `server` for the file it lists as `name`, and receives it like a pushed one.
+    pub fn get(self, server: &str, name: &str) -> Result<(), ReceiveError> {
+        let request = Message::Get {
+            name: name.to_string(),
+            range: None,
+        };
+        self.fetch(server, &request)
+    }
+
+    /// Asks `server` for the bytes in `range` of the file it lists as `name`, and writes
+    /// them in place into our copy of the file.
+    pub fn get_range(
+        self,
+        server: &str,
+        name: &str,
+        range: Range<u64>,
+    ) -> Result<(), ReceiveError> {
+        let request = Message::Get {
+            name: name.to_string(),
+            range: Some(range),
+        };
+        self.fetch(server, &request)
+    }
+
+    /// Asks the receiver at `server` for `bytes` of synthetic data, and receives them for a
+    /// speed test.
+    pub(crate) fn get_synthetic(self, server: &str, bytes: u64) -> Result<(), ReceiveError> {
+        Receiver {
+            speedtest: true,
+            ..self
+        }
+        .fetch(server, &Message::SpeedTest { bytes })
+    }
+
+    /// Sends `request` to `server` and receives the transfer it answers with.
+    fn fetch(self, server: &str, request: &Message) -> Result<(), ReceiveError> {
+        let (socket, transport) = self.dial(server)?;
+        let server = socket.peer().expect("Dialed sockets have a peer");
+        // Whatever comes back is the transfer starting, the server repeats it until
+        // answered.
+        self.ask(&socket, request, |_| Ok(true))?;
+        info!(%server, ?request, "The server is sending.");
+        Receiver {
+            transport: Some(transport),
+            once: true,
+            tcp_fallback: false,
+            announce: false,
+            upnp: false,
+            multicast: None,
+            rendezvous: None,
+            max_sessions: 1,
+            access: Access::new().allow(Rule {
+                range: server.ip().into(),
+                token: None,
+            }),
+            ..self
+        }
+        .receive()
+    }
+
+    /// Socket talking to the serving host at `server`, from a port of our own.
+    fn dial(&self, server: &str) -> Result<(Socket, Arc<dyn Transport>), ReceiveError> {
+        let server = server.to_socket_addrs()?.next().ok_or_else(|| {
+            std::io::Error::new(ErrorKind::InvalidInput, "Server address did not resolve")
+        })?;
+        let transport = match &self.transport {
+            Some(transport) => transport.clone(),
+            None if server.is_ipv4() => bind_udp("0.0.0.0:0")?,
+            None => bind_udp("[::]:0")?,
+        };
+        let socket = Socket::new(
+            impair(transport.clone(), self.chaos, 0),
+            None,
+            crypto::SPACE_RECEIVER,
+        )?
+        .trace(self.tracer.clone());
+        socket.connect(server);
+        // Speed tests come from a port of their own.
+        Ok((socket.any_port(), transport))
+    }
+
+    /// Sends `request`, padded so that no answer outweighs it, until `take` has what it
+    /// waits for, doubling the wait between attempts. Gives up after the idle timeout,
+    /// and fails on an Abort.
+    fn ask(
+        &self,
+        socket: &Socket,
+        request: &Message,
+        mut take: impl FnMut(Message) -> Result<bool, ReceiveError>,
+    ) -> Result<(), ReceiveError> {
+        let mut packet = request.serialize();
+        packet.resize(MTU, 0);
+        let start = Instant::now();
+        let mut backoff = INITIAL_BACKOFF;
+        let mut buf = vec![0; MTU];
+        loop {
+            let remaining = match self.idle_timeout.checked_sub(start.elapsed()) {
+                Some(remaining) if !remaining.is_zero() => remaining,
+                _ => return Err(ReceiveError::Inactive(self.idle_timeout)),
+            };
+            socket.send(&packet)?;
+            let deadline = Instant::now() + backoff.min(remaining);
+            loop {
+                let wait = deadline.saturating_duration_since(Instant::now());
+                if wait.is_zero() {
+                    break;
+                }
+                socket.set_read_timeout(Some(wait))?;
+                match socket.recv(&mut buf) {
+                    Ok(size) => match Message::parse(&buf[..size]) {
+                        Ok(Message::Abort { reason }) => return Err(ReceiveError::Aborted(reason)),
+                        Ok(message) => {
+                            if take(message)? {
+                                return Ok(());
+                            }
+                        }
+                        Err(err) => warn!(error = ?err, "Could not parse packet."),
+                    },
+                    Err(err)
+                        if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                    {
+                        break
+                    }
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            debug!(?backoff, "No answer from the server, asking again.");
+            backoff *= 2;
+        }
+    }
+
+    /// Runs the sessions of the senders reaching `socket`, one after the other. With
+    /// several at once, `lane` is the part of the traffic the socket gets.
+    fn serve(
+        &self,
+        socket: Socket,
+        tcp_streams: Option<&Mutex<mpsc::Receiver<TcpStream>>>,
+        rendezvous_server: Option<SocketAddr>,
+        lane: Option<&Arc<Lane>>,
+    ) -> Result<(), ReceiveError> {
+        let mut state = ReceiverState::new();
+        let mut staged: Option<StagedGroup> = None;
+        let mut batch: Option<Batch> = None;
+        let mut signature: Option<Signature> = None;
+        // Transfer the previous sender started right after its last one.
+        let mut pending: Option<(Socket, Offer)> = None;
+        loop {
+            if let Some(lane) = lane {
+                lane.end();
+            }
+            let (socket, offer) = match pending.take() {
+                Some(next) => next,
+                None => {
+                    socket.disconnect();
+                    // The workers share the port, once is enough.
+                    if lane.is_none_or(|lane| lane.is_first()) {
+                        let addr = socket.local_addr()?;
+                        info!(%addr, "Listening.");
+                        if let Some(listening) = &self.listening {
+                            listening(addr);
+                        }
+                    }
+                    state.reset();
+                    let session = self.wait_for_sender(
+                        &socket,
+                        &mut state,
+                        &mut batch,
+                        &mut signature,
+                        tcp_streams,
+                        rendezvous_server,
+                    )?;
+                    match session {
+                        Some(session) => session,
+                        // The sender announced a batch we already had all of.
+                        None => return Ok(()),
+                    }
+                }
+            };
+            let member = batch
+                .as_ref()
+                .and_then(|batch| batch.target(&offer.filename));
+            let id = session_id()?;
+            let session = info_span!(
+                "session",
+                id,
+                peer = field::Empty,
+                filename = offer.filename,
+            );
+            if let Some(peer) = socket.peer() {
+                session.record("peer", field::display(peer));
+                if let Some(lane) = lane {
+                    lane.start(peer.ip());
+                }
+            }
+            let _session = session.enter();
+            let entry = |outcome, started: Instant| Entry {
+                peer: socket.peer(),
+                filename: &offer.filename,
+                size: None,
+                duration: started.elapsed(),
+                sha256: None,
+                outcome,
+            };
+            // Speed tests are no transfers to keep track of.
+            let record = |entry: Entry| {
+                if !offer.synthetic {
+                    self.record(entry)
+                }
+            };
+            let nb_parts = offer.parts;
+            // Transfers taken back after a restart were accepted before it.
+            let resumed = state.resumed();
+            let refusal = match resumed {
+                true => None,
+                false => self.refusal(&offer, socket.peer()),
+            };
+            if let Some((reason, err)) = refusal {
+                record(entry(Outcome::Refused(&reason), Instant::now()));
+                socket.send(&Message::Abort { reason }.serialize())?;
+                // The rest of the group will not come.
+                if offer.group.is_some() {
+                    if let Some(group) = staged.take() {
+                        group.discard(self.keep_partial);
+                    }
+                }
+                if self.once {
+                    return Err(err);
+                }
+                warn!(error = %err, "Transfer refused, waiting for the next sender.");
+                continue;
+            }
+
+            // Members of a group are staged next to their final path and only published
+            // together, once the whole group is there.
+            let path = match &offer.group {
+                Some(member) => {
+                    if staged.as_ref().is_some_and(|group| {
+                        group.name != member.name || group.count != member.count
+                    }) {
+                        warn!(
+                            group = member.name,
+                            "New transfer group, discarding the previous one."
+                        );
+                        if let Some(group) = staged.take() {
+                            group.discard(self.keep_partial);
+                        }
+                    }
+                    if staged.is_none() {
+                        let dir = self.group_dir(&member.name)?;
+                        std::fs::create_dir_all(&dir)?;
+                        staged = Some(StagedGroup {
+                            name: member.name.clone(),
+                            count: member.count,
+                            dir,
+                            members: BTreeMap::new(),
+                        });
+                    }
+                    let group = staged.as_ref().expect("Group is staged");
+                    group.dir.join(self.member_name(&offer.filename))
+                }
+                // Ranges are written in place, into our copy of the file.
+                None if offer.offset.is_some() => member
+                    .clone()
+                    .unwrap_or_else(|| self.target_path(&offer.filename)),
+                // Renamed once complete, and cleared by the scanner if any, so consumers
+                // never see a half written file under its final name.
+                None => {
+                    let target = member.clone();
+                    partial_path(&target.unwrap_or_else(|| self.target_path(&offer.filename)))
+                }
+            };
+            // Sized before accepting, so a full disk fails the transfer before it starts.
+            // Synthetic data has nowhere to go, and text messages shown are not stored.
+            let sunk =
+                self.sink.is_some() && !offer.synthetic && !(offer.text && self.text.is_some());
+            let created = match (&self.sink, offer.synthetic, resumed) {
+                (Some(open), _, _) if sunk => open(&offer.filename, offer.size).map(Output::Sink),
+                (_, true, _) => null_device().map(Output::File),
+                (_, false, true) => OpenOptions::new().write(true).open(&path).map(Output::File),
+                (_, false, false) => {
+                    let len = offer.size.unwrap_or(u64::from(nb_parts) * PART_SIZE as u64);
+                    self.create(&path, len, offer.offset.is_some())
+                        .map(Output::File)
+                }
+            };
+            let output = match created {
+                Ok(output) => output,
+                Err(err) => {
+                    let reason = format!("could not create the file: {err}");
+                    record(entry(Outcome::Refused(&reason), Instant::now()));
+                    socket.send(&Message::Abort { reason }.serialize())?;
+                    return Err(err.into());
+                }
+            };
+            if !resumed {
+                let capabilities = self.capabilities();
+                state.accepted(capabilities.clone());
+                let accept = Message::Accept {
+                    capabilities: Some(capabilities),
+                };
+                socket.send(&accept.serialize())?;
+            }
+            // Members of a batch are left out, the next run would not know of the batch.
+            let saved_path =
+                (self.resume && member.is_none() && !sunk && resume::resumable(&offer))
+                    .then(|| resume::path_of(&self.target_path(&offer.filename)));
+            let saved = saved_path
+                .clone()
+                .zip(socket.peer())
+                .map(|(path, peer)| Checkpoint::new(path, peer, &offer));
+            // Hashed as it is written when the journal, the hooks or the manifest want its
+            // digest. Deltas and chunks only become the file once patched, and ranges are
+            // pieces of one.
+            let wanted = self.journal.is_some()
+                || !self.hooks.is_empty()
+                || (member.is_some() && batch.is_some());
+            let follower = (wanted
+                && !sunk
+                && !offer.delta
+                && !offer.dedup
+                && !offer.synthetic
+                && offer.offset.is_none())
+            .then(|| Follower::new(&path))
+            .and_then(|follower| {
+                follower
+                    .inspect_err(|err| warn!(error = %err, "Could not hash the file as it comes."))
+                    .ok()
+            });
+            let accepted = Instant::now();
+            self.metrics.started();
+            let tracked = self.activity.as_ref().map(|activity| {
+                activity.start(
+                    Direction::Receive,
+                    &id,
+                    socket.peer(),
+                    &offer.filename,
+                    nb_parts,
+                )
+            });
+            let listed = tracked.as_ref().map(|tracked| Arc::clone(tracked));
+            let share = match lane {
+                Some(lane) => Share::Lane(lane.clone()),
+                None => Share::Whole(self.rate_cap.clone()),
+            };
+            // Also repeated with every Sync answer, but the first burst should respect it.
+            if let Some(bytes_per_sec) = share.get() {
+                socket.send(&Message::RateLimit { bytes_per_sec }.serialize())?;
+            }
+
+            let (file_tx, file_rx) = mpsc::channel();
+            let (sync_tx, sync_rx) = mpsc::channel();
+
+            let watchdog = Arc::new(Watchdog::new());
+
+            let pool = BufferPool::new(POOL_SIZE);
+            let writer = match output {
+                Output::Sink(sink) => {
+                    // Holes would hold sequential sinks up.
+                    let parts = Reassembler::new(Ordered::new(sink), nb_parts, false, pool.clone());
+                    let watchdog = watchdog.clone();
+                    let metrics = self.metrics.clone();
+                    let size = offer.size;
+                    spawn(move || {
+                        let stored = handle_sink_write(parts, size, file_rx, metrics);
+                        if let Ok(true) = stored {
+                            watchdog.stored.store(true, Ordering::Release);
+                        }
+                        watchdog.check(stored.map(drop))
+                    })
+                }
+                Output::File(file) => {
+                    // Zero parts of a range still have to overwrite what was there.
+                    let sparse = self.sparse && offer.offset.is_none();
+                    // The null device cannot be sized, parts written from an offset leave it be.
+                    let offset = offer.offset.or(offer.synthetic.then_some(0));
+                    let parts = Reassembler::new(file, nb_parts, sparse, pool.clone())
+                        .at(offset.unwrap_or(0))
+                        .stored(state.received());
+                    let watchdog = watchdog.clone();
+                    let metrics = self.metrics.clone();
+                    let synthetic = offer.synthetic;
+                    let hashed = follower.as_ref().map(Follower::reports);
+                    let size = offer.size;
+                    spawn(move || {
+                        let written = handle_file_write(
+                            parts,
+                            offset.is_some(),
+                            size,
+                            file_rx,
+                            metrics,
+                            saved,
+                            hashed,
+                        );
+                        let stored = written.and_then(|file| match file {
+                            // The null device cannot be synced.
+                            Some(file) if !synthetic => file.sync_data().map(|()| true),
+                            Some(_) => Ok(true),
+                            None => Ok(false),
+                        });
+                        if let Ok(true) = stored {
+                            watchdog.stored.store(true, Ordering::Release);
+                        }
+                        watchdog.check(stored.map(drop))
+                    })
+                }
+            };
+            let sync = {
+                let socket = socket.try_clone()?;
+
+                let watchdog = watchdog.clone();
+                let metrics = self.metrics.clone();
+                let tune = self.recv_buffer.is_none();
+                let listed = listed.clone();
+                spawn(move || {
+                    watchdog.check(handle_client_sync(
+                        socket, sync_rx, share, metrics, tune, listed,
+                    ))
+                })
+            };
+            let reaper = {
+                let watchdog = watchdog.clone();
+                let (idle_timeout, max_duration) = (self.idle_timeout, self.max_duration);
+                let (listed, stop) = (listed.clone(), self.stop.clone());
+                spawn(move || handle_reaper(watchdog, idle_timeout, max_duration, listed, stop))
+            };
+            let progress = match (&self.progress, listed) {
+                (progress, None) => progress.clone(),
+                (progress, Some(listed)) => {
+                    let progress = progress.clone();
+                    Some(Arc::new(move |update: Progress| {
+                        listed.progress(update.parts_done);
+                        if let Some(progress) = &progress {
+                            progress(update);
+                        }
+                    }) as ProgressCallback)
+                }
+            };
+            let read = handle_client_read(
+                socket.try_clone()?,
+                &mut state,
+                ReaderOutputs {
+                    file: file_tx,
+                    sync: sync_tx,
+                },
+                watchdog.clone(),
+                progress,
+                self.quota.clone(),
+                pool,
+            );
+            watchdog.finished.store(true, Ordering::Relaxed);
+            drop(tracked);
+
+            // The first error any thread ran into ends the session.
+            let mut failure = None;
+            let next = read.unwrap_or_else(|err| {
+                failure = Some(err);
+                None
+            });
+            for thread in [writer, sync] {
+                let result = thread
+                    .join()
+                    .unwrap_or_else(|_| Err(std::io::Error::other("a receiver thread panicked")));
+                if let Err(err) = result {
+                    failure.get_or_insert(err);
+                }
+            }
+            if reaper.join().is_err() {
+                error!("The reaper thread panicked.");
+            }
+            if let Some(next) = next {
+                pending = Some((socket.try_clone()?, next));
+            }
+
+            if let Some(err) = &failure {
+                error!(error = %err, "Session failed.");
+                let reason = match err.kind() {
+                    ErrorKind::StorageFull => "receiver ran out of disk space".to_string(),
+                    _ => format!("receiver failed: {err}"),
+                };
+                if let Err(err) = socket.send(&Message::Abort { reason }.serialize()) {
+                    warn!(error = ?err, "Could not tell the sender about the failure.");
+                }
+            }
+            // The reader only lets a transfer end once it is stored.
+            if failure.is_none()
+                && watchdog.expired().is_none()
+                && !watchdog.stored.load(Ordering::Acquire)
+            {
+                failure = Some(std::io::Error::other(
+                    "the session ended before every part was stored",
+                ));
+            }
+            let expiry = if failure.is_some() {
+                Some(Expiry::Failed)
+            } else {
+                watchdog.expired()
+            };
+            if expiry == Some(Expiry::TooLong) {
+                let reason = "the transfer went over the receiver's time limit".to_string();
+                if let Err(err) = socket.send(&Message::Abort { reason }.serialize()) {
+                    warn!(error = ?err, "Could not tell the sender about the time limit.");
+                }
+            }
+            if expiry == Some(Expiry::Cancelled) {
+                let reason = CANCELLED.to_string();
+                if let Err(err) = socket.send(&Message::Abort { reason }.serialize()) {
+                    warn!(error = ?err, "Could not tell the sender about the cancellation.");
+                }
+            }
+            self.metrics.ended(expiry.is_none());
+            // The resume state goes with the partial file, unless it is kept.
+            if let Some(saved) = saved_path
+                .as_ref()
+                .filter(|_| expiry.is_none() || !self.keep_partial)
+            {
+                forget(saved);
+            }
+            if let Some(expiry) = expiry {
+                if offer.group.is_some() {
+                    // All or nothing: the members we already have go with it.
+                    if let Some(group) = staged.take() {
+                        group.discard(self.keep_partial);
+                    }
+                } else if offer.synthetic || sunk {
+                    // Nothing was written here, the writer aborted the sink if any.
+                } else if self.keep_partial || offer.offset.is_some() {
+                    warn!(path = %path.display(), "Keeping the partial file of the aborted session.");
+                } else if let Err(err) = std::fs::remove_file(&path) {
+                    warn!(path = %path.display(), error = ?err, "Could not remove the partial file.");
+                }
+                let err = match expiry {
+                    Expiry::Inactive => ReceiveError::Inactive(self.idle_timeout),
+                    Expiry::TooLong => ReceiveError::TooLong(self.max_duration.unwrap_or_default()),
+                    Expiry::Disconnected => ReceiveError::Disconnected,
+                    Expiry::Quota => {
+                        ReceiveError::Quota(self.quota.as_ref().map_or(0, Quota::limit))
+                    }
+                    Expiry::Aborted(reason) => ReceiveError::Aborted(reason),
+                    Expiry::Cancelled => ReceiveError::Cancelled,
+                    Expiry::Failed => match failure {
+                        Some(err) => err.into(),
+                        None => ReceiveError::Io(std::io::Error::other("session failed")),
+                    },
+                };
+                record(entry(Outcome::Failed(&err.to_string()), accepted));
+                if self.once {
+                    return Err(err);
+                }
+                warn!(error = %err, "Session reaped, waiting for the next sender.");
+                continue;
+            }
+            if offer.synthetic {
+                info!("Speed test finished.");
+                if self.once {
+                    return Ok(());
+                }
+                continue;
+            }
+            if sunk {
+                info!("Transfer finished, the sink has the file.");
+                record(Entry {
+                    size: offer.size,
+                    ..entry(Outcome::Completed, accepted)
+                });
+                if self.once {
+                    return Ok(());
+                }
+                continue;
+            }
+            info!(path = %path.display(), "Transfer finished.");
+            if offer.delta || offer.dedup {
+                let basis = offer.delta.then(|| {
+                    member
+                        .clone()
+                        .unwrap_or_else(|| self.target_path(&offer.filename))
+                });
+                let cache = self.chunk_cache.as_ref().filter(|_| offer.dedup);
+                if let Err(err) = patch(basis.as_deref(), cache, &path) {
+                    let err = ReceiveError::from(err);
+                    record(entry(Outcome::Failed(&err.to_string()), accepted));
+                    if let Err(err) = std::fs::remove_file(&path) {
+                        warn!(path = %path.display(), error = ?err, "Could not remove the delta.");
+                    }
+                    if offer.group.is_some() {
+                        if let Some(group) = staged.take() {
+                            group.discard(self.keep_partial);
+                        }
+                    }
+                    if self.once {
+                        return Err(err);
+                    }
+                    warn!(error = %err, "Could not apply the delta, waiting for the next sender.");
+                    continue;
+                }
+            }
+            // Ranges were sized upfront, and went over our copy of the file.
+            if let Some(err) = self.overrun(&path).filter(|_| offer.offset.is_none()) {
+                record(entry(Outcome::Refused(&err.to_string()), accepted));
+                if let Err(err) = std::fs::remove_file(&path) {
+                    warn!(path = %path.display(), error = ?err, "Could not remove the file.");
+                }
+                if offer.group.is_some() {
+                    if let Some(group) = staged.take() {
+                        group.discard(self.keep_partial);
+                    }
+                }
+                if self.once {
+                    return Err(err);
+                }
+                warn!(error = %err, "Transfer discarded, waiting for the next sender.");
+                continue;
+            }
+            // Hashed before publishing, which may move the file away.
+            let size = std::fs::metadata(&path).map(|meta| meta.len()).ok();
+            let digest = follower.zip(size).and_then(|(follower, size)| {
+                follower
+                    .finish(size)
+                    .inspect_err(|err| warn!(error = %err, "Could not hash the file as it came."))
+                    .ok()
+            });
+            let finished = entry(Outcome::Completed, accepted);
+            let finished = Entry {
+                size,
+                sha256: (self.journal.is_some() || !self.hooks.is_empty())
+                    .then(|| digest.or_else(|| file_digest(&path).ok()))
+                    .flatten(),
+                ..finished
+            };
+            if let Some(shown) = self.text.as_ref().filter(|_| offer.text) {
+                let read = std::fs::read(&path);
+                if let Err(err) = std::fs::remove_file(&path) {
+                    warn!(path = %path.display(), error = ?err, "Could not remove the message.");
+                }
+                match read {
+                    Ok(text) => {
+                        shown(socket.peer(), &String::from_utf8_lossy(&text));
+                        record(finished);
+                    }
+                    Err(err) => {
+                        let err = ReceiveError::from(err);
+                        record(Entry {
+                            outcome: Outcome::Failed(&err.to_string()),
+                            ..finished
+                        });
+                        if self.once {
+                            return Err(err);
+                        }
+                        warn!(error = %err, "Could not read the message, waiting for the next sender.");
+                        continue;
+                    }
+                }
+                if self.once {
+                    return Ok(());
+                }
+                continue;
+            }
+
+            let published = match offer.group {
+                Some(member) => {
+                    let group = staged.as_mut().expect("Group is staged");
+                    let target = group
+                        .dir
+                        .parent()
+                        .unwrap_or(Path::new("."))
+                        .join(path.file_name().expect("Staged members have a file name"));
+                    group.members.insert(member.index, (path, target));
+                    if !group.is_complete() {
+                        record(finished);
+                        continue;
+                    }
+                    self.publish_group(staged.take().expect("Group is staged"))
+                }
+                None => match (&member, &mut batch) {
+                    (Some(target), Some(batch)) => {
+                        self.publish_member(batch, &offer.filename, &path, target, digest)
+                    }
+                    _ => self.publish(&path, &self.target_path(&offer.filename)),
+                },
+            };
+            let error = published.as_ref().err().map(ToString::to_string);
+            record(match &error {
+                None => finished,
+                Some(reason) => Entry {
+                    outcome: Outcome::Failed(reason),
+                    ..finished
+                },
+            });
+            if let Err(err) = published {
+                if self.once {
+                    return Err(err);
+                }
+                warn!(error = %err, "Could not publish the transfer, waiting for the next sender.");
+                continue;
+            }
+            if member.is_some() && batch.as_ref().is_some_and(|batch| !batch.is_complete()) {
+                continue;
+            }
+
+            if self.once {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Why `offer` is turned down, if it is, as told to the sender. Refused upfront rather
+    /// than failing halfway through.
+    fn refusal(&self, offer: &Offer, peer: Option<SocketAddr>) -> Option<(String, ReceiveError)> {
+        if offer.synthetic {
+            // Thrown away, so no limit of what we keep applies.
+            let reason = match self.speedtest {
+                false => {
+                    let reason = "the receiver does not take speed tests".to_string();
+                    return Some((reason.clone(), ReceiveError::Declined(reason)));
+                }
+                true if offer.offset.is_some()
+                    || offer.group.is_some()
+                    || offer.delta
+                    || offer.dedup
+                    || offer.text =>
+                {
+                    "a speed test is sent whole and on its own"
+                }
+                true => return None,
+            };
+            return Some((
+                reason.to_string(),
+                std::io::Error::new(ErrorKind::InvalidInput, reason).into(),
+            ));
+        }
+        if offer.offset.is_some() && (offer.group.is_some() || offer.delta || offer.dedup) {
+            let reason = "a range cannot be sent in a group or as a delta";
+            return Some((
+                reason.to_string(),
+                std::io::Error::new(ErrorKind::InvalidInput, reason).into(),
+            ));
+        }
+        if offer.text
+            && (offer.offset.is_some() || offer.group.is_some() || offer.delta || offer.dedup)
+        {
+            let reason = "a text message is sent whole and on its own";
+            return Some((
+                reason.to_string(),
+                std::io::Error::new(ErrorKind::InvalidInput, reason).into(),
+            ));
+        }
+        if self.sink.is_some()
+            && (offer.offset.is_some() || offer.group.is_some() || offer.delta || offer.dedup)
+        {
+            let reason = "the receiver only takes whole files, on their own";
+            return Some((
+                reason.to_string(),
+                std::io::Error::new(ErrorKind::InvalidInput, reason).into(),
+            ));
+        }
+        // Only the last part can be shorter than PART_SIZE.
+        let parts = u64::from(offer.parts);
+        if let Some(size) = offer
+            .size
+            .filter(|size| size.div_ceil(PART_SIZE as u64) != parts)
+        {
+            let reason = format!("{size} bytes do not make {parts} parts");
+            return Some((
+                reason.clone(),
+                std::io::Error::new(ErrorKind::InvalidInput, reason).into(),
+            ));
+        }
+        let least = offer
+            .size
+            .unwrap_or(parts.saturating_sub(1) * PART_SIZE as u64 + u64::from(parts > 0));
+        if offer.text && least > MAX_TEXT as u64 {
+            let limit = MAX_TEXT as u64;
+            return Some((
+                format!("text message of at least {least} bytes, over the limit of {limit}"),
+                ReceiveError::TooLarge { size: least, limit },
+            ));
+        }
+        // A range makes the file at least as long as where it ends.
+        let size = offer.offset.unwrap_or(0).saturating_add(least);
+        if let Some(quota) = self
+            .quota
+            .as_ref()
+            .filter(|quota| least > quota.remaining())
+        {
+            return Some((quota.reason(), ReceiveError::Quota(quota.limit())));
+        }
+        if let Some(limit) = self.max_file_size.filter(|limit| size > *limit) {
+            return Some((
+                format!("file of at least {size} bytes, over the limit of {limit}"),
+                ReceiveError::TooLarge { size, limit },
+            ));
+        }
+        // Files handed to a sink take no room here.
+        if let Some(limit) = self.disk_quota.filter(|_| self.sink.is_none()) {
+            match probe::used_space(self.output_dir()) {
+                Ok(used) if used.saturating_add(least) > limit => {
+                    return Some((
+                        format!("quota of {limit} bytes exceeded, {used} already used"),
+                        ReceiveError::Quota(limit),
+                    ));
+                }
+                Ok(_) => {}
+                Err(err) => warn!(error = ?err, "Could not tell how much the output holds."),
+            }
+        }
+        match probe::free_space(self.output_dir()).filter(|_| self.sink.is_none()) {
+            Some(free) if least > free => {
+                return Some((
+                    format!("not enough disk space, {least} bytes needed and {free} free"),
+                    ReceiveError::NoSpace {
+                        needed: least,
+                        free,
+                    },
+                ))
+            }
+            _ => {}
+        }
+        let command = self.accept_hook.as_ref()?;
+        let reason = match hook::ask(command, &offer.filename, size, peer) {
+            Ok(reason) => reason?,
+            Err(err) => {
+                warn!(error = ?err, "Could not run the accept hook.");
+                "the receiver could not check its policy".to_string()
+            }
+        };
+        Some((reason.clone(), ReceiveError::Declined(reason)))
+    }
+
+    /// Why the finished file at `path` cannot be kept, if it cannot: a delta can rebuild a
+    /// file much larger than what was sent, and the last part can be up to a part longer
+    /// than we counted.
+    fn overrun(&self, path: &Path) -> Option<ReceiveError> {
+        let size = std::fs::metadata(path).map(|meta| meta.len()).ok()?;
+        if let Some(limit) = self.max_file_size.filter(|limit| size > *limit) {
+            return Some(ReceiveError::TooLarge { size, limit });
+        }
+        let limit = self.disk_quota?;
+        match probe::used_space(self.output_dir()) {
+            Ok(used) if used > limit => Some(ReceiveError::Quota(limit)),
+            _ => None,
+        }
+    }
+
+    /// Writes `entry` to the journal, if we keep one, and runs the hooks.
+    fn record(&self, entry: Entry) {
+        if let Some(ended) = &self.ended {
+            ended(entry.to_line(SystemTime::now()).trim_end());
+        }
+        if let Some(journal) = &self.journal {
+            if let Err(err) = journal.record(&entry) {
+                warn!(error = ?err, "Could not write to the journal.");
+            }
+        }
+        for hook in &self.hooks {
+            if let Err(err) = hook.run(&entry) {
+                warn!(error = ?err, ?hook, "Transfer hook failed.");
+            }
+        }
+    }
+
+    /// Waits for a Send message, over UDP or over a TCP fallback connection, and returns
+    /// the socket the session runs on. Transfers relayed by the `rendezvous_server` are
+    /// only taken with [`Receiver::relay_fallback`].
+    ///
+    /// Manifests are answered meanwhile, and the `batch` they announce kept. Returns None
+    /// when receiving only once and the batch needs none of its files. Signatures are
+    /// answered too, from the last `signature` while the file stays the same.
+    fn wait_for_sender(
+        &self,
+        socket: &Socket,
+        state: &mut ReceiverState,
+        batch: &mut Option<Batch>,
+        signature: &mut Option<Signature>,
+        tcp_streams: Option<&Mutex<mpsc::Receiver<TcpStream>>>,
+        rendezvous_server: Option<SocketAddr>,
+    ) -> Result<Option<(Socket, Offer)>, ReceiveError> {
+        let polled = tcp_streams.is_some() || self.stop.is_some();
+        socket.set_read_timeout(polled.then_some(TCP_POLL))?;
+        let mut buf: Vec<u8> = vec![0; MTU];
+        loop {
+            if self
+                .stop
+                .as_ref()
+                .is_some_and(|stop| stop.load(Ordering::Relaxed))
+            {
+                return Err(ReceiveError::Cancelled);
+            }
+            match socket.recv_from(&mut buf) {
+                Ok((_, peer)) if rendezvous_server == Some(peer) && !self.relay_fallback => {
+                    debug!(%peer, "Dropping datagram relayed by the rendezvous server.");
+                }
+                // The peers a rendezvous server relays proved they know the code already.
+                Ok((_, peer))
+                    if rendezvous_server != Some(peer) && !self.access.admits(peer.ip()) =>
+                {
+                    debug!(%peer, "Dropping datagram from a sender we do not take.");
+                }
+                Ok((size, peer)) => match Message::parse(&buf[..size]) {
+                    Ok(Message::Probe) => self.answer_probe(socket, peer, size),
+                    Ok(Message::SpeedTest { .. }) if size < MTU => {
+                        debug!(%peer, "Ignoring undersized speed test request.");
+                    }
+                    Ok(Message::SpeedTest { bytes }) => self.answer_speedtest(socket, peer, bytes),
+                    Ok(Message::Chunks {
+                        first,
+                        token,
+                        digests,
+                    }) => {
+                        if self.authorizes(peer, token.as_deref(), rendezvous_server) {
+                            self.answer_chunks(socket, peer, first, &digests)
+                        } else {
+                            refuse_request(socket, peer, "chunks");
+                        }
+                    }
+                    Ok(Message::Signatures {
+                        filename,
+                        first,
+                        token,
+                    }) => {
+                        if self.authorizes(peer, token.as_deref(), rendezvous_server) {
+                            let path = batch
+                                .as_ref()
+                                .and_then(|batch| batch.target(&filename))
+                                .unwrap_or_else(|| self.target_path(&filename));
+                            self.answer_signatures(socket, peer, size, signature, &path, first);
+                        } else {
+                            refuse_request(socket, peer, "checksums");
+                        }
+                    }
+                    Ok(Message::Manifest {
+                        first,
+                        total,
+                        mirror,
+                        token,
+                        entries,
+                    }) => {
+                        let answer =
+                            match self.authorizes(peer, token.as_deref(), rendezvous_server) {
+                                true => {
+                                    self.take_manifest(peer, batch, first, total, mirror, entries)
+                                }
+                                false => {
+                                    warn!(%peer, "Refusing a batch without a valid token.");
+                                    Message::Abort {
+                                        reason: "not authorized".to_string(),
+                                    }
+                                }
+                            };
+                        if let Err(err) = socket.send_to(&answer.serialize(), peer) {
+                            warn!(%peer, error = ?err, "Could not answer the manifest.");
+                        }
+                        if self.once && batch.as_ref().is_some_and(Batch::is_complete) {
+                            info!("Already had every file of the batch.");
+                            return Ok(None);
+                        }
+                    }
+                    Ok(msg) => {
+                        // A sender still at a transfer we took before restarting.
+                        if matches!(msg, Message::Sync { .. } | Message::Part { .. }) {
+                            if let Some(saved) = self.take_resumable(peer) {
+                                state.resume(saved.offer.parts, saved.stored());
+                                socket.connect(peer);
+                                info!(%peer, offer = ?saved.offer, "Taking the transfer back.");
+                                return Ok(Some((socket.try_clone()?, saved.offer)));
+                            }
+                        }
+                        for action in state.on_message(msg) {
+                            match action {
+                                ReceiverAction::Start(offer)
+                                    if !self.authorizes(
+                                        peer,
+                                        offer.token.as_deref(),
+                                        rendezvous_server,
+                                    ) =>
+                                {
+                                    refuse_unauthorized(socket, state, peer);
+                                }
+                                ReceiverAction::Start(offer) => {
+                                    socket.connect(peer);
+                                    info!(%peer, ?offer, "Accepting transfer.");
+                                    return Ok(Some((socket.try_clone()?, offer)));
+                                }
+                                ReceiverAction::Unexpected(msg) => {
+                                    warn!(message = ?msg, "Received unexpected message.")
+                                }
+                                _ => {}
+                            }
+                        }
+                    }
+                    Err(err) => warn!(error = ?err, "Could not parse packet."),
+                },
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    let Some(stream) = tcp_streams.and_then(|streams| {
+                        streams
+                            .lock()
+                            .expect("Could not lock TCP streams")
+                            .try_recv()
+                            .ok()
+                    }) else {
+                        continue;
+                    };
+                    match accept_tcp(socket, state, &self.access, stream) {
+                        Ok(Some(session)) => {
+                            session.0.mark(self.dscp);
+                            return Ok(Some(session));
+                        }
+                        Ok(None) => warn!("TCP connection did not start a transfer we take."),
+                        Err(err) => warn!(error = ?err, "Could not accept TCP connection."),
+                    }
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    /// Whether `peer` may send, presenting `token`. The peers the `rendezvous_server`
+    /// relays proved they know the code already.
+    fn authorizes(
+        &self,
+        peer: SocketAddr,
+        token: Option<&str>,
+        rendezvous_server: Option<SocketAddr>,
+    ) -> bool {
+        rendezvous_server == Some(peer) || self.access.authorizes(peer.ip(), token)
+    }
+
+    /// The transfer `peer` was sending us before a restart, if we can take it back.
+    fn take_resumable(&self, peer: SocketAddr) -> Option<Saved> {
+        self.resumable
+            .lock()
+            .expect("Could not lock resumable transfers")
+            .remove(&peer)
+    }
+
+    /// Takes in the Manifest entries `first..` of a batch of `total` files, and returns the
+    /// answer with the ones we already have. A Manifest starting over announces a new batch,
+    /// which `mirror` can make mirror a directory of ours.
+    fn take_manifest(
+        &self,
+        peer: SocketAddr,
+        batch: &mut Option<Batch>,
+        first: u32,
+        total: u32,
+        mirror: Option<Mirror>,
+        entries: Vec<ManifestEntry>,
+    ) -> Message {
+        let announced = match batch {
+            Some(batch) if first > 0 && batch.total() == total => Ok(batch),
+            _ if self.output.as_ref().is_some_and(|output| !output.is_dir()) => Err(
+                std::io::Error::new(ErrorKind::InvalidInput, "cannot receive a batch in a file"),
+            ),
+            _ if self.sink.is_some() => Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "cannot receive a batch in a sink",
+            )),
+            _ if mirror.as_ref().is_some_and(|mirror| mirror.delete) && !self.allow_delete => {
+                Err(std::io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    "the receiver does not remove files, see --allow-delete",
+                ))
+            }
+            _ => {
+                info!(%peer, files = total, ?mirror, "Sender announced a batch.");
+                let output = self.output_dir().to_path_buf();
+                match mirror {
+                    Some(mirror) => Batch::mirror(output, total, &mirror),
+                    None => Ok(Batch::new(output, total)),
+                }
+                .map(|announced| batch.insert(announced))
+            }
+        };
+        match announced.and_then(|batch| batch.announce(first, entries)) {
+            Ok(ids) => Message::Have { first, ids },
+            Err(err) => {
+                warn!(%peer, error = %err, "Refusing the batch.");
+                *batch = None;
+                Message::Abort {
+                    reason: format!("could not take the batch: {err}"),
+                }
+            }
+        }
+    }
+
+    /// Tells whoever probed the LAN that we are listening, as long as the probe was at
+    /// least as large as our answer.
+    fn answer_probe(&self, socket: &Socket, from: SocketAddr, size: usize) {
+        let answer = Message::Presence {
+            hostname: probe::hostname(),
+            free_bytes: probe::free_space(self.output_dir()),
+        }
+        .serialize();
+        if answer.len() > size {
+            debug!(%from, "Ignoring undersized probe.");
+            return;
+        }
+        if let Err(err) = socket.send_to(&answer, from) {
+            warn!(%from, error = ?err, "Could not answer probe.");
+        }
+    }
 
-#[derive(Debug)]
-struct Sync {
-    ack: Vec<u32>,
-    loss: Vec<u32>,
+    /// Sends `bytes` of synthetic data to the host asking for them, from a port and a
+    /// thread of their own, or tells it we do not take speed tests.
+    fn answer_speedtest(&self, socket: &Socket, from: SocketAddr, bytes: u64) {
+        let refuse = |reason: String| {
+            if let Err(err) = socket.send_to(&Message::Abort { reason }.serialize(), from) {
+                warn!(%from, error = ?err, "Could not refuse the speed test.");
+            }
+        };
+        if !self.speedtest {
+            return refuse("the receiver does not take speed tests".to_string());
+        }
+        let bytes = {
+            let mut speedtests = self.speedtests.lock().expect("Could not lock speed tests");
+            speedtests.retain(|_, served| {
+                served.sending.is_some() || served.window.elapsed() < SPEEDTEST_WINDOW
+            });
+            let served = speedtests.entry(from.ip()).or_insert_with(|| Served {
+                sending: None,
+                ended: None,
+                window: Instant::now(),
+                bytes: 0,
+            });
+            if served.sending.is_some()
+                || served
+                    .ended
+                    .is_some_and(|(to, at)| to == from && at.elapsed() < REPEATED_SPEEDTEST)
+            {
+                return;
+            }
+            if served.window.elapsed() >= SPEEDTEST_WINDOW {
+                served.window = Instant::now();
+                served.bytes = 0;
+            }
+            let bytes = bytes
+                .min(crate::speedtest::MAX_ROUND)
+                .min(SPEEDTEST_BUDGET - served.bytes);
+            if bytes > 0 {
+                served.bytes += bytes;
+                served.sending = Some(from);
+            }
+            bytes
+        };
+        if bytes == 0 {
+            return refuse(format!(
+                "{} had its {SPEEDTEST_BUDGET} bytes of speed tests, try again later",
+                from.ip()
+            ));
+        }
+        let transport = match socket.transport().bind_another() {
+            Ok(transport) => transport,
+            Err(err) => {
+                end_speedtest(&self.speedtests, from);
+                warn!(%from, error = %err, "Could not bind a socket for the speed test.");
+                return refuse(format!("could not bind a socket for the speed test: {err}"));
+            }
+        };
+        info!(%from, bytes, "Sending synthetic data for a speed test.");
+        let sender = crate::Sender::new(from.to_string())
+            .transport(transport)
+            .connect_timeout(self.idle_timeout);
+        let speedtests = self.speedtests.clone();
+        spawn(move || {
+            match sender.send_synthetic(bytes) {
+                Ok(()) => info!(%from, "Speed test sent."),
+                Err(err) => warn!(%from, error = %err, "Could not send the speed test."),
+            }
+            end_speedtest(&speedtests, from);
+        });
+    }
+
+    /// Answers a Chunks with the indices of the chunks our cache has.
+    fn answer_chunks(&self, socket: &Socket, from: SocketAddr, first: u32, digests: &[[u8; 32]]) {
+        let ids = (first..)
+            .zip(digests)
+            .filter(|(_, digest)| {
+                self.chunk_cache
+                    .as_ref()
+                    .is_some_and(|cache| cache.contains(digest))
+            })
+            .map(|(id, _)| id)
+            .collect();
+        let answer = Message::Have { first, ids };
+        if let Err(err) = socket.send_to(&answer.serialize(), from) {
+            warn!(%from, error = ?err, "Could not answer the chunks.");
+        }
+    }
+
+    /// Answers a Signatures with the checksums of our copy at `path` from block `first` on,
+    /// as long as the request was at least as large as our answer.
+    fn answer_signatures(
+        &self,
+        socket: &Socket,
+        from: SocketAddr,
+        size: usize,
+        signature: &mut Option<Signature>,
+        path: &Path,
+        first: u32,
+    ) {
+        if !signature
+            .as_ref()
+            .is_some_and(|signature| signature.is_current(path))
+        {
+            debug!(path = %path.display(), "Checksumming our copy.");
+            *signature = match Signature::of(path) {
+                Ok(checksummed) => checksummed,
+                Err(err) => {
+                    warn!(path = %path.display(), error = %err, "Could not checksum our copy.");
+                    None
+                }
+            };
+        }
+        let answer = match signature {
+            Some(signature) => signature.blocks(first),
+            None => Message::Blocks {
+                first: 0,
+                total: 0,
+                block_size: 0,
+                sums: Vec::new(),
+            },
+        }
+        .serialize();
+        if answer.len() > size {
+            debug!(%from, "Ignoring undersized signatures request.");
+            return;
+        }
+        if let Err(err) = socket.send_to(&answer, from) {
+            warn!(%from, error = ?err, "Could not answer the signatures request.");
+        }
+    }
+
+    /// Creates the output file, sized for `len` bytes. Without the size of the transfer,
+    /// that is a full last part, which the writer trims once it has it. A `range` opens the
+    /// file as it is instead.
+    fn create(&self, path: &Path, len: u64, range: bool) -> std::io::Result<File> {
+        if range {
+            return OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path);
+        }
+        let file = File::create(path)?;
+        if self.sparse {
+            file.set_len(len)?;
+        } else {
+            preallocate(&file, len)?;
+        }
+        Ok(file)
+    }
+
+    /// Moves a received file to `target` once the scanner, if any, cleared it.
+    fn publish(&self, path: &Path, target: &Path) -> Result<(), ReceiveError> {
+        self.screen(path, target)?;
+        if path != target {
+            std::fs::rename(path, target)?;
+        }
+        Ok(())
+    }
+
+    /// Publishes a file of the `batch`, once it checked it is the one the manifest announced.
+    /// Its `digest` is computed here unless it was as it came.
+    fn publish_member(
+        &self,
+        batch: &mut Batch,
+        filename: &str,
+        path: &Path,
+        target: &Path,
+        digest: Option<[u8; 32]>,
+    ) -> Result<(), ReceiveError> {
+        let digest = match digest {
+            Some(digest) => digest,
+            None => file_digest(path)?,
+        };
+        if batch.digest(filename) != Some(digest) {
+            if let Err(err) = std::fs::remove_file(path) {
+                warn!(path = %path.display(), error = ?err, "Could not remove the mismatched file.");
+            }
+            return Err(ReceiveError::Mismatch(filename.to_string()));
+        }
+        self.publish(path, target)?;
+        batch.received(filename, target)?;
+        if batch.is_complete() {
+            info!(files = batch.total(), "Batch complete.");
+        }
+        Ok(())
+    }
+
+    /// Publishes a whole group, or none of it if the scanner rejects any member.
+    fn publish_group(&self, group: StagedGroup) -> Result<(), ReceiveError> {
+        for (staged, target) in group.members.values() {
+            if let Err(err) = self.screen(staged, target) {
+                group.discard(false);
+                return Err(err);
+            }
+        }
+        Ok(group.publish()?)
+    }
+
+    /// Runs the scanner on `path`, and quarantines it unless it is clean. A scanner that
+    /// cannot give an answer rejects the file too.
+    fn screen(&self, path: &Path, target: &Path) -> Result<(), ReceiveError> {
+        let Some(scanner) = &self.scanner else {
+            return Ok(());
+        };
+        let name = target.display().to_string();
+        let err = match scanner.scan(path) {
+            Ok(Verdict::Clean) => {
+                info!(path = name, "Scan clean.");
+                return Ok(());
+            }
+            Ok(Verdict::Infected(found)) => {
+                warn!(path = name, found, "Scanner rejected the file.");
+                ReceiveError::Rejected(name, found)
+            }
+            Err(err) => {
+                warn!(path = name, error = %err, "Could not scan the file.");
+                err.into()
+            }
+        };
+        self.reject(path, target);
+        Err(err)
+    }
+
+    fn reject(&self, path: &Path, target: &Path) {
+        let result = match &self.quarantine {
+            Some(dir) => std::fs::create_dir_all(dir).and_then(|_| {
+                std::fs::rename(path, dir.join(target.file_name().unwrap_or_default()))
+            }),
+            None => std::fs::remove_file(path),
+        };
+        if let Err(err) = result {
+            error!(path = %path.display(), error = ?err, "Could not dispose of the rejected file.");
+        }
+    }
+
+    /// What we tell senders in our Accept, once the file was sized.
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            max_part_size: PART_SIZE as u32,
+            max_in_flight: self.max_in_flight,
+            compression: Vec::new(),
+            encryption: if cfg!(feature = "crypto") {
+                vec![crypto::CIPHER.to_string()]
+            } else {
+                Vec::new()
+            },
+            free_bytes: probe::free_space(self.output_dir()),
+        }
+    }
+
+    /// Directory the received files end up in.
+    fn output_dir(&self) -> &Path {
+        match &self.output {
+            Some(output) if output.is_dir() => output.as_path(),
+            Some(output) => output
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+                .unwrap_or(Path::new(".")),
+            None => Path::new("."),
+        }
+    }
+
+    fn target_path(&self, filename: &str) -> PathBuf {
+        let name = self.member_name(filename);
+        paths::long(match &self.output {
+            Some(output) if output.is_dir() => output.join(name),
+            Some(output) => output.clone(),
+            None => name,
+        })
+    }
+
+    fn member_name(&self, filename: &str) -> PathBuf {
+        // Never let the sender pick a directory for us.
+        Path::new(filename)
+            .file_name()
+            .map(|name| PathBuf::from(&*paths::local_name(&name.to_string_lossy())))
+            .unwrap_or_else(|| PathBuf::from("received"))
+    }
+
+    /// Staging directory of a group, hidden in the directory the files end up in.
+    fn group_dir(&self, name: &str) -> std::io::Result<PathBuf> {
+        let name = Path::new(name)
+            .file_name()
+            .map(|name| paths::local_name(&name.to_string_lossy()).into_owned())
+            .unwrap_or_else(|| "group".to_string());
+        let base = match &self.output {
+            Some(output) if output.is_dir() => output.clone(),
+            Some(output) => {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "{} is not a directory, cannot receive a group",
+                        output.display()
+                    ),
+                ))
+            }
+            None => PathBuf::from("."),
+        };
+        Ok(base.join(format!(".sanic-group-{name}")))
+    }
 }
 
-impl Sync {
-    fn new(ack: Vec<u32>, loss: Vec<u32>) -> Self {
-        Sync { ack, loss }
+/// Accepts TCP fallback connections on the same address as the UDP socket.
+fn listen_tcp(addr: SocketAddr) -> std::io::Result<mpsc::Receiver<TcpStream>> {
+    let listener = TcpListener::bind(addr)?;
+    let (stream_tx, stream_rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if stream_tx.send(stream).is_err() {
+                        break;
+                    }
+                }
+                Err(err) => warn!(error = ?err, "Could not accept TCP connection."),
+            }
+        }
+    });
+    Ok(stream_rx)
+}
+
+fn accept_tcp(
+    socket: &Socket,
+    state: &mut ReceiverState,
+    access: &Access,
+    stream: TcpStream,
+) -> std::io::Result<Option<(Socket, Offer)>> {
+    let transport = TcpTransport::new(stream)?;
+    let peer = transport.peer_addr();
+    if !access.admits(peer.ip()) {
+        debug!(%peer, "Closing TCP connection from a sender we do not take.");
+        return Ok(None);
+    }
+    let session = socket.with_transport(Arc::new(transport));
+    session.connect(peer);
+    session.set_read_timeout(Some(TCP_HANDSHAKE_TIMEOUT))?;
+    let mut buf: Vec<u8> = vec![0; MTU];
+    let size = session.recv(&mut buf)?;
+    let Ok(msg) = Message::parse(&buf[..size]) else {
+        return Ok(None);
+    };
+    for action in state.on_message(msg) {
+        if let ReceiverAction::Start(offer) = action {
+            if !access.authorizes(peer.ip(), offer.token.as_deref()) {
+                refuse_unauthorized(&session, state, peer);
+                return Ok(None);
+            }
+            info!(%peer, ?offer, "Accepting transfer over TCP.");
+            return Ok(Some((session, offer)));
+        }
     }
+    Ok(None)
 }
 
-fn handle_file_write(file: File, nb_parts: u32, file_chan: Receiver<(u32, Vec<u8>)>) {
-    let mut file = file;
-    let mut part_buffer: Vec<(u32, Vec<u8>)> = Vec::with_capacity(100);
-    let mut parts_received = 0;
+/// Tells the senders no lane was free for to come back later.
+fn turn_away(socket: &Socket) {
+    let mut buf = vec![0; MTU];
     loop {
-        match file_chan.recv() {
-            Ok(part) => {
-                part_buffer.push(part);
-                parts_received += 1;
-                // Our buffer is full or if we received all the parts, we write to the file
-                if part_buffer.len() == part_buffer.capacity() || parts_received == nb_parts {
-                    part_buffer.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
-                    let mut cursor: u64 = (part_buffer[0].0 as usize * PART_SIZE) as u64;
-                    let mut slab: Vec<u8> = Vec::with_capacity(BUF_CAPACITY);
-                    for i in 0..(part_buffer.len() - 1) {
-                        // if the slab is full, we write and empty it
-                        if slab.len() == slab.capacity() {
-                            file.seek(SeekFrom::Start(cursor))
-                                .expect("Could not seek in file");
-                            file.write_all(&slab).expect("Could not push slab to file");
-                            cursor += slab.len() as u64;
-                            slab.clear();
-                        }
-
-                        // If the part are continuous, we push to the slab
-                        if part_buffer[i].0 + 1 == part_buffer[i + 1].0 {
-                            slab.extend(&part_buffer[i].1);
-                        } else {
-                            // If the part are not continuous, we commit the current slab and start a new one
-                            file.seek(SeekFrom::Start(cursor))
-                                .expect("Could not seek in file");
-                            file.write_all(&slab).expect("Could not push slab to file");
-                            cursor += slab.len() as u64;
-                            slab.clear();
-                            slab.extend(&part_buffer[i].1);
-                        }
+        match socket.recv_from(&mut buf) {
+            Ok((size, peer)) => {
+                if let Ok(Message::Send { .. }) = Message::parse(&buf[..size]) {
+                    warn!(%peer, "Turning a sender away, every session is taken.");
+                    let reason = "busy with other transfers, try again later".to_string();
+                    if let Err(err) = socket.send_to(&Message::Abort { reason }.serialize(), peer) {
+                        warn!(%peer, error = ?err, "Could not turn the sender away.");
                     }
                 }
+            }
+            Err(err) if err.kind() == ErrorKind::NotConnected => return,
+            Err(err) => warn!(error = ?err, "Could not receive from the senders turned away."),
+        }
+    }
+}
 
-                if parts_received == nb_parts {
-                    info!("Finished writing the file.");
-                    break;
+/// Turns down the Send of `peer`, which did not present a token we take, and waits for
+/// the next sender.
+fn refuse_unauthorized(socket: &Socket, state: &mut ReceiverState, peer: SocketAddr) {
+    warn!(%peer, "Refusing a sender without a valid token.");
+    state.reset();
+    let reason = "not authorized".to_string();
+    if let Err(err) = socket.send_to(&Message::Abort { reason }.serialize(), peer) {
+        warn!(%peer, error = ?err, "Could not tell the sender it is not authorized.");
+    }
+}
+
+/// Records that the speed test sent to `to` ended.
+fn end_speedtest(speedtests: &SpeedTests, to: SocketAddr) {
+    let mut speedtests = speedtests.lock().expect("Could not lock speed tests");
+    if let Some(served) = speedtests.get_mut(&to.ip()) {
+        served.sending = None;
+        served.ended = Some((to, Instant::now()));
+    }
+}
+
+/// Turns down the request for `what` of `peer`, which did not present a token we take.
+fn refuse_request(socket: &Socket, peer: SocketAddr, what: &str) {
+    warn!(%peer, what, "Refusing a request without a valid token.");
+    let reason = "not authorized".to_string();
+    if let Err(err) = socket.send_to(&Message::Abort { reason }.serialize(), peer) {
+        warn!(%peer, error = ?err, "Could not refuse the request.");
+    }
+}
+
+/// Reserves `len` bytes on disk for `file`, so running out of space shows up now rather
+/// than in the middle of the transfer.
+#[cfg(all(target_os = "linux", feature = "fallocate"))]
+fn preallocate(file: &File, len: u64) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    if len == 0 {
+        return Ok(());
+    }
+    // SAFETY: plain syscall on a file descriptor we own.
+    let result = unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, len as libc::off_t) };
+    match result {
+        0 => Ok(()),
+        _ => match std::io::Error::last_os_error() {
+            // Not every filesystem can, sizing the file is the next best thing.
+            err if err.raw_os_error() == Some(libc::EOPNOTSUPP) => file.set_len(len),
+            err => Err(err),
+        },
+    }
+}
+
+/// Sizes `file` to `len` bytes, without reserving them on disk.
+#[cfg(not(all(target_os = "linux", feature = "fallocate")))]
+fn preallocate(file: &File, len: u64) -> std::io::Result<()> {
+    file.set_len(len)
+}
+
+/// Replaces the delta received at `path` with the file it rebuilds from our copy `basis`
+/// and our chunk `cache`.
+fn patch(basis: Option<&Path>, cache: Option<&ChunkCache>, path: &Path) -> std::io::Result<()> {
+    let rebuilt = partial_path(path);
+    let result = File::create(&rebuilt).and_then(|out| {
+        let delta = File::open(path)?;
+        match basis {
+            Some(basis) => delta::apply(File::open(basis)?, cache, delta, out)?,
+            None => delta::apply(std::io::empty(), cache, delta, out)?,
+        }
+        std::fs::rename(&rebuilt, path)
+    });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&rebuilt);
+    }
+    result
+}
+
+/// Where the synthetic data of speed tests is written.
+fn null_device() -> std::io::Result<File> {
+    let path = match cfg!(windows) {
+        true => "NUL",
+        false => "/dev/null",
+    };
+    OpenOptions::new().write(true).open(path)
+}
+
+/// Where a file is written until it is complete: next to `target`, with a suffix.
+/// Removes the resume state at `path`, once there is nothing to take back.
+fn forget(path: &Path) {
+    match std::fs::remove_file(path) {
+        // Nothing was saved yet.
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => {
+            warn!(path = %path.display(), error = ?err, "Could not remove the resume state.")
+        }
+        Ok(()) => {}
+    }
+}
+
+fn partial_path(target: &Path) -> PathBuf {
+    let name = target.file_name().unwrap_or_default().to_string_lossy();
+    target.with_file_name(format!("{name}.sanic-partial"))
+}
+
+/// Writes the parts of the transfer into `file`, from `offset` on for a range. Hands the
+/// file back once every part was written, None when the reader gave up first.
+fn handle_file_write(
+    mut parts: Reassembler<File>,
+    range: bool,
+    size: Option<u64>,
+    file_chan: mpsc::Receiver<(u32, Vec<u8>)>,
+    metrics: Metrics,
+    mut saved: Option<Checkpoint>,
+    hashed: Option<mpsc::Sender<u64>>,
+) -> std::io::Result<Option<File>> {
+    let nb_parts = parts.nb_parts();
+    // Parts below were handed to the hashing, the ones kept from before a restart first.
+    let mut reported = 0;
+    let mut report = |parts: &Reassembler<File>| {
+        let below = parts.written_below();
+        if below > reported {
+            reported = below;
+            if let Some(hashed) = &hashed {
+                // It stops on errors, which finishing it tells.
+                let _ = hashed.send(u64::from(below) * PART_SIZE as u64);
+            }
+        }
+    };
+    report(&parts);
+    while !parts.is_complete() {
+        match file_chan.recv() {
+            Ok((id, mut data)) => {
+                match size {
+                    Some(size) => fit(id, &mut data, size)?,
+                    // Only the last part can be short, and the file was sized for a full one.
+                    None if id == nb_parts - 1 && !range => {
+                        let len = id as u64 * PART_SIZE as u64 + data.len() as u64;
+                        parts.get_ref().set_len(len)?;
+                    }
+                    None => {}
+                }
+                metrics.received(data.len() as u64);
+                parts.push(id, data)?;
+                report(&parts);
+                if let Some(saved) = saved.as_mut().filter(|saved| saved.due()) {
+                    // Only what was written out survives us.
+                    parts.flush()?;
+                    checkpoint(saved, &parts);
                 }
             }
-            Err(err) => {
-                error!(error = ?err, "Could not receive part.");
-                panic!()
+            // The reader gave up on the session, keep what we already have.
+            Err(_) => {
+                warn!(
+                    parts_received = parts.parts_taken(),
+                    nb_parts, "Transfer aborted before all parts were received."
+                );
+                parts.flush()?;
+                if let Some(saved) = &mut saved {
+                    checkpoint(saved, &parts);
+                }
+                return Ok(None);
             }
         }
     }
+    info!("Finished writing the file.");
+    Ok(Some(parts.into_inner()))
 }
 
-fn handle_client_sync(socket: UdpSocket, sync_chan: Receiver<Sync>) {
-    loop {
-        match sync_chan.recv() {
-            Ok(sync) => {
-                if sync.ack.len() > 0 {
-                    socket
-                        .send(&Message::Ack { ids: sync.ack }.serialize())
-                        .expect("Could not send ACK");
-                }
-                if sync.loss.len() > 0 {
-                    socket
-                        .send(&Message::Loss { ids: sync.loss }.serialize())
-                        .expect("Could not send LOSS");
+/// Puts the parts from `file_chan` in the sink of `parts`, and finishes it once they are
+/// all there. Returns whether they were, the sink being aborted otherwise.
+fn handle_sink_write(
+    mut parts: Reassembler<Ordered>,
+    size: Option<u64>,
+    file_chan: mpsc::Receiver<(u32, Vec<u8>)>,
+    metrics: Metrics,
+) -> std::io::Result<bool> {
+    let nb_parts = parts.nb_parts();
+    let mut len = size.unwrap_or(0);
+    let mut fill = || {
+        while !parts.is_complete() {
+            let Ok((id, mut data)) = file_chan.recv() else {
+                warn!(
+                    parts_received = parts.parts_taken(),
+                    nb_parts, "Transfer aborted before all parts were received."
+                );
+                return Ok(false);
+            };
+            match size {
+                Some(size) => fit(id, &mut data, size)?,
+                // Only the last part can be short.
+                None if id == nb_parts - 1 => {
+                    len = u64::from(id) * PART_SIZE as u64 + data.len() as u64;
                 }
+                None => {}
+            }
+            metrics.received(data.len() as u64);
+            parts.push(id, data)?;
+        }
+        Ok(true)
+    };
+    let filled = fill();
+    let mut sink = parts.into_inner();
+    match filled {
+        Ok(true) => {
+            if let Err(err) = sink.finish(len) {
+                sink.abort();
+                return Err(err);
+            }
+            info!("Finished writing to the sink.");
+            Ok(true)
+        }
+        filled => {
+            sink.abort();
+            filled
+        }
+    }
+}
+
+/// Cuts `data`, part `id` of a transfer of `size` bytes, to what it holds: nothing past
+/// the end of the transfer is written.
+fn fit(id: u32, data: &mut Vec<u8>, size: u64) -> std::io::Result<()> {
+    let offset = u64::from(id) * PART_SIZE as u64;
+    let holds = size.saturating_sub(offset).min(PART_SIZE as u64) as usize;
+    if data.len() < holds {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("part {id} holds {} bytes, {holds} expected", data.len()),
+        ));
+    }
+    data.truncate(holds);
+    Ok(())
+}
+
+/// Saves which parts `parts` wrote, for a restart to take the transfer back.
+fn checkpoint(saved: &mut Checkpoint, parts: &Reassembler<File>) {
+    if let Err(err) = saved.save(parts.taken()) {
+        warn!(error = ?err, "Could not save the resume state.");
+    }
+}
+
+/// Aborts the session once the sender has been silent for `idle_timeout`, once it has
+/// been running for longer than `max_duration`, or once the operator cancelled it, there
+/// or by setting `stop`.
+fn handle_reaper(
+    watchdog: Arc<Watchdog>,
+    idle_timeout: Duration,
+    max_duration: Option<Duration>,
+    listed: Option<Arc<Session>>,
+    stop: Option<Arc<AtomicBool>>,
+) {
+    while !watchdog.finished.load(Ordering::Relaxed) {
+        std::thread::sleep(REAPER_TICK);
+        let expiry = if listed.as_ref().is_some_and(|listed| listed.is_cancelled())
+            || stop
+                .as_ref()
+                .is_some_and(|stop| stop.load(Ordering::Relaxed))
+        {
+            Expiry::Cancelled
+        } else if watchdog.idle() >= idle_timeout {
+            Expiry::Inactive
+        } else if max_duration.is_some_and(|max| watchdog.started.elapsed() >= max) {
+            Expiry::TooLong
+        } else {
+            continue;
+        };
+        warn!(?expiry, elapsed = ?watchdog.started.elapsed(), "Reaping stale session.");
+        watchdog.expire(expiry);
+        return;
+    }
+}
+
+fn handle_client_sync(
+    socket: Socket,
+    sync_chan: mpsc::Receiver<Sync>,
+    share: Share,
+    metrics: Metrics,
+    tune: bool,
+    listed: Option<Arc<Session>>,
+) -> std::io::Result<()> {
+    // Once a cap was announced, it is repeated with every answer so a lost RateLimit, or
+    // lifting the cap, still reaches the sender. Pauses too.
+    let mut announced = false;
+    let mut paused_once = false;
+    let mut lost = 0;
+    let mut tuner = if tune {
+        BufferTuner::new(&socket)
+    } else {
+        None
+    };
+    // The reader drops its end of the channel once the transfer is over.
+    while let Ok(sync) = sync_chan.recv() {
+        if let Some(growing) = &mut tuner {
+            if !growing.arrived(&socket, sync.arrived) {
+                tuner = None;
+            }
+        }
+        // Every Sync gets an answer, even if it acknowledges nothing.
+        if !sync.ack.is_empty() || sync.loss.is_empty() {
+            socket.send(&Message::Ack { ids: sync.ack }.serialize())?;
+        }
+        if !sync.loss.is_empty() {
+            metrics.lost(sync.loss.len() as u64);
+            lost += sync.loss.len() as u64;
+            if let Some(listed) = &listed {
+                listed.lost(lost);
+            }
+            socket.send(&Message::Loss { ids: sync.loss }.serialize())?;
+        }
+        let cap = share.get();
+        if cap.is_some() || announced {
+            announced = true;
+            let bytes_per_sec = cap.unwrap_or(0);
+            socket.send(&Message::RateLimit { bytes_per_sec }.serialize())?;
+        }
+        let paused = listed.as_ref().is_some_and(|listed| listed.is_paused());
+        if paused || paused_once {
+            paused_once = true;
+            socket.send(&Message::Pause { paused }.serialize())?;
+        }
+    }
+    Ok(())
+}
+
+/// Grows the receive buffer of the socket with the rate parts arrive at, so it holds what
+/// comes in over a Sync interval. The sender only hears about losses once per interval, and
+/// whatever the kernel dropped in the meantime has to be sent again.
+struct BufferTuner {
+    size: usize,
+    since: Instant,
+    bytes: u64,
+}
+
+impl BufferTuner {
+    /// None when the transport has no kernel buffer to grow.
+    fn new(socket: &Socket) -> Option<Self> {
+        let size = socket.buffer(Buffer::Recv).ok()?;
+        Some(BufferTuner {
+            size,
+            since: Instant::now(),
+            bytes: 0,
+        })
+    }
+
+    /// `parts` more arrived. Returns false once the kernel stopped granting more, as asking
+    /// again would not get any.
+    fn arrived(&mut self, socket: &Socket, parts: usize) -> bool {
+        self.bytes += (parts * PART_SIZE) as u64;
+        let elapsed = self.since.elapsed();
+        if elapsed < TUNE_INTERVAL {
+            return true;
+        }
+        let wanted = wanted_buffer(self.bytes, elapsed);
+        self.since = Instant::now();
+        self.bytes = 0;
+        if wanted <= self.size {
+            return true;
+        }
+        match socket.set_buffer(Buffer::Recv, wanted) {
+            Ok(granted) if granted >= wanted => {
+                debug!(bytes = granted, "Grew the receive buffer.");
+                self.size = granted;
+                true
+            }
+            Ok(granted) => {
+                info!(
+                    bytes = granted,
+                    wanted, "The kernel capped the receive buffer, see net.core.rmem_max."
+                );
+                false
             }
             Err(err) => {
-                error!(error = ?err, "Failed to received from sync channel.");
+                warn!(error = ?err, "Could not grow the receive buffer.");
+                false
             }
         }
     }
 }
 
+/// Receive buffer holding a Sync interval of parts, when `bytes` arrived over `elapsed`.
+fn wanted_buffer(bytes: u64, elapsed: Duration) -> usize {
+    let rate = bytes as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+    ((rate * SYNC_INTERVAL.as_secs_f64()) as usize).min(MAX_TUNED_BUFFER)
+}
+
+/// Where the reader hands what it received: parts to the writer, Syncs to answer to the
+/// sync thread. Dropping them tells both threads the transfer is over.
+struct ReaderOutputs {
+    file: mpsc::Sender<(u32, Vec<u8>)>,
+    sync: mpsc::Sender<Sync>,
+}
+
+fn finished(state: &ReceiverState) {
+    match state.root() {
+        Some(root) => {
+            let root = merkle::hex(&root);
+            info!(root, "Transfer finished and verified!");
+        }
+        None => info!("Transfer finished!"),
+    }
+}
+
 fn handle_client_read(
-    socket: UdpSocket,
-    nb_parts: u32,
-    parts_received: Arc<Mutex<Vec<u32>>>,
-    file_chan: Sender<(u32, Vec<u8>)>,
-    sync_chan: Sender<Sync>,
-) {
-    let mut buf: Vec<u8> = vec![0; MTU];
+    socket: Socket,
+    state: &mut ReceiverState,
+    outputs: ReaderOutputs,
+    watchdog: Arc<Watchdog>,
+    progress: Option<ProgressCallback>,
+    quota: Option<Quota>,
+    pool: BufferPool,
+) -> std::io::Result<Option<Offer>> {
+    let mut bufs: Vec<Vec<u8>> = vec![vec![0; MTU]; MAX_BATCH];
+    socket.set_read_timeout(Some(REAPER_TICK))?;
+    let mut arrived = 0;
     loop {
-        match socket.recv(&mut buf) {
-            Ok(size) => {
-                let data = &buf[..size];
-                match Message::parse(data) {
-                    Ok(Message::Part { id, data }) => {
-                        file_chan
-                            .send((id, data))
-                            .expect("Could not send chunk to writer.");
-                        {
-                            let mut _guard =
-                                parts_received.lock().expect("Could not lock ack array");
-                            _guard.push(id);
-                            _guard.sort();
-                            if _guard.len() as u32 >= nb_parts {
-                                info!("Transfer finished!");
-                                break;
-                            }
+        match socket.recv_batch(&mut bufs) {
+            Ok(sizes) => {
+                for (buf, size) in bufs.iter().zip(sizes) {
+                    let data = &buf[..size];
+                    let msg = match Message::parse_with(data, || pool.get()) {
+                        Ok(msg) => msg,
+                        Err(err) => {
+                            warn!(error = ?err, "Could not parse packet.");
+                            continue;
                         }
+                    };
+                    let actions = state.on_message(msg);
+                    // Requests for another transfer must not keep this one lingering.
+                    if actions.is_empty()
+                        || actions
+                            .iter()
+                            .any(|action| !matches!(action, ReceiverAction::Unexpected(_)))
+                    {
+                        watchdog.touch();
                     }
-                    Ok(Message::Sync { ids }) => {
-                        let mut ack: Vec<u32> = Vec::new();
-                        let mut loss: Vec<u32> = Vec::new();
-                        // We check if we received those part and if not we consider them lost.
-                        // Note: Due to the lack of ordering garanties in UDP, we might get an id in SYNC that we are going to receive right after
-                        for id in ids {
-                            if parts_received
-                                .lock()
-                                .expect("Could not lock ack array")
-                                .binary_search(&id)
-                                .is_err()
-                            {
-                                warn!("Detected packet loss.");
-                                loss.push(id);
-                            } else {
-                                ack.push(id);
+                    for action in actions {
+                        match action {
+                            ReceiverAction::Write { id, data } => {
+                                arrived += 1;
+                                if let Some(quota) = &quota {
+                                    if !quota.consume(data.len() as u64) {
+                                        let reason = quota.reason();
+                                        warn!(reason, "Aborting the transfer.");
+                                        if let Err(err) =
+                                            socket.send(&Message::Abort { reason }.serialize())
+                                        {
+                                            warn!(error = ?err, "Could not tell the sender about the abort.");
+                                        }
+                                        watchdog.expire(Expiry::Quota);
+                                        return Ok(None);
+                                    }
+                                }
+                                // The writer only hangs up when it failed, and says why.
+                                if outputs.file.send((id, data)).is_err() {
+                                    return Ok(None);
+                                }
+                            }
+                            ReceiverAction::Progress(update) => {
+                                if let Some(progress) = &progress {
+                                    progress(update);
+                                }
+                            }
+                            ReceiverAction::Corrupt(block) => {
+                                warn!(block, "Block failed verification, asking for it again.");
+                            }
+                            ReceiverAction::Complete => finished(state),
+                            ReceiverAction::Resync(messages) => {
+                                debug!("Telling the sender what we have since the restart.");
+                                for message in messages {
+                                    socket.send(&message.serialize())?;
+                                }
+                            }
+                            ReceiverAction::Sync { ack, loss } => {
+                                if !loss.is_empty() {
+                                    warn!(parts = loss.len(), "Detected packet loss.");
+                                }
+                                let sync = Sync::new(ack, loss, std::mem::take(&mut arrived));
+                                if outputs.sync.send(sync).is_err() {
+                                    return Ok(None);
+                                }
+                            }
+                            ReceiverAction::Accept { capabilities } => {
+                                socket.send(&Message::Accept { capabilities }.serialize())?;
+                            }
+                            ReceiverAction::Next(offer) => {
+                                info!(?offer, "Accepting next transfer.");
+                                return Ok(Some(offer));
+                            }
+                            ReceiverAction::Aborted(reason) => {
+                                warn!(reason, "Sender aborted the transfer.");
+                                watchdog.expire(Expiry::Aborted(reason));
+                                return Ok(None);
+                            }
+                            ReceiverAction::Start(_) => {}
+                            ReceiverAction::Unexpected(msg) => {
+                                warn!(message = ?msg, "Received unexpected message.");
                             }
                         }
-                        sync_chan
-                            .send(Sync::new(ack, loss))
-                            .expect("Could not trigger sync.");
-                    }
-                    Ok(msg) => {
-                        warn!(message = ?msg, "Received unexpected message.");
                     }
-                    Err(err) => {
-                        warn!(error = ?err, "Could not parse packet.");
+                }
+                // Reaped or cancelled while the sender keeps sending.
+                if watchdog.expired().is_some() {
+                    break;
+                }
+            }
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::ConnectionRefused
+                ) =>
+            {
+                if watchdog.expired().is_some() {
+                    break;
+                }
+                // Once we have everything, stop when the sender went quiet or is already gone.
+                if state.phase() == Phase::Finishing
+                    && (err.kind() == ErrorKind::ConnectionRefused || watchdog.idle() >= LINGER)
+                {
+                    break;
+                }
+            }
+            // A TCP fallback connection was closed.
+            Err(err) if err.kind() == ErrorKind::ConnectionReset => {
+                if state.phase() != Phase::Finishing {
+                    watchdog.expire(Expiry::Disconnected);
+                }
+                break;
+            }
+            Err(err) => return Err(err),
+        }
+        // Complete once the writer caught up too.
+        if watchdog.stored.load(Ordering::Acquire) && state.stored().is_some() {
+            finished(state);
+        }
+        // Acknowledges what arrived every few parts or milliseconds, rather than only when
+        // the sender asks.
+        if let Some(report) = state.report(Instant::now()) {
+            socket.send(&report.serialize())?;
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sink::{Memory, Process},
+        testing::{Link, TempDir},
+        transport::MemoryTransport,
+    };
+
+    #[test]
+    fn receive_buffers_hold_a_sync_interval() {
+        // 10MB/s for 200ms.
+        assert_eq!(wanted_buffer(20_000_000, Duration::from_secs(2)), 2_000_000);
+        assert_eq!(wanted_buffer(0, Duration::from_secs(1)), 0);
+        assert_eq!(
+            wanted_buffer(u64::MAX, Duration::from_secs(1)),
+            MAX_TUNED_BUFFER
+        );
+    }
+
+    #[test]
+    fn transfers_larger_than_the_disk_are_refused() {
+        let dir = TempDir::new("no-space");
+        let link = Link::new();
+        let receiving = link.receive(Receiver::new().output(dir.path()).once(true));
+
+        // About 6TB.
+        let sender = link.endpoint("127.0.0.1:6667");
+        let send = Message::Send {
+            filename: "huge.iso".to_string(),
+            parts: u32::MAX,
+            group: None,
+            delta: false,
+            dedup: false,
+            verify: false,
+            token: None,
+            offset: None,
+            text: false,
+            synthetic: false,
+            size: None,
+        };
+        sender
+            .send_datagram(&send.serialize(), link.receiver_addr())
+            .unwrap();
+        let mut buf = [0; MTU];
+        let (len, _) = sender.recv_datagram(&mut buf).unwrap();
+        match Message::parse(&buf[..len]).unwrap() {
+            Message::Abort { reason } => assert!(reason.starts_with("not enough disk space")),
+            other => panic!("expected an Abort, got {other:?}"),
+        }
+
+        let err = receiving.join().unwrap().unwrap_err();
+        assert!(matches!(err, ReceiveError::NoSpace { .. }), "{err}");
+        assert!(!dir.join("huge.iso").exists());
+    }
+
+    /// Offer of a file of `parts` parts, or of a range of one from `offset` on.
+    fn offer(parts: u32, offset: Option<u64>) -> Offer {
+        Offer {
+            filename: "f".to_string(),
+            parts,
+            group: None,
+            delta: false,
+            dedup: false,
+            verify: false,
+            token: None,
+            offset,
+            text: false,
+            synthetic: false,
+            size: None,
+        }
+    }
+
+    #[test]
+    fn offers_over_the_limits_are_refused() {
+        let dir = TempDir::new("quota");
+        dir.write("kept", [0; 2000]);
+        let receiver = Receiver::new()
+            .output(dir.path())
+            .max_file_size(3000)
+            .quota(4000);
+        assert!(receiver.refusal(&offer(2, None), None).is_none());
+        match receiver.refusal(&offer(3, None), None) {
+            Some((reason, ReceiveError::Quota(4000))) => {
+                assert_eq!(reason, "quota of 4000 bytes exceeded, 2000 already used")
+            }
+            other => panic!("expected the quota to refuse, got {other:?}"),
+        }
+        assert!(matches!(
+            receiver.refusal(&offer(1, Some(3000)), None),
+            Some((
+                _,
+                ReceiveError::TooLarge {
+                    size: 3001,
+                    limit: 3000
+                }
+            ))
+        ));
+        let delta = Offer {
+            delta: true,
+            ..offer(1, Some(0))
+        };
+        assert!(receiver.refusal(&delta, None).is_some());
+        let text = Offer {
+            text: true,
+            ..offer(MAX_TEXT.div_ceil(PART_SIZE) as u32 + 1, None)
+        };
+        assert!(matches!(
+            receiver.refusal(&text, None),
+            Some((_, ReceiveError::TooLarge { .. }))
+        ));
+        let exact = Offer {
+            size: Some(PART_SIZE as u64 + 1),
+            ..offer(2, None)
+        };
+        assert!(receiver.refusal(&exact, None).is_none());
+        let mismatched = Offer {
+            size: Some(PART_SIZE as u64),
+            ..offer(2, None)
+        };
+        assert!(receiver.refusal(&mismatched, None).is_some());
+    }
+
+    #[test]
+    fn senders_past_the_limit_are_turned_away() {
+        let dir = TempDir::new("sessions");
+        let link = Link::new();
+        // Runs for good, like a daemon.
+        link.receive(Receiver::new().output(dir.path()).max_sessions(2));
+
+        let senders: Vec<_> = ["127.0.0.2:6667", "127.0.0.3:6667", "127.0.0.4:6667"]
+            .iter()
+            .map(|addr| link.endpoint(addr))
+            .collect();
+        let answer = |sender: &MemoryTransport, filename: &str| {
+            let send = Message::Send {
+                filename: filename.to_string(),
+                parts: 1,
+                group: None,
+                delta: false,
+                dedup: false,
+                verify: false,
+                token: None,
+                offset: None,
+                text: false,
+                synthetic: false,
+                size: None,
+            };
+            sender
+                .send_datagram(&send.serialize(), link.receiver_addr())
+                .unwrap();
+            let mut buf = [0; MTU];
+            let (len, _) = sender.recv_datagram(&mut buf).unwrap();
+            Message::parse(&buf[..len]).unwrap()
+        };
+        assert!(matches!(answer(&senders[0], "a"), Message::Accept { .. }));
+        assert!(matches!(answer(&senders[1], "b"), Message::Accept { .. }));
+        assert!(matches!(answer(&senders[2], "c"), Message::Abort { .. }));
+
+        for (sender, byte) in senders[..2].iter().zip([1, 2]) {
+            let part = Message::Part {
+                id: 0,
+                data: vec![byte; 10],
+            };
+            sender
+                .send_datagram(&part.serialize(), link.receiver_addr())
+                .unwrap();
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !(dir.join("a").exists() && dir.join("b").exists()) && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(std::fs::read(dir.join("a")).unwrap(), vec![1; 10]);
+        assert_eq!(std::fs::read(dir.join("b")).unwrap(), vec![2; 10]);
+        assert!(!dir.join("c").exists());
+    }
+
+    #[test]
+    fn operators_pause_and_cancel_sessions() {
+        let dir = TempDir::new("activity");
+        let file = dir.write("slow", vec![7; 200 * PART_SIZE]);
+        let link = Link::new();
+        let activity = Activity::default();
+        link.receive(
+            Receiver::new()
+                .output(dir.join("output"))
+                .rate_cap(RateCap::new(Some(20 * PART_SIZE as u64)))
+                .activity(activity.clone()),
+        );
+        let sender = link.sender();
+        let sent = std::thread::spawn(move || sender.send(&file));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let session = loop {
+            if let Some(listed) = activity.list().first() {
+                break activity.get(&listed.id).unwrap();
+            }
+            assert!(Instant::now() < deadline, "the session was not listed");
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        session.pause();
+        // The sender hears of it with the next Sync answer, and its parts in flight land.
+        std::thread::sleep(SYNC_INTERVAL * 4);
+        let paused = session.snapshot().parts_done;
+        std::thread::sleep(SYNC_INTERVAL * 3);
+        assert_eq!(session.snapshot().parts_done, paused);
+        assert!(paused < 200);
+
+        session.cancel();
+        match sent.join().unwrap() {
+            Err(crate::SendError::Aborted(reason)) => assert_eq!(reason, CANCELLED),
+            other => panic!("the transfer was not cancelled: {other:?}"),
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !activity.list().is_empty() {
+            assert!(Instant::now() < deadline, "the session is still listed");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn text_messages_are_shown_not_stored() {
+        let dir = TempDir::new("text");
+        let link = Link::new();
+        let (shown, texts) = mpsc::channel();
+        let receiving = link.receive(
+            Receiver::new()
+                .output(dir.path())
+                .once(true)
+                .on_text(move |peer, text| shown.send((peer, text.to_string())).unwrap()),
+        );
+        let sender = crate::Sender::new(link.receiver_addr().to_string())
+            .transport(link.endpoint("127.0.0.2:6667"));
+        let text = format!("https://example.com/{}", "é".repeat(PART_SIZE));
+        sender.send_text(&text).unwrap();
+        receiving.join().unwrap().unwrap();
+        assert_eq!(
+            texts.recv().unwrap(),
+            (Some("127.0.0.2:6667".parse().unwrap()), text)
+        );
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        let long = "x".repeat(MAX_TEXT + 1);
+        assert!(crate::Sender::new("127.0.0.1:6666")
+            .send_text(&long)
+            .is_err());
+    }
+
+    #[test]
+    fn encrypted_transfers_under_one_key_get_through() {
+        let dir = TempDir::new("sealed");
+        let output = dir.dir("output");
+        let key = SessionKey::generate().unwrap();
+        let link = Link::new();
+        // Same key, part ids and sequence numbers for both, only the subkeys differ.
+        for (name, byte) in [("a", 1), ("b", 2)] {
+            let receiving =
+                link.receive(Receiver::new().output(&output).once(true).key(key.clone()));
+            let data = vec![byte; 30 * PART_SIZE + 17];
+            let file = dir.write(name, &data);
+            link.sender().key(key.clone()).send(&file).unwrap();
+            receiving.join().unwrap().unwrap();
+            assert_eq!(std::fs::read(output.join(name)).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn rejected_files_are_quarantined() {
+        let dir = TempDir::new("scanned");
+        let output = dir.dir("output");
+        let link = Link::new();
+        for (name, content) in [("clean.txt", "notes"), ("evil.txt", "EVIL payload")] {
+            let receiving = link.receive(
+                Receiver::new()
+                    .output(&output)
+                    .once(true)
+                    .scanner(Scanner::Command(
+                        "if grep -q EVIL; then echo Evil.Test; exit 1; fi".to_string(),
+                    ))
+                    .quarantine(dir.join("quarantine")),
+            );
+            let _ = link.sender().send(&dir.write(name, content));
+            let received = receiving.join().unwrap();
+            let published = output.join(name);
+            match name {
+                "clean.txt" => {
+                    received.unwrap();
+                    assert_eq!(std::fs::read_to_string(published).unwrap(), content);
+                }
+                _ => {
+                    match received {
+                        Err(ReceiveError::Rejected(_, found)) => assert_eq!(found, "Evil.Test"),
+                        other => panic!("expected the scanner to reject, got {other:?}"),
                     }
+                    assert!(!published.exists());
+                    let quarantined = dir.join("quarantine").join(name);
+                    assert_eq!(std::fs::read_to_string(quarantined).unwrap(), content);
                 }
             }
-            Err(err) => {
-                error!(error=?err, "Error when receiving from socket.");
-                panic!();
+        }
+        // Nothing is left under a hidden name either.
+        assert_eq!(std::fs::read_dir(&output).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn files_appear_once_complete() {
+        let dir = TempDir::new("partial");
+        let link = Link::new();
+        let receiving = link.receive(Receiver::new().output(dir.path()).once(true));
+
+        let sender = link.endpoint("127.0.0.1:6667");
+        let send = |message: Message| {
+            sender
+                .send_datagram(&message.serialize(), link.receiver_addr())
+                .unwrap()
+        };
+        send(Message::Send {
+            filename: "f.bin".to_string(),
+            parts: 2,
+            group: None,
+            delta: false,
+            dedup: false,
+            verify: false,
+            token: None,
+            offset: None,
+            text: false,
+            synthetic: false,
+            size: None,
+        });
+        let mut buf = [0; MTU];
+        let (len, _) = sender.recv_datagram(&mut buf).unwrap();
+        assert!(matches!(
+            Message::parse(&buf[..len]).unwrap(),
+            Message::Accept {
+                capabilities: Some(Capabilities {
+                    max_part_size,
+                    ..
+                }),
+            } if max_part_size == PART_SIZE as u32
+        ));
+        send(Message::Part {
+            id: 0,
+            data: vec![1; PART_SIZE],
+        });
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(dir.join("f.bin.sanic-partial").exists());
+        assert!(!dir.join("f.bin").exists());
+
+        send(Message::Part {
+            id: 1,
+            data: vec![2; 10],
+        });
+        receiving.join().unwrap().unwrap();
+        let received = std::fs::read(dir.join("f.bin")).unwrap();
+        assert_eq!(received.len(), PART_SIZE + 10);
+        assert!(!dir.join("f.bin.sanic-partial").exists());
+    }
+
+    #[test]
+    fn mirrors_remove_files_only_when_allowed() {
+        let dir = TempDir::new("mirror-delete");
+        dir.write("kept.txt", "kept");
+        let manifest = |token: Option<&str>, entries: Vec<ManifestEntry>| Message::Manifest {
+            first: 0,
+            total: entries.len() as u32,
+            mirror: Some(Mirror {
+                dir: String::new(),
+                checksum: false,
+                delete: true,
+            }),
+            token: token.map(str::to_string),
+            entries,
+        };
+        let entry = ManifestEntry {
+            path: "other.txt".to_string(),
+            size: 5,
+            modified: 0,
+            sha256: [0; 32],
+        };
+        let ask = |link: &Link, manifest: Message| {
+            let sender = link.endpoint("127.0.0.1:6667");
+            sender
+                .send_datagram(&manifest.serialize(), link.receiver_addr())
+                .unwrap();
+            let mut buf = [0; MTU];
+            let (len, _) = sender.recv_datagram(&mut buf).unwrap();
+            Message::parse(&buf[..len]).unwrap()
+        };
+
+        let guarded = Link::new();
+        // Runs for good, like a daemon.
+        guarded.receive(
+            Receiver::new()
+                .output(dir.path())
+                .allow_delete(true)
+                .access(Access::new().allow(Rule {
+                    range: "127.0.0.0/8".parse().unwrap(),
+                    token: Some("secret".to_string()),
+                })),
+        );
+        for unauthorized in [
+            manifest(None, Vec::new()),
+            manifest(Some("guess"), vec![entry.clone()]),
+        ] {
+            match ask(&guarded, unauthorized) {
+                Message::Abort { reason } => assert_eq!(reason, "not authorized"),
+                other => panic!("expected an Abort, got {other:?}"),
+            }
+            assert!(dir.join("kept.txt").exists());
+        }
+        let empty = ask(&guarded, manifest(Some("secret"), Vec::new()));
+        assert!(matches!(empty, Message::Have { .. }), "{empty:?}");
+        assert!(dir.join("kept.txt").exists());
+
+        let unguarded = Link::new();
+        unguarded.receive(Receiver::new().output(dir.path()));
+        let refused = ask(&unguarded, manifest(None, vec![entry.clone()]));
+        assert!(matches!(refused, Message::Abort { .. }), "{refused:?}");
+        assert!(dir.join("kept.txt").exists());
+
+        let allowed = ask(&guarded, manifest(Some("secret"), vec![entry]));
+        assert!(matches!(allowed, Message::Have { .. }), "{allowed:?}");
+        assert!(!dir.join("kept.txt").exists());
+    }
+
+    #[test]
+    fn checksums_and_chunks_are_shown_only_to_authorized_senders() {
+        let dir = TempDir::new("signatures-token");
+        dir.write("ours.bin", [7; 3 * PART_SIZE]);
+        let link = Link::new();
+        link.receive(
+            Receiver::new()
+                .output(dir.path())
+                .access(Access::new().allow(Rule {
+                    range: "127.0.0.0/8".parse().unwrap(),
+                    token: Some("secret".to_string()),
+                })),
+        );
+        let sender = link.endpoint("127.0.0.1:6667");
+        let ask = |request: Vec<u8>| {
+            sender
+                .send_datagram(&request, link.receiver_addr())
+                .unwrap();
+            let mut buf = [0; MTU];
+            let (len, _) = sender.recv_datagram(&mut buf).unwrap();
+            Message::parse(&buf[..len]).unwrap()
+        };
+        let signatures = |token: Option<&str>| {
+            let mut request = Message::Signatures {
+                filename: "ours.bin".to_string(),
+                first: 0,
+                token: token.map(str::to_string),
+            }
+            .serialize();
+            request.resize(MTU, 0);
+            request
+        };
+        let chunks = |token: Option<&str>| {
+            Message::Chunks {
+                first: 0,
+                token: token.map(str::to_string),
+                digests: vec![[0; 32]],
+            }
+            .serialize()
+        };
+        for token in [None, Some("guess")] {
+            for request in [signatures(token), chunks(token)] {
+                match ask(request) {
+                    Message::Abort { reason } => assert_eq!(reason, "not authorized"),
+                    other => panic!("expected an Abort, got {other:?}"),
+                }
             }
         }
+        match ask(signatures(Some("secret"))) {
+            Message::Blocks { sums, .. } => assert!(!sums.is_empty()),
+            other => panic!("expected Blocks, got {other:?}"),
+        }
+        match ask(chunks(Some("secret"))) {
+            Message::Have { ids, .. } => assert!(ids.is_empty()),
+            other => panic!("expected a Have, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn files_come_out_as_long_as_they_went_in() {
+        let dir = TempDir::new("sizes");
+        let link = Link::new();
+        for len in [1, PART_SIZE - 1, PART_SIZE + 1, 3 * PART_SIZE + 17] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8 + 1).collect();
+            let source = dir.write("source.bin", &data);
+            let output = dir.dir(format!("out-{len}"));
+            let receiving = link.receive(Receiver::new().output(&output).once(true));
+            link.sender().send(&source).unwrap();
+            receiving.join().unwrap().unwrap();
+            assert_eq!(
+                std::fs::read(output.join("source.bin")).unwrap(),
+                data,
+                "{len}"
+            );
+        }
+    }
+
+    #[test]
+    fn sinks_take_the_files_instead_of_the_output() {
+        let dir = TempDir::new("sinks");
+        let output = dir.dir("output");
+        let data: Vec<u8> = (0..5 * PART_SIZE + 3).map(|i| (i % 251) as u8).collect();
+        let source = dir.write("source.bin", &data);
+        let piped = dir.join("piped.bin");
+        let command = format!(
+            "test \"$SANIC_FILENAME\" = source.bin && cat > {}",
+            piped.display()
+        );
+        let memory = Memory::new();
+        let sinks: [Box<dyn Sink>; 2] = [
+            Box::new(memory.clone()),
+            Box::new(Process::spawn(&command, "source.bin", None).unwrap()),
+        ];
+        for sink in sinks {
+            let sink = Mutex::new(Some(sink));
+            let link = Link::new();
+            let receiving = link.receive(
+                Receiver::new()
+                    .output(&output)
+                    .sink(move |_, size| {
+                        assert_eq!(size, Some(5 * PART_SIZE as u64 + 3));
+                        Ok(sink.lock().unwrap().take().unwrap())
+                    })
+                    .once(true),
+            );
+            link.sender().send(&source).unwrap();
+            receiving.join().unwrap().unwrap();
+        }
+        assert_eq!(memory.contents(), data);
+        assert_eq!(std::fs::read(&piped).unwrap(), data);
+        assert_eq!(std::fs::read_dir(&output).unwrap().count(), 0);
     }
 }
@@ -0,0 +1,301 @@
+//! The system clipboard, through the tools each platform has for it: wl-paste and wl-copy
+//! under Wayland, xclip or xsel under X11, pbpaste and pbcopy on macOS and PowerShell on
+//! Windows. Only the Wayland and xclip tools handle images.
+
+use std::{
+    ffi::OsString,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+/// Name images from the clipboard are sent under, which a receiver with --clipboard puts
+/// back in its own.
+pub const IMAGE_NAME: &str = "clipboard.png";
+
+pub enum Contents {
+    Text(String),
+    /// A PNG image.
+    Image(Vec<u8>),
+}
+
+struct Tool {
+    /// Prints the text in the clipboard.
+    paste: &'static [&'static str],
+    /// Prints the types the clipboard holds, one per line.
+    types: Option<&'static [&'static str]>,
+    /// Prints the PNG image in the clipboard.
+    paste_image: Option<&'static [&'static str]>,
+    /// Replaces the clipboard with the text on its stdin.
+    copy: &'static [&'static str],
+    /// Replaces the clipboard with the PNG image on its stdin.
+    copy_image: Option<&'static [&'static str]>,
+}
+
+const WAYLAND: Tool = Tool {
+    paste: &["wl-paste", "--no-newline"],
+    types: Some(&["wl-paste", "--list-types"]),
+    paste_image: Some(&["wl-paste", "--type", "image/png"]),
+    copy: &["wl-copy"],
+    copy_image: Some(&["wl-copy", "--type", "image/png"]),
+};
+
+const XCLIP: Tool = Tool {
+    paste: &["xclip", "-selection", "clipboard", "-out"],
+    types: Some(&[
+        "xclip",
+        "-selection",
+        "clipboard",
+        "-target",
+        "TARGETS",
+        "-out",
+    ]),
+    paste_image: Some(&[
+        "xclip",
+        "-selection",
+        "clipboard",
+        "-target",
+        "image/png",
+        "-out",
+    ]),
+    copy: &["xclip", "-selection", "clipboard", "-in"],
+    copy_image: Some(&[
+        "xclip",
+        "-selection",
+        "clipboard",
+        "-target",
+        "image/png",
+        "-in",
+    ]),
+};
+
+const XSEL: Tool = Tool {
+    paste: &["xsel", "--clipboard", "--output"],
+    types: None,
+    paste_image: None,
+    copy: &["xsel", "--clipboard", "--input"],
+    copy_image: None,
+};
+
+const MACOS: Tool = Tool {
+    paste: &["pbpaste"],
+    types: None,
+    paste_image: None,
+    copy: &["pbcopy"],
+    copy_image: None,
+};
+
+const WINDOWS: Tool = Tool {
+    paste: &["powershell", "-NoProfile", "-Command", "Get-Clipboard -Raw"],
+    types: None,
+    paste_image: None,
+    copy: &[
+        "powershell",
+        "-NoProfile",
+        "-Command",
+        "[Console]::In.ReadToEnd() | Set-Clipboard",
+    ],
+    copy_image: None,
+};
+
+/// What the clipboard holds, its image when it has one and we can read it.
+pub fn read() -> io::Result<Contents> {
+    Clipboard::current().read()
+}
+
+pub fn write_text(text: &str) -> io::Result<()> {
+    Clipboard::current().write_text(text)
+}
+
+/// Puts the PNG image `png` in the clipboard.
+pub fn write_image(png: &[u8]) -> io::Result<()> {
+    Clipboard::current().write_image(png)
+}
+
+/// The clipboard of a graphical session, and where the tools for it are looked for.
+struct Clipboard {
+    path: Option<OsString>,
+    wayland: bool,
+    x11: bool,
+}
+
+impl Clipboard {
+    /// The clipboard of the session we run in, with the tools on our `PATH`.
+    fn current() -> Self {
+        let session = |var| std::env::var_os(var).is_some_and(|value| !value.is_empty());
+        Clipboard {
+            path: std::env::var_os("PATH"),
+            wayland: session("WAYLAND_DISPLAY"),
+            x11: session("DISPLAY"),
+        }
+    }
+
+    fn read(&self) -> io::Result<Contents> {
+        let tool = self.tool()?;
+        if let (Some(types), Some(paste_image)) = (tool.types, tool.paste_image) {
+            // An empty clipboard has no types, and no text either.
+            let types = self.run(types, None).unwrap_or_default();
+            if String::from_utf8_lossy(&types)
+                .lines()
+                .any(|kind| kind.trim() == "image/png")
+            {
+                return Ok(Contents::Image(self.run(paste_image, None)?));
+            }
+        }
+        let text = self.run(tool.paste, None)?;
+        String::from_utf8(text)
+            .map(Contents::Text)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "the clipboard is not text"))
+    }
+
+    fn write_text(&self, text: &str) -> io::Result<()> {
+        self.run(self.tool()?.copy, Some(text.as_bytes())).map(drop)
+    }
+
+    fn write_image(&self, png: &[u8]) -> io::Result<()> {
+        let copy_image = self.tool()?.copy_image.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the clipboard tool cannot copy images",
+            )
+        })?;
+        self.run(copy_image, Some(png)).map(drop)
+    }
+
+    fn tool(&self) -> io::Result<&'static Tool> {
+        if cfg!(windows) {
+            return Ok(&WINDOWS);
+        }
+        if cfg!(target_os = "macos") {
+            return Ok(&MACOS);
+        }
+        if self.wayland && self.find("wl-paste").is_some() {
+            return Ok(&WAYLAND);
+        }
+        if self.x11 {
+            if self.find("xclip").is_some() {
+                return Ok(&XCLIP);
+            }
+            if self.find("xsel").is_some() {
+                return Ok(&XSEL);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no clipboard to use, it takes a graphical session with wl-clipboard, xclip or xsel",
+        ))
+    }
+
+    /// Path of `program` in the directories of our `PATH`.
+    fn find(&self, program: &str) -> Option<PathBuf> {
+        std::env::split_paths(self.path.as_ref()?)
+            .map(|dir| dir.join(program))
+            .find(|path| is_file(path))
+    }
+
+    /// Runs `command` with `input` on its stdin, and returns what it printed.
+    fn run(&self, command: &[&str], input: Option<&[u8]>) -> io::Result<Vec<u8>> {
+        let (program, args) = command.split_first().expect("Commands have a program");
+        // The name as it is for the system to look up when we do not find it, like the
+        // programs of Windows and their extension.
+        let mut child = Command::new(self.find(program).unwrap_or_else(|| program.into()))
+            .args(args)
+            .stdin(match input {
+                Some(_) => Stdio::piped(),
+                None => Stdio::null(),
+            })
+            .stdout(match input {
+                // wl-copy stays around to serve the clipboard, and must not hold a pipe of
+                // ours.
+                Some(_) => Stdio::null(),
+                None => Stdio::piped(),
+            })
+            .spawn()?;
+        if let Some(input) = input {
+            let mut stdin = child.stdin.take().expect("Clipboard stdin is piped");
+            stdin.write_all(input)?;
+        }
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!("{program} {}", output.status)));
+        }
+        Ok(output.stdout)
+    }
+}
+
+fn is_file(path: &Path) -> bool {
+    path.metadata().is_ok_and(|meta| meta.is_file())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::tempdir::TempDir;
+
+    #[test]
+    fn tools_that_paste_images_list_types() {
+        for tool in [&WAYLAND, &XCLIP, &XSEL, &MACOS, &WINDOWS] {
+            assert_eq!(tool.types.is_some(), tool.paste_image.is_some());
+            assert_eq!(tool.paste_image.is_some(), tool.copy_image.is_some());
+        }
+    }
+
+    #[test]
+    fn commands_take_stdin_and_give_stdout() {
+        let clipboard = Clipboard::current();
+        assert_eq!(clipboard.run(&["printf", "hi"], None).unwrap(), b"hi");
+        assert!(clipboard.run(&["sh", "-c", "exit 3"], None).is_err());
+        assert!(clipboard.run(&["sanic-no-such-tool"], None).is_err());
+        assert!(clipboard
+            .run(&["sh", "-c", "cat >/dev/null"], Some(b"text"))
+            .is_ok());
+        assert!(clipboard.find("sh").is_some());
+        assert!(clipboard.find("sanic-no-such-tool").is_none());
+    }
+
+    #[test]
+    fn the_clipboard_holds_text_and_images() {
+        // Stand-ins for wl-paste and wl-copy, keeping the clipboard in a file.
+        let dir = TempDir::new("clipboard");
+        let script = |name: &str, body: String| {
+            let path = dir.write(name, format!("#!/bin/sh\n{body}\n"));
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        };
+        let clip = dir.join("clip");
+        let kind = dir.join("kind");
+        script(
+            "wl-copy",
+            format!(
+                r#"cat > {clip}; [ "$2" ] && echo "$2" > {kind} || echo text/plain > {kind}"#,
+                clip = clip.display(),
+                kind = kind.display()
+            ),
+        );
+        script(
+            "wl-paste",
+            format!(
+                r#"[ "$1" = --list-types ] && exec cat {kind}; exec cat {clip}"#,
+                clip = clip.display(),
+                kind = kind.display()
+            ),
+        );
+        let clipboard = Clipboard {
+            path: Some(dir.path().into()),
+            wayland: true,
+            x11: false,
+        };
+
+        clipboard.write_text("héllo").unwrap();
+        assert!(matches!(clipboard.read().unwrap(), Contents::Text(text) if text == "héllo"));
+        clipboard.write_image(b"\x89PNG").unwrap();
+        assert!(matches!(clipboard.read().unwrap(), Contents::Image(png) if png == b"\x89PNG"));
+        std::fs::write(&kind, "text/plain\n").unwrap();
+        std::fs::write(&clip, [0xff, 0xfe]).unwrap();
+        assert_eq!(
+            clipboard.read().err().unwrap().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
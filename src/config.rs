@@ -0,0 +1,380 @@
+//! Defaults for the command line flags, read from `~/.config/sanic/config.toml`:
+//!
+//! ```toml
+//! # Used by every command that has the flag.
+//! port = 7000
+//! rate-limit = "50M"
+//!
+//! # Over the defaults with --profile wan.
+//! [profile.wan]
+//! rate-limit = "5M"
+//! cc = "ledbat"
+//! allow = ["10.0.0.0/8", "192.168.0.0/16"]
+//! ```
+//!
+//! Keys are long flag names, a string, a number, a boolean or an array of strings for
+//! flags given several times. Flags on the command line win, then the profile, then the
+//! defaults; keys that a command does not have are left out for it. Session keys are only
+//! ever taken from the command line, so that no transfer is encrypted with a static key
+//! nobody asked for.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use clap::CommandFactory;
+
+use crate::Cli;
+
+/// Flags the file cannot set.
+const COMMAND_LINE_ONLY: [&str; 2] = ["key-file", "key-stdin"];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Bool(bool),
+    Array(Vec<String>),
+}
+
+/// Settings of the file, by section: "" for the defaults, then the profiles by name.
+type Sections = BTreeMap<String, BTreeMap<String, Value>>;
+
+/// `args` with the flags the config file sets for their command added, right after the
+/// command so that its positional arguments stay last.
+pub fn apply(args: Vec<OsString>) -> Result<Vec<OsString>, String> {
+    let (path, profile) = wanted(&args);
+    let explicit = path.is_some();
+    let Some(path) = path.or_else(default_path) else {
+        return Ok(args);
+    };
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        // Only a file asked for has to be there.
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound && !explicit => {
+            return match profile {
+                Some(profile) => Err(format!(
+                    "no profile '{profile}', {} is missing",
+                    path.display()
+                )),
+                None => Ok(args),
+            }
+        }
+        Err(err) => return Err(format!("could not read {}: {err}", path.display())),
+    };
+    let sections = parse(&text).map_err(|err| format!("{}: {err}", path.display()))?;
+    let mut settings = sections.get("").cloned().unwrap_or_default();
+    if let Some(profile) = &profile {
+        let overrides = sections
+            .get(profile.as_str())
+            .ok_or_else(|| format!("no profile '{profile}' in {}", path.display()))?;
+        settings.extend(overrides.clone());
+    }
+    with_settings(args, &settings)
+}
+
+/// The config file and profile asked for with `--config` and `--profile`, which are
+/// global flags so they can come anywhere.
+fn wanted(args: &[OsString]) -> (Option<PathBuf>, Option<String>) {
+    let (mut path, mut profile) = (None, None);
+    let mut words = args.iter().skip(1).filter_map(|arg| arg.to_str());
+    while let Some(word) = words.next() {
+        if word == "--" {
+            break;
+        }
+        let (flag, value) = match word.split_once('=') {
+            Some((flag, value)) => (flag, Some(value.to_string())),
+            None => (word, None),
+        };
+        match flag {
+            "--config" => {
+                path = value
+                    .or_else(|| words.next().map(str::to_string))
+                    .map(PathBuf::from)
+            }
+            "--profile" => profile = value.or_else(|| words.next().map(str::to_string)),
+            _ => {}
+        }
+    }
+    (path, profile)
+}
+
+/// `$XDG_CONFIG_HOME/sanic/config.toml`, or under `~/.config`.
+fn default_path() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => Path::new(&std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("sanic").join("config.toml"))
+}
+
+fn with_settings(
+    mut args: Vec<OsString>,
+    settings: &BTreeMap<String, Value>,
+) -> Result<Vec<OsString>, String> {
+    let cli = Cli::command();
+    // The command is the first word naming one, past the values of the global flags.
+    let mut at = 1;
+    let command = loop {
+        let Some(word) = args.get(at).and_then(|arg| arg.to_str()) else {
+            return Ok(args);
+        };
+        if matches!(word, "--log-format" | "--config" | "--profile") {
+            at += 2;
+            continue;
+        }
+        if let Some(command) = cli.find_subcommand(word) {
+            break command;
+        }
+        at += 1;
+    };
+    let given = |long: &str, short: Option<char>| {
+        args[at + 1..]
+            .iter()
+            .filter_map(|arg| arg.to_str())
+            .any(|arg| {
+                arg == format!("--{long}")
+                    || arg.starts_with(&format!("--{long}="))
+                    || short.is_some_and(|short| {
+                        arg.starts_with(&format!("-{short}")) && !arg.starts_with("--")
+                    })
+            })
+    };
+
+    let mut added = Vec::new();
+    for (key, value) in settings {
+        let Some(arg) = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(key))
+        else {
+            continue;
+        };
+        if given(key, arg.get_short()) {
+            continue;
+        }
+        let flag = format!("--{key}");
+        match (value, arg.get_action().takes_values()) {
+            (Value::Bool(true), false) => added.push(flag.into()),
+            (Value::Bool(false), false) => {}
+            (Value::Bool(_), true) | (_, false) => {
+                return Err(format!(
+                    "'{key}' of {} takes {}",
+                    command.get_name(),
+                    match arg.get_action().takes_values() {
+                        true => "a value",
+                        false => "true or false",
+                    }
+                ))
+            }
+            (Value::String(value), true) => added.extend([flag.into(), value.into()]),
+            (Value::Integer(value), true) => added.extend([flag.into(), value.to_string().into()]),
+            (Value::Array(values), true) => {
+                for value in values {
+                    added.extend([OsString::from(&flag), value.into()]);
+                }
+            }
+        }
+    }
+    args.splice(at + 1..at + 1, added);
+    Ok(args)
+}
+
+/// The subset of TOML the file is written in: comments, `[profile.<name>]` headers and
+/// `key = value` lines, each on a line of its own.
+fn parse(text: &str) -> Result<Sections, String> {
+    let mut sections = Sections::new();
+    let mut section = String::new();
+    for (index, line) in text.lines().enumerate() {
+        let at = |err: String| format!("line {}: {err}", index + 1);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('[') {
+            let header = header
+                .split_once(']')
+                .filter(|(_, rest)| comment(rest))
+                .map(|(header, _)| header.trim())
+                .ok_or_else(|| at("unclosed section header".to_string()))?;
+            section = match header.strip_prefix("profile.") {
+                Some(name) if !name.is_empty() => unquote(name).to_string(),
+                _ => {
+                    return Err(at(format!(
+                        "unknown section [{header}], expected [profile.<name>]"
+                    )))
+                }
+            };
+            sections.entry(section.clone()).or_default();
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| at(format!("expected 'key = value', got '{line}'")))?;
+        let key = unquote(key.trim()).replace('_', "-");
+        if COMMAND_LINE_ONLY.contains(&key.as_str()) {
+            return Err(at(format!("'{key}' is only taken on the command line")));
+        }
+        let (value, rest) = parse_value(value.trim_start()).map_err(at)?;
+        if !comment(rest) {
+            return Err(at(format!("unexpected '{}' after the value", rest.trim())));
+        }
+        sections
+            .entry(section.clone())
+            .or_default()
+            .insert(key, value);
+    }
+    Ok(sections)
+}
+
+/// Whether `rest` of a line is only blanks and a comment.
+fn comment(rest: &str) -> bool {
+    let rest = rest.trim_start();
+    rest.is_empty() || rest.starts_with('#')
+}
+
+fn unquote(name: &str) -> &str {
+    name.strip_prefix('"')
+        .and_then(|name| name.strip_suffix('"'))
+        .unwrap_or(name)
+}
+
+/// The value at the start of `text`, and what follows it.
+fn parse_value(text: &str) -> Result<(Value, &str), String> {
+    if let Some(rest) = text.strip_prefix('[') {
+        let mut values = Vec::new();
+        let mut rest = rest.trim_start();
+        loop {
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((Value::Array(values), after));
+            }
+            let (value, after) = parse_string(rest)?;
+            values.push(value);
+            rest = after.trim_start();
+            rest = rest.strip_prefix(',').unwrap_or(rest).trim_start();
+        }
+    }
+    if text.starts_with('"') {
+        let (value, rest) = parse_string(text)?;
+        return Ok((Value::String(value), rest));
+    }
+    let end = text.find([' ', '\t', '#']).unwrap_or(text.len());
+    let (word, rest) = text.split_at(end);
+    let value = match word {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        word => Value::Integer(
+            word.replace('_', "")
+                .parse()
+                .map_err(|_| format!("invalid value '{word}', strings are quoted"))?,
+        ),
+    };
+    Ok((value, rest))
+}
+
+/// A basic string with its escapes, or a literal one in single quotes.
+fn parse_string(text: &str) -> Result<(String, &str), String> {
+    if let Some(literal) = text.strip_prefix('\'') {
+        let end = literal.find('\'').ok_or("unclosed string")?;
+        return Ok((literal[..end].to_string(), &literal[end + 1..]));
+    }
+    let basic = text
+        .strip_prefix('"')
+        .ok_or_else(|| format!("expected a string, got '{text}'"))?;
+    let mut value = String::new();
+    let mut chars = basic.char_indices();
+    while let Some((at, c)) = chars.next() {
+        match c {
+            '"' => return Ok((value, &basic[at + 1..])),
+            '\\' => match chars.next().map(|(_, c)| c) {
+                Some('n') => value.push('\n'),
+                Some('t') => value.push('\t'),
+                Some(c @ ('"' | '\\')) => value.push(c),
+                _ => return Err("unsupported escape in string".to_string()),
+            },
+            c => value.push(c),
+        }
+    }
+    Err("unclosed string".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<OsString> {
+        line.split_whitespace().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn settings_go_after_the_command_unless_given() {
+        let sections = parse(
+            "# defaults\n\
+             port = 7000\n\
+             rate_limit = \"50M\" # a comment\n\
+             once = true\n\
+             \n\
+             [profile.lan]\n\
+             allow = ['10.0.0.0/8', \"192.168.0.0/16\"]\n\
+             output = \"/srv/in box\"\n",
+        )
+        .unwrap();
+        assert_eq!(sections[""]["rate-limit"], Value::String("50M".to_string()));
+        assert_eq!(
+            sections["lan"]["output"],
+            Value::String("/srv/in box".to_string())
+        );
+
+        let mut settings = sections[""].clone();
+        settings.extend(sections["lan"].clone());
+        let applied = with_settings(
+            args("sanic --log-format text receive -o /tmp --port 1"),
+            &settings,
+        )
+        .unwrap();
+        assert_eq!(
+            applied,
+            args(
+                "sanic --log-format text receive --allow 10.0.0.0/8 --allow 192.168.0.0/16 \
+                 --once --rate-limit 50M -o /tmp --port 1"
+            )
+        );
+        // ctl has none of them.
+        let ctl = args("sanic ctl /run/sanic.sock list-sessions");
+        assert_eq!(with_settings(ctl.clone(), &settings).unwrap(), ctl);
+    }
+
+    #[test]
+    fn mistakes_name_their_line() {
+        assert_eq!(
+            parse("port = 70x").unwrap_err(),
+            "line 1: invalid value '70x', strings are quoted"
+        );
+        assert!(parse("\n[lan]\n")
+            .unwrap_err()
+            .starts_with("line 2: unknown section"));
+        assert!(parse("output = \"/srv").is_err());
+        assert!(parse("port = 1 2").is_err());
+        assert_eq!(
+            parse("[profile.wan]\nkey-file = \"/home/me/key\"").unwrap_err(),
+            "line 2: 'key-file' is only taken on the command line"
+        );
+        let settings = parse("once = \"yes\"").unwrap();
+        assert!(with_settings(args("sanic receive"), &settings[""]).is_err());
+    }
+
+    #[test]
+    fn config_and_profile_are_found_anywhere() {
+        assert_eq!(
+            wanted(&args(
+                "sanic receive --profile lan --config=/etc/sanic.toml -o x"
+            )),
+            (
+                Some(PathBuf::from("/etc/sanic.toml")),
+                Some("lan".to_string())
+            )
+        );
+        assert_eq!(wanted(&args("sanic send -- --profile")), (None, None));
+    }
+}
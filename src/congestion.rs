@@ -0,0 +1,616 @@
+//! Congestion control: how fast the sender goes when neither the receiver nor the path
+//! cap its rate.
+//!
+//! By default the sender goes as fast as it can and resends what got lost. In the
+//! background, it follows [`Ledbat`] instead and yields to the other traffic of the link.
+//! With [`Bbr`], it sends at the bandwidth of the bottleneck it measured, which keeps long
+//! fat links full without the losses of going as fast as it can.
+//!
+//! Even going as fast as it can, the sender spreads its parts out at a bit more than the
+//! [`Delivery`] rate, rather than bursting batches that shallow switch buffers drop.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::{server::SYNC_INTERVAL, transport::MAX_BATCH, PART_SIZE};
+
+/// Rate before the first answer, a batch per Sync interval.
+const INITIAL_RATE: f64 = (MAX_BATCH * PART_SIZE) as f64 / SYNC_INTERVAL.as_secs_f64();
+/// Slowest a controlled transfer goes, so it still notices when the link frees up.
+const MIN_RATE: f64 = (16 * PART_SIZE) as f64;
+
+/// Queueing delay a background transfer keeps at the bottleneck. LEDBAT allows up to 100ms,
+/// less keeps video calls on the same link comfortable.
+const TARGET: Duration = Duration::from_millis(60);
+/// How long each lowest round trip is remembered, and how many are. Routes change, so the
+/// base delay must be able to go up again.
+const BASE_BUCKET: Duration = Duration::from_secs(60);
+const BASE_HISTORY: usize = 10;
+/// Share of the rate gained per round trip on target, and lost per round trip a target
+/// over it.
+const GAIN_UP: f64 = 0.1;
+const GAIN_DOWN: f64 = 0.5;
+
+/// Pacing gain of the startup, the lowest that doubles the delivery rate every round trip.
+const STARTUP_GAIN: f64 = 2.885;
+/// Gains of the rounds of a bandwidth probing cycle: one above the estimate to find more,
+/// one below to drain what that queued, then six cruising at the estimate.
+const PROBE_GAINS: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+/// Round trips the bottleneck bandwidth is the highest delivery rate of.
+const BANDWIDTH_WINDOW: usize = 10;
+/// How long the lowest round trip holds before we drain the queue to measure it again.
+const MIN_RTT_WINDOW: Duration = Duration::from_secs(10);
+/// Rounds of startup the delivery rate may stop growing by a quarter before the pipe is
+/// deemed full.
+const FULL_ROUNDS: u32 = 3;
+
+/// Pacing gain of unlimited transfers over their delivery rate, the one Linux paces slow
+/// start at: the rate can still double every round trip.
+const PACING_GAIN: f64 = 2.0;
+/// Answers the delivery rate is the highest of.
+const DELIVERY_WINDOW: usize = 10;
+
+/// How the sender paces itself, see [`Sender::congestion`](crate::Sender::congestion).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Congestion {
+    /// As fast as the receiver and the path allow, resending what got lost.
+    #[default]
+    Unlimited,
+    /// Only use the capacity nobody else does, backing off as soon as queues build up at
+    /// the bottleneck, for bulk transfers that must not disturb interactive traffic.
+    Background,
+    /// At the bandwidth of the bottleneck, measured along with the lowest round trip, for
+    /// long fat links where going as fast as we can mostly fills queues and loses parts.
+    Bbr,
+}
+
+/// Takes `unlimited`, `ledbat` or `background`, and `bbr`.
+impl FromStr for Congestion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "unlimited" => Ok(Congestion::Unlimited),
+            "ledbat" | "background" => Ok(Congestion::Background),
+            "bbr" => Ok(Congestion::Bbr),
+            _ => Err(format!(
+                "{s} is not a congestion controller, try unlimited, ledbat or bbr"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Congestion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Congestion::Unlimited => "unlimited",
+            Congestion::Background => "ledbat",
+            Congestion::Bbr => "bbr",
+        })
+    }
+}
+
+/// What a controller learns from a round trip.
+struct Round {
+    rtt: Duration,
+    now: Instant,
+    /// Bytes per second acknowledged since the previous round trip, None on the first.
+    delivered: Option<f64>,
+    /// Whether the receiver reported parts lost since the previous round trip.
+    lost: bool,
+}
+
+/// Picks the rate of a transfer from the Acks, Losses and round trips of the receiver's
+/// answers.
+///
+/// A round trip goes from a Sync to the first answer after it. While an answer is late,
+/// the Syncs sent in the meantime are not timed: its answer would pass for theirs.
+#[derive(Debug)]
+pub(crate) struct Controller {
+    algorithm: Algorithm,
+    /// Since the last round trip.
+    acked: u64,
+    lost: bool,
+    /// When the Sync being timed went out.
+    asked: Option<Instant>,
+    /// When the previous round trip was asked and answered.
+    last: Option<(Instant, Instant)>,
+}
+
+#[derive(Debug)]
+enum Algorithm {
+    Ledbat(Ledbat),
+    Bbr(Bbr),
+}
+
+impl Controller {
+    /// None for [`Congestion::Unlimited`], which needs no control.
+    pub fn new(congestion: Congestion) -> Option<Self> {
+        let algorithm = match congestion {
+            Congestion::Unlimited => return None,
+            Congestion::Background => Algorithm::Ledbat(Ledbat::new()),
+            Congestion::Bbr => Algorithm::Bbr(Bbr::new()),
+        };
+        Some(Controller {
+            algorithm,
+            acked: 0,
+            lost: false,
+            asked: None,
+            last: None,
+        })
+    }
+
+    /// Bytes per second to send at.
+    pub fn rate(&self) -> u64 {
+        let rate = match &self.algorithm {
+            Algorithm::Ledbat(ledbat) => ledbat.rate,
+            Algorithm::Bbr(bbr) => bbr.rate(),
+        };
+        rate as u64
+    }
+
+    /// `parts` more were acknowledged.
+    pub fn acked(&mut self, parts: usize) {
+        self.acked += (parts * PART_SIZE) as u64;
+    }
+
+    /// The receiver reported parts lost.
+    pub fn lost(&mut self) {
+        self.lost = true;
+    }
+
+    /// A Sync went out at `now`.
+    pub fn asked(&mut self, now: Instant) {
+        self.asked.get_or_insert(now);
+    }
+
+    /// An answer came at `now`. The first one after a Sync ends a round trip, which
+    /// updates the rate with what happened since the previous one.
+    pub fn answered(&mut self, now: Instant) {
+        let Some(asked) = self.asked.take() else {
+            return;
+        };
+        // The receiver acknowledges what arrived between two Syncs. Like BBR, take the
+        // longer of the intervals between the Syncs and between their answers: a burst of
+        // answers out of a queue, or a burst of sends into one, do not pass for bandwidth.
+        let interval = self
+            .last
+            .replace((asked, now))
+            .map(|(last_asked, last_answered)| {
+                (asked - last_asked).max(now.saturating_duration_since(last_answered))
+            });
+        let acked = std::mem::take(&mut self.acked);
+        let round = Round {
+            rtt: now.saturating_duration_since(asked),
+            now,
+            delivered: interval
+                .filter(|interval| !interval.is_zero())
+                .map(|interval| acked as f64 / interval.as_secs_f64()),
+            lost: std::mem::take(&mut self.lost),
+        };
+        match &mut self.algorithm {
+            Algorithm::Ledbat(ledbat) => ledbat.round_trip(&round),
+            Algorithm::Bbr(bbr) => bbr.round_trip(&round),
+        }
+    }
+}
+
+/// Rate the receiver acknowledges parts at, which unlimited transfers are paced on.
+#[derive(Debug, Default)]
+pub(crate) struct Delivery {
+    /// Since the last Ack.
+    acked: u64,
+    /// When the last Ack came.
+    since: Option<Instant>,
+    /// Bytes per second acknowledged between Acks, the oldest first.
+    samples: VecDeque<f64>,
+}
+
+impl Delivery {
+    /// `parts` more were acknowledged.
+    pub fn acked(&mut self, parts: usize) {
+        self.acked += (parts * PART_SIZE) as u64;
+    }
+
+    /// An answer came at `now`. The Loss answering the same Sync as an Ack acknowledges
+    /// nothing, and must not pass for an interval without deliveries.
+    pub fn answered(&mut self, now: Instant) {
+        if self.acked == 0 {
+            return;
+        }
+        let acked = std::mem::take(&mut self.acked);
+        let Some(since) = self.since.replace(now) else {
+            return;
+        };
+        let interval = now.saturating_duration_since(since);
+        if interval.is_zero() {
+            return;
+        }
+        if self.samples.len() == DELIVERY_WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples
+            .push_back(acked as f64 / interval.as_secs_f64());
+    }
+
+    /// Bytes per second to pace at, a batch per Sync interval until the receiver
+    /// acknowledged parts twice. A slow interval, the receiver busy writing, does not hold
+    /// the transfer back.
+    pub fn pacing_rate(&self) -> u64 {
+        let highest = self.samples.iter().copied().fold(0.0, f64::max);
+        (highest * PACING_GAIN).max(INITIAL_RATE) as u64
+    }
+}
+
+/// Delay-based controller after LEDBAT (RFC 6817), working on a rate rather than a
+/// window. The queue we build at the bottleneck shows as round trips longer than the
+/// lowest one seen: we speed up while it stays under [`TARGET`], and slow down once it
+/// grows past it, well before the queue overflows and other flows lose packets.
+#[derive(Debug)]
+pub(crate) struct Ledbat {
+    /// Bytes per second.
+    rate: f64,
+    slow_start: bool,
+    /// Lowest round trip of each bucket, the oldest first.
+    base: VecDeque<(Instant, Duration)>,
+}
+
+impl Ledbat {
+    fn new() -> Self {
+        Ledbat {
+            rate: INITIAL_RATE,
+            slow_start: true,
+            base: VecDeque::new(),
+        }
+    }
+
+    fn round_trip(&mut self, round: &Round) {
+        let base = self.base_delay(round.rtt, round.now);
+        if round.lost {
+            // Delay did not warn us, like on links with tiny buffers, so back off as TCP does.
+            self.slow_start = false;
+            self.rate = (self.rate / 2.0).max(MIN_RATE);
+            return;
+        }
+        let queueing = round.rtt.saturating_sub(base);
+        let off_target = (1.0 - queueing.as_secs_f64() / TARGET.as_secs_f64()).max(-1.0);
+        // Only speed up when actually sending at the rate, not waiting on the disk.
+        let limited = round
+            .delivered
+            .is_some_and(|delivered| delivered < self.rate / 2.0);
+        if self.slow_start && queueing < TARGET / 2 {
+            if !limited {
+                self.rate *= 2.0;
+            }
+            return;
+        }
+        self.slow_start = false;
+        if off_target < 0.0 {
+            self.rate *= 1.0 + GAIN_DOWN * off_target;
+        } else if !limited {
+            self.rate *= 1.0 + GAIN_UP * off_target;
+        }
+        self.rate = self.rate.max(MIN_RATE);
+    }
+
+    /// Records `rtt` and returns the lowest round trip of the last minutes.
+    fn base_delay(&mut self, rtt: Duration, now: Instant) -> Duration {
+        match self.base.back_mut() {
+            Some((start, lowest)) if now - *start < BASE_BUCKET => *lowest = (*lowest).min(rtt),
+            _ => {
+                if self.base.len() == BASE_HISTORY {
+                    self.base.pop_front();
+                }
+                self.base.push_back((now, rtt));
+            }
+        }
+        self.base
+            .iter()
+            .map(|(_, lowest)| *lowest)
+            .min()
+            .unwrap_or(rtt)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// Doubling the rate every round trip until the delivery rate stops following.
+    Startup,
+    /// Draining the queue the startup built.
+    Drain,
+    /// Cruising at the estimate, at this phase of [`PROBE_GAINS`].
+    ProbeBandwidth(usize),
+    /// Draining the queue for a round trip, to measure the lowest one again.
+    ProbeRtt,
+}
+
+/// Model-based controller after BBR: the rate follows the bottleneck bandwidth, the
+/// highest delivery rate of the last round trips, while the lowest round trip tells when
+/// the queue we built is gone. Losses are left alone, as on long fat links they mostly come
+/// from elsewhere and backing off for them leaves the link idle.
+#[derive(Debug)]
+pub(crate) struct Bbr {
+    mode: Mode,
+    /// Delivery rates of the last round trips, the oldest first.
+    delivered: VecDeque<f64>,
+    /// Lowest round trip, and when it was last seen.
+    min_rtt: Option<(Duration, Instant)>,
+    /// Bottleneck bandwidth the startup last grew to, and the rounds since.
+    full: (f64, u32),
+}
+
+impl Bbr {
+    fn new() -> Self {
+        Bbr {
+            mode: Mode::Startup,
+            delivered: VecDeque::new(),
+            min_rtt: None,
+            full: (0.0, 0),
+        }
+    }
+
+    /// Estimate of the bottleneck bandwidth, in bytes per second.
+    fn bandwidth(&self) -> Option<f64> {
+        self.delivered.iter().copied().reduce(f64::max)
+    }
+
+    fn gain(&self) -> f64 {
+        match self.mode {
+            Mode::Startup => STARTUP_GAIN,
+            Mode::Drain => 1.0 / STARTUP_GAIN,
+            Mode::ProbeBandwidth(phase) => PROBE_GAINS[phase],
+            Mode::ProbeRtt => 0.5,
+        }
+    }
+
+    fn rate(&self) -> f64 {
+        match (self.bandwidth(), self.mode) {
+            // The first answers acknowledge little, the startup only ever speeds up.
+            (Some(bandwidth), Mode::Startup) => (bandwidth * STARTUP_GAIN).max(INITIAL_RATE),
+            (Some(bandwidth), _) => (bandwidth * self.gain()).max(MIN_RATE),
+            (None, _) => INITIAL_RATE,
+        }
+    }
+
+    fn round_trip(&mut self, round: &Round) {
+        if let Some(delivered) = round.delivered {
+            if self.delivered.len() == BANDWIDTH_WINDOW {
+                self.delivered.pop_front();
+            }
+            self.delivered.push_back(delivered);
+        }
+        let expired = self
+            .min_rtt
+            .is_some_and(|(_, seen)| round.now - seen > MIN_RTT_WINDOW);
+        if expired || self.min_rtt.is_none_or(|(min, _)| round.rtt <= min) {
+            self.min_rtt = Some((round.rtt, round.now));
+        }
+        self.mode = match self.mode {
+            Mode::Startup => {
+                let bandwidth = self.bandwidth().unwrap_or_default();
+                let (full, rounds) = &mut self.full;
+                if bandwidth >= *full * 1.25 {
+                    *full = bandwidth;
+                    *rounds = 0;
+                } else {
+                    *rounds += 1;
+                }
+                match *rounds >= FULL_ROUNDS {
+                    true => Mode::Drain,
+                    false => Mode::Startup,
+                }
+            }
+            Mode::Drain if self.drained(round.rtt) => Mode::ProbeBandwidth(0),
+            Mode::Drain => Mode::Drain,
+            Mode::ProbeBandwidth(phase) => Mode::ProbeBandwidth((phase + 1) % PROBE_GAINS.len()),
+            Mode::ProbeRtt => Mode::ProbeBandwidth(0),
+        };
+        if expired && matches!(self.mode, Mode::ProbeBandwidth(_)) {
+            self.mode = Mode::ProbeRtt;
+        }
+    }
+
+    /// Whether `rtt` is back near the lowest, the queue at the bottleneck gone.
+    fn drained(&self, rtt: Duration) -> bool {
+        self.min_rtt.is_none_or(|(min, _)| rtt <= min + min / 4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROUND: Duration = Duration::from_millis(200);
+
+    /// Runs `rounds` round trips of `rtt`, a Sync interval apart, acknowledging what was
+    /// sent at the rate up to the `capacity` of the link.
+    fn run(
+        controller: &mut Controller,
+        now: &mut Instant,
+        rounds: usize,
+        rtt: Duration,
+        capacity: f64,
+    ) {
+        for _ in 0..rounds {
+            let rate = (controller.rate() as f64).min(capacity);
+            let parts = rate * ROUND.as_secs_f64() / PART_SIZE as f64;
+            controller.asked(*now);
+            controller.acked(parts as usize);
+            controller.answered(*now + rtt);
+            *now += ROUND;
+        }
+    }
+
+    fn bbr(controller: &Controller) -> &Bbr {
+        match &controller.algorithm {
+            Algorithm::Bbr(bbr) => bbr,
+            Algorithm::Ledbat(_) => panic!("Not BBR"),
+        }
+    }
+
+    #[test]
+    fn unlimited_transfers_have_no_controller() {
+        assert!(Controller::new(Congestion::Unlimited).is_none());
+        for congestion in [
+            Congestion::Unlimited,
+            Congestion::Background,
+            Congestion::Bbr,
+        ] {
+            assert_eq!(congestion.to_string().parse(), Ok(congestion));
+        }
+        assert_eq!("background".parse(), Ok(Congestion::Background));
+        assert!("cubic".parse::<Congestion>().is_err());
+    }
+
+    #[test]
+    fn background_transfers_yield_to_queues() {
+        let mut ledbat = Controller::new(Congestion::Background).unwrap();
+        let mut now = Instant::now();
+        let rtt = Duration::from_millis(20);
+        run(&mut ledbat, &mut now, 5, rtt, f64::INFINITY);
+        assert_eq!(ledbat.rate(), (INITIAL_RATE * 32.0) as u64);
+        // Someone else fills the queue past the target.
+        let fast = ledbat.rate();
+        let queued = Duration::from_millis(140);
+        run(&mut ledbat, &mut now, 1, queued, f64::INFINITY);
+        assert_eq!(ledbat.rate(), fast / 2);
+        let full = Duration::from_millis(200);
+        run(&mut ledbat, &mut now, 30, full, f64::INFINITY);
+        assert_eq!(ledbat.rate(), MIN_RATE as u64);
+        // And leaves.
+        run(&mut ledbat, &mut now, 10, rtt, f64::INFINITY);
+        assert!(ledbat.rate() > MIN_RATE as u64 * 2);
+    }
+
+    #[test]
+    fn losses_halve_the_rate() {
+        let mut ledbat = Controller::new(Congestion::Background).unwrap();
+        let mut now = Instant::now();
+        let rtt = Duration::from_millis(20);
+        run(&mut ledbat, &mut now, 3, rtt, f64::INFINITY);
+        let rate = ledbat.rate();
+        ledbat.lost();
+        run(&mut ledbat, &mut now, 1, rtt, f64::INFINITY);
+        assert_eq!(ledbat.rate(), rate / 2);
+        // Out of slow start, it only grows by a tenth per round trip.
+        run(&mut ledbat, &mut now, 1, rtt, f64::INFINITY);
+        assert_eq!(ledbat.rate(), (rate as f64 / 2.0 * 1.1) as u64);
+    }
+
+    #[test]
+    fn idle_senders_do_not_speed_up() {
+        let mut ledbat = Controller::new(Congestion::Background).unwrap();
+        let mut now = Instant::now();
+        for _ in 0..5 {
+            ledbat.asked(now);
+            ledbat.answered(now + Duration::from_millis(20));
+            now += ROUND;
+        }
+        // The first round trip has nothing to compare with.
+        assert_eq!(ledbat.rate(), (INITIAL_RATE * 2.0) as u64);
+    }
+
+    #[test]
+    fn base_delay_follows_route_changes() {
+        let mut ledbat = Ledbat::new();
+        let now = Instant::now();
+        assert_eq!(
+            ledbat.base_delay(Duration::from_millis(30), now),
+            Duration::from_millis(30)
+        );
+        let later = now + Duration::from_secs(1);
+        assert_eq!(
+            ledbat.base_delay(Duration::from_millis(50), later),
+            Duration::from_millis(30)
+        );
+        let much_later = now + BASE_BUCKET * (BASE_HISTORY as u32 + 1);
+        assert_eq!(
+            ledbat.base_delay(Duration::from_millis(50), much_later),
+            Duration::from_millis(30)
+        );
+        for minute in 1..=BASE_HISTORY as u32 {
+            ledbat.base_delay(Duration::from_millis(50), much_later + BASE_BUCKET * minute);
+        }
+        assert_eq!(
+            ledbat.base_delay(Duration::from_millis(50), much_later + BASE_BUCKET * 11),
+            Duration::from_millis(50)
+        );
+    }
+
+    #[test]
+    fn bbr_finds_the_bottleneck() {
+        let mut controller = Controller::new(Congestion::Bbr).unwrap();
+        let mut now = Instant::now();
+        let (rtt, capacity) = (Duration::from_millis(80), 100_000_000.0);
+        run(&mut controller, &mut now, 30, rtt, capacity);
+        let bbr = bbr(&controller);
+        assert!(matches!(bbr.mode, Mode::ProbeBandwidth(_)));
+        let bandwidth = bbr.bandwidth().unwrap();
+        assert!(
+            (capacity * 0.99..=capacity).contains(&bandwidth),
+            "{bandwidth}"
+        );
+        // Cruising at the bandwidth, probing above it once per cycle.
+        let mut gains = Vec::new();
+        for _ in 0..PROBE_GAINS.len() {
+            run(&mut controller, &mut now, 1, rtt, capacity);
+            gains.push(controller.rate() as f64 / bandwidth);
+        }
+        gains.sort_by(f64::total_cmp);
+        assert!((0.74..0.76).contains(&gains[0]), "{gains:?}");
+        assert!((1.24..1.26).contains(&gains[7]), "{gains:?}");
+        assert!(gains[1..7].iter().all(|gain| (0.99..1.01).contains(gain)));
+    }
+
+    #[test]
+    fn bbr_drains_to_measure_the_round_trip_again() {
+        let mut controller = Controller::new(Congestion::Bbr).unwrap();
+        let mut now = Instant::now();
+        let (rtt, capacity) = (Duration::from_millis(50), 10_000_000.0);
+        run(&mut controller, &mut now, 20, rtt, capacity);
+        assert!(matches!(bbr(&controller).mode, Mode::ProbeBandwidth(_)));
+        // A queue stays at the bottleneck, the lowest round trip expires.
+        let queued = Duration::from_millis(90);
+        let start = now;
+        while bbr(&controller).mode != Mode::ProbeRtt {
+            assert!(now - start <= MIN_RTT_WINDOW + ROUND);
+            run(&mut controller, &mut now, 1, queued, capacity);
+        }
+        assert!(now - start >= MIN_RTT_WINDOW - ROUND);
+        let bandwidth = bbr(&controller).bandwidth().unwrap();
+        assert_eq!(controller.rate(), (bandwidth * 0.5) as u64);
+        // The half rate round drained it, and showed the lowest round trip again.
+        run(&mut controller, &mut now, 1, rtt, capacity);
+        assert_eq!(bbr(&controller).mode, Mode::ProbeBandwidth(0));
+        assert_eq!(bbr(&controller).min_rtt.unwrap().0, rtt);
+    }
+
+    #[test]
+    fn unlimited_transfers_pace_above_their_delivery_rate() {
+        let mut delivery = Delivery::default();
+        let now = Instant::now();
+        let parts = 1_000_000 / PART_SIZE;
+        // The first Ack only starts the clock, and a Loss without Acks is no interval.
+        delivery.acked(parts);
+        delivery.answered(now);
+        delivery.answered(now + ROUND / 2);
+        assert_eq!(delivery.pacing_rate(), INITIAL_RATE as u64);
+        delivery.acked(parts);
+        delivery.answered(now + ROUND);
+        let rate = (parts * PART_SIZE) as f64 / ROUND.as_secs_f64();
+        assert_eq!(delivery.pacing_rate(), (rate * PACING_GAIN) as u64);
+        // A slow interval does not slow the transfer down, until it is all there is.
+        delivery.acked(1);
+        delivery.answered(now + 2 * ROUND);
+        assert_eq!(delivery.pacing_rate(), (rate * PACING_GAIN) as u64);
+        for round in 3..3 + DELIVERY_WINDOW as u32 {
+            delivery.acked(1);
+            delivery.answered(now + round * ROUND);
+        }
+        assert_eq!(delivery.pacing_rate(), INITIAL_RATE as u64);
+    }
+}
@@ -0,0 +1,357 @@
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    ops::Range,
+    path::Path,
+};
+
+use sanic::{activity::Activity, queue::Queue, RateCap};
+use tracing::{info, warn};
+
+/// How long a control connection may stay silent before it is closed.
+#[cfg(unix)]
+const IDLE: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Parses a number of bytes, or of bytes per second, with an optional K, M or G (powers of
+/// 1000) or KiB, MiB or GiB (powers of 1024) suffix.
+pub fn parse_bytes(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, base) = match value.strip_suffix("iB") {
+        Some(number) => (number, 1024u64),
+        None => (value, 1000),
+    };
+    let (digits, power) = match number.char_indices().last() {
+        Some((at, 'k' | 'K')) => (&number[..at], 1),
+        Some((at, 'm' | 'M')) => (&number[..at], 2),
+        Some((at, 'g' | 'G')) => (&number[..at], 3),
+        _ if base == 1024 => return Err(format!("invalid amount '{value}'")),
+        _ => (number, 0),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|value| value.checked_mul(base.pow(power)))
+        .ok_or_else(|| format!("invalid amount '{value}'"))
+}
+
+/// Parses a range of bytes as `START-END`, END excluded, or `START-` for the rest of the
+/// file, both with the suffixes of [`parse_bytes`].
+pub fn parse_range(value: &str) -> Result<Range<u64>, String> {
+    let (start, end) = value
+        .split_once('-')
+        .ok_or_else(|| format!("invalid range '{value}', expected START-END"))?;
+    let start = parse_bytes(start)?;
+    let end = match end.trim() {
+        "" => u64::MAX,
+        end => parse_bytes(end)?,
+    };
+    if start > end {
+        return Err(format!("range '{value}' ends before it starts"));
+    }
+    Ok(start..end)
+}
+
+/// Serves operator commands for a running receiver or queue on a unix socket, one per line:
+///
+/// - `rate-limit <rate>` caps the transfers, see [`parse_bytes`],
+/// - `rate-limit off` lifts the cap,
+/// - `list-sessions` lists the transfers running, as a JSON array,
+/// - `get-stats <id>` tells how far a transfer got, as a JSON object,
+/// - `pause <id>`, `resume <id>` and `cancel <id>` act on a transfer,
+/// - `enqueue <priority> <path>` queues a file or directory, with a `queue`.
+///
+/// Answers are `ok`, followed by the JSON on the same line if any, or `error: <why>`.
+#[cfg(unix)]
+pub fn serve(
+    path: &Path,
+    cap: RateCap,
+    activity: Activity,
+    queue: Option<Queue>,
+) -> io::Result<()> {
+    use std::os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    };
+
+    // A socket left behind by a previous run would make the bind fail, but anything else
+    // there, or a socket someone still listens on, is not ours to remove.
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.file_type().is_socket() => {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        Ok(_) if UnixStream::connect(path).is_ok() => {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is listened on already", path.display()),
+            ));
+        }
+        Ok(_) => std::fs::remove_file(path)?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+    let listener = UnixListener::bind(path)?;
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let (stream, writer) = match stream.and_then(|stream| {
+                stream.set_read_timeout(Some(IDLE))?;
+                let writer = stream.try_clone()?;
+                Ok((stream, writer))
+            }) {
+                Ok(connection) => connection,
+                Err(err) => {
+                    warn!(error = ?err, "Could not accept control connection.");
+                    continue;
+                }
+            };
+            // A connection left open does not hold the others up.
+            let (cap, activity, queue) = (cap.clone(), activity.clone(), queue.clone());
+            std::thread::spawn(move || answer(stream, writer, &cap, &activity, queue.as_ref()));
+        }
+    });
+    Ok(())
+}
+
+/// Answers the commands of a control connection until it closes or goes idle.
+#[cfg(unix)]
+fn answer(
+    stream: impl io::Read,
+    mut writer: impl Write,
+    cap: &RateCap,
+    activity: &Activity,
+    queue: Option<&Queue>,
+) {
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        let reply = match execute(&line, cap, activity, queue) {
+            Ok(None) => "ok\n".to_string(),
+            Ok(Some(json)) => format!("ok {json}\n"),
+            Err(err) => format!("error: {err}\n"),
+        };
+        if writer.write_all(reply.as_bytes()).is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn serve(
+    _path: &Path,
+    _cap: RateCap,
+    _activity: Activity,
+    _queue: Option<Queue>,
+) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "control sockets are only supported on unix",
+    ))
+}
+
+/// Runs `commands` on the control socket at `path`, failing at the first one refused, and
+/// returns what they answered after the `ok`.
+#[cfg(unix)]
+pub fn run(path: &Path, commands: &[String]) -> io::Result<Vec<String>> {
+    let mut stream = std::os::unix::net::UnixStream::connect(path)?;
+    let mut replies = BufReader::new(stream.try_clone()?).lines();
+    let mut answers = Vec::new();
+    for command in commands {
+        writeln!(stream, "{command}")?;
+        let reply = replies
+            .next()
+            .unwrap_or_else(|| Err(io::ErrorKind::UnexpectedEof.into()))?;
+        if let Some(err) = reply.strip_prefix("error: ") {
+            return Err(io::Error::other(err.to_string()));
+        }
+        let answer = reply.strip_prefix("ok").unwrap_or(&reply);
+        answers.push(answer.trim_start().to_string());
+    }
+    Ok(answers)
+}
+
+#[cfg(not(unix))]
+pub fn run(_path: &Path, _commands: &[String]) -> io::Result<Vec<String>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "control sockets are only supported on unix",
+    ))
+}
+
+/// Runs `command`, and returns the JSON it answers with if any.
+fn execute(
+    command: &str,
+    cap: &RateCap,
+    activity: &Activity,
+    queue: Option<&Queue>,
+) -> Result<Option<String>, String> {
+    // Paths take the rest of the line, spaces included.
+    if let Some(rest) = command.trim().strip_prefix("enqueue ") {
+        let queue = queue.ok_or("nothing to queue files on here")?;
+        let (priority, path) = rest
+            .trim_start()
+            .split_once(' ')
+            .ok_or_else(|| format!("expected 'enqueue <priority> <path>', got '{command}'"))?;
+        let priority = priority
+            .parse()
+            .map_err(|_| format!("invalid priority '{priority}', expected 0 to 255"))?;
+        let path = Path::new(path.trim_start());
+        if !path.is_absolute() || !path.exists() {
+            return Err(format!(
+                "{} is not an absolute path to a file",
+                path.display()
+            ));
+        }
+        info!(path = %path.display(), priority, "File queued.");
+        queue.push(path, priority);
+        return Ok(None);
+    }
+    let mut words = command.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some("rate-limit"), Some("off"), None) => {
+            info!("Rate cap lifted.");
+            cap.set(None);
+            Ok(None)
+        }
+        (Some("rate-limit"), Some(rate), None) => {
+            let bytes_per_sec = parse_bytes(rate)?;
+            info!(bytes_per_sec, "Rate cap changed.");
+            cap.set(Some(bytes_per_sec));
+            Ok(None)
+        }
+        (Some("list-sessions"), None, None) => {
+            let sessions: Vec<String> = activity.list().iter().map(|s| s.to_json()).collect();
+            Ok(Some(format!("[{}]", sessions.join(","))))
+        }
+        (Some(verb @ ("get-stats" | "pause" | "resume" | "cancel")), Some(id), None) => {
+            let session = activity
+                .get(id)
+                .ok_or_else(|| format!("no session '{id}' running"))?;
+            match verb {
+                "get-stats" => return Ok(Some(session.snapshot().to_json())),
+                "pause" => session.pause(),
+                "resume" => session.resume(),
+                _ => session.cancel(),
+            }
+            info!(id, verb, "Session changed by the operator.");
+            Ok(None)
+        }
+        _ => Err(format!("unknown command '{}'", command.trim())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sanic::Sender;
+
+    use super::*;
+
+    #[test]
+    fn amounts_take_decimal_and_binary_suffixes() {
+        assert_eq!(parse_bytes("512"), Ok(512));
+        assert_eq!(parse_bytes(" 10k "), Ok(10_000));
+        assert_eq!(parse_bytes("100M"), Ok(100_000_000));
+        assert_eq!(parse_bytes("2G"), Ok(2_000_000_000));
+        assert_eq!(parse_bytes("4KiB"), Ok(4096));
+        assert_eq!(parse_bytes("1GiB"), Ok(1 << 30));
+        assert!(parse_bytes("4iB").is_err());
+        assert!(parse_bytes("fast").is_err());
+        assert!(parse_bytes("-1M").is_err());
+        assert!(parse_bytes("99999999999G").is_err());
+    }
+
+    #[test]
+    fn ranges_may_leave_the_end_open() {
+        assert_eq!(parse_range("1k-2k"), Ok(1000..2000));
+        assert_eq!(parse_range("1MiB-"), Ok(1 << 20..u64::MAX));
+        assert!(parse_range("100").is_err());
+        assert!(parse_range("2k-1k").is_err());
+    }
+
+    #[test]
+    fn commands_act_on_the_receiver() {
+        let cap = RateCap::default();
+        let activity = Activity::default();
+        let run = |command: &str| execute(command, &cap, &activity, None);
+
+        assert_eq!(run("rate-limit 10M"), Ok(None));
+        assert_eq!(cap.get(), Some(10_000_000));
+        assert_eq!(run("  rate-limit   off "), Ok(None));
+        assert_eq!(cap.get(), None);
+        assert!(run("rate-limit fast").is_err());
+        assert_eq!(run("list-sessions"), Ok(Some("[]".to_string())));
+        for verb in ["get-stats", "pause", "resume", "cancel"] {
+            assert_eq!(
+                run(&format!("{verb} 0a1b2c3d")),
+                Err("no session '0a1b2c3d' running".to_string())
+            );
+        }
+        assert!(run("pause").is_err());
+        assert!(run("list-sessions now").is_err());
+        assert_eq!(run("reboot"), Err("unknown command 'reboot'".to_string()));
+        assert!(run("enqueue 1 /tmp").is_err());
+    }
+
+    #[test]
+    fn files_are_queued_by_absolute_path() {
+        let queue = Queue::new(Sender::new("127.0.0.1:6666"));
+        let dir = std::env::temp_dir();
+        let run = |command: &str| {
+            execute(
+                command,
+                &RateCap::default(),
+                &Activity::default(),
+                Some(&queue),
+            )
+        };
+        assert_eq!(run(&format!("enqueue 7 {}", dir.display())), Ok(None));
+        assert!(run("enqueue 7 relative/path").is_err());
+        assert!(run("enqueue 300 /tmp").is_err());
+        assert!(run("enqueue /tmp").is_err());
+        assert!(run(&format!(
+            "enqueue 7 {}",
+            dir.join("sanic-missing").display()
+        ))
+        .is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn commands_go_over_the_socket() {
+        let path = std::env::temp_dir().join(format!("sanic-control-{}.sock", std::process::id()));
+        let cap = RateCap::default();
+        serve(&path, cap.clone(), Activity::default(), None).unwrap();
+        let commands = ["rate-limit 1M".to_string(), "list-sessions".to_string()];
+        assert_eq!(run(&path, &commands).unwrap(), ["", "[]"]);
+        assert_eq!(cap.get(), Some(1_000_000));
+        let err = run(&path, &["pause 0a1b2c3d".to_string()]).unwrap_err();
+        assert_eq!(err.to_string(), "no session '0a1b2c3d' running");
+
+        // A connection left open does not keep the others waiting.
+        let _idle = std::os::unix::net::UnixStream::connect(&path).unwrap();
+        assert_eq!(run(&path, &["rate-limit off".to_string()]).unwrap(), [""]);
+
+        // Someone listens there already.
+        let err = serve(&path, cap.clone(), Activity::default(), None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_sockets_are_replaced() {
+        let path = std::env::temp_dir().join(format!("sanic-control-{}.txt", std::process::id()));
+        std::fs::write(&path, "notes").unwrap();
+        let err = serve(&path, RateCap::default(), Activity::default(), None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "notes");
+        std::fs::remove_file(&path).unwrap();
+
+        // The socket of a run that is gone is.
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        serve(&path, RateCap::default(), Activity::default(), None).unwrap();
+        assert_eq!(run(&path, &["list-sessions".to_string()]).unwrap(), ["[]"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,336 @@
+use std::fs::File;
+use std::io::{self, Read};
+#[cfg(feature = "crypto")]
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "crypto")]
+use chacha20poly1305::{AeadInPlace, ChaCha20Poly1305, KeyInit, Nonce};
+#[cfg(feature = "crypto")]
+use hkdf::Hkdf;
+#[cfg(feature = "crypto")]
+use sha2::Sha256;
+use thiserror::Error;
+
+#[cfg(feature = "crypto")]
+use crate::permutation::PartIdPermutation;
+
+/// Name of the cipher [`Cipher`] seals with, as receivers advertise it.
+pub const CIPHER: &str = "chacha20-poly1305";
+/// Bytes added to every datagram by [`Cipher::seal`]: key space, transfer id, sequence
+/// number and tag.
+pub const OVERHEAD: usize = HEADER + TAG_SIZE;
+const HEADER: usize = 1 + TRANSFER_ID + 4;
+const TRANSFER_ID: usize = 16;
+const TAG_SIZE: usize = 16;
+/// Peer transfers whose subkeys are kept, enough for the senders of a busy receiver.
+#[cfg(feature = "crypto")]
+const KNOWN_TRANSFERS: usize = 16;
+
+/// Sequence spaces, so that no two datagrams of a transfer are ever sealed with the same
+/// nonce. Parts use their part id as sequence number: a retransmission is the exact same
+/// datagram, and the permuted id is all an observer gets to see.
+pub const SPACE_PARTS: u8 = 0;
+pub const SPACE_SENDER: u8 = 1;
+pub const SPACE_RECEIVER: u8 = 2;
+
+#[derive(Error, Debug)]
+pub enum CryptoError {
+    #[error("Datagram too short to be sealed.")]
+    Truncated,
+
+    #[error("Datagram failed authentication.")]
+    BadTag,
+
+    #[error("Datagram sealed by ourselves, and sent back.")]
+    Reflected,
+}
+
+/// Secret shared by both ends of an encrypted transfer.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SessionKey {
+    pub key: [u8; 32],
+    /// Random per-session input of the part id permutation.
+    pub salt: u64,
+}
+
+impl std::fmt::Debug for SessionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SessionKey(..)")
+    }
+}
+
+impl SessionKey {
+    pub fn generate() -> std::io::Result<Self> {
+        let mut bytes = [0u8; 40];
+        random_bytes(&mut bytes)?;
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&bytes[..32]);
+        let salt = u64::from_be_bytes(bytes[32..].try_into().unwrap());
+        Ok(SessionKey { key, salt })
+    }
+
+    /// Hexadecimal form used to hand the key over to another process.
+    pub fn to_hex(&self) -> String {
+        let mut hex: String = self.key.iter().map(|b| format!("{b:02x}")).collect();
+        hex.push_str(&format!("{:016x}", self.salt));
+        hex
+    }
+
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        let hex = hex.trim();
+        if hex.len() != 80 || !hex.is_ascii() {
+            return None;
+        }
+        let mut key = [0u8; 32];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+        }
+        let salt = u64::from_str_radix(&hex[64..], 16).ok()?;
+        Some(SessionKey { key, salt })
+    }
+}
+
+/// Seals and opens datagrams with ChaCha20-Poly1305, under subkeys of the session key.
+///
+/// Every cipher draws a random transfer id, and seals with the subkey HKDF derives from
+/// the session key and that id: sequence numbers restart with every transfer, and the
+/// fresh subkey is what keeps two transfers under the same session key from ever sealing
+/// with the same key and nonce. A sealed datagram is `space || transfer id || permuted
+/// sequence number || ciphertext || tag`, the header being authenticated as associated
+/// data. Datagrams are opened with the subkey of the transfer id they carry.
+#[cfg(feature = "crypto")]
+pub struct Cipher {
+    key: [u8; 32],
+    salt: u64,
+    transfer: [u8; TRANSFER_ID],
+    ours: Subkey,
+    /// Subkeys of the peers' transfers lately opened, most recent first. Only the ones
+    /// that opened a datagram get in, so forged transfer ids cannot push them out.
+    theirs: Mutex<Vec<([u8; TRANSFER_ID], Arc<Subkey>)>>,
+}
+
+/// Key and part id permutation of a transfer.
+#[cfg(feature = "crypto")]
+struct Subkey {
+    aead: ChaCha20Poly1305,
+    ids: PartIdPermutation,
+}
+
+#[cfg(feature = "crypto")]
+impl Subkey {
+    fn derive(session: &[u8; 32], salt: u64, transfer: &[u8; TRANSFER_ID]) -> Self {
+        let key = hkdf(session, transfer, b"sanic transfer");
+        Subkey {
+            aead: ChaCha20Poly1305::new(&key.into()),
+            ids: PartIdPermutation::new(&key, salt),
+        }
+    }
+}
+
+#[cfg(feature = "crypto")]
+impl Cipher {
+    pub fn new(session: &SessionKey) -> io::Result<Self> {
+        let mut transfer = [0u8; TRANSFER_ID];
+        random_bytes(&mut transfer)?;
+        Ok(Cipher {
+            key: session.key,
+            salt: session.salt,
+            transfer,
+            ours: Subkey::derive(&session.key, session.salt, &transfer),
+            theirs: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub fn seal(&self, space: u8, seq: u32, packet: &[u8]) -> Vec<u8> {
+        let mut datagram = Vec::with_capacity(packet.len() + OVERHEAD);
+        datagram.push(space);
+        datagram.extend(self.transfer);
+        datagram.extend(self.ours.ids.encode(seq).to_be_bytes());
+        datagram.extend(packet);
+        let (header, body) = datagram.split_at_mut(HEADER);
+        let tag = self
+            .ours
+            .aead
+            .encrypt_in_place_detached(&nonce(space, seq), header, body)
+            .expect("Datagrams are far below the ChaCha20 length limit");
+        datagram.extend(tag);
+        datagram
+    }
+
+    pub fn open(&self, datagram: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if datagram.len() < OVERHEAD {
+            return Err(CryptoError::Truncated);
+        }
+        let (header, rest) = datagram.split_at(HEADER);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_SIZE);
+        let transfer: [u8; TRANSFER_ID] = header[1..1 + TRANSFER_ID].try_into().unwrap();
+        if transfer == self.transfer {
+            return Err(CryptoError::Reflected);
+        }
+        let known = self.known(&transfer);
+        let subkey = match &known {
+            Some(subkey) => subkey,
+            None => &Arc::new(Subkey::derive(&self.key, self.salt, &transfer)),
+        };
+        let permuted = u32::from_be_bytes(header[1 + TRANSFER_ID..].try_into().unwrap());
+        let nonce = nonce(header[0], subkey.ids.decode(permuted));
+        let mut packet = ciphertext.to_vec();
+        subkey
+            .aead
+            .decrypt_in_place_detached(&nonce, header, &mut packet, tag.into())
+            .map_err(|_| CryptoError::BadTag)?;
+        let mut theirs = self.theirs.lock().expect("Could not lock subkeys");
+        if !theirs.iter().any(|(id, _)| *id == transfer) {
+            theirs.insert(0, (transfer, subkey.clone()));
+            theirs.truncate(KNOWN_TRANSFERS);
+        }
+        Ok(packet)
+    }
+
+    /// Subkey of the peer's `transfer`, if one of its datagrams opened lately.
+    fn known(&self, transfer: &[u8; TRANSFER_ID]) -> Option<Arc<Subkey>> {
+        let mut theirs = self.theirs.lock().expect("Could not lock subkeys");
+        let at = theirs.iter().position(|(id, _)| id == transfer)?;
+        if at > 0 {
+            let entry = theirs.remove(at);
+            theirs.insert(0, entry);
+        }
+        Some(theirs[0].1.clone())
+    }
+}
+
+/// Without the `crypto` feature there is no cipher, and sessions with a key fail.
+#[cfg(not(feature = "crypto"))]
+pub enum Cipher {}
+
+#[cfg(not(feature = "crypto"))]
+impl Cipher {
+    pub fn new(_session: &SessionKey) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "sanic was built without encryption (the crypto feature)",
+        ))
+    }
+
+    pub fn seal(&self, _space: u8, _seq: u32, _packet: &[u8]) -> Vec<u8> {
+        match *self {}
+    }
+
+    pub fn open(&self, _datagram: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        match *self {}
+    }
+}
+
+pub fn random_bytes(buf: &mut [u8]) -> io::Result<()> {
+    File::open("/dev/urandom")?.read_exact(buf)
+}
+
+/// 32 bytes of HKDF-SHA256 (RFC 5869) output.
+#[cfg(feature = "crypto")]
+fn hkdf(ikm: &[u8], salt: &[u8], info: &[u8]) -> [u8; 32] {
+    let mut okm = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(salt), ikm)
+        .expand(info, &mut okm)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    okm
+}
+
+#[cfg(feature = "crypto")]
+fn nonce(space: u8, seq: u32) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[0] = space;
+    nonce[8..].copy_from_slice(&seq.to_be_bytes());
+    nonce.into()
+}
+
+#[cfg(all(test, feature = "crypto"))]
+mod tests {
+    use super::*;
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn aead_matches_rfc_8439() {
+        // Section 2.8.2
+        let key: [u8; 32] = std::array::from_fn(|i| 0x80 + i as u8);
+        let nonce: [u8; 12] = unhex("070000004041424344454647").try_into().unwrap();
+        let aad = unhex("50515253c0c1c2c3c4c5c6c7");
+        let mut text = b"Ladies and Gentlemen of the class of '99: If I could offer you only one \
+            tip for the future, sunscreen would be it."
+            .to_vec();
+        let tag = ChaCha20Poly1305::new(&key.into())
+            .encrypt_in_place_detached(&nonce.into(), &aad, &mut text)
+            .unwrap();
+        assert_eq!(
+            text,
+            unhex(
+                "d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d63dbea45e8ca96712\
+                 82fafb69da92728b1a71de0a9e060b2905d6a5b67ecd3b3692ddbd7f2d778b8c9803aee328091b58\
+                 fab324e4fad675945585808b4831d7bc3ff4def08e4b7a9de576d26586cec64b6116"
+            )
+        );
+        assert_eq!(tag[..], unhex("1ae10b594f09e26a7e902ecbd0600691"));
+    }
+
+    #[test]
+    fn subkeys_match_rfc_5869() {
+        // Test case 1, of which a 32 byte output is the start.
+        let okm = hkdf(
+            &[0x0b; 22],
+            &unhex("000102030405060708090a0b0c"),
+            &unhex("f0f1f2f3f4f5f6f7f8f9"),
+        );
+        assert_eq!(
+            okm[..],
+            unhex("3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf")
+        );
+    }
+
+    #[test]
+    fn transfers_under_one_key_seal_with_their_own_subkey() {
+        let session = SessionKey::generate().unwrap();
+        let (first, second) = (
+            Cipher::new(&session).unwrap(),
+            Cipher::new(&session).unwrap(),
+        );
+        let packet = b"same packet, same sequence number";
+        let (a, b) = (
+            first.seal(SPACE_SENDER, 0, packet),
+            second.seal(SPACE_SENDER, 0, packet),
+        );
+        assert_eq!(a.len(), packet.len() + OVERHEAD);
+        // Same nonce, but no keystream in common.
+        let keystream = |sealed: &[u8]| -> Vec<u8> {
+            sealed[HEADER..HEADER + packet.len()]
+                .iter()
+                .zip(packet)
+                .map(|(c, p)| c ^ p)
+                .collect()
+        };
+        assert_ne!(keystream(&a), keystream(&b));
+
+        let receiver = Cipher::new(&session).unwrap();
+        assert_eq!(receiver.open(&a).unwrap(), packet);
+        assert_eq!(receiver.open(&b).unwrap(), packet);
+        assert_eq!(first.open(&b).unwrap(), packet);
+        assert!(matches!(first.open(&a), Err(CryptoError::Reflected)));
+
+        let mut tampered = a.clone();
+        tampered[HEADER] ^= 1;
+        assert!(matches!(receiver.open(&tampered), Err(CryptoError::BadTag)));
+        let mut forged = a.clone();
+        forged[1] ^= 1;
+        assert!(matches!(receiver.open(&forged), Err(CryptoError::BadTag)));
+        assert!(matches!(
+            receiver.open(&a[..OVERHEAD - 1]),
+            Err(CryptoError::Truncated)
+        ));
+        let other = Cipher::new(&SessionKey::generate().unwrap()).unwrap();
+        assert!(matches!(other.open(&a), Err(CryptoError::BadTag)));
+    }
+}
@@ -0,0 +1,172 @@
+//! Deduplication across transfers: the sender cuts files into chunks where their content
+//! says so, rather than at fixed offsets, so an insertion only changes the chunks around
+//! it. The receiver keeps the chunks it got in a cache, and the sender skips the ones
+//! already there.
+
+use std::{
+    fs::File,
+    io::{self, ErrorKind, Read},
+    path::PathBuf,
+};
+
+use sha2::{Digest, Sha256};
+
+/// Chunks are at least this long, so that a run of cut points does not make tiny ones.
+const MIN_CHUNK: usize = 16 * 1024;
+/// Chunks are cut here whatever their content.
+pub(crate) const MAX_CHUNK: usize = 256 * 1024;
+/// Cut where the top bits of the hash are zero, 64KiB in on average.
+const CUT_MASK: u64 = 0xffff << 48;
+
+/// Random values the gear hash adds for each byte, from splitmix64.
+const GEAR: [u64; 256] = {
+    let mut gear = [0; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+    while i < gear.len() {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        gear[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    gear
+};
+
+/// Chunk of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Chunk {
+    pub len: u32,
+    pub sha256: [u8; 32],
+}
+
+/// Cuts what `reader` holds into chunks.
+pub(crate) fn chunks(mut reader: impl Read) -> io::Result<Vec<Chunk>> {
+    let mut chunks = Vec::new();
+    let mut buf = vec![0; 1024 * 1024];
+    let mut hash = Sha256::new();
+    let (mut gear, mut len) = (0u64, 0usize);
+    loop {
+        let read = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        let mut start = 0;
+        for (i, &byte) in buf[..read].iter().enumerate() {
+            gear = (gear << 1).wrapping_add(GEAR[byte as usize]);
+            len += 1;
+            if (len >= MIN_CHUNK && gear & CUT_MASK == 0) || len == MAX_CHUNK {
+                hash.update(&buf[start..=i]);
+                chunks.push(Chunk {
+                    len: len as u32,
+                    sha256: hash.finalize_reset().into(),
+                });
+                start = i + 1;
+                (gear, len) = (0, 0);
+            }
+        }
+        hash.update(&buf[start..read]);
+    }
+    if len > 0 {
+        chunks.push(Chunk {
+            len: len as u32,
+            sha256: hash.finalize().into(),
+        });
+    }
+    Ok(chunks)
+}
+
+/// Chunks received in previous transfers, a file each, named after their digest.
+#[derive(Debug, Clone)]
+pub(crate) struct ChunkCache {
+    dir: PathBuf,
+}
+
+impl ChunkCache {
+    pub fn new(dir: PathBuf) -> Self {
+        ChunkCache { dir }
+    }
+
+    fn path(&self, sha256: &[u8; 32]) -> PathBuf {
+        let name: String = sha256.iter().map(|byte| format!("{byte:02x}")).collect();
+        self.dir.join(name)
+    }
+
+    pub fn contains(&self, sha256: &[u8; 32]) -> bool {
+        self.path(sha256).is_file()
+    }
+
+    pub fn open(&self, sha256: &[u8; 32]) -> io::Result<File> {
+        File::open(self.path(sha256))
+    }
+
+    /// Keeps `data`, the chunk with digest `sha256`.
+    pub fn store(&self, sha256: &[u8; 32], data: &[u8]) -> io::Result<()> {
+        let path = self.path(sha256);
+        if path.is_file() {
+            return Ok(());
+        }
+        std::fs::create_dir_all(&self.dir)?;
+        // Renamed once written, so a chunk is never seen half written.
+        let written = path.with_extension(format!("{}", std::process::id()));
+        std::fs::write(&written, data)?;
+        std::fs::rename(&written, &path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn noise(len: usize, mut state: u64) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn chunks_follow_the_content() {
+        let data = noise(4 * 1024 * 1024, 1);
+        let before = chunks(data.as_slice()).unwrap();
+        assert_eq!(
+            before.iter().map(|chunk| chunk.len as usize).sum::<usize>(),
+            data.len()
+        );
+        assert!(before.iter().all(|chunk| (chunk.len as usize) <= MAX_CHUNK));
+        assert!(before.len() > 16, "{} chunks", before.len());
+
+        let mut edited = data.clone();
+        edited.splice(1_000_000..1_000_000, noise(1000, 2));
+        let after = chunks(edited.as_slice()).unwrap();
+        // Only the chunks around the insertion change, the cut points after it move along.
+        let changed = after.iter().filter(|chunk| !before.contains(chunk)).count();
+        assert!(changed <= 2, "{changed} chunks changed");
+    }
+
+    #[test]
+    fn cached_chunks_are_found_again() {
+        let dir = TempDir::new("chunks");
+        let cache = ChunkCache::new(dir.join("cache"));
+        let data = noise(1000, 3);
+        let chunk = chunks(data.as_slice()).unwrap()[0];
+        assert!(!cache.contains(&chunk.sha256));
+        cache.store(&chunk.sha256, &data).unwrap();
+        assert!(cache.contains(&chunk.sha256));
+        let mut stored = Vec::new();
+        cache
+            .open(&chunk.sha256)
+            .unwrap()
+            .read_to_end(&mut stored)
+            .unwrap();
+        assert_eq!(stored, data);
+    }
+}
@@ -0,0 +1,605 @@
+//! Delta transfers, rsync style: the receiver sends the checksums of the blocks of its copy
+//! of a file, the sender looks for them at any offset of its version with a rolling
+//! checksum, and sends a delta of copies of those blocks and of the bytes in between.
+//!
+//! The delta starts with the SHA-256 of the file it rebuilds and the block size, then
+//! holds copies of runs of blocks (`0`, first block, count) and literal bytes (`1`,
+//! length, bytes). Chunked deltas, see [`crate::dedup`], refer to the chunks of the
+//! receiver's cache instead (`2`, digest).
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs::{File, OpenOptions},
+    io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU32, Ordering},
+    time::SystemTime,
+};
+
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::{
+    dedup::{Chunk, ChunkCache, MAX_CHUNK},
+    protocol::{BlockSum, Message, MAX_BLOCK_SUMS},
+};
+
+pub(crate) const MIN_BLOCK_SIZE: u32 = 1024;
+pub(crate) const MAX_BLOCK_SIZE: u32 = 128 * 1024;
+const COPY: u8 = 0;
+const LITERAL: u8 = 1;
+const CHUNK: u8 = 2;
+/// Longest literal, which bounds what the encoder buffers.
+const MAX_LITERAL: usize = 1024 * 1024;
+
+/// Blocks of about the square root of the file, so both the checksums and the literal
+/// around a change stay small.
+pub(crate) fn block_size(len: u64) -> u32 {
+    let size = (len as f64).sqrt() as u64 / 64 * 64;
+    size.clamp(MIN_BLOCK_SIZE.into(), MAX_BLOCK_SIZE.into()) as u32
+}
+
+/// rsync's weak checksum, which slides over data a byte at a time.
+#[derive(Debug, Clone, Copy)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(data: &[u8]) -> Self {
+        let len = data.len() as u32;
+        let (mut a, mut b) = (0u32, 0u32);
+        for (i, &byte) in data.iter().enumerate() {
+            a = a.wrapping_add(byte.into());
+            b = b.wrapping_add((len - i as u32).wrapping_mul(byte.into()));
+        }
+        Rolling { a, b, len }
+    }
+
+    /// Slides the window a byte further, `out` leaving it and `next` entering it.
+    fn roll(&mut self, out: u8, next: u8) {
+        self.a = self.a.wrapping_sub(out.into()).wrapping_add(next.into());
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out.into()))
+            .wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn strong(block: &[u8]) -> [u8; 16] {
+    let mut strong = [0; 16];
+    strong.copy_from_slice(&Sha256::digest(block)[..16]);
+    strong
+}
+
+/// Checksums of the blocks of a file we have a copy of.
+#[derive(Debug)]
+pub(crate) struct Signature {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    len: u64,
+    block_size: u32,
+    sums: Vec<BlockSum>,
+}
+
+impl Signature {
+    /// Checksums the file at `path`, None if there is no such file.
+    pub fn of(path: &Path) -> io::Result<Option<Self>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let metadata = file.metadata()?;
+        if !metadata.is_file() {
+            return Ok(None);
+        }
+        let block_size = block_size(metadata.len());
+        let mut reader = BufReader::new(file);
+        let mut block = vec![0; block_size as usize];
+        let mut sums = Vec::new();
+        loop {
+            let read = read_full(&mut reader, &mut block)?;
+            if read == 0 {
+                break;
+            }
+            sums.push(BlockSum {
+                weak: Rolling::new(&block[..read]).digest(),
+                strong: strong(&block[..read]),
+            });
+        }
+        Ok(Some(Signature {
+            path: path.to_path_buf(),
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+            block_size,
+            sums,
+        }))
+    }
+
+    /// Whether it is still the one of the file at `path`.
+    pub fn is_current(&self, path: &Path) -> bool {
+        self.path == path
+            && std::fs::metadata(path).is_ok_and(|metadata| {
+                metadata.len() == self.len && metadata.modified().ok() == self.modified
+            })
+    }
+
+    /// Blocks answering a Signatures from block `first` on.
+    pub fn blocks(&self, first: u32) -> Message {
+        let first = (first as usize).min(self.sums.len());
+        let last = (first + MAX_BLOCK_SUMS).min(self.sums.len());
+        Message::Blocks {
+            first: first as u32,
+            total: self.sums.len() as u32,
+            block_size: self.block_size,
+            sums: self.sums[first..last].to_vec(),
+        }
+    }
+}
+
+/// Fills `buf` as much as the reader allows, returning how much it read.
+pub(crate) fn read_full(reader: &mut (impl Read + ?Sized), buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(read)
+}
+
+/// Delta the sender stages in the temporary directory, removed once dropped.
+pub(crate) struct DeltaFile {
+    path: PathBuf,
+    pub file: File,
+}
+
+impl DeltaFile {
+    pub fn create() -> io::Result<Self> {
+        static COUNT: AtomicU32 = AtomicU32::new(0);
+        let count = COUNT.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("sanic-delta-{}-{count}", std::process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        Ok(DeltaFile { path, file })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for DeltaFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// What a delta is made of.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Encoded {
+    /// Bytes of the file the receiver already has, in its copy or in its cache.
+    pub copied: u64,
+    /// Bytes of the file sent as they are.
+    pub literal: u64,
+}
+
+/// Writes to `out` the delta rebuilding `new` from the receiver's copy, whose blocks of
+/// `block_size` bytes have the checksums `sums`.
+pub(crate) fn encode(
+    sums: &[BlockSum],
+    block_size: u32,
+    new: impl Read,
+    mut out: impl Write + Seek,
+) -> io::Result<Encoded> {
+    let block_size = block_size as usize;
+    // The last block may be short, and would only match at the very end of the file.
+    let mut index: HashMap<u32, Vec<(u32, [u8; 16])>> = HashMap::new();
+    for (i, sum) in sums.iter().enumerate().take(sums.len().saturating_sub(1)) {
+        index
+            .entry(sum.weak)
+            .or_default()
+            .push((i as u32, sum.strong));
+    }
+    let find = |window: &[u8], weak: u32| {
+        let candidates = index.get(&weak)?;
+        let strong = strong(window);
+        candidates
+            .iter()
+            .find(|(_, sum)| *sum == strong)
+            .map(|(i, _)| *i)
+    };
+
+    let start = out.stream_position()?;
+    out.write_all(&[0; 32])?;
+    out.write_all(&(block_size as u32).to_be_bytes())?;
+    let mut writer = Writer {
+        out: BufWriter::new(&mut out),
+        run: None,
+        encoded: Encoded::default(),
+    };
+    let mut reader = new;
+    let mut hash = Sha256::new();
+    let mut buf: Vec<u8> = Vec::new();
+    let mut chunk = vec![0; MAX_LITERAL];
+    let mut eof = false;
+    // Window start, and start of the bytes no block matched.
+    let (mut pos, mut literal) = (0, 0);
+    let mut rolling: Option<Rolling> = None;
+    loop {
+        while !eof && buf.len() <= pos + block_size {
+            match reader.read(&mut chunk)? {
+                0 => eof = true,
+                read => {
+                    hash.update(&chunk[..read]);
+                    buf.extend(&chunk[..read]);
+                }
+            }
+        }
+        if buf.len() - pos < block_size {
+            break;
+        }
+        let window = &buf[pos..pos + block_size];
+        let current = rolling.get_or_insert_with(|| Rolling::new(window));
+        if let Some(block) = find(window, current.digest()) {
+            writer.literal(&buf[literal..pos])?;
+            writer.copy(block, block_size)?;
+            pos += block_size;
+            literal = pos;
+            rolling = None;
+        } else if buf.len() == pos + block_size {
+            break;
+        } else {
+            current.roll(buf[pos], buf[pos + block_size]);
+            pos += 1;
+            if pos - literal == MAX_LITERAL {
+                writer.literal(&buf[literal..pos])?;
+                literal = pos;
+            }
+        }
+        // Keep the buffer from growing with the file.
+        if literal >= MAX_LITERAL {
+            buf.drain(..literal);
+            pos -= literal;
+            literal = 0;
+        }
+    }
+    writer.literal(&buf[literal..])?;
+    let encoded = writer.finish()?;
+    out.seek(SeekFrom::Start(start))?;
+    out.write_all(&hash.finalize())?;
+    out.seek(SeekFrom::End(0))?;
+    Ok(encoded)
+}
+
+/// Writes to `out` the delta of `file`, cut in `chunks`, where the chunks at the indices
+/// in `cached` refer to the receiver's cache.
+pub(crate) fn encode_chunks(
+    chunks: &[Chunk],
+    cached: &BTreeSet<u32>,
+    mut file: impl Read,
+    mut out: impl Write + Seek,
+) -> io::Result<Encoded> {
+    let start = out.stream_position()?;
+    out.write_all(&[0; 32])?;
+    // No blocks to copy from.
+    out.write_all(&0u32.to_be_bytes())?;
+    let mut writer = Writer {
+        out: BufWriter::new(&mut out),
+        run: None,
+        encoded: Encoded::default(),
+    };
+    let mut hash = Sha256::new();
+    let mut buf = vec![0; MAX_CHUNK];
+    for (i, chunk) in chunks.iter().enumerate() {
+        let data = &mut buf[..chunk.len as usize];
+        if read_full(&mut file, data)? < data.len() {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "the file shrank while sending it",
+            ));
+        }
+        hash.update(&*data);
+        if cached.contains(&(i as u32)) {
+            if Sha256::digest(&*data)[..] != chunk.sha256 {
+                return Err(io::Error::other("the file changed while sending it"));
+            }
+            writer.cached(chunk)?;
+        } else {
+            writer.literal(data)?;
+        }
+    }
+    let encoded = writer.finish()?;
+    out.seek(SeekFrom::Start(start))?;
+    out.write_all(&hash.finalize())?;
+    out.seek(SeekFrom::End(0))?;
+    Ok(encoded)
+}
+
+/// Writes the operations of a delta, merging copies of consecutive blocks.
+struct Writer<W: Write> {
+    out: W,
+    /// First block and count of the copy being extended.
+    run: Option<(u32, u32)>,
+    encoded: Encoded,
+}
+
+impl<W: Write> Writer<W> {
+    fn copy(&mut self, block: u32, block_size: usize) -> io::Result<()> {
+        self.encoded.copied += block_size as u64;
+        match &mut self.run {
+            Some((first, count)) if *first + *count == block => *count += 1,
+            _ => {
+                self.flush_run()?;
+                self.run = Some((block, 1));
+            }
+        }
+        Ok(())
+    }
+
+    fn literal(&mut self, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.flush_run()?;
+        self.encoded.literal += data.len() as u64;
+        self.out.write_all(&[LITERAL])?;
+        self.out.write_all(&(data.len() as u32).to_be_bytes())?;
+        self.out.write_all(data)
+    }
+
+    fn cached(&mut self, chunk: &Chunk) -> io::Result<()> {
+        self.flush_run()?;
+        self.encoded.copied += u64::from(chunk.len);
+        self.out.write_all(&[CHUNK])?;
+        self.out.write_all(&chunk.sha256)
+    }
+
+    fn flush_run(&mut self) -> io::Result<()> {
+        if let Some((first, count)) = self.run.take() {
+            self.out.write_all(&[COPY])?;
+            self.out.write_all(&first.to_be_bytes())?;
+            self.out.write_all(&count.to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<Encoded> {
+        self.flush_run()?;
+        self.out.flush()?;
+        Ok(self.encoded)
+    }
+}
+
+/// Rebuilds into `out` the file the `delta` describes, from our copy `basis` and the
+/// chunks of our `cache`, and checks it is the one the sender has. The literal bytes of a
+/// chunked delta are chunks, kept in the cache for the next transfers.
+pub(crate) fn apply(
+    mut basis: impl Read + Seek,
+    cache: Option<&ChunkCache>,
+    delta: impl Read,
+    out: impl Write,
+) -> io::Result<()> {
+    let invalid = |reason: &str| io::Error::new(ErrorKind::InvalidData, reason.to_string());
+    let mut delta = BufReader::new(delta);
+    let mut out = BufWriter::new(out);
+    let mut expected = [0; 32];
+    delta.read_exact(&mut expected)?;
+    let mut word = [0; 4];
+    delta.read_exact(&mut word)?;
+    let block_size = u64::from(u32::from_be_bytes(word));
+    let mut hash = Sha256::new();
+    let mut buf = vec![0; MAX_LITERAL];
+    let mut op = [0; 1];
+    loop {
+        if delta.read(&mut op)? == 0 {
+            break;
+        }
+        match op[0] {
+            COPY => {
+                delta.read_exact(&mut word)?;
+                let first = u64::from(u32::from_be_bytes(word));
+                delta.read_exact(&mut word)?;
+                let len = u64::from(u32::from_be_bytes(word)) * block_size;
+                basis.seek(SeekFrom::Start(first * block_size))?;
+                if pipe(&mut basis, len, &mut buf, &mut hash, &mut out)? < len {
+                    return Err(invalid("the delta refers past the end of the file"));
+                }
+            }
+            LITERAL => {
+                delta.read_exact(&mut word)?;
+                let len = u64::from(u32::from_be_bytes(word));
+                if pipe(&mut delta, len, &mut buf, &mut hash, &mut out)? < len {
+                    return Err(invalid("the delta ends in the middle of a literal"));
+                }
+                if let Some(cache) = cache.filter(|_| len as usize <= MAX_CHUNK) {
+                    let chunk = &buf[..len as usize];
+                    if let Err(err) = cache.store(&Sha256::digest(chunk).into(), chunk) {
+                        warn!(error = %err, "Could not cache a chunk.");
+                    }
+                }
+            }
+            CHUNK => {
+                let mut sha256 = [0; 32];
+                delta.read_exact(&mut sha256)?;
+                let cache = cache.ok_or_else(|| invalid("the delta refers to cached chunks"))?;
+                pipe(
+                    &mut cache.open(&sha256)?,
+                    u64::MAX,
+                    &mut buf,
+                    &mut hash,
+                    &mut out,
+                )?;
+            }
+            _ => return Err(invalid("unknown operation in the delta")),
+        }
+    }
+    out.flush()?;
+    if hash.finalize()[..] != expected {
+        return Err(invalid("the rebuilt file does not match the sender's"));
+    }
+    Ok(())
+}
+
+/// Copies up to `len` bytes from `source` to `out` through `buf`, hashing them, and
+/// returns how many there were.
+fn pipe(
+    source: &mut impl Read,
+    len: u64,
+    buf: &mut [u8],
+    hash: &mut Sha256,
+    out: &mut impl Write,
+) -> io::Result<u64> {
+    let mut done = 0;
+    while done < len {
+        let take = (len - done).min(buf.len() as u64) as usize;
+        let read = read_full(source, &mut buf[..take])?;
+        hash.update(&buf[..read]);
+        out.write_all(&buf[..read])?;
+        done += read as u64;
+        if read < take {
+            break;
+        }
+    }
+    Ok(done)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dedup;
+    use crate::testing::TempDir;
+    use std::io::Cursor;
+
+    /// Bytes that do not repeat, so only the blocks we mean to share match.
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    fn sums(basis: &[u8], block_size: u32) -> Vec<BlockSum> {
+        basis
+            .chunks(block_size as usize)
+            .map(|block| BlockSum {
+                weak: Rolling::new(block).digest(),
+                strong: strong(block),
+            })
+            .collect()
+    }
+
+    fn round_trip(basis: &[u8], new: &[u8], block_size: u32) -> Encoded {
+        let mut delta = Cursor::new(Vec::new());
+        let encoded = encode(&sums(basis, block_size), block_size, new, &mut delta).unwrap();
+        let mut rebuilt = Vec::new();
+        apply(
+            Cursor::new(basis),
+            None,
+            delta.get_ref().as_slice(),
+            &mut rebuilt,
+        )
+        .unwrap();
+        assert_eq!(rebuilt, new);
+        assert_eq!(encoded.copied + encoded.literal, new.len() as u64);
+        encoded
+    }
+
+    #[test]
+    fn rolling_matches_a_fresh_checksum() {
+        let data = noise(300, 1);
+        let mut rolling = Rolling::new(&data[..100]);
+        for start in 1..200 {
+            rolling.roll(data[start - 1], data[start + 99]);
+            assert_eq!(
+                rolling.digest(),
+                Rolling::new(&data[start..start + 100]).digest()
+            );
+        }
+    }
+
+    #[test]
+    fn deltas_only_carry_what_changed() {
+        let basis = noise(64 * 1024, 2);
+        let mut new = basis.clone();
+        // An insertion shifts everything after it, which the rolling checksum follows.
+        new.splice(10_000..10_000, noise(100, 3));
+        new[40_000] ^= 0xff;
+        let encoded = round_trip(&basis, &new, 1024);
+        assert!(encoded.literal < 4 * 1024, "{encoded:?}");
+    }
+
+    #[test]
+    fn deltas_rebuild_any_file() {
+        let basis = noise(10_000, 4);
+        round_trip(&basis, &[], 1024);
+        round_trip(&[], &basis, 1024);
+        round_trip(&basis, &noise(3 * MAX_LITERAL + 17, 5), 1024);
+        let encoded = round_trip(&basis, &basis, 1024);
+        // The last block goes as it is.
+        assert_eq!(encoded.literal, 10_000 % 1024);
+    }
+
+    #[test]
+    fn chunked_deltas_use_and_fill_the_cache() {
+        let dir = TempDir::new("delta-cache");
+        let cache = ChunkCache::new(dir.join("cache"));
+        let old = noise(2 * 1024 * 1024, 7);
+        let mut new = old.clone();
+        new.splice(700_000..700_000, noise(1000, 8));
+
+        for (file, least_cached) in [(&old, 0), (&new, 3 * 512 * 1024)] {
+            let chunks = dedup::chunks(file.as_slice()).unwrap();
+            let cached: BTreeSet<u32> = (0..chunks.len() as u32)
+                .filter(|&i| cache.contains(&chunks[i as usize].sha256))
+                .collect();
+            let mut delta = Cursor::new(Vec::new());
+            let encoded = encode_chunks(&chunks, &cached, file.as_slice(), &mut delta).unwrap();
+            assert!(encoded.copied >= least_cached, "{encoded:?}");
+            let mut rebuilt = Vec::new();
+            apply(
+                io::empty(),
+                Some(&cache),
+                delta.get_ref().as_slice(),
+                &mut rebuilt,
+            )
+            .unwrap();
+            assert_eq!(&rebuilt, file);
+        }
+    }
+
+    #[test]
+    fn deltas_against_another_copy_fail() {
+        let basis = noise(8 * 1024, 6);
+        let mut delta = Cursor::new(Vec::new());
+        encode(&sums(&basis, 1024), 1024, basis.as_slice(), &mut delta).unwrap();
+        let mut other = basis.clone();
+        other[0] ^= 1;
+        let err = apply(
+            Cursor::new(other),
+            None,
+            delta.get_ref().as_slice(),
+            Vec::new(),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
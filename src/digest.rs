@@ -0,0 +1,154 @@
+//! Digests of files, read ahead of the hashing or followed as they are written.
+
+use std::{
+    fs::File,
+    io::{self, ErrorKind, Read},
+    path::Path,
+    sync::mpsc,
+    thread::JoinHandle,
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::spawn;
+
+/// Bytes read at once when hashing files.
+const CHUNK: usize = 1024 * 1024;
+/// Chunks read ahead of the one being hashed.
+const READ_AHEAD: usize = 2;
+
+/// Digest of the content of the file at `path`. The next chunks are read on a thread of
+/// their own while one is hashed, so the disk and the hashing go at once.
+pub(crate) fn file_digest(path: &Path) -> io::Result<[u8; 32]> {
+    let mut file = File::open(path)?;
+    let (full_tx, full) = mpsc::sync_channel(READ_AHEAD);
+    // Buffers go back and forth rather than being allocated for every chunk.
+    let (empty_tx, empty) = mpsc::channel();
+    for _ in 0..=READ_AHEAD {
+        empty_tx
+            .send(vec![0; CHUNK])
+            .expect("The receiver is right here");
+    }
+    std::thread::scope(|scope| {
+        scope.spawn(move || {
+            for mut buf in empty {
+                buf.resize(CHUNK, 0);
+                match read(&mut file, &mut buf) {
+                    Ok(0) => break,
+                    Ok(read) => {
+                        buf.truncate(read);
+                        // The hashing failed.
+                        if full_tx.send(Ok(buf)).is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        let _ = full_tx.send(Err(err));
+                        break;
+                    }
+                }
+            }
+        });
+        let mut hash = Sha256::new();
+        for buf in full {
+            let buf = buf?;
+            hash.update(&buf);
+            let _ = empty_tx.send(buf);
+        }
+        Ok(hash.finalize().into())
+    })
+}
+
+fn read(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        match file.read(buf) {
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            read => return read,
+        }
+    }
+}
+
+/// Hashes a file as it is written, on a thread of its own: the bytes written so far are
+/// read back while they are still in the page cache, so the digest is ready about when the
+/// last part is.
+pub(crate) struct Follower {
+    written: mpsc::Sender<u64>,
+    thread: JoinHandle<io::Result<([u8; 32], u64)>>,
+}
+
+impl Follower {
+    pub fn new(path: &Path) -> io::Result<Self> {
+        // A cursor of our own, the writer may move its one around.
+        let mut file = File::open(path)?;
+        let (written, reports) = mpsc::channel::<u64>();
+        let thread = spawn(move || {
+            let mut hash = Sha256::new();
+            let mut hashed = 0;
+            let mut buf = vec![0; CHUNK];
+            // Ends once every writer is done.
+            while let Ok(mut below) = reports.recv() {
+                below = reports.try_iter().fold(below, u64::max);
+                while hashed < below {
+                    let len = (below - hashed).min(CHUNK as u64) as usize;
+                    match read(&mut file, &mut buf[..len])? {
+                        // Past the end of the file, until it is sized.
+                        0 => break,
+                        read => {
+                            hash.update(&buf[..read]);
+                            hashed += read as u64;
+                        }
+                    }
+                }
+            }
+            Ok((hash.finalize().into(), hashed))
+        });
+        Ok(Follower { written, thread })
+    }
+
+    /// Where the writer reports that every byte below is written.
+    pub fn reports(&self) -> mpsc::Sender<u64> {
+        self.written.clone()
+    }
+
+    /// Digest of the file, once every writer dropped its reports, provided that the `len`
+    /// bytes of the file were hashed.
+    pub fn finish(self, len: u64) -> io::Result<[u8; 32]> {
+        // A thread that stopped says why once joined.
+        let _ = self.written.send(len);
+        drop(self.written);
+        let (digest, hashed) = self
+            .thread
+            .join()
+            .map_err(|_| io::Error::other("the hashing thread panicked"))??;
+        if hashed != len {
+            return Err(io::Error::other(format!(
+                "hashed {hashed} bytes of a file of {len}"
+            )));
+        }
+        Ok(digest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn files_hash_the_same_however_they_are_read() {
+        let dir = TempDir::new("digest");
+        let path = dir.join("file.bin");
+        let data: Vec<u8> = (0..3 * CHUNK + 12_345).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        let expected: [u8; 32] = Sha256::digest(&data).into();
+        assert_eq!(file_digest(&path).unwrap(), expected);
+
+        let follower = Follower::new(&path).unwrap();
+        let reports = follower.reports();
+        for below in [10, 5, CHUNK as u64 + 7, 4 * CHUNK as u64] {
+            reports.send(below).unwrap();
+        }
+        drop(reports);
+        assert_eq!(follower.finish(data.len() as u64).unwrap(), expected);
+    }
+}
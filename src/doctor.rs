@@ -0,0 +1,494 @@
+//! Checks of the path to a receiver, for `sanic doctor`: whether it answers probes, the
+//! largest datagram that gets there unfragmented, the kernel limits on socket buffers, the
+//! NAT we are behind as far as our gateway tells, and the throughput of a short speed test.
+//! What looks wrong comes with [`Diagnosis::suggestions`].
+
+use std::{
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use tracing::debug;
+
+use crate::{
+    client::MAX_TUNED_BUFFER,
+    portmap::{Method, PortMapping},
+    probe::PROBE_SIZE,
+    protocol::Message,
+    speedtest::{Report, SpeedTest, SpeedTestError},
+    SendError, MTU,
+};
+
+/// Share of lost parts past which a speed test calls for a gentler rate.
+const HIGH_LOSS: f64 = 0.02;
+
+/// Checkup of the path to the receiver at an address.
+///
+/// ```no_run
+/// let diagnosis = sanic::doctor::Doctor::new("192.168.1.20:6666").run()?;
+/// for suggestion in diagnosis.suggestions() {
+///     println!("{suggestion}");
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct Doctor {
+    addr: String,
+    timeout: Duration,
+    speedtest: Duration,
+}
+
+#[derive(Debug)]
+pub struct Diagnosis {
+    pub addr: SocketAddr,
+    /// Our address on the way to the receiver.
+    pub local: SocketAddr,
+    pub reach: Reach,
+    /// Time the first answer to a probe took.
+    pub rtt: Option<Duration>,
+    /// Largest datagram that got an answer without being fragmented. None when the receiver
+    /// did not answer, or we could not forbid fragmentation.
+    pub path_mtu: Option<usize>,
+    pub buffers: Option<KernelBuffers>,
+    pub nat: Nat,
+    /// Speed test of both ways, when the receiver answered and one was asked for.
+    pub throughput: Option<Result<Report, SpeedTestError>>,
+}
+
+/// How the receiver answered probes, which idle receivers do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reach {
+    /// Its port is reachable from us and its answers reach us back.
+    Answered {
+        hostname: String,
+        free_bytes: Option<u64>,
+    },
+    /// The host told us nothing listens on the port.
+    Refused,
+    /// Nothing came back: a firewall drops the datagrams, or the receiver is busy.
+    Silent,
+}
+
+/// Caps of the kernel on the socket buffers programs can ask for, on Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelBuffers {
+    /// net.core.rmem_max
+    pub rmem_max: usize,
+    /// net.core.wmem_max
+    pub wmem_max: usize,
+}
+
+/// Network address translation between us and the receiver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Nat {
+    /// Our address is public.
+    None,
+    /// The receiver is on a private network with us.
+    Local,
+    /// Behind a gateway that forwards ports when asked, from this public address.
+    Mappable {
+        external: SocketAddr,
+        method: Method,
+    },
+    /// Behind a gateway that does not forward ports when asked.
+    Unmappable,
+}
+
+impl Doctor {
+    pub fn new(addr: impl Into<String>) -> Self {
+        Doctor {
+            addr: addr.into(),
+            timeout: Duration::from_secs(2),
+            speedtest: Duration::from_secs(3),
+        }
+    }
+
+    /// How long to wait for the receiver to answer a probe.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How long to test the throughput each way, zero to leave it out.
+    pub fn speedtest(mut self, duration: Duration) -> Self {
+        self.speedtest = duration;
+        self
+    }
+
+    pub fn run(&self) -> io::Result<Diagnosis> {
+        let addr = self.addr.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(ErrorKind::NotFound, format!("no address for {}", self.addr))
+        })?;
+        let socket = UdpSocket::bind(match addr {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        })?;
+        // Connected, so that the host telling us the port is closed shows up.
+        socket.connect(addr)?;
+        let local = socket.local_addr()?;
+
+        let (reach, rtt) = reach(&socket, self.timeout)?;
+        let answered = matches!(reach, Reach::Answered { .. });
+        let path_mtu = match rtt {
+            Some(rtt) if answered => path_mtu(
+                &socket,
+                (rtt * 4).clamp(Duration::from_millis(100), self.timeout),
+            )?,
+            _ => None,
+        };
+        let throughput = (answered && !self.speedtest.is_zero()).then(|| {
+            SpeedTest::new(addr.to_string())
+                .duration(self.speedtest)
+                .connect_timeout(self.timeout)
+                .run()
+        });
+        Ok(Diagnosis {
+            addr,
+            local,
+            reach,
+            rtt,
+            path_mtu,
+            buffers: kernel_buffers(),
+            nat: nat(local.ip(), addr.ip()),
+            throughput,
+        })
+    }
+}
+
+impl Diagnosis {
+    /// What to change, about everything that looks wrong.
+    pub fn suggestions(&self) -> Vec<String> {
+        let mut suggestions = Vec::new();
+        let port = self.addr.port();
+        match &self.reach {
+            Reach::Answered { .. } => {}
+            Reach::Refused => suggestions.push(format!(
+                "Nothing listens on UDP port {port} of {}: start `sanic receive` there.",
+                self.addr.ip()
+            )),
+            Reach::Silent => suggestions.push(format!(
+                "No answer from {}: open UDP port {port} in the firewalls on the way, or try \
+                 again once the receiver is done with its transfer.",
+                self.addr
+            )),
+        }
+        if let Some(mtu) = self.path_mtu.filter(|&mtu| mtu < MTU) {
+            suggestions.push(format!(
+                "Datagrams over {mtu} bytes get fragmented on the way, and sanic sends {MTU}: \
+                 every lost fragment loses a part. Look for a tunnel or VPN lowering the MTU."
+            ));
+        }
+        if let Some(buffers) = self.buffers {
+            let low: Vec<_> = [
+                ("net.core.rmem_max", buffers.rmem_max),
+                ("net.core.wmem_max", buffers.wmem_max),
+            ]
+            .into_iter()
+            .filter(|&(_, max)| max < MAX_TUNED_BUFFER)
+            .map(|(name, _)| format!("{name}={MAX_TUNED_BUFFER}"))
+            .collect();
+            if !low.is_empty() {
+                suggestions.push(format!(
+                    "The kernel caps the socket buffers below what fast transfers need: raise \
+                     them with `sysctl -w {}`.",
+                    low.join(" ")
+                ));
+            }
+        }
+        match self.nat {
+            Nat::Mappable { external, .. } if is_private(external.ip()) => suggestions.push(
+                "The gateway's public address is private too, another NAT is in the way: \
+                 receive here with --code and --relay to be reachable from the internet."
+                    .to_string(),
+            ),
+            Nat::Mappable { .. } => suggestions.push(
+                "We are behind a NAT: receive here with --upnp to be reachable from the internet."
+                    .to_string(),
+            ),
+            Nat::Unmappable => suggestions.push(format!(
+                "We are behind a NAT that does not forward ports when asked: forward UDP port \
+                 {port} on the router, or receive here with --code and --relay."
+            )),
+            Nat::None | Nat::Local => {}
+        }
+        match &self.throughput {
+            Some(Ok(report)) => {
+                for (direction, way) in [("Uploads", report.upload), ("Downloads", report.download)]
+                {
+                    if way.loss() > HIGH_LOSS {
+                        suggestions.push(format!(
+                            "{direction} lost {:.1}% of the parts: send with --cc bbr, or cap \
+                             the receiver with --rate-limit.",
+                            way.loss() * 100.0
+                        ));
+                    }
+                }
+            }
+            Some(Err(SpeedTestError::Upload(SendError::Aborted(_)))) => suggestions.push(
+                "The receiver does not take speed tests: start it with --speedtest to measure \
+                 the throughput."
+                    .to_string(),
+            ),
+            Some(Err(err)) => suggestions.push(format!("The speed test failed: {err}.")),
+            None => {}
+        }
+        suggestions
+    }
+}
+
+/// Probes the receiver a few times over `timeout`.
+fn reach(socket: &UdpSocket, timeout: Duration) -> io::Result<(Reach, Option<Duration>)> {
+    let wait = (timeout / 3).max(Duration::from_millis(1));
+    for _ in 0..3 {
+        let sent = Instant::now();
+        match exchange(socket, PROBE_SIZE, wait) {
+            Ok(Some(Message::Presence {
+                hostname,
+                free_bytes,
+            })) => {
+                let reach = Reach::Answered {
+                    hostname,
+                    free_bytes,
+                };
+                return Ok((reach, Some(sent.elapsed())));
+            }
+            Ok(_) => {}
+            Err(err) if err.kind() == ErrorKind::ConnectionRefused => {
+                return Ok((Reach::Refused, None))
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok((Reach::Silent, None))
+}
+
+/// Largest probe answered with fragmentation forbidden, searched between the smallest
+/// probe and our datagrams.
+fn path_mtu(socket: &UdpSocket, wait: Duration) -> io::Result<Option<usize>> {
+    if let Err(err) = dont_fragment(socket) {
+        debug!(error = ?err, "Cannot forbid fragmentation, the path MTU is not measured.");
+        return Ok(None);
+    }
+    let passes = |size| match exchange(socket, size, wait) {
+        Ok(answer) => Ok(answer.is_some()),
+        // Too large for our interface, or for a router on the way that told us.
+        Err(err) if err.kind() != ErrorKind::ConnectionRefused => Ok(false),
+        Err(err) => Err(err),
+    };
+    if passes(MTU)? || passes(MTU)? {
+        return Ok(Some(MTU));
+    }
+    let (mut low, mut high) = (PROBE_SIZE, MTU);
+    while high - low > 1 {
+        let mid = (low + high) / 2;
+        if passes(mid)? || passes(mid)? {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    Ok(Some(low))
+}
+
+/// Sends a probe padded to `size` and waits up to `wait` for the answer.
+fn exchange(socket: &UdpSocket, size: usize, wait: Duration) -> io::Result<Option<Message>> {
+    // Answers to earlier probes that came in late must not count for this one.
+    socket.set_nonblocking(true)?;
+    let mut buf = vec![0; MTU];
+    while socket.recv(&mut buf).is_ok() {}
+    socket.set_nonblocking(false)?;
+
+    let mut probe = Message::Probe.serialize();
+    probe.resize(size, 0);
+    socket.send(&probe)?;
+    let deadline = Instant::now() + wait;
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Ok(None);
+        }
+        socket.set_read_timeout(Some(left))?;
+        match socket.recv(&mut buf) {
+            Ok(size) => {
+                if let Ok(answer @ Message::Presence { .. }) = Message::parse(&buf[..size]) {
+                    return Ok(Some(answer));
+                }
+            }
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Has the kernel send our datagrams with the Don't Fragment bit, whatever it thinks the
+/// path MTU is, and fail to send the ones too large for the interface.
+#[cfg(all(target_os = "linux", feature = "pmtu"))]
+fn dont_fragment(socket: &UdpSocket) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let (level, name, value) = match socket.local_addr()? {
+        SocketAddr::V4(_) => (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_PROBE,
+        ),
+        SocketAddr::V6(_) => (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_PROBE,
+        ),
+    };
+    // SAFETY: `value` is valid for the duration of the call.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(all(target_os = "linux", feature = "pmtu")))]
+fn dont_fragment(_socket: &UdpSocket) -> io::Result<()> {
+    Err(ErrorKind::Unsupported.into())
+}
+
+fn kernel_buffers() -> Option<KernelBuffers> {
+    let read = |name| {
+        std::fs::read_to_string(format!("/proc/sys/net/core/{name}"))
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+    };
+    Some(KernelBuffers {
+        rmem_max: read("rmem_max")?,
+        wmem_max: read("wmem_max")?,
+    })
+}
+
+/// What NAT is between `local` and `peer`. Asks the gateway for a port mapping, which it
+/// removes right away, when we are on a private network and the receiver is not.
+fn nat(local: IpAddr, peer: IpAddr) -> Nat {
+    if !is_private(local) {
+        return Nat::None;
+    }
+    if is_private(peer) {
+        return Nat::Local;
+    }
+    let mapping = UdpSocket::bind((local, 0))
+        .and_then(|socket| socket.local_addr())
+        .and_then(|addr| PortMapping::start(addr.port()));
+    match mapping {
+        Ok(mapping) => Nat::Mappable {
+            external: mapping.external(),
+            method: mapping.method(),
+        },
+        Err(err) => {
+            debug!(error = ?err, "Could not map a port on the gateway.");
+            Nat::Unmappable
+        }
+    }
+}
+
+/// Whether `ip` is only routed within a private network: RFC 1918, shared address space
+/// of carrier-grade NATs, loopback, link-local and IPv6 unique local addresses.
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || (ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64)
+        }
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unique_local() || ip.is_unicast_link_local(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Receiver;
+
+    #[test]
+    fn receivers_on_loopback_are_reachable_and_local() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let receiver = Receiver::new().transport(socket);
+        std::thread::spawn(move || receiver.receive());
+
+        let diagnosis = Doctor::new(addr.to_string())
+            .speedtest(Duration::ZERO)
+            .run()
+            .unwrap();
+        assert!(matches!(diagnosis.reach, Reach::Answered { .. }));
+        assert!(diagnosis.rtt.is_some());
+        assert_eq!(diagnosis.nat, Nat::Local);
+        if let Some(mtu) = diagnosis.path_mtu {
+            assert_eq!(mtu, MTU);
+        }
+    }
+
+    #[test]
+    fn closed_ports_are_told_apart() {
+        let addr = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let diagnosis = Doctor::new(addr.to_string())
+            .timeout(Duration::from_millis(300))
+            .run()
+            .unwrap();
+        assert_eq!(diagnosis.reach, Reach::Refused);
+        assert!(diagnosis.suggestions()[0].starts_with("Nothing listens on UDP port"));
+    }
+
+    #[test]
+    fn suggestions_follow_what_is_wrong() {
+        let diagnosis = Diagnosis {
+            addr: "203.0.113.7:6666".parse().unwrap(),
+            local: "192.168.1.2:40000".parse().unwrap(),
+            reach: Reach::Answered {
+                hostname: "nas".to_string(),
+                free_bytes: None,
+            },
+            rtt: Some(Duration::from_millis(20)),
+            path_mtu: Some(1400),
+            buffers: Some(KernelBuffers {
+                rmem_max: 212992,
+                wmem_max: MAX_TUNED_BUFFER,
+            }),
+            nat: Nat::Unmappable,
+            throughput: None,
+        };
+        let suggestions = diagnosis.suggestions();
+        assert_eq!(suggestions.len(), 3, "{suggestions:?}");
+        assert!(suggestions[0].starts_with("Datagrams over 1400 bytes"));
+        assert!(suggestions[1].ends_with(&format!(
+            "`sysctl -w net.core.rmem_max={MAX_TUNED_BUFFER}`."
+        )));
+        assert!(suggestions[2].contains("forward UDP port 6666"));
+    }
+
+    #[test]
+    fn private_addresses_are_recognized() {
+        for ip in [
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.0.1",
+            "100.64.0.1",
+            "fd00::1",
+        ] {
+            assert!(is_private(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in ["8.8.8.8", "100.128.0.1", "2001:db8::1"] {
+            assert!(!is_private(ip.parse().unwrap()), "{ip}");
+        }
+    }
+}
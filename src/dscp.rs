@@ -0,0 +1,140 @@
+//! DiffServ code points, which mark our datagrams so QoS setups can tell sanic traffic
+//! apart: `CS1` for background transfers, `AF41` for priority ones.
+
+use std::{fmt, str::FromStr};
+
+/// DiffServ code point, the upper six bits of the IPv4 TOS and IPv6 traffic class.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dscp(u8);
+
+/// Names of RFC 2474, 2597 and 3246, along with their code points.
+const NAMES: [(&str, u8); 21] = [
+    ("CS0", 0),
+    ("CS1", 8),
+    ("AF11", 10),
+    ("AF12", 12),
+    ("AF13", 14),
+    ("CS2", 16),
+    ("AF21", 18),
+    ("AF22", 20),
+    ("AF23", 22),
+    ("CS3", 24),
+    ("AF31", 26),
+    ("AF32", 28),
+    ("AF33", 30),
+    ("CS4", 32),
+    ("AF41", 34),
+    ("AF42", 36),
+    ("AF43", 38),
+    ("CS5", 40),
+    ("EF", 46),
+    ("CS6", 48),
+    ("CS7", 56),
+];
+
+impl Dscp {
+    /// Code point `value`, None past the six bits it fits in.
+    pub fn new(value: u8) -> Option<Self> {
+        (value < 64).then_some(Dscp(value))
+    }
+
+    pub fn value(self) -> u8 {
+        self.0
+    }
+
+    /// Byte of the IPv4 TOS and IPv6 traffic class, where the code point leaves the two
+    /// lower bits to ECN.
+    pub fn tos(self) -> u8 {
+        self.0 << 2
+    }
+}
+
+/// Takes a name like `AF41` or `cs1`, or a number from 0 to 63.
+impl FromStr for Dscp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((_, value)) = NAMES.iter().find(|(name, _)| name.eq_ignore_ascii_case(s)) {
+            return Ok(Dscp(*value));
+        }
+        s.parse()
+            .ok()
+            .and_then(Dscp::new)
+            .ok_or_else(|| format!("{s} is neither a DSCP name like AF41 nor a number up to 63"))
+    }
+}
+
+impl fmt::Display for Dscp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match NAMES.iter().find(|(_, value)| *value == self.0) {
+            Some((name, _)) => f.write_str(name),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+/// Marks what `socket` sends with `dscp`, in the header of its IP version.
+#[cfg(all(target_os = "linux", feature = "dscp"))]
+pub(crate) fn mark(
+    socket: &impl std::os::fd::AsRawFd,
+    ipv6: bool,
+    dscp: Dscp,
+) -> std::io::Result<()> {
+    let (level, option) = match ipv6 {
+        false => (libc::IPPROTO_IP, libc::IP_TOS),
+        true => (libc::IPPROTO_IPV6, libc::IPV6_TCLASS),
+    };
+    let value = dscp.tos() as libc::c_int;
+    // SAFETY: `value` is valid for the duration of the call.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            option,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn code_points_parse_by_name_or_number() {
+        assert_eq!("CS1".parse(), Ok(Dscp(8)));
+        assert_eq!("af41".parse(), Ok(Dscp(34)));
+        assert_eq!("46".parse(), Ok(Dscp(46)));
+        assert!("64".parse::<Dscp>().is_err());
+        assert!("AF44".parse::<Dscp>().is_err());
+        assert_eq!(Dscp(34).tos(), 0x88);
+        assert_eq!(Dscp(34).to_string(), "AF41");
+        assert_eq!(Dscp(7).to_string(), "7");
+    }
+
+    #[cfg(all(target_os = "linux", feature = "dscp"))]
+    #[test]
+    fn sockets_are_marked() {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        mark(&socket, false, Dscp(34)).unwrap();
+        let mut value: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: `value` and `len` are valid for the duration of the call.
+        let result = unsafe {
+            libc::getsockopt(
+                std::os::fd::AsRawFd::as_raw_fd(&socket),
+                libc::IPPROTO_IP,
+                libc::IP_TOS,
+                &mut value as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(result, 0);
+        assert_eq!(value, 0x88);
+    }
+}
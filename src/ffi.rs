@@ -0,0 +1,361 @@
+//! C interface, for applications in other languages to send and receive files without
+//! running the sanic binary. Build the library with
+//! `cargo rustc --release --lib --features ffi --crate-type cdylib` (or `staticlib`); the
+//! build writes the matching header to its `OUT_DIR`, and to `$SANIC_HEADER_DIR` when set,
+//! as `SANIC_HEADER_DIR=include` does for the copy checked in.
+//!
+//! Transfers block the calling thread until they end, and return [`SANIC_OK`] or one of
+//! the negative `SANIC_ERR_*` codes, [`sanic_last_error`] telling what went wrong. Panics
+//! stop at the interface, as [`SANIC_ERR_PANIC`] from transfers. Progress
+//! callbacks are called from the threads of the transfer, with the `user_data` they were
+//! given, which must be safe to use from there.
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use crate::{Progress, ReceiveError, Receiver, SendError, Sender};
+
+/// The transfer went through.
+pub const SANIC_OK: c_int = 0;
+/// A string argument was null or not UTF-8.
+pub const SANIC_ERR_ARGUMENT: c_int = -1;
+/// Reading, writing or the network failed.
+pub const SANIC_ERR_IO: c_int = -2;
+/// The transfer was cancelled with [`sanic_cancel`].
+pub const SANIC_ERR_CANCELLED: c_int = -3;
+/// The other end did not answer, or went silent.
+pub const SANIC_ERR_TIMEOUT: c_int = -4;
+/// The other end refused or aborted the transfer.
+pub const SANIC_ERR_REFUSED: c_int = -5;
+/// Any other failure, see [`sanic_last_error`].
+pub const SANIC_ERR_OTHER: c_int = -6;
+/// The library panicked, a bug, see [`sanic_last_error`].
+pub const SANIC_ERR_PANIC: c_int = -7;
+
+/// Called as parts go through, with the `user_data` the transfer was given.
+pub type SanicProgress =
+    Option<unsafe extern "C" fn(parts_done: u32, parts_total: u32, user_data: *mut c_void)>;
+
+/// Cancels the transfers it is given to once [`sanic_cancel`] is called.
+pub struct SanicCancel(Arc<AtomicBool>);
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+/// The caller vouches for `user_data` being usable from the threads of the transfer.
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+unsafe impl Sync for UserData {}
+
+/// A new cancel handle, to free with [`sanic_cancel_free`].
+#[no_mangle]
+pub extern "C" fn sanic_cancel_new() -> *mut SanicCancel {
+    catch_unwind(|| Box::into_raw(Box::new(SanicCancel(Arc::new(AtomicBool::new(false))))))
+        .unwrap_or(std::ptr::null_mut())
+}
+
+/// Cancels the transfers running with `cancel`, and the ones started with it later.
+///
+/// # Safety
+///
+/// `cancel` must come from [`sanic_cancel_new`] and not be freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn sanic_cancel(cancel: *const SanicCancel) {
+    let _ = catch_unwind(|| {
+        if let Some(cancel) = cancel.as_ref() {
+            cancel.0.store(true, Ordering::Relaxed);
+        }
+    });
+}
+
+/// Frees `cancel`. Transfers still running with it can no longer be cancelled.
+///
+/// # Safety
+///
+/// `cancel` must come from [`sanic_cancel_new`], and is not to be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn sanic_cancel_free(cancel: *mut SanicCancel) {
+    let _ = catch_unwind(|| {
+        if !cancel.is_null() {
+            drop(Box::from_raw(cancel));
+        }
+    });
+}
+
+/// Sends the file at `path` to the receiver at `addr`, like `sanic send`.
+///
+/// # Safety
+///
+/// `addr` and `path` must be null or NUL terminated strings, and `cancel` null or a live
+/// handle from [`sanic_cancel_new`].
+#[no_mangle]
+pub unsafe extern "C" fn sanic_send(
+    addr: *const c_char,
+    path: *const c_char,
+    progress: SanicProgress,
+    user_data: *mut c_void,
+    cancel: *const SanicCancel,
+) -> c_int {
+    guard(|| send(addr, path, progress, user_data, cancel))
+}
+
+unsafe fn send(
+    addr: *const c_char,
+    path: *const c_char,
+    progress: SanicProgress,
+    user_data: *mut c_void,
+    cancel: *const SanicCancel,
+) -> c_int {
+    let (Some(addr), Some(path)) = (string(addr), string(path)) else {
+        return fail(SANIC_ERR_ARGUMENT, "addr and path must be UTF-8 strings");
+    };
+    let mut sender = Sender::new(addr);
+    if let Some(callback) = progress {
+        let user_data = UserData(user_data);
+        sender = sender.on_progress(move |update| report(callback, &user_data, update));
+    }
+    if let Some(cancel) = cancel.as_ref() {
+        sender = sender.stop_on(cancel.0.clone());
+    }
+    match sender.send(PathBuf::from(path).as_path()) {
+        Ok(()) => SANIC_OK,
+        Err(err) => {
+            let code = match err {
+                SendError::Io(_) => SANIC_ERR_IO,
+                SendError::Cancelled => SANIC_ERR_CANCELLED,
+                SendError::NoAnswer(_) => SANIC_ERR_TIMEOUT,
+                SendError::Aborted(_) | SendError::Incompatible(_) => SANIC_ERR_REFUSED,
+                _ => SANIC_ERR_OTHER,
+            };
+            fail(code, &err.to_string())
+        }
+    }
+}
+
+/// Listens on `bind` (null for 0.0.0.0:6666) until a sender comes, and receives its file
+/// into `output`, a directory or the path of the file (null for the current directory),
+/// like `sanic receive --once`.
+///
+/// # Safety
+///
+/// `bind` and `output` must be null or NUL terminated strings, and `cancel` null or a live
+/// handle from [`sanic_cancel_new`].
+#[no_mangle]
+pub unsafe extern "C" fn sanic_receive(
+    bind: *const c_char,
+    output: *const c_char,
+    progress: SanicProgress,
+    user_data: *mut c_void,
+    cancel: *const SanicCancel,
+) -> c_int {
+    guard(|| receive(bind, output, progress, user_data, cancel))
+}
+
+unsafe fn receive(
+    bind: *const c_char,
+    output: *const c_char,
+    progress: SanicProgress,
+    user_data: *mut c_void,
+    cancel: *const SanicCancel,
+) -> c_int {
+    let mut receiver = Receiver::new().once(true);
+    if !bind.is_null() {
+        let Some(bind) = string(bind) else {
+            return fail(SANIC_ERR_ARGUMENT, "bind must be a UTF-8 string");
+        };
+        receiver = receiver.bind(bind);
+    }
+    if !output.is_null() {
+        let Some(output) = string(output) else {
+            return fail(SANIC_ERR_ARGUMENT, "output must be a UTF-8 string");
+        };
+        receiver = receiver.output(output);
+    }
+    if let Some(callback) = progress {
+        let user_data = UserData(user_data);
+        receiver = receiver.on_progress(move |update| report(callback, &user_data, update));
+    }
+    if let Some(cancel) = cancel.as_ref() {
+        receiver = receiver.stop_on(cancel.0.clone());
+    }
+    match receiver.receive() {
+        Ok(()) => SANIC_OK,
+        Err(err) => {
+            let code = match err {
+                ReceiveError::Io(_) => SANIC_ERR_IO,
+                ReceiveError::Cancelled => SANIC_ERR_CANCELLED,
+                ReceiveError::Inactive(_) => SANIC_ERR_TIMEOUT,
+                ReceiveError::Aborted(_) | ReceiveError::Disconnected => SANIC_ERR_REFUSED,
+                _ => SANIC_ERR_OTHER,
+            };
+            fail(code, &err.to_string())
+        }
+    }
+}
+
+/// Message of the last error on this thread, empty if there was none. It stays valid
+/// until the next one.
+#[no_mangle]
+pub extern "C" fn sanic_last_error() -> *const c_char {
+    catch_unwind(|| LAST_ERROR.with(|last| last.borrow().as_ptr())).unwrap_or(std::ptr::null())
+}
+
+/// Runs the transfer `f`, turning a panic into [`SANIC_ERR_PANIC`] rather than unwinding
+/// into the caller.
+fn guard(f: impl FnOnce() -> c_int) -> c_int {
+    catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|panic| {
+        let message = match panic.downcast_ref::<&str>() {
+            Some(message) => message,
+            None => panic.downcast_ref::<String>().map_or("", String::as_str),
+        };
+        fail(SANIC_ERR_PANIC, &format!("sanic panicked: {message}"))
+    })
+}
+
+/// `ptr` as a string, None if it is null or not UTF-8.
+unsafe fn string(ptr: *const c_char) -> Option<String> {
+    if ptr.is_null() {
+        return None;
+    }
+    CStr::from_ptr(ptr).to_str().ok().map(str::to_string)
+}
+
+fn report(
+    callback: unsafe extern "C" fn(u32, u32, *mut c_void),
+    user_data: &UserData,
+    update: Progress,
+) {
+    // SAFETY: the caller of the transfer vouched for the callback and its user data.
+    unsafe { callback(update.parts_done, update.parts_total, user_data.0) }
+}
+
+/// Keeps `message` for [`sanic_last_error`] and returns `code`.
+fn fail(code: c_int, message: &str) -> c_int {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    use std::{
+        net::UdpSocket,
+        sync::atomic::AtomicU32,
+        time::{Duration, Instant},
+    };
+
+    unsafe extern "C" fn count(done: u32, _total: u32, user_data: *mut c_void) {
+        (*(user_data as *const AtomicU32)).fetch_max(done, Ordering::Relaxed);
+    }
+
+    fn free_port() -> u16 {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.local_addr().unwrap().port()
+    }
+
+    #[test]
+    fn files_go_through_the_c_interface() {
+        let dir = TempDir::new("ffi");
+        let content: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let source = dir.write("source.bin", &content);
+        let received = dir.join("received.bin");
+        let addr = CString::new(format!("127.0.0.1:{}", free_port())).unwrap();
+
+        let receiving = {
+            let (addr, output) = (
+                addr.clone(),
+                CString::new(received.to_str().unwrap()).unwrap(),
+            );
+            std::thread::spawn(move || unsafe {
+                let parts = AtomicU32::new(0);
+                let code = sanic_receive(
+                    addr.as_ptr(),
+                    output.as_ptr(),
+                    Some(count),
+                    &parts as *const AtomicU32 as *mut c_void,
+                    std::ptr::null(),
+                );
+                (code, parts.into_inner())
+            })
+        };
+        std::thread::sleep(Duration::from_millis(200));
+        let parts = AtomicU32::new(0);
+        let path = CString::new(source.to_str().unwrap()).unwrap();
+        let code = unsafe {
+            sanic_send(
+                addr.as_ptr(),
+                path.as_ptr(),
+                Some(count),
+                &parts as *const AtomicU32 as *mut c_void,
+                std::ptr::null(),
+            )
+        };
+        assert_eq!(code, SANIC_OK);
+        let total = content.len().div_ceil(crate::PART_SIZE) as u32;
+        assert_eq!(parts.into_inner(), total);
+        assert_eq!(receiving.join().unwrap(), (SANIC_OK, total));
+        assert_eq!(std::fs::read(&received).unwrap(), content);
+    }
+
+    #[test]
+    fn cancelled_receivers_stop_waiting() {
+        let addr = CString::new(format!("127.0.0.1:{}", free_port())).unwrap();
+        let cancel = sanic_cancel_new() as usize;
+        let start = Instant::now();
+        let receiving = std::thread::spawn(move || unsafe {
+            sanic_receive(
+                addr.as_ptr(),
+                std::ptr::null(),
+                None,
+                std::ptr::null_mut(),
+                cancel as *const SanicCancel,
+            )
+        });
+        std::thread::sleep(Duration::from_millis(100));
+        unsafe { sanic_cancel(cancel as *const SanicCancel) };
+        assert_eq!(receiving.join().unwrap(), SANIC_ERR_CANCELLED);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        unsafe { sanic_cancel_free(cancel as *mut SanicCancel) };
+
+        let message = unsafe { CStr::from_ptr(sanic_last_error()) };
+        assert_eq!(message.to_str().unwrap(), "");
+        assert_eq!(
+            unsafe {
+                sanic_send(
+                    std::ptr::null(),
+                    std::ptr::null(),
+                    None,
+                    std::ptr::null_mut(),
+                    std::ptr::null(),
+                )
+            },
+            SANIC_ERR_ARGUMENT
+        );
+        let message = unsafe { CStr::from_ptr(sanic_last_error()) };
+        assert!(!message.to_bytes().is_empty());
+    }
+
+    #[test]
+    fn panics_stop_at_the_interface() {
+        assert_eq!(guard(|| SANIC_OK), SANIC_OK);
+        assert_eq!(guard(|| panic!("bad state {}", 4)), SANIC_ERR_PANIC);
+        let message = unsafe { CStr::from_ptr(sanic_last_error()) };
+        assert_eq!(message.to_str().unwrap(), "sanic panicked: bad state 4");
+        assert_eq!(guard(|| panic!("bad state")), SANIC_ERR_PANIC);
+        let message = unsafe { CStr::from_ptr(sanic_last_error()) };
+        assert_eq!(message.to_str().unwrap(), "sanic panicked: bad state");
+    }
+}
@@ -0,0 +1,321 @@
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use tracing::{info, warn};
+
+use crate::protocol::{HopStats, Message};
+
+/// How often each hop reports its statistics back towards the sender.
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+/// How often idle sessions are checked for expiry.
+const SESSION_POLL: Duration = Duration::from_millis(500);
+/// Large enough for any datagram, sealed or not.
+const DATAGRAM_SIZE: usize = 64 * 1024;
+
+/// Forwarding hop between a sender and a receiver that cannot reach each other directly.
+///
+/// Datagrams are forwarded as they are, so encrypted sessions stay end to end encrypted
+/// and the hop never needs the key. Hops can be chained by pointing one at the next:
+/// sender → A → B → receiver. Each hop reports what it forwarded back towards the sender,
+/// and earlier hops pass these reports along like any other datagram.
+///
+/// ```no_run
+/// sanic::forward::Forwarder::new("10.1.0.20:6666").name("dmz").run()?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct Forwarder {
+    bind: String,
+    next: String,
+    name: Option<String>,
+    idle_timeout: Duration,
+}
+
+impl Forwarder {
+    /// Forwards to `next`, the receiver or the next hop.
+    pub fn new(next: impl Into<String>) -> Self {
+        Forwarder {
+            bind: "0.0.0.0:6666".to_string(),
+            next: next.into(),
+            name: None,
+            idle_timeout: Duration::from_secs(60),
+        }
+    }
+
+    /// Local address senders, or the previous hop, talk to.
+    pub fn bind(mut self, addr: impl Into<String>) -> Self {
+        self.bind = addr.into();
+        self
+    }
+
+    /// Name of the hop in its statistics, its address by default.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Forget a session once it has been silent for this long.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Forwards sessions until an I/O error occurs.
+    pub fn run(&self) -> io::Result<()> {
+        let next: SocketAddr = self.next.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(ErrorKind::InvalidInput, "Next hop address did not resolve")
+        })?;
+        let listener = Arc::new(UdpSocket::bind(&self.bind)?);
+        listener.set_read_timeout(Some(SESSION_POLL))?;
+        let name = match &self.name {
+            Some(name) => name.clone(),
+            None => listener.local_addr()?.to_string(),
+        };
+        info!(addr = %listener.local_addr()?, %next, "Forwarding.");
+
+        let mut sessions: HashMap<SocketAddr, Arc<Session>> = HashMap::new();
+        let mut buf: Vec<u8> = vec![0; DATAGRAM_SIZE];
+        loop {
+            sessions.retain(|_, session| !session.closed.load(Ordering::Relaxed));
+            let (size, from) = match listener.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    continue
+                }
+                // An ICMP error for an earlier datagram, the sender is gone.
+                Err(err) if err.kind() == ErrorKind::ConnectionRefused => continue,
+                Err(err) => return Err(err),
+            };
+            let session = match sessions.get(&from) {
+                Some(session) => session.clone(),
+                None => {
+                    info!(%from, "New session.");
+                    let session = Session::open(next)?;
+                    let hop = Hop {
+                        listener: listener.clone(),
+                        upstream: from,
+                        session: session.clone(),
+                        name: name.clone(),
+                        idle_timeout: self.idle_timeout,
+                    };
+                    std::thread::spawn(move || hop.handle_downstream());
+                    sessions.insert(from, session.clone());
+                    session
+                }
+            };
+            session.touch();
+            match session.socket.send(&buf[..size]) {
+                Ok(_) => session.count(&session.to_receiver, size),
+                Err(err) => warn!(error = ?err, "Could not forward datagram."),
+            }
+        }
+    }
+}
+
+/// Traffic of one sender, which gets its own socket towards the next hop so the next hop
+/// sees each session coming from a different address.
+struct Session {
+    socket: UdpSocket,
+    started: Instant,
+    /// Milliseconds between `started` and the last datagram, in either direction.
+    last_activity: AtomicU64,
+    closed: AtomicBool,
+    to_receiver: AtomicU64,
+    to_sender: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Session {
+    fn open(next: SocketAddr) -> io::Result<Arc<Self>> {
+        let bind: SocketAddr = match next {
+            SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+            SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+        };
+        let socket = UdpSocket::bind(bind)?;
+        socket.connect(next)?;
+        socket.set_read_timeout(Some(SESSION_POLL))?;
+        Ok(Arc::new(Session {
+            socket,
+            started: Instant::now(),
+            last_activity: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+            to_receiver: AtomicU64::new(0),
+            to_sender: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+        }))
+    }
+
+    fn touch(&self) {
+        self.last_activity
+            .store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn idle(&self) -> Duration {
+        self.started.elapsed().saturating_sub(Duration::from_millis(
+            self.last_activity.load(Ordering::Relaxed),
+        ))
+    }
+
+    fn count(&self, direction: &AtomicU64, size: usize) {
+        direction.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    fn stats(&self, hop: &str) -> HopStats {
+        HopStats {
+            hop: hop.to_string(),
+            to_receiver: self.to_receiver.load(Ordering::Relaxed),
+            to_sender: self.to_sender.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Return path of a session, from the next hop back to the sender.
+struct Hop {
+    listener: Arc<UdpSocket>,
+    upstream: SocketAddr,
+    session: Arc<Session>,
+    name: String,
+    idle_timeout: Duration,
+}
+
+impl Hop {
+    fn handle_downstream(self) {
+        let mut buf: Vec<u8> = vec![0; DATAGRAM_SIZE];
+        let mut last_report = Instant::now();
+        loop {
+            match self.session.socket.recv(&mut buf) {
+                Ok(size) => {
+                    self.session.touch();
+                    match self.listener.send_to(&buf[..size], self.upstream) {
+                        Ok(_) => self.session.count(&self.session.to_sender, size),
+                        Err(err) => warn!(error = ?err, "Could not forward datagram."),
+                    }
+                }
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::ConnectionRefused
+                    ) => {}
+                Err(err) => {
+                    warn!(error = ?err, "Session socket failed.");
+                    break;
+                }
+            }
+
+            if self.session.idle() >= self.idle_timeout {
+                info!(upstream = %self.upstream, stats = ?self.session.stats(&self.name), "Session expired.");
+                break;
+            }
+            if last_report.elapsed() >= STATS_INTERVAL {
+                last_report = Instant::now();
+                let report = Message::HopStats(self.session.stats(&self.name)).serialize();
+                if let Err(err) = self.listener.send_to(&report, self.upstream) {
+                    warn!(error = ?err, "Could not report hop statistics.");
+                }
+            }
+        }
+        self.session.closed.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs a hop named `name` in front of `next`, and returns its address.
+    fn hop(name: &str, next: SocketAddr, idle_timeout: Duration) -> SocketAddr {
+        let addr = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let forwarder = Forwarder::new(next.to_string())
+            .bind(addr.to_string())
+            .name(name)
+            .idle_timeout(idle_timeout);
+        std::thread::spawn(move || forwarder.run());
+        addr
+    }
+
+    fn socket() -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        socket
+    }
+
+    #[test]
+    fn datagrams_go_both_ways_through_chained_hops() {
+        let receiver = socket();
+        let second = hop("b", receiver.local_addr().unwrap(), Duration::from_secs(60));
+        let first = hop("a", second, Duration::from_secs(60));
+        let sender = socket();
+        // The hop may not be bound yet, send until the receiver gets something.
+        let mut buf = [0; DATAGRAM_SIZE];
+        receiver
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let from = loop {
+            sender.send_to(b"part", first).unwrap();
+            if let Ok((size, from)) = receiver.recv_from(&mut buf) {
+                assert_eq!(&buf[..size], b"part");
+                break from;
+            }
+        };
+        receiver.send_to(b"ack", from).unwrap();
+
+        let mut acked = false;
+        let mut reports: HashMap<String, HopStats> = HashMap::new();
+        while !acked || reports.len() < 2 {
+            let (size, from) = sender.recv_from(&mut buf).expect("Hops went quiet");
+            assert_eq!(from, first);
+            match Message::parse(&buf[..size]) {
+                Ok(Message::HopStats(stats)) => {
+                    reports.insert(stats.hop.clone(), stats);
+                }
+                _ => {
+                    assert_eq!(&buf[..size], b"ack");
+                    acked = true;
+                }
+            }
+        }
+        assert!(reports["a"].to_receiver >= 1);
+        assert!(reports["b"].to_receiver >= 1);
+        assert!(reports["b"].bytes >= 4);
+    }
+
+    #[test]
+    fn silent_sessions_are_forgotten() {
+        let receiver = socket();
+        let first = hop(
+            "a",
+            receiver.local_addr().unwrap(),
+            Duration::from_millis(100),
+        );
+        let sender = socket();
+        let mut buf = [0; DATAGRAM_SIZE];
+        receiver
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let before = loop {
+            sender.send_to(b"part", first).unwrap();
+            if let Ok((_, from)) = receiver.recv_from(&mut buf) {
+                break from;
+            }
+        };
+        // The hop looks at its sessions every SESSION_POLL.
+        std::thread::sleep(3 * SESSION_POLL);
+        sender.send_to(b"part", first).unwrap();
+        let (_, after) = receiver.recv_from(&mut buf).unwrap();
+        assert_ne!(before, after, "The expired session was reused");
+    }
+}
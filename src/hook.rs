@@ -0,0 +1,330 @@
+//! What a receiver runs when a transfer ends, completed or not, so that pipelines can pick
+//! files up as soon as they arrive. Hooks are told what the journal records, as the same
+//! JSON object.
+//!
+//! An accept hook runs before instead, and decides whether the transfer is taken.
+//!
+//! Webhooks go over https with the `tls` feature, checked against the Mozilla root
+//! certificates like uploads to S3.
+
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    process::{Command, Stdio},
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    journal::{Entry, Outcome},
+    s3::Stream,
+    stats::json_string,
+};
+
+/// How long a webhook has to answer.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Run by the receiver after every transfer, which waits for it.
+#[derive(Debug, Clone)]
+pub enum Hook {
+    /// A shell command, run in the current directory with the JSON object on its stdin and
+    /// its fields in `SANIC_OUTCOME`, `SANIC_REASON`, `SANIC_FILENAME`, `SANIC_SIZE`,
+    /// `SANIC_SHA256`, `SANIC_PEER` and `SANIC_DURATION_MS`. Fields not known are empty.
+    Command(String),
+    /// An `http://` or `https://` URL the JSON object is POSTed to, see [`parse_url`].
+    Webhook(String),
+}
+
+impl Hook {
+    pub(crate) fn run(&self, entry: &Entry) -> io::Result<()> {
+        let payload = entry.to_line(SystemTime::now());
+        match self {
+            Hook::Command(command) => run_command(command, entry, &payload),
+            Hook::Webhook(url) => post(url, payload.trim_end()),
+        }
+    }
+}
+
+/// Asks the accept hook `command` whether to take a transfer of `filename`, at least `size`
+/// bytes, from `peer`. It gets them as its arguments, `$1` to `$3` with the peer empty when
+/// not known, and as a JSON object on its stdin. Exit status 0 accepts; otherwise returns
+/// why it declined, the first line it printed if any.
+pub(crate) fn ask(
+    command: &str,
+    filename: &str,
+    size: u64,
+    peer: Option<SocketAddr>,
+) -> io::Result<Option<String>> {
+    let peer = peer.map(|peer| peer.to_string());
+    let json = format!(
+        "{{\"peer\":{},\"filename\":{},\"size\":{size}}}\n",
+        peer.as_deref()
+            .map_or_else(|| "null".to_string(), json_string),
+        json_string(filename),
+    );
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .arg("sh")
+        .arg(filename)
+        .arg(size.to_string())
+        .arg(peer.unwrap_or_default())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("Hook stdin is piped");
+    match stdin.write_all(json.as_bytes()) {
+        // The command need not read it.
+        Err(err) if err.kind() == io::ErrorKind::BrokenPipe => {}
+        written => written?,
+    }
+    drop(stdin);
+    let output = child.wait_with_output()?;
+    if output.status.success() {
+        return Ok(None);
+    }
+    let printed = String::from_utf8_lossy(&output.stdout);
+    Ok(Some(match printed.lines().next().map(str::trim) {
+        Some(reason) if !reason.is_empty() => reason.to_string(),
+        _ => "declined by the receiver's policy".to_string(),
+    }))
+}
+
+/// Checks that `url` is one a webhook can be POSTed to: `http://`, or `https://` with the
+/// `tls` feature, with a host, an optional port and an optional path.
+pub fn parse_url(url: &str) -> Result<String, String> {
+    let (_, _, tls) = split_url(url)?;
+    if tls && cfg!(not(feature = "tls")) {
+        return Err(format!(
+            "'{url}' is https, which needs sanic built with the tls feature"
+        ));
+    }
+    Ok(url.to_string())
+}
+
+/// `host:port` and path of `url`, and whether it is https.
+fn split_url(url: &str) -> Result<(String, &str, bool), String> {
+    let (rest, tls) = match (url.strip_prefix("http://"), url.strip_prefix("https://")) {
+        (Some(rest), _) => (rest, false),
+        (_, Some(rest)) => (rest, true),
+        _ => return Err(format!("'{url}' is not an http:// or https:// URL")),
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(at) => rest.split_at(at),
+        None => (rest, "/"),
+    };
+    if authority.is_empty() {
+        return Err(format!("'{url}' has no host"));
+    }
+    // Bracketed IPv6 addresses have colons of their own.
+    let has_port = authority
+        .rsplit_once(':')
+        .is_some_and(|(_, port)| !port.contains(']'));
+    match (has_port, tls) {
+        (true, _) => Ok((authority.to_string(), path, tls)),
+        (false, false) => Ok((format!("{authority}:80"), path, tls)),
+        (false, true) => Ok((format!("{authority}:443"), path, tls)),
+    }
+}
+
+fn run_command(command: &str, entry: &Entry, payload: &str) -> io::Result<()> {
+    let (outcome, reason) = match entry.outcome {
+        Outcome::Completed => ("completed", ""),
+        Outcome::Refused(reason) => ("refused", reason),
+        Outcome::Failed(reason) => ("failed", reason),
+    };
+    let or_empty = |value: Option<String>| value.unwrap_or_default();
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("SANIC_OUTCOME", outcome)
+        .env("SANIC_REASON", reason)
+        .env("SANIC_FILENAME", entry.filename)
+        .env(
+            "SANIC_SIZE",
+            or_empty(entry.size.map(|size| size.to_string())),
+        )
+        .env(
+            "SANIC_SHA256",
+            or_empty(
+                entry
+                    .sha256
+                    .map(|digest| digest.iter().map(|byte| format!("{byte:02x}")).collect()),
+            ),
+        )
+        .env(
+            "SANIC_PEER",
+            or_empty(entry.peer.map(|peer| peer.to_string())),
+        )
+        .env("SANIC_DURATION_MS", entry.duration.as_millis().to_string())
+        .stdin(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("Hook stdin is piped");
+    match stdin.write_all(payload.as_bytes()) {
+        // The command need not read it.
+        Err(err) if err.kind() == io::ErrorKind::BrokenPipe => {}
+        written => written?,
+    }
+    drop(stdin);
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("the hook command {status}")));
+    }
+    Ok(())
+}
+
+fn post(url: &str, body: &str) -> io::Result<()> {
+    let (authority, path, tls) = split_url(url).map_err(io::Error::other)?;
+    let addr = authority
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::other(format!("{authority} does not resolve")))?;
+    let stream = TcpStream::connect_timeout(&addr, WEBHOOK_TIMEOUT)?;
+    stream.set_read_timeout(Some(WEBHOOK_TIMEOUT))?;
+    stream.set_write_timeout(Some(WEBHOOK_TIMEOUT))?;
+    let mut stream = encrypt(stream, &authority, tls)?;
+    write!(
+        stream,
+        "POST {path} HTTP/1.1\r\nHost: {authority}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()?;
+    let mut status = String::new();
+    BufReader::new(stream).read_line(&mut status)?;
+    // "HTTP/1.1 204 No Content"
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!(
+            "the webhook answered '{}'",
+            status.trim_end()
+        ))),
+    }
+}
+
+/// `stream` to `authority`, over TLS if `tls`.
+fn encrypt(stream: TcpStream, authority: &str, tls: bool) -> io::Result<Box<dyn Stream>> {
+    if !tls {
+        return Ok(Box::new(stream));
+    }
+    #[cfg(feature = "tls")]
+    {
+        // Without port nor brackets, as the certificate names it.
+        let host = match authority.strip_prefix('[') {
+            Some(rest) => rest.split(']').next().unwrap_or_default(),
+            None => authority.split(':').next().unwrap_or_default(),
+        };
+        let name = rustls::pki_types::ServerName::try_from(host.to_string())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let connection = rustls::ClientConnection::new(crate::s3::tls_config(), name)
+            .map_err(io::Error::other)?;
+        Ok(Box::new(rustls::StreamOwned::new(connection, stream)))
+    }
+    #[cfg(not(feature = "tls"))]
+    {
+        let _ = (stream, authority);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "https webhooks need sanic built with the tls feature",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use std::{io::Read, net::TcpListener};
+
+    fn entry() -> Entry<'static> {
+        Entry {
+            peer: Some("192.168.1.12:6667".parse().unwrap()),
+            filename: "report.pdf",
+            size: Some(3),
+            duration: Duration::from_millis(412),
+            sha256: Some([0xab; 32]),
+            outcome: Outcome::Completed,
+        }
+    }
+
+    #[test]
+    fn urls_need_http_and_a_host() {
+        assert_eq!(
+            split_url("http://hooks.local/new?x=1"),
+            Ok(("hooks.local:80".to_string(), "/new?x=1", false))
+        );
+        assert_eq!(
+            split_url("http://[::1]:8080"),
+            Ok(("[::1]:8080".to_string(), "/", false))
+        );
+        assert_eq!(
+            split_url("https://[::1]/"),
+            Ok(("[::1]:443".to_string(), "/", true))
+        );
+        assert_eq!(
+            parse_url("https://hooks.local/").is_ok(),
+            cfg!(feature = "tls")
+        );
+        assert!(parse_url("ftp://hooks.local/").is_err());
+        assert!(parse_url("http:///new").is_err());
+    }
+
+    #[test]
+    fn webhooks_get_the_journal_line() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/arrived", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            while !String::from_utf8_lossy(&request).ends_with('}') {
+                let read = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..read]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        Hook::Webhook(url).run(&entry()).unwrap();
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /arrived HTTP/1.1\r\n"));
+        assert!(request.ends_with(
+            "\"filename\":\"report.pdf\",\"size\":3,\"duration_ms\":412,\
+             \"sha256\":\"abababababababababababababababababababababababababababababababab\",\
+             \"outcome\":\"completed\"}"
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn commands_get_the_fields_in_their_environment() {
+        let dir = TempDir::new("hook");
+        let path = dir.join("outcome");
+        let command = format!(
+            "echo \"$SANIC_OUTCOME $SANIC_FILENAME $SANIC_SIZE $SANIC_PEER\" > {}",
+            path.display()
+        );
+        Hook::Command(command).run(&entry()).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(written, "completed report.pdf 3 192.168.1.12:6667\n");
+        assert!(Hook::Command("exit 3".to_string()).run(&entry()).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn accept_hooks_decide_by_exit_status() {
+        let peer = Some("192.168.1.12:6667".parse().unwrap());
+        let small = "[ \"$2\" -lt 1000 ] || { echo \"$1 is too big\"; exit 1; }";
+        assert_eq!(ask(small, "a.txt", 10, peer).unwrap(), None);
+        assert_eq!(
+            ask(small, "b.iso", 5000, peer).unwrap().as_deref(),
+            Some("b.iso is too big")
+        );
+        let from = "grep -q '\"peer\":\"192.168.1.12:6667\",\"filename\":\"a.txt\"'";
+        assert_eq!(ask(from, "a.txt", 10, peer).unwrap(), None);
+        assert_eq!(
+            ask("exit 1", "a.txt", 10, None).unwrap().as_deref(),
+            Some("declined by the receiver's policy")
+        );
+    }
+}
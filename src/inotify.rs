@@ -0,0 +1,100 @@
+//! Notifications of changes in directories, with inotify on Linux.
+
+use std::{
+    ffi::CString,
+    io,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+    path::Path,
+    time::Duration,
+};
+
+/// Files written, moved in or closed after writing, and directories created.
+const EVENTS: u32 = libc::IN_CREATE | libc::IN_MODIFY | libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO;
+
+pub(crate) struct Inotify(OwnedFd);
+
+impl Inotify {
+    pub fn new() -> io::Result<Self> {
+        // SAFETY: plain syscall, the descriptor it returns is ours.
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` was just opened and nothing else owns it.
+        Ok(Inotify(unsafe { OwnedFd::from_raw_fd(fd) }))
+    }
+
+    /// Watches the entries of `dir`, not the ones of its subdirectories. Watching a
+    /// directory again changes nothing.
+    pub fn watch(&self, dir: &Path) -> io::Result<()> {
+        let path = CString::new(dir.as_os_str().as_bytes())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Path with a NUL byte"))?;
+        // SAFETY: `path` is a valid C string for the duration of the call.
+        let watch = unsafe { libc::inotify_add_watch(self.0.as_raw_fd(), path.as_ptr(), EVENTS) };
+        if watch < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Waits up to `timeout` for something to change, and returns whether it did. The
+    /// events are drained, it is up to the caller to look at what changed.
+    pub fn wait(&self, timeout: Duration) -> io::Result<bool> {
+        let mut poll = libc::pollfd {
+            fd: self.0.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        // SAFETY: `poll` is valid for the duration of the call.
+        match unsafe { libc::poll(&mut poll, 1, timeout) } {
+            0 => return Ok(false),
+            ready if ready < 0 => {
+                let err = io::Error::last_os_error();
+                return match err.kind() {
+                    io::ErrorKind::Interrupted => Ok(false),
+                    _ => Err(err),
+                };
+            }
+            _ => {}
+        }
+        let mut buf = [0u8; 4096];
+        loop {
+            // SAFETY: `buf` is valid for writes of its length.
+            let read =
+                unsafe { libc::read(self.0.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+            match read {
+                0 => return Ok(true),
+                read if read > 0 => continue,
+                _ => {}
+            }
+            let err = io::Error::last_os_error();
+            return match err.kind() {
+                io::ErrorKind::WouldBlock => Ok(true),
+                io::ErrorKind::Interrupted => continue,
+                _ => Err(err),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn new_files_are_noticed() {
+        let dir = TempDir::new("inotify");
+        let inotify = Inotify::new().unwrap();
+        inotify.watch(dir.path()).unwrap();
+        assert!(!inotify.wait(Duration::from_millis(10)).unwrap());
+        dir.write("new", b"data");
+        assert!(inotify.wait(Duration::from_secs(5)).unwrap());
+        // Drained by the wait.
+        assert!(!inotify.wait(Duration::from_millis(10)).unwrap());
+    }
+}
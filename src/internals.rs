@@ -0,0 +1,41 @@
+//! Private code the benchmarks in `benches/` measure, not part of the API.
+
+use std::io::{self, IoSlice};
+
+use crate::{
+    pool::BufferPool,
+    protocol::BufferError,
+    reassembly::{Reassembler, WriteAt},
+};
+
+/// Writes the Part `part_id` holding `data` to `packet`.
+pub fn make_parts_packet(
+    data: &[u8],
+    part_id: u32,
+    packet: &mut Vec<u8>,
+) -> Result<(), BufferError> {
+    crate::server::make_parts_packet(data, part_id, packet)
+}
+
+/// Puts `parts` back together the way the receiver does, and returns how many bytes
+/// were written. The bytes themselves are dropped, to leave the disk out.
+pub fn reassemble(
+    nb_parts: u32,
+    parts: impl IntoIterator<Item = (u32, Vec<u8>)>,
+) -> io::Result<u64> {
+    let mut reassembler = Reassembler::new(Discard(0), nb_parts, false, BufferPool::new(16));
+    for (id, data) in parts {
+        reassembler.push(id, data)?;
+    }
+    reassembler.flush()?;
+    Ok(reassembler.get_ref().0)
+}
+
+struct Discard(u64);
+
+impl WriteAt for Discard {
+    fn write_all_at(&mut self, bufs: &mut [IoSlice], _offset: u64) -> io::Result<()> {
+        self.0 += bufs.iter().map(|buf| buf.len() as u64).sum::<u64>();
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+//! Append-only record of every transfer a receiver took, one JSON object per line, for
+//! receivers used as drop boxes by several people.
+//!
+//! ```text
+//! {"time":"2026-03-01T09:12:44Z","peer":"192.168.1.12:6667","filename":"report.pdf","size":181244,"duration_ms":412,"sha256":"9f86d0…","outcome":"completed"}
+//! ```
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{merkle::parse_hex, stats::json_string};
+
+/// Handle on the journal file, clones write to the same one.
+#[derive(Debug, Clone)]
+pub struct Journal(Arc<Mutex<File>>);
+
+/// What a journal line says about a transfer.
+#[derive(Debug)]
+pub(crate) struct Entry<'a> {
+    pub peer: Option<SocketAddr>,
+    pub filename: &'a str,
+    /// Bytes of the received file, once we have all of it.
+    pub size: Option<u64>,
+    /// From the Accept, or the refusal, to the end of the transfer.
+    pub duration: Duration,
+    pub sha256: Option<[u8; 32]>,
+    pub outcome: Outcome<'a>,
+}
+
+#[derive(Debug)]
+pub(crate) enum Outcome<'a> {
+    Completed,
+    /// Turned down before it started.
+    Refused(&'a str),
+    Failed(&'a str),
+}
+
+impl Journal {
+    /// Opens the journal at `path`, creating it if needed. Existing lines are kept.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Journal(Arc::new(Mutex::new(file))))
+    }
+
+    /// Appends `entry`, in a single write so that lines of several receivers sharing the
+    /// journal do not interleave.
+    pub(crate) fn record(&self, entry: &Entry) -> io::Result<()> {
+        let line = entry.to_line(SystemTime::now());
+        let mut file = self.0.lock().expect("Could not lock journal");
+        file.write_all(line.as_bytes())?;
+        file.sync_data()
+    }
+}
+
+/// SHA-256 the journal at `path` recorded for the last completed transfer of `filename`.
+pub fn last_digest(path: impl AsRef<Path>, filename: &str) -> io::Result<Option<[u8; 32]>> {
+    let journal = std::fs::read_to_string(path)?;
+    // Only lines we wrote are expected, so their fields are where we put them.
+    let name = format!("\"filename\":{},", json_string(filename));
+    Ok(journal
+        .lines()
+        .filter(|line| line.contains(&name) && line.ends_with("\"outcome\":\"completed\"}"))
+        .filter_map(|line| {
+            let (_, digest) = line.split_once("\"sha256\":\"")?;
+            parse_hex(digest.get(..64)?)
+        })
+        .next_back())
+}
+
+impl Entry<'_> {
+    pub(crate) fn to_line(&self, now: SystemTime) -> String {
+        let or_null = |value: Option<String>| value.unwrap_or_else(|| "null".to_string());
+        let outcome = match self.outcome {
+            Outcome::Completed => "\"completed\"".to_string(),
+            Outcome::Refused(reason) => format!("\"refused\",\"reason\":{}", json_string(reason)),
+            Outcome::Failed(reason) => format!("\"failed\",\"reason\":{}", json_string(reason)),
+        };
+        format!(
+            "{{\"time\":\"{}\",\"peer\":{},\"filename\":{},\"size\":{},\"duration_ms\":{},\
+             \"sha256\":{},\"outcome\":{outcome}}}\n",
+            utc(now),
+            or_null(self.peer.map(|peer| format!("\"{peer}\""))),
+            json_string(self.filename),
+            or_null(self.size.map(|size| size.to_string())),
+            self.duration.as_millis(),
+            or_null(self.sha256.map(|digest| {
+                let hex: String = digest.iter().map(|byte| format!("{byte:02x}")).collect();
+                format!("\"{hex}\"")
+            })),
+        )
+    }
+}
+
+/// `time` as an RFC 3339 UTC timestamp, to the second.
+pub(crate) fn utc(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let (days, secs) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    // Civil date of a day count, after Howard Hinnant's days_from_civil inverse.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn timestamps_are_utc_dates() {
+        assert_eq!(utc(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        let leap_day = UNIX_EPOCH + Duration::from_secs(951_782_400 + 3_723);
+        assert_eq!(utc(leap_day), "2000-02-29T01:02:03Z");
+        let new_year = UNIX_EPOCH + Duration::from_secs(1_798_761_599);
+        assert_eq!(utc(new_year), "2026-12-31T23:59:59Z");
+    }
+
+    #[test]
+    fn entries_append_one_line_each() {
+        let dir = TempDir::new("journal");
+        let path = dir.join("journal.jsonl");
+        let journal = Journal::open(&path).unwrap();
+        journal
+            .record(&Entry {
+                peer: Some("192.168.1.12:6667".parse().unwrap()),
+                filename: "report \"final\".pdf",
+                size: Some(3),
+                duration: Duration::from_millis(412),
+                sha256: Some([0xab; 32]),
+                outcome: Outcome::Completed,
+            })
+            .unwrap();
+        Journal::open(&path)
+            .unwrap()
+            .record(&Entry {
+                peer: None,
+                filename: "big.iso",
+                size: None,
+                duration: Duration::ZERO,
+                sha256: None,
+                outcome: Outcome::Refused("quota of 10 bytes exceeded"),
+            })
+            .unwrap();
+
+        let journal = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = journal.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("{\"time\":\""));
+        assert!(lines[0].ends_with(&format!(
+            "\"peer\":\"192.168.1.12:6667\",\"filename\":\"report \\\"final\\\".pdf\",\
+             \"size\":3,\"duration_ms\":412,\"sha256\":\"{}\",\"outcome\":\"completed\"}}",
+            "ab".repeat(32)
+        )));
+        assert!(lines[1].ends_with(
+            "\"peer\":null,\"filename\":\"big.iso\",\"size\":null,\"duration_ms\":0,\
+             \"sha256\":null,\"outcome\":\"refused\",\"reason\":\"quota of 10 bytes exceeded\"}"
+        ));
+    }
+
+    #[test]
+    fn digests_are_found_again() {
+        let dir = TempDir::new("journal-find");
+        let path = dir.join("journal.jsonl");
+        let journal = Journal::open(&path).unwrap();
+        for (sha256, outcome) in [
+            ([1; 32], Outcome::Completed),
+            ([2; 32], Outcome::Completed),
+            ([3; 32], Outcome::Failed("sender went away")),
+        ] {
+            let entry = Entry {
+                peer: None,
+                filename: "backup.tar",
+                size: Some(1),
+                duration: Duration::ZERO,
+                sha256: Some(sha256),
+                outcome,
+            };
+            journal.record(&entry).unwrap();
+        }
+        assert_eq!(last_digest(&path, "backup.tar").unwrap(), Some([2; 32]));
+        assert_eq!(last_digest(&path, "backup").unwrap(), None);
+    }
+}
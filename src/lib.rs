@@ -0,0 +1,179 @@
+//! Sanic: a toy fast udp-based file transfer protocol.
+//!
+//! [`Sender`] pushes a file to a [`Receiver`] listening on another host:
+//!
+//! ```no_run
+//! use sanic::{Receiver, Sender};
+//!
+//! // On the receiving host
+//! Receiver::new().output("/tmp").once(true).receive()?;
+//!
+//! // On the sending host
+//! Sender::new("192.168.1.20:6666")
+//!     .on_progress(|p| println!("{}/{}", p.parts_done, p.parts_total))
+//!     .send("archive.tar".as_ref())?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+};
+
+pub mod access;
+pub mod activity;
+mod batch;
+mod bloom;
+mod client;
+pub mod congestion;
+pub mod crypto;
+mod dedup;
+mod delta;
+mod digest;
+pub mod doctor;
+pub mod dscp;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod forward;
+pub mod hook;
+#[cfg(all(target_os = "linux", feature = "inotify"))]
+mod inotify;
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod internals;
+pub mod journal;
+#[cfg(feature = "mdns")]
+pub mod mdns;
+pub mod merkle;
+pub mod metrics;
+#[cfg(all(target_os = "linux", feature = "mmap"))]
+mod mmap;
+#[cfg(all(target_os = "linux", feature = "mmsg"))]
+mod mmsg;
+mod multicast;
+mod multipath;
+#[cfg(all(target_os = "linux", feature = "gso"))]
+mod offload;
+mod pake;
+mod paths;
+#[cfg(feature = "crypto")]
+mod permutation;
+mod pool;
+mod portmap;
+pub mod probe;
+pub mod protocol;
+pub mod pull;
+#[cfg(all(target_os = "linux", feature = "pwritev"))]
+mod pwritev;
+pub mod queue;
+mod reassembly;
+pub mod rendezvous;
+mod resume;
+mod retransmit;
+mod s3;
+pub mod scan;
+mod server;
+pub mod sessions;
+mod sim;
+pub mod sink;
+#[cfg(all(target_os = "linux", feature = "sockbuf"))]
+mod sockbuf;
+mod socket;
+mod socks;
+pub mod speedtest;
+mod state;
+pub mod stats;
+#[cfg(target_os = "linux")]
+pub mod systemd;
+#[cfg(test)]
+mod tempdir;
+#[cfg(test)]
+mod testing;
+pub mod trace;
+pub mod transport;
+#[cfg(all(target_os = "linux", feature = "uring"))]
+mod uring;
+pub mod watch;
+mod web;
+
+pub use client::{RateCap, ReceiveError, Receiver};
+pub use multicast::{MulticastReport, MulticastSender};
+pub use s3::{S3Url, S3};
+pub use server::{SendError, Sender};
+pub use sim::{ImpairedTransport, Impairments};
+pub use socks::{Proxy, Socks5Transport};
+pub use web::Web;
+
+pub const MTU: usize = 1500;
+pub const BUF_CAPACITY: usize = 8 * 1024 * 1024; // 8Mib
+pub const PART_SIZE: usize = MTU - 1 - 4;
+/// Longest text message, in bytes, see [`Sender::send_text`].
+pub const MAX_TEXT: usize = 64 * 1024;
+
+/// Snapshot of a transfer's advancement, handed to progress callbacks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub parts_done: u32,
+    pub parts_total: u32,
+}
+
+pub(crate) type ProgressCallback = Arc<dyn Fn(Progress) + Send + Sync>;
+
+/// Random id tying together the log lines of a transfer.
+pub(crate) fn session_id() -> io::Result<String> {
+    let mut id = [0; 4];
+    crypto::random_bytes(&mut id)?;
+    Ok(id.iter().map(|byte| format!("{byte:02x}")).collect())
+}
+
+/// Spawns a thread that logs in the current span, like the one of the session.
+pub(crate) fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let span = tracing::Span::current();
+    std::thread::spawn(move || span.in_scope(f))
+}
+
+/// Byte budget shared by every transfer of a sender or a receiver. Clones share it.
+#[derive(Debug, Clone)]
+pub(crate) struct Quota {
+    limit: u64,
+    used: Arc<AtomicU64>,
+}
+
+impl Quota {
+    pub fn new(limit: u64) -> Self {
+        Quota {
+            limit,
+            used: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    pub fn remaining(&self) -> u64 {
+        self.limit.saturating_sub(self.used.load(Ordering::Relaxed))
+    }
+
+    /// Counts `bytes` against the budget, and returns false once it is exceeded.
+    pub fn consume(&self, bytes: u64) -> bool {
+        self.used.fetch_add(bytes, Ordering::Relaxed) + bytes <= self.limit
+    }
+
+    pub fn exceeded(&self) -> bool {
+        self.used.load(Ordering::Relaxed) > self.limit
+    }
+
+    /// Why a transfer over budget was stopped, as told to the peer.
+    pub fn reason(&self) -> String {
+        format!("quota of {} bytes exceeded", self.limit)
+    }
+}
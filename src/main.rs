@@ -1,87 +1,2138 @@
-use byteorder::ReadBytesExt;
-use clap::{Parser, Subcommand};
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{Cursor, Read, Write};
-use std::path::Path;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::mpsc::{Receiver, Sender};
-use std::sync::{Arc, Mutex};
-use std::{net::UdpSocket, path::PathBuf};
+use clap::{Parser, Subcommand, ValueEnum};
+use sanic::access::{Access, Cidr, Rule};
+use sanic::activity::Activity;
+use sanic::congestion::Congestion;
+use sanic::crypto::SessionKey;
+use sanic::doctor::{Doctor, Nat, Reach};
+use sanic::dscp::Dscp;
+use sanic::forward::Forwarder;
+use sanic::hook::{self, Hook};
+use sanic::journal;
+use sanic::journal::Journal;
+#[cfg(feature = "mdns")]
+use sanic::mdns;
+use sanic::merkle;
+use sanic::metrics::Metrics;
+use sanic::probe;
+use sanic::protocol::Mirror;
+use sanic::pull::{Library, Slices};
+use sanic::queue::{Class, Queue};
+use sanic::rendezvous::{self, Relay};
+use sanic::scan::Scanner;
+use sanic::sessions::Weight;
+use sanic::sink::{Process, Sink};
+use sanic::speedtest::{SpeedTest, SpeedTestError};
+use sanic::stats::json_string;
+#[cfg(target_os = "linux")]
+use sanic::systemd::{self, Notifier};
+use sanic::trace::{self, Tracer};
+use sanic::watch::Watcher;
+use sanic::{
+    Impairments, MulticastSender, Proxy, RateCap, ReceiveError, Receiver, S3Url, SendError, Sender,
+    Socks5Transport, Web, S3,
+};
+use std::io::BufRead;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+#[cfg(target_os = "linux")]
+use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
-use tracing::{error, info, warn};
 
-mod client;
-mod protocol;
-mod server;
+use crate::remote::{Destination, RemoteError, RemoteReceiver};
 
-const MTU: usize = 1500;
-const BUF_CAPACITY: usize = 8 * 1024 * 1024; // 8Mib
-const PART_SIZE: usize = MTU - 1 - 4;
+mod bench;
+mod clipboard;
+mod config;
+mod control;
+mod remote;
+// Shared with the library, whose tests use more of it.
+#[cfg(test)]
+#[allow(dead_code)]
+mod tempdir;
+#[cfg(feature = "tui")]
+mod top;
+
+/// How long `send` waits for a receiver to answer to its name over mDNS.
+const MDNS_TIMEOUT: Duration = Duration::from_secs(1);
 
 #[derive(Parser)]
 #[command(name = "Sanic")]
 #[command(author = "Maël Naccache Tüfekçi <contact@maeln.com>")]
 #[command(version = "1.0")]
 #[command(about = "Gotta go fast", long_about = None)]
+#[command(after_help = EXIT_CODES)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// Log what happens to stderr, as text or as JSON lines for log collectors, each
+    /// with the id, peer and filename of its transfer
+    #[arg(long, global = true, value_enum)]
+    log_format: Option<LogFormat>,
+    /// Take the flags of this profile of the config file, over its defaults
+    #[arg(long, global = true)]
+    #[allow(dead_code)] // Read by config::apply, before parsing.
+    profile: Option<String>,
+    /// Take default flags from this file instead of ~/.config/sanic/config.toml
+    #[arg(long, global = true)]
+    #[allow(dead_code)]
+    config: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
 }
 
+// Parsed once, the size of the largest variant does not matter.
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Commands {
-    Send { ip: String, file: PathBuf },
-    Receive {},
+    Send {
+        /// Receiver address, or the name of a receiver announced over mDNS. With --code,
+        /// the rendezvous server instead
+        ip: String,
+        /// Files to send. A directory is sent as a batch: the receiver recreates its tree
+        /// and is only sent the files it does not already have
+        #[arg(required_unless_present_any = ["watch", "text", "clipboard"])]
+        files: Vec<PathBuf>,
+        /// Send this text instead, a URL or a token, which the receiver prints rather than
+        /// storing
+        #[arg(
+            long,
+            conflicts_with_all = ["files", "watch", "group", "multicast", "delta", "dedup"]
+        )]
+        text: Option<String>,
+        /// Send what the clipboard holds instead, its text or its image
+        #[arg(
+            long,
+            conflicts_with_all = ["files", "text", "watch", "group", "multicast", "delta", "dedup"]
+        )]
+        clipboard: bool,
+        /// Keep sending the files that show up or change under this directory, and
+        /// remember what was sent in a .sanic-sent file there
+        #[arg(long, value_name = "DIR", conflicts_with_all = ["files", "multicast", "group", "code"])]
+        watch: Option<PathBuf>,
+        /// With --watch, how long a file has to go unchanged before it is sent, in seconds
+        #[arg(long, default_value_t = 2, requires = "watch")]
+        debounce: u64,
+        /// With --watch, where to remember what was sent instead
+        #[arg(long, value_name = "PATH", requires = "watch")]
+        watch_record: Option<PathBuf>,
+        /// Send the files as a group the receiver only publishes once all of them arrived
+        #[arg(long)]
+        group: Option<String>,
+        /// Give up if the receiver has not accepted the transfer after this many seconds
+        #[arg(long, default_value_t = 30)]
+        connect_timeout: u64,
+        /// Number of times the Send handshake is retried before giving up
+        #[arg(long, default_value_t = 8)]
+        retries: u32,
+        /// Switch to TCP if the receiver did not answer over UDP after this many seconds
+        #[arg(long, default_value_t = 5)]
+        tcp_fallback_after: u64,
+        /// Never fall back to TCP
+        #[arg(long)]
+        no_tcp_fallback: bool,
+        /// Stop once this many bytes were sent, retransmissions included (K, M and G suffixes
+        /// allowed)
+        #[arg(long, value_parser = control::parse_bytes)]
+        max_bytes: Option<u64>,
+        /// Stripe the parts over this many UDP sockets, each with its own source port
+        #[arg(long, default_value_t = 1)]
+        streams: usize,
+        /// Local address to send from, as ip or ip:port. Repeat it with the addresses of
+        /// other interfaces to send over all of them at once
+        #[arg(long)]
+        bind: Vec<String>,
+        /// Send to every receiver that joined the multicast group `ip` instead
+        #[arg(long, conflicts_with_all = ["group", "max_bytes"])]
+        multicast: bool,
+        /// Go through this SOCKS5 proxy and its UDP relay, as socks5://[user:password@]host[:port],
+        /// where only a proxy lets traffic out
+        #[arg(long, value_name = "URL", conflicts_with_all = ["multicast", "bind"])]
+        proxy: Option<Proxy>,
+        /// With --multicast, wait for this many receivers instead of taking whoever
+        /// answered within two seconds
+        #[arg(long, requires = "multicast")]
+        receivers: Option<usize>,
+        /// Meet the receiver at the rendezvous server `ip` instead, for peers behind NATs,
+        /// and encrypt the transfer with a key derived from a code. Prints a new code for
+        /// the receiver to type in, unless given one with --code=<CODE>
+        #[arg(
+            long,
+            num_args = 0..=1,
+            require_equals = true,
+            conflicts_with_all = ["multicast", "key_stdin"]
+        )]
+        code: Option<Option<String>>,
+        /// With --code, have the rendezvous server relay the transfer when the receiver
+        /// did not answer directly after this many seconds
+        #[arg(long, requires = "code")]
+        relay_fallback_after: Option<u64>,
+        /// Read the session key from the first line of stdin and encrypt the transfer
+        #[arg(long, conflicts_with = "multicast")]
+        key_stdin: bool,
+        /// Read the session key from the first line of this file and encrypt the transfer
+        #[arg(long, conflicts_with_all = ["multicast", "key_stdin", "code"])]
+        key_file: Option<PathBuf>,
+        /// Print the statistics of each transfer once it ended, as a table or as JSON
+        #[arg(
+            long,
+            value_enum,
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "table",
+            conflicts_with = "multicast"
+        )]
+        stats: Option<StatsFormat>,
+        /// Print what happens as JSON lines instead, progress and statistics included, each
+        /// an object with the "event" it is about
+        #[arg(long)]
+        json: bool,
+        /// Record every message sent and received to this file, to read with `sanic trace dump`
+        #[arg(long, conflicts_with = "multicast")]
+        trace_packets: Option<PathBuf>,
+        /// Lose, reorder or delay the datagrams we send, to test a deployment on a bad link,
+        /// as in `loss=5%,reorder=1%,duplicate=1%,latency=20ms,jitter=5ms,bandwidth=1M,seed=7`
+        #[arg(long, value_name = "IMPAIRMENTS", conflicts_with = "multicast")]
+        chaos: Option<Impairments>,
+        /// Kernel send buffer of each socket, in bytes (K, M and G suffixes allowed), capped
+        /// by net.core.wmem_max
+        #[arg(long, value_parser = control::parse_bytes, conflicts_with = "multicast")]
+        sndbuf: Option<u64>,
+        /// Kernel receive buffer of each socket, in bytes (K, M and G suffixes allowed),
+        /// capped by net.core.rmem_max
+        #[arg(long, value_parser = control::parse_bytes, conflicts_with = "multicast")]
+        rcvbuf: Option<u64>,
+        /// Mark the datagrams for QoS with this DiffServ code point, a name like CS1 for
+        /// background transfers or AF41 for priority ones, or a number up to 63
+        #[arg(long, conflicts_with = "multicast")]
+        dscp: Option<Dscp>,
+        /// Only use the bandwidth nobody else does, backing off as soon as other traffic
+        /// queues up on the link, for bulk transfers that must not disturb video calls
+        #[arg(long, conflicts_with_all = ["multicast", "cc"])]
+        background: bool,
+        /// Congestion controller: unlimited goes as fast as the receiver allows, ledbat is
+        /// --background, and bbr sends at the bandwidth it measured at the bottleneck, for
+        /// long fat links
+        #[arg(long, value_name = "CONTROLLER", conflicts_with = "multicast")]
+        cc: Option<Congestion>,
+        /// Spread the parts out evenly at the rate the receiver takes them. Off sends them in
+        /// batches, as fast as possible, for loopback benchmarks
+        #[arg(long, value_enum, default_value_t = Pacing::On, conflicts_with = "multicast")]
+        pacing: Pacing,
+        /// When the receiver already has an older version of a file, only send the blocks
+        /// that changed, rsync style
+        #[arg(long, conflicts_with_all = ["multicast", "group", "code"])]
+        delta: bool,
+        /// Cut the files into chunks where their content says so, and skip the chunks the
+        /// receiver kept from previous transfers with --chunk-cache, for nightly backups
+        #[arg(long, conflicts_with_all = ["multicast", "code"])]
+        dedup: bool,
+        /// Verify the file block by block as it arrives, so a corrupt block is sent again
+        /// on its own rather than found out at the end
+        #[arg(long, conflicts_with = "multicast")]
+        verify: bool,
+        /// Token for receivers that ask the senders of our address for one with --allow.
+        /// It goes in the clear unless the transfer is encrypted
+        #[arg(long, conflicts_with = "multicast")]
+        token: Option<String>,
+        /// Save how much of each file the receiver acknowledged, so that once restarted we go
+        /// on from there instead of starting over
+        #[arg(long, conflicts_with = "multicast")]
+        resume: bool,
+    },
+    Receive {
+        /// Exit after the first transfer
+        #[arg(long)]
+        once: bool,
+        /// Print what happens as JSON lines instead, progress and the journal line of every
+        /// transfer included, each an object with the "event" it is about
+        #[arg(long)]
+        json: bool,
+        /// Put the text messages and the images sent with `send --clipboard` in the
+        /// clipboard, rather than printing or keeping them
+        #[arg(long)]
+        clipboard: bool,
+        /// Take speed tests from `sanic speedtest`, which send and ask for synthetic data
+        /// that is never stored
+        #[arg(long)]
+        speedtest: bool,
+        /// Where to store the received file
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+        /// UDP port to listen on, 0 lets the system pick one. Under systemd socket
+        /// activation, the socket it passes is listened on instead
+        #[arg(long, default_value_t = 6666)]
+        port: u16,
+        /// Code the sender printed, to meet it at the rendezvous server given with --relay
+        /// and derive the session key from
+        #[arg(requires = "relay", conflicts_with = "key_stdin")]
+        code: Option<String>,
+        /// Read the session key from the first line of stdin and only accept encrypted transfers
+        #[arg(long)]
+        key_stdin: bool,
+        /// Read the session key from the first line of this file and only accept encrypted
+        /// transfers
+        #[arg(long, conflicts_with_all = ["key_stdin", "code"])]
+        key_file: Option<PathBuf>,
+        /// Abort a transfer when the sender has been silent for this many seconds
+        #[arg(long, default_value_t = 60)]
+        idle_timeout: u64,
+        /// Abort a transfer still running after this many seconds
+        #[arg(long)]
+        max_duration: Option<u64>,
+        /// Keep the partial file of an aborted transfer, <name>.sanic-partial, instead of
+        /// deleting it
+        #[arg(long)]
+        keep_partial: bool,
+        /// Save which parts of a transfer are on disk, <name>.sanic-resume, so that once
+        /// restarted we take the transfer back from its sender instead of starting over
+        #[arg(long)]
+        resume: bool,
+        /// Leave holes in received files where the data is all zeroes
+        #[arg(long)]
+        sparse: bool,
+        /// Let `sanic sync --delete` remove the files of our directories that its source
+        /// does not have
+        #[arg(long)]
+        allow_delete: bool,
+        /// Do not accept transfers over TCP from senders that cannot reach us over UDP
+        #[arg(long)]
+        no_tcp_fallback: bool,
+        /// Scan received files with the clamd daemon listening on this unix socket
+        #[arg(long, conflicts_with = "scan_command")]
+        scan_clamd: Option<PathBuf>,
+        /// Scan received files with this shell command, fed on its stdin. Exit status 0
+        /// means clean, 1 means infected
+        #[arg(long)]
+        scan_command: Option<String>,
+        /// Move files rejected by the scanner to this directory instead of deleting them
+        #[arg(long)]
+        quarantine: Option<PathBuf>,
+        /// Only take transfers from this address or range, like 192.168.1.0/24, and ask its
+        /// senders for a token with 192.168.1.0/24=TOKEN. Repeat it to allow several
+        #[arg(long, value_name = "RANGE[=TOKEN]")]
+        allow: Vec<Rule>,
+        /// Never take transfers from this address or range, whatever is allowed
+        #[arg(long, value_name = "RANGE")]
+        deny: Vec<Cidr>,
+        /// Ask senders to stay under this rate, in bytes per second (K, M and G suffixes allowed)
+        #[arg(long, value_parser = control::parse_bytes)]
+        rate_limit: Option<u64>,
+        /// Refuse or abort transfers once this many bytes were received (K, M and G suffixes
+        /// allowed)
+        #[arg(long, value_parser = control::parse_bytes)]
+        max_bytes: Option<u64>,
+        /// Refuse files over this size (K, M and G suffixes allowed)
+        #[arg(long, value_parser = control::parse_bytes)]
+        max_file_size: Option<u64>,
+        /// Keep the output directory under this size, counting the files already there (K, M
+        /// and G suffixes allowed)
+        #[arg(long, value_parser = control::parse_bytes)]
+        quota: Option<u64>,
+        /// Receive from up to this many senders at once, turning the next ones away
+        #[arg(long, default_value_t = 1, conflicts_with_all = ["once", "multicast", "relay"])]
+        max_sessions: usize,
+        /// Give the senders of this address or range that many shares of --rate-limit
+        /// while several sessions run, against 1 for the others, like 10.0.0.0/8=3
+        #[arg(long, value_name = "RANGE=WEIGHT")]
+        weight: Vec<Weight>,
+        /// Accept commands such as `rate-limit 10M`, `list-sessions` or `pause <id>` on this
+        /// unix socket, see `sanic ctl --help`
+        #[arg(long)]
+        control_socket: Option<PathBuf>,
+        /// Serve a page on this address showing the transfers and the files received,
+        /// which browsers can download, like 127.0.0.1:8080
+        #[arg(long)]
+        web: Option<SocketAddr>,
+        /// Serve Prometheus metrics over HTTP at /metrics on this address, as ip:port
+        #[arg(long)]
+        metrics: Option<SocketAddr>,
+        /// Append a JSON line for every transfer to this file, with its peer, filename, size,
+        /// duration, SHA-256 and outcome
+        #[arg(long)]
+        journal: Option<PathBuf>,
+        /// Run this shell command after every transfer, with the journal line on its stdin
+        /// and its fields in SANIC_OUTCOME, SANIC_FILENAME, SANIC_SIZE, SANIC_SHA256,
+        /// SANIC_PEER, SANIC_DURATION_MS and SANIC_REASON
+        #[arg(long)]
+        on_complete: Option<String>,
+        /// POST the journal line of every transfer to this http:// or https:// URL
+        #[arg(long, value_parser = hook::parse_url)]
+        webhook: Option<String>,
+        /// Before taking a transfer, run this shell command with the filename, size in bytes
+        /// and sender as $1, $2 and $3, and as JSON on stdin. Transfers are taken when it
+        /// exits with 0, and refused otherwise with the first line it printed
+        #[arg(long)]
+        accept_hook: Option<String>,
+        /// Stream every file to the stdin of this shell command instead of storing it, with
+        /// its name and size in $SANIC_FILENAME and $SANIC_SIZE. Only whole files sent on
+        /// their own are taken then
+        #[arg(long, value_name = "COMMAND", conflicts_with = "resume")]
+        pipe: Option<String>,
+        /// Upload every file to S3, or storage speaking its API, instead of storing it: to
+        /// the key of an s3://bucket/key URL, or under it if it ends with /. Credentials,
+        /// region and endpoint come from the AWS_* variables, the endpoint of AWS being
+        /// https. Only whole files sent on their own are taken then
+        #[arg(long, value_name = "URL", conflicts_with_all = ["resume", "pipe"])]
+        to: Option<S3Url>,
+        /// Upload over plain http to an AWS_ENDPOINT_URL off this host, files and session
+        /// token in the clear
+        #[arg(long, requires = "to")]
+        plain_http: bool,
+        /// Record every message sent and received to this file, to read with `sanic trace dump`
+        #[arg(long)]
+        trace_packets: Option<PathBuf>,
+        /// Lose, reorder or delay the datagrams we send, to test a deployment on a bad link,
+        /// see `sanic send --help`
+        #[arg(long, value_name = "IMPAIRMENTS")]
+        chaos: Option<Impairments>,
+        /// Kernel send buffer of the socket, in bytes (K, M and G suffixes allowed), capped
+        /// by net.core.wmem_max
+        #[arg(long, value_parser = control::parse_bytes)]
+        sndbuf: Option<u64>,
+        /// Kernel receive buffer of the socket, in bytes (K, M and G suffixes allowed),
+        /// capped by net.core.rmem_max. By default it grows with the rate of the transfers
+        #[arg(long, value_parser = control::parse_bytes)]
+        rcvbuf: Option<u64>,
+        /// Mark the datagrams we send for QoS with this DiffServ code point, see `sanic send
+        /// --help`
+        #[arg(long)]
+        dscp: Option<Dscp>,
+        /// Most parts a sender may have waiting for an Ack, to bound what it keeps in
+        /// flight towards us. Told to senders when accepting their transfers
+        #[arg(long, value_name = "PARTS")]
+        max_in_flight: Option<u32>,
+        /// Keep the chunks of the transfers sent with --dedup in this directory, so that
+        /// the next ones skip what we already got
+        #[arg(long, value_name = "DIR")]
+        chunk_cache: Option<PathBuf>,
+        /// Also receive the transfers sent to this multicast group
+        #[arg(long, conflicts_with = "key_stdin")]
+        multicast: Option<Ipv4Addr>,
+        /// Announce the receiver over mDNS, so senders on the LAN can find it by name
+        #[arg(long)]
+        announce: bool,
+        /// Have the home router forward the port to us over NAT-PMP or UPnP, and print the
+        /// public address senders on the internet can send to
+        #[arg(long, conflicts_with_all = ["multicast", "relay"])]
+        upnp: bool,
+        /// Receive through this SOCKS5 proxy, as socks5://[user:password@]host[:port]: senders
+        /// reach us at the address of its UDP relay, if it forwards the datagrams of any host
+        #[arg(long, value_name = "URL", conflicts_with_all = ["multicast", "upnp"])]
+        proxy: Option<Proxy>,
+        /// Rendezvous server to meet the sender at, as host or host:port
+        #[arg(long, requires = "code", conflicts_with = "multicast")]
+        relay: Option<String>,
+        /// Also take the transfer when the rendezvous server relays it
+        #[arg(long, requires = "relay")]
+        relay_fallback: bool,
+    },
+    /// List the receivers announced on the LAN, or listening on its broadcast domain
+    Discover {
+        /// How long to wait for answers, in seconds
+        #[arg(long, default_value_t = 2)]
+        timeout: u64,
+        /// UDP port to probe for receivers that do not announce themselves
+        #[arg(long, default_value_t = 6666)]
+        port: u16,
+        /// Print a JSON line per receiver found instead
+        #[arg(long)]
+        json: bool,
+    },
+    /// Measure the throughput, loss and round trip time to a receiver started with
+    /// --speedtest, by sending it synthetic data and then asking it for some
+    Speedtest {
+        /// Receiver to test against, as an IP or a name
+        ip: String,
+        /// How long to test each way, in seconds
+        #[arg(long, default_value_t = 10)]
+        duration: u64,
+        /// Print a JSON line per direction instead
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check the path to a receiver: whether it answers, the largest datagram that gets
+    /// there unfragmented, the kernel buffer limits, the NAT we are behind and the
+    /// throughput, and suggest what to change
+    Doctor {
+        /// Receiver to check, as an IP or a name
+        ip: String,
+        /// How long to wait for the receiver to answer, in seconds
+        #[arg(long, default_value_t = 2)]
+        timeout: u64,
+        /// How long to test the throughput each way, in seconds, 0 to skip it. Takes a
+        /// receiver started with --speedtest
+        #[arg(long, default_value_t = 3)]
+        speedtest: u64,
+    },
+    /// Forward transfers to a receiver, or to the next hop, that senders cannot reach
+    Forward {
+        /// Receiver or next hop, as host:port
+        next: String,
+        /// UDP port senders, or the previous hop, talk to
+        #[arg(long, default_value_t = 6666)]
+        port: u16,
+        /// Name of this hop in the statistics reported to the sender
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// Introduce senders and receivers behind NATs that registered the same code
+    Relay {
+        /// UDP port peers register on
+        #[arg(long, default_value_t = 6668)]
+        port: u16,
+        /// Forget a code once nobody registered it for this many seconds
+        #[arg(long, default_value_t = 600)]
+        ttl: u64,
+        /// Also relay the transfers of the peers that cannot reach each other, at most at
+        /// this many bytes per second for each pair (K, M and G suffixes allowed)
+        #[arg(long, value_parser = control::parse_bytes)]
+        forward_rate: Option<u64>,
+    },
+    /// Serve the files under a directory to receivers that list them and get them with
+    /// `sanic get`
+    Serve {
+        dir: PathBuf,
+        /// UDP port to serve on
+        #[arg(long, default_value_t = 6666)]
+        port: u16,
+        /// Give up on a receiver that has not accepted its file after this many seconds
+        #[arg(long, default_value_t = 30)]
+        connect_timeout: u64,
+        /// Send to up to this many receivers at once
+        #[arg(long, default_value_t = 1)]
+        downloads: usize,
+        /// Stay under this rate in all, in bytes per second (K, M and G suffixes allowed)
+        #[arg(long, value_parser = control::parse_bytes)]
+        rate_limit: Option<u64>,
+        /// Downloads of more than this many bytes are bulk ones (K, M and G suffixes allowed)
+        #[arg(long, value_parser = control::parse_bytes, default_value = "64MiB")]
+        bulk_above: u64,
+        /// Split --rate-limit between interactive and bulk downloads running at once by
+        /// these weights
+        #[arg(long, value_name = "INTERACTIVE:BULK", default_value = "8:1")]
+        slices: Slices,
+    },
+    /// List the files a host serves with `sanic serve`, with their size and SHA-256
+    Ls {
+        /// Address of the serving host
+        ip: String,
+        /// UDP port the host serves on
+        #[arg(long, default_value_t = 6666)]
+        port: u16,
+        /// Give up when the host has been silent for this many seconds
+        #[arg(long, default_value_t = 60)]
+        idle_timeout: u64,
+    },
+    /// Get one of the files a host serves with `sanic serve`
+    Get {
+        /// Address of the serving host
+        ip: String,
+        /// Path of the file to get, as `sanic ls` lists it
+        name: String,
+        /// Only get the bytes in START-END, END excluded, or START- for the rest of the
+        /// file, and write them in place into the output file (KiB, MiB and GiB suffixes
+        /// allowed)
+        #[arg(long, value_parser = control::parse_range)]
+        range: Option<Range<u64>>,
+        /// UDP port the host serves on
+        #[arg(long, default_value_t = 6666)]
+        port: u16,
+        /// Where to store the file
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+        /// Give up when the host has been silent for this many seconds
+        #[arg(long, default_value_t = 60)]
+        idle_timeout: u64,
+    },
+    /// Read packet traces recorded with --trace-packets
+    Trace {
+        #[command(subcommand)]
+        command: TraceCommand,
+    },
+    /// Check a file received long ago against what its transfer hashed to
+    Verify {
+        file: PathBuf,
+        /// SHA-256 of the file or Merkle root of its verified transfer, in hexadecimal,
+        /// or the --journal of the receiver, which has the SHA-256 of the last transfer
+        /// of a file of that name
+        expected: String,
+    },
+    /// Time a transfer of a synthetic file between a sender and a receiver in this
+    /// process, and report its throughput, CPU time and allocations
+    Bench {
+        /// Size of the file (K, M and G suffixes allowed)
+        #[arg(long, default_value = "100M", value_parser = control::parse_bytes)]
+        size: u64,
+        /// Transfer over the in-process simulator with these impairments instead of
+        /// loopback, see --chaos in `sanic send --help`
+        #[arg(long, value_name = "IMPAIRMENTS")]
+        sim: Option<Impairments>,
+        /// Number of transfers, each reported on its own
+        #[arg(long, default_value_t = 1)]
+        runs: u32,
+    },
+    /// Copy a file to another host, starting the receiver there over ssh
+    Cp {
+        src: PathBuf,
+        /// Destination in the form [user@]host:path
+        dest: String,
+        /// ssh binary used to reach the destination
+        #[arg(long, default_value = "ssh")]
+        ssh: String,
+        /// Path to the sanic binary on the destination host
+        #[arg(long, default_value = "sanic")]
+        remote_sanic: String,
+        /// Encrypt the transfer with a session key negotiated over the ssh channel
+        #[arg(long)]
+        encrypt: bool,
+        #[arg(long, default_value_t = 30)]
+        connect_timeout: u64,
+        #[arg(long, default_value_t = 8)]
+        retries: u32,
+    },
+    /// Make a directory of a receiver a copy of a local one, sending only the files that
+    /// are missing there or changed
+    Sync {
+        src: PathBuf,
+        /// Destination in the form host:dir, the directory being relative to the output
+        /// directory of the receiver
+        dest: String,
+        /// Remove the files of the destination that the source does not have, which the
+        /// receiver must allow with --allow-delete
+        #[arg(long)]
+        delete: bool,
+        /// Tell changed files by their SHA-256 rather than by their size and modification
+        /// time
+        #[arg(long)]
+        checksum: bool,
+        /// Only send the blocks of changed files that differ from the receiver's copy
+        #[arg(long)]
+        delta: bool,
+        #[arg(long, default_value_t = 30)]
+        connect_timeout: u64,
+        #[arg(long, default_value_t = 8)]
+        retries: u32,
+    },
+    /// Keep running and send the files queued with `sanic enqueue`, highest priority first
+    Queue {
+        /// Receiver address, or the name of a receiver announced over mDNS
+        ip: String,
+        /// Take files to send, and commands such as `rate-limit 10M` or `pause <id>`, on this
+        /// unix socket
+        #[arg(long)]
+        control_socket: PathBuf,
+        /// Send up to this many files at once
+        #[arg(long, default_value_t = 2)]
+        slots: usize,
+        /// Give the files of this priority that many shares of --rate-limit while several
+        /// are sent, against 1 for the others, like 9=4
+        #[arg(long, value_name = "PRIORITY=WEIGHT")]
+        class: Vec<Class>,
+        /// When a file waits for a slot, stop sending one of a lower priority and send it
+        /// again later
+        #[arg(long)]
+        preempt: bool,
+        /// Stay under this rate in all, in bytes per second (K, M and G suffixes allowed)
+        #[arg(long, value_parser = control::parse_bytes)]
+        rate_limit: Option<u64>,
+        #[arg(long, default_value_t = 30)]
+        connect_timeout: u64,
+        #[arg(long, default_value_t = 8)]
+        retries: u32,
+    },
+    /// Run a command on the control socket of a running receiver or queue and print what
+    /// it answers: `list-sessions`, `get-stats <id>`, `pause <id>`, `resume <id>`,
+    /// `cancel <id>`, `rate-limit <rate>` or `rate-limit off`. Sessions go by the id of
+    /// their log lines
+    Ctl {
+        /// Control socket of the receiver or queue
+        control_socket: PathBuf,
+        /// Command and its arguments, like `pause 0a1b2c3d`
+        #[arg(required = true, num_args = 1..)]
+        command: Vec<String>,
+    },
+    /// Show the transfers of a running receiver or queue live, with their progress, rate,
+    /// losses and ETA. Keys pause, resume and cancel the one selected
+    #[cfg(feature = "tui")]
+    Top {
+        /// Control socket of the receiver or queue
+        control_socket: PathBuf,
+        /// Seconds between refreshes
+        #[arg(long, default_value_t = 1)]
+        interval: u64,
+    },
+    /// Queue files on a running `sanic queue`
+    Enqueue {
+        /// Control socket of the queue
+        #[arg(long)]
+        control_socket: PathBuf,
+        /// Files of higher priorities go first, from 0 to 255
+        #[arg(long, default_value_t = 0)]
+        priority: u8,
+        /// Files to send, directories being sent as batches
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum TraceCommand {
+    /// Print every message of a trace, with when it was sent or received and the peer
+    Dump { file: PathBuf },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum StatsFormat {
+    Table,
+    Json,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Pacing {
+    On,
+    Off,
 }
 
-fn main() {
-    let cli = Cli::parse();
-    match &cli.command {
-        Commands::Send { ip, file } => {
-            send(ip.clone(), 6666, file);
+/// Why a command failed, which decides the exit code.
+#[derive(Error, Debug)]
+enum CliError {
+    #[error("{0}")]
+    Send(#[from] SendError),
+
+    #[error("{0}")]
+    Receive(#[from] ReceiveError),
+
+    #[error("{0}")]
+    Remote(#[from] RemoteError),
+
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("could not read the session key from {0}")]
+    Key(String),
+
+    #[error("{0}")]
+    Mismatch(String),
+}
+
+impl From<SpeedTestError> for CliError {
+    fn from(err: SpeedTestError) -> Self {
+        match err {
+            SpeedTestError::Upload(err) => CliError::Send(err),
+            SpeedTestError::Download(err) => CliError::Receive(err),
         }
-        Commands::Receive {} => {
-            receive();
+    }
+}
+
+impl CliError {
+    /// What the process exits with, see [`EXIT_CODES`].
+    fn exit_code(&self) -> u8 {
+        match self {
+            CliError::Send(SendError::NoAnswer(_))
+            | CliError::Receive(ReceiveError::Disconnected)
+            | CliError::Remote(RemoteError::ReceiverDied) => 3,
+            CliError::Send(SendError::Aborted(_) | SendError::Incompatible(_))
+            | CliError::Receive(
+                ReceiveError::Declined(_)
+                | ReceiveError::TooLarge { .. }
+                | ReceiveError::Aborted(_)
+                | ReceiveError::Rejected(..),
+            ) => 4,
+            CliError::Receive(ReceiveError::Mismatch(_)) | CliError::Mismatch(_) => 5,
+            CliError::Send(SendError::Quota(_))
+            | CliError::Receive(ReceiveError::Quota(_) | ReceiveError::NoSpace { .. }) => 6,
+            CliError::Send(SendError::Cancelled) | CliError::Receive(ReceiveError::Cancelled) => 7,
+            CliError::Receive(ReceiveError::Inactive(_) | ReceiveError::TooLong(_)) => 8,
+            CliError::Remote(RemoteError::InvalidDestination(_)) | CliError::Key(_) => 2,
+            CliError::Send(SendError::Io(err))
+            | CliError::Receive(ReceiveError::Io(err))
+            | CliError::Io(err) => io_exit_code(err),
+            _ => 1,
         }
     }
 }
 
-fn send(ip: String, port: usize, file: &Path) -> () {
-    let disp_path = file.to_string_lossy();
-    println!("Sending {disp_path} to {ip}:{port}");
-    let socket = UdpSocket::bind("0.0.0.0:6667").expect("Could not bind socket");
-    socket
-        .connect(format!("{ip}:{port}"))
-        .expect("Could not connect to receiver");
+/// Told by `sanic --help`, for the scripts that run sanic.
+const EXIT_CODES: &str = "Exit codes:
+  0  success
+  1  local failure
+  2  bad arguments
+  3  the peer could not be reached, or went away
+  4  the peer refused or aborted the transfer
+  5  a checksum did not match
+  6  the disk is full, or a quota was exceeded
+  7  the transfer was cancelled
+  8  the transfer timed out";
 
-    let mut write_buf: Vec<u8> = Vec::with_capacity(MTU);
-    write!(&mut write_buf, "Hello!").expect("Could not write message");
+/// I/O errors that have a class of their own.
+fn io_exit_code(err: &std::io::Error) -> u8 {
+    use std::io::ErrorKind;
+    match err.kind() {
+        ErrorKind::ConnectionRefused
+        | ErrorKind::ConnectionReset
+        | ErrorKind::HostUnreachable
+        | ErrorKind::NetworkUnreachable
+        | ErrorKind::AddrNotAvailable => 3,
+        ErrorKind::StorageFull | ErrorKind::QuotaExceeded => 6,
+        ErrorKind::TimedOut => 8,
+        _ => 1,
+    }
+}
 
-    match socket.send(&write_buf) {
-        Ok(sent) => println!("Sent {sent} octets"),
+fn main() -> ExitCode {
+    let args = match config::apply(std::env::args_os().collect()) {
+        Ok(args) => args,
         Err(err) => {
             println!("Error {err}");
-            return;
+            return ExitCode::from(2);
+        }
+    };
+    let cli = Cli::parse_from(args);
+    JSON.store(cli.command.json(), Ordering::Relaxed);
+    let logs = tracing_subscriber::fmt().with_writer(std::io::stderr);
+    match cli.log_format {
+        Some(LogFormat::Text) => logs.init(),
+        Some(LogFormat::Json) => logs.json().with_span_list(false).init(),
+        None => {}
+    }
+    match run(&cli.command) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            tell(
+                &format!("Error {err}"),
+                "error",
+                &[
+                    ("error", json_string(&err.to_string())),
+                    ("exit_code", err.exit_code().to_string()),
+                ],
+            );
+            ExitCode::from(err.exit_code())
+        }
+    }
+}
+
+/// Set by --json: commands then print what happens as JSON lines.
+static JSON: AtomicBool = AtomicBool::new(false);
+
+impl Commands {
+    fn json(&self) -> bool {
+        match self {
+            Commands::Send { json, .. }
+            | Commands::Receive { json, .. }
+            | Commands::Discover { json, .. }
+            | Commands::Speedtest { json, .. } => *json,
+            _ => false,
         }
     }
+}
+
+/// Prints `text`, or after --json the object of `event` with `fields`, their values
+/// already in JSON.
+fn tell(text: &str, event: &str, fields: &[(&str, String)]) {
+    if !JSON.load(Ordering::Relaxed) {
+        println!("{text}");
+        return;
+    }
+    let mut line = format!("{{\"event\":\"{event}\"");
+    for (name, value) in fields {
+        line.push_str(&format!(",\"{name}\":{value}"));
+    }
+    println!("{line}}}");
+}
 
-    println!("Finished");
+/// After --json, prints the progress of transfers as they go, at every percent.
+fn progress_events() -> impl Fn(sanic::Progress) + Send + Sync + 'static {
+    let last = AtomicU32::new(u32::MAX);
+    move |progress| {
+        let percent = match progress.parts_total {
+            0 => 100,
+            total => (u64::from(progress.parts_done) * 100 / u64::from(total)) as u32,
+        };
+        if last.swap(percent, Ordering::Relaxed) != percent {
+            tell(
+                "",
+                "progress",
+                &[
+                    ("parts_done", progress.parts_done.to_string()),
+                    ("parts_total", progress.parts_total.to_string()),
+                ],
+            );
+        }
+    }
 }
 
-fn receive() -> () {
-    let socket = UdpSocket::bind("0.0.0.0:6666").expect("Could not bind socket");
-    println!("Listening at port {}", socket.local_addr().unwrap().port());
-    let mut read_buf: Vec<u8> = vec![0; MTU];
-    loop {
-        match socket.recv(&mut read_buf) {
-            Ok(size) => println!("Received {size} octets."),
-            Err(err) => {
-                println!("Error {err}.");
-                return;
+fn run(command: &Commands) -> Result<(), CliError> {
+    match command {
+        Commands::Send {
+            ip,
+            files,
+            text,
+            clipboard,
+            watch,
+            debounce,
+            watch_record,
+            group,
+            connect_timeout,
+            retries,
+            tcp_fallback_after,
+            no_tcp_fallback,
+            max_bytes,
+            streams,
+            bind,
+            multicast,
+            proxy,
+            receivers,
+            code,
+            relay_fallback_after,
+            key_stdin,
+            key_file,
+            stats,
+            trace_packets,
+            chaos,
+            sndbuf,
+            rcvbuf,
+            dscp,
+            background,
+            cc,
+            pacing,
+            delta,
+            dedup,
+            verify,
+            token,
+            resume,
+            json: _,
+        } => {
+            if *multicast {
+                let mut sender = MulticastSender::new(format!("{ip}:6666"))
+                    .connect_timeout(Duration::from_secs(*connect_timeout));
+                if let Some(first) = bind.first() {
+                    sender = sender.bind(bind_addr(first));
+                }
+                if let Some(receivers) = receivers {
+                    sender = sender.receivers(*receivers);
+                }
+                return files
+                    .iter()
+                    .try_for_each(|file| send_multicast(&sender, file));
+            }
+            let tcp_fallback = (!no_tcp_fallback).then(|| Duration::from_secs(*tcp_fallback_after));
+            let addr = match code {
+                Some(_) => relay_addr(ip),
+                None => peer_addr(ip),
+            };
+            let mut sender = Sender::new(addr)
+                .connect_timeout(Duration::from_secs(*connect_timeout))
+                .retries(*retries)
+                .tcp_fallback(tcp_fallback)
+                .streams(*streams)
+                .on_hop_stats(|stats| {
+                    tell(
+                        &format!(
+                            "Hop {}: {} datagrams to the receiver, {} back, {} bytes",
+                            stats.hop, stats.to_receiver, stats.to_sender, stats.bytes
+                        ),
+                        "hop",
+                        &[
+                            ("hop", stats.hop.to_string()),
+                            ("to_receiver", stats.to_receiver.to_string()),
+                            ("to_sender", stats.to_sender.to_string()),
+                            ("bytes", stats.bytes.to_string()),
+                        ],
+                    )
+                });
+            if JSON.load(Ordering::Relaxed) {
+                sender = sender.on_progress(progress_events());
+            }
+            if let Some(max_bytes) = max_bytes {
+                sender = sender.max_bytes(*max_bytes);
+            }
+            if let Some(proxy) = proxy {
+                sender = sender.transport(Socks5Transport::connect(proxy)?);
+            }
+            if let Some(path) = trace_packets {
+                sender = sender.trace_packets(Tracer::create(path)?);
+            }
+            if let Some(chaos) = chaos {
+                tell(
+                    &format!("Impairing the link with {chaos}"),
+                    "impairing",
+                    &[("chaos", json_string(&chaos.to_string()))],
+                );
+                sender = sender.chaos(*chaos);
+            }
+            if let Some(bytes) = sndbuf {
+                sender = sender.send_buffer(*bytes as usize);
+            }
+            if let Some(bytes) = rcvbuf {
+                sender = sender.recv_buffer(*bytes as usize);
+            }
+            if let Some(dscp) = dscp {
+                sender = sender.dscp(*dscp);
+            }
+            if *background {
+                sender = sender.congestion(Congestion::Background);
+            }
+            if let Some(cc) = cc {
+                sender = sender.congestion(*cc);
+            }
+            if let Pacing::Off = pacing {
+                sender = sender.pacing(false);
+            }
+            if *delta {
+                sender = sender.delta(true);
+            }
+            if *dedup {
+                sender = sender.dedup(true);
+            }
+            if *verify {
+                sender = sender.verify(true);
+            }
+            if let Some(token) = token {
+                sender = sender.token(token);
+            }
+            if *resume {
+                sender = sender.resume(true);
+            }
+            match stats {
+                // The statistics are events like the others.
+                _ if JSON.load(Ordering::Relaxed) => {
+                    sender = sender.on_stats(|stats| {
+                        let json = stats.to_json();
+                        println!("{{\"event\":\"stats\",{}", &json[1..])
+                    })
+                }
+                Some(StatsFormat::Table) => sender = sender.on_stats(|stats| print!("{stats}")),
+                Some(StatsFormat::Json) => {
+                    sender = sender.on_stats(|stats| println!("{}", stats.to_json()))
+                }
+                None => {}
+            }
+            if let Some(code) = code {
+                let code = match code {
+                    Some(code) => code.trim().to_string(),
+                    None => {
+                        let code = rendezvous::new_code()?;
+                        tell(
+                            &format!(
+                                "Code: {code}\nOn the receiving host: sanic receive {code} --relay {ip}"
+                            ),
+                            "code",
+                            &[("code", json_string(&code))],
+                        );
+                        code
+                    }
+                };
+                sender = sender
+                    .rendezvous(code)
+                    .relay_fallback(relay_fallback_after.map(Duration::from_secs));
+            }
+            if *key_stdin {
+                sender = sender.key(read_key()?);
+            }
+            if let Some(path) = key_file {
+                sender = sender.key(read_key_file(path)?);
+            }
+            if let Some((first, others)) = bind.split_first() {
+                sender = sender.bind(bind_addr(first));
+                for addr in others {
+                    sender = sender.path(bind_addr(addr));
+                }
+            }
+            if let Some(text) = text {
+                return send_text(sender, text);
+            }
+            if *clipboard {
+                return send_clipboard(sender);
+            }
+            if let Some(dir) = watch {
+                let mut watcher = Watcher::new(dir, sender)
+                    .debounce(Duration::from_secs(*debounce))
+                    .on_sent(|path| {
+                        let path = path.display().to_string();
+                        tell(
+                            &format!("Sent {path}"),
+                            "sent",
+                            &[("file", json_string(&path))],
+                        )
+                    });
+                if let Some(path) = watch_record {
+                    watcher = watcher.record(path);
+                }
+                tell(
+                    &format!("Watching {}, sending to {ip}", dir.display()),
+                    "watching",
+                    &[
+                        ("dir", json_string(&dir.display().to_string())),
+                        ("to", json_string(ip)),
+                    ],
+                );
+                return Ok(watcher.run()?);
+            }
+            match group {
+                Some(group) => send_group(sender, group, files),
+                None => files
+                    .iter()
+                    .try_for_each(|file| send(sender.clone(), file, None)),
             }
         }
+        Commands::Receive {
+            code,
+            once,
+            output,
+            port,
+            key_stdin,
+            key_file,
+            idle_timeout,
+            max_duration,
+            keep_partial,
+            resume,
+            sparse,
+            allow_delete,
+            no_tcp_fallback,
+            scan_clamd,
+            scan_command,
+            quarantine,
+            allow,
+            deny,
+            rate_limit,
+            max_bytes,
+            max_file_size,
+            quota,
+            max_sessions,
+            weight,
+            control_socket,
+            web,
+            metrics,
+            journal,
+            on_complete,
+            webhook,
+            accept_hook,
+            pipe,
+            to,
+            plain_http,
+            trace_packets,
+            chaos,
+            sndbuf,
+            rcvbuf,
+            dscp,
+            max_in_flight,
+            chunk_cache,
+            multicast,
+            announce,
+            upnp,
+            proxy,
+            relay,
+            relay_fallback,
+            clipboard,
+            speedtest,
+            json: _,
+        } => {
+            let mut receiver = Receiver::new()
+                .bind(format!("0.0.0.0:{port}"))
+                .once(*once)
+                .idle_timeout(Duration::from_secs(*idle_timeout))
+                .keep_partial(*keep_partial)
+                .resume(*resume)
+                .sparse(*sparse)
+                .allow_delete(*allow_delete)
+                .tcp_fallback(!no_tcp_fallback)
+                .speedtest(*speedtest)
+                .announce(*announce)
+                .upnp(*upnp)
+                .on_port_mapped(|addr| {
+                    tell(
+                        &format!("Reachable from the internet at {addr}"),
+                        "mapped",
+                        &[("addr", json_string(&addr.to_string()))],
+                    )
+                })
+                .on_text({
+                    let to_clipboard = *clipboard;
+                    move |peer, text| {
+                        let peer = peer.map(|peer| peer.to_string());
+                        if copied(to_clipboard, "text", peer.as_deref(), || {
+                            clipboard::write_text(text)
+                        }) {
+                            return;
+                        }
+                        tell(
+                            &format!("Message from {}:\n{text}", peer.as_deref().unwrap_or("?")),
+                            "text",
+                            &[
+                                (
+                                    "peer",
+                                    peer.as_deref()
+                                        .map_or_else(|| "null".to_string(), json_string),
+                                ),
+                                ("text", json_string(text)),
+                            ],
+                        )
+                    }
+                });
+            // Before any thread starts, as it clears the variables telling about it.
+            #[cfg(target_os = "linux")]
+            if let Some(socket) = systemd::listen_socket()? {
+                receiver = receiver.socket(socket);
+            }
+            if JSON.load(Ordering::Relaxed) {
+                receiver = receiver.on_progress(progress_events());
+            }
+            // Where images from a clipboard land, as for any file.
+            let image = clipboard.then(|| match output {
+                Some(output) if output.is_dir() => output.join(clipboard::IMAGE_NAME),
+                Some(output) => output.clone(),
+                None => PathBuf::from(clipboard::IMAGE_NAME),
+            });
+            if JSON.load(Ordering::Relaxed) || image.is_some() {
+                receiver = receiver.on_transfer_end(move |line| {
+                    if JSON.load(Ordering::Relaxed) {
+                        println!("{{\"event\":\"transfer\",{}", &line[1..]);
+                    }
+                    if let Some(image) = &image {
+                        copy_image(line, image);
+                    }
+                });
+            }
+            if let Some(max_duration) = max_duration {
+                receiver = receiver.max_duration(Duration::from_secs(*max_duration));
+            }
+            if let Some(max_bytes) = max_bytes {
+                receiver = receiver.max_bytes(*max_bytes);
+            }
+            if let Some(max_file_size) = max_file_size {
+                receiver = receiver.max_file_size(*max_file_size);
+            }
+            if let Some(quota) = quota {
+                receiver = receiver.quota(*quota);
+            }
+            receiver = receiver.max_sessions(*max_sessions);
+            for weight in weight {
+                receiver = receiver.weight(weight.clone());
+            }
+            if let Some(output) = output {
+                receiver = receiver.output(output);
+            }
+            if let Some(socket) = scan_clamd {
+                receiver = receiver.scanner(Scanner::Clamd(socket.clone()));
+            }
+            if let Some(command) = scan_command {
+                receiver = receiver.scanner(Scanner::Command(command.clone()));
+            }
+            if let Some(quarantine) = quarantine {
+                receiver = receiver.quarantine(quarantine);
+            }
+            let access = allow.iter().cloned().fold(Access::new(), Access::allow);
+            receiver = receiver.access(deny.iter().copied().fold(access, Access::deny));
+            if let Some(proxy) = proxy {
+                let transport = Socks5Transport::connect(proxy)?;
+                let relay = transport.relay();
+                tell(
+                    &format!("Reachable through the proxy at {relay}"),
+                    "proxied",
+                    &[("addr", json_string(&relay.to_string()))],
+                );
+                receiver = receiver.transport(transport);
+            }
+            if let Some(group) = multicast {
+                receiver = receiver.multicast(*group);
+            }
+            if let (Some(relay), Some(code)) = (relay, code) {
+                receiver = receiver
+                    .rendezvous(relay_addr(relay), code.trim())
+                    .relay_fallback(*relay_fallback);
+            }
+            let rate_cap = RateCap::new(*rate_limit);
+            receiver = receiver.rate_cap(rate_cap.clone());
+            if control_socket.is_some() || web.is_some() {
+                let activity = Activity::default();
+                if let Some(path) = control_socket {
+                    control::serve(path, rate_cap, activity.clone(), None)?;
+                }
+                if let Some(addr) = web {
+                    let dir = output.clone().unwrap_or_else(|| PathBuf::from("."));
+                    let addr = Web::new(dir, activity.clone()).serve(addr)?;
+                    tell(
+                        &format!("Serving the web page on http://{addr}/"),
+                        "web",
+                        &[("addr", json_string(&addr.to_string()))],
+                    );
+                }
+                receiver = receiver.activity(activity);
+            }
+            if let Some(addr) = metrics {
+                let metrics = Metrics::default();
+                metrics.serve(addr)?;
+                receiver = receiver.metrics(metrics);
+            }
+            if let Some(path) = journal {
+                receiver = receiver.journal(Journal::open(path)?);
+            }
+            if let Some(command) = on_complete {
+                receiver = receiver.hook(Hook::Command(command.clone()));
+            }
+            if let Some(url) = webhook {
+                receiver = receiver.hook(Hook::Webhook(url.clone()));
+            }
+            if let Some(command) = accept_hook {
+                receiver = receiver.accept_hook(command);
+            }
+            if let Some(command) = pipe.clone() {
+                receiver = receiver.sink(move |filename, size| {
+                    Ok(Box::new(Process::spawn(&command, filename, size)?) as Box<dyn Sink>)
+                });
+            }
+            if let Some(url) = to.clone() {
+                let s3 = S3::from_env()?.plain_http(*plain_http);
+                receiver = receiver.sink(move |filename, size| {
+                    let upload = s3.upload(&url.bucket, &url.key_for(filename), size)?;
+                    Ok(Box::new(upload) as Box<dyn Sink>)
+                });
+            }
+            if let Some(path) = trace_packets {
+                receiver = receiver.trace_packets(Tracer::create(path)?);
+            }
+            if let Some(chaos) = chaos {
+                tell(
+                    &format!("Impairing the link with {chaos}"),
+                    "impairing",
+                    &[("chaos", json_string(&chaos.to_string()))],
+                );
+                receiver = receiver.chaos(*chaos);
+            }
+            if let Some(bytes) = sndbuf {
+                receiver = receiver.send_buffer(*bytes as usize);
+            }
+            if let Some(bytes) = rcvbuf {
+                receiver = receiver.recv_buffer(*bytes as usize);
+            }
+            if let Some(dscp) = dscp {
+                receiver = receiver.dscp(*dscp);
+            }
+            if let Some(parts) = max_in_flight {
+                receiver = receiver.max_in_flight(*parts);
+            }
+            if let Some(dir) = chunk_cache {
+                receiver = receiver.chunk_cache(dir);
+            }
+            if let Some(path) = key_file {
+                receiver = receiver.key(read_key_file(path)?);
+            }
+            receive(receiver, *key_stdin)
+        }
+        Commands::Discover { timeout, port, .. } => discover(Duration::from_secs(*timeout), *port),
+        Commands::Doctor {
+            ip,
+            timeout,
+            speedtest,
+        } => doctor(
+            &peer_addr(ip),
+            Duration::from_secs(*timeout),
+            Duration::from_secs(*speedtest),
+        ),
+        Commands::Speedtest { ip, duration, .. } => {
+            speedtest(&peer_addr(ip), Duration::from_secs(*duration))
+        }
+        Commands::Forward { next, port, name } => {
+            let mut forwarder = Forwarder::new(next.clone()).bind(format!("0.0.0.0:{port}"));
+            if let Some(name) = name {
+                forwarder = forwarder.name(name.clone());
+            }
+            println!("Forwarding port {port} to {next}");
+            Ok(forwarder.run()?)
+        }
+        Commands::Relay {
+            port,
+            ttl,
+            forward_rate,
+        } => {
+            let mut relay = Relay::new()
+                .bind(format!("0.0.0.0:{port}"))
+                .ttl(Duration::from_secs(*ttl));
+            if let Some(rate) = forward_rate {
+                relay = relay.forward(*rate);
+            }
+            println!("Relaying introductions on port {port}");
+            Ok(relay.run()?)
+        }
+        Commands::Serve {
+            dir,
+            port,
+            connect_timeout,
+            downloads,
+            rate_limit,
+            bulk_above,
+            slices,
+        } => {
+            let library = Library::new(dir)
+                .bind(format!("0.0.0.0:{port}"))
+                .connect_timeout(Duration::from_secs(*connect_timeout))
+                .downloads(*downloads)
+                .rate_cap(RateCap::new(*rate_limit))
+                .bulk_above(*bulk_above)
+                .slices(*slices);
+            println!("Serving {} on port {port}", dir.display());
+            Ok(library.serve()?)
+        }
+        Commands::Ls {
+            ip,
+            port,
+            idle_timeout,
+        } => {
+            let receiver = Receiver::new().idle_timeout(Duration::from_secs(*idle_timeout));
+            for entry in receiver.list(&format!("{ip}:{port}"))? {
+                let sha256 = merkle::hex(&entry.sha256);
+                println!("{:>12}  {sha256}  {}", entry.size, entry.path);
+            }
+            Ok(())
+        }
+        Commands::Get {
+            ip,
+            name,
+            range,
+            port,
+            output,
+            idle_timeout,
+        } => {
+            let mut receiver = Receiver::new().idle_timeout(Duration::from_secs(*idle_timeout));
+            if let Some(output) = output {
+                receiver = receiver.output(output);
+            }
+            let server = format!("{ip}:{port}");
+            match range {
+                Some(range) => Ok(receiver.get_range(&server, name, range.clone())?),
+                None => Ok(receiver.get(&server, name)?),
+            }
+        }
+        Commands::Trace {
+            command: TraceCommand::Dump { file },
+        } => match trace::dump(file, &mut std::io::stdout().lock()) {
+            // Piped into a pager or head, which quit early.
+            Err(err) if err.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+            result => Ok(result?),
+        },
+        Commands::Verify { file, expected } => verify(file, expected),
+        Commands::Bench { size, sim, runs } => bench::bench(*size, *sim, *runs),
+        Commands::Cp {
+            src,
+            dest,
+            ssh,
+            remote_sanic,
+            encrypt,
+            connect_timeout,
+            retries,
+        } => cp(
+            src,
+            dest,
+            ssh,
+            remote_sanic,
+            *encrypt,
+            Duration::from_secs(*connect_timeout),
+            *retries,
+        ),
+        Commands::Sync {
+            src,
+            dest,
+            delete,
+            checksum,
+            delta,
+            connect_timeout,
+            retries,
+        } => {
+            let invalid = || RemoteError::InvalidDestination(dest.clone());
+            let dest = Destination::parse(dest)?;
+            if dest.user.is_some() {
+                return Err(invalid().into());
+            }
+            let mirror = Mirror {
+                dir: mirror_dir(&dest.path).ok_or_else(invalid)?,
+                checksum: *checksum,
+                delete: *delete,
+            };
+            let sender = Sender::new(peer_addr(&dest.host))
+                .connect_timeout(Duration::from_secs(*connect_timeout))
+                .retries(*retries)
+                .delta(*delta);
+            println!("Syncing {} to {}", src.display(), sender.addr());
+            sender.mirror(src, &mirror)?;
+            println!("Finished");
+            Ok(())
+        }
+        Commands::Queue {
+            ip,
+            control_socket,
+            slots,
+            class,
+            preempt,
+            rate_limit,
+            connect_timeout,
+            retries,
+        } => {
+            // Each transfer running needs a port of its own.
+            let activity = Activity::default();
+            let sender = Sender::new(peer_addr(ip))
+                .bind("0.0.0.0:0")
+                .connect_timeout(Duration::from_secs(*connect_timeout))
+                .retries(*retries)
+                .activity(activity.clone());
+            let rate_cap = RateCap::new(*rate_limit);
+            let mut queue = Queue::new(sender)
+                .slots(*slots)
+                .rate_cap(rate_cap.clone())
+                .preempt(*preempt)
+                .on_done(|path, sent| match sent {
+                    Ok(()) => println!("Sent {}", path.display()),
+                    Err(err) => println!("Could not send {}: {err}", path.display()),
+                });
+            for class in class {
+                queue = queue.class(*class);
+            }
+            control::serve(control_socket, rate_cap, activity, Some(queue.clone()))?;
+            println!("Queue ready at {}", control_socket.display());
+            Ok(queue.run()?)
+        }
+        Commands::Enqueue {
+            control_socket,
+            priority,
+            files,
+        } => {
+            let commands = files
+                .iter()
+                .map(|file| {
+                    let path = std::fs::canonicalize(file)?;
+                    Ok(format!("enqueue {priority} {}", path.display()))
+                })
+                .collect::<std::io::Result<Vec<_>>>()?;
+            control::run(control_socket, &commands)?;
+            println!("Queued {} files", commands.len());
+            Ok(())
+        }
+        Commands::Ctl {
+            control_socket,
+            command,
+        } => {
+            for answer in control::run(control_socket, &[command.join(" ")])? {
+                if !answer.is_empty() {
+                    println!("{answer}");
+                }
+            }
+            Ok(())
+        }
+        #[cfg(feature = "tui")]
+        Commands::Top {
+            control_socket,
+            interval,
+        } => top::top(control_socket, Duration::from_secs((*interval).max(1))),
+    }
+}
+
+/// `dir` with its components separated by `/`, as a Manifest takes it, None unless it is
+/// relative and stays under where it starts.
+fn mirror_dir(dir: &Path) -> Option<String> {
+    let mut names = Vec::new();
+    for component in dir.components() {
+        match component {
+            Component::Normal(name) => names.push(name.to_str()?),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(names.join("/"))
+}
+
+/// Address of the receiver `ip` names. Names without a domain, or under `.local`, are
+/// looked up over mDNS first, then among the receivers answering a broadcast probe, then
+/// like any other host name.
+fn peer_addr(ip: &str) -> String {
+    if ip.parse::<IpAddr>().is_err() && (!ip.contains('.') || ip.ends_with(".local")) {
+        #[cfg(feature = "mdns")]
+        if let Ok(Some(addr)) = mdns::resolve(ip, MDNS_TIMEOUT) {
+            return addr.to_string();
+        }
+        let bare = ip.strip_suffix(".local").unwrap_or(ip);
+        if let Ok(found) = probe::broadcast(6666, MDNS_TIMEOUT) {
+            if let Some(presence) = found
+                .iter()
+                .find(|presence| presence.hostname.eq_ignore_ascii_case(bare))
+            {
+                return presence.addr.to_string();
+            }
+        }
+    }
+    format!("{ip}:6666")
+}
+
+/// Lists the receivers found over mDNS and by a broadcast probe, at the same time, with
+/// the free space of the ones that answered the probe.
+fn discover(timeout: Duration, port: u16) -> Result<(), CliError> {
+    let probing = std::thread::spawn(move || probe::broadcast(port, timeout));
+    #[cfg(feature = "mdns")]
+    let services: Vec<(String, String, SocketAddr)> = mdns::browse(timeout)?
+        .into_iter()
+        .map(|service| (service.name, service.host, service.addr))
+        .collect();
+    #[cfg(not(feature = "mdns"))]
+    let services: Vec<(String, String, SocketAddr)> = Vec::new();
+    let mut found = probing.join().expect("Probe thread panicked")?;
+
+    let mut lines = Vec::new();
+    for (name, host, addr) in services {
+        let free = found
+            .iter()
+            .position(|presence| presence.addr == addr)
+            .map(|index| found.remove(index).free_bytes);
+        lines.push((name, host, addr, free.flatten()));
+    }
+    for presence in found {
+        lines.push((
+            presence.hostname,
+            "-".to_string(),
+            presence.addr,
+            presence.free_bytes,
+        ));
+    }
+    if lines.is_empty() && !JSON.load(Ordering::Relaxed) {
+        println!("No receiver found");
+    }
+    for (name, host, addr, free) in lines {
+        let text = format!(
+            "{name}\t{host}\t{addr}\t{}",
+            free.map_or_else(|| "-".to_string(), human_bytes)
+        );
+        let host = match host.as_str() {
+            "-" => "null".to_string(),
+            host => json_string(host),
+        };
+        tell(
+            &text,
+            "receiver",
+            &[
+                ("name", json_string(&name)),
+                ("host", host),
+                ("addr", json_string(&addr.to_string())),
+                (
+                    "free_bytes",
+                    free.map_or_else(|| "null".to_string(), |free| free.to_string()),
+                ),
+            ],
+        );
+    }
+    Ok(())
+}
+
+/// Runs a speed test against the receiver at `addr`, and tells how each way went.
+fn speedtest(addr: &str, duration: Duration) -> Result<(), CliError> {
+    if !JSON.load(Ordering::Relaxed) {
+        println!("Testing {addr} for {}s each way", duration.as_secs());
+    }
+    let report = SpeedTest::new(addr).duration(duration).run()?;
+    for (direction, way) in [("upload", report.upload), ("download", report.download)] {
+        tell(
+            &format!(
+                "{direction:<8}  {}/s  {:.2}% lost  {} in {:.1}s",
+                human_bytes(way.bytes_per_sec()),
+                way.loss() * 100.0,
+                human_bytes(way.bytes),
+                way.elapsed.as_secs_f64()
+            ),
+            direction,
+            &[
+                ("bytes", way.bytes.to_string()),
+                ("elapsed_ms", way.elapsed.as_millis().to_string()),
+                ("bytes_per_sec", way.bytes_per_sec().to_string()),
+                ("parts", way.parts.to_string()),
+                ("parts_lost", way.parts_lost.to_string()),
+            ],
+        );
+    }
+    if let Some(rtt) = report.rtt {
+        tell(
+            &format!(
+                "rtt       p50 {:.2?}  p90 {:.2?}  p99 {:.2?}  max {:.2?}",
+                rtt.p50, rtt.p90, rtt.p99, rtt.max
+            ),
+            "rtt",
+            &[
+                ("samples", rtt.samples.to_string()),
+                ("p50_us", rtt.p50.as_micros().to_string()),
+                ("p90_us", rtt.p90.as_micros().to_string()),
+                ("p99_us", rtt.p99.as_micros().to_string()),
+                ("max_us", rtt.max.as_micros().to_string()),
+            ],
+        );
+    }
+    Ok(())
+}
+
+/// Checks the path to the receiver at `addr`, and prints what was found and what to change.
+fn doctor(addr: &str, timeout: Duration, speedtest: Duration) -> Result<(), CliError> {
+    let diagnosis = Doctor::new(addr)
+        .timeout(timeout)
+        .speedtest(speedtest)
+        .run()?;
+    let rtt = diagnosis
+        .rtt
+        .map_or_else(String::new, |rtt| format!(", answered in {rtt:.2?}"));
+    match &diagnosis.reach {
+        Reach::Answered {
+            hostname,
+            free_bytes,
+        } => println!(
+            "Receiver    {} at {}{rtt}, {} free",
+            hostname,
+            diagnosis.addr,
+            free_bytes.map_or_else(|| "?".to_string(), human_bytes)
+        ),
+        Reach::Refused => println!("Receiver    none, {} refused the probe", diagnosis.addr),
+        Reach::Silent => println!("Receiver    no answer from {}", diagnosis.addr),
+    }
+    match diagnosis.path_mtu {
+        Some(mtu) => println!("Path MTU    datagrams of {mtu} bytes get through unfragmented"),
+        None => println!("Path MTU    not measured"),
+    }
+    match diagnosis.buffers {
+        Some(buffers) => println!(
+            "Buffers     net.core.rmem_max {}, net.core.wmem_max {}",
+            human_bytes(buffers.rmem_max as u64),
+            human_bytes(buffers.wmem_max as u64)
+        ),
+        None => println!("Buffers     unknown"),
+    }
+    match diagnosis.nat {
+        Nat::None => println!("NAT         none, {} is public", diagnosis.local.ip()),
+        Nat::Local => println!("NAT         none, the receiver is on our network"),
+        Nat::Mappable { external, method } => {
+            println!("NAT         the gateway forwards ports over {method}, from {external}")
+        }
+        Nat::Unmappable => println!("NAT         the gateway does not forward ports when asked"),
+    }
+    match &diagnosis.throughput {
+        Some(Ok(report)) => {
+            for (direction, way) in [("Upload", report.upload), ("Download", report.download)] {
+                println!(
+                    "{direction:<10}  {}/s, {:.2}% lost",
+                    human_bytes(way.bytes_per_sec()),
+                    way.loss() * 100.0
+                );
+            }
+        }
+        Some(Err(_)) => println!("Throughput  not measured"),
+        None => {}
+    }
+    let suggestions = diagnosis.suggestions();
+    if suggestions.is_empty() {
+        println!("Nothing to change.");
+    }
+    for suggestion in suggestions {
+        println!("- {suggestion}");
+    }
+    Ok(())
+}
+
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+/// Address of the rendezvous server `host`, on the default port unless it has one.
+fn relay_addr(host: &str) -> String {
+    match host.parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, 6668).to_string(),
+        Err(_) if host.contains(':') => host.to_string(),
+        Err(_) => format!("{host}:6668"),
+    }
+}
+
+/// A bare IP binds to a port the system picks.
+fn bind_addr(addr: &str) -> String {
+    match addr.parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, 0).to_string(),
+        Err(_) => addr.to_string(),
+    }
+}
+
+fn send(sender: Sender, file: &Path, key: Option<SessionKey>) -> Result<(), CliError> {
+    let disp_path = file.to_string_lossy();
+    let file_json = json_string(&disp_path);
+    tell(
+        &format!("Sending {disp_path} to {}", sender.addr()),
+        "sending",
+        &[
+            ("file", file_json.clone()),
+            ("to", json_string(sender.addr())),
+        ],
+    );
+    let mut sender = sender;
+    if let Some(key) = key {
+        sender = sender.key(key);
+    }
+    if file.is_dir() {
+        sender.send_batch(file)?;
+    } else {
+        sender.send(file)?;
+    }
+    tell("Finished", "sent", &[("file", file_json)]);
+    Ok(())
+}
+
+fn send_text(sender: Sender, text: &str) -> Result<(), CliError> {
+    tell(
+        &format!("Sending a message to {}", sender.addr()),
+        "sending",
+        &[
+            ("text", json_string(text)),
+            ("to", json_string(sender.addr())),
+        ],
+    );
+    sender.send_text(text)?;
+    tell("Finished", "sent", &[("text", json_string(text))]);
+    Ok(())
+}
+
+fn send_clipboard(sender: Sender) -> Result<(), CliError> {
+    match clipboard::read()? {
+        clipboard::Contents::Text(text) => send_text(sender, &text),
+        clipboard::Contents::Image(png) => {
+            // Sent under the name the receiver knows it by.
+            let dir = std::env::temp_dir().join(format!("sanic-clipboard-{}", std::process::id()));
+            std::fs::create_dir_all(&dir)?;
+            let path = dir.join(clipboard::IMAGE_NAME);
+            let sent = std::fs::write(&path, png)
+                .map_err(CliError::from)
+                .and_then(|()| send(sender, &path, None));
+            let _ = std::fs::remove_dir_all(&dir);
+            sent
+        }
+    }
+}
+
+fn send_multicast(sender: &MulticastSender, file: &Path) -> Result<(), CliError> {
+    let file_json = json_string(&file.to_string_lossy());
+    tell(
+        &format!(
+            "Sending {} to group {}",
+            file.to_string_lossy(),
+            sender.group()
+        ),
+        "sending",
+        &[
+            ("file", file_json.clone()),
+            ("to", json_string(sender.group())),
+        ],
+    );
+    let report = sender.send(file)?;
+    for addr in &report.delivered {
+        let addr_json = json_string(&addr.to_string());
+        tell(
+            &format!("Delivered to {addr}"),
+            "delivered",
+            &[("addr", addr_json)],
+        );
     }
+    for (addr, reason) in &report.dropped {
+        tell(
+            &format!("{addr} dropped out: {reason}"),
+            "dropped",
+            &[
+                ("addr", json_string(&addr.to_string())),
+                ("reason", json_string(reason)),
+            ],
+        );
+    }
+    if !report.dropped.is_empty() {
+        let total = report.delivered.len() + report.dropped.len();
+        let reason = format!(
+            "{} of {total} receivers did not get the file",
+            report.dropped.len()
+        );
+        return Err(SendError::Aborted(reason).into());
+    }
+    tell("Finished", "sent", &[("file", file_json)]);
+    Ok(())
+}
 
-    println!("Finished");
+fn send_group(sender: Sender, name: &str, files: &[PathBuf]) -> Result<(), CliError> {
+    tell(
+        &format!(
+            "Sending group {name} ({} files) to {}",
+            files.len(),
+            sender.addr()
+        ),
+        "sending",
+        &[
+            ("group", json_string(name)),
+            ("files", files.len().to_string()),
+            ("to", json_string(sender.addr())),
+        ],
+    );
+    sender.send_group(name, files)?;
+    tell("Finished", "sent", &[("group", json_string(name))]);
+    Ok(())
+}
+
+fn verify(file: &Path, expected: &str) -> Result<(), CliError> {
+    let digests = merkle::digest_file(file)?;
+    let expected = match merkle::parse_hex(expected) {
+        Some(digest) => digest,
+        None => {
+            let name = file
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            journal::last_digest(expected, &name)
+                .map_err(|err| {
+                    CliError::Mismatch(format!(
+                        "{expected} is neither a digest nor a journal we can read: {err}"
+                    ))
+                })?
+                .ok_or_else(|| {
+                    CliError::Mismatch(format!("{expected} has no completed transfer of {name}"))
+                })?
+        }
+    };
+    let matched = if expected == digests.sha256 {
+        "SHA-256"
+    } else if expected == digests.root {
+        "Merkle root"
+    } else {
+        return Err(CliError::Mismatch(format!(
+            "{} does not match: SHA-256 {}, Merkle root {}",
+            file.display(),
+            merkle::hex(&digests.sha256),
+            merkle::hex(&digests.root)
+        )));
+    };
+    println!("{}: OK ({matched})", file.display());
+    Ok(())
+}
+
+fn cp(
+    src: &Path,
+    dest: &str,
+    ssh: &str,
+    remote_sanic: &str,
+    encrypt: bool,
+    connect_timeout: Duration,
+    retries: u32,
+) -> Result<(), CliError> {
+    let dest = Destination::parse(dest)?;
+    let key = if encrypt {
+        Some(SessionKey::generate()?)
+    } else {
+        None
+    };
+    let remote = RemoteReceiver::spawn(ssh, remote_sanic, &dest, key.as_ref())?;
+
+    let host = if dest.host.contains(':') {
+        format!("[{}]", dest.host)
+    } else {
+        dest.host.clone()
+    };
+    let sender = Sender::new(format!("{host}:{}", remote.port))
+        .connect_timeout(connect_timeout)
+        .retries(retries);
+    let sent = send(sender, src, key);
+    // On success the receiver exits by itself thanks to --once.
+    let grace = if sent.is_ok() {
+        Duration::from_secs(10)
+    } else {
+        Duration::ZERO
+    };
+    remote.finish(grace);
+    sent
+}
+
+/// Reads the session key, in hex, from the first line of stdin.
+fn read_key() -> Result<SessionKey, CliError> {
+    let mut line = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut line)
+        .ok()
+        .and_then(|_| SessionKey::from_hex(&line))
+        .ok_or_else(|| CliError::Key("stdin".to_string()))
+}
+
+/// Reads the session key, in hex, from the first line of the file at `path`.
+fn read_key_file(path: &Path) -> Result<SessionKey, CliError> {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|text| SessionKey::from_hex(text.lines().next()?))
+        .ok_or_else(|| CliError::Key(path.display().to_string()))
+}
+
+/// Puts what `copy` copies in the clipboard when asked to, and tells whether it did. A
+/// failure is told, the caller then shows or keeps what came instead.
+fn copied(
+    clipboard: bool,
+    kind: &str,
+    peer: Option<&str>,
+    copy: impl FnOnce() -> std::io::Result<()>,
+) -> bool {
+    if !clipboard {
+        return false;
+    }
+    let peer_json = peer.map_or_else(|| "null".to_string(), json_string);
+    match copy() {
+        Ok(()) => {
+            tell(
+                &format!(
+                    "Copied the {kind} from {} to the clipboard",
+                    peer.unwrap_or("?")
+                ),
+                "copied",
+                &[("kind", json_string(kind)), ("peer", peer_json)],
+            );
+            true
+        }
+        Err(err) => {
+            tell(
+                &format!("Could not copy the {kind} to the clipboard: {err}"),
+                "copy_failed",
+                &[
+                    ("kind", json_string(kind)),
+                    ("error", json_string(&err.to_string())),
+                ],
+            );
+            false
+        }
+    }
+}
+
+/// Moves the image a sender's clipboard had into ours, once `line` of the journal tells it
+/// arrived at `path`.
+fn copy_image(line: &str, path: &Path) {
+    // Only lines the receiver wrote, so their fields are where it put them.
+    let name = format!("\"filename\":{},", json_string(clipboard::IMAGE_NAME));
+    if !line.contains(&name) || !line.ends_with("\"outcome\":\"completed\"}") {
+        return;
+    }
+    let peer = line
+        .split_once("\"peer\":\"")
+        .and_then(|(_, rest)| rest.split_once('"'))
+        .map(|(peer, _)| peer);
+    let copy = || std::fs::read(path).and_then(|png| clipboard::write_image(&png));
+    if copied(true, "image", peer, copy) {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+fn receive(receiver: Receiver, key_stdin: bool) -> Result<(), CliError> {
+    // Tells systemd we are ready once listening, for Type=notify services.
+    #[cfg(target_os = "linux")]
+    let notifier = Notifier::from_env()?.map(Arc::new);
+    #[cfg(target_os = "linux")]
+    if let Some(notifier) = &notifier {
+        notifier.keep_alive();
+    }
+    #[cfg(target_os = "linux")]
+    let ready = notifier.clone();
+    let mut receiver = receiver.on_listening(move |addr| {
+        tell(
+            &format!("Listening at port {}", addr.port()),
+            "listening",
+            &[("port", addr.port().to_string())],
+        );
+        #[cfg(target_os = "linux")]
+        if let Some(notifier) = &ready {
+            let _ = notifier.notify(&format!(
+                "READY=1\nSTATUS=Listening at port {}",
+                addr.port()
+            ));
+        }
+    });
+    if key_stdin {
+        receiver = receiver.key(read_key()?);
+    }
+    let received = receiver.receive();
+    #[cfg(target_os = "linux")]
+    if let Some(notifier) = &notifier {
+        let _ = notifier.notify("STOPPING=1");
+    }
+    received?;
+    tell("Finished", "finished", &[]);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Error, ErrorKind};
+
+    use super::*;
+
+    #[test]
+    fn failures_exit_with_their_class() {
+        let cases: Vec<(CliError, u8)> = vec![
+            (SendError::NoAnswer(3).into(), 3),
+            (ReceiveError::Disconnected.into(), 3),
+            (RemoteError::ReceiverDied.into(), 3),
+            (SendError::Aborted("no".to_string()).into(), 4),
+            (SendError::Incompatible("v2".to_string()).into(), 4),
+            (ReceiveError::Declined("no".to_string()).into(), 4),
+            (
+                ReceiveError::Rejected("a".to_string(), "Eicar".to_string()).into(),
+                4,
+            ),
+            (ReceiveError::TooLarge { size: 2, limit: 1 }.into(), 4),
+            (ReceiveError::Mismatch("a".to_string()).into(), 5),
+            (CliError::Mismatch("a".to_string()), 5),
+            (SendError::Quota(1).into(), 6),
+            (ReceiveError::NoSpace { needed: 2, free: 1 }.into(), 6),
+            (SendError::Cancelled.into(), 7),
+            (ReceiveError::Cancelled.into(), 7),
+            (ReceiveError::Inactive(Duration::from_secs(1)).into(), 8),
+            (ReceiveError::TooLong(Duration::from_secs(1)).into(), 8),
+            (
+                RemoteError::InvalidDestination("-oops".to_string()).into(),
+                2,
+            ),
+            (CliError::Key("key".to_string()), 2),
+            (Error::from(ErrorKind::PermissionDenied).into(), 1),
+        ];
+        for (err, code) in cases {
+            assert_eq!(err.exit_code(), code, "{err:?}");
+        }
+    }
+
+    #[test]
+    fn io_errors_exit_like_the_failures_they_stand_for() {
+        for (kind, code) in [
+            (ErrorKind::ConnectionRefused, 3),
+            (ErrorKind::HostUnreachable, 3),
+            (ErrorKind::StorageFull, 6),
+            (ErrorKind::TimedOut, 8),
+            (ErrorKind::NotFound, 1),
+        ] {
+            assert_eq!(CliError::Io(kind.into()).exit_code(), code);
+            assert_eq!(CliError::Send(SendError::Io(kind.into())).exit_code(), code);
+            assert_eq!(
+                CliError::Receive(ReceiveError::Io(kind.into())).exit_code(),
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn every_exit_code_is_documented() {
+        for code in 0..=8 {
+            assert!(EXIT_CODES.contains(&format!("\n  {code}  ")), "{code}");
+        }
+    }
 }
@@ -0,0 +1,672 @@
+//! Just enough multicast DNS (RFC 6762) and DNS service discovery (RFC 6763) for receivers
+//! to announce themselves as `_sanic._udp` services, and for senders to find them.
+
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use thiserror::Error;
+use tracing::{debug, info, warn};
+
+use crate::probe::local_ip;
+
+/// Service type receivers register under.
+pub const SERVICE: &str = "_sanic._udp.local";
+
+const GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 251), PORT);
+const PORT: u16 = 5353;
+/// Seconds the records stay in the caches of other hosts.
+const TTL: u32 = 120;
+/// How often the responder wakes up to notice it was dropped.
+const POLL: Duration = Duration::from_millis(500);
+/// Largest mDNS message, jumbo frames included.
+const MAX_MESSAGE: usize = 9000;
+/// Compression pointers followed in a single name before giving up on it.
+const MAX_POINTERS: usize = 32;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Top bit of the class: "unicast response" in questions, "cache flush" in records.
+const CLASS_FLAG: u16 = 0x8000;
+const FLAGS_RESPONSE: u16 = 0x8400;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum DnsError {
+    #[error("DNS message truncated.")]
+    Truncated,
+
+    #[error("Invalid name in DNS message.")]
+    BadName,
+}
+
+/// Receiver found on the network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Service {
+    /// Instance name, the host name of the receiver unless it picked another one.
+    pub name: String,
+    /// Host name, under `.local`.
+    pub host: String,
+    pub addr: SocketAddr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Question {
+    name: String,
+    qtype: u16,
+    /// The querier asked for the answer over unicast.
+    unicast: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum RData {
+    Ptr(String),
+    Srv { port: u16, target: String },
+    Txt(Vec<String>),
+    A(Ipv4Addr),
+    Other(u16),
+}
+
+impl RData {
+    fn rtype(&self) -> u16 {
+        match self {
+            RData::Ptr(_) => TYPE_PTR,
+            RData::Srv { .. } => TYPE_SRV,
+            RData::Txt(_) => TYPE_TXT,
+            RData::A(_) => TYPE_A,
+            RData::Other(rtype) => *rtype,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Record {
+    name: String,
+    ttl: u32,
+    data: RData,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct DnsMessage {
+    id: u16,
+    response: bool,
+    questions: Vec<Question>,
+    /// Answers and additional records alike.
+    records: Vec<Record>,
+}
+
+impl DnsMessage {
+    fn parse(data: &[u8]) -> Result<Self, DnsError> {
+        let mut reader = Reader { data, pos: 0 };
+        let id = reader.u16()?;
+        let flags = reader.u16()?;
+        let questions = reader.u16()?;
+        let counts = [reader.u16()?, reader.u16()?, reader.u16()?];
+        let mut message = DnsMessage {
+            id,
+            response: flags & 0x8000 != 0,
+            ..Default::default()
+        };
+        for _ in 0..questions {
+            let name = reader.name()?;
+            let qtype = reader.u16()?;
+            let class = reader.u16()?;
+            message.questions.push(Question {
+                name,
+                qtype,
+                unicast: class & CLASS_FLAG != 0,
+            });
+        }
+        for _ in 0..counts.iter().map(|count| *count as usize).sum::<usize>() {
+            let name = reader.name()?;
+            let rtype = reader.u16()?;
+            let _class = reader.u16()?;
+            let ttl = reader.u32()?;
+            let len = reader.u16()? as usize;
+            let end = reader.pos + len;
+            if end > data.len() {
+                return Err(DnsError::Truncated);
+            }
+            let data = match rtype {
+                TYPE_PTR => RData::Ptr(reader.name()?),
+                TYPE_SRV => {
+                    let _priority = reader.u16()?;
+                    let _weight = reader.u16()?;
+                    let port = reader.u16()?;
+                    RData::Srv {
+                        port,
+                        target: reader.name()?,
+                    }
+                }
+                TYPE_TXT => {
+                    let mut strings = Vec::new();
+                    while reader.pos < end {
+                        let len = reader.u8()? as usize;
+                        let string = reader.take(len)?;
+                        strings.push(String::from_utf8_lossy(string).to_string());
+                    }
+                    RData::Txt(strings)
+                }
+                TYPE_A if len == 4 => {
+                    let octets: [u8; 4] = reader.take(4)?.try_into().expect("Four bytes");
+                    RData::A(Ipv4Addr::from(octets))
+                }
+                rtype => RData::Other(rtype),
+            };
+            reader.pos = end;
+            message.records.push(Record { name, ttl, data });
+        }
+        Ok(message)
+    }
+
+    /// Serializes the message, without name compression.
+    fn serialize(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(512);
+        buf.extend(self.id.to_be_bytes());
+        let flags = if self.response { FLAGS_RESPONSE } else { 0 };
+        buf.extend(flags.to_be_bytes());
+        buf.extend((self.questions.len() as u16).to_be_bytes());
+        buf.extend((self.records.len() as u16).to_be_bytes());
+        buf.extend([0; 4]);
+        for question in &self.questions {
+            write_name(&mut buf, &question.name);
+            buf.extend(question.qtype.to_be_bytes());
+            let class = CLASS_IN | if question.unicast { CLASS_FLAG } else { 0 };
+            buf.extend(class.to_be_bytes());
+        }
+        for record in &self.records {
+            write_name(&mut buf, &record.name);
+            buf.extend(record.data.rtype().to_be_bytes());
+            // Only the PTR is shared with the other receivers, we own the rest.
+            let class = match record.data {
+                RData::Ptr(_) => CLASS_IN,
+                _ => CLASS_IN | CLASS_FLAG,
+            };
+            buf.extend(class.to_be_bytes());
+            buf.extend(record.ttl.to_be_bytes());
+            let len_at = buf.len();
+            buf.extend([0; 2]);
+            match &record.data {
+                RData::Ptr(name) => write_name(&mut buf, name),
+                RData::Srv { port, target } => {
+                    buf.extend([0; 4]);
+                    buf.extend(port.to_be_bytes());
+                    write_name(&mut buf, target);
+                }
+                RData::Txt(strings) => {
+                    for string in strings {
+                        let string = &string.as_bytes()[..string.len().min(255)];
+                        buf.push(string.len() as u8);
+                        buf.extend(string);
+                    }
+                    // An empty TXT still holds one empty string.
+                    if strings.is_empty() {
+                        buf.push(0);
+                    }
+                }
+                RData::A(ip) => buf.extend(ip.octets()),
+                RData::Other(_) => {}
+            }
+            let len = (buf.len() - len_at - 2) as u16;
+            buf[len_at..len_at + 2].copy_from_slice(&len.to_be_bytes());
+        }
+        buf
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], DnsError> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or(DnsError::Truncated)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, DnsError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, DnsError> {
+        Ok(u16::from_be_bytes(
+            self.take(2)?.try_into().expect("Two bytes"),
+        ))
+    }
+
+    fn u32(&mut self) -> Result<u32, DnsError> {
+        Ok(u32::from_be_bytes(
+            self.take(4)?.try_into().expect("Four bytes"),
+        ))
+    }
+
+    /// Reads a name, following compression pointers.
+    fn name(&mut self) -> Result<String, DnsError> {
+        let mut labels: Vec<String> = Vec::new();
+        let mut pos = self.pos;
+        let mut jumped = false;
+        let mut pointers = 0;
+        loop {
+            let len = *self.data.get(pos).ok_or(DnsError::Truncated)? as usize;
+            match len {
+                0 => {
+                    if !jumped {
+                        self.pos = pos + 1;
+                    }
+                    return Ok(labels.join("."));
+                }
+                len if len & 0xc0 == 0xc0 => {
+                    let low = *self.data.get(pos + 1).ok_or(DnsError::Truncated)? as usize;
+                    pointers += 1;
+                    if pointers > MAX_POINTERS {
+                        return Err(DnsError::BadName);
+                    }
+                    if !jumped {
+                        self.pos = pos + 2;
+                        jumped = true;
+                    }
+                    pos = (len & 0x3f) << 8 | low;
+                }
+                len if len & 0xc0 != 0 => return Err(DnsError::BadName),
+                len => {
+                    let label = self
+                        .data
+                        .get(pos + 1..pos + 1 + len)
+                        .ok_or(DnsError::Truncated)?;
+                    labels.push(String::from_utf8_lossy(label).to_string());
+                    pos += 1 + len;
+                }
+            }
+        }
+    }
+}
+
+fn write_name(buf: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        buf.push(label.len() as u8);
+        buf.extend(label);
+    }
+    buf.push(0);
+}
+
+/// Records of an announced receiver.
+struct Records {
+    /// Full instance name, `<name>._sanic._udp.local`.
+    instance: String,
+    /// `<host>.local`.
+    host: String,
+    port: u16,
+}
+
+impl Records {
+    fn new(name: &str, port: u16) -> Self {
+        // Dots would split the instance name in several labels.
+        let name = name.replace('.', "-");
+        Records {
+            instance: format!("{name}.{SERVICE}"),
+            host: format!("{name}.local"),
+            port,
+        }
+    }
+
+    fn concerns(&self, question: &Question) -> bool {
+        let name = question.name.as_str();
+        let matches = |other: &str| name.eq_ignore_ascii_case(other);
+        match question.qtype {
+            TYPE_PTR => matches(SERVICE),
+            TYPE_SRV | TYPE_TXT => matches(&self.instance),
+            TYPE_A => matches(&self.host),
+            TYPE_ANY => matches(SERVICE) || matches(&self.instance) || matches(&self.host),
+            _ => false,
+        }
+    }
+
+    fn all(&self, ip: Ipv4Addr, ttl: u32) -> Vec<Record> {
+        let record = |name: &str, data| Record {
+            name: name.to_string(),
+            ttl,
+            data,
+        };
+        vec![
+            record(SERVICE, RData::Ptr(self.instance.clone())),
+            record(
+                &self.instance,
+                RData::Srv {
+                    port: self.port,
+                    target: self.host.clone(),
+                },
+            ),
+            record(&self.instance, RData::Txt(vec!["v=1".to_string()])),
+            record(&self.host, RData::A(ip)),
+        ]
+    }
+}
+
+/// Answers mDNS queries for a receiver, until dropped.
+pub struct Announcer {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Announcer {
+    /// Announces the receiver listening on `port` as `name`, and keeps answering queries
+    /// about it. Needs UDP port 5353, which a system responder like avahi may hold.
+    pub fn start(name: &str, port: u16) -> io::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, PORT)).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("could not bind the mDNS port {PORT}, is another responder running? {err}"),
+            )
+        })?;
+        socket.join_multicast_v4(GROUP.ip(), &Ipv4Addr::UNSPECIFIED)?;
+        socket.set_multicast_ttl_v4(255)?;
+        socket.set_read_timeout(Some(POLL))?;
+        let records = Records::new(name, port);
+        info!(instance = records.instance, "Announcing over mDNS.");
+        announce(&socket, &records, TTL)?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            std::thread::spawn(move || respond(socket, records, stop))
+        };
+        Ok(Announcer {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Announcer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Sends every record to the group, unasked. A zero `ttl` withdraws them.
+fn announce(socket: &UdpSocket, records: &Records, ttl: u32) -> io::Result<()> {
+    let message = DnsMessage {
+        response: true,
+        records: records.all(local_ip(GROUP.into())?, ttl),
+        ..Default::default()
+    };
+    socket.send_to(&message.serialize(), GROUP)?;
+    Ok(())
+}
+
+fn respond(socket: UdpSocket, records: Records, stop: Arc<AtomicBool>) {
+    let mut buf = vec![0; MAX_MESSAGE];
+    while !stop.load(Ordering::Relaxed) {
+        let (size, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                continue
+            }
+            Err(err) => {
+                warn!(error = ?err, "mDNS responder failed.");
+                return;
+            }
+        };
+        let query = match DnsMessage::parse(&buf[..size]) {
+            Ok(query) if !query.response => query,
+            Ok(_) => continue,
+            Err(err) => {
+                debug!(%from, error = ?err, "Could not parse mDNS message.");
+                continue;
+            }
+        };
+        let asked: Vec<Question> = query
+            .questions
+            .into_iter()
+            .filter(|question| records.concerns(question))
+            .collect();
+        if asked.is_empty() {
+            continue;
+        }
+        // One-shot queriers do not listen on 5353: they get their id and questions back,
+        // over unicast, like a plain DNS answer.
+        let legacy = from.port() != PORT;
+        let to = if legacy || asked.iter().any(|question| question.unicast) {
+            from
+        } else {
+            GROUP.into()
+        };
+        let ip = match local_ip(to) {
+            Ok(ip) => ip,
+            Err(err) => {
+                debug!(%to, error = ?err, "No route to the querier.");
+                continue;
+            }
+        };
+        let answer = DnsMessage {
+            id: if legacy { query.id } else { 0 },
+            response: true,
+            questions: if legacy { asked } else { Vec::new() },
+            records: records.all(ip, TTL),
+        };
+        debug!(%from, "Answering mDNS query.");
+        if let Err(err) = socket.send_to(&answer.serialize(), to) {
+            debug!(%to, error = ?err, "Could not answer mDNS query.");
+        }
+    }
+    if let Err(err) = announce(&socket, &records, 0) {
+        debug!(error = ?err, "Could not withdraw the mDNS records.");
+    }
+}
+
+/// Lists the receivers answering within `timeout`.
+pub fn browse(timeout: Duration) -> io::Result<Vec<Service>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_multicast_ttl_v4(255)?;
+    let query = DnsMessage {
+        id: std::process::id() as u16,
+        questions: vec![Question {
+            name: SERVICE.to_string(),
+            qtype: TYPE_PTR,
+            unicast: true,
+        }],
+        ..Default::default()
+    }
+    .serialize();
+
+    let start = Instant::now();
+    let mut asked = 0;
+    let mut records = Vec::new();
+    let mut buf = vec![0; MAX_MESSAGE];
+    loop {
+        let elapsed = start.elapsed();
+        if elapsed >= timeout {
+            break;
+        }
+        // Asked a second time halfway, in case the first query got lost.
+        if asked == 0 || (asked == 1 && elapsed >= timeout / 2) {
+            socket.send_to(&query, GROUP)?;
+            asked += 1;
+        }
+        let wait = (timeout - elapsed)
+            .min(timeout / 2)
+            .max(Duration::from_millis(1));
+        socket.set_read_timeout(Some(wait))?;
+        match socket.recv_from(&mut buf) {
+            Ok((size, from)) => match DnsMessage::parse(&buf[..size]) {
+                Ok(message) if message.response => {
+                    records.extend(message.records.into_iter().map(|record| (record, from)))
+                }
+                Ok(_) => {}
+                Err(err) => debug!(%from, error = ?err, "Could not parse mDNS message."),
+            },
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(services(&records))
+}
+
+/// Puts the services back together from the records they were announced with. Hosts whose
+/// address record went missing are reached at the address they answered from.
+fn services(records: &[(Record, SocketAddr)]) -> Vec<Service> {
+    let key = |name: &str| name.to_ascii_lowercase();
+    let mut targets = HashMap::new();
+    let mut addresses = HashMap::new();
+    for (record, _) in records {
+        match &record.data {
+            RData::Srv { port, target } => {
+                targets.insert(key(&record.name), (*port, target.clone()));
+            }
+            RData::A(ip) => {
+                addresses.insert(key(&record.name), *ip);
+            }
+            _ => {}
+        }
+    }
+    let mut services: Vec<Service> = Vec::new();
+    for (record, from) in records {
+        let RData::Ptr(instance) = &record.data else {
+            continue;
+        };
+        if !record.name.eq_ignore_ascii_case(SERVICE) || record.ttl == 0 {
+            continue;
+        }
+        let Some((port, target)) = targets.get(&key(instance)) else {
+            continue;
+        };
+        let ip = addresses
+            .get(&key(target))
+            .map_or(from.ip(), |ip| IpAddr::V4(*ip));
+        let name = instance
+            .strip_suffix(SERVICE)
+            .unwrap_or(instance)
+            .trim_end_matches('.')
+            .to_string();
+        if services.iter().any(|service| service.name == name) {
+            continue;
+        }
+        services.push(Service {
+            name,
+            host: target.clone(),
+            addr: SocketAddr::new(ip, *port),
+        });
+    }
+    services
+}
+
+/// Address of the receiver announced as `name`, either its instance or its host name, with
+/// or without `.local`.
+pub fn resolve(name: &str, timeout: Duration) -> io::Result<Option<SocketAddr>> {
+    let name = name.trim_end_matches('.');
+    let bare = name.strip_suffix(".local").unwrap_or(name);
+    Ok(browse(timeout)?
+        .into_iter()
+        .find(|service| {
+            service.name.eq_ignore_ascii_case(bare)
+                || service.host.eq_ignore_ascii_case(&format!("{bare}.local"))
+        })
+        .map(|service| service.addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from() -> SocketAddr {
+        SocketAddr::from(([192, 168, 1, 7], PORT))
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let records = Records::new("rack12", 6666);
+        let message = DnsMessage {
+            id: 7,
+            response: true,
+            questions: vec![Question {
+                name: SERVICE.to_string(),
+                qtype: TYPE_PTR,
+                unicast: true,
+            }],
+            records: records.all(Ipv4Addr::new(10, 0, 0, 12), TTL),
+        };
+        assert_eq!(DnsMessage::parse(&message.serialize()), Ok(message));
+    }
+
+    #[test]
+    fn compressed_names_are_followed() {
+        // Header with one answer: a PTR from _sanic._udp.local to a.<pointer to it>.
+        let mut data = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 0];
+        write_name(&mut data, SERVICE);
+        data.extend([0, 12, 0, 1, 0, 0, 0, 120, 0, 4, 1, b'a', 0xc0, 12]);
+        let message = DnsMessage::parse(&data).unwrap();
+        assert_eq!(message.records[0].data, RData::Ptr(format!("a.{SERVICE}")));
+
+        // A name pointing at itself never ends.
+        let mut looping = data.clone();
+        let end = looping.len();
+        looping[end - 2..].copy_from_slice(&[0xc0, end as u8 - 2]);
+        assert_eq!(DnsMessage::parse(&looping), Err(DnsError::BadName));
+        assert_eq!(DnsMessage::parse(&data[..20]), Err(DnsError::Truncated));
+    }
+
+    #[test]
+    fn only_our_names_are_answered() {
+        let records = Records::new("rack12.example.com", 6666);
+        let question = |name: &str, qtype| Question {
+            name: name.to_string(),
+            qtype,
+            unicast: false,
+        };
+        assert!(records.concerns(&question("_SANIC._udp.local", TYPE_PTR)));
+        assert!(records.concerns(&question("rack12-example-com._sanic._udp.local", TYPE_SRV)));
+        assert!(records.concerns(&question("rack12-example-com.local", TYPE_ANY)));
+        assert!(!records.concerns(&question("_http._tcp.local", TYPE_PTR)));
+        assert!(!records.concerns(&question("other.local", TYPE_A)));
+    }
+
+    #[test]
+    fn services_are_put_back_together() {
+        let mut records: Vec<(Record, SocketAddr)> = Records::new("rack12", 6666)
+            .all(Ipv4Addr::new(10, 0, 0, 12), TTL)
+            .into_iter()
+            .map(|record| (record, from()))
+            .collect();
+        // Another receiver whose address record got lost, and a withdrawn one.
+        let other = Records::new("rack13", 7000).all(Ipv4Addr::new(10, 0, 0, 13), TTL);
+        records.extend(other.into_iter().take(2).map(|record| (record, from())));
+        let gone = Records::new("rack14", 6666).all(Ipv4Addr::new(10, 0, 0, 14), 0);
+        records.extend(gone.into_iter().map(|record| (record, from())));
+
+        assert_eq!(
+            services(&records),
+            vec![
+                Service {
+                    name: "rack12".to_string(),
+                    host: "rack12.local".to_string(),
+                    addr: SocketAddr::from(([10, 0, 0, 12], 6666)),
+                },
+                Service {
+                    name: "rack13".to_string(),
+                    host: "rack13.local".to_string(),
+                    addr: SocketAddr::from(([192, 168, 1, 7], 7000)),
+                },
+            ]
+        );
+    }
+}
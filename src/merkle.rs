@@ -0,0 +1,370 @@
+//! Verification of transfers block by block. Every part is hashed, and the digest of a
+//! block is the hash of the hashes of its parts, so the receiver checks a block as soon as
+//! all of its parts are in, whatever order they came in. A block that does not match is
+//! sent again on its own. The block digests are the leaves of a Merkle tree whose root
+//! stands for the whole file.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufReader},
+    ops::Range,
+    path::Path,
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{delta::read_full, PART_SIZE};
+
+/// Parts in a block, all but the last one of a file.
+pub(crate) const BLOCK_PARTS: u32 = 64;
+
+/// Block part `id` belongs to.
+pub(crate) fn block_of(id: u32) -> u32 {
+    id / BLOCK_PARTS
+}
+
+/// Ids of the parts of `block`, in a file of `nb_parts`.
+pub(crate) fn parts_of(block: u32, nb_parts: u32) -> Range<u32> {
+    let start = block.saturating_mul(BLOCK_PARTS).min(nb_parts);
+    start..start.saturating_add(BLOCK_PARTS).min(nb_parts)
+}
+
+fn part_hash(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// Digest of a block, from the hashes of its parts in order.
+fn block_digest<'a>(parts: impl IntoIterator<Item = &'a [u8; 32]>) -> [u8; 32] {
+    // Leaves and inner nodes are told apart, so neither passes for the other.
+    let mut hash = Sha256::new();
+    hash.update([0]);
+    for part in parts {
+        hash.update(part);
+    }
+    hash.finalize().into()
+}
+
+fn node(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hash = Sha256::new();
+    hash.update([1]);
+    hash.update(left);
+    hash.update(right);
+    hash.finalize().into()
+}
+
+/// Hexadecimal form of a digest, as the logs and the journal show them.
+pub fn hex(digest: &[u8; 32]) -> String {
+    digest.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Digest back from its hexadecimal form, in either case.
+pub fn parse_hex(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut digest = [0; 32];
+    for (byte, pair) in digest.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let pair = std::str::from_utf8(pair).ok()?;
+        *byte = u8::from_str_radix(pair, 16).ok()?;
+    }
+    Some(digest)
+}
+
+/// What a file hashes to, whole and as a verified transfer would send it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileDigests {
+    pub sha256: [u8; 32],
+    /// Root of the Merkle tree over its blocks of parts.
+    pub root: [u8; 32],
+}
+
+/// Hashes the file at `path` both ways, in a single read.
+pub fn digest_file(path: &Path) -> io::Result<FileDigests> {
+    let mut reader = BufReader::with_capacity(1024 * 1024, File::open(path)?);
+    let mut sha256 = Sha256::new();
+    let mut digester = Digester::default();
+    let mut part = vec![0; PART_SIZE];
+    loop {
+        let read = read_full(&mut reader, &mut part)?;
+        if read == 0 {
+            break;
+        }
+        sha256.update(&part[..read]);
+        digester.part(&part[..read]);
+    }
+    digester.finish();
+    Ok(FileDigests {
+        sha256: sha256.finalize().into(),
+        root: digester.root(),
+    })
+}
+
+/// Merkle tree built leaf by leaf, keeping only the roots of its full subtrees.
+#[derive(Debug, Default)]
+pub(crate) struct Tree {
+    /// Roots of the full subtrees with their height, the tallest first.
+    peaks: Vec<(u32, [u8; 32])>,
+}
+
+impl Tree {
+    pub fn push(&mut self, leaf: [u8; 32]) {
+        let (mut height, mut hash) = (0, leaf);
+        while let Some(&(peak, left)) = self.peaks.last() {
+            if peak != height {
+                break;
+            }
+            self.peaks.pop();
+            (height, hash) = (height + 1, node(&left, &hash));
+        }
+        self.peaks.push((height, hash));
+    }
+
+    /// Root over the leaves so far, the digest of an empty block without any.
+    pub fn root(&self) -> [u8; 32] {
+        self.peaks
+            .iter()
+            .rev()
+            .map(|(_, hash)| *hash)
+            .reduce(|right, left| node(&left, &right))
+            .unwrap_or_else(|| block_digest([]))
+    }
+}
+
+/// Digests of the blocks of a file, as its parts go out in order.
+#[derive(Debug, Default)]
+pub(crate) struct Digester {
+    /// Hashes of the parts of the current block so far.
+    parts: Vec<[u8; 32]>,
+    block: u32,
+    tree: Tree,
+}
+
+impl Digester {
+    /// Takes in the next part, and returns its block with its digest if it completes it.
+    pub fn part(&mut self, data: &[u8]) -> Option<(u32, [u8; 32])> {
+        self.parts.push(part_hash(data));
+        (self.parts.len() as u32 == BLOCK_PARTS).then(|| self.close())
+    }
+
+    /// The last block, if the last part left it short.
+    pub fn finish(&mut self) -> Option<(u32, [u8; 32])> {
+        (!self.parts.is_empty()).then(|| self.close())
+    }
+
+    pub fn root(&self) -> [u8; 32] {
+        self.tree.root()
+    }
+
+    fn close(&mut self) -> (u32, [u8; 32]) {
+        let digest = block_digest(&self.parts);
+        self.parts.clear();
+        self.tree.push(digest);
+        self.block += 1;
+        (self.block - 1, digest)
+    }
+}
+
+/// Outcome of checking a block.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Check {
+    /// Some of its parts, or its digest, are still missing.
+    Pending,
+    Verified,
+    /// Its parts do not match its digest, both were dropped.
+    Corrupt,
+}
+
+/// Checks the parts of a file against the digests of their blocks, as they come.
+#[derive(Debug)]
+pub(crate) struct Verifier {
+    nb_parts: u32,
+    /// Hashes of the parts received, by block, for the blocks not verified yet.
+    parts: BTreeMap<u32, BTreeMap<u32, [u8; 32]>>,
+    /// Digests of the blocks not verified yet.
+    expected: BTreeMap<u32, [u8; 32]>,
+    /// Blocks verified ahead of the next one the tree takes.
+    ahead: BTreeMap<u32, [u8; 32]>,
+    /// Every block before this one is verified.
+    next: u32,
+    tree: Tree,
+}
+
+impl Verifier {
+    pub fn new(nb_parts: u32) -> Self {
+        Verifier {
+            nb_parts,
+            parts: BTreeMap::new(),
+            expected: BTreeMap::new(),
+            ahead: BTreeMap::new(),
+            next: 0,
+            tree: Tree::default(),
+        }
+    }
+
+    fn blocks(&self) -> u32 {
+        self.nb_parts.div_ceil(BLOCK_PARTS)
+    }
+
+    fn is_verified(&self, block: u32) -> bool {
+        block < self.next || self.ahead.contains_key(&block)
+    }
+
+    /// Whether the block of part `id` was verified, so the part may be acknowledged.
+    pub fn covers(&self, id: u32) -> bool {
+        self.is_verified(block_of(id))
+    }
+
+    /// Part `id` arrived with `data`.
+    pub fn part(&mut self, id: u32, data: &[u8]) -> Check {
+        let block = block_of(id);
+        if id >= self.nb_parts || self.is_verified(block) {
+            return Check::Pending;
+        }
+        self.parts
+            .entry(block)
+            .or_default()
+            .insert(id, part_hash(data));
+        self.check(block)
+    }
+
+    /// The sender told us the `digest` of `block`.
+    pub fn expect(&mut self, block: u32, digest: [u8; 32]) -> Check {
+        if block >= self.blocks() || self.is_verified(block) {
+            return Check::Pending;
+        }
+        self.expected.insert(block, digest);
+        self.check(block)
+    }
+
+    fn check(&mut self, block: u32) -> Check {
+        let parts = self.parts.get(&block).map_or(0, BTreeMap::len);
+        if parts < parts_of(block, self.nb_parts).len() {
+            return Check::Pending;
+        }
+        // The digest may be the corrupt one, the sender repeats it.
+        let Some(expected) = self.expected.remove(&block) else {
+            return Check::Pending;
+        };
+        let parts = self.parts.remove(&block).unwrap_or_default();
+        if block_digest(parts.values()) != expected {
+            return Check::Corrupt;
+        }
+        self.ahead.insert(block, expected);
+        while let Some(digest) = self.ahead.remove(&self.next) {
+            self.tree.push(digest);
+            self.next += 1;
+        }
+        Check::Verified
+    }
+
+    /// Whether every block was verified.
+    pub fn is_complete(&self) -> bool {
+        self.next >= self.blocks()
+    }
+
+    /// Root of the file, once every block was verified.
+    pub fn root(&self) -> Option<[u8; 32]> {
+        self.is_complete().then(|| self.tree.root())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn part(id: u32) -> Vec<u8> {
+        vec![id as u8; 100]
+    }
+
+    fn digests(nb_parts: u32) -> (Vec<(u32, [u8; 32])>, [u8; 32]) {
+        let mut digester = Digester::default();
+        let mut digests: Vec<_> = (0..nb_parts)
+            .filter_map(|id| digester.part(&part(id)))
+            .collect();
+        digests.extend(digester.finish());
+        (digests, digester.root())
+    }
+
+    #[test]
+    fn trees_grow_leaf_by_leaf() {
+        let leaves: Vec<[u8; 32]> = (0..5).map(|i| [i; 32]).collect();
+        let mut tree = Tree::default();
+        assert_eq!(tree.root(), block_digest([]));
+        for leaf in &leaves {
+            tree.push(*leaf);
+        }
+        let left = node(&node(&leaves[0], &leaves[1]), &node(&leaves[2], &leaves[3]));
+        assert_eq!(tree.root(), node(&left, &leaves[4]));
+    }
+
+    #[test]
+    fn blocks_verify_in_any_order() {
+        let nb_parts = 3 * BLOCK_PARTS + 5;
+        let (digests, root) = digests(nb_parts);
+        assert_eq!(digests.len(), 4);
+        let mut verifier = Verifier::new(nb_parts);
+        for (block, digest) in digests.iter().rev() {
+            assert_eq!(verifier.expect(*block, *digest), Check::Pending);
+        }
+        let mut checks = Vec::new();
+        for id in (0..nb_parts).rev() {
+            checks.push(verifier.part(id, &part(id)));
+        }
+        assert_eq!(
+            checks
+                .iter()
+                .filter(|check| **check == Check::Verified)
+                .count(),
+            4
+        );
+        assert!(verifier.covers(0) && verifier.is_complete());
+        assert_eq!(verifier.root(), Some(root));
+    }
+
+    #[test]
+    fn corrupt_blocks_are_dropped() {
+        let nb_parts = 2 * BLOCK_PARTS;
+        let (digests, root) = digests(nb_parts);
+        let mut verifier = Verifier::new(nb_parts);
+        for (block, digest) in &digests {
+            verifier.expect(*block, *digest);
+        }
+        for id in 0..nb_parts - 1 {
+            verifier.part(id, &part(id));
+        }
+        assert!(verifier.covers(0) && !verifier.covers(nb_parts - 1));
+        assert_eq!(verifier.part(nb_parts - 1, &[0; 100]), Check::Corrupt);
+        assert!(!verifier.covers(BLOCK_PARTS));
+        // The whole block comes again, with its digest.
+        for id in parts_of(1, nb_parts) {
+            assert_eq!(verifier.part(id, &part(id)), Check::Pending);
+        }
+        assert_eq!(verifier.expect(1, digests[1].1), Check::Verified);
+        assert_eq!(verifier.root(), Some(root));
+    }
+
+    #[test]
+    fn files_hash_like_their_transfers() {
+        let dir = TempDir::new("merkle");
+        let path = dir.join("file.bin");
+        let data: Vec<u8> = (0..3 * PART_SIZE + 10).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        let digests = digest_file(&path).unwrap();
+
+        let mut digester = Digester::default();
+        for part in data.chunks(PART_SIZE) {
+            digester.part(part);
+        }
+        digester.finish();
+        assert_eq!(digests.root, digester.root());
+        assert_eq!(digests.sha256, <[u8; 32]>::from(Sha256::digest(&data)));
+        assert_eq!(parse_hex(&hex(&digests.root)), Some(digests.root));
+        assert_eq!(
+            parse_hex(&hex(&digests.root).to_uppercase()),
+            Some(digests.root)
+        );
+        assert_eq!(parse_hex("not a digest"), None);
+    }
+}
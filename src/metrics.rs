@@ -0,0 +1,207 @@
+//! Counters of a long running [`Receiver`](crate::Receiver), served over HTTP in the
+//! Prometheus text format so operators can graph them.
+//!
+//! ```no_run
+//! let metrics = sanic::metrics::Metrics::default();
+//! metrics.serve("0.0.0.0:9166")?;
+//! sanic::Receiver::new().metrics(metrics).receive()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tracing::{info, warn};
+
+/// How long a scraper has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Handle on the counters, shared by the receiver and the endpoint.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics(Arc<Counters>);
+
+#[derive(Debug, Default)]
+struct Counters {
+    started: AtomicU64,
+    completed: AtomicU64,
+    failed: AtomicU64,
+    bytes: AtomicU64,
+    lost: AtomicU64,
+    active: AtomicU64,
+}
+
+impl Metrics {
+    /// A transfer was accepted.
+    pub(crate) fn started(&self) {
+        self.0.started.fetch_add(1, Ordering::Relaxed);
+        self.0.active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The transfer ended, `completed` when we have all of it.
+    pub(crate) fn ended(&self, completed: bool) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+        match completed {
+            true => self.0.completed.fetch_add(1, Ordering::Relaxed),
+            false => self.0.failed.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    pub(crate) fn received(&self, bytes: u64) {
+        self.0.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// The sender was told about `parts` lost parts.
+    pub(crate) fn lost(&self, parts: u64) {
+        self.0.lost.fetch_add(parts, Ordering::Relaxed);
+    }
+
+    /// The counters in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let metrics = [
+            (
+                "sanic_transfers_started_total",
+                "counter",
+                "Transfers accepted.",
+                &self.0.started,
+            ),
+            (
+                "sanic_transfers_completed_total",
+                "counter",
+                "Transfers received in full.",
+                &self.0.completed,
+            ),
+            (
+                "sanic_transfers_failed_total",
+                "counter",
+                "Transfers aborted, reaped or failed after they were accepted.",
+                &self.0.failed,
+            ),
+            (
+                "sanic_received_bytes_total",
+                "counter",
+                "Bytes of file data received.",
+                &self.0.bytes,
+            ),
+            (
+                "sanic_lost_parts_total",
+                "counter",
+                "Parts reported lost to the senders.",
+                &self.0.lost,
+            ),
+            (
+                "sanic_active_sessions",
+                "gauge",
+                "Transfers in progress.",
+                &self.0.active,
+            ),
+        ];
+        let mut text = String::new();
+        for (name, kind, help, value) in metrics {
+            let value = value.load(Ordering::Relaxed);
+            let _ = write!(
+                text,
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+            );
+        }
+        text
+    }
+
+    /// Serves the counters at `/metrics` on `addr` from a background thread, and returns
+    /// the address it listens on.
+    pub fn serve(&self, addr: impl ToSocketAddrs) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)?;
+        let local = listener.local_addr()?;
+        info!(addr = %local, "Serving metrics.");
+        let metrics = self.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let answered = stream.and_then(|stream| metrics.answer(stream));
+                if let Err(err) = answered {
+                    warn!(error = ?err, "Could not answer a metrics request.");
+                }
+            }
+        });
+        Ok(local)
+    }
+
+    fn answer(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut request = String::new();
+        reader.read_line(&mut request)?;
+        // The headers do not matter, but the client may wait for us to read them.
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 2 {
+            header.clear();
+        }
+
+        let mut words = request.split_whitespace();
+        let (status, body) = match (words.next(), words.next()) {
+            (Some("GET"), Some("/metrics")) => ("200 OK", self.render()),
+            (Some("GET"), _) => ("404 Not Found", "Not found, try /metrics\n".to_string()),
+            _ => ("405 Method Not Allowed", String::new()),
+        };
+        let mut stream = stream;
+        write!(
+            stream,
+            "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )?;
+        stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn counters_render_in_text_format() {
+        let metrics = Metrics::default();
+        metrics.started();
+        metrics.started();
+        metrics.received(1400);
+        metrics.lost(3);
+        metrics.ended(true);
+        let text = metrics.render();
+        assert!(text.contains(
+            "# HELP sanic_transfers_started_total Transfers accepted.\n\
+             # TYPE sanic_transfers_started_total counter\n\
+             sanic_transfers_started_total 2\n"
+        ));
+        assert!(text.contains("\nsanic_transfers_completed_total 1\n"));
+        assert!(text.contains("\nsanic_transfers_failed_total 0\n"));
+        assert!(text.contains("\nsanic_received_bytes_total 1400\n"));
+        assert!(text.contains("\nsanic_lost_parts_total 3\n"));
+        assert!(text.contains("# TYPE sanic_active_sessions gauge\nsanic_active_sessions 1\n"));
+    }
+
+    #[test]
+    fn endpoint_serves_metrics() {
+        let metrics = Metrics::default();
+        metrics.received(42);
+        let addr = metrics.serve("127.0.0.1:0").unwrap();
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(&metrics.render()));
+        assert!(response.contains("sanic_received_bytes_total 42\n"));
+        assert!(get("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -0,0 +1,68 @@
+//! Read-only memory mappings of the files the sender reads, on Linux.
+
+use std::{fs::File, io, ops::Deref, os::fd::AsRawFd, ptr::NonNull};
+
+/// Whole file mapped in memory, read sequentially.
+///
+/// The file must not shrink while it is mapped: reading the missing pages raises SIGBUS.
+pub(crate) struct Mapping {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// SAFETY: the mapping is read-only and owned by this value alone.
+unsafe impl Send for Mapping {}
+
+impl Mapping {
+    pub fn new(file: &File) -> io::Result<Self> {
+        let len = usize::try_from(file.metadata()?.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "File too large to map"))?;
+        // mmap refuses empty mappings.
+        if len == 0 {
+            return Ok(Mapping {
+                ptr: NonNull::dangling(),
+                len,
+            });
+        }
+        // SAFETY: a fresh private read-only mapping of `len` bytes of an open file.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // Only a hint to read ahead more aggressively, failing changes nothing.
+        // SAFETY: `ptr` and `len` describe the mapping we just created.
+        unsafe { libc::madvise(ptr, len, libc::MADV_SEQUENTIAL) };
+        Ok(Mapping {
+            ptr: NonNull::new(ptr as *mut u8).expect("mmap does not map at address zero"),
+            len,
+        })
+    }
+}
+
+impl Deref for Mapping {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: `ptr` points to `len` readable bytes until the mapping is dropped, or is
+        // dangling with a length of zero.
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            // SAFETY: unmaps exactly the mapping created in `new`, nothing borrows it anymore.
+            unsafe { libc::munmap(self.ptr.as_ptr() as *mut libc::c_void, self.len) };
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! `sendmmsg`/`recvmmsg` batching for UDP sockets on Linux.
+
+use std::{
+    io,
+    mem::{self, MaybeUninit},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket},
+    os::fd::AsRawFd,
+};
+
+pub(crate) fn send_batch(
+    socket: &UdpSocket,
+    datagrams: &[&[u8]],
+    peer: SocketAddr,
+) -> io::Result<usize> {
+    let (addr, addr_len) = to_sockaddr(peer);
+    let mut iovecs: Vec<libc::iovec> = datagrams
+        .iter()
+        .map(|datagram| libc::iovec {
+            iov_base: datagram.as_ptr() as *mut libc::c_void,
+            iov_len: datagram.len(),
+        })
+        .collect();
+    let mut headers: Vec<libc::mmsghdr> = iovecs
+        .iter_mut()
+        .map(|iovec| {
+            // SAFETY: mmsghdr is plain old data, all zeroes is a valid value.
+            let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+            header.msg_hdr.msg_name = &addr as *const _ as *mut libc::c_void;
+            header.msg_hdr.msg_namelen = addr_len;
+            header.msg_hdr.msg_iov = iovec;
+            header.msg_hdr.msg_iovlen = 1;
+            header
+        })
+        .collect();
+
+    // The kernel may send only part of the batch, send the rest until it is all out.
+    let mut sent = 0;
+    while sent < headers.len() {
+        // SAFETY: the headers point to `addr` and to the iovecs, which point to the
+        // datagrams, all of which outlive the call.
+        let result = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                headers[sent..].as_mut_ptr(),
+                (headers.len() - sent) as libc::c_uint,
+                0,
+            )
+        };
+        if result < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        sent += result as usize;
+    }
+    Ok(sent)
+}
+
+/// Blocks until a datagram arrives, or the read timeout expires, then also takes the ones
+/// already queued, up to `bufs.len()`.
+pub(crate) fn recv_batch(
+    socket: &UdpSocket,
+    bufs: &mut [Vec<u8>],
+) -> io::Result<Vec<(usize, SocketAddr)>> {
+    let mut addrs: Vec<MaybeUninit<libc::sockaddr_storage>> =
+        vec![MaybeUninit::zeroed(); bufs.len()];
+    let mut iovecs: Vec<libc::iovec> = bufs
+        .iter_mut()
+        .map(|buf| libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        })
+        .collect();
+    let mut headers: Vec<libc::mmsghdr> = iovecs
+        .iter_mut()
+        .zip(addrs.iter_mut())
+        .map(|(iovec, addr)| {
+            // SAFETY: mmsghdr is plain old data, all zeroes is a valid value.
+            let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+            header.msg_hdr.msg_name = addr.as_mut_ptr() as *mut libc::c_void;
+            header.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as u32;
+            header.msg_hdr.msg_iov = iovec;
+            header.msg_hdr.msg_iovlen = 1;
+            header
+        })
+        .collect();
+
+    let received = loop {
+        // SAFETY: the headers point to the address slots and to the iovecs, which point to
+        // the buffers, all of which outlive the call. The socket read timeout applies.
+        let result = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                headers.as_mut_ptr(),
+                headers.len() as libc::c_uint,
+                libc::MSG_WAITFORONE,
+                std::ptr::null_mut(),
+            )
+        };
+        if result >= 0 {
+            break result as usize;
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    };
+
+    headers[..received]
+        .iter()
+        .zip(addrs.iter())
+        .map(|(header, addr)| {
+            // SAFETY: the kernel filled the address of every received message.
+            let addr = from_sockaddr(unsafe { addr.assume_init_ref() })?;
+            Ok((header.msg_len as usize, addr))
+        })
+        .collect()
+}
+
+pub(crate) fn to_sockaddr(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    // SAFETY: sockaddr_storage is plain old data, all zeroes is a valid value.
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sin = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: addr.port().to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from_ne_bytes(addr.ip().octets()),
+                },
+                sin_zero: [0; 8],
+            };
+            // SAFETY: sockaddr_storage is large and aligned enough for any address.
+            unsafe { (&mut storage as *mut _ as *mut libc::sockaddr_in).write(sin) };
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: addr.port().to_be(),
+                sin6_flowinfo: addr.flowinfo(),
+                sin6_addr: libc::in6_addr {
+                    s6_addr: addr.ip().octets(),
+                },
+                sin6_scope_id: addr.scope_id(),
+            };
+            // SAFETY: sockaddr_storage is large and aligned enough for any address.
+            unsafe { (&mut storage as *mut _ as *mut libc::sockaddr_in6).write(sin6) };
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+pub(crate) fn from_sockaddr(storage: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            // SAFETY: the family says this is a sockaddr_in.
+            let sin = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            Ok(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes()),
+                u16::from_be(sin.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            // SAFETY: the family says this is a sockaddr_in6.
+            let sin6 = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            Ok(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+                u16::from_be(sin6.sin6_port),
+                sin6.sin6_flowinfo,
+                sin6.sin6_scope_id,
+            )))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Unsupported address family",
+        )),
+    }
+}
@@ -0,0 +1,822 @@
+//! One-to-many transfers: every part goes to a multicast group once, and each receiver that
+//! joined the group acknowledges it on its own, over unicast.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs::File,
+    io::{self, ErrorKind},
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, error, info, info_span, warn};
+
+use crate::{
+    pool::BufferPool,
+    protocol::Message,
+    server::{
+        make_parts_packet, open_source, Pacer, SendError, Source, INITIAL_BACKOFF, SYNC_INTERVAL,
+    },
+    session_id, spawn,
+    state::SYNC_IDS_PER_PACKET,
+    transport::{Transport, MAX_BATCH},
+    MTU, PART_SIZE,
+};
+
+/// How long the Send is repeated to the group when the number of receivers is not known.
+const DISCOVERY: Duration = Duration::from_secs(2);
+/// Sync rounds still repaired over the group once every part was sent. Whatever is missing
+/// after that is only missing on a few receivers, and sent to each of them alone.
+const GROUP_REPAIR_ROUNDS: u32 = 3;
+/// A receiver that did not answer for this long is left behind.
+const MEMBER_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the answer loop wakes up to send the Syncs and repairs.
+const TICK: Duration = Duration::from_millis(50);
+/// Packet buffers kept for reuse once acknowledged by every receiver.
+const POOL_SIZE: usize = 4096;
+
+/// Where a packet goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Target {
+    Group,
+    Member(SocketAddr),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum MulticastAction {
+    /// A receiver aborted its side of the transfer, the others carry on.
+    Dropped(SocketAddr, String),
+    /// A host that is not part of the transfer talked to us.
+    Stranger(SocketAddr),
+    Unexpected(Message),
+}
+
+/// Receiver of a multicast transfer.
+struct Member {
+    addr: SocketAddr,
+    /// Parts sent that it did not acknowledge yet.
+    waiting: BTreeSet<u32>,
+    /// Parts it reported lost since the last repair.
+    lost: BTreeSet<u32>,
+    rate_limit: Option<u64>,
+    last_heard: Instant,
+}
+
+/// Sending end of a multicast transfer: tracks the parts each receiver still needs, and
+/// merges their losses into as few repairs as possible.
+pub(crate) struct MulticastState {
+    members: Vec<Member>,
+    /// Receivers that dropped out, and why.
+    dropped: Vec<(SocketAddr, String)>,
+    /// Packet of every part some receiver still waits for, with how many of them do.
+    packets: HashMap<u32, (Vec<u8>, usize)>,
+    /// Packets nobody needs anymore, for the caller to reuse.
+    released: Vec<Vec<u8>>,
+    sent_all: bool,
+    /// Repair rounds since every part was sent.
+    rounds: u32,
+}
+
+impl MulticastState {
+    pub fn new(members: &[SocketAddr], now: Instant) -> Self {
+        MulticastState {
+            members: members
+                .iter()
+                .map(|&addr| Member {
+                    addr,
+                    waiting: BTreeSet::new(),
+                    lost: BTreeSet::new(),
+                    rate_limit: None,
+                    last_heard: now,
+                })
+                .collect(),
+            dropped: Vec::new(),
+            packets: HashMap::new(),
+            released: Vec::new(),
+            sent_all: false,
+            rounds: 0,
+        }
+    }
+
+    /// Receivers still in the transfer.
+    pub fn members(&self) -> Vec<SocketAddr> {
+        self.members.iter().map(|member| member.addr).collect()
+    }
+
+    pub fn dropped(&self) -> &[(SocketAddr, String)] {
+        &self.dropped
+    }
+
+    /// Every remaining receiver has every part, or none is left.
+    pub fn is_done(&self) -> bool {
+        self.members.is_empty() || (self.sent_all && self.packets.is_empty())
+    }
+
+    /// Lowest rate cap of the receivers.
+    pub fn rate_limit(&self) -> Option<u64> {
+        self.members
+            .iter()
+            .filter_map(|member| member.rate_limit)
+            .min()
+    }
+
+    /// `packet` for part `id` is about to go to the group.
+    pub fn track(&mut self, id: u32, packet: Vec<u8>) {
+        if self.members.is_empty() {
+            self.released.push(packet);
+            return;
+        }
+        for member in &mut self.members {
+            member.waiting.insert(id);
+        }
+        self.packets.insert(id, (packet, self.members.len()));
+    }
+
+    pub fn packet(&self, id: u32) -> Option<&[u8]> {
+        self.packets.get(&id).map(|(packet, _)| packet.as_slice())
+    }
+
+    /// Hands back the packets every receiver acknowledged since the last call.
+    pub fn recycle(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.released)
+    }
+
+    pub fn sent_all(&mut self) {
+        self.sent_all = true;
+    }
+
+    pub fn on_message(
+        &mut self,
+        from: SocketAddr,
+        message: Message,
+        now: Instant,
+    ) -> Option<MulticastAction> {
+        let Some(index) = self.members.iter().position(|member| member.addr == from) else {
+            return Some(MulticastAction::Stranger(from));
+        };
+        self.members[index].last_heard = now;
+        match message {
+            Message::Ack { ids } => {
+                for id in ids {
+                    let member = &mut self.members[index];
+                    member.lost.remove(&id);
+                    if member.waiting.remove(&id) {
+                        self.release(id);
+                    }
+                }
+                None
+            }
+            Message::Loss { ids } => {
+                let member = &mut self.members[index];
+                let lost = ids.into_iter().filter(|id| member.waiting.contains(id));
+                member.lost.extend(lost);
+                None
+            }
+            Message::RateLimit { bytes_per_sec } => {
+                self.members[index].rate_limit = Some(bytes_per_sec).filter(|rate| *rate > 0);
+                None
+            }
+            Message::Abort { reason } => {
+                self.drop_member(index, reason.clone());
+                Some(MulticastAction::Dropped(from, reason))
+            }
+            // Our Send was repeated during the discovery.
+            Message::Accept { .. } | Message::HopStats(_) => None,
+            message => Some(MulticastAction::Unexpected(message)),
+        }
+    }
+
+    /// Drops the receivers that went silent while still missing parts, and returns them.
+    /// The ones that have everything are done, and may well have left already.
+    pub fn expire(&mut self, now: Instant) -> Vec<SocketAddr> {
+        let mut expired = Vec::new();
+        while let Some(index) = self.members.iter().position(|member| {
+            !member.waiting.is_empty()
+                && now.saturating_duration_since(member.last_heard) > MEMBER_TIMEOUT
+        }) {
+            expired.push(self.members[index].addr);
+            self.drop_member(index, "stopped answering".to_string());
+        }
+        expired
+    }
+
+    /// Gives up on every receiver.
+    pub fn abort(&mut self, reason: &str) {
+        while !self.members.is_empty() {
+            self.drop_member(self.members.len() - 1, reason.to_string());
+        }
+    }
+
+    /// Parts to send again, merged over every receiver while the group still gets them,
+    /// then for each receiver alone. Call it once per Sync round.
+    pub fn repairs(&mut self) -> Vec<(Target, Vec<Vec<u8>>)> {
+        let over_group = self.over_group();
+        if self.sent_all {
+            self.rounds += 1;
+        }
+        let mut repairs = Vec::new();
+        if over_group {
+            let lost: BTreeSet<u32> = self
+                .members
+                .iter_mut()
+                .flat_map(|member| std::mem::take(&mut member.lost))
+                .collect();
+            let packets = self.packets_of(lost);
+            if !packets.is_empty() {
+                repairs.push((Target::Group, packets));
+            }
+            return repairs;
+        }
+        for index in 0..self.members.len() {
+            let lost = std::mem::take(&mut self.members[index].lost);
+            let packets = self.packets_of(lost);
+            if !packets.is_empty() {
+                repairs.push((Target::Member(self.members[index].addr), packets));
+            }
+        }
+        repairs
+    }
+
+    /// Syncs about the parts still waiting for an Ack: one over the group for all of them,
+    /// then each receiver asked about its own once the repairs go over unicast.
+    pub fn syncs(&self) -> Vec<(Target, Message)> {
+        let chunks = |target, ids: Vec<u32>| {
+            ids.chunks(SYNC_IDS_PER_PACKET)
+                .map(|chunk| {
+                    (
+                        target,
+                        Message::Sync {
+                            ids: chunk.to_vec(),
+                        },
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        if self.over_group() {
+            let ids: BTreeSet<u32> = self.packets.keys().copied().collect();
+            return chunks(Target::Group, ids.into_iter().collect());
+        }
+        self.members
+            .iter()
+            .flat_map(|member| {
+                let ids = member.waiting.iter().copied().collect();
+                chunks(Target::Member(member.addr), ids)
+            })
+            .collect()
+    }
+
+    fn over_group(&self) -> bool {
+        !self.sent_all || self.rounds < GROUP_REPAIR_ROUNDS
+    }
+
+    fn packets_of(&self, ids: BTreeSet<u32>) -> Vec<Vec<u8>> {
+        ids.into_iter()
+            .filter_map(|id| self.packet(id).map(<[u8]>::to_vec))
+            .collect()
+    }
+
+    fn drop_member(&mut self, index: usize, reason: String) {
+        let member = self.members.remove(index);
+        for id in member.waiting {
+            self.release(id);
+        }
+        self.dropped.push((member.addr, reason));
+    }
+
+    /// One less receiver waits for part `id`.
+    fn release(&mut self, id: u32) {
+        let Some((_, waiting)) = self.packets.get_mut(&id) else {
+            return;
+        };
+        *waiting -= 1;
+        if *waiting == 0 {
+            if let Some((packet, _)) = self.packets.remove(&id) {
+                self.released.push(packet);
+            }
+        }
+    }
+}
+
+/// Outcome of a multicast transfer.
+#[derive(Debug, Clone, Default)]
+pub struct MulticastReport {
+    /// Receivers that got the whole file.
+    pub delivered: Vec<SocketAddr>,
+    /// Receivers that refused the transfer or dropped out, and why.
+    pub dropped: Vec<(SocketAddr, String)>,
+}
+
+/// Sends a file to every receiver that joined a multicast group, so a whole rack gets it
+/// for the bandwidth of one transfer. Receivers join with [`crate::Receiver::multicast`].
+///
+/// Multicast transfers cannot be encrypted: the receivers would share a key, and with it
+/// their nonces.
+///
+/// ```no_run
+/// let sender = sanic::MulticastSender::new("239.1.2.3:6666").receivers(8);
+/// let report = sender.send("image.iso".as_ref())?;
+/// # Ok::<(), sanic::SendError>(())
+/// ```
+#[derive(Clone)]
+pub struct MulticastSender {
+    group: String,
+    bind: String,
+    receivers: Option<usize>,
+    connect_timeout: Duration,
+}
+
+impl MulticastSender {
+    pub fn new(group: impl Into<String>) -> Self {
+        MulticastSender {
+            group: group.into(),
+            bind: "0.0.0.0:6667".to_string(),
+            receivers: None,
+            connect_timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// Address of the group.
+    pub fn group(&self) -> &str {
+        &self.group
+    }
+
+    /// Local address the socket is bound to.
+    pub fn bind(mut self, addr: impl Into<String>) -> Self {
+        self.bind = addr.into();
+        self
+    }
+
+    /// Wait for this many receivers to accept, instead of taking whoever answered within
+    /// two seconds.
+    pub fn receivers(mut self, count: usize) -> Self {
+        self.receivers = Some(count.max(1));
+        self
+    }
+
+    /// Give up if fewer receivers than asked for accepted after this long.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Sends `file` to the group, and returns once every receiver acknowledged every part
+    /// or dropped out. Fails when none of them got the file.
+    pub fn send(&self, file: &Path) -> Result<MulticastReport, SendError> {
+        let handle = File::open(file)?;
+        let size = handle.metadata()?.len();
+        let nb_parts = size.div_ceil(PART_SIZE as u64) as u32;
+        let group = self
+            .group
+            .to_socket_addrs()?
+            .next()
+            .filter(|group| group.ip().is_multicast())
+            .ok_or_else(|| {
+                io::Error::new(ErrorKind::InvalidInput, "Not a multicast group address")
+            })?;
+
+        let filename = file
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let session = info_span!("session", id = session_id()?, peer = %group, filename);
+        let _session = session.enter();
+
+        let socket = Arc::new(UdpSocket::bind(&self.bind)?);
+        let request = Message::Send {
+            filename,
+            parts: nb_parts,
+            group: None,
+            delta: false,
+            dedup: false,
+            verify: false,
+            token: None,
+            offset: None,
+            text: false,
+            synthetic: false,
+            size: Some(size),
+        };
+        let state = Arc::new(Mutex::new(self.discover(&socket, group, &request)?));
+        let (source, reader) = open_source(handle, None);
+        let pool = BufferPool::new(POOL_SIZE);
+        let sender = {
+            let socket = socket.clone();
+            let state = state.clone();
+            let pool = pool.clone();
+            spawn(move || handle_send(&socket, group, source, &state, pool))
+        };
+        let answered = handle_answers(&socket, group, &state, &pool);
+
+        if let Some(reader) = reader {
+            if reader.join().is_err() {
+                error!("The file reader thread panicked.");
+            }
+        }
+        let sent = sender
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("the sender thread panicked")));
+        answered.and(sent)?;
+
+        let state = state.lock().expect("Could not lock state");
+        let report = MulticastReport {
+            delivered: state.members(),
+            dropped: state.dropped().to_vec(),
+        };
+        if report.delivered.is_empty() {
+            let reason = match report.dropped.last() {
+                Some((_, reason)) => reason.clone(),
+                None => "no receiver left".to_string(),
+            };
+            return Err(SendError::Aborted(reason));
+        }
+        Ok(report)
+    }
+
+    /// Sends `request` to the group until enough receivers accepted, or until the discovery
+    /// window closed, and starts the transfer with the receivers that accepted.
+    fn discover(
+        &self,
+        socket: &UdpSocket,
+        group: SocketAddr,
+        request: &Message,
+    ) -> Result<MulticastState, SendError> {
+        let start = Instant::now();
+        let window = match self.receivers {
+            Some(_) => self.connect_timeout,
+            None => DISCOVERY,
+        };
+        let packet = request.serialize();
+        let mut backoff = INITIAL_BACKOFF;
+        let mut buf: Vec<u8> = vec![0; MTU];
+        let mut attempts = 0;
+        let mut members = Vec::new();
+        let mut refused = Vec::new();
+        'discovery: while let Some(remaining) = window
+            .checked_sub(start.elapsed())
+            .filter(|remaining| !remaining.is_zero())
+        {
+            attempts += 1;
+            socket.send_to(&packet, group)?;
+
+            let deadline = Instant::now() + backoff.min(remaining);
+            loop {
+                let wait = deadline.saturating_duration_since(Instant::now());
+                if wait.is_zero() {
+                    break;
+                }
+                socket.set_read_timeout(Some(wait))?;
+                match socket.recv_from(&mut buf) {
+                    Ok((size, from)) => match Message::parse(&buf[..size]) {
+                        Ok(Message::Accept { .. }) if !members.contains(&from) => {
+                            info!(%from, "Receiver accepted the transfer.");
+                            members.push(from);
+                            if self.receivers == Some(members.len()) {
+                                break 'discovery;
+                            }
+                        }
+                        Ok(Message::Abort { reason }) => {
+                            warn!(%from, reason, "Receiver refused the transfer.");
+                            members.retain(|member| *member != from);
+                            refused.push((from, reason));
+                        }
+                        Ok(msg) => debug!(%from, message = ?msg, "Ignoring message."),
+                        Err(err) => warn!(error = ?err, "Could not parse packet."),
+                    },
+                    Err(err)
+                        if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                    {
+                        break
+                    }
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            backoff *= 2;
+        }
+
+        if members.is_empty() {
+            return Err(SendError::NoAnswer(attempts));
+        }
+        if self.receivers.is_some_and(|count| members.len() < count) {
+            let reason = format!("only {} receivers showed up", members.len());
+            warn!(reason, "Aborting the transfer.");
+            socket.send_to(&Message::Abort { reason }.serialize(), group)?;
+            return Err(SendError::NoAnswer(attempts));
+        }
+        info!(
+            receivers = members.len(),
+            "Receivers accepted the transfer."
+        );
+        let mut state = MulticastState::new(&members, Instant::now());
+        state.dropped = refused;
+        Ok(state)
+    }
+}
+
+/// Cuts the file into parts and sends each of them to the group, once.
+fn handle_send(
+    socket: &UdpSocket,
+    group: SocketAddr,
+    source: Source,
+    state: &Mutex<MulticastState>,
+    pool: BufferPool,
+) -> io::Result<()> {
+    let mut part_id = 0;
+    let mut pacer = Pacer::new();
+    let mut send = |data: &[u8]| -> io::Result<bool> {
+        for parts in data.chunks(PART_SIZE * MAX_BATCH) {
+            let mut batch: Vec<Vec<u8>> = Vec::with_capacity(MAX_BATCH);
+            for chunk in parts.chunks(PART_SIZE) {
+                let mut packet = pool.get();
+                make_parts_packet(chunk, part_id + batch.len() as u32, &mut packet)
+                    .expect("Chunk too big!");
+                batch.push(packet);
+            }
+            let rate_limit = state.lock().expect("Could not lock state").rate_limit();
+            pacer.pace(batch.iter().map(Vec::len).sum(), rate_limit);
+
+            let mut tracked = state.lock().expect("Could not lock state");
+            if tracked.is_done() {
+                info!("Every receiver dropped out, stop sending.");
+                return Ok(false);
+            }
+            let first = part_id;
+            for packet in batch {
+                tracked.track(part_id, packet);
+                part_id += 1;
+            }
+            // Sent under the lock, so an Ack cannot recycle a packet before it is out.
+            let packets: Vec<&[u8]> = (first..part_id)
+                .filter_map(|id| tracked.packet(id))
+                .collect();
+            if let Err(err) = socket.send_datagrams(&packets, group) {
+                drop(tracked);
+                return Err(fail(socket, group, state, err));
+            }
+        }
+        Ok(true)
+    };
+    match source {
+        Source::Read(channel) => {
+            while let Ok(data) = channel.recv() {
+                let data = data.map_err(|err| fail(socket, group, state, err))?;
+                if !send(&data)? {
+                    return Ok(());
+                }
+            }
+        }
+        #[cfg(all(target_os = "linux", feature = "mmap"))]
+        Source::Mapped(mapping) => {
+            if !send(&mapping)? {
+                return Ok(());
+            }
+        }
+    }
+    state.lock().expect("Could not lock state").sent_all();
+    info!(parts = part_id, "All parts sent to the group.");
+    Ok(())
+}
+
+/// Takes the answers of every receiver, and sends the Syncs and repairs once per round.
+fn handle_answers(
+    socket: &UdpSocket,
+    group: SocketAddr,
+    state: &Mutex<MulticastState>,
+    pool: &BufferPool,
+) -> io::Result<()> {
+    let mut buf: Vec<u8> = vec![0; MTU];
+    socket
+        .set_read_timeout(Some(TICK))
+        .map_err(|err| fail(socket, group, state, err))?;
+    let mut next_round = Instant::now() + SYNC_INTERVAL;
+    while !state.lock().expect("Could not lock state").is_done() {
+        match socket.recv_from(&mut buf) {
+            Ok((size, from)) => {
+                let msg = match Message::parse(&buf[..size]) {
+                    Ok(msg) => msg,
+                    Err(err) => {
+                        warn!(error = ?err, "Could not parse packet.");
+                        continue;
+                    }
+                };
+                let (action, released) = {
+                    let mut state = state.lock().expect("Could not lock state");
+                    (state.on_message(from, msg, Instant::now()), state.recycle())
+                };
+                pool.put_all(released);
+                match action {
+                    Some(MulticastAction::Dropped(addr, reason)) => {
+                        warn!(%addr, reason, "Receiver dropped out.");
+                    }
+                    Some(MulticastAction::Stranger(addr)) => {
+                        debug!(%addr, "Turning away a receiver that joined too late.");
+                        let reason = "the multicast transfer already started".to_string();
+                        socket
+                            .send_to(&Message::Abort { reason }.serialize(), addr)
+                            .map_err(|err| fail(socket, group, state, err))?;
+                    }
+                    Some(MulticastAction::Unexpected(msg)) => {
+                        warn!(%from, message = ?msg, "Received unexpected message.");
+                    }
+                    None => {}
+                }
+            }
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(err) => return Err(fail(socket, group, state, err)),
+        }
+
+        let now = Instant::now();
+        if now < next_round {
+            continue;
+        }
+        next_round = now + SYNC_INTERVAL;
+        let (repairs, syncs) = {
+            let mut state = state.lock().expect("Could not lock state");
+            for addr in state.expire(now) {
+                warn!(%addr, "Receiver stopped answering, leaving it behind.");
+            }
+            (state.repairs(), state.syncs())
+        };
+        // Repairs first, so the Syncs do not report them lost again.
+        let sent = repairs
+            .iter()
+            .try_for_each(|(target, packets)| {
+                let packets: Vec<&[u8]> = packets.iter().map(Vec::as_slice).collect();
+                socket
+                    .send_datagrams(&packets, address(*target, group))
+                    .map(drop)
+            })
+            .and_then(|_| {
+                syncs.iter().try_for_each(|(target, sync)| {
+                    socket
+                        .send_to(&sync.serialize(), address(*target, group))
+                        .map(drop)
+                })
+            });
+        sent.map_err(|err| fail(socket, group, state, err))?;
+    }
+    Ok(())
+}
+
+fn address(target: Target, group: SocketAddr) -> SocketAddr {
+    match target {
+        Target::Group => group,
+        Target::Member(addr) => addr,
+    }
+}
+
+/// Gives up on every receiver after a local error, tells them why, and returns the error.
+fn fail(
+    socket: &UdpSocket,
+    group: SocketAddr,
+    state: &Mutex<MulticastState>,
+    err: io::Error,
+) -> io::Error {
+    error!(error = ?err, "Transfer failed.");
+    let reason = format!("sender failed: {err}");
+    state.lock().expect("Could not lock state").abort(&reason);
+    if let Err(err) = socket.send_to(&Message::Abort { reason }.serialize(), group) {
+        warn!(error = ?err, "Could not tell the receivers about the abort.");
+    }
+    err
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    fn packet(id: u32) -> Vec<u8> {
+        let mut packet = vec![2];
+        packet.extend(id.to_be_bytes());
+        packet
+    }
+
+    fn ack(ids: &[u32]) -> Message {
+        Message::Ack { ids: ids.to_vec() }
+    }
+
+    fn loss(ids: &[u32]) -> Message {
+        Message::Loss { ids: ids.to_vec() }
+    }
+
+    fn sending(parts: u32, members: &[SocketAddr]) -> (MulticastState, Instant) {
+        let now = Instant::now();
+        let mut state = MulticastState::new(members, now);
+        for id in 0..parts {
+            state.track(id, packet(id));
+        }
+        (state, now)
+    }
+
+    #[test]
+    fn packets_are_kept_until_every_receiver_acked_them() {
+        let (mut state, now) = sending(3, &[addr(1), addr(2)]);
+        state.sent_all();
+        assert_eq!(state.on_message(addr(1), ack(&[0, 1, 2]), now), None);
+        assert!(state.recycle().is_empty());
+        assert!(!state.is_done());
+        state.on_message(addr(2), ack(&[0, 2]), now);
+        assert_eq!(state.recycle(), vec![packet(0), packet(2)]);
+        // Duplicated Acks do not release anything twice.
+        state.on_message(addr(1), ack(&[1]), now);
+        assert!(state.recycle().is_empty());
+        state.on_message(addr(2), ack(&[1]), now);
+        assert!(state.is_done());
+        assert_eq!(state.members(), vec![addr(1), addr(2)]);
+    }
+
+    #[test]
+    fn losses_are_repaired_once_over_the_group() {
+        let (mut state, now) = sending(4, &[addr(1), addr(2), addr(3)]);
+        state.on_message(addr(1), loss(&[1, 2]), now);
+        state.on_message(addr(2), loss(&[2, 3]), now);
+        state.on_message(addr(3), loss(&[2]), now);
+        assert_eq!(
+            state.repairs(),
+            vec![(Target::Group, vec![packet(1), packet(2), packet(3)])]
+        );
+        assert_eq!(state.repairs(), vec![]);
+        assert_eq!(
+            state.syncs(),
+            vec![(
+                Target::Group,
+                Message::Sync {
+                    ids: vec![0, 1, 2, 3]
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn stragglers_are_repaired_over_unicast() {
+        let (mut state, now) = sending(3, &[addr(1), addr(2)]);
+        state.sent_all();
+        for _ in 0..GROUP_REPAIR_ROUNDS {
+            state.repairs();
+        }
+        state.on_message(addr(1), ack(&[0, 1, 2]), now);
+        state.on_message(addr(2), ack(&[0]), now);
+        state.on_message(addr(2), loss(&[1, 2]), now);
+        // A Loss for a part the receiver has since acknowledged is not repaired.
+        state.on_message(addr(1), loss(&[1]), now);
+        assert_eq!(
+            state.repairs(),
+            vec![(Target::Member(addr(2)), vec![packet(1), packet(2)])]
+        );
+        assert_eq!(
+            state.syncs(),
+            vec![(Target::Member(addr(2)), Message::Sync { ids: vec![1, 2] })]
+        );
+    }
+
+    #[test]
+    fn dropped_receivers_release_their_parts() {
+        let (mut state, now) = sending(2, &[addr(1), addr(2)]);
+        state.sent_all();
+        state.on_message(addr(1), ack(&[0, 1]), now);
+        let reason = "disk full".to_string();
+        assert_eq!(
+            state.on_message(
+                addr(2),
+                Message::Abort {
+                    reason: reason.clone()
+                },
+                now
+            ),
+            Some(MulticastAction::Dropped(addr(2), reason.clone()))
+        );
+        assert_eq!(state.recycle().len(), 2);
+        assert!(state.is_done());
+        assert_eq!(state.members(), vec![addr(1)]);
+        assert_eq!(state.dropped(), &[(addr(2), reason)]);
+    }
+
+    #[test]
+    fn silent_receivers_are_left_behind() {
+        let (mut state, now) = sending(1, &[addr(1), addr(2), addr(3)]);
+        state.on_message(addr(1), ack(&[0]), now + MEMBER_TIMEOUT);
+        state.on_message(addr(3), ack(&[0]), now);
+        // The third one is just done.
+        assert_eq!(state.expire(now + MEMBER_TIMEOUT * 2), vec![addr(2)]);
+        assert_eq!(state.recycle(), vec![packet(0)]);
+        assert_eq!(state.members(), vec![addr(1), addr(3)]);
+    }
+
+    #[test]
+    fn strangers_and_rate_limits() {
+        let (mut state, now) = sending(1, &[addr(1), addr(2)]);
+        assert_eq!(
+            state.on_message(addr(3), Message::Accept { capabilities: None }, now),
+            Some(MulticastAction::Stranger(addr(3)))
+        );
+        state.on_message(addr(1), Message::RateLimit { bytes_per_sec: 500 }, now);
+        state.on_message(addr(2), Message::RateLimit { bytes_per_sec: 200 }, now);
+        assert_eq!(state.rate_limit(), Some(200));
+        state.on_message(addr(2), Message::RateLimit { bytes_per_sec: 0 }, now);
+        assert_eq!(state.rate_limit(), Some(500));
+    }
+}
@@ -0,0 +1,211 @@
+use std::{
+    collections::HashMap,
+    ops::Range,
+    time::{Duration, Instant},
+};
+
+/// RTT assumed for a path until its first acknowledgement.
+const INITIAL_RTT: Duration = Duration::from_millis(100);
+/// Parts unacknowledged for this long, or four RTTs if longer, count as lost on their path.
+const MIN_EXPIRY: Duration = Duration::from_secs(1);
+
+/// How one of the sender's paths is doing.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct PathStats {
+    pub sent: u64,
+    pub acked: u64,
+    pub lost: u64,
+    /// Smoothed time between sending a part and getting its Ack, Sync interval included.
+    pub srtt: Option<Duration>,
+    /// Sending over it failed, it is not used anymore.
+    pub down: bool,
+}
+
+impl PathStats {
+    /// Share of the parts that made it, starting at one half so fresh paths get traffic.
+    fn delivery(&self) -> f64 {
+        (self.acked + 1) as f64 / (self.acked + self.lost + 2) as f64
+    }
+
+    fn weight(&self) -> f64 {
+        if self.down {
+            return 0.0;
+        }
+        self.delivery() / self.srtt.unwrap_or(INITIAL_RTT).as_secs_f64()
+    }
+}
+
+/// Spreads batches of parts over the sender's paths, in proportion to how well each one
+/// delivers: its delivery rate over its RTT.
+pub(crate) struct Paths {
+    stats: Vec<PathStats>,
+    /// Credit of every path in the smooth weighted round robin.
+    credit: Vec<f64>,
+    /// Path and send time of the parts waiting for their Ack.
+    in_flight: HashMap<u32, (usize, Instant)>,
+}
+
+impl Paths {
+    pub fn new(count: usize) -> Self {
+        Paths {
+            stats: vec![PathStats::default(); count],
+            credit: vec![0.0; count],
+            in_flight: HashMap::new(),
+        }
+    }
+
+    pub fn stats(&self) -> &[PathStats] {
+        &self.stats
+    }
+
+    /// Path for the next batch, or None once every path is down.
+    pub fn pick(&mut self) -> Option<usize> {
+        let weights: Vec<f64> = self.stats.iter().map(PathStats::weight).collect();
+        let total: f64 = weights.iter().sum();
+        if total <= 0.0 {
+            return None;
+        }
+        for (credit, weight) in self.credit.iter_mut().zip(&weights) {
+            *credit += weight;
+        }
+        let best = (0..weights.len())
+            .filter(|&path| weights[path] > 0.0)
+            .max_by(|&a, &b| self.credit[a].total_cmp(&self.credit[b]))?;
+        self.credit[best] -= total;
+        Some(best)
+    }
+
+    /// Path the control messages go over: the live one that delivers best.
+    pub fn best(&self) -> Option<usize> {
+        (0..self.stats.len())
+            .filter(|&path| !self.stats[path].down)
+            .max_by(|&a, &b| self.stats[a].weight().total_cmp(&self.stats[b].weight()))
+    }
+
+    pub fn sent(&mut self, path: usize, ids: Range<u32>, now: Instant) {
+        self.stats[path].sent += ids.len() as u64;
+        self.in_flight.extend(ids.map(|id| (id, (path, now))));
+    }
+
+    pub fn acked(&mut self, ids: &[u32], now: Instant) {
+        for id in ids {
+            let Some((path, sent)) = self.in_flight.remove(id) else {
+                continue;
+            };
+            let stats = &mut self.stats[path];
+            stats.acked += 1;
+            let sample = now.saturating_duration_since(sent);
+            stats.srtt = Some(match stats.srtt {
+                Some(srtt) => (srtt * 7 + sample) / 8,
+                None => sample,
+            });
+        }
+    }
+
+    pub fn lost(&mut self, ids: &[u32]) {
+        for id in ids {
+            if let Some((path, _)) = self.in_flight.remove(id) {
+                self.stats[path].lost += 1;
+            }
+        }
+    }
+
+    /// Counts the parts that went unanswered for too long as lost, so a path that silently
+    /// stopped delivering, Syncs included, loses its traffic.
+    pub fn expire(&mut self, now: Instant) {
+        let stats = &mut self.stats;
+        self.in_flight.retain(|_, (path, sent)| {
+            let rtt = stats[*path].srtt.unwrap_or(INITIAL_RTT);
+            let expired = now.saturating_duration_since(*sent) > MIN_EXPIRY.max(rtt * 4);
+            if expired {
+                stats[*path].lost += 1;
+            }
+            !expired
+        });
+    }
+
+    pub fn down(&mut self, path: usize) {
+        self.stats[path].down = true;
+        self.credit[path] = 0.0;
+    }
+
+    /// Whether some path can still be used.
+    pub fn any_up(&self) -> bool {
+        self.stats.iter().any(|stats| !stats.down)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn picks(paths: &mut Paths, count: usize) -> Vec<usize> {
+        let mut picked = vec![0; paths.stats().len()];
+        for _ in 0..count {
+            picked[paths.pick().unwrap()] += 1;
+        }
+        picked
+    }
+
+    #[test]
+    fn fresh_paths_share_evenly() {
+        let mut paths = Paths::new(3);
+        assert_eq!(picks(&mut paths, 300), vec![100, 100, 100]);
+    }
+
+    #[test]
+    fn lossy_and_slow_paths_get_less() {
+        let start = Instant::now();
+        let mut paths = Paths::new(3);
+        paths.sent(0, 0..100, start);
+        paths.sent(1, 100..200, start);
+        paths.sent(2, 200..300, start);
+        let later = start + Duration::from_millis(20);
+        paths.acked(&(0..100).collect::<Vec<_>>(), later);
+        // Same RTT, but half of the parts lost.
+        paths.acked(&(100..150).collect::<Vec<_>>(), later);
+        paths.lost(&(150..200).collect::<Vec<_>>());
+        // No loss, but five times slower.
+        paths.acked(
+            &(200..300).collect::<Vec<_>>(),
+            start + Duration::from_millis(100),
+        );
+
+        let picked = picks(&mut paths, 1000);
+        assert!(picked[0] > picked[1] && picked[1] > picked[2], "{picked:?}");
+        assert_eq!(paths.best(), Some(0));
+        assert_eq!(paths.stats()[1].lost, 50);
+    }
+
+    #[test]
+    fn down_paths_are_left_out() {
+        let mut paths = Paths::new(2);
+        paths.down(0);
+        assert_eq!(picks(&mut paths, 10), vec![0, 10]);
+        assert_eq!(paths.best(), Some(1));
+        paths.down(1);
+        assert!(!paths.any_up());
+        assert_eq!(paths.pick(), None);
+        assert_eq!(paths.best(), None);
+    }
+
+    #[test]
+    fn silent_paths_expire() {
+        let start = Instant::now();
+        let mut paths = Paths::new(2);
+        paths.sent(0, 0..10, start);
+        paths.sent(1, 10..20, start);
+        paths.acked(
+            &(10..20).collect::<Vec<_>>(),
+            start + Duration::from_millis(10),
+        );
+        paths.expire(start + Duration::from_millis(500));
+        assert_eq!(paths.stats()[0].lost, 0);
+        paths.expire(start + Duration::from_secs(2));
+        assert_eq!(paths.stats()[0].lost, 10);
+        assert_eq!(paths.best(), Some(1));
+        // Late Acks for expired parts change nothing.
+        paths.acked(&[0, 1], start + Duration::from_secs(3));
+        assert_eq!(paths.stats()[0].acked, 0);
+    }
+}
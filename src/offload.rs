@@ -0,0 +1,308 @@
+//! UDP generic segmentation (GSO) and receive (GRO) offload on Linux.
+//!
+//! With GSO, a run of equally sized datagrams goes to the kernel as one super-packet that
+//! is split as late as possible, by the NIC when it can. With GRO, the kernel merges the
+//! datagrams of a flow back into super-packets that we split ourselves.
+
+use std::{
+    collections::VecDeque,
+    io, mem,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    os::fd::AsRawFd,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tracing::{debug, warn};
+
+use crate::{
+    dscp::Dscp,
+    mmsg::{from_sockaddr, to_sockaddr},
+    transport::{rebind_udp, Buffer, Transport},
+};
+
+/// Most segments the kernel accepts in a single super-packet.
+const MAX_SEGMENTS: usize = 64;
+/// Stays below the 64KiB UDP limit, whatever the IP version.
+const MAX_SUPER_PACKET: usize = 65_000;
+
+/// UDP socket sending through GSO and receiving through GRO where the kernel supports them,
+/// and like a plain socket otherwise.
+pub(crate) struct OffloadSocket {
+    socket: UdpSocket,
+    /// Cleared on the first send the kernel or the NIC refuses to segment.
+    gso: AtomicBool,
+    gro: bool,
+    scratch: Mutex<Vec<u8>>,
+    /// Segments of the last super-packet the caller had no room for yet.
+    pending: Mutex<VecDeque<(Vec<u8>, SocketAddr)>>,
+}
+
+impl OffloadSocket {
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr)?;
+        let gso = supports_gso(&socket);
+        let gro = enable_gro(&socket);
+        debug!(gso, gro, "UDP offload support.");
+        Ok(OffloadSocket {
+            socket,
+            gso: AtomicBool::new(gso),
+            gro,
+            scratch: Mutex::new(vec![0; u16::MAX as usize]),
+            pending: Mutex::new(VecDeque::new()),
+        })
+    }
+
+    fn send_segments(&self, datagrams: &[&[u8]], size: usize, peer: SocketAddr) -> io::Result<()> {
+        let (addr, addr_len) = to_sockaddr(peer);
+        let mut iovecs: Vec<libc::iovec> = datagrams
+            .iter()
+            .map(|datagram| libc::iovec {
+                iov_base: datagram.as_ptr() as *mut libc::c_void,
+                iov_len: datagram.len(),
+            })
+            .collect();
+        // SAFETY: CMSG_SPACE only computes a size.
+        let mut control =
+            vec![0u8; unsafe { libc::CMSG_SPACE(mem::size_of::<u16>() as u32) } as usize];
+        // SAFETY: msghdr is plain old data, all zeroes is a valid value.
+        let mut header: libc::msghdr = unsafe { mem::zeroed() };
+        header.msg_name = &addr as *const _ as *mut libc::c_void;
+        header.msg_namelen = addr_len;
+        header.msg_iov = iovecs.as_mut_ptr();
+        header.msg_iovlen = iovecs.len();
+        header.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        header.msg_controllen = control.len();
+        // SAFETY: the control buffer has room for exactly one cmsg carrying a u16.
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&header);
+            (*cmsg).cmsg_level = libc::SOL_UDP;
+            (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as usize;
+            (libc::CMSG_DATA(cmsg) as *mut u16).write_unaligned(size as u16);
+        }
+        loop {
+            // SAFETY: the header points to `addr`, the iovecs and the control buffer, all of
+            // which outlive the call.
+            let result = unsafe { libc::sendmsg(self.socket.as_raw_fd(), &header, 0) };
+            if result >= 0 {
+                return Ok(());
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+
+    /// Receives one datagram, or one super-packet, and returns its size, its segment size
+    /// and its sender.
+    fn recv_super_packet(&self, scratch: &mut [u8]) -> io::Result<(usize, usize, SocketAddr)> {
+        // SAFETY: sockaddr_storage is plain old data, all zeroes is a valid value.
+        let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut iovec = libc::iovec {
+            iov_base: scratch.as_mut_ptr() as *mut libc::c_void,
+            iov_len: scratch.len(),
+        };
+        // SAFETY: CMSG_SPACE only computes a size.
+        let mut control =
+            vec![0u8; unsafe { libc::CMSG_SPACE(mem::size_of::<libc::c_int>() as u32) } as usize];
+        // SAFETY: msghdr is plain old data, all zeroes is a valid value.
+        let mut header: libc::msghdr = unsafe { mem::zeroed() };
+        header.msg_name = &mut addr as *mut _ as *mut libc::c_void;
+        header.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as u32;
+        header.msg_iov = &mut iovec;
+        header.msg_iovlen = 1;
+        header.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        header.msg_controllen = control.len();
+
+        let size = loop {
+            // SAFETY: the header points to `addr`, the iovec over `scratch` and the control
+            // buffer, all of which outlive the call. The socket read timeout applies.
+            let result = unsafe { libc::recvmsg(self.socket.as_raw_fd(), &mut header, 0) };
+            if result >= 0 {
+                break result as usize;
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        };
+
+        let mut segment = size;
+        // SAFETY: the kernel filled the control buffer and set its length in the header.
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&header);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_UDP && (*cmsg).cmsg_type == libc::UDP_GRO {
+                    let gso_size = (libc::CMSG_DATA(cmsg) as *const libc::c_int).read_unaligned();
+                    if gso_size > 0 {
+                        segment = gso_size as usize;
+                    }
+                }
+                cmsg = libc::CMSG_NXTHDR(&header, cmsg);
+            }
+        }
+        Ok((size, segment, from_sockaddr(&addr)?))
+    }
+
+    /// Receives a super-packet and splits it in `bufs`, keeping the segments that do not
+    /// fit for later.
+    fn recv_segments(&self, bufs: &mut [Vec<u8>]) -> io::Result<Vec<(usize, SocketAddr)>> {
+        let mut scratch = self.scratch.lock().expect("Could not lock scratch buffer");
+        let (size, segment, from) = self.recv_super_packet(&mut scratch)?;
+        let mut received = Vec::new();
+        let mut pending = self
+            .pending
+            .lock()
+            .expect("Could not lock pending segments");
+        for datagram in scratch[..size].chunks(segment.max(1)) {
+            match bufs.get_mut(received.len()) {
+                Some(buf) => received.push((copy_truncated(datagram, buf), from)),
+                None => pending.push_back((datagram.to_vec(), from)),
+            }
+        }
+        Ok(received)
+    }
+}
+
+impl Transport for OffloadSocket {
+    fn send_datagram(&self, datagram: &[u8], peer: SocketAddr) -> io::Result<usize> {
+        self.socket.send_to(datagram, peer)
+    }
+
+    fn recv_datagram(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut bufs = [vec![0; buf.len()]];
+        let (size, from) = self.recv_datagrams(&mut bufs)?[0];
+        buf[..size].copy_from_slice(&bufs[0][..size]);
+        Ok((size, from))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn send_datagrams(&self, datagrams: &[&[u8]], peer: SocketAddr) -> io::Result<usize> {
+        let mut start = 0;
+        while start < datagrams.len() {
+            if !self.gso.load(Ordering::Relaxed) {
+                return Ok(start + self.socket.send_datagrams(&datagrams[start..], peer)?);
+            }
+            // Segments all have the same size, but the last one may be shorter.
+            let size = datagrams[start].len();
+            let max = MAX_SEGMENTS.min(MAX_SUPER_PACKET / size.max(1));
+            let mut end = start + 1;
+            while end < datagrams.len() && end - start < max && datagrams[end].len() == size {
+                end += 1;
+            }
+            if end < datagrams.len() && end - start < max && datagrams[end].len() < size {
+                end += 1;
+            }
+            if end - start == 1 {
+                self.socket.send_to(datagrams[start], peer)?;
+                start = end;
+                continue;
+            }
+            match self.send_segments(&datagrams[start..end], size, peer) {
+                Ok(()) => start = end,
+                // EIO comes from NICs without checksum offload, the others from kernels
+                // without UDP_SEGMENT.
+                Err(err)
+                    if matches!(
+                        err.raw_os_error(),
+                        Some(libc::EIO | libc::EINVAL | libc::ENOPROTOOPT | libc::EOPNOTSUPP)
+                    ) =>
+                {
+                    warn!(error = %err, "UDP segmentation offload refused, falling back.");
+                    self.gso.store(false, Ordering::Relaxed);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(datagrams.len())
+    }
+
+    fn recv_datagrams(&self, bufs: &mut [Vec<u8>]) -> io::Result<Vec<(usize, SocketAddr)>> {
+        {
+            let mut pending = self
+                .pending
+                .lock()
+                .expect("Could not lock pending segments");
+            let mut received = Vec::new();
+            while received.len() < bufs.len() {
+                let Some((datagram, from)) = pending.pop_front() else {
+                    break;
+                };
+                received.push((copy_truncated(&datagram, &mut bufs[received.len()]), from));
+            }
+            if !received.is_empty() {
+                return Ok(received);
+            }
+        }
+        if !self.gro {
+            return self.socket.recv_datagrams(bufs);
+        }
+        self.recv_segments(bufs)
+    }
+
+    fn buffer(&self, buffer: Buffer) -> io::Result<usize> {
+        self.socket.buffer(buffer)
+    }
+
+    fn set_buffer(&self, buffer: Buffer, bytes: usize) -> io::Result<usize> {
+        self.socket.set_buffer(buffer, bytes)
+    }
+
+    fn set_dscp(&self, dscp: Dscp) -> io::Result<()> {
+        self.socket.set_dscp(dscp)
+    }
+
+    fn bind_another(&self) -> io::Result<Arc<dyn Transport>> {
+        rebind_udp(self.socket.local_addr()?)
+    }
+}
+
+/// Like UDP, a datagram larger than the buffer is truncated.
+fn copy_truncated(datagram: &[u8], buf: &mut [u8]) -> usize {
+    let size = datagram.len().min(buf.len());
+    buf[..size].copy_from_slice(&datagram[..size]);
+    size
+}
+
+fn supports_gso(socket: &UdpSocket) -> bool {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: `value` and `len` are valid for the duration of the call.
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_UDP,
+            libc::UDP_SEGMENT,
+            &mut value as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    result == 0
+}
+
+fn enable_gro(socket: &UdpSocket) -> bool {
+    let value: libc::c_int = 1;
+    // SAFETY: `value` is valid for the duration of the call.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_UDP,
+            libc::UDP_GRO,
+            &value as *const _ as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    result == 0
+}
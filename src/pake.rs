@@ -0,0 +1,191 @@
+//! Key exchange authenticated by a code phrase, so that two peers that only share a short
+//! code end up with a strong [`SessionKey`] that nobody relaying their messages learns.
+//!
+//! This is SPAKE2 (RFC 9382) over Ristretto255, whose arithmetic curve25519-dalek runs in
+//! constant time: each side blinds a Diffie-Hellman share with a point scaled by the
+//! phrase, and both expand the key from the transcript with HKDF-SHA256. Someone in the
+//! middle gets a single guess of the phrase per exchange, and a wrong guess, like a wrong
+//! phrase, only leaves both sides with keys that do not match.
+//!
+//! It is the only key exchange of sanic, and it is not post-quantum: someone recording it
+//! could get the key once discrete logarithms fall. Adding a KEM like ML-KEM would not fit
+//! the meeting: both peers register at once and the relay introduces them with each
+//! other's message, while a KEM needs one side to answer the other's key, a second round
+//! through NATs with its own losses. Keys that must outlive such an adversary come another
+//! way: a `--key` shared beforehand, 256 bits of symmetric key, or `cp --encrypt`, which
+//! hands it over ssh, whose key exchange is hybrid post-quantum since OpenSSH 9.0.
+
+use std::io;
+
+#[cfg(feature = "crypto")]
+use curve25519_dalek::{
+    constants::RISTRETTO_BASEPOINT_POINT,
+    ristretto::{CompressedRistretto, RistrettoPoint},
+    traits::Identity,
+    Scalar,
+};
+#[cfg(feature = "crypto")]
+use hkdf::Hkdf;
+#[cfg(feature = "crypto")]
+use sha2::Sha256;
+
+#[cfg(feature = "crypto")]
+use crate::crypto::random_bytes;
+use crate::crypto::SessionKey;
+
+/// Size of the group elements on the wire.
+#[cfg(feature = "crypto")]
+const ELEMENT_SIZE: usize = 32;
+
+/// One side of the exchange: send [`Pake::message`] to the peer, then hand what it sent
+/// back to [`Pake::finish`].
+#[cfg(feature = "crypto")]
+pub(crate) struct Pake {
+    sender: bool,
+    /// The phrase, as a scalar.
+    password: Scalar,
+    secret: Scalar,
+    message: [u8; ELEMENT_SIZE],
+}
+
+#[cfg(feature = "crypto")]
+impl Pake {
+    pub fn new(phrase: &str, sender: bool) -> io::Result<Self> {
+        let mut secret = [0; 64];
+        random_bytes(&mut secret)?;
+        let secret = Scalar::from_bytes_mod_order_wide(&secret);
+        let password =
+            Scalar::from_bytes_mod_order_wide(&expand(phrase.as_bytes(), b"sanic spake2 password"));
+        let blind = if sender { blind_m() } else { blind_n() };
+        let message = (RISTRETTO_BASEPOINT_POINT * secret + blind * password)
+            .compress()
+            .to_bytes();
+        Ok(Pake {
+            sender,
+            password,
+            secret,
+            message,
+        })
+    }
+
+    pub fn message(&self) -> &[u8] {
+        &self.message
+    }
+
+    /// Key shared with the peer that sent `peer`, if it is a valid element. It only
+    /// matches the peer's if both used the same phrase.
+    pub fn finish(&self, peer: &[u8]) -> Option<SessionKey> {
+        let element = CompressedRistretto::from_slice(peer).ok()?.decompress()?;
+        if element == RistrettoPoint::identity() {
+            return None;
+        }
+        let blind = if self.sender { blind_n() } else { blind_m() };
+        let shared = (element - blind * self.password) * self.secret;
+        if shared == RistrettoPoint::identity() {
+            return None;
+        }
+        let (ours, theirs) = (self.message.as_slice(), peer);
+        let (sender, receiver) = if self.sender {
+            (ours, theirs)
+        } else {
+            (theirs, ours)
+        };
+        let mut transcript = Vec::new();
+        for part in [
+            sender,
+            receiver,
+            shared.compress().as_bytes(),
+            self.password.as_bytes(),
+        ] {
+            transcript.extend((part.len() as u64).to_le_bytes());
+            transcript.extend(part);
+        }
+        let okm = Hkdf::<Sha256>::new(None, &transcript);
+        let mut key = [0; 32];
+        let mut salt = [0; 8];
+        okm.expand(b"sanic key", &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        okm.expand(b"sanic salt", &mut salt)
+            .expect("8 bytes is a valid HKDF-SHA256 output length");
+        Some(SessionKey {
+            key,
+            salt: u64::from_be_bytes(salt),
+        })
+    }
+}
+
+/// Without the `crypto` feature there is no key exchange, and meetings fail.
+#[cfg(not(feature = "crypto"))]
+pub(crate) enum Pake {}
+
+#[cfg(not(feature = "crypto"))]
+impl Pake {
+    pub fn new(_phrase: &str, _sender: bool) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "sanic was built without encryption (the crypto feature)",
+        ))
+    }
+
+    pub fn message(&self) -> &[u8] {
+        match *self {}
+    }
+
+    pub fn finish(&self, _peer: &[u8]) -> Option<SessionKey> {
+        match *self {}
+    }
+}
+
+/// Point the sender blinds its share with. Nobody knows its discrete logarithm, nor the
+/// one of [`blind_n`].
+#[cfg(feature = "crypto")]
+fn blind_m() -> RistrettoPoint {
+    RistrettoPoint::from_uniform_bytes(&expand(b"sanic spake2 M", b"sanic spake2 point"))
+}
+
+/// Point the receiver blinds its share with.
+#[cfg(feature = "crypto")]
+fn blind_n() -> RistrettoPoint {
+    RistrettoPoint::from_uniform_bytes(&expand(b"sanic spake2 N", b"sanic spake2 point"))
+}
+
+/// 64 bytes of HKDF-SHA256 output, as uniform as scalars and points need them.
+#[cfg(feature = "crypto")]
+fn expand(ikm: &[u8], info: &[u8]) -> [u8; 64] {
+    let mut okm = [0; 64];
+    Hkdf::<Sha256>::new(None, ikm)
+        .expand(info, &mut okm)
+        .expect("64 bytes is a valid HKDF-SHA256 output length");
+    okm
+}
+
+#[cfg(all(test, feature = "crypto"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_same_phrase_gives_the_same_key() {
+        let sender = Pake::new("7-basilisk-tundra", true).unwrap();
+        let receiver = Pake::new("7-basilisk-tundra", false).unwrap();
+        let key = sender.finish(receiver.message()).unwrap();
+        assert_eq!(receiver.finish(sender.message()), Some(key.clone()));
+
+        let guess = Pake::new("7-basilisk-tundrb", false).unwrap();
+        assert_ne!(guess.finish(sender.message()), Some(key.clone()));
+        assert_ne!(sender.finish(guess.message()), Some(key.clone()));
+
+        // Both sides of the exchange have to be played, one each.
+        let other = Pake::new("7-basilisk-tundra", true).unwrap();
+        assert_ne!(other.finish(sender.message()), Some(key));
+    }
+
+    #[test]
+    fn degenerate_elements_are_refused() {
+        let pake = Pake::new("7-basilisk-tundra", true).unwrap();
+        let identity = RistrettoPoint::identity().compress().to_bytes();
+        for element in [&identity[..], &[0xff; ELEMENT_SIZE], &[2], &[1; 256]] {
+            assert!(pake.finish(element).is_none());
+        }
+        assert_ne!(blind_m(), blind_n());
+    }
+}
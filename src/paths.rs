@@ -0,0 +1,113 @@
+//! Names and paths of received files, as the local filesystem takes them. Unix takes
+//! anything but `/` and NUL, which senders cannot pick anyway. Windows forbids more
+//! characters, reserves device names like `CON` or `NUL.txt` in every directory, drops
+//! trailing dots and spaces, and limits paths to 260 characters unless they are verbatim
+//! (`\\?\C:\...`).
+
+use std::{borrow::Cow, path::PathBuf};
+
+/// Device names of Windows, whatever the extension.
+#[cfg(any(windows, test))]
+const RESERVED: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+/// Paths from this long on are made verbatim, leaving room under MAX_PATH (260) for the
+/// suffix of partial files.
+#[cfg(windows)]
+const LONG_PATH: usize = 240;
+
+/// `name`, a single component sent by a peer, as a file name here.
+pub(crate) fn local_name(name: &str) -> Cow<'_, str> {
+    #[cfg(windows)]
+    return windows_name(name);
+    #[cfg(not(windows))]
+    Cow::Borrowed(name)
+}
+
+/// `path`, where a received file goes, in the form that lets Windows open it whatever its
+/// length.
+pub(crate) fn long(path: PathBuf) -> PathBuf {
+    #[cfg(windows)]
+    if path.as_os_str().len() >= LONG_PATH {
+        let Ok(absolute) = std::path::absolute(&path) else {
+            return path;
+        };
+        return match absolute.to_str().and_then(verbatim) {
+            Some(verbatim) => PathBuf::from(verbatim),
+            None => absolute,
+        };
+    }
+    path
+}
+
+/// `name` with the characters Windows forbids replaced by `_`, without the trailing dots
+/// and spaces it would drop, and `_` ahead of device names.
+#[cfg(any(windows, test))]
+fn windows_name(name: &str) -> Cow<'_, str> {
+    let forbidden = |c: char| {
+        c.is_control() || matches!(c, '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*')
+    };
+    let trimmed = name.trim_end_matches(['.', ' ']);
+    let stem = trimmed.split('.').next().unwrap_or_default().trim_end();
+    let reserved = RESERVED
+        .iter()
+        .any(|device| device.eq_ignore_ascii_case(stem));
+    if trimmed.len() == name.len() && !reserved && !name.contains(forbidden) && !name.is_empty() {
+        return Cow::Borrowed(name);
+    }
+    let mut local: String = trimmed.replace(forbidden, "_");
+    if reserved {
+        local.insert(0, '_');
+    }
+    if local.is_empty() {
+        local.push('_');
+    }
+    Cow::Owned(local)
+}
+
+/// Verbatim form of the absolute Windows path `path`, None if it is one already.
+#[cfg(any(windows, test))]
+fn verbatim(path: &str) -> Option<String> {
+    if path.starts_with(r"\\?\") || path.starts_with(r"\\.\") {
+        return None;
+    }
+    Some(match path.strip_prefix(r"\\") {
+        Some(share) => format!(r"\\?\UNC\{share}"),
+        None => format!(r"\\?\{path}"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_made_safe_for_windows() {
+        assert_eq!(windows_name("report.pdf"), "report.pdf");
+        assert!(matches!(windows_name("report.pdf"), Cow::Borrowed(_)));
+        assert_eq!(windows_name("a<b>:c\"d|e?f*g\\h"), "a_b__c_d_e_f_g_h");
+        assert_eq!(windows_name("tab\there"), "tab_here");
+        assert_eq!(windows_name("notes. . "), "notes");
+        assert_eq!(windows_name("con"), "_con");
+        assert_eq!(windows_name("NUL.txt"), "_NUL.txt");
+        assert_eq!(windows_name("lpt1 .tar.gz"), "_lpt1 .tar.gz");
+        assert_eq!(windows_name("console"), "console");
+        assert_eq!(windows_name("COM10"), "COM10");
+        assert_eq!(windows_name(".."), "_");
+        assert_eq!(windows_name(""), "_");
+    }
+
+    #[test]
+    fn long_paths_are_made_verbatim() {
+        assert_eq!(
+            verbatim(r"C:\Users\me\file.bin").as_deref(),
+            Some(r"\\?\C:\Users\me\file.bin")
+        );
+        assert_eq!(
+            verbatim(r"\\server\share\file.bin").as_deref(),
+            Some(r"\\?\UNC\server\share\file.bin")
+        );
+        assert_eq!(verbatim(r"\\?\C:\file.bin"), None);
+    }
+}
@@ -0,0 +1,125 @@
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Keyed, bijective mapping of part ids used on the wire in encrypted transfers.
+///
+/// Sequential part ids leak the progress and the size of a transfer to anyone
+/// watching the link, even if the payload itself is encrypted. Each session
+/// derives its own permutation from the session key and a random salt, so the
+/// ids on the wire look random while both ends keep tracking parts with the
+/// sequential ids internally.
+///
+/// The permutation is a balanced Feistel network over the two 16-bit halves of
+/// the id, which makes it a bijection on the whole `u32` space for any key. Its
+/// round keys are expanded from the key and the salt with HKDF-SHA256, and its
+/// round function is HMAC-SHA256 under the key of the round, truncated to a half.
+#[derive(Debug, Clone)]
+pub struct PartIdPermutation {
+    rounds: [Hmac<Sha256>; ROUNDS],
+}
+
+const ROUNDS: usize = 8;
+
+impl PartIdPermutation {
+    pub fn new(session_key: &[u8], salt: u64) -> Self {
+        let mut round_keys = [0; 32 * ROUNDS];
+        Hkdf::<Sha256>::new(Some(&salt.to_be_bytes()), session_key)
+            .expand(b"sanic part ids", &mut round_keys)
+            .expect("256 bytes is a valid HKDF-SHA256 output length");
+        let mut keys = round_keys.chunks_exact(32);
+        let rounds = std::array::from_fn(|_| {
+            let key = keys.next().expect("There is a key per round");
+            Hmac::new_from_slice(key).expect("HMAC takes keys of any size")
+        });
+        PartIdPermutation { rounds }
+    }
+
+    /// Maps an internal (sequential) part id to the id sent on the wire.
+    pub fn encode(&self, id: u32) -> u32 {
+        let (mut left, mut right) = ((id >> 16) as u16, id as u16);
+        for mac in self.rounds.iter() {
+            let next = left ^ round(mac, right);
+            left = right;
+            right = next;
+        }
+        ((left as u32) << 16) | right as u32
+    }
+
+    /// Maps an id received from the wire back to the internal part id.
+    pub fn decode(&self, id: u32) -> u32 {
+        let (mut left, mut right) = ((id >> 16) as u16, id as u16);
+        for mac in self.rounds.iter().rev() {
+            let prev = right ^ round(mac, left);
+            right = left;
+            left = prev;
+        }
+        ((left as u32) << 16) | right as u32
+    }
+}
+
+/// The first 16 bits of the HMAC of `half` under the key of the round.
+fn round(mac: &Hmac<Sha256>, half: u16) -> u16 {
+    let mut mac = mac.clone();
+    mac.update(&half.to_be_bytes());
+    let tag = mac.finalize().into_bytes();
+    u16::from_be_bytes([tag[0], tag[1]])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    #[test]
+    fn ids_of_any_transfer_size_map_one_to_one() {
+        let permutation = PartIdPermutation::new(&KEY, 42);
+        // The ids of any smaller transfer are the first of these, past a first half.
+        let mut wire = HashSet::new();
+        for id in 0..65_537 + 1000 {
+            let encoded = permutation.encode(id);
+            assert!(wire.insert(encoded), "{id} collides");
+            assert_eq!(permutation.decode(encoded), id);
+        }
+    }
+
+    #[test]
+    fn edge_ids_round_trip() {
+        let permutation = PartIdPermutation::new(&KEY, 42);
+        for id in [
+            0,
+            1,
+            u16::MAX as u32 - 1,
+            u16::MAX as u32,
+            u16::MAX as u32 + 1,
+            u32::MAX - 1,
+            u32::MAX,
+        ] {
+            assert_eq!(permutation.decode(permutation.encode(id)), id);
+            assert_eq!(permutation.encode(permutation.decode(id)), id);
+        }
+        // Without cycle walking, wire ids span all of u32: the top ids decode apart too.
+        let top: HashSet<u32> = (u32::MAX - 65_535..=u32::MAX)
+            .map(|id| permutation.decode(id))
+            .collect();
+        assert_eq!(top.len(), 65_536);
+    }
+
+    #[test]
+    fn salts_and_keys_give_their_own_permutation() {
+        let ids = 0..1000;
+        let wire = |key: &[u8], salt| {
+            let permutation = PartIdPermutation::new(key, salt);
+            ids.clone()
+                .map(|id| permutation.encode(id))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(wire(&KEY, 1), wire(&KEY, 1));
+        assert_ne!(wire(&KEY, 1), wire(&KEY, 2));
+        assert_ne!(wire(&KEY, 1), wire(&[8; 32], 1));
+        assert_ne!(wire(&KEY, 0), ids.clone().collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,64 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+use crate::MTU;
+
+/// Packet buffers handed back after use, so the data path does not allocate for every
+/// packet. Clones share the same buffers.
+#[derive(Debug, Clone)]
+pub(crate) struct BufferPool {
+    free: Arc<Mutex<Vec<Vec<u8>>>>,
+    /// Most buffers kept around, the others are freed.
+    max: usize,
+    allocated: Arc<AtomicU64>,
+    reused: Arc<AtomicU64>,
+}
+
+impl BufferPool {
+    pub fn new(max: usize) -> Self {
+        BufferPool {
+            free: Arc::new(Mutex::new(Vec::new())),
+            max,
+            allocated: Arc::new(AtomicU64::new(0)),
+            reused: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// An empty buffer with room for at least a whole packet.
+    pub fn get(&self) -> Vec<u8> {
+        let reused = self.free.lock().expect("Could not lock buffer pool").pop();
+        let counter = match reused {
+            Some(_) => &self.reused,
+            None => &self.allocated,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        reused.unwrap_or_else(|| Vec::with_capacity(MTU))
+    }
+
+    /// Buffers handed out so far that had to be allocated, and that were reused.
+    pub fn counts(&self) -> (u64, u64) {
+        (
+            self.allocated.load(Ordering::Relaxed),
+            self.reused.load(Ordering::Relaxed),
+        )
+    }
+
+    pub fn put(&self, mut buf: Vec<u8>) {
+        if buf.capacity() < MTU {
+            return;
+        }
+        let mut free = self.free.lock().expect("Could not lock buffer pool");
+        if free.len() < self.max {
+            buf.clear();
+            free.push(buf);
+        }
+    }
+
+    pub fn put_all(&self, bufs: impl IntoIterator<Item = Vec<u8>>) {
+        for buf in bufs {
+            self.put(buf);
+        }
+    }
+}
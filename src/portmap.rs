@@ -0,0 +1,618 @@
+//! Port mappings on home routers, so that senders on the internet reach a receiver behind
+//! one: NAT-PMP (RFC 6886) when the default gateway speaks it, as it takes a single
+//! datagram, UPnP IGD otherwise. Only UDP is mapped.
+
+use std::{
+    fmt,
+    io::{self, ErrorKind, Read, Write},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpStream, ToSocketAddrs, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, info, warn};
+
+use crate::probe::local_ip;
+
+const NATPMP_PORT: u16 = 5351;
+/// NAT-PMP requests are sent again after 250ms, then twice as long every time.
+const NATPMP_TRIES: u32 = 4;
+const SSDP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900);
+const IGD: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+/// Services of an IGD that map ports, by order of preference.
+const SERVICES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+/// How long gateways have to answer a UPnP search.
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(3);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+/// Largest description or SOAP answer we read from a gateway.
+const MAX_HTTP: u64 = 1024 * 1024;
+/// Lifetime we ask for. Mappings are renewed halfway through.
+const LIFETIME: Duration = Duration::from_secs(3600);
+/// How long to wait before trying again when a renewal failed.
+const RETRY: Duration = Duration::from_secs(60);
+/// How often the renewing thread wakes up to notice it was dropped.
+const POLL: Duration = Duration::from_millis(500);
+/// SOAP error of the gateways that only map ports for good.
+const ONLY_PERMANENT_LEASES: &str = "725";
+
+/// How the port got mapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    NatPmp,
+    Upnp,
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Method::NatPmp => "NAT-PMP",
+            Method::Upnp => "UPnP",
+        })
+    }
+}
+
+/// UDP port mapped on the gateway, renewed in the background and removed once dropped.
+pub struct PortMapping {
+    external: SocketAddr,
+    method: Method,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PortMapping {
+    /// Asks the gateway to forward the UDP `port` of its public address to the same port
+    /// of ours.
+    pub fn start(port: u16) -> io::Result<Self> {
+        let gateway = find_gateway()?;
+        let (external_port, lifetime) = gateway.map(port)?;
+        let external = SocketAddr::from((gateway.external_ip()?, external_port));
+        let method = gateway.method();
+        info!(%external, %method, "Mapped the port on the gateway.");
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            std::thread::spawn(move || maintain(gateway, port, external_port, lifetime, stop))
+        };
+        Ok(PortMapping {
+            external,
+            method,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Public address senders reach us at.
+    pub fn external(&self) -> SocketAddr {
+        self.external
+    }
+
+    pub fn method(&self) -> Method {
+        self.method
+    }
+}
+
+impl Drop for PortMapping {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Renews the mapping until stopped, then removes it.
+fn maintain(
+    gateway: Gateway,
+    port: u16,
+    external_port: u16,
+    lifetime: Option<Duration>,
+    stop: Arc<AtomicBool>,
+) {
+    let mut renew = lifetime.map(|lifetime| Instant::now() + lifetime / 2);
+    while !stop.load(Ordering::Relaxed) {
+        std::thread::sleep(POLL);
+        if renew.is_some_and(|at| Instant::now() >= at) {
+            renew = match gateway.map(port) {
+                Ok((renewed, lifetime)) => {
+                    if renewed != external_port {
+                        warn!(
+                            port = renewed,
+                            "The gateway moved our mapping to another port."
+                        );
+                    }
+                    lifetime.map(|lifetime| Instant::now() + lifetime / 2)
+                }
+                Err(err) => {
+                    warn!(error = %err, "Could not renew the port mapping.");
+                    Some(Instant::now() + RETRY)
+                }
+            };
+        }
+    }
+    if let Err(err) = gateway.unmap(port, external_port) {
+        debug!(error = %err, "Could not remove the port mapping.");
+    }
+}
+
+/// Gateway that maps ports for us.
+enum Gateway {
+    NatPmp(SocketAddr),
+    Upnp {
+        control: Url,
+        service: &'static str,
+        /// Our address on the gateway's side.
+        internal: Ipv4Addr,
+    },
+}
+
+fn find_gateway() -> io::Result<Gateway> {
+    if let Some(router) = default_gateway() {
+        let gateway = SocketAddr::from((router, NATPMP_PORT));
+        match natpmp(gateway, &[0, 0], 0) {
+            Ok(_) => return Ok(Gateway::NatPmp(gateway)),
+            Err(err) => debug!(%gateway, error = %err, "No NAT-PMP on the gateway."),
+        }
+    }
+    upnp_gateway().map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("no gateway mapped the port over NAT-PMP or UPnP: {err}"),
+        )
+    })
+}
+
+impl Gateway {
+    fn method(&self) -> Method {
+        match self {
+            Gateway::NatPmp(_) => Method::NatPmp,
+            Gateway::Upnp { .. } => Method::Upnp,
+        }
+    }
+
+    /// Maps `port`, and returns the external port along with how long the mapping lasts,
+    /// if not for good.
+    fn map(&self, port: u16) -> io::Result<(u16, Option<Duration>)> {
+        match self {
+            Gateway::NatPmp(gateway) => {
+                let answer = natpmp(*gateway, &natpmp_map(port, port, LIFETIME), 1)?;
+                let external = u16::from_be_bytes([answer[2], answer[3]]);
+                let lifetime = u32::from_be_bytes(answer[4..8].try_into().unwrap());
+                Ok((external, Some(Duration::from_secs(lifetime.into()))))
+            }
+            Gateway::Upnp { internal, .. } => {
+                let add = |lease: Duration| {
+                    self.soap(
+                        "AddPortMapping",
+                        &[
+                            ("NewRemoteHost", String::new()),
+                            ("NewExternalPort", port.to_string()),
+                            ("NewProtocol", "UDP".to_string()),
+                            ("NewInternalPort", port.to_string()),
+                            ("NewInternalClient", internal.to_string()),
+                            ("NewEnabled", "1".to_string()),
+                            ("NewPortMappingDescription", "sanic".to_string()),
+                            ("NewLeaseDuration", lease.as_secs().to_string()),
+                        ],
+                    )
+                };
+                match add(LIFETIME)? {
+                    (200, _) => Ok((port, Some(LIFETIME))),
+                    (_, fault) if tag(&fault, "errorCode") == Some(ONLY_PERMANENT_LEASES) => {
+                        match add(Duration::ZERO)? {
+                            (200, _) => Ok((port, None)),
+                            (_, fault) => Err(refused("AddPortMapping", &fault)),
+                        }
+                    }
+                    (_, fault) => Err(refused("AddPortMapping", &fault)),
+                }
+            }
+        }
+    }
+
+    fn unmap(&self, port: u16, external_port: u16) -> io::Result<()> {
+        match self {
+            // A zero lifetime deletes the mapping.
+            Gateway::NatPmp(gateway) => {
+                natpmp(*gateway, &natpmp_map(port, 0, Duration::ZERO), 1).map(drop)
+            }
+            Gateway::Upnp { .. } => {
+                let args = [
+                    ("NewRemoteHost", String::new()),
+                    ("NewExternalPort", external_port.to_string()),
+                    ("NewProtocol", "UDP".to_string()),
+                ];
+                match self.soap("DeletePortMapping", &args)? {
+                    (200, _) => Ok(()),
+                    (_, fault) => Err(refused("DeletePortMapping", &fault)),
+                }
+            }
+        }
+    }
+
+    fn external_ip(&self) -> io::Result<Ipv4Addr> {
+        let ip = match self {
+            Gateway::NatPmp(gateway) => {
+                let answer = natpmp(*gateway, &[0, 0], 0)?;
+                return Ok(Ipv4Addr::new(answer[0], answer[1], answer[2], answer[3]));
+            }
+            Gateway::Upnp { .. } => match self.soap("GetExternalIPAddress", &[])? {
+                (200, answer) => {
+                    tag(&answer, "NewExternalIPAddress").and_then(|ip| ip.parse().ok())
+                }
+                (_, fault) => return Err(refused("GetExternalIPAddress", &fault)),
+            },
+        };
+        ip.ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                "the gateway did not tell its public address",
+            )
+        })
+    }
+
+    /// Calls `action` on the UPnP service of the gateway and returns the HTTP status of
+    /// the answer along with it.
+    fn soap(&self, action: &str, args: &[(&str, String)]) -> io::Result<(u16, String)> {
+        let Gateway::Upnp {
+            control, service, ..
+        } = self
+        else {
+            unreachable!("SOAP is only spoken to UPnP gateways");
+        };
+        let args: String = args
+            .iter()
+            .map(|(name, value)| format!("<{name}>{value}</{name}>"))
+            .collect();
+        let body = format!(
+            "<?xml version=\"1.0\"?>\
+             <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+             s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+             <s:Body><u:{action} xmlns:u=\"{service}\">{args}</u:{action}></s:Body>\
+             </s:Envelope>"
+        );
+        let headers = format!(
+            "Content-Type: text/xml; charset=\"utf-8\"\r\nSOAPAction: \"{service}#{action}\"\r\n"
+        );
+        http(control, "POST", &headers, &body)
+    }
+}
+
+/// Error for the SOAP `fault` a gateway answered `action` with.
+fn refused(action: &str, fault: &str) -> io::Error {
+    io::Error::new(
+        ErrorKind::ConnectionRefused,
+        format!(
+            "the gateway refused {action}: error {} {}",
+            tag(fault, "errorCode").unwrap_or("?"),
+            tag(fault, "errorDescription").unwrap_or_default()
+        ),
+    )
+}
+
+/// Default gateway of the host, as the kernel routes.
+fn default_gateway() -> Option<Ipv4Addr> {
+    parse_route(&std::fs::read_to_string("/proc/net/route").ok()?)
+}
+
+/// Gateway of the default route in a `/proc/net/route` table, whose addresses are
+/// written in hexadecimal in the byte order of the host.
+fn parse_route(table: &str) -> Option<Ipv4Addr> {
+    const RTF_GATEWAY: u16 = 0x2;
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let flags = u16::from_str_radix(fields.get(3)?, 16).ok()?;
+        if *fields.get(1)? != "00000000" || flags & RTF_GATEWAY == 0 {
+            return None;
+        }
+        let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+        Some(Ipv4Addr::from(gateway.to_ne_bytes()))
+    })
+}
+
+fn natpmp_map(port: u16, external_port: u16, lifetime: Duration) -> Vec<u8> {
+    let mut request = vec![0, 1, 0, 0];
+    request.extend(port.to_be_bytes());
+    request.extend(external_port.to_be_bytes());
+    request.extend((lifetime.as_secs() as u32).to_be_bytes());
+    request
+}
+
+/// Sends a NAT-PMP `request` for `opcode` to `gateway` until it answers, and returns what
+/// follows the header and epoch of the answer.
+fn natpmp(gateway: SocketAddr, request: &[u8], opcode: u8) -> io::Result<Vec<u8>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(gateway)?;
+    let mut wait = Duration::from_millis(250);
+    let mut buf = [0; 64];
+    for _ in 0..NATPMP_TRIES {
+        socket.send(request)?;
+        socket.set_read_timeout(Some(wait))?;
+        match socket.recv(&mut buf) {
+            Ok(size) => return parse_natpmp(&buf[..size], opcode),
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(err) => return Err(err),
+        }
+        wait *= 2;
+    }
+    Err(io::Error::new(
+        ErrorKind::TimedOut,
+        "no answer from the NAT-PMP gateway",
+    ))
+}
+
+fn parse_natpmp(answer: &[u8], opcode: u8) -> io::Result<Vec<u8>> {
+    let invalid = || io::Error::new(ErrorKind::InvalidData, "invalid NAT-PMP answer");
+    // Version, opcode, result code, seconds since the gateway started, then the payload.
+    let expected = match opcode {
+        0 => 4,
+        _ => 8,
+    };
+    if answer.len() < 8 + expected || answer[0] != 0 || answer[1] != 128 + opcode {
+        return Err(invalid());
+    }
+    match u16::from_be_bytes([answer[2], answer[3]]) {
+        0 => Ok(answer[8..8 + expected].to_vec()),
+        result => Err(io::Error::new(
+            ErrorKind::ConnectionRefused,
+            format!("the gateway refused the mapping, NAT-PMP result {result}"),
+        )),
+    }
+}
+
+/// Searches the LAN for an Internet Gateway Device and the service of it that maps ports.
+fn upnp_gateway() -> io::Result<Gateway> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {SSDP}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: {IGD}\r\n\r\n"
+    );
+    socket.send_to(search.as_bytes(), SSDP)?;
+
+    let deadline = Instant::now() + SEARCH_TIMEOUT;
+    let mut buf = [0; 2048];
+    loop {
+        let Some(wait) = deadline
+            .checked_duration_since(Instant::now())
+            .filter(|wait| !wait.is_zero())
+        else {
+            return Err(io::Error::new(
+                ErrorKind::NotFound,
+                "no UPnP gateway answered",
+            ));
+        };
+        socket.set_read_timeout(Some(wait))?;
+        let (size, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                continue
+            }
+            Err(err) => return Err(err),
+        };
+        let answer = String::from_utf8_lossy(&buf[..size]);
+        let Some(location) = header(&answer, "location") else {
+            continue;
+        };
+        match describe(location) {
+            Ok(gateway) => return Ok(gateway),
+            Err(err) => debug!(%from, location, error = %err, "Unusable UPnP gateway."),
+        }
+    }
+}
+
+/// Reads the description of the device at `location` to find how to map ports on it.
+fn describe(location: &str) -> io::Result<Gateway> {
+    let url = Url::parse(location)?;
+    let (_, description) = http(&url, "GET", "", "")?;
+    let (service, control) = find_service(&description)
+        .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "the gateway does not map ports"))?;
+    let control = url.join(control)?;
+    let internal = local_ip(control.addr)?;
+    Ok(Gateway::Upnp {
+        control,
+        service,
+        internal,
+    })
+}
+
+/// Service of a device description that maps ports, and its control URL.
+fn find_service(description: &str) -> Option<(&'static str, &str)> {
+    let services: Vec<(&str, &str)> = description
+        .split("<service>")
+        .skip(1)
+        .filter_map(|service| Some((tag(service, "serviceType")?, tag(service, "controlURL")?)))
+        .collect();
+    SERVICES.iter().find_map(|&wanted| {
+        services
+            .iter()
+            .find(|(kind, _)| *kind == wanted)
+            .map(|&(_, control)| (wanted, control))
+    })
+}
+
+/// Text of the first `name` element of `xml`, namespace prefixes aside.
+fn tag<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = xml;
+    loop {
+        let start = rest.find('<')?;
+        rest = &rest[start + 1..];
+        let end = rest.find('>')?;
+        let element = &rest[..end];
+        let local = element.rsplit(':').next().unwrap_or(element);
+        rest = &rest[end + 1..];
+        if local == name {
+            return Some(rest[..rest.find('<')?].trim());
+        }
+    }
+}
+
+/// Value of the header `name` in an HTTP message, case aside.
+fn header<'a>(message: &'a str, name: &str) -> Option<&'a str> {
+    message.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+/// Plain `http://` URL, all UPnP gateways speak.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Url {
+    host: String,
+    addr: SocketAddr,
+    path: String,
+}
+
+impl Url {
+    fn parse(url: &str) -> io::Result<Self> {
+        let invalid = || io::Error::new(ErrorKind::InvalidData, format!("unsupported URL {url}"));
+        let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+        let (host, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, "/"),
+        };
+        let addr = match host.to_socket_addrs() {
+            Ok(mut addrs) => addrs.next(),
+            Err(_) => (host, 80).to_socket_addrs()?.next(),
+        }
+        .ok_or_else(invalid)?;
+        Ok(Url {
+            host: host.to_string(),
+            addr,
+            path: path.to_string(),
+        })
+    }
+
+    /// `reference` taken relative to this URL.
+    fn join(&self, reference: &str) -> io::Result<Self> {
+        if reference.starts_with("http://") {
+            return Url::parse(reference);
+        }
+        let path = match reference.strip_prefix('/') {
+            Some(_) => reference.to_string(),
+            None => format!("/{reference}"),
+        };
+        Ok(Url {
+            path,
+            ..self.clone()
+        })
+    }
+}
+
+/// Sends an HTTP/1.0 request, which keeps gateways from chunking their answer, and returns
+/// the status code along with the body.
+fn http(url: &Url, method: &str, headers: &str, body: &str) -> io::Result<(u16, String)> {
+    let mut stream = TcpStream::connect_timeout(&url.addr, HTTP_TIMEOUT)?;
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+    write!(
+        stream,
+        "{method} {} HTTP/1.0\r\nHost: {}\r\n{headers}Content-Length: {}\r\n\r\n{body}",
+        url.path,
+        url.host,
+        body.len()
+    )?;
+    let mut answer = Vec::new();
+    stream.take(MAX_HTTP).read_to_end(&mut answer)?;
+    parse_http(&String::from_utf8_lossy(&answer))
+}
+
+fn parse_http(answer: &str) -> io::Result<(u16, String)> {
+    let invalid = || io::Error::new(ErrorKind::InvalidData, "invalid HTTP answer");
+    let (head, body) = answer.split_once("\r\n\r\n").ok_or_else(invalid)?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(invalid)?;
+    Ok((status, body.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_route_is_found() {
+        let table = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+                     eth0\t0000A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\n\
+                     eth0\t00000000\t0101A8C0\t0003\t0\t0\t100\t00000000\n";
+        let expected = Ipv4Addr::from(0x0101A8C0u32.to_ne_bytes());
+        assert_eq!(parse_route(table), Some(expected));
+        #[cfg(target_endian = "little")]
+        assert_eq!(expected, Ipv4Addr::new(192, 168, 1, 1));
+        assert_eq!(parse_route("Iface\tDestination\tGateway\tFlags\n"), None);
+    }
+
+    #[test]
+    fn natpmp_answers_are_checked() {
+        let mut mapped = vec![0, 129, 0, 0, 0, 0, 0, 9];
+        mapped.extend([0x1a, 0x0a, 0x1a, 0x0b, 0, 0, 0x0e, 0x10]);
+        assert_eq!(
+            parse_natpmp(&mapped, 1).unwrap(),
+            [0x1a, 0x0a, 0x1a, 0x0b, 0, 0, 0x0e, 0x10]
+        );
+        assert!(parse_natpmp(&mapped, 0).is_err());
+        assert!(parse_natpmp(&mapped[..12], 1).is_err());
+
+        let refused = [0, 129, 0, 3, 0, 0, 0, 9, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(
+            parse_natpmp(&refused, 1).unwrap_err().kind(),
+            ErrorKind::ConnectionRefused
+        );
+        let address = [0, 128, 0, 0, 0, 0, 0, 9, 203, 0, 113, 7];
+        assert_eq!(parse_natpmp(&address, 0).unwrap(), [203, 0, 113, 7]);
+    }
+
+    #[test]
+    fn the_mapping_service_is_found_in_the_description() {
+        let description = "<root><device><serviceList>\
+            <service><serviceType>urn:schemas-upnp-org:service:WANPPPConnection:1</serviceType>\
+            <controlURL>/ctl/PPP</controlURL></service>\
+            <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+            <controlURL> /ctl/IPConn </controlURL></service>\
+            </serviceList></device></root>";
+        assert_eq!(
+            find_service(description),
+            Some((
+                "urn:schemas-upnp-org:service:WANIPConnection:1",
+                "/ctl/IPConn"
+            ))
+        );
+        assert_eq!(find_service("<root></root>"), None);
+
+        let answer = "<s:Envelope><s:Body><u:GetExternalIPAddressResponse>\
+            <NewExternalIPAddress>203.0.113.7</NewExternalIPAddress>\
+            </u:GetExternalIPAddressResponse></s:Body></s:Envelope>";
+        assert_eq!(tag(answer, "NewExternalIPAddress"), Some("203.0.113.7"));
+        assert_eq!(tag(answer, "Body"), Some(""));
+        assert_eq!(tag(answer, "errorCode"), None);
+    }
+
+    #[test]
+    fn locations_are_parsed() {
+        let search = "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\n\
+                      Location: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n";
+        let location = header(search, "LOCATION").unwrap();
+        let url = Url::parse(location).unwrap();
+        assert_eq!(url.addr, "192.168.1.1:5000".parse().unwrap());
+        assert_eq!(url.path, "/rootDesc.xml");
+        assert_eq!(url.join("ctl/IPConn").unwrap().path, "/ctl/IPConn");
+        assert_eq!(
+            Url::parse("http://192.168.1.1").unwrap().addr,
+            "192.168.1.1:80".parse().unwrap()
+        );
+        assert!(Url::parse("https://192.168.1.1/").is_err());
+
+        assert_eq!(
+            parse_http("HTTP/1.1 500 Internal Server Error\r\nServer: x\r\n\r\n<fault/>").unwrap(),
+            (500, "<fault/>".to_string())
+        );
+    }
+}
@@ -0,0 +1,178 @@
+//! Discovery for networks that do not let mDNS through: a Probe broadcast on the sanic
+//! port, which idle receivers answer with their host name and the space they have left.
+
+use std::{
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    path::Path,
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use tracing::debug;
+
+use crate::{
+    protocol::{Field, Message},
+    MTU,
+};
+
+/// Probes are padded to the largest answer, so that receivers never send back more than
+/// they got and cannot be used to amplify traffic towards a spoofed address.
+pub(crate) const PROBE_SIZE: usize = 1 + 8 + Field::Hostname.max();
+
+/// Receiver that answered a Probe.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Presence {
+    pub hostname: String,
+    pub addr: SocketAddr,
+    /// Space left where it writes the files it receives, when it could tell.
+    pub free_bytes: Option<u64>,
+}
+
+/// Lists the receivers listening on `port` that answer a broadcast within `timeout`.
+pub fn broadcast(port: u16, timeout: Duration) -> io::Result<Vec<Presence>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+    let mut probe = Message::Probe.serialize();
+    probe.resize(PROBE_SIZE, 0);
+
+    let start = Instant::now();
+    let mut sent = 0;
+    let mut found: Vec<Presence> = Vec::new();
+    let mut buf = vec![0; MTU];
+    loop {
+        let elapsed = start.elapsed();
+        if elapsed >= timeout {
+            break;
+        }
+        // Sent a second time halfway, in case the first one got lost.
+        if sent == 0 || (sent == 1 && elapsed >= timeout / 2) {
+            socket.send_to(&probe, (Ipv4Addr::BROADCAST, port))?;
+            sent += 1;
+        }
+        let wait = (timeout - elapsed)
+            .min(timeout / 2)
+            .max(Duration::from_millis(1));
+        socket.set_read_timeout(Some(wait))?;
+        match socket.recv_from(&mut buf) {
+            Ok((size, addr)) => match Message::parse(&buf[..size]) {
+                Ok(Message::Presence {
+                    hostname,
+                    free_bytes,
+                }) if found.iter().all(|presence| presence.addr != addr) => found.push(Presence {
+                    hostname,
+                    addr,
+                    free_bytes,
+                }),
+                Ok(_) => {}
+                Err(err) => debug!(%addr, error = ?err, "Could not parse answer to a probe."),
+            },
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(found)
+}
+
+/// Space available to us on the filesystem of `dir`, as `df` reports it.
+pub(crate) fn free_space(dir: &Path) -> Option<u64> {
+    let output = Command::new("df")
+        .arg("-P")
+        .arg("-k")
+        .arg(dir)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    parse_df(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Bytes the files under `dir` hold, symbolic links left out.
+pub(crate) fn used_space(dir: &Path) -> io::Result<u64> {
+    let mut used = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            used += used_space(&entry.path())?;
+        } else if file_type.is_file() {
+            used += entry.metadata()?.len();
+        }
+    }
+    Ok(used)
+}
+
+/// Available bytes in the POSIX output of `df -k`: a header, then the filesystem, its
+/// size, used and available kilobytes, capacity and mount point.
+fn parse_df(output: &str) -> Option<u64> {
+    let kilobytes: u64 = output
+        .lines()
+        .nth(1)?
+        .split_whitespace()
+        .nth(3)?
+        .parse()
+        .ok()?;
+    kilobytes.checked_mul(1024)
+}
+
+/// Address of the interface packets to `peer` leave from.
+pub(crate) fn local_ip(peer: SocketAddr) -> io::Result<Ipv4Addr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(peer)?;
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(ip) => Ok(ip),
+        IpAddr::V6(_) => Err(ErrorKind::AddrNotAvailable.into()),
+    }
+}
+
+/// Name of this host, for announcements and Presence answers.
+pub(crate) fn hostname() -> String {
+    ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .chain(std::env::var("HOSTNAME"))
+        .map(|name| {
+            name.trim()
+                .split('.')
+                .next()
+                .unwrap_or_default()
+                .to_string()
+        })
+        .find(|name| !name.is_empty())
+        .unwrap_or_else(|| "sanic".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn df_output_is_parsed() {
+        let output = "Filesystem     1024-blocks     Used Available Capacity Mounted on\n\
+                      /dev/nvme0n1p2   490691512 97418848 368251436      21% /\n";
+        assert_eq!(parse_df(output), Some(368251436 * 1024));
+        assert_eq!(parse_df("Filesystem 1024-blocks Used Available\n"), None);
+        assert_eq!(parse_df(""), None);
+    }
+
+    #[test]
+    fn used_space_adds_up_the_tree() {
+        let dir = TempDir::new("used");
+        dir.write("a", [0; 100]);
+        dir.write("sub/b", [0; 23]);
+        assert_eq!(used_space(dir.path()).unwrap(), 123);
+    }
+
+    #[test]
+    fn probes_cover_the_largest_answer() {
+        let answer = Message::Presence {
+            hostname: "h".repeat(Field::Hostname.max()),
+            free_bytes: Some(u64::MAX - 1),
+        };
+        assert_eq!(answer.serialize().len(), PROBE_SIZE);
+    }
+}
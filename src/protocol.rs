@@ -1,9 +1,50 @@
-use std::io::{Cursor, Read};
+use std::{
+    io::{Cursor, Read},
+    net::{IpAddr, SocketAddr},
+    ops::Range,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use byteorder::ReadBytesExt;
 use thiserror::Error;
 
-use crate::MTU;
+use crate::{speedtest, MAX_TEXT, MTU, PART_SIZE};
+
+/// Flag of a Send whose parts make up a delta.
+const DELTA: u8 = 1;
+/// Flag of a Send whose parts make up a chunked delta.
+const DEDUP: u8 = 2;
+/// Flag of a Send whose blocks of parts come with digests to verify them against.
+const VERIFY: u8 = 4;
+/// Flag of a Send, a Manifest, a Signatures or a Chunks followed by the token of its sender.
+const TOKEN: u8 = 8;
+/// Flag of a Send of a range of its file, followed by the offset of that range.
+const OFFSET: u8 = 16;
+/// Flag of a Send of a text message rather than a file, see [`crate::Sender::send_text`].
+const TEXT: u8 = 32;
+/// Flag of a Send of the synthetic data of a speed test, see [`crate::speedtest`].
+const SYNTHETIC: u8 = 64;
+/// Flag of a Send followed by the exact size of what its parts make up.
+const SIZE: u8 = 128;
+/// Flag of a Manifest of a mirroring batch, followed by the directory it mirrors.
+const MIRROR: u8 = 1;
+/// Flag of a mirroring Manifest whose files are told apart by their digest.
+const CHECKSUM: u8 = 2;
+/// Flag of a mirroring Manifest removing the files it does not have.
+const DELETE: u8 = 4;
+/// Bytes of a [`BlockSum`] in a Blocks message.
+const BLOCK_SUM_LEN: usize = 4 + 16;
+/// Most block checksums a Blocks message carries, after its message id and header.
+pub const MAX_BLOCK_SUMS: usize = (MTU - 1 - 12) / BLOCK_SUM_LEN;
+/// Most digests a Digests message carries, after its message id and first index.
+pub const MAX_DIGESTS: usize = (MTU - 1 - 4) / 32;
+/// Most digests a Chunks message carries, after its message id, first index, flags and
+/// the longest token.
+pub const MAX_CHUNK_DIGESTS: usize = (MTU - 1 - 4 - 1 - 1 - Field::Token.max()) / 32;
+/// Most ranges a Received message carries, after its message id and cumulative bound.
+pub const MAX_RECEIVED_RANGES: usize = (MTU - 1 - 4) / 8;
+/// Most ranges a Resync message carries, after its message id and bounds.
+pub const MAX_RESYNC_RANGES: usize = (MTU - 1 - 8) / 8;
 
 #[derive(Error, Debug)]
 pub enum MarshallError {
@@ -12,27 +53,399 @@ pub enum MarshallError {
 
     #[error("Buffer too small")]
     OutOfSpace,
+
+    #[error("{field:?} of length {len} is over the limit of {}", field.max())]
+    Oversized { field: Field, len: u64 },
+}
+
+/// Lengths read off the wire that the parser bounds before allocating for them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Filename,
+    GroupName,
+    /// Ids of a Sync, an Ack or a Loss.
+    Ids,
+    HopName,
+    AbortReason,
+    Hostname,
+    /// Code two peers meet under at a rendezvous server.
+    Code,
+    /// Key exchange message two peers pass each other through a rendezvous server.
+    Pake,
+    /// Comma separated algorithm names of an Accept.
+    Algorithms,
+    /// Token a sender authorizes itself with.
+    Token,
+    /// Parts of a Send, at most the ones its size makes and the ones the kind of transfer
+    /// takes.
+    Parts,
+}
+
+const FIELDS: usize = 11;
+
+static REJECTED: [AtomicU64; FIELDS] = [const { AtomicU64::new(0) }; FIELDS];
+
+impl Field {
+    pub const ALL: [Field; FIELDS] = [
+        Field::Filename,
+        Field::GroupName,
+        Field::Ids,
+        Field::HopName,
+        Field::AbortReason,
+        Field::Hostname,
+        Field::Code,
+        Field::Pake,
+        Field::Algorithms,
+        Field::Token,
+        Field::Parts,
+    ];
+
+    /// Longest value accepted, in bytes, in ids for [`Field::Ids`], or in parts of a file
+    /// for [`Field::Parts`].
+    pub const fn max(self) -> usize {
+        match self {
+            Field::Filename => 1024,
+            Field::GroupName => 255,
+            Field::Ids => (MTU - 1 - 4) / 4,
+            Field::HopName => 255,
+            Field::AbortReason => 512,
+            Field::Hostname => 255,
+            Field::Code => 64,
+            Field::Pake => 512,
+            Field::Algorithms => 128,
+            // Leaves room for the longest filename and group name in a Send.
+            Field::Token => 128,
+            Field::Parts => u32::MAX as usize,
+        }
+    }
+
+    /// Most parts of a Send of a text message, or of the synthetic data of a speed test,
+    /// which both stay far below the ones of a file.
+    const fn max_parts(text: bool, synthetic: bool) -> usize {
+        match (text, synthetic) {
+            (true, _) => MAX_TEXT.div_ceil(PART_SIZE),
+            (false, true) => speedtest::MAX_ROUND.div_ceil(PART_SIZE as u64) as usize,
+            (false, false) => Field::Parts.max(),
+        }
+    }
+
+    /// How many values of this field were refused for their length, since the process
+    /// started.
+    pub fn rejected(self) -> u64 {
+        REJECTED[self as usize].load(Ordering::Relaxed)
+    }
+
+    /// Checks a length read off the wire against our maximum and against the `available`
+    /// units left in the packet, so that a lying length never gets allocated.
+    fn guard(self, len: u64, available: usize) -> Result<usize, MarshallError> {
+        if len > self.max().min(available) as u64 {
+            REJECTED[self as usize].fetch_add(1, Ordering::Relaxed);
+            return Err(MarshallError::Oversized { field: self, len });
+        }
+        Ok(len as usize)
+    }
+}
+
+/// Membership of a file in a transfer group, which the receiver only publishes once all
+/// of its `count` members arrived.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupMember {
+    pub name: String,
+    pub index: u32,
+    pub count: u32,
+}
+
+/// Traffic a forwarding hop saw for a session, sent back towards the sender.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HopStats {
+    pub hop: String,
+    /// Datagrams forwarded towards the receiver.
+    pub to_receiver: u64,
+    /// Datagrams forwarded back towards the sender.
+    pub to_sender: u64,
+    pub bytes: u64,
+}
+
+/// What a receiver can take, sent with its Accept so the sender configures itself rather
+/// than assume both ends were built alike.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Longest part data, in bytes.
+    pub max_part_size: u32,
+    /// Most parts the sender may have waiting for an Ack, None without a limit.
+    pub max_in_flight: Option<u32>,
+    /// Compression algorithms it can undo.
+    pub compression: Vec<String>,
+    /// Ciphers it can open sealed transfers with.
+    pub encryption: Vec<String>,
+    /// Space left where the file goes, when the receiver could tell.
+    pub free_bytes: Option<u64>,
+}
+
+/// File of a batch, with its path relative to the directory the batch was sent from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// Components separated by `/`, whatever the platform.
+    pub path: String,
+    pub size: u64,
+    /// Modification time, in seconds since the epoch.
+    pub modified: u64,
+    pub sha256: [u8; 32],
+}
+
+/// Directory of the receiver a batch mirrors, rather than adds to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mirror {
+    /// Where it is under the output directory, components separated by `/`. Empty for the
+    /// output directory itself.
+    pub dir: String,
+    /// Tell the files the receiver already has by their digest, rather than by their size
+    /// and modification time.
+    pub checksum: bool,
+    /// Remove the files of the directory the batch does not have.
+    pub delete: bool,
+}
+
+impl Mirror {
+    /// Bytes it takes in a Manifest, after its flags.
+    pub(crate) fn encoded_len(&self) -> usize {
+        2 + self.dir.len()
+    }
+}
+
+/// Checksums of a block of the receiver's copy of a file, which the sender looks for in
+/// its version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockSum {
+    /// Rolling checksum, cheap to slide over the sender's version byte by byte.
+    pub weak: u32,
+    /// First bytes of the SHA-256 of the block, confirming a weak match.
+    pub strong: [u8; 16],
+}
+
+impl ManifestEntry {
+    /// Bytes it takes in a Manifest.
+    pub(crate) fn encoded_len(&self) -> usize {
+        2 + self.path.len() + 8 + 8 + 32
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum Message {
     // ID: 0
-    Send { filename: String, parts: u32 },
+    Send {
+        filename: String,
+        parts: u32,
+        group: Option<GroupMember>,
+        /// The parts make up a delta against the receiver's copy of the file rather than
+        /// the file itself.
+        delta: bool,
+        /// The parts make up a delta against the chunks the receiver keeps.
+        dedup: bool,
+        /// Digests of the blocks of parts follow, the receiver only acknowledges the parts
+        /// of a block once it checked them.
+        verify: bool,
+        /// Token for receivers that only take transfers from the senders presenting it.
+        token: Option<String>,
+        /// Byte of the file the first part goes at, for transfers of a range of it, which
+        /// the receiver writes in place.
+        offset: Option<u64>,
+        /// The parts make up a short UTF-8 text to show rather than a file to store.
+        text: bool,
+        /// The parts are synthetic data of a speed test, thrown away rather than stored.
+        synthetic: bool,
+        /// Bytes the parts make up, so the last one is cut to what it should hold. Senders
+        /// that predate it leave the receiver to trust the last part.
+        size: Option<u64>,
+    },
     // ID: 1
-    Accept,
+    /// Receivers that predate capabilities send none.
+    Accept {
+        capabilities: Option<Capabilities>,
+    },
     // ID: 2
-    Part { id: u32, data: Vec<u8> },
+    Part {
+        id: u32,
+        data: Vec<u8>,
+    },
     // ID: 3
-    Sync { ids: Vec<u32> },
+    Sync {
+        ids: Vec<u32>,
+    },
     // ID: 4
-    Ack { ids: Vec<u32> },
+    Ack {
+        ids: Vec<u32>,
+    },
     // ID: 5
-    Loss { ids: Vec<u32> },
+    Loss {
+        ids: Vec<u32>,
+    },
+    // ID: 6
+    /// Cap the receiver asks the sender to respect, 0 lifting it.
+    RateLimit {
+        bytes_per_sec: u64,
+    },
+    // ID: 7
+    /// Injected by forwarding hops, never sealed.
+    HopStats(HopStats),
+    // ID: 8
+    /// The peer gave up on the transfer, with why.
+    Abort {
+        reason: String,
+    },
+    // ID: 9
+    /// Sent over another path of the sender, which the receiver then takes parts from.
+    Join,
+    // ID: 10
+    /// Broadcast to find the receivers listening on the LAN.
+    Probe,
+    // ID: 11
+    /// Answer of an idle receiver to a Probe.
+    Presence {
+        hostname: String,
+        /// Space left where the received files go, when the receiver could tell.
+        free_bytes: Option<u64>,
+    },
+    // ID: 12
+    /// Asks a rendezvous server to introduce us to the peer registering the same code.
+    Register {
+        code: String,
+        /// Whether we send the file, as the code pairs a sender with a receiver.
+        sender: bool,
+        /// Key exchange message for the peer, passed along with the introduction.
+        pake: Vec<u8>,
+    },
+    // ID: 13
+    /// Address the rendezvous server saw the peer registering from, and the key exchange
+    /// message it registered with.
+    Introduce {
+        peer: SocketAddr,
+        pake: Vec<u8>,
+    },
+    // ID: 14
+    /// Sent by the receiver to the sender it was introduced to, opening its NAT for it.
+    Punch,
+    // ID: 15
+    /// Asks the rendezvous server to forward our datagrams to the peer we were introduced
+    /// to under `code`, as we could not reach it. Answered with the RateLimit it forwards
+    /// at, or with an Abort.
+    Relay {
+        code: String,
+    },
+    // ID: 16
+    /// Entries `first..first + entries.len()` of a batch of `total` files, announced
+    /// before any of them is sent. Answered with a Have, unless it answers a List.
+    Manifest {
+        first: u32,
+        total: u32,
+        mirror: Option<Mirror>,
+        /// Token of the sender, for the receivers that take batches only from the senders
+        /// with it.
+        token: Option<String>,
+        entries: Vec<ManifestEntry>,
+    },
+    // ID: 17
+    /// Indices of the files of a Manifest, or of the chunks of a Chunks, the receiver
+    /// already has, so the sender skips them. `first` is the one of the message answered.
+    Have {
+        first: u32,
+        ids: Vec<u32>,
+    },
+    // ID: 18
+    /// Asks for the checksums of the receiver's copy of `filename`, from block `first` on.
+    /// Answered with a Blocks, as long as it is padded to at least the size of the answer.
+    Signatures {
+        filename: String,
+        first: u32,
+        /// Token of the sender, for the receivers that show their files only to the
+        /// senders with it.
+        token: Option<String>,
+    },
+    // ID: 19
+    /// Checksums of blocks `first..first + sums.len()` of the receiver's copy of a file,
+    /// cut in `total` blocks of `block_size` bytes. No blocks at all when it has no copy.
+    Blocks {
+        first: u32,
+        total: u32,
+        block_size: u32,
+        sums: Vec<BlockSum>,
+    },
+    // ID: 20
+    /// Digests of the chunks `first..first + digests.len()` of a file, sent ahead of a
+    /// chunked delta. Answered with a Have.
+    Chunks {
+        first: u32,
+        /// Token of the sender, for the receivers that show their cache only to the
+        /// senders with it.
+        token: Option<String>,
+        digests: Vec<[u8; 32]>,
+    },
+    // ID: 21
+    /// Digests of the blocks `first..first + digests.len()` of a verified transfer, sent
+    /// along with its parts.
+    Digests {
+        first: u32,
+        digests: Vec<[u8; 32]>,
+    },
+    // ID: 22
+    /// Asks a serving host for the files it serves, from entry `first` on. Answered with a
+    /// Manifest, as long as it is padded to at least the size of the answer.
+    List {
+        first: u32,
+    },
+    // ID: 23
+    /// Asks a serving host for the file it lists as `name`, or for the bytes of it in
+    /// `range`. Answered with the Send of its transfer, or an Abort, as long as it is padded
+    /// to a whole datagram.
+    Get {
+        name: String,
+        range: Option<Range<u64>>,
+    },
+    // ID: 24
+    /// The receiver's operator paused the transfer, or resumed it. Repeated with every Sync
+    /// answer while paused, the sender keeps syncing but sends no new parts.
+    Pause {
+        paused: bool,
+    },
+    // ID: 25
+    /// Asks a receiver taking speed tests for `bytes` of synthetic data. Answered with the
+    /// Send of that transfer, from the port it went to, or an Abort, as long as it is
+    /// padded to a whole datagram.
+    SpeedTest {
+        bytes: u64,
+    },
+    // ID: 26
+    /// Sent by the receiver every few parts or milliseconds without being asked: every part
+    /// below `below` arrived, and so did the ones in `ranges` that arrived since the last.
+    /// Acknowledges them like an Ack, so Syncs only ask about the rest.
+    Received {
+        below: u32,
+        ranges: Vec<Range<u32>>,
+    },
+    // ID: 27
+    /// Sent by a receiver that restarted and took the transfer back, in answer to the
+    /// sender's Syncs: of the parts `first..end`, it has the ones in `ranges` stored and
+    /// none of the others, which are to be sent again even if acknowledged before. Several
+    /// of them cover every part when one cannot list all the ranges.
+    Resync {
+        first: u32,
+        end: u32,
+        ranges: Vec<Range<u32>>,
+    },
 }
 
 impl Message {
     pub fn parse(data: &[u8]) -> Result<Self, MarshallError> {
-        match data[0] {
+        Self::parse_with(data, Vec::new)
+    }
+
+    /// Like [`Message::parse`], but copies the data of a Part into the buffer `alloc` gives,
+    /// such as a recycled one.
+    pub fn parse_with(data: &[u8], alloc: impl FnOnce() -> Vec<u8>) -> Result<Self, MarshallError> {
+        let (kind, data) = data
+            .split_first()
+            .ok_or(MarshallError::UnableToDeserialize)?;
+        match kind {
             0 => {
                 let mut reader = Cursor::new(data);
                 let parts = reader
@@ -41,66 +454,398 @@ impl Message {
                 let string_size = reader
                     .read_u32::<byteorder::BigEndian>()
                     .map_err(|_| MarshallError::UnableToDeserialize)?;
-                let mut string_bytes: Vec<u8> = vec![0; string_size as usize];
+                let string_size = Field::Filename.guard(string_size.into(), remaining(&reader))?;
+                let mut string_bytes: Vec<u8> = vec![0; string_size];
                 reader
                     .read_exact(&mut string_bytes)
                     .map_err(|_| MarshallError::UnableToDeserialize)?;
-                let filename = String::from_utf8_lossy(&string_bytes).to_string();
-                Ok(Message::Send { filename, parts })
+                let filename = utf8(string_bytes)?;
+                // The group section is optional, plain transfers end with the filename.
+                // Flags come after it, behind a group name length of all ones if there is
+                // no group, which receivers that predate them refuse.
+                let mut group = None;
+                let mut flags = 0;
+                let mut token = None;
+                let mut offset = None;
+                let mut size = None;
+                if remaining(&reader) > 0 {
+                    let position = reader.position();
+                    let len = reader
+                        .read_u32::<byteorder::BigEndian>()
+                        .map_err(|_| MarshallError::UnableToDeserialize)?;
+                    if len != u32::MAX {
+                        reader.set_position(position);
+                        group = Some(read_group_member(&mut reader)?);
+                    }
+                    if remaining(&reader) > 0 {
+                        flags = reader
+                            .read_u8()
+                            .map_err(|_| MarshallError::UnableToDeserialize)?;
+                    }
+                    if flags & TOKEN != 0 {
+                        token = Some(read_token(&mut reader)?);
+                    }
+                    if flags & OFFSET != 0 {
+                        offset = Some(
+                            reader
+                                .read_u64::<byteorder::BigEndian>()
+                                .map_err(|_| MarshallError::UnableToDeserialize)?,
+                        );
+                    }
+                    if flags & SIZE != 0 {
+                        size = Some(
+                            reader
+                                .read_u64::<byteorder::BigEndian>()
+                                .map_err(|_| MarshallError::UnableToDeserialize)?,
+                        );
+                    }
+                }
+                let text = flags & TEXT != 0;
+                let synthetic = flags & SYNTHETIC != 0;
+                // Receivers size what they track of a transfer after its parts, so they go
+                // by no more than the size and the kind of transfer make.
+                let made = size.map_or(usize::MAX, |size| {
+                    usize::try_from(size.div_ceil(PART_SIZE as u64)).unwrap_or(usize::MAX)
+                });
+                let available = Field::max_parts(text, synthetic).min(made);
+                let parts = Field::Parts.guard(parts.into(), available)? as u32;
+                Ok(Message::Send {
+                    filename,
+                    parts,
+                    group,
+                    delta: flags & DELTA != 0,
+                    dedup: flags & DEDUP != 0,
+                    verify: flags & VERIFY != 0,
+                    token,
+                    offset,
+                    text,
+                    synthetic,
+                    size,
+                })
             }
-            1 => Ok(Message::Accept),
+            1 => Ok(Message::Accept {
+                capabilities: if data.is_empty() {
+                    None
+                } else {
+                    Some(read_capabilities(&mut Cursor::new(data))?)
+                },
+            }),
             2 => {
                 let mut reader = Cursor::new(data);
                 let id = reader
                     .read_u32::<byteorder::BigEndian>()
                     .map_err(|_| MarshallError::UnableToDeserialize)?;
-                let mut buffer: Vec<u8> = vec![0; data.len() - 4 - 1];
+                let mut buffer = alloc();
+                buffer.clear();
                 reader
                     .read_to_end(&mut buffer)
                     .map_err(|_| MarshallError::UnableToDeserialize)?;
                 Ok(Message::Part { id, data: buffer })
             }
-            3 => {
+            3 => Ok(Message::Sync {
+                ids: read_ids(data)?,
+            }),
+            4 => Ok(Message::Ack {
+                ids: read_ids(data)?,
+            }),
+            5 => Ok(Message::Loss {
+                ids: read_ids(data)?,
+            }),
+            6 => {
                 let mut reader = Cursor::new(data);
-                let size = reader
-                    .read_u32::<byteorder::BigEndian>()
+                let bytes_per_sec = reader
+                    .read_u64::<byteorder::BigEndian>()
                     .map_err(|_| MarshallError::UnableToDeserialize)?;
-                let mut ids: Vec<u32> = Vec::with_capacity(size as usize);
-                for _ in 0..size {
-                    let id = reader
-                        .read_u32::<byteorder::BigEndian>()
+                Ok(Message::RateLimit { bytes_per_sec })
+            }
+            7 => {
+                let mut reader = Cursor::new(data);
+                let mut counters = [0u64; 3];
+                for counter in counters.iter_mut() {
+                    *counter = reader
+                        .read_u64::<byteorder::BigEndian>()
                         .map_err(|_| MarshallError::UnableToDeserialize)?;
-                    ids.push(id);
                 }
-                Ok(Message::Sync { ids })
+                Field::HopName.guard(remaining(&reader) as u64, usize::MAX)?;
+                let mut hop = String::new();
+                reader
+                    .read_to_string(&mut hop)
+                    .map_err(|_| MarshallError::UnableToDeserialize)?;
+                let [to_receiver, to_sender, bytes] = counters;
+                Ok(Message::HopStats(HopStats {
+                    hop,
+                    to_receiver,
+                    to_sender,
+                    bytes,
+                }))
+            }
+            8 => {
+                Field::AbortReason.guard(data.len() as u64, usize::MAX)?;
+                Ok(Message::Abort {
+                    reason: utf8(data.to_vec())?,
+                })
+            }
+            9 => Ok(Message::Join),
+            10 => Ok(Message::Probe),
+            11 => {
+                let mut reader = Cursor::new(data);
+                let free_bytes = reader
+                    .read_u64::<byteorder::BigEndian>()
+                    .map_err(|_| MarshallError::UnableToDeserialize)?;
+                Field::Hostname.guard(remaining(&reader) as u64, usize::MAX)?;
+                let mut hostname = String::new();
+                reader
+                    .read_to_string(&mut hostname)
+                    .map_err(|_| MarshallError::UnableToDeserialize)?;
+                Ok(Message::Presence {
+                    hostname,
+                    // All ones stands for unknown.
+                    free_bytes: Some(free_bytes).filter(|&free| free != u64::MAX),
+                })
+            }
+            12 => {
+                let mut reader = Cursor::new(data);
+                let sender = reader
+                    .read_u8()
+                    .map_err(|_| MarshallError::UnableToDeserialize)?;
+                let len = reader
+                    .read_u8()
+                    .map_err(|_| MarshallError::UnableToDeserialize)?;
+                let len = Field::Code.guard(len.into(), remaining(&reader))?;
+                let mut code = vec![0; len];
+                reader
+                    .read_exact(&mut code)
+                    .map_err(|_| MarshallError::UnableToDeserialize)?;
+                Ok(Message::Register {
+                    code: utf8(code)?,
+                    sender: sender != 0,
+                    pake: read_pake(&mut reader)?,
+                })
+            }
+            13 => {
+                let mut reader = Cursor::new(data);
+                Ok(Message::Introduce {
+                    peer: read_addr(&mut reader)?,
+                    pake: read_pake(&mut reader)?,
+                })
+            }
+            14 => Ok(Message::Punch),
+            15 => {
+                Field::Code.guard(data.len() as u64, usize::MAX)?;
+                Ok(Message::Relay {
+                    code: utf8(data.to_vec())?,
+                })
+            }
+            16 => {
+                let mut reader = Cursor::new(data);
+                let first = reader
+                    .read_u32::<byteorder::BigEndian>()
+                    .map_err(|_| MarshallError::UnableToDeserialize)?;
+                let total = reader
+                    .read_u32::<byteorder::BigEndian>()
+                    .map_err(|_| MarshallError::UnableToDeserialize)?;
+                let flags = reader
+                    .read_u8()
+                    .map_err(|_| MarshallError::UnableToDeserialize)?;
+                let mirror = match flags & MIRROR {
+                    0 => None,
+                    _ => Some(Mirror {
+                        dir: read_path(&mut reader)?,
+                        checksum: flags & CHECKSUM != 0,
+                        delete: flags & DELETE != 0,
+                    }),
+                };
+                let token = match flags & TOKEN {
+                    0 => None,
+                    _ => Some(read_token(&mut reader)?),
+                };
+                let mut entries = Vec::new();
+                while remaining(&reader) > 0 {
+                    entries.push(read_manifest_entry(&mut reader)?);
+                }
+                Ok(Message::Manifest {
+                    first,
+                    total,
+                    mirror,
+                    token,
+                    entries,
+                })
             }
-            4 => {
+            17 => {
+                let (first, ids) = data
+                    .split_first_chunk()
+                    .ok_or(MarshallError::UnableToDeserialize)?;
+                Ok(Message::Have {
+                    first: u32::from_be_bytes(*first),
+                    ids: read_ids(ids)?,
+                })
+            }
+            18 => {
                 let mut reader = Cursor::new(data);
-                let size = reader
+                let first = reader
                     .read_u32::<byteorder::BigEndian>()
                     .map_err(|_| MarshallError::UnableToDeserialize)?;
-                let mut ids: Vec<u32> = Vec::with_capacity(size as usize);
-                for _ in 0..size {
-                    let id = reader
+                let len = reader
+                    .read_u16::<byteorder::BigEndian>()
+                    .map_err(|_| MarshallError::UnableToDeserialize)?;
+                let len = Field::Filename.guard(len.into(), remaining(&reader))?;
+                let mut filename = vec![0; len];
+                reader
+                    .read_exact(&mut filename)
+                    .map_err(|_| MarshallError::UnableToDeserialize)?;
+                let flags = reader
+                    .read_u8()
+                    .map_err(|_| MarshallError::UnableToDeserialize)?;
+                let token = match flags & TOKEN {
+                    0 => None,
+                    _ => Some(read_token(&mut reader)?),
+                };
+                // Followed by the padding.
+                Ok(Message::Signatures {
+                    filename: utf8(filename)?,
+                    first,
+                    token,
+                })
+            }
+            19 => {
+                let mut reader = Cursor::new(data);
+                let mut header = [0u32; 3];
+                for value in header.iter_mut() {
+                    *value = reader
+                        .read_u32::<byteorder::BigEndian>()
+                        .map_err(|_| MarshallError::UnableToDeserialize)?;
+                }
+                let [first, total, block_size] = header;
+                let mut sums = Vec::with_capacity(remaining(&reader) / BLOCK_SUM_LEN);
+                while remaining(&reader) > 0 {
+                    let weak = reader
                         .read_u32::<byteorder::BigEndian>()
                         .map_err(|_| MarshallError::UnableToDeserialize)?;
-                    ids.push(id);
+                    let mut strong = [0; 16];
+                    reader
+                        .read_exact(&mut strong)
+                        .map_err(|_| MarshallError::UnableToDeserialize)?;
+                    sums.push(BlockSum { weak, strong });
                 }
-                Ok(Message::Ack { ids })
+                Ok(Message::Blocks {
+                    first,
+                    total,
+                    block_size,
+                    sums,
+                })
             }
-            5 => {
+            20 => {
                 let mut reader = Cursor::new(data);
-                let size = reader
+                let first = reader
                     .read_u32::<byteorder::BigEndian>()
                     .map_err(|_| MarshallError::UnableToDeserialize)?;
-                let mut ids: Vec<u32> = Vec::with_capacity(size as usize);
-                for _ in 0..size {
-                    let id = reader
-                        .read_u32::<byteorder::BigEndian>()
-                        .map_err(|_| MarshallError::UnableToDeserialize)?;
-                    ids.push(id);
+                let flags = reader
+                    .read_u8()
+                    .map_err(|_| MarshallError::UnableToDeserialize)?;
+                let token = match flags & TOKEN {
+                    0 => None,
+                    _ => Some(read_token(&mut reader)?),
+                };
+                Ok(Message::Chunks {
+                    first,
+                    token,
+                    digests: read_digests(&data[reader.position() as usize..])?,
+                })
+            }
+            21 => {
+                let (first, digests) = data
+                    .split_first_chunk()
+                    .ok_or(MarshallError::UnableToDeserialize)?;
+                Ok(Message::Digests {
+                    first: u32::from_be_bytes(*first),
+                    digests: read_digests(digests)?,
+                })
+            }
+            22 => {
+                let (first, _padding) = data
+                    .split_first_chunk()
+                    .ok_or(MarshallError::UnableToDeserialize)?;
+                Ok(Message::List {
+                    first: u32::from_be_bytes(*first),
+                })
+            }
+            23 => {
+                let mut reader = Cursor::new(data);
+                let len = reader
+                    .read_u16::<byteorder::BigEndian>()
+                    .map_err(|_| MarshallError::UnableToDeserialize)?;
+                let len = Field::Filename.guard(len.into(), remaining(&reader))?;
+                let mut name = vec![0; len];
+                reader
+                    .read_exact(&mut name)
+                    .map_err(|_| MarshallError::UnableToDeserialize)?;
+                // Then a range if the next byte is set, and the padding.
+                let range = match reader.read_u8() {
+                    Ok(0) | Err(_) => None,
+                    Ok(_) => {
+                        let mut bounds = [0; 2];
+                        reader
+                            .read_u64_into::<byteorder::BigEndian>(&mut bounds)
+                            .map_err(|_| MarshallError::UnableToDeserialize)?;
+                        Some(bounds[0]..bounds[1])
+                    }
+                };
+                Ok(Message::Get {
+                    name: utf8(name)?,
+                    range,
+                })
+            }
+            24 => match data.first() {
+                Some(paused) => Ok(Message::Pause {
+                    paused: *paused != 0,
+                }),
+                None => Err(MarshallError::UnableToDeserialize),
+            },
+            25 => {
+                let bytes = Cursor::new(data)
+                    .read_u64::<byteorder::BigEndian>()
+                    .map_err(|_| MarshallError::UnableToDeserialize)?;
+                Ok(Message::SpeedTest { bytes })
+            }
+            26 => {
+                let (below, ranges) = data
+                    .split_first_chunk()
+                    .ok_or(MarshallError::UnableToDeserialize)?;
+                let (ranges, rest) = ranges.as_chunks::<8>();
+                if !rest.is_empty() {
+                    return Err(MarshallError::UnableToDeserialize);
                 }
-                Ok(Message::Loss { ids })
+                Ok(Message::Received {
+                    below: u32::from_be_bytes(*below),
+                    ranges: ranges
+                        .iter()
+                        .map(|range| {
+                            let (bounds, _) = range.as_chunks::<4>();
+                            u32::from_be_bytes(bounds[0])..u32::from_be_bytes(bounds[1])
+                        })
+                        .collect(),
+                })
+            }
+            27 => {
+                let (bounds, ranges) = data
+                    .split_first_chunk::<8>()
+                    .ok_or(MarshallError::UnableToDeserialize)?;
+                let (ranges, rest) = ranges.as_chunks::<8>();
+                if !rest.is_empty() {
+                    return Err(MarshallError::UnableToDeserialize);
+                }
+                let (bounds, _) = bounds.as_chunks::<4>();
+                Ok(Message::Resync {
+                    first: u32::from_be_bytes(bounds[0]),
+                    end: u32::from_be_bytes(bounds[1]),
+                    ranges: ranges
+                        .iter()
+                        .map(|range| {
+                            let (bounds, _) = range.as_chunks::<4>();
+                            u32::from_be_bytes(bounds[0])..u32::from_be_bytes(bounds[1])
+                        })
+                        .collect(),
+                })
             }
             _ => Err(MarshallError::UnableToDeserialize),
         }
@@ -110,14 +855,74 @@ impl Message {
         let mut buf: Vec<u8> = Vec::with_capacity(MTU);
 
         match self {
-            Message::Send { filename, parts } => {
+            Message::Send {
+                filename,
+                parts,
+                group,
+                delta,
+                dedup,
+                verify,
+                token,
+                offset,
+                text,
+                synthetic,
+                size,
+            } => {
+                let flags: u8 = [
+                    (*delta, DELTA),
+                    (*dedup, DEDUP),
+                    (*verify, VERIFY),
+                    (token.is_some(), TOKEN),
+                    (offset.is_some(), OFFSET),
+                    (*text, TEXT),
+                    (*synthetic, SYNTHETIC),
+                    (size.is_some(), SIZE),
+                ]
+                .iter()
+                .filter(|(set, _)| *set)
+                .map(|(_, flag)| flag)
+                .sum();
                 buf.push(0);
                 buf.extend(parts.to_be_bytes());
-                buf.extend(filename.len().to_be_bytes());
+                buf.extend((filename.len() as u32).to_be_bytes());
                 buf.extend(filename.as_bytes());
+                match group {
+                    Some(group) => {
+                        buf.extend((group.name.len() as u32).to_be_bytes());
+                        buf.extend(group.name.as_bytes());
+                        buf.extend(group.index.to_be_bytes());
+                        buf.extend(group.count.to_be_bytes());
+                    }
+                    None if flags != 0 => buf.extend(u32::MAX.to_be_bytes()),
+                    None => {}
+                }
+                if flags != 0 {
+                    buf.push(flags);
+                }
+                if let Some(token) = token {
+                    write_token(&mut buf, token);
+                }
+                if let Some(offset) = offset {
+                    buf.extend(offset.to_be_bytes());
+                }
+                if let Some(size) = size {
+                    buf.extend(size.to_be_bytes());
+                }
             }
-            Message::Accept => {
+            Message::Accept { capabilities } => {
                 buf.push(1);
+                if let Some(capabilities) = capabilities {
+                    buf.extend(capabilities.max_part_size.to_be_bytes());
+                    // All ones stand for no limit, and for an unknown free space.
+                    buf.extend(capabilities.max_in_flight.unwrap_or(u32::MAX).to_be_bytes());
+                    buf.extend(capabilities.free_bytes.unwrap_or(u64::MAX).to_be_bytes());
+                    for names in [&capabilities.compression, &capabilities.encryption] {
+                        let names = names.join(",");
+                        // Saturates for oversized lists, which the guard then refuses.
+                        buf.push(names.len().min(u8::MAX as usize) as u8);
+                        buf.extend(names.as_bytes());
+                    }
+                }
             }
             Message::Part { id, data } => {
                 buf.push(2);
@@ -126,31 +931,428 @@ impl Message {
             }
             Message::Sync { ids } => {
                 buf.push(3);
-                buf.extend(ids.len().to_be_bytes());
+                buf.extend((ids.len() as u32).to_be_bytes());
                 for i in ids {
                     buf.extend(i.to_be_bytes());
                 }
             }
             Message::Ack { ids } => {
                 buf.push(4);
-                buf.extend(ids.len().to_be_bytes());
+                buf.extend((ids.len() as u32).to_be_bytes());
                 for i in ids {
                     buf.extend(i.to_be_bytes());
                 }
             }
             Message::Loss { ids } => {
                 buf.push(5);
-                buf.extend(ids.len().to_be_bytes());
+                buf.extend((ids.len() as u32).to_be_bytes());
+                for i in ids {
+                    buf.extend(i.to_be_bytes());
+                }
+            }
+            Message::RateLimit { bytes_per_sec } => {
+                buf.push(6);
+                buf.extend(bytes_per_sec.to_be_bytes());
+            }
+            Message::HopStats(stats) => {
+                buf.push(7);
+                buf.extend(stats.to_receiver.to_be_bytes());
+                buf.extend(stats.to_sender.to_be_bytes());
+                buf.extend(stats.bytes.to_be_bytes());
+                buf.extend(stats.hop.as_bytes());
+            }
+            Message::Abort { reason } => {
+                buf.push(8);
+                buf.extend(reason.as_bytes());
+            }
+            Message::Join => {
+                buf.push(9);
+            }
+            Message::Probe => {
+                buf.push(10);
+            }
+            Message::Presence {
+                hostname,
+                free_bytes,
+            } => {
+                buf.push(11);
+                buf.extend(free_bytes.unwrap_or(u64::MAX).to_be_bytes());
+                buf.extend(hostname.as_bytes());
+            }
+            Message::Register { code, sender, pake } => {
+                buf.push(12);
+                buf.push(u8::from(*sender));
+                // Saturates for oversized codes, which the guard then refuses.
+                buf.push(code.len().min(u8::MAX as usize) as u8);
+                buf.extend(code.as_bytes());
+                buf.extend(pake);
+            }
+            Message::Introduce { peer, pake } => {
+                buf.push(13);
+                match peer.ip() {
+                    IpAddr::V4(ip) => {
+                        buf.push(4);
+                        buf.extend(ip.octets());
+                    }
+                    IpAddr::V6(ip) => {
+                        buf.push(6);
+                        buf.extend(ip.octets());
+                    }
+                }
+                buf.extend(peer.port().to_be_bytes());
+                buf.extend(pake);
+            }
+            Message::Punch => {
+                buf.push(14);
+            }
+            Message::Relay { code } => {
+                buf.push(15);
+                buf.extend(code.as_bytes());
+            }
+            Message::Manifest {
+                first,
+                total,
+                mirror,
+                token,
+                entries,
+            } => {
+                buf.push(16);
+                buf.extend(first.to_be_bytes());
+                buf.extend(total.to_be_bytes());
+                let mut flags = 0;
+                if let Some(mirror) = mirror {
+                    flags |= MIRROR;
+                    if mirror.checksum {
+                        flags |= CHECKSUM;
+                    }
+                    if mirror.delete {
+                        flags |= DELETE;
+                    }
+                }
+                if token.is_some() {
+                    flags |= TOKEN;
+                }
+                buf.push(flags);
+                if let Some(mirror) = mirror {
+                    write_path(&mut buf, &mirror.dir);
+                }
+                if let Some(token) = token {
+                    write_token(&mut buf, token);
+                }
+                for entry in entries {
+                    write_path(&mut buf, &entry.path);
+                    buf.extend(entry.size.to_be_bytes());
+                    buf.extend(entry.modified.to_be_bytes());
+                    buf.extend(entry.sha256);
+                }
+            }
+            Message::Have { first, ids } => {
+                buf.push(17);
+                buf.extend(first.to_be_bytes());
+                buf.extend((ids.len() as u32).to_be_bytes());
                 for i in ids {
                     buf.extend(i.to_be_bytes());
                 }
             }
+            Message::Signatures {
+                filename,
+                first,
+                token,
+            } => {
+                buf.push(18);
+                buf.extend(first.to_be_bytes());
+                // Saturates for oversized names, which the guard then refuses.
+                buf.extend((filename.len().min(u16::MAX as usize) as u16).to_be_bytes());
+                buf.extend(filename.as_bytes());
+                match token {
+                    Some(token) => {
+                        buf.push(TOKEN);
+                        write_token(&mut buf, token);
+                    }
+                    None => buf.push(0),
+                }
+            }
+            Message::Blocks {
+                first,
+                total,
+                block_size,
+                sums,
+            } => {
+                buf.push(19);
+                buf.extend(first.to_be_bytes());
+                buf.extend(total.to_be_bytes());
+                buf.extend(block_size.to_be_bytes());
+                for sum in sums {
+                    buf.extend(sum.weak.to_be_bytes());
+                    buf.extend(sum.strong);
+                }
+            }
+            Message::Chunks {
+                first,
+                token,
+                digests,
+            } => {
+                buf.push(20);
+                buf.extend(first.to_be_bytes());
+                match token {
+                    Some(token) => {
+                        buf.push(TOKEN);
+                        write_token(&mut buf, token);
+                    }
+                    None => buf.push(0),
+                }
+                for digest in digests {
+                    buf.extend(digest);
+                }
+            }
+            Message::Digests { first, digests } => {
+                buf.push(21);
+                buf.extend(first.to_be_bytes());
+                for digest in digests {
+                    buf.extend(digest);
+                }
+            }
+            Message::List { first } => {
+                buf.push(22);
+                buf.extend(first.to_be_bytes());
+            }
+            Message::Get { name, range } => {
+                buf.push(23);
+                // Saturates for oversized names, which the guard then refuses.
+                buf.extend((name.len().min(u16::MAX as usize) as u16).to_be_bytes());
+                buf.extend(name.as_bytes());
+                if let Some(range) = range {
+                    buf.push(1);
+                    buf.extend(range.start.to_be_bytes());
+                    buf.extend(range.end.to_be_bytes());
+                }
+            }
+            Message::Pause { paused } => {
+                buf.push(24);
+                buf.push(u8::from(*paused));
+            }
+            Message::SpeedTest { bytes } => {
+                buf.push(25);
+                buf.extend(bytes.to_be_bytes());
+            }
+            Message::Received { below, ranges } => {
+                buf.push(26);
+                buf.extend(below.to_be_bytes());
+                for range in ranges {
+                    buf.extend(range.start.to_be_bytes());
+                    buf.extend(range.end.to_be_bytes());
+                }
+            }
+            Message::Resync { first, end, ranges } => {
+                buf.push(27);
+                buf.extend(first.to_be_bytes());
+                buf.extend(end.to_be_bytes());
+                for range in ranges {
+                    buf.extend(range.start.to_be_bytes());
+                    buf.extend(range.end.to_be_bytes());
+                }
+            }
         }
 
         buf
     }
 }
 
+/// Bytes left to read.
+fn remaining(reader: &Cursor<&[u8]>) -> usize {
+    reader
+        .get_ref()
+        .len()
+        .saturating_sub(reader.position() as usize)
+}
+
+/// Reads the digests that end a Chunks or a Digests.
+fn read_digests(data: &[u8]) -> Result<Vec<[u8; 32]>, MarshallError> {
+    let (digests, rest) = data.as_chunks::<32>();
+    if !rest.is_empty() {
+        return Err(MarshallError::UnableToDeserialize);
+    }
+    Ok(digests.to_vec())
+}
+
+/// Reads the id list of a Sync, an Ack or a Loss.
+fn read_ids(data: &[u8]) -> Result<Vec<u32>, MarshallError> {
+    let mut reader = Cursor::new(data);
+    let size = reader
+        .read_u32::<byteorder::BigEndian>()
+        .map_err(|_| MarshallError::UnableToDeserialize)?;
+    let size = Field::Ids.guard(size.into(), remaining(&reader) / 4)?;
+    let mut ids: Vec<u32> = Vec::with_capacity(size);
+    for _ in 0..size {
+        let id = reader
+            .read_u32::<byteorder::BigEndian>()
+            .map_err(|_| MarshallError::UnableToDeserialize)?;
+        ids.push(id);
+    }
+    Ok(ids)
+}
+
+fn read_addr(reader: &mut Cursor<&[u8]>) -> Result<SocketAddr, MarshallError> {
+    let family = reader
+        .read_u8()
+        .map_err(|_| MarshallError::UnableToDeserialize)?;
+    let ip = match family {
+        4 => {
+            let mut octets = [0; 4];
+            reader
+                .read_exact(&mut octets)
+                .map_err(|_| MarshallError::UnableToDeserialize)?;
+            IpAddr::from(octets)
+        }
+        6 => {
+            let mut octets = [0; 16];
+            reader
+                .read_exact(&mut octets)
+                .map_err(|_| MarshallError::UnableToDeserialize)?;
+            IpAddr::from(octets)
+        }
+        _ => return Err(MarshallError::UnableToDeserialize),
+    };
+    let port = reader
+        .read_u16::<byteorder::BigEndian>()
+        .map_err(|_| MarshallError::UnableToDeserialize)?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Key exchange message that ends a Register or an Introduce.
+fn read_pake(reader: &mut Cursor<&[u8]>) -> Result<Vec<u8>, MarshallError> {
+    Field::Pake.guard(remaining(reader) as u64, usize::MAX)?;
+    let mut pake = Vec::new();
+    reader
+        .read_to_end(&mut pake)
+        .map_err(|_| MarshallError::UnableToDeserialize)?;
+    Ok(pake)
+}
+
+fn read_capabilities(reader: &mut Cursor<&[u8]>) -> Result<Capabilities, MarshallError> {
+    let max_part_size = reader
+        .read_u32::<byteorder::BigEndian>()
+        .map_err(|_| MarshallError::UnableToDeserialize)?;
+    let max_in_flight = reader
+        .read_u32::<byteorder::BigEndian>()
+        .map_err(|_| MarshallError::UnableToDeserialize)?;
+    let free_bytes = reader
+        .read_u64::<byteorder::BigEndian>()
+        .map_err(|_| MarshallError::UnableToDeserialize)?;
+    Ok(Capabilities {
+        max_part_size,
+        max_in_flight: Some(max_in_flight).filter(|&max| max != u32::MAX),
+        compression: read_algorithms(reader)?,
+        encryption: read_algorithms(reader)?,
+        free_bytes: Some(free_bytes).filter(|&free| free != u64::MAX),
+    })
+}
+
+/// Reads a comma separated list of algorithm names.
+fn read_algorithms(reader: &mut Cursor<&[u8]>) -> Result<Vec<String>, MarshallError> {
+    let len = reader
+        .read_u8()
+        .map_err(|_| MarshallError::UnableToDeserialize)?;
+    let len = Field::Algorithms.guard(len.into(), remaining(reader))?;
+    let mut names = vec![0; len];
+    reader
+        .read_exact(&mut names)
+        .map_err(|_| MarshallError::UnableToDeserialize)?;
+    Ok(utf8(names)?
+        .split(',')
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Writes a path of a Manifest, after its length.
+fn write_path(buf: &mut Vec<u8>, path: &str) {
+    // Saturates for oversized paths, which the guard then refuses.
+    buf.extend((path.len().min(u16::MAX as usize) as u16).to_be_bytes());
+    buf.extend(path.as_bytes());
+}
+
+fn read_path(reader: &mut Cursor<&[u8]>) -> Result<String, MarshallError> {
+    let len = reader
+        .read_u16::<byteorder::BigEndian>()
+        .map_err(|_| MarshallError::UnableToDeserialize)?;
+    let len = Field::Filename.guard(len.into(), remaining(reader))?;
+    let mut path = vec![0; len];
+    reader
+        .read_exact(&mut path)
+        .map_err(|_| MarshallError::UnableToDeserialize)?;
+    utf8(path)
+}
+
+/// `bytes` as text. Bytes that are not UTF-8 are refused rather than replaced, which
+/// would make the text longer than the length it was checked against.
+fn utf8(bytes: Vec<u8>) -> Result<String, MarshallError> {
+    String::from_utf8(bytes).map_err(|_| MarshallError::UnableToDeserialize)
+}
+
+fn write_token(buf: &mut Vec<u8>, token: &str) {
+    // Saturates for oversized tokens, which the guard then refuses.
+    buf.push(token.len().min(u8::MAX as usize) as u8);
+    buf.extend(token.as_bytes());
+}
+
+fn read_token(reader: &mut Cursor<&[u8]>) -> Result<String, MarshallError> {
+    let len = reader
+        .read_u8()
+        .map_err(|_| MarshallError::UnableToDeserialize)?;
+    let len = Field::Token.guard(len.into(), remaining(reader))?;
+    let mut token = vec![0; len];
+    reader
+        .read_exact(&mut token)
+        .map_err(|_| MarshallError::UnableToDeserialize)?;
+    utf8(token)
+}
+
+fn read_manifest_entry(reader: &mut Cursor<&[u8]>) -> Result<ManifestEntry, MarshallError> {
+    let path = read_path(reader)?;
+    let size = reader
+        .read_u64::<byteorder::BigEndian>()
+        .map_err(|_| MarshallError::UnableToDeserialize)?;
+    let modified = reader
+        .read_u64::<byteorder::BigEndian>()
+        .map_err(|_| MarshallError::UnableToDeserialize)?;
+    let mut sha256 = [0; 32];
+    reader
+        .read_exact(&mut sha256)
+        .map_err(|_| MarshallError::UnableToDeserialize)?;
+    Ok(ManifestEntry {
+        path,
+        size,
+        modified,
+        sha256,
+    })
+}
+
+fn read_group_member(reader: &mut Cursor<&[u8]>) -> Result<GroupMember, MarshallError> {
+    let name_size = reader
+        .read_u32::<byteorder::BigEndian>()
+        .map_err(|_| MarshallError::UnableToDeserialize)?;
+    let name_size = Field::GroupName.guard(name_size.into(), remaining(reader))?;
+    let mut name_bytes: Vec<u8> = vec![0; name_size];
+    reader
+        .read_exact(&mut name_bytes)
+        .map_err(|_| MarshallError::UnableToDeserialize)?;
+    let index = reader
+        .read_u32::<byteorder::BigEndian>()
+        .map_err(|_| MarshallError::UnableToDeserialize)?;
+    let count = reader
+        .read_u32::<byteorder::BigEndian>()
+        .map_err(|_| MarshallError::UnableToDeserialize)?;
+    if index >= count {
+        return Err(MarshallError::UnableToDeserialize);
+    }
+    Ok(GroupMember {
+        name: utf8(name_bytes)?,
+        index,
+        count,
+    })
+}
+
 #[derive(Debug, Error)]
 pub enum BufferError {
     #[error("unknown")]
@@ -162,3 +1364,684 @@ pub enum BufferError {
     #[error("Reached EOF")]
     EOF,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{server::make_parts_packet, PART_SIZE};
+    use proptest::{collection::vec, option, prelude::*, strategy::LazyJust};
+
+    fn with_len(kind: u8, len: u32, rest: &[u8]) -> Vec<u8> {
+        let mut packet = vec![kind];
+        packet.extend(len.to_be_bytes());
+        packet.extend(rest);
+        packet
+    }
+
+    fn assert_oversized(packet: &[u8], expected: Field) {
+        let before = expected.rejected();
+        match Message::parse(packet) {
+            Err(MarshallError::Oversized { field, .. }) => assert_eq!(field, expected),
+            other => panic!("expected an oversized {expected:?}, got {other:?}"),
+        }
+        assert!(expected.rejected() > before);
+    }
+
+    #[test]
+    fn lying_lengths_are_rejected() {
+        for len in [u32::MAX, 1 << 31, 1 << 20, 2] {
+            for kind in [3, 4, 5] {
+                assert_oversized(&with_len(kind, len, &[0; 4]), Field::Ids);
+            }
+            let mut send = vec![0];
+            send.extend(1u32.to_be_bytes());
+            send.extend(len.to_be_bytes());
+            send.push(b'a');
+            assert_oversized(&send, Field::Filename);
+        }
+    }
+
+    #[test]
+    fn text_that_is_not_utf8_is_rejected() {
+        let send = |filename: &str, token: Option<&str>| Message::Send {
+            filename: filename.to_string(),
+            parts: 1,
+            group: None,
+            delta: false,
+            dedup: false,
+            verify: false,
+            token: token.map(str::to_string),
+            offset: None,
+            text: false,
+            synthetic: false,
+            size: None,
+        };
+        for message in [
+            send("é", None),
+            send("f", Some("é")),
+            Message::Abort {
+                reason: "é".to_string(),
+            },
+            Message::Register {
+                code: "é".to_string(),
+                sender: true,
+                pake: vec![1; 32],
+            },
+            Message::Relay {
+                code: "é".to_string(),
+            },
+            Message::Signatures {
+                filename: "é".to_string(),
+                first: 0,
+                token: None,
+            },
+            Message::Get {
+                name: "é".to_string(),
+                range: None,
+            },
+        ] {
+            let mut packet = message.serialize();
+            assert!(Message::parse(&packet).is_ok(), "{message:?}");
+            // The two bytes of the é, now bytes that never appear in UTF-8.
+            let at = packet
+                .windows(2)
+                .position(|pair| pair == [0xc3, 0xa9])
+                .unwrap();
+            packet[at..at + 2].copy_from_slice(&[0xff, 0xfe]);
+            assert!(
+                matches!(
+                    Message::parse(&packet),
+                    Err(MarshallError::UnableToDeserialize)
+                ),
+                "{message:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn lengths_over_the_maximum_are_rejected() {
+        let ids = Field::Ids.max() as u32 + 1;
+        assert_oversized(&with_len(4, ids, &vec![0; 4 * ids as usize]), Field::Ids);
+
+        let mut hop = vec![7];
+        hop.extend([0; 24]);
+        hop.extend(vec![b'h'; Field::HopName.max() + 1]);
+        assert_oversized(&hop, Field::HopName);
+
+        let mut abort = vec![8];
+        abort.extend(vec![b'r'; Field::AbortReason.max() + 1]);
+        assert_oversized(&abort, Field::AbortReason);
+
+        let presence = Message::Presence {
+            hostname: "h".repeat(Field::Hostname.max() + 1),
+            free_bytes: None,
+        };
+        assert_oversized(&presence.serialize(), Field::Hostname);
+
+        let register = Message::Register {
+            code: "c".repeat(Field::Code.max() + 1),
+            sender: true,
+            pake: vec![1; 256],
+        };
+        assert_oversized(&register.serialize(), Field::Code);
+
+        let register = Message::Register {
+            code: "c".to_string(),
+            sender: true,
+            pake: vec![1; Field::Pake.max() + 1],
+        };
+        assert_oversized(&register.serialize(), Field::Pake);
+
+        let relay = Message::Relay {
+            code: "c".repeat(Field::Code.max() + 1),
+        };
+        assert_oversized(&relay.serialize(), Field::Code);
+
+        let accept = Message::Accept {
+            capabilities: Some(Capabilities {
+                max_part_size: PART_SIZE as u32,
+                max_in_flight: Some(64),
+                compression: vec!["c".repeat(Field::Algorithms.max() + 1)],
+                encryption: Vec::new(),
+                free_bytes: None,
+            }),
+        };
+        assert_oversized(&accept.serialize(), Field::Algorithms);
+
+        let manifest = Message::Manifest {
+            first: 0,
+            total: 1,
+            mirror: None,
+            token: None,
+            entries: vec![ManifestEntry {
+                path: "p".repeat(Field::Filename.max() + 1),
+                size: 1,
+                modified: 0,
+                sha256: [0; 32],
+            }],
+        };
+        assert_oversized(&manifest.serialize(), Field::Filename);
+
+        let manifest = Message::Manifest {
+            first: 0,
+            total: 0,
+            mirror: Some(Mirror {
+                dir: "d".repeat(Field::Filename.max() + 1),
+                checksum: false,
+                delete: true,
+            }),
+            token: None,
+            entries: Vec::new(),
+        };
+        assert_oversized(&manifest.serialize(), Field::Filename);
+
+        let manifest = Message::Manifest {
+            first: 0,
+            total: 0,
+            mirror: None,
+            token: Some("t".repeat(Field::Token.max() + 1)),
+            entries: Vec::new(),
+        };
+        assert_oversized(&manifest.serialize(), Field::Token);
+
+        let send = Message::Send {
+            filename: "f".repeat(Field::Filename.max() + 1),
+            parts: 1,
+            group: None,
+            delta: false,
+            dedup: false,
+            verify: false,
+            token: None,
+            offset: None,
+            text: false,
+            synthetic: false,
+            size: None,
+        };
+        assert_oversized(&send.serialize(), Field::Filename);
+
+        let send = Message::Send {
+            filename: "f".to_string(),
+            parts: 1,
+            group: Some(GroupMember {
+                name: "g".repeat(Field::GroupName.max() + 1),
+                index: 0,
+                count: 1,
+            }),
+            delta: false,
+            dedup: false,
+            verify: false,
+            token: None,
+            offset: None,
+            text: false,
+            synthetic: false,
+            size: None,
+        };
+        assert_oversized(&send.serialize(), Field::GroupName);
+
+        let send = Message::Send {
+            filename: "f".to_string(),
+            parts: 1,
+            group: None,
+            delta: false,
+            dedup: false,
+            verify: false,
+            token: Some("t".repeat(Field::Token.max() + 1)),
+            offset: None,
+            text: false,
+            synthetic: false,
+            size: None,
+        };
+        assert_oversized(&send.serialize(), Field::Token);
+
+        let signatures = Message::Signatures {
+            filename: "f".repeat(Field::Filename.max() + 1),
+            first: 0,
+            token: None,
+        };
+        assert_oversized(&signatures.serialize(), Field::Filename);
+
+        let signatures = Message::Signatures {
+            filename: "f".to_string(),
+            first: 0,
+            token: Some("t".repeat(Field::Token.max() + 1)),
+        };
+        assert_oversized(&signatures.serialize(), Field::Token);
+
+        let get = Message::Get {
+            name: "n".repeat(Field::Filename.max() + 1),
+            range: None,
+        };
+        assert_oversized(&get.serialize(), Field::Filename);
+    }
+
+    #[test]
+    fn part_counts_over_what_a_send_makes_are_rejected() {
+        let send = |parts, text, synthetic, size| Message::Send {
+            filename: "f".to_string(),
+            parts,
+            group: None,
+            delta: false,
+            dedup: false,
+            verify: false,
+            token: None,
+            offset: None,
+            text,
+            synthetic,
+            size,
+        };
+        let text_parts = MAX_TEXT.div_ceil(PART_SIZE) as u32;
+        let synthetic_parts = speedtest::MAX_ROUND.div_ceil(PART_SIZE as u64) as u32;
+        for parts in [u32::MAX, 1 << 31, synthetic_parts + 1] {
+            assert_oversized(&send(parts, true, false, None).serialize(), Field::Parts);
+            assert_oversized(&send(parts, false, true, None).serialize(), Field::Parts);
+            assert_oversized(
+                &send(parts, false, false, Some(10)).serialize(),
+                Field::Parts,
+            );
+        }
+        assert_oversized(
+            &send(text_parts + 1, true, false, None).serialize(),
+            Field::Parts,
+        );
+        assert_oversized(&send(2, false, false, Some(0)).serialize(), Field::Parts);
+        let size = Some(3 * PART_SIZE as u64 + 1);
+        assert_oversized(&send(5, false, false, size).serialize(), Field::Parts);
+
+        for message in [
+            send(text_parts, true, false, None),
+            send(synthetic_parts, false, true, None),
+            send(u32::MAX, false, false, None),
+            send(4, false, false, size),
+            send(0, false, false, Some(0)),
+        ] {
+            assert_eq!(Message::parse(&message.serialize()).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn lengths_at_the_maximum_round_trip() {
+        let messages = [
+            Message::Ack {
+                ids: (0..Field::Ids.max() as u32).collect(),
+            },
+            Message::Send {
+                filename: "f".repeat(Field::Filename.max()),
+                parts: 3,
+                group: Some(GroupMember {
+                    name: "g".repeat(Field::GroupName.max() - 200),
+                    index: 1,
+                    count: 2,
+                }),
+                delta: true,
+                dedup: true,
+                verify: true,
+                token: Some("t".repeat(Field::Token.max())),
+                offset: Some(u64::MAX),
+                text: true,
+                synthetic: false,
+                size: Some(u64::MAX),
+            },
+            Message::Chunks {
+                first: 3,
+                token: Some("t".repeat(Field::Token.max())),
+                digests: vec![[7; 32]; MAX_CHUNK_DIGESTS],
+            },
+            Message::Digests {
+                first: 5,
+                digests: vec![[9; 32]; MAX_DIGESTS],
+            },
+            Message::Blocks {
+                first: 7,
+                total: 1000,
+                block_size: 4096,
+                sums: vec![
+                    BlockSum {
+                        weak: 1,
+                        strong: [2; 16],
+                    };
+                    MAX_BLOCK_SUMS
+                ],
+            },
+            Message::HopStats(HopStats {
+                hop: "h".repeat(Field::HopName.max()),
+                to_receiver: 1,
+                to_sender: 2,
+                bytes: 3,
+            }),
+            Message::Abort {
+                reason: "r".repeat(Field::AbortReason.max()),
+            },
+            Message::Presence {
+                hostname: "h".repeat(Field::Hostname.max()),
+                free_bytes: Some(42),
+            },
+            Message::Presence {
+                hostname: "h".to_string(),
+                free_bytes: None,
+            },
+            Message::Register {
+                code: "c".repeat(Field::Code.max()),
+                sender: false,
+                pake: vec![1; Field::Pake.max()],
+            },
+            Message::Relay {
+                code: "c".repeat(Field::Code.max()),
+            },
+            Message::Accept {
+                capabilities: Some(Capabilities {
+                    max_part_size: PART_SIZE as u32,
+                    max_in_flight: None,
+                    compression: Vec::new(),
+                    encryption: vec!["e".repeat(Field::Algorithms.max())],
+                    free_bytes: Some(42),
+                }),
+            },
+        ];
+        for message in messages {
+            let packet = message.serialize();
+            assert!(packet.len() <= MTU);
+            assert_eq!(Message::parse(&packet).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn requests_ignore_their_padding() {
+        for message in [
+            Message::List { first: 7 },
+            Message::Get {
+                name: "dir/file".to_string(),
+                range: None,
+            },
+            Message::Get {
+                name: "dir/file".to_string(),
+                range: Some(1000..2000),
+            },
+        ] {
+            let mut packet = message.serialize();
+            packet.resize(MTU, 0);
+            assert_eq!(Message::parse(&packet).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn introductions_round_trip() {
+        for peer in ["203.0.113.7:40123", "[2001:db8::1]:6666"] {
+            let message = Message::Introduce {
+                peer: peer.parse().unwrap(),
+                pake: vec![1; Field::Pake.max()],
+            };
+            assert_eq!(Message::parse(&message.serialize()).unwrap(), message);
+        }
+        assert!(Message::parse(&[13, 5, 0, 0]).is_err());
+        assert!(Message::parse(&[13, 4, 1, 2, 3, 4]).is_err());
+    }
+
+    /// Text of at most `max` bytes, either printable ASCII or any characters.
+    fn text(max: usize) -> impl Strategy<Value = String> {
+        prop_oneof![
+            proptest::string::string_regex(&format!("[ -~]{{0,{max}}}")).unwrap(),
+            vec(any::<char>(), 0..=max / 4).prop_map(String::from_iter),
+        ]
+    }
+
+    fn ids() -> impl Strategy<Value = Vec<u32>> {
+        vec(any::<u32>(), 0..=Field::Ids.max())
+    }
+
+    fn group_member() -> impl Strategy<Value = GroupMember> {
+        (text(Field::GroupName.max()), 1..=u32::MAX)
+            .prop_flat_map(|(name, count)| (Just(name), 0..count, Just(count)))
+            .prop_map(|(name, index, count)| GroupMember { name, index, count })
+    }
+
+    fn pake() -> impl Strategy<Value = Vec<u8>> {
+        vec(any::<u8>(), 0..=Field::Pake.max())
+    }
+
+    fn manifest_entry() -> impl Strategy<Value = ManifestEntry> {
+        (text(256), any::<[u64; 2]>(), any::<[u8; 32]>()).prop_map(
+            |(path, [size, modified], sha256)| ManifestEntry {
+                path,
+                size,
+                modified,
+                sha256,
+            },
+        )
+    }
+
+    fn mirror() -> impl Strategy<Value = Mirror> {
+        (text(256), any::<bool>(), any::<bool>()).prop_map(|(dir, checksum, delete)| Mirror {
+            dir,
+            checksum,
+            delete,
+        })
+    }
+
+    fn block_sum() -> impl Strategy<Value = BlockSum> {
+        (any::<u32>(), any::<[u8; 16]>()).prop_map(|(weak, strong)| BlockSum { weak, strong })
+    }
+
+    fn algorithms() -> impl Strategy<Value = Vec<String>> {
+        vec("[a-z0-9-]{1,24}", 0..=4)
+    }
+
+    fn capabilities() -> impl Strategy<Value = Capabilities> {
+        // All ones stand for no limit, and for an unknown free space.
+        (
+            any::<u32>(),
+            option::of(0..u32::MAX),
+            algorithms(),
+            algorithms(),
+            option::of(0..u64::MAX),
+        )
+            .prop_map(
+                |(max_part_size, max_in_flight, compression, encryption, free_bytes)| {
+                    Capabilities {
+                        max_part_size,
+                        max_in_flight,
+                        compression,
+                        encryption,
+                        free_bytes,
+                    }
+                },
+            )
+    }
+
+    fn message() -> impl Strategy<Value = Message> {
+        prop_oneof![
+            (
+                text(Field::Filename.max()),
+                any::<u32>(),
+                option::of(group_member()),
+                any::<bool>(),
+                any::<bool>(),
+                any::<bool>(),
+                option::of(text(Field::Token.max())),
+                option::of(any::<u64>()),
+                any::<bool>(),
+                any::<bool>(),
+                option::of(0..PART_SIZE as u64)
+            )
+                .prop_map(
+                    |(
+                        filename,
+                        parts,
+                        group,
+                        delta,
+                        dedup,
+                        verify,
+                        token,
+                        offset,
+                        text,
+                        synthetic,
+                        short,
+                    )| {
+                        // As many parts as the kind of transfer takes, the last one maybe
+                        // `short` of a whole part.
+                        let parts = match Field::max_parts(text, synthetic) {
+                            max if max >= u32::MAX as usize => parts,
+                            max => parts % (max as u32 + 1),
+                        };
+                        let size = short.map(|short| {
+                            (u64::from(parts) * PART_SIZE as u64).saturating_sub(short)
+                        });
+                        Message::Send {
+                            filename,
+                            parts,
+                            group,
+                            delta,
+                            dedup,
+                            verify,
+                            token,
+                            offset,
+                            text,
+                            synthetic,
+                            size,
+                        }
+                    }
+                ),
+            option::of(capabilities()).prop_map(|capabilities| Message::Accept { capabilities }),
+            (any::<u32>(), vec(any::<u8>(), 0..=PART_SIZE))
+                .prop_map(|(id, data)| Message::Part { id, data }),
+            ids().prop_map(|ids| Message::Sync { ids }),
+            ids().prop_map(|ids| Message::Ack { ids }),
+            ids().prop_map(|ids| Message::Loss { ids }),
+            any::<u64>().prop_map(|bytes_per_sec| Message::RateLimit { bytes_per_sec }),
+            (text(Field::HopName.max()), any::<[u64; 3]>()).prop_map(
+                |(hop, [to_receiver, to_sender, bytes])| Message::HopStats(HopStats {
+                    hop,
+                    to_receiver,
+                    to_sender,
+                    bytes
+                })
+            ),
+            text(Field::AbortReason.max()).prop_map(|reason| Message::Abort { reason }),
+            LazyJust::new(|| Message::Join),
+            LazyJust::new(|| Message::Probe),
+            // All ones stands for an unknown free space.
+            (text(Field::Hostname.max()), option::of(0..u64::MAX)).prop_map(
+                |(hostname, free_bytes)| Message::Presence {
+                    hostname,
+                    free_bytes
+                }
+            ),
+            (text(Field::Code.max()), any::<bool>(), pake())
+                .prop_map(|(code, sender, pake)| Message::Register { code, sender, pake }),
+            (any::<IpAddr>(), any::<u16>(), pake()).prop_map(|(ip, port, pake)| {
+                Message::Introduce {
+                    peer: SocketAddr::new(ip, port),
+                    pake,
+                }
+            }),
+            LazyJust::new(|| Message::Punch),
+            text(Field::Code.max()).prop_map(|code| Message::Relay { code }),
+            (
+                any::<u32>(),
+                any::<u32>(),
+                option::of(mirror()),
+                option::of(text(Field::Token.max())),
+                vec(manifest_entry(), 0..=3)
+            )
+                .prop_map(|(first, total, mirror, token, entries)| Message::Manifest {
+                    first,
+                    total,
+                    mirror,
+                    token,
+                    entries
+                }),
+            (any::<u32>(), vec(any::<u32>(), 0..=64))
+                .prop_map(|(first, ids)| Message::Have { first, ids }),
+            (
+                text(Field::Filename.max()),
+                any::<u32>(),
+                option::of(text(Field::Token.max()))
+            )
+                .prop_map(|(filename, first, token)| Message::Signatures {
+                    filename,
+                    first,
+                    token
+                }),
+            (any::<[u32; 3]>(), vec(block_sum(), 0..=MAX_BLOCK_SUMS)).prop_map(
+                |([first, total, block_size], sums)| Message::Blocks {
+                    first,
+                    total,
+                    block_size,
+                    sums
+                }
+            ),
+            (
+                any::<u32>(),
+                option::of(text(Field::Token.max())),
+                vec(any::<[u8; 32]>(), 0..=MAX_CHUNK_DIGESTS)
+            )
+                .prop_map(|(first, token, digests)| Message::Chunks {
+                    first,
+                    token,
+                    digests
+                }),
+            (any::<u32>(), vec(any::<[u8; 32]>(), 0..=MAX_DIGESTS))
+                .prop_map(|(first, digests)| Message::Digests { first, digests }),
+            any::<u32>().prop_map(|first| Message::List { first }),
+            (text(Field::Filename.max()), option::of(any::<(u64, u64)>())).prop_map(
+                |(name, range)| Message::Get {
+                    name,
+                    range: range.map(|(start, end)| start..end),
+                }
+            ),
+            any::<bool>().prop_map(|paused| Message::Pause { paused }),
+            any::<u64>().prop_map(|bytes| Message::SpeedTest { bytes }),
+            (
+                any::<u32>(),
+                vec(any::<(u32, u32)>(), 0..=MAX_RECEIVED_RANGES)
+            )
+                .prop_map(|(below, ranges)| Message::Received {
+                    below,
+                    ranges: ranges.into_iter().map(|(start, end)| start..end).collect(),
+                }),
+            (
+                any::<(u32, u32)>(),
+                vec(any::<(u32, u32)>(), 0..=MAX_RESYNC_RANGES)
+            )
+                .prop_map(|((first, end), ranges)| Message::Resync {
+                    first,
+                    end,
+                    ranges: ranges.into_iter().map(|(start, end)| start..end).collect(),
+                }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn messages_round_trip(message in message()) {
+            let packet = message.serialize();
+            prop_assert!(packet.len() <= MTU, "{} bytes", packet.len());
+            prop_assert_eq!(Message::parse(&packet).unwrap(), message);
+        }
+
+        #[test]
+        fn parts_packets_fit_the_mtu(
+            id in any::<u32>(),
+            data in vec(any::<u8>(), 0..=PART_SIZE + 10),
+            recycled in vec(any::<u8>(), 0..=MTU),
+        ) {
+            // Fresh buffers are empty, recycled ones hold a previous packet.
+            for mut packet in [Vec::with_capacity(MTU), recycled.clone()] {
+                let made = make_parts_packet(&data, id, &mut packet);
+                if data.len() > PART_SIZE {
+                    prop_assert!(matches!(made, Err(BufferError::DoesNotFit)));
+                    continue;
+                }
+                prop_assert!(made.is_ok());
+                prop_assert!(packet.len() <= MTU);
+                let part = Message::Part { id, data: data.clone() };
+                prop_assert_eq!(&packet, &part.serialize());
+                prop_assert_eq!(Message::parse(&packet).unwrap(), part);
+            }
+        }
+
+        #[test]
+        fn any_bytes_parse_without_panicking(packet in vec(any::<u8>(), 0..=MTU)) {
+            let _ = Message::parse(&packet);
+        }
+    }
+}
@@ -0,0 +1,600 @@
+//! Pull mode: a host serves the files of a directory, and receivers list them and get the
+//! ones they want with a [`Receiver`](crate::Receiver). The file asked for is pushed like
+//! a sender would, from the port the Get went to, so that it gets through the NAT of
+//! whoever asked.
+//!
+//! Several downloads can run at once, each receiver on a lane of its own. Every slice, the
+//! rate cap is split again between them, interactive downloads getting more of it than
+//! bulk ones, so that a small file goes through quickly whatever huge ones are running.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    ops::Range,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use tracing::{debug, info, warn};
+
+use crate::{
+    batch,
+    client::RateCap,
+    crypto,
+    digest::file_digest,
+    protocol::{ManifestEntry, Message},
+    queue::shares,
+    server::{SendError, Sender},
+    sessions::{Lane, Scheduler},
+    socket::Socket,
+    transport::{bind_udp, Transport},
+    MTU,
+};
+
+/// How long a Get for the file we just sent is taken for a late copy of the one we served.
+const REPEATED: Duration = Duration::from_secs(2);
+/// How often the rate cap is split again between the downloads running.
+const SLICE: Duration = Duration::from_millis(100);
+
+/// How much of the rate cap interactive and bulk downloads get in each slice, against one
+/// another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Slices {
+    pub interactive: u32,
+    pub bulk: u32,
+}
+
+impl Default for Slices {
+    fn default() -> Self {
+        Slices {
+            interactive: 8,
+            bulk: 1,
+        }
+    }
+}
+
+/// Takes `<interactive>:<bulk>`.
+impl FromStr for Slices {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let weight = |weight: &str| {
+            weight
+                .parse()
+                .ok()
+                .filter(|weight| *weight > 0)
+                .ok_or_else(|| format!("{s} does not have positive weights"))
+        };
+        let (interactive, bulk) = s
+            .split_once(':')
+            .ok_or_else(|| format!("{s} is not two weights like 8:1"))?;
+        Ok(Slices {
+            interactive: weight(interactive)?,
+            bulk: weight(bulk)?,
+        })
+    }
+}
+
+/// Directory a host serves the files of.
+///
+/// ```no_run
+/// sanic::pull::Library::new("/srv/files").serve()?;
+/// # Ok::<(), sanic::SendError>(())
+/// ```
+pub struct Library {
+    dir: PathBuf,
+    bind: String,
+    transport: Option<Arc<dyn Transport>>,
+    connect_timeout: Duration,
+    downloads: usize,
+    cap: RateCap,
+    bulk_above: u64,
+    slices: Slices,
+    digests: Mutex<HashMap<PathBuf, Hashed>>,
+    /// Caps of the downloads running on lanes, with their weight, by the order they
+    /// started in.
+    running: Mutex<BTreeMap<u64, (u32, RateCap)>>,
+}
+
+/// SHA-256 of a file, with the size and modification time it was taken at.
+struct Hashed {
+    len: u64,
+    modified: SystemTime,
+    sha256: [u8; 32],
+}
+
+impl Library {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Library {
+            dir: dir.into(),
+            bind: "0.0.0.0:6666".to_string(),
+            transport: None,
+            connect_timeout: Duration::from_secs(30),
+            downloads: 1,
+            cap: RateCap::default(),
+            bulk_above: 64 * 1024 * 1024,
+            slices: Slices::default(),
+            digests: Mutex::new(HashMap::new()),
+            running: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn bind(mut self, addr: impl Into<String>) -> Self {
+        self.bind = addr.into();
+        self
+    }
+
+    /// Serve over `transport` instead of a UDP socket.
+    pub fn transport(mut self, transport: impl Transport + 'static) -> Self {
+        self.transport = Some(Arc::new(transport));
+        self
+    }
+
+    /// Give up on a receiver that has not accepted the file it asked for after this long.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Run up to `downloads` downloads at once, for as many receivers, 1 by default. The
+    /// others are told to try again later.
+    pub fn downloads(mut self, downloads: usize) -> Self {
+        self.downloads = downloads.max(1);
+        self
+    }
+
+    /// Stay under `cap` in all, which can change while downloads run.
+    pub fn rate_cap(mut self, cap: RateCap) -> Self {
+        self.cap = cap;
+        self
+    }
+
+    /// Downloads of more than `bytes` are bulk ones, 64 MiB by default.
+    pub fn bulk_above(mut self, bytes: u64) -> Self {
+        self.bulk_above = bytes;
+        self
+    }
+
+    /// Split the rate cap between interactive and bulk downloads by `slices`, 8 to 1 by
+    /// default.
+    pub fn slices(mut self, slices: Slices) -> Self {
+        self.slices = slices;
+        self
+    }
+
+    /// Answers the receivers listing the files and getting them, until the socket fails.
+    pub fn serve(&self) -> Result<(), SendError> {
+        let transport = match &self.transport {
+            Some(transport) => transport.clone(),
+            None => bind_udp(&self.bind)?,
+        };
+        let socket = Socket::new(transport.clone(), None, crypto::SPACE_SENDER)?;
+        info!(
+            addr = %socket.local_addr()?,
+            dir = %self.dir.display(),
+            downloads = self.downloads,
+            "Serving."
+        );
+        if self.downloads <= 1 {
+            return self.answer(&socket, &transport, None);
+        }
+        let (scheduler, lanes, overflow) =
+            Scheduler::new(transport, self.downloads, Vec::new(), RateCap::default());
+        let failure = Mutex::new(None);
+        let dispatched = std::thread::scope(|scope| {
+            scope.spawn(|| {
+                while !scheduler.is_stopped() {
+                    self.slice();
+                    std::thread::sleep(SLICE);
+                }
+            });
+            let overflow = socket.with_transport(overflow);
+            scope.spawn(move || self.turn_away(&overflow));
+            for lane in lanes {
+                let socket = socket.with_transport(lane.clone());
+                let (scheduler, failure) = (&scheduler, &failure);
+                scope.spawn(move || {
+                    let transport: Arc<dyn Transport> = lane.clone();
+                    if let Err(err) = self.answer(&socket, &transport, Some(&lane)) {
+                        // The first failure takes the others down.
+                        failure
+                            .lock()
+                            .expect("Could not lock failure")
+                            .get_or_insert(err);
+                        scheduler.stop();
+                    }
+                });
+            }
+            scheduler.dispatch()
+        });
+        dispatched?;
+        match failure.into_inner().expect("Could not lock failure") {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Answers the lists and gets reaching `socket`, one download at a time, until it
+    /// fails.
+    fn answer(
+        &self,
+        socket: &Socket,
+        transport: &Arc<dyn Transport>,
+        lane: Option<&Lane>,
+    ) -> Result<(), SendError> {
+        let mut buf = vec![0; MTU];
+        let mut served: Option<(SocketAddr, Message, Instant)> = None;
+        loop {
+            // Transfers leave their own timeout behind.
+            socket.set_read_timeout(None)?;
+            let (size, peer) = socket.recv_from(&mut buf)?;
+            match Message::parse(&buf[..size]) {
+                Ok(Message::List { first }) => self.answer_list(socket, peer, size, first),
+                Ok(Message::Get { .. }) if size < MTU => {
+                    debug!(%peer, "Ignoring undersized get.");
+                }
+                Ok(get @ Message::Get { .. }) => {
+                    if served.as_ref().is_some_and(|(to, request, at)| {
+                        *to == peer && *request == get && at.elapsed() < REPEATED
+                    }) {
+                        continue;
+                    }
+                    if let Message::Get { name, range } = &get {
+                        if let Some(lane) = lane {
+                            lane.start(peer.ip());
+                        }
+                        self.answer_get(socket, transport, lane, peer, name, range.clone());
+                        if let Some(lane) = lane {
+                            lane.end();
+                        }
+                    }
+                    served = Some((peer, get, Instant::now()));
+                }
+                Ok(msg) => debug!(%peer, message = ?msg, "Ignoring message."),
+                Err(err) => debug!(%peer, error = ?err, "Could not parse packet."),
+            }
+        }
+    }
+
+    /// Answers the receivers no lane is free for, listing the files but refusing to send
+    /// them, until the lanes stop.
+    fn turn_away(&self, socket: &Socket) {
+        let mut buf = vec![0; MTU];
+        loop {
+            match socket.recv_from(&mut buf) {
+                Ok((size, peer)) => match Message::parse(&buf[..size]) {
+                    Ok(Message::List { first }) => self.answer_list(socket, peer, size, first),
+                    Ok(Message::Get { .. }) if size == MTU => {
+                        let reason = "busy with other downloads, try again later".to_string();
+                        refuse(socket, peer, reason);
+                    }
+                    _ => {}
+                },
+                Err(err) if err.kind() == ErrorKind::NotConnected => return,
+                Err(err) => {
+                    warn!(error = ?err, "Could not receive from the receivers turned away.")
+                }
+            }
+        }
+    }
+
+    /// Splits the rate cap between the downloads running on lanes, by their weight.
+    fn slice(&self) {
+        let running = self.running.lock().expect("Could not lock downloads");
+        let weights: Vec<u32> = running.values().map(|(weight, _)| *weight).collect();
+        for ((_, cap), share) in running.values().zip(shares(self.cap.get(), &weights)) {
+            cap.set(share);
+        }
+    }
+
+    /// Files we serve, with their entry in the listing, hashed again only once they
+    /// changed.
+    fn entries(&self) -> io::Result<Vec<(PathBuf, ManifestEntry)>> {
+        let mut digests = self.digests.lock().expect("Could not lock digests");
+        batch::walk_with(&self.dir, &mut |path, metadata| {
+            let modified = metadata.modified()?;
+            match digests.get(path) {
+                Some(hashed) if hashed.len == metadata.len() && hashed.modified == modified => {
+                    Ok(hashed.sha256)
+                }
+                _ => {
+                    let sha256 = file_digest(path)?;
+                    let len = metadata.len();
+                    digests.insert(
+                        path.to_path_buf(),
+                        Hashed {
+                            len,
+                            modified,
+                            sha256,
+                        },
+                    );
+                    Ok(sha256)
+                }
+            }
+        })
+    }
+
+    fn answer_list(&self, socket: &Socket, peer: SocketAddr, size: usize, first: u32) {
+        let entries = match self.entries() {
+            Ok(entries) => entries
+                .into_iter()
+                .map(|(_, entry)| entry)
+                .collect::<Vec<_>>(),
+            Err(err) => {
+                warn!(error = %err, "Could not list the files we serve.");
+                return;
+            }
+        };
+        let answer = batch::manifest(&entries, first).serialize();
+        if answer.len() > size {
+            debug!(%peer, "Ignoring undersized list.");
+            return;
+        }
+        if let Err(err) = socket.send_to(&answer, peer) {
+            warn!(%peer, error = ?err, "Could not answer the list.");
+        }
+    }
+
+    /// Sends `peer` the file we list as `name`, or the bytes of it in `range`, if we do.
+    fn answer_get(
+        &self,
+        socket: &Socket,
+        transport: &Arc<dyn Transport>,
+        lane: Option<&Lane>,
+        peer: SocketAddr,
+        name: &str,
+        range: Option<Range<u64>>,
+    ) {
+        let found = self
+            .entries()
+            .map(|entries| entries.into_iter().find(|(_, entry)| entry.path == name));
+        let (path, entry) = match found {
+            Ok(Some(found)) => found,
+            Ok(None) => return refuse(socket, peer, format!("no file named {name} here")),
+            Err(err) => return refuse(socket, peer, format!("could not list the files: {err}")),
+        };
+        if let Some(range) = range
+            .as_ref()
+            .filter(|range| range.start > range.end || range.start > entry.size)
+        {
+            let reason = format!(
+                "range {}..{} is not within the {} bytes of {name}",
+                range.start, range.end, entry.size
+            );
+            return refuse(socket, peer, reason);
+        }
+        let bytes = range
+            .as_ref()
+            .map_or(entry.size, |range| range.end.min(entry.size) - range.start);
+        let bulk = bytes > self.bulk_above;
+        info!(%peer, name, ?range, bulk, "Sending a file we were asked for.");
+        let sender = Sender::new(peer.to_string())
+            .transport(transport.clone())
+            .connect_timeout(self.connect_timeout);
+        let sent = match lane {
+            None => send(sender.rate_cap(self.cap.clone()), &path, range),
+            Some(_) => {
+                let weight = match bulk {
+                    true => self.slices.bulk,
+                    false => self.slices.interactive,
+                };
+                let cap = RateCap::default();
+                let order = {
+                    let mut running = self.running.lock().expect("Could not lock downloads");
+                    let order = running.keys().next_back().map_or(0, |last| last + 1);
+                    running.insert(order, (weight, cap.clone()));
+                    order
+                };
+                self.slice();
+                let sent = send(sender.rate_cap(cap), &path, range);
+                self.running
+                    .lock()
+                    .expect("Could not lock downloads")
+                    .remove(&order);
+                self.slice();
+                sent
+            }
+        };
+        match sent {
+            Ok(()) => info!(%peer, name, "Sent the file we were asked for."),
+            Err(err) => warn!(%peer, name, error = %err, "Could not send the file."),
+        }
+    }
+}
+
+fn send(sender: Sender, path: &Path, range: Option<Range<u64>>) -> Result<(), SendError> {
+    match range {
+        Some(range) => sender.send_range(path, range),
+        None => sender.send(path),
+    }
+}
+
+fn refuse(socket: &Socket, peer: SocketAddr, reason: String) {
+    warn!(%peer, reason, "Refusing a get.");
+    if let Err(err) = socket.send_to(&Message::Abort { reason }.serialize(), peer) {
+        if err.kind() != ErrorKind::WouldBlock {
+            warn!(%peer, error = ?err, "Could not refuse the get.");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{Link, TempDir},
+        ReceiveError, Receiver,
+    };
+
+    #[test]
+    fn receivers_list_and_get_served_files() {
+        let root = TempDir::new("pull");
+        let (served, output) = (root.dir("served"), root.dir("output"));
+        root.write("served/a.bin", vec![7; 5000]);
+        root.write("served/sub/b.txt", b"hello");
+        let link = Link::new();
+        let library = Library::new(&served).transport(link.receiver_end());
+        // Serves for good, like a daemon.
+        std::thread::spawn(move || library.serve());
+
+        let receiver = || {
+            Receiver::new()
+                .transport(link.endpoint("127.0.0.2:0"))
+                .output(&output)
+                .idle_timeout(Duration::from_secs(5))
+        };
+        let mut listed: Vec<_> = receiver()
+            .list("127.0.0.1:6666")
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.path, entry.size))
+            .collect();
+        listed.sort();
+        assert_eq!(
+            listed,
+            [("a.bin".to_string(), 5000), ("sub/b.txt".to_string(), 5)]
+        );
+
+        receiver().get("127.0.0.1:6666", "a.bin").unwrap();
+        assert_eq!(std::fs::read(output.join("a.bin")).unwrap(), vec![7; 5000]);
+        assert!(matches!(
+            receiver().get("127.0.0.1:6666", "c.bin"),
+            Err(ReceiveError::Aborted(_))
+        ));
+    }
+
+    #[test]
+    fn ranges_are_written_in_place() {
+        let root = TempDir::new("range");
+        let (served, output) = (root.dir("served"), root.dir("output"));
+        let contents: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+        root.write("served/a.bin", &contents);
+        // What an earlier, interrupted download left.
+        root.write("output/a.bin", &contents[..3000]);
+        let link = Link::new();
+        let library = Library::new(&served).transport(link.receiver_end());
+        std::thread::spawn(move || library.serve());
+
+        let receiver = || {
+            Receiver::new()
+                .transport(link.endpoint("127.0.0.2:0"))
+                .output(&output)
+                .idle_timeout(Duration::from_secs(5))
+        };
+        receiver()
+            .get_range("127.0.0.1:6666", "a.bin", 3000..u64::MAX)
+            .unwrap();
+        assert_eq!(std::fs::read(output.join("a.bin")).unwrap(), contents);
+
+        std::fs::write(output.join("a.bin"), [0; 100]).unwrap();
+        receiver()
+            .get_range("127.0.0.1:6666", "a.bin", 50..60)
+            .unwrap();
+        let slice = std::fs::read(output.join("a.bin")).unwrap();
+        assert_eq!(slice[50..60], contents[50..60]);
+        assert!(slice[..50]
+            .iter()
+            .chain(&slice[60..])
+            .all(|byte| *byte == 0));
+        assert!(matches!(
+            receiver().get_range("127.0.0.1:6666", "a.bin", 20_000..30_000),
+            Err(ReceiveError::Aborted(_))
+        ));
+    }
+
+    #[test]
+    fn small_downloads_go_through_bulk_ones() {
+        let root = TempDir::new("slices");
+        let (served, output) = (root.dir("served"), root.dir("output"));
+        let bulk: Vec<u8> = (0..500_000).map(|i| (i % 251) as u8).collect();
+        root.write("served/bulk.bin", &bulk);
+        root.write("served/small.txt", b"hello");
+        let link = Link::new();
+        let library = Library::new(&served)
+            .transport(link.receiver_end())
+            .downloads(2)
+            .rate_cap(RateCap::new(Some(400_000)))
+            .bulk_above(100_000)
+            .slices("8:1".parse().unwrap());
+        std::thread::spawn(move || library.serve());
+
+        let receiver = |addr| {
+            Receiver::new()
+                .transport(link.endpoint(addr))
+                .output(&output)
+                .idle_timeout(Duration::from_secs(10))
+        };
+        let pulling = std::thread::spawn({
+            let receiver = receiver("127.0.0.2:0");
+            move || receiver.get("127.0.0.1:6666", "bulk.bin")
+        });
+        std::thread::sleep(Duration::from_millis(300));
+        receiver("127.0.0.3:0")
+            .get("127.0.0.1:6666", "small.txt")
+            .unwrap();
+        assert_eq!(std::fs::read(output.join("small.txt")).unwrap(), b"hello");
+        // The bulk download takes more than a second at the whole cap.
+        assert!(!pulling.is_finished());
+        // One lane sends the bulk download, the other stays with the last receiver a while.
+        assert!(matches!(
+            receiver("127.0.0.4:0").get("127.0.0.1:6666", "small.txt"),
+            Err(ReceiveError::Aborted(_))
+        ));
+        pulling.join().unwrap().unwrap();
+        assert_eq!(std::fs::read(output.join("bulk.bin")).unwrap(), bulk);
+
+        assert!("0:1".parse::<Slices>().is_err());
+        assert!("8".parse::<Slices>().is_err());
+    }
+
+    #[test]
+    fn long_listings_take_several_requests() {
+        let dir = TempDir::new("listing");
+        let names: Vec<_> = (0..40)
+            .map(|i| format!("{i:02}-{}", "x".repeat(80)))
+            .collect();
+        for name in &names {
+            dir.write(name, name);
+        }
+        let link = Link::new();
+        let library = Library::new(dir.path()).transport(link.receiver_end());
+        std::thread::spawn(move || library.serve());
+
+        let mut listed: Vec<_> = Receiver::new()
+            .transport(link.endpoint("127.0.0.2:0"))
+            .list("127.0.0.1:6666")
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.path)
+            .collect();
+        listed.sort();
+        assert_eq!(listed, names);
+    }
+
+    #[test]
+    fn unpadded_requests_are_not_answered() {
+        let dir = TempDir::new("unpadded");
+        let link = Link::new();
+        let library = Library::new(dir.path()).transport(link.receiver_end());
+        std::thread::spawn(move || library.serve());
+
+        let client = link.endpoint("127.0.0.2:6667");
+        client
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let get = Message::Get {
+            name: "a.bin".to_string(),
+            range: None,
+        };
+        for request in [Message::List { first: 0 }, get] {
+            client
+                .send_datagram(&request.serialize(), link.receiver_addr())
+                .unwrap();
+        }
+        let mut buf = [0; MTU];
+        assert!(client.recv_datagram(&mut buf).is_err());
+    }
+}
@@ -0,0 +1,42 @@
+//! Positioned vectored writes of received parts with `pwritev` on Linux.
+
+use std::{
+    fs::File,
+    io::{self, IoSlice},
+    os::fd::AsRawFd,
+};
+
+/// Most buffers a single call takes, IOV_MAX on Linux.
+const MAX_IOVECS: usize = 1024;
+
+/// Writes `bufs` back to back at `offset`, without moving the file cursor.
+pub(crate) fn write_all_at(file: &File, mut bufs: &mut [IoSlice], offset: u64) -> io::Result<()> {
+    let mut offset = offset;
+    // The kernel may write only part of the buffers, write the rest until it is all out.
+    while !bufs.is_empty() {
+        let count = bufs.len().min(MAX_IOVECS);
+        // SAFETY: IoSlice is guaranteed to be ABI compatible with iovec on unix, and the
+        // buffers outlive the call.
+        let result = unsafe {
+            libc::pwritev(
+                file.as_raw_fd(),
+                bufs.as_ptr() as *const libc::iovec,
+                count as libc::c_int,
+                offset as libc::off_t,
+            )
+        };
+        if result < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        if result == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        offset += result as u64;
+        IoSlice::advance_slices(&mut bufs, result as usize);
+    }
+    Ok(())
+}
@@ -0,0 +1,366 @@
+//! Long-running sender taking files from a queue, highest priority first. A few transfers
+//! run at once and split the rate cap by the weight of their priority. With preemption, a
+//! file waiting for a slot stops the transfer of a lower priority running, which goes back
+//! to the queue and starts over once there is room again.
+
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
+    io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::Duration,
+};
+
+use tracing::{info, warn};
+
+use crate::{client::RateCap, SendError, Sender};
+
+/// How often the shares of the rate cap are looked at again, for when the cap changed.
+const RESHARE: Duration = Duration::from_millis(200);
+
+type DoneCallback = Arc<dyn Fn(&Path, &Result<(), SendError>) + Send + Sync>;
+
+/// Weight of the files of a priority, against the others' 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Class {
+    pub priority: u8,
+    pub weight: u32,
+}
+
+/// Takes `<priority>=<weight>`.
+impl FromStr for Class {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (priority, weight) = s
+            .split_once('=')
+            .ok_or_else(|| format!("{s} is not a priority and a weight like 2=8"))?;
+        let priority = priority
+            .parse()
+            .map_err(|_| format!("{s} does not have a priority from 0 to 255"))?;
+        let weight = weight
+            .parse()
+            .ok()
+            .filter(|weight| *weight > 0)
+            .ok_or_else(|| format!("{s} does not have a positive weight"))?;
+        Ok(Class { priority, weight })
+    }
+}
+
+/// Files waiting and transfers running.
+#[derive(Default)]
+struct Jobs {
+    /// By priority, highest first, then in the order they were queued.
+    waiting: BTreeMap<(Reverse<u8>, u64), PathBuf>,
+    running: Vec<Running>,
+    /// Order of the next file queued.
+    next: u64,
+}
+
+struct Running {
+    priority: u8,
+    order: u64,
+    cap: RateCap,
+    stop: Arc<AtomicBool>,
+}
+
+/// Sends the files queued with [`Queue::push`], from another thread or over a control
+/// socket, with clones of a [`Sender`].
+///
+/// Transfers running at once need a socket each: bind the sender to port 0 rather than a
+/// fixed one, and give it no transport unless a single slot runs.
+#[derive(Clone)]
+pub struct Queue {
+    sender: Sender,
+    slots: usize,
+    classes: Vec<Class>,
+    cap: RateCap,
+    preempt: bool,
+    done: Option<DoneCallback>,
+    jobs: Arc<(Mutex<Jobs>, Condvar)>,
+}
+
+impl Queue {
+    pub fn new(sender: Sender) -> Self {
+        Queue {
+            sender,
+            slots: 2,
+            classes: Vec::new(),
+            cap: RateCap::default(),
+            preempt: false,
+            done: None,
+            jobs: Arc::default(),
+        }
+    }
+
+    /// Run up to `slots` transfers at once, 2 by default.
+    pub fn slots(mut self, slots: usize) -> Self {
+        self.slots = slots.max(1);
+        self
+    }
+
+    /// Give the files of a priority a weight other than 1.
+    pub fn class(mut self, class: Class) -> Self {
+        self.classes
+            .retain(|known| known.priority != class.priority);
+        self.classes.push(class);
+        self
+    }
+
+    /// Rate cap the transfers running split between them, which can change while they run.
+    pub fn rate_cap(mut self, cap: RateCap) -> Self {
+        self.cap = cap;
+        self
+    }
+
+    /// Stop a transfer of a lower priority when a file waits for a slot, rather than wait
+    /// for it to end.
+    pub fn preempt(mut self, preempt: bool) -> Self {
+        self.preempt = preempt;
+        self
+    }
+
+    /// Called once a file was sent, or could not be.
+    pub fn on_done(
+        mut self,
+        callback: impl Fn(&Path, &Result<(), SendError>) + Send + Sync + 'static,
+    ) -> Self {
+        self.done = Some(Arc::new(callback));
+        self
+    }
+
+    /// Queues `path`, a file or a directory sent as a batch.
+    pub fn push(&self, path: impl Into<PathBuf>, priority: u8) {
+        let (jobs, changed) = &*self.jobs;
+        let mut jobs = jobs.lock().expect("Could not lock the queue");
+        let order = jobs.next;
+        jobs.next += 1;
+        jobs.waiting.insert((Reverse(priority), order), path.into());
+        changed.notify_all();
+    }
+
+    /// Sends what is queued, for good. Only failing to start a thread stops it.
+    pub fn run(&self) -> io::Result<()> {
+        let (jobs, changed) = &*self.jobs;
+        let mut jobs = jobs.lock().expect("Could not lock the queue");
+        loop {
+            while jobs.running.len() < self.slots {
+                let Some(((Reverse(priority), order), path)) = jobs.waiting.pop_first() else {
+                    break;
+                };
+                let running = Running {
+                    priority,
+                    order,
+                    cap: RateCap::default(),
+                    stop: Arc::new(AtomicBool::new(false)),
+                };
+                self.start(&running, path)?;
+                jobs.running.push(running);
+            }
+            if self.preempt {
+                preempt(&mut jobs);
+            }
+            let weights: Vec<u32> = jobs
+                .running
+                .iter()
+                .map(|running| self.weight(running.priority))
+                .collect();
+            for (running, share) in jobs.running.iter().zip(shares(self.cap.get(), &weights)) {
+                running.cap.set(share);
+            }
+            jobs = changed
+                .wait_timeout(jobs, RESHARE)
+                .expect("Could not lock the queue")
+                .0;
+        }
+    }
+
+    /// Sends `path` on a thread of its own, which puts it back in the queue if it was
+    /// stopped.
+    fn start(&self, running: &Running, path: PathBuf) -> io::Result<()> {
+        info!(path = %path.display(), priority = running.priority, "Sending a queued file.");
+        let sender = self
+            .sender
+            .clone()
+            .rate_cap(running.cap.clone())
+            .stop_on(running.stop.clone());
+        let (priority, order, stop) = (running.priority, running.order, running.stop.clone());
+        let queue = self.clone();
+        std::thread::Builder::new()
+            .name("queued".to_string())
+            .spawn(move || {
+                let sent = match path.is_dir() {
+                    true => sender.send_batch(&path),
+                    false => sender.send(&path),
+                };
+                let preempted = sent.is_err() && stop.load(Ordering::Relaxed);
+                if !preempted {
+                    if let Err(err) = &sent {
+                        warn!(path = %path.display(), error = %err, "Could not send a queued file.");
+                    }
+                    if let Some(done) = &queue.done {
+                        done(&path, &sent);
+                    }
+                }
+                let (jobs, changed) = &*queue.jobs;
+                let mut jobs = jobs.lock().expect("Could not lock the queue");
+                jobs.running.retain(|running| running.order != order);
+                if preempted {
+                    info!(path = %path.display(), "Queued file preempted, sending it again later.");
+                    jobs.waiting.insert((Reverse(priority), order), path);
+                }
+                changed.notify_all();
+            })?;
+        Ok(())
+    }
+
+    fn weight(&self, priority: u8) -> u32 {
+        self.classes
+            .iter()
+            .find(|class| class.priority == priority)
+            .map_or(1, |class| class.weight)
+    }
+}
+
+/// Stops the running transfer of the lowest priority, the last started of them, if it is
+/// below the priority of the first file waiting and none is stopping already.
+fn preempt(jobs: &mut Jobs) {
+    let Some(&(Reverse(waiting), _)) = jobs.waiting.keys().next() else {
+        return;
+    };
+    if jobs
+        .running
+        .iter()
+        .any(|running| running.stop.load(Ordering::Relaxed))
+    {
+        return;
+    }
+    let lowest = jobs
+        .running
+        .iter()
+        .filter(|running| running.priority < waiting)
+        .min_by_key(|running| (running.priority, Reverse(running.order)));
+    if let Some(lowest) = lowest {
+        info!(
+            priority = lowest.priority,
+            waiting, "Preempting a transfer for a file of a higher priority."
+        );
+        lowest.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Splits `cap` between transfers of `weights`, none lower than a byte per second. No
+/// cap for any of them without one.
+pub(crate) fn shares(cap: Option<u64>, weights: &[u32]) -> Vec<Option<u64>> {
+    let total: u64 = weights.iter().map(|&weight| u64::from(weight)).sum();
+    weights
+        .iter()
+        .map(|&weight| {
+            let cap = cap?;
+            let share = u128::from(cap) * u128::from(weight) / u128::from(total.max(1));
+            Some((share as u64).max(1))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{Link, TempDir},
+        Receiver,
+    };
+
+    #[test]
+    fn the_cap_is_split_by_weight() {
+        assert_eq!(
+            shares(Some(9000), &[1, 2]),
+            [Some(3000), Some(6000)].to_vec()
+        );
+        assert_eq!(shares(Some(1), &[1, 1000]), [Some(1), Some(1)].to_vec());
+        assert_eq!(shares(None, &[1, 2]), [None, None].to_vec());
+        assert!(shares(Some(1000), &[]).is_empty());
+        assert_eq!(
+            "2=8".parse(),
+            Ok(Class {
+                priority: 2,
+                weight: 8
+            })
+        );
+        assert!("2=0".parse::<Class>().is_err());
+        assert!("300=1".parse::<Class>().is_err());
+    }
+
+    #[test]
+    fn higher_priorities_go_first_and_preempt_lower_ones() {
+        let running = |priority, order| Running {
+            priority,
+            order,
+            cap: RateCap::default(),
+            stop: Arc::new(AtomicBool::new(false)),
+        };
+        let mut jobs = Jobs::default();
+        for (path, priority) in [("bulk", 0), ("urgent", 9), ("normal", 5), ("later", 5)] {
+            let order = jobs.next;
+            jobs.next += 1;
+            jobs.waiting
+                .insert((Reverse(priority), order), PathBuf::from(path));
+        }
+        let order: Vec<_> = jobs
+            .waiting
+            .values()
+            .map(|path| path.to_str().unwrap())
+            .collect();
+        assert_eq!(order, ["urgent", "normal", "later", "bulk"]);
+
+        jobs.waiting
+            .retain(|(Reverse(priority), _), _| *priority < 9);
+        jobs.running = vec![running(0, 10), running(0, 11), running(7, 12)];
+        preempt(&mut jobs);
+        let stopped: Vec<_> = jobs
+            .running
+            .iter()
+            .map(|running| running.stop.load(Ordering::Relaxed))
+            .collect();
+        assert_eq!(stopped, [false, true, false]);
+        // One at a time.
+        jobs.running.remove(0);
+        preempt(&mut jobs);
+        assert!(!jobs.running[1].stop.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn queued_files_are_sent_by_priority() {
+        let root = TempDir::new("queue");
+        let output = root.dir("output");
+        let link = Link::new();
+        link.receive(Receiver::new().output(&output));
+        let (done, sent) = std::sync::mpsc::channel();
+        let queue = Queue::new(link.sender())
+            .slots(1)
+            .on_done(move |path, result| {
+                assert!(result.is_ok(), "{result:?}");
+                done.send(path.file_name().unwrap().to_owned()).unwrap();
+            });
+        for (name, priority) in [("low", 0), ("high", 9), ("middle", 5)] {
+            queue.push(root.write(format!("queued/{name}"), name), priority);
+        }
+        let running = queue.clone();
+        std::thread::spawn(move || running.run());
+        let order: Vec<_> = (0..3)
+            .map(|_| sent.recv_timeout(Duration::from_secs(10)).unwrap())
+            .collect();
+        assert_eq!(order, ["high", "middle", "low"]);
+        // The receiver publishes the last file once it is sure the sender is done.
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while std::fs::read(output.join("low")).ok().as_deref() != Some(b"low") {
+            assert!(std::time::Instant::now() < deadline, "low did not arrive");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
@@ -0,0 +1,329 @@
+use std::{
+    fs::File,
+    io::{self, IoSlice},
+};
+
+use crate::{pool::BufferPool, BUF_CAPACITY, PART_SIZE};
+
+/// Parts held back before they are sorted and written.
+const PENDING_PARTS: usize = 100;
+
+/// Where the reassembler puts the parts.
+pub(crate) trait WriteAt {
+    /// Writes `bufs` back to back, starting at `offset`.
+    fn write_all_at(&mut self, bufs: &mut [IoSlice], offset: u64) -> io::Result<()>;
+}
+
+#[cfg(all(target_os = "linux", feature = "uring"))]
+impl WriteAt for File {
+    fn write_all_at(&mut self, bufs: &mut [IoSlice], offset: u64) -> io::Result<()> {
+        crate::uring::write_all_at(self, bufs, offset)
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "pwritev", not(feature = "uring")))]
+impl WriteAt for File {
+    fn write_all_at(&mut self, bufs: &mut [IoSlice], offset: u64) -> io::Result<()> {
+        crate::pwritev::write_all_at(self, bufs, offset)
+    }
+}
+
+#[cfg(not(all(target_os = "linux", feature = "pwritev")))]
+impl WriteAt for File {
+    fn write_all_at(&mut self, bufs: &mut [IoSlice], offset: u64) -> io::Result<()> {
+        seek_and_write(self, bufs, offset)
+    }
+}
+
+/// Portable [`WriteAt`], through the cursor of `out`. Every write is a writev on unix, and
+/// a write per buffer elsewhere.
+#[cfg(any(test, not(all(target_os = "linux", feature = "pwritev"))))]
+fn seek_and_write<W: io::Write + io::Seek>(
+    out: &mut W,
+    mut bufs: &mut [IoSlice],
+    offset: u64,
+) -> io::Result<()> {
+    out.seek(io::SeekFrom::Start(offset))?;
+    while !bufs.is_empty() {
+        match out.write_vectored(bufs) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(written) => IoSlice::advance_slices(&mut bufs, written),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Puts parts received in any order back at their offset in the output. Parts are held
+/// back a little, then written in runs of consecutive ids, every part exactly once.
+pub(crate) struct Reassembler<W> {
+    out: W,
+    nb_parts: u32,
+    /// Where part 0 goes in the output.
+    base: u64,
+    /// Leave holes where parts are all zeroes.
+    sparse: bool,
+    pool: BufferPool,
+    pending: Vec<(u32, Vec<u8>)>,
+    /// One bit per part, set once it was taken.
+    taken: Vec<u64>,
+    parts_taken: u32,
+    /// Every part below is in the output.
+    written_below: u32,
+}
+
+impl<W: WriteAt> Reassembler<W> {
+    pub fn new(out: W, nb_parts: u32, sparse: bool, pool: BufferPool) -> Self {
+        Reassembler {
+            out,
+            nb_parts,
+            base: 0,
+            sparse,
+            pool,
+            pending: Vec::with_capacity(PENDING_PARTS),
+            taken: vec![0; (nb_parts as usize).div_ceil(64)],
+            parts_taken: 0,
+            written_below: 0,
+        }
+    }
+
+    /// Writes part `id` at `base + id * PART_SIZE` instead, for a range of the output.
+    pub fn at(mut self, base: u64) -> Self {
+        self.base = base;
+        self
+    }
+
+    /// Counts the parts in `ids` as already in the output, as when taking a transfer back.
+    pub fn stored(mut self, ids: impl IntoIterator<Item = u32>) -> Self {
+        let nb_parts = self.nb_parts;
+        for id in ids.into_iter().filter(|&id| id < nb_parts) {
+            self.take(id);
+        }
+        self.advance();
+        self
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.out
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    pub fn nb_parts(&self) -> u32 {
+        self.nb_parts
+    }
+
+    pub fn parts_taken(&self) -> u32 {
+        self.parts_taken
+    }
+
+    /// One bit per part taken, in words of 64. All of them are in the output after a flush.
+    pub fn taken(&self) -> &[u64] {
+        &self.taken
+    }
+
+    /// Parts from the first one on that are all in the output.
+    pub fn written_below(&self) -> u32 {
+        self.written_below
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.parts_taken == self.nb_parts
+    }
+
+    /// Takes part `id`, and writes the parts held back once there are enough of them or
+    /// the last one arrived. Duplicates and ids past the end are dropped.
+    pub fn push(&mut self, id: u32, data: Vec<u8>) -> io::Result<()> {
+        if id >= self.nb_parts || !self.take(id) {
+            self.pool.put(data);
+            return Ok(());
+        }
+        if self.sparse && data.iter().all(|byte| *byte == 0) {
+            self.pool.put(data);
+        } else {
+            self.pending.push((id, data));
+        }
+        if self.pending.len() >= PENDING_PARTS || self.is_complete() {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Writes every part held back, then hands their buffers back to the pool.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.pending.sort_unstable_by_key(|part| part.0);
+        let mut bufs: Vec<IoSlice> = Vec::with_capacity(self.pending.len());
+        let mut offset = 0;
+        let mut next = None;
+        for (id, data) in &self.pending {
+            // Runs of consecutive parts go out in one write, up to BUF_CAPACITY bytes.
+            if next != Some(*id) || (bufs.len() + 1) * PART_SIZE > BUF_CAPACITY {
+                self.out.write_all_at(&mut bufs, offset)?;
+                bufs.clear();
+                offset = self.base + *id as u64 * PART_SIZE as u64;
+            }
+            bufs.push(IoSlice::new(data));
+            next = Some(id + 1);
+        }
+        self.out.write_all_at(&mut bufs, offset)?;
+        drop(bufs);
+        self.pool
+            .put_all(self.pending.drain(..).map(|(_, data)| data));
+        self.advance();
+        Ok(())
+    }
+
+    /// Moves `written_below` past the parts taken, with none held back.
+    fn advance(&mut self) {
+        while self.written_below < self.nb_parts && self.is_taken(self.written_below) {
+            self.written_below += 1;
+        }
+    }
+
+    fn is_taken(&self, id: u32) -> bool {
+        self.taken[id as usize / 64] & (1 << (id % 64)) != 0
+    }
+
+    /// Marks part `id` as taken, and returns false if it already was.
+    fn take(&mut self, id: u32) -> bool {
+        if self.is_taken(id) {
+            return false;
+        }
+        self.taken[id as usize / 64] |= 1 << (id % 64);
+        self.parts_taken += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    impl WriteAt for Cursor<Vec<u8>> {
+        fn write_all_at(&mut self, bufs: &mut [IoSlice], offset: u64) -> io::Result<()> {
+            seek_and_write(self, bufs, offset)
+        }
+    }
+
+    /// Contents of part `id`, `len` bytes long.
+    fn part(id: u32, len: usize) -> Vec<u8> {
+        (0..len).map(|i| (id as usize * 7 + i) as u8 | 1).collect()
+    }
+
+    /// Length of part `id`, only the last one being short.
+    fn len(id: u32, nb_parts: u32, last_len: usize) -> usize {
+        if id == nb_parts - 1 {
+            last_len
+        } else {
+            PART_SIZE
+        }
+    }
+
+    fn reassemble(nb_parts: u32, last_len: usize, order: &[u32]) -> Vec<u8> {
+        let pool = BufferPool::new(16);
+        let mut parts = Reassembler::new(Cursor::new(Vec::new()), nb_parts, false, pool);
+        for &id in order {
+            parts
+                .push(id, part(id, len(id, nb_parts, last_len)))
+                .unwrap();
+        }
+        assert!(parts.is_complete());
+        assert!(parts.pending.is_empty());
+        parts.out.into_inner()
+    }
+
+    fn expected(nb_parts: u32, last_len: usize) -> Vec<u8> {
+        (0..nb_parts)
+            .flat_map(|id| part(id, len(id, nb_parts, last_len)))
+            .collect()
+    }
+
+    #[test]
+    fn in_order() {
+        let order: Vec<u32> = (0..250).collect();
+        assert_eq!(reassemble(250, 10, &order), expected(250, 10));
+    }
+
+    #[test]
+    fn out_of_order_across_flushes() {
+        // Every flush gets a scattered subset, and runs are split by missing ids.
+        let order: Vec<u32> = (0..301).map(|i| (i * 97) % 301).collect();
+        assert_eq!(reassemble(301, PART_SIZE, &order), expected(301, PART_SIZE));
+        let order: Vec<u32> = (0..301).rev().collect();
+        assert_eq!(reassemble(301, 1, &order), expected(301, 1));
+    }
+
+    #[test]
+    fn duplicates_are_written_once() {
+        let mut parts = Reassembler::new(Cursor::new(Vec::new()), 3, false, BufferPool::new(4));
+        for id in [1, 1, 0, 1, 7] {
+            parts.push(id, part(id, PART_SIZE)).unwrap();
+        }
+        assert_eq!(parts.parts_taken(), 2);
+        assert!(!parts.is_complete());
+        parts.push(2, part(2, 5)).unwrap();
+        assert!(parts.is_complete());
+        assert_eq!(parts.out.into_inner(), expected(3, 5));
+    }
+
+    #[test]
+    fn sparse_skips_zero_parts() {
+        let mut parts = Reassembler::new(Cursor::new(Vec::new()), 4, true, BufferPool::new(4));
+        parts.push(3, part(3, 3)).unwrap();
+        parts.push(1, vec![0; PART_SIZE]).unwrap();
+        parts.push(0, part(0, PART_SIZE)).unwrap();
+        parts.push(2, vec![0; PART_SIZE]).unwrap();
+        assert!(parts.is_complete());
+        let out = parts.out.into_inner();
+        assert_eq!(&out[..PART_SIZE], &part(0, PART_SIZE)[..]);
+        assert!(out[PART_SIZE..3 * PART_SIZE].iter().all(|byte| *byte == 0));
+        assert_eq!(&out[3 * PART_SIZE..], &part(3, 3)[..]);
+    }
+
+    #[test]
+    fn flush_keeps_what_arrived() {
+        let mut parts = Reassembler::new(Cursor::new(Vec::new()), 5, false, BufferPool::new(4));
+        parts.push(2, part(2, PART_SIZE)).unwrap();
+        parts.push(0, part(0, PART_SIZE)).unwrap();
+        // Held back until flushed.
+        assert_eq!(parts.written_below(), 0);
+        parts.flush().unwrap();
+        assert_eq!(parts.written_below(), 1);
+        let out = parts.out.into_inner();
+        assert_eq!(out.len(), 3 * PART_SIZE);
+        assert_eq!(&out[..PART_SIZE], &part(0, PART_SIZE)[..]);
+        assert_eq!(&out[2 * PART_SIZE..], &part(2, PART_SIZE)[..]);
+    }
+
+    #[test]
+    fn stored_parts_are_not_written_again() {
+        let out = Cursor::new(vec![9; PART_SIZE]);
+        let mut parts = Reassembler::new(out, 3, false, BufferPool::new(4)).stored([0, 5]);
+        assert_eq!(parts.taken(), &[1]);
+        assert_eq!(parts.written_below(), 1);
+        parts.push(0, part(0, PART_SIZE)).unwrap();
+        parts.push(2, part(2, 3)).unwrap();
+        assert!(!parts.is_complete());
+        parts.push(1, part(1, PART_SIZE)).unwrap();
+        assert!(parts.is_complete());
+        let out = parts.out.into_inner();
+        assert_eq!(&out[..PART_SIZE], &[9; PART_SIZE]);
+        assert_eq!(&out[PART_SIZE..], &expected(3, 3)[PART_SIZE..]);
+    }
+
+    #[test]
+    fn ranges_go_at_their_offset() {
+        let out = Cursor::new(vec![9; 10]);
+        let mut parts = Reassembler::new(out, 2, false, BufferPool::new(4)).at(4);
+        parts.push(1, part(1, 3)).unwrap();
+        parts.push(0, part(0, PART_SIZE)).unwrap();
+        let out = parts.out.into_inner();
+        assert_eq!(&out[..4], &[9; 4]);
+        assert_eq!(&out[4..], &expected(2, 3)[..]);
+    }
+}
@@ -0,0 +1,235 @@
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use sanic::crypto::SessionKey;
+use thiserror::Error;
+use tracing::{info, warn};
+
+#[derive(Error, Debug)]
+pub enum RemoteError {
+    #[error("Invalid destination {0:?}, expected [user@]host:path.")]
+    InvalidDestination(String),
+
+    #[error("Could not start ssh: {0}")]
+    Spawn(std::io::Error),
+
+    #[error("The remote receiver exited before it started listening.")]
+    ReceiverDied,
+}
+
+/// A scp-like `[user@]host:path` destination.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Destination {
+    pub user: Option<String>,
+    pub host: String,
+    pub path: PathBuf,
+}
+
+impl Destination {
+    pub fn parse(dest: &str) -> Result<Self, RemoteError> {
+        let invalid = || RemoteError::InvalidDestination(dest.to_string());
+        // The user is before the host, an @ in the path is part of the path.
+        let host_end = dest.find([':', '[']).unwrap_or(dest.len());
+        let (user, rest) = match dest[..host_end].rfind('@') {
+            Some(at) if at > 0 => (Some(&dest[..at]), &dest[at + 1..]),
+            Some(_) => return Err(invalid()),
+            None => (None, dest),
+        };
+        // IPv6 literals are written between brackets, like scp does.
+        let (host, path) = if let Some(rest) = rest.strip_prefix('[') {
+            let (host, path) = rest.split_once(']').ok_or_else(invalid)?;
+            (host, path.strip_prefix(':').ok_or_else(invalid)?)
+        } else {
+            rest.split_once(':').ok_or_else(invalid)?
+        };
+        // ssh would take them for options.
+        if host.is_empty()
+            || host.starts_with('-')
+            || user.is_some_and(|user| user.starts_with('-'))
+        {
+            return Err(invalid());
+        }
+
+        Ok(Destination {
+            user: user.map(str::to_string),
+            host: host.to_string(),
+            path: PathBuf::from(path),
+        })
+    }
+
+    /// The `[user@]host` argument handed to ssh.
+    pub fn ssh_target(&self) -> String {
+        match &self.user {
+            Some(user) => format!("{user}@{}", self.host),
+            None => self.host.clone(),
+        }
+    }
+}
+
+/// A `sanic receive --once` process started on the destination host over ssh.
+pub struct RemoteReceiver {
+    child: Child,
+    /// UDP port the remote receiver listens on.
+    pub port: u16,
+}
+
+impl RemoteReceiver {
+    /// Starts the remote receiver and blocks until it reports that it is listening.
+    ///
+    /// With a `key`, the transfer is encrypted: the key goes through the ssh channel's
+    /// stdin, so it never shows up in the remote process list, and the receiver binds
+    /// whatever port is free and reports it back over the same channel. The receiver
+    /// reports in JSON whatever its config says, so the port is read the same way.
+    pub fn spawn(
+        ssh: &str,
+        remote_sanic: &str,
+        dest: &Destination,
+        key: Option<&SessionKey>,
+    ) -> Result<Self, RemoteError> {
+        let mut remote_cmd = format!("{remote_sanic} receive --once --json");
+        if key.is_some() {
+            remote_cmd.push_str(" --port 0 --key-stdin");
+        }
+        if !dest.path.as_os_str().is_empty() {
+            remote_cmd.push_str(&format!(
+                " --output {}",
+                shell_quote(&dest.path.to_string_lossy())
+            ));
+        }
+        info!(
+            target = dest.ssh_target(),
+            command = remote_cmd,
+            "Starting remote receiver."
+        );
+
+        let mut child = Command::new(ssh)
+            .arg("-T")
+            .arg("--")
+            .arg(dest.ssh_target())
+            .arg(remote_cmd)
+            .stdin(if key.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(RemoteError::Spawn)?;
+
+        if let (Some(key), Some(mut stdin)) = (key, child.stdin.take()) {
+            writeln!(stdin, "{}", key.to_hex()).map_err(RemoteError::Spawn)?;
+        }
+
+        let stdout = child.stdout.take().ok_or(RemoteError::ReceiverDied)?;
+        let mut lines = BufReader::new(stdout).lines();
+        let port = loop {
+            match lines.next() {
+                Some(Ok(line)) => match listening_port(&line) {
+                    Some(port) => break port,
+                    None => continue,
+                },
+                _ => {
+                    let _ = child.wait();
+                    return Err(RemoteError::ReceiverDied);
+                }
+            }
+        };
+        // Keep draining the remote output so the receiver never blocks on a full pipe.
+        std::thread::spawn(move || lines.for_each(drop));
+
+        Ok(RemoteReceiver { child, port })
+    }
+
+    /// Waits for the remote receiver to exit on its own, killing it after `grace`.
+    pub fn finish(mut self, grace: Duration) {
+        let start = Instant::now();
+        while start.elapsed() < grace {
+            match self.child.try_wait() {
+                Ok(Some(_)) => return,
+                Ok(None) => std::thread::sleep(Duration::from_millis(50)),
+                Err(_) => break,
+            }
+        }
+        warn!("Remote receiver did not exit, killing the ssh session.");
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Port of the `listening` event a receiver prints with --json.
+fn listening_port(line: &str) -> Option<u16> {
+    line.strip_prefix(r#"{"event":"listening","port":"#)?
+        .strip_suffix('}')?
+        .parse()
+        .ok()
+}
+
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(dest: &str) -> Option<(Option<String>, String, PathBuf)> {
+        let dest = Destination::parse(dest).ok()?;
+        Some((dest.user, dest.host, dest.path))
+    }
+
+    #[test]
+    fn destinations_parse_like_scp() {
+        let at = |user: Option<&str>, host: &str, path: &str| {
+            Some((
+                user.map(str::to_string),
+                host.to_string(),
+                PathBuf::from(path),
+            ))
+        };
+        assert_eq!(parse("host:/srv/in"), at(None, "host", "/srv/in"));
+        assert_eq!(parse("me@host:"), at(Some("me"), "host", ""));
+        assert_eq!(parse("me@[::1]:in"), at(Some("me"), "::1", "in"));
+        assert_eq!(parse("host:mail@home"), at(None, "host", "mail@home"));
+        assert_eq!(parse("me@host:a@b:c"), at(Some("me"), "host", "a@b:c"));
+        assert_eq!(parse("me@corp@host:in"), at(Some("me@corp"), "host", "in"));
+        for invalid in [
+            "host",
+            ":path",
+            "@host:path",
+            "[::1:path",
+            "[::1]path",
+            "-oProxyCommand=sh:path",
+            "-oProxyCommand=sh@host:path",
+            "me@-oProxyCommand=sh:path",
+        ] {
+            assert_eq!(parse(invalid), None, "{invalid}");
+        }
+        assert_eq!(
+            Destination::parse("me@[::1]:in").unwrap().ssh_target(),
+            "me@::1"
+        );
+    }
+
+    #[test]
+    fn arguments_are_quoted_for_the_remote_shell() {
+        assert_eq!(shell_quote("/srv/in box"), "'/srv/in box'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote("$(rm -rf ~); `x`"), "'$(rm -rf ~); `x`'");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    #[test]
+    fn the_port_is_read_off_the_listening_event() {
+        assert_eq!(
+            listening_port(r#"{"event":"listening","port":40123}"#),
+            Some(40123)
+        );
+        assert_eq!(listening_port("Listening at port 40123"), None);
+        assert_eq!(
+            listening_port(r#"{"event":"progress","parts_done":1}"#),
+            None
+        );
+    }
+}
@@ -0,0 +1,781 @@
+//! Transfers between peers that only share a code such as `7-basilisk-tundra`, made up by
+//! [`new_code`]. Both register the number in front of it with a [`Relay`], which tells
+//! each one the public address it saw the other one at, along with the key exchange
+//! message the other one registered with. The peers derive the session key from the
+//! whole code through that exchange, so the relay never learns the key, and only gets a
+//! single guess at the code were it to impersonate a peer.
+//!
+//! The sender then sends straight to the receiver, which punches a hole in its own NAT
+//! for it by sending it a few datagrams first. The relay only brokers the introduction,
+//! the transfer itself never goes through it.
+//!
+//! Some NATs, the symmetric ones, give every destination its own public address, so the
+//! one the relay saw is of no use to the peer. Relays that opt in then forward the
+//! datagrams of the pair, at a capped rate. They only ever see them sealed.
+
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    net::{SocketAddr, UdpSocket},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, info, warn};
+
+use crate::{
+    crypto::{random_bytes, SessionKey},
+    pake::Pake,
+    protocol::Message,
+    socket::Socket,
+    transport::Transport,
+    MTU,
+};
+
+/// How long a relay keeps a code around, and how long receivers wait for their sender.
+pub const CODE_TTL: Duration = Duration::from_secs(10 * 60);
+/// How often peers register again until they are introduced, in case a datagram got lost.
+const REGISTER_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the relay wakes up to forget expired codes.
+const POLL: Duration = Duration::from_millis(500);
+/// The receiver punches for about two seconds, while the first Sends are on their way.
+const PUNCHES: u32 = 20;
+const PUNCH_INTERVAL: Duration = Duration::from_millis(100);
+/// Bytes a relayed pair may send at once over its rate, so that batches of parts get by.
+const BURST: f64 = (64 * MTU) as f64;
+/// Large enough for any datagram we forward, sealed or not.
+const MAX_DATAGRAM: usize = 64 * 1024;
+
+/// Rendezvous server peers behind NATs register with to find each other.
+///
+/// ```no_run
+/// sanic::rendezvous::Relay::new().bind("0.0.0.0:6668").run()?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct Relay {
+    bind: String,
+    ttl: Duration,
+    forward_rate: Option<u64>,
+}
+
+impl Default for Relay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Relay {
+    pub fn new() -> Self {
+        Relay {
+            bind: "0.0.0.0:6668".to_string(),
+            ttl: CODE_TTL,
+            forward_rate: None,
+        }
+    }
+
+    /// Local address peers register with.
+    pub fn bind(mut self, addr: impl Into<String>) -> Self {
+        self.bind = addr.into();
+        self
+    }
+
+    /// Forget a code once nobody registered it for this long.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Also forward the datagrams of the pairs that could not reach each other directly,
+    /// at most `bytes_per_sec` for each pair, both directions together. Off by default.
+    pub fn forward(mut self, bytes_per_sec: u64) -> Self {
+        self.forward_rate = Some(bytes_per_sec);
+        self
+    }
+
+    /// Introduces peers until an I/O error occurs.
+    pub fn run(&self) -> io::Result<()> {
+        let socket = UdpSocket::bind(&self.bind)?;
+        info!(addr = %socket.local_addr()?, "Brokering introductions.");
+        serve(&socket, self.ttl, self.forward_rate)
+    }
+}
+
+fn serve(socket: &UdpSocket, ttl: Duration, forward_rate: Option<u64>) -> io::Result<()> {
+    socket.set_read_timeout(Some(POLL))?;
+    let mut registry = Registry::new(ttl);
+    let mut buf = vec![0; MAX_DATAGRAM];
+    loop {
+        registry.expire(Instant::now());
+        let (size, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                continue
+            }
+            Err(err) if err.kind() == ErrorKind::ConnectionRefused => continue,
+            Err(err) => return Err(err),
+        };
+        // Our own messages are in the clear, and sealed datagrams start with their
+        // sequence space, which never collides with their type.
+        if !matches!(buf.first(), Some(&12 | &15)) {
+            match registry.forward(from, size, Instant::now()) {
+                Forward::To(to) => {
+                    if let Err(err) = socket.send_to(&buf[..size], to) {
+                        debug!(%to, error = ?err, "Could not forward datagram.");
+                    }
+                }
+                Forward::Throttled => debug!(%from, "Dropping datagram over the rate."),
+                Forward::Unknown => debug!(%from, "Dropping datagram from a stranger."),
+            }
+            continue;
+        }
+        match Message::parse(&buf[..size]) {
+            Ok(Message::Register { code, sender, pake }) => introduce(
+                socket,
+                registry.register(&code, sender, Side { addr: from, pake }, Instant::now()),
+                from,
+            ),
+            Ok(Message::Relay { code }) => {
+                let answer = match forward_rate {
+                    None => Err("This relay does not forward transfers."),
+                    Some(rate) => registry.relay(&code, from, rate, Instant::now()),
+                };
+                let answer = match answer {
+                    Ok(bytes_per_sec) => {
+                        info!(%from, "Relaying the transfer.");
+                        Message::RateLimit { bytes_per_sec }
+                    }
+                    Err(reason) => {
+                        warn!(%from, reason, "Refusing to relay.");
+                        Message::Abort {
+                            reason: reason.to_string(),
+                        }
+                    }
+                };
+                if let Err(err) = socket.send_to(&answer.serialize(), from) {
+                    warn!(%from, error = ?err, "Could not answer relay request.");
+                }
+            }
+            Ok(msg) => debug!(%from, message = ?msg, "Ignoring message."),
+            Err(err) => debug!(%from, error = ?err, "Could not parse packet."),
+        }
+    }
+}
+
+fn introduce(socket: &UdpSocket, registration: Registration, from: SocketAddr) {
+    let (sender, receiver) = match registration {
+        Registration::Waiting => return,
+        Registration::Taken => {
+            warn!(%from, "Code already registered from another address.");
+            return;
+        }
+        Registration::Paired { sender, receiver } => (sender, receiver),
+    };
+    info!(sender = %sender.addr, receiver = %receiver.addr, "Introducing peers.");
+    for (to, peer) in [(&sender, &receiver), (&receiver, &sender)] {
+        let introduce = Message::Introduce {
+            peer: peer.addr,
+            pake: peer.pake.clone(),
+        };
+        if let Err(err) = socket.send_to(&introduce.serialize(), to.addr) {
+            warn!(to = %to.addr, error = ?err, "Could not introduce peer.");
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Registration {
+    /// Nobody registered the other side of the code yet.
+    Waiting,
+    /// Another address holds our side of the code.
+    Taken,
+    Paired {
+        sender: Side,
+        receiver: Side,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Forward {
+    To(SocketAddr),
+    /// The pair went over its rate.
+    Throttled,
+    /// Not a peer we relay for.
+    Unknown,
+}
+
+/// Peer registered on one side of a code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Side {
+    addr: SocketAddr,
+    /// Key exchange message, passed to the other side.
+    pake: Vec<u8>,
+}
+
+/// Both sides of a code, as far as they registered.
+struct Code {
+    sender: Option<Side>,
+    receiver: Option<Side>,
+    touched: Instant,
+    /// Set once we forward the datagrams of the pair.
+    relayed: Option<Bucket>,
+}
+
+/// Rate of a relayed pair, both directions together.
+struct Bucket {
+    bytes_per_sec: u64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn new(bytes_per_sec: u64, now: Instant) -> Self {
+        let mut bucket = Bucket {
+            bytes_per_sec,
+            tokens: 0.0,
+            refilled: now,
+        };
+        bucket.tokens = bucket.capacity();
+        bucket
+    }
+
+    fn capacity(&self) -> f64 {
+        (self.bytes_per_sec as f64 / 4.0).max(BURST)
+    }
+
+    fn take(&mut self, len: usize, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.bytes_per_sec as f64).min(self.capacity());
+        self.refilled = now;
+        if self.tokens < len as f64 {
+            return false;
+        }
+        self.tokens -= len as f64;
+        true
+    }
+}
+
+/// Codes the relay knows about. Paired codes are kept until they expire, so peers whose
+/// introduction got lost get it again when they register anew.
+struct Registry {
+    codes: HashMap<String, Code>,
+    /// Code of every peer we forward the datagrams of.
+    relayed: HashMap<SocketAddr, String>,
+    ttl: Duration,
+}
+
+impl Registry {
+    fn new(ttl: Duration) -> Self {
+        Registry {
+            codes: HashMap::new(),
+            relayed: HashMap::new(),
+            ttl,
+        }
+    }
+
+    fn register(&mut self, code: &str, sender: bool, from: Side, now: Instant) -> Registration {
+        let entry = self.codes.entry(code.to_string()).or_insert(Code {
+            sender: None,
+            receiver: None,
+            touched: now,
+            relayed: None,
+        });
+        let side = if sender {
+            &mut entry.sender
+        } else {
+            &mut entry.receiver
+        };
+        match side {
+            Some(registered) if registered.addr != from.addr => return Registration::Taken,
+            _ => *side = Some(from),
+        }
+        entry.touched = now;
+        match (&entry.sender, &entry.receiver) {
+            (Some(sender), Some(receiver)) => Registration::Paired {
+                sender: sender.clone(),
+                receiver: receiver.clone(),
+            },
+            _ => Registration::Waiting,
+        }
+    }
+
+    /// Starts forwarding the datagrams of the pair `from` belongs to, at `bytes_per_sec`,
+    /// and returns the rate to stay under.
+    fn relay(
+        &mut self,
+        code: &str,
+        from: SocketAddr,
+        bytes_per_sec: u64,
+        now: Instant,
+    ) -> Result<u64, &'static str> {
+        let entry = self.codes.get_mut(code).ok_or("Unknown code.")?;
+        let (Some(sender), Some(receiver)) = (&entry.sender, &entry.receiver) else {
+            return Err("The peer did not register yet.");
+        };
+        let (sender, receiver) = (sender.addr, receiver.addr);
+        if from != sender && from != receiver {
+            return Err("Not a peer of this code.");
+        }
+        entry.touched = now;
+        entry
+            .relayed
+            .get_or_insert_with(|| Bucket::new(bytes_per_sec, now));
+        for peer in [sender, receiver] {
+            self.relayed.insert(peer, code.to_string());
+        }
+        Ok(bytes_per_sec)
+    }
+
+    /// Where to forward a datagram of `len` bytes coming from `from`.
+    fn forward(&mut self, from: SocketAddr, len: usize, now: Instant) -> Forward {
+        let Some(entry) = self
+            .relayed
+            .get(&from)
+            .and_then(|code| self.codes.get_mut(code))
+        else {
+            return Forward::Unknown;
+        };
+        let (Some(sender), Some(receiver), Some(bucket)) =
+            (&entry.sender, &entry.receiver, entry.relayed.as_mut())
+        else {
+            return Forward::Unknown;
+        };
+        entry.touched = now;
+        if !bucket.take(len, now) {
+            return Forward::Throttled;
+        }
+        Forward::To(if from == sender.addr {
+            receiver.addr
+        } else {
+            sender.addr
+        })
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let ttl = self.ttl;
+        self.codes
+            .retain(|_, code| now.saturating_duration_since(code.touched) < ttl);
+        let codes = &self.codes;
+        self.relayed.retain(|_, code| codes.contains_key(code));
+    }
+}
+
+/// How to reach the peer of a meeting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Route {
+    /// Straight to the peer, at the address the relay introduced it with.
+    Direct(SocketAddr),
+    /// Through the relay, which forwards our datagrams at most at `bytes_per_sec`.
+    Relayed {
+        relay: SocketAddr,
+        bytes_per_sec: u64,
+    },
+}
+
+/// Code a peer meets the other one under, along with the route it found to it and the
+/// key they agreed on, which the next transfers of the session reuse.
+#[derive(Clone)]
+pub(crate) struct Meeting {
+    code: String,
+    met: Arc<Mutex<Option<(Route, SessionKey)>>>,
+}
+
+impl Meeting {
+    pub fn new(code: impl Into<String>) -> Self {
+        Meeting {
+            code: code.into(),
+            met: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Route to the peer and the key to seal the transfer with, registering with `relay`
+    /// over `transport` to learn its public address and run the key exchange the first
+    /// time.
+    pub fn route(
+        &self,
+        transport: &dyn Transport,
+        relay: SocketAddr,
+        sender: bool,
+        timeout: Duration,
+    ) -> io::Result<(Route, SessionKey)> {
+        let mut met = self.met.lock().expect("Could not lock meeting");
+        if let Some(met) = &*met {
+            return Ok(met.clone());
+        }
+        let pake = Pake::new(&self.code, sender)?;
+        let register = Message::Register {
+            code: nameplate(&self.code).to_string(),
+            sender,
+            pake: pake.message().to_vec(),
+        }
+        .serialize();
+        info!(%relay, "Waiting for the peer at the relay.");
+        let (peer, key) = ask(transport, relay, &register, timeout, |msg| match msg {
+            Message::Introduce { peer, pake: theirs } => {
+                Some(pake.finish(&theirs).map(|key| (peer, key)).ok_or_else(|| {
+                    io::Error::new(ErrorKind::InvalidData, "Invalid key exchange from the peer")
+                }))
+            }
+            _ => None,
+        })?;
+        info!(%peer, "Introduced to the peer.");
+        *met = Some((Route::Direct(peer), key.clone()));
+        Ok((Route::Direct(peer), key))
+    }
+
+    /// Asks `relay` to forward our datagrams to the peer, as we could not reach it
+    /// directly, for this transfer and the next ones. Returns the rate it forwards at.
+    pub fn relay(
+        &self,
+        transport: &dyn Transport,
+        relay: SocketAddr,
+        timeout: Duration,
+    ) -> io::Result<u64> {
+        let request = Message::Relay {
+            code: nameplate(&self.code).to_string(),
+        }
+        .serialize();
+        let bytes_per_sec = ask(transport, relay, &request, timeout, |msg| match msg {
+            Message::RateLimit { bytes_per_sec } => Some(Ok(bytes_per_sec)),
+            Message::Abort { reason } => Some(Err(io::Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("The relay refused to forward the transfer: {reason}"),
+            ))),
+            _ => None,
+        })?;
+        if let Some((route, _)) = &mut *self.met.lock().expect("Could not lock meeting") {
+            *route = Route::Relayed {
+                relay,
+                bytes_per_sec,
+            };
+        }
+        Ok(bytes_per_sec)
+    }
+}
+
+/// Part of `code` the relay pairs peers by: the number in front of it. The rest of it
+/// only goes into the key exchange, and never over the wire.
+fn nameplate(code: &str) -> &str {
+    code.split_once('-')
+        .map_or(code, |(nameplate, _)| nameplate)
+}
+
+/// Makes up a code for a receiver to type in, a number followed by two words.
+pub fn new_code() -> io::Result<String> {
+    let mut random = [0; 4];
+    random_bytes(&mut random)?;
+    let number = u16::from_be_bytes([random[0], random[1]]) % 999 + 1;
+    Ok(format!(
+        "{number}-{}-{}",
+        WORDS[random[2] as usize], WORDS[random[3] as usize]
+    ))
+}
+
+/// Words codes are made of, 256 of them so that a byte picks one.
+const WORDS: [&str; 256] = [
+    "acorn", "adrift", "agenda", "almond", "amber", "anchor", "anvil", "apple", "apron", "arcade",
+    "arctic", "armada", "arrow", "aspen", "atlas", "autumn", "badger", "bagel", "bamboo", "banjo",
+    "barley", "basil", "basilisk", "beacon", "beetle", "bishop", "blanket", "blizzard", "bonfire",
+    "bramble", "breeze", "bridge", "brisket", "bronze", "bucket", "buffalo", "bugle", "butter",
+    "cabin", "cactus", "camel", "candle", "canyon", "carbon", "cargo", "carrot", "castle", "cedar",
+    "cellar", "cherry", "chisel", "cider", "cinder", "circus", "citrus", "clover", "cobalt",
+    "comet", "compass", "copper", "coral", "cotton", "cougar", "coyote", "cradle", "crater",
+    "cricket", "crystal", "cupola", "cypress", "dagger", "dahlia", "dancer", "delta", "desert",
+    "diesel", "dolphin", "domino", "dragon", "drizzle", "eagle", "easel", "echo", "eclipse",
+    "elbow", "ember", "emerald", "engine", "falcon", "feather", "fennel", "ferry", "fiddle",
+    "figure", "fjord", "flannel", "flint", "forest", "fossil", "fountain", "fox", "galaxy",
+    "garden", "garlic", "gazelle", "geyser", "ginger", "glacier", "goblet", "gopher", "granite",
+    "gravel", "harbor", "harvest", "hazel", "hedgehog", "helmet", "heron", "hickory", "honey",
+    "horizon", "hornet", "husky", "iceberg", "igloo", "indigo", "island", "ivory", "jackal",
+    "jaguar", "jasmine", "jigsaw", "jungle", "juniper", "kayak", "kernel", "kettle", "kiwi",
+    "koala", "ladder", "lagoon", "lantern", "lava", "lemon", "lentil", "lilac", "linen", "lizard",
+    "lobster", "locket", "lotus", "lumber", "magnet", "mango", "maple", "marble", "meadow",
+    "melon", "meteor", "mitten", "monsoon", "mosaic", "muffin", "mustard", "nebula", "nectar",
+    "needle", "nickel", "nutmeg", "oasis", "oatmeal", "ocean", "olive", "onyx", "orbit", "orchid",
+    "otter", "oyster", "paddle", "pagoda", "palace", "panda", "paprika", "parcel", "parrot",
+    "pebble", "pelican", "pepper", "pigeon", "pillow", "pine", "pirate", "plasma", "plum",
+    "pocket", "polar", "poppy", "potato", "prairie", "prism", "pumpkin", "puzzle", "quartz",
+    "quiver", "rabbit", "radish", "raven", "reef", "ribbon", "river", "robin", "rocket", "saddle",
+    "saffron", "salmon", "sapphire", "satchel", "scarlet", "sequoia", "shadow", "sherbet",
+    "signal", "silver", "sparrow", "spinach", "spruce", "squid", "starling", "summit", "sundial",
+    "swallow", "tango", "teapot", "temple", "thistle", "thunder", "tiger", "timber", "tomato",
+    "topaz", "tornado", "tulip", "tundra", "turnip", "tuxedo", "umbrella", "valley", "velvet",
+    "violet", "volcano", "walnut", "walrus", "whistle", "willow", "yarrow", "zebra",
+];
+
+/// Sends `request` to `relay` every [`REGISTER_INTERVAL`] until `answer` makes something
+/// of what the relay sends back. Goes over the very socket the transfer then runs on, so
+/// that our NAT keeps the same public address.
+fn ask<T>(
+    transport: &dyn Transport,
+    relay: SocketAddr,
+    request: &[u8],
+    timeout: Duration,
+    mut answer: impl FnMut(Message) -> Option<io::Result<T>>,
+) -> io::Result<T> {
+    let start = Instant::now();
+    let mut next = start;
+    let mut buf = vec![0; MTU];
+    loop {
+        let now = Instant::now();
+        let Some(remaining) = timeout
+            .checked_sub(now - start)
+            .filter(|left| !left.is_zero())
+        else {
+            return Err(io::Error::new(
+                ErrorKind::TimedOut,
+                "No answer from the relay in time",
+            ));
+        };
+        if now >= next {
+            transport.send_datagram(request, relay)?;
+            next = now + REGISTER_INTERVAL;
+        }
+        let wait = next
+            .saturating_duration_since(now)
+            .min(remaining)
+            .max(Duration::from_millis(1));
+        transport.set_read_timeout(Some(wait))?;
+        match transport.recv_datagram(&mut buf) {
+            Ok((size, from)) if from == relay => match Message::parse(&buf[..size]) {
+                Ok(msg) => {
+                    if let Some(result) = answer(msg) {
+                        transport.set_read_timeout(None)?;
+                        return result;
+                    }
+                }
+                Err(err) => debug!(error = ?err, "Could not parse packet from the relay."),
+            },
+            Ok((_, from)) => debug!(%from, "Dropping datagram from a stranger."),
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::ConnectionRefused
+                ) => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Opens our NAT for `peer`, in the background, by sending it a burst of Punches: it lets
+/// the replies to whatever we send in, and the sender's Sends look like such replies.
+pub(crate) fn punch(socket: Socket, peer: SocketAddr) {
+    std::thread::spawn(move || {
+        let punch = Message::Punch.serialize();
+        for _ in 0..PUNCHES {
+            if let Err(err) = socket.send_to(&punch, peer) {
+                warn!(%peer, error = ?err, "Could not punch through our NAT.");
+                return;
+            }
+            std::thread::sleep(PUNCH_INTERVAL);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([203, 0, 113, 7], port))
+    }
+
+    fn side(port: u16) -> Side {
+        Side {
+            addr: addr(port),
+            pake: vec![port as u8; 4],
+        }
+    }
+
+    #[test]
+    fn codes_pair_a_sender_with_a_receiver() {
+        let now = Instant::now();
+        let mut registry = Registry::new(CODE_TTL);
+        assert_eq!(
+            registry.register("7", true, side(1), now),
+            Registration::Waiting
+        );
+        // Registering again while waiting changes nothing.
+        assert_eq!(
+            registry.register("7", true, side(1), now),
+            Registration::Waiting
+        );
+        assert_eq!(
+            registry.register("other", false, side(2), now),
+            Registration::Waiting
+        );
+        let paired = Registration::Paired {
+            sender: side(1),
+            receiver: side(3),
+        };
+        assert_eq!(registry.register("7", false, side(3), now), paired);
+        // Peers that missed their introduction get it again.
+        assert_eq!(registry.register("7", true, side(1), now), paired);
+        // Nobody else can take a side over.
+        assert_eq!(
+            registry.register("7", true, side(4), now),
+            Registration::Taken
+        );
+    }
+
+    #[test]
+    fn codes_expire() {
+        let now = Instant::now();
+        let mut registry = Registry::new(Duration::from_secs(60));
+        registry.register("code", true, side(1), now);
+        registry.expire(now + Duration::from_secs(30));
+        registry.register("code", false, side(2), now + Duration::from_secs(30));
+        registry.expire(now + Duration::from_secs(89));
+        assert_eq!(registry.codes.len(), 1);
+        registry.expire(now + Duration::from_secs(90));
+        assert!(registry.codes.is_empty());
+        assert_eq!(
+            registry.register("code", true, side(4), now + Duration::from_secs(91)),
+            Registration::Waiting
+        );
+    }
+
+    #[test]
+    fn relayed_pairs_are_forwarded_under_their_rate() {
+        let now = Instant::now();
+        let mut registry = Registry::new(CODE_TTL);
+        registry.register("code", true, side(1), now);
+        assert!(registry.relay("code", addr(1), 1_000_000, now).is_err());
+        registry.register("code", false, side(2), now);
+        assert!(registry.relay("code", addr(3), 1_000_000, now).is_err());
+        assert_eq!(registry.forward(addr(1), 100, now), Forward::Unknown);
+
+        assert_eq!(
+            registry.relay("code", addr(1), 1_000_000, now),
+            Ok(1_000_000)
+        );
+        assert_eq!(registry.forward(addr(1), 100, now), Forward::To(addr(2)));
+        assert_eq!(registry.forward(addr(2), 100, now), Forward::To(addr(1)));
+        assert_eq!(registry.forward(addr(3), 100, now), Forward::Unknown);
+
+        // A quarter of a second worth of burst, then the rate.
+        let mut forwarded = 0;
+        while registry.forward(addr(1), MTU, now) != Forward::Throttled {
+            forwarded += MTU;
+        }
+        assert!((240_000..=250_000).contains(&forwarded), "{forwarded}");
+        let later = now + Duration::from_millis(10);
+        assert_eq!(
+            registry.forward(addr(1), 9_000, later),
+            Forward::To(addr(2))
+        );
+        assert_eq!(registry.forward(addr(1), 2_000, later), Forward::Throttled);
+
+        let expired = later + CODE_TTL;
+        registry.expire(expired);
+        assert_eq!(registry.forward(addr(1), 1, expired), Forward::Unknown);
+    }
+
+    #[test]
+    fn peers_meet_through_the_relay() {
+        let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let relay_addr = relay.local_addr().unwrap();
+        std::thread::spawn(move || serve(&relay, CODE_TTL, Some(1_000_000)));
+
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver_addr = receiver.local_addr().unwrap();
+        let waiting = std::thread::spawn(move || {
+            let met = Meeting::new("7-basilisk-tundra")
+                .route(&receiver, relay_addr, false, Duration::from_secs(5))
+                .unwrap();
+            (receiver, met)
+        });
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let meeting = Meeting::new("7-basilisk-tundra");
+        let (route, key) = meeting
+            .route(&sender, relay_addr, true, Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(route, Route::Direct(receiver_addr));
+        let (receiver, (route, receiver_key)) = waiting.join().unwrap();
+        assert_eq!(route, Route::Direct(sender.local_addr().unwrap()));
+        assert_eq!(key, receiver_key);
+
+        let relayed = Route::Relayed {
+            relay: relay_addr,
+            bytes_per_sec: 1_000_000,
+        };
+        assert_eq!(
+            meeting
+                .relay(&sender, relay_addr, Duration::from_secs(5))
+                .unwrap(),
+            1_000_000
+        );
+        sender.send_to(&[0, 1, 2, 3], relay_addr).unwrap();
+        let mut buf = [0; 16];
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(receiver.recv_from(&mut buf).unwrap(), (4, relay_addr));
+        assert_eq!(buf[..4], [0, 1, 2, 3]);
+        // Later transfers reuse the route.
+        let stranger: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        assert_eq!(
+            meeting
+                .route(&sender, stranger, true, Duration::from_millis(1))
+                .unwrap(),
+            (relayed, key)
+        );
+    }
+
+    #[test]
+    fn peers_with_different_codes_get_different_keys() {
+        let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let relay_addr = relay.local_addr().unwrap();
+        std::thread::spawn(move || serve(&relay, CODE_TTL, None));
+
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let waiting = std::thread::spawn(move || {
+            Meeting::new("7-basilisk-tundrx")
+                .route(&receiver, relay_addr, false, Duration::from_secs(5))
+                .unwrap()
+        });
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (_, key) = Meeting::new("7-basilisk-tundra")
+            .route(&sender, relay_addr, true, Duration::from_secs(5))
+            .unwrap();
+        assert_ne!(waiting.join().unwrap().1, key);
+    }
+
+    #[test]
+    fn codes_are_a_nameplate_and_two_words() {
+        let code = new_code().unwrap();
+        let mut parts = code.split('-');
+        let number: u16 = parts.next().unwrap().parse().unwrap();
+        assert!((1..=999).contains(&number));
+        for _ in 0..2 {
+            assert!(WORDS.contains(&parts.next().unwrap()));
+        }
+        assert_eq!(parts.next(), None);
+        assert_eq!(nameplate("7-basilisk-tundra"), "7");
+        assert_eq!(nameplate("plain"), "plain");
+        let mut words = WORDS.to_vec();
+        words.sort_unstable();
+        words.dedup();
+        assert_eq!(words.len(), WORDS.len());
+    }
+
+    #[test]
+    fn relays_have_to_opt_in() {
+        let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let relay_addr = relay.local_addr().unwrap();
+        std::thread::spawn(move || serve(&relay, CODE_TTL, None));
+
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        std::thread::spawn(move || {
+            Meeting::new("code").route(&receiver, relay_addr, false, Duration::from_secs(5))
+        });
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let meeting = Meeting::new("code");
+        meeting
+            .route(&sender, relay_addr, true, Duration::from_secs(5))
+            .unwrap();
+        let err = meeting
+            .relay(&sender, relay_addr, Duration::from_secs(5))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+    }
+}
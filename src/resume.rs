@@ -0,0 +1,365 @@
+//! Resume state of a receiver, so that a transfer survives the receiving process dying.
+//! Next to the file of each transfer, a `.sanic-resume` file tells who was sending it, what, and
+//! which parts made it to disk:
+//!
+//! ```text
+//! sanic-resume 1
+//! 192.168.1.12:40112
+//! <parts> <offset or -> <delta> <dedup> <size or ->
+//! <filename>
+//! <one bit per part stored, in little endian words of 64>
+//! ```
+//!
+//! Restarted with resume on, the receiver takes the transfer back when that sender's Syncs
+//! or parts reach it, and answers with what it has. That answer is one bit per part, not a
+//! hash per region: nothing kept is compared again, and only the parts missing are sent,
+//! which is as little as any narrowing down could send.
+//!
+//! Senders keep theirs in the temporary directory, named after the receiver and the file, with
+//! every part below the last line acknowledged:
+//!
+//! ```text
+//! sanic-send 1
+//! 192.168.1.20:6666
+//! /home/me/disk image.iso
+//! <first byte sent> <bytes sent> <modification time> <parts>
+//! 1742
+//! ```
+//!
+//! Restarted with resume on, the sender takes those parts as acknowledged and goes on from
+//! there. The receiver confirms them, or reports the ones it no longer has.
+
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    ops::Range,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::{merkle, state::Offer};
+
+const MAGIC: &str = "sanic-resume 1";
+const SEND_MAGIC: &str = "sanic-send 1";
+const SUFFIX: &str = ".sanic-resume";
+/// How often the parts stored are saved, at most.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Resume state of the transfer meant for `target`.
+pub(crate) fn path_of(target: &Path) -> PathBuf {
+    let name = target.file_name().unwrap_or_default().to_string_lossy();
+    target.with_file_name(format!("{name}{SUFFIX}"))
+}
+
+/// Whether a transfer of `offer` can be taken back. Verified blocks, groups, messages and
+/// speed tests start over instead.
+pub(crate) fn resumable(offer: &Offer) -> bool {
+    !offer.verify
+        && offer.group.is_none()
+        && !offer.text
+        && !offer.synthetic
+        && offer.parts > 0
+        && !offer.filename.contains('\n')
+}
+
+/// Saves which parts of a transfer are stored, every now and then.
+#[derive(Debug)]
+pub(crate) struct Checkpoint {
+    path: PathBuf,
+    header: String,
+    saved: Instant,
+}
+
+impl Checkpoint {
+    pub fn new(path: PathBuf, peer: SocketAddr, offer: &Offer) -> Self {
+        let optional =
+            |value: Option<u64>| value.map_or_else(|| "-".to_string(), |value| value.to_string());
+        let header = format!(
+            "{MAGIC}\n{peer}\n{} {} {} {} {}\n{}\n",
+            offer.parts,
+            optional(offer.offset),
+            u8::from(offer.delta),
+            u8::from(offer.dedup),
+            optional(offer.size),
+            offer.filename
+        );
+        Checkpoint {
+            path,
+            header,
+            saved: Instant::now(),
+        }
+    }
+
+    /// Whether the last save is old enough for another one.
+    pub fn due(&self) -> bool {
+        self.saved.elapsed() >= CHECKPOINT_INTERVAL
+    }
+
+    /// Replaces what was saved with `stored`, one bit per part on disk, all at once.
+    pub fn save(&mut self, stored: &[u64]) -> io::Result<()> {
+        let mut contents = self.header.clone().into_bytes();
+        for word in stored {
+            contents.extend(word.to_le_bytes());
+        }
+        let name = self.path.file_name().unwrap_or_default().to_string_lossy();
+        let staged = self.path.with_file_name(format!("{name}.tmp"));
+        std::fs::write(&staged, contents)?;
+        std::fs::rename(&staged, &self.path)?;
+        self.saved = Instant::now();
+        Ok(())
+    }
+}
+
+/// A transfer saved by a [`Checkpoint`].
+#[derive(Debug)]
+pub(crate) struct Saved {
+    pub peer: SocketAddr,
+    pub offer: Offer,
+    stored: Vec<u64>,
+}
+
+impl Saved {
+    /// Parts that were on disk.
+    pub fn stored(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.offer.parts).filter(|&id| self.stored[id as usize / 64] & (1 << (id % 64)) != 0)
+    }
+}
+
+pub(crate) fn load(path: &Path) -> io::Result<Saved> {
+    let invalid = || io::Error::new(ErrorKind::InvalidData, "not a resume state");
+    let contents = std::fs::read(path)?;
+    let mut lines = contents.splitn(5, |byte| *byte == b'\n');
+    let mut line = || {
+        lines
+            .next()
+            .and_then(|line| std::str::from_utf8(line).ok())
+            .ok_or_else(invalid)
+    };
+    if line()? != MAGIC {
+        return Err(invalid());
+    }
+    let peer = line()?.parse().map_err(|_| invalid())?;
+    let fields: Vec<&str> = line()?.split(' ').collect();
+    let filename = line()?.to_string();
+    let [parts, offset, delta, dedup, size] = fields[..] else {
+        return Err(invalid());
+    };
+    let parts: u32 = parts.parse().map_err(|_| invalid())?;
+    let optional = |value: &str| match value {
+        "-" => Ok(None),
+        value => value.parse().map(Some).map_err(|_| invalid()),
+    };
+    let offset = optional(offset)?;
+    let size = optional(size)?;
+    let stored = lines.next().ok_or_else(invalid)?;
+    let (words, rest) = stored.as_chunks::<8>();
+    if !rest.is_empty() || words.len() != (parts as usize).div_ceil(64) {
+        return Err(invalid());
+    }
+    Ok(Saved {
+        peer,
+        offer: Offer {
+            filename,
+            parts,
+            group: None,
+            delta: delta == "1",
+            dedup: dedup == "1",
+            verify: false,
+            token: None,
+            offset,
+            text: false,
+            synthetic: false,
+            size,
+        },
+        stored: words.iter().map(|word| u64::from_le_bytes(*word)).collect(),
+    })
+}
+
+/// The transfers saved in `dir`, by the peer that was sending them.
+pub(crate) fn scan(dir: &Path) -> io::Result<HashMap<SocketAddr, Saved>> {
+    let mut saved = HashMap::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.to_string_lossy().ends_with(SUFFIX) {
+            continue;
+        }
+        match load(&path) {
+            Ok(transfer) => {
+                info!(
+                    path = %path.display(),
+                    peer = %transfer.peer,
+                    filename = transfer.offer.filename,
+                    "Found a transfer to take back."
+                );
+                if let Some(previous) = saved.insert(transfer.peer, transfer) {
+                    warn!(
+                        peer = %previous.peer,
+                        filename = previous.offer.filename,
+                        "Another transfer from the same sender was saved, leaving this one."
+                    );
+                }
+            }
+            Err(err) => warn!(path = %path.display(), error = %err, "Could not load resume state."),
+        }
+    }
+    Ok(saved)
+}
+
+/// Saves how far a sender got, every now and then.
+#[derive(Debug)]
+pub(crate) struct SendCheckpoint {
+    path: PathBuf,
+    header: String,
+    saved: Instant,
+    /// Parts below were acknowledged as of the last save.
+    acked_below: u32,
+}
+
+impl SendCheckpoint {
+    /// State of sending the bytes of `file` in `range` to `peer`, in `nb_parts` parts.
+    pub fn new(
+        peer: SocketAddr,
+        file: &Path,
+        range: Range<u64>,
+        nb_parts: u32,
+    ) -> io::Result<Self> {
+        let file = file.canonicalize()?;
+        let modified = std::fs::metadata(&file)?
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let header = format!(
+            "{SEND_MAGIC}\n{peer}\n{}\n{} {} {}.{:09} {nb_parts}\n",
+            file.display(),
+            range.start,
+            range.end - range.start,
+            modified.as_secs(),
+            modified.subsec_nanos()
+        );
+        // The same file sent again to the same receiver, changed or not, replaces its state.
+        let name = Sha256::digest(format!("{peer}\n{}\n{}", file.display(), range.start));
+        let name = &merkle::hex(&name.into())[..32];
+        Ok(SendCheckpoint {
+            path: std::env::temp_dir().join(format!("sanic-send-{name}")),
+            header,
+            saved: Instant::now(),
+            acked_below: 0,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Parts acknowledged in a previous attempt at this very transfer, of a file that did
+    /// not change since.
+    pub fn load(&mut self) -> u32 {
+        let Ok(contents) = std::fs::read_to_string(&self.path) else {
+            return 0;
+        };
+        let Some(acked_below) = contents
+            .strip_prefix(&self.header)
+            .and_then(|rest| rest.trim_end().parse().ok())
+        else {
+            warn!(path = %self.path.display(), "Leaving the state of another transfer.");
+            return 0;
+        };
+        self.acked_below = acked_below;
+        acked_below
+    }
+
+    /// Whether the last save is old enough for another one.
+    pub fn due(&self) -> bool {
+        self.saved.elapsed() >= CHECKPOINT_INTERVAL
+    }
+
+    /// Replaces what was saved with every part below `acked_below` acknowledged.
+    pub fn save(&mut self, acked_below: u32) -> io::Result<()> {
+        self.saved = Instant::now();
+        if acked_below == self.acked_below {
+            return Ok(());
+        }
+        let name = self.path.file_name().unwrap_or_default().to_string_lossy();
+        let staged = self.path.with_file_name(format!("{name}.tmp"));
+        std::fs::write(&staged, format!("{}{acked_below}\n", self.header))?;
+        std::fs::rename(&staged, &self.path)?;
+        self.acked_below = acked_below;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn offer(parts: u32) -> Offer {
+        Offer {
+            filename: "disk image.iso".to_string(),
+            parts,
+            group: None,
+            delta: true,
+            dedup: false,
+            verify: false,
+            token: None,
+            offset: Some(4096),
+            text: false,
+            synthetic: false,
+            size: Some(u64::from(parts) * 1000),
+        }
+    }
+
+    #[test]
+    fn checkpoints_load_back() {
+        let dir = TempDir::new("resume");
+        let path = path_of(&dir.join("disk image.iso"));
+        assert_eq!(path, dir.join("disk image.iso.sanic-resume"));
+        let peer: SocketAddr = "10.0.0.2:40112".parse().unwrap();
+        let mut checkpoint = Checkpoint::new(path.clone(), peer, &offer(70));
+        checkpoint.save(&[0b1011, 1 << 5]).unwrap();
+
+        let saved = scan(dir.path()).unwrap().remove(&peer).unwrap();
+        assert_eq!(saved.offer, offer(70));
+        assert_eq!(saved.stored().collect::<Vec<_>>(), vec![0, 1, 3, 69]);
+    }
+
+    #[test]
+    fn damaged_checkpoints_are_refused() {
+        let dir = TempDir::new("damaged");
+        let path = dir.join("checkpoint");
+        let peer: SocketAddr = "10.0.0.2:40112".parse().unwrap();
+        let mut checkpoint = Checkpoint::new(path.clone(), peer, &offer(70));
+        // A word short.
+        checkpoint.save(&[0b1011]).unwrap();
+        assert_eq!(load(&path).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn senders_go_on_from_the_same_file_only() {
+        let dir = TempDir::new("send-source");
+        let file = dir.write("file", [7; 100]);
+        let peer: SocketAddr = "10.0.0.2:6666".parse().unwrap();
+        let mut checkpoint = SendCheckpoint::new(peer, &file, 0..100, 1).unwrap();
+        assert_eq!(checkpoint.load(), 0);
+        checkpoint.save(1).unwrap();
+        assert_eq!(
+            SendCheckpoint::new(peer, &file, 0..100, 1).unwrap().load(),
+            1
+        );
+        // Another range is another transfer.
+        assert_eq!(
+            SendCheckpoint::new(peer, &file, 0..50, 1).unwrap().load(),
+            0
+        );
+
+        std::fs::write(&file, [8; 90]).unwrap();
+        let mut changed = SendCheckpoint::new(peer, &file, 0..90, 1).unwrap();
+        assert_eq!(changed.path(), checkpoint.path());
+        assert_eq!(changed.load(), 0);
+        std::fs::remove_file(checkpoint.path()).unwrap();
+    }
+}
@@ -0,0 +1,209 @@
+//! When to send parts again. Every part on the wire gets a deadline in a hierarchical
+//! timing wheel, a retransmission timeout after it went out, by when its Ack should be
+//! back. Parts still unacknowledged at their deadline go again, with the timeout doubled
+//! every time.
+
+use std::time::{Duration, Instant};
+
+use crate::server::SYNC_INTERVAL;
+
+/// A level of the wheel covers 6 bits of the tick count.
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+/// Four levels of 1ms ticks reach about 4.6 hours ahead, later deadlines wait in the last.
+const LEVELS: usize = 4;
+const TICK: Duration = Duration::from_millis(1);
+/// Timeout until a round trip was measured, as in RFC 6298.
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+/// Longest the backoff gets, unless a single round trip takes longer.
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Items due at a point in time, kept in slots of growing spans: the first level has a
+/// slot per tick, each next one a slot per lap of the level below. Slots of the upper
+/// levels are spread over the lower ones as time reaches them, so scheduling and expiring
+/// take constant time whatever the number of items.
+#[derive(Debug)]
+pub(crate) struct TimingWheel<T> {
+    start: Instant,
+    /// Ticks since `start` the wheel went up to.
+    now: u64,
+    /// Items along with the tick they are due at, by level then slot.
+    levels: Vec<Vec<Vec<(u64, T)>>>,
+    /// Items in each level.
+    counts: [usize; LEVELS],
+    len: usize,
+}
+
+impl<T> TimingWheel<T> {
+    pub fn new(start: Instant) -> Self {
+        TimingWheel {
+            start,
+            now: 0,
+            levels: (0..LEVELS)
+                .map(|_| (0..SLOTS).map(|_| Vec::new()).collect())
+                .collect(),
+            counts: [0; LEVELS],
+            len: 0,
+        }
+    }
+
+    /// Has `expire` hand back `item` once `deadline` passed, at the next call for one
+    /// already gone.
+    pub fn schedule(&mut self, deadline: Instant, item: T) {
+        let since = deadline.saturating_duration_since(self.start);
+        let tick = since.as_nanos().div_ceil(TICK.as_nanos()) as u64;
+        self.insert(tick.max(self.now + 1), item);
+        self.len += 1;
+    }
+
+    /// The items whose deadline passed at `now`, earliest first.
+    pub fn expire(&mut self, now: Instant) -> Vec<T> {
+        let target =
+            (now.saturating_duration_since(self.start).as_nanos() / TICK.as_nanos()) as u64;
+        let mut expired = Vec::new();
+        while self.now < target {
+            if self.len == 0 {
+                self.now = target;
+                break;
+            }
+            // Nothing happens before the next slot of the lowest level with items.
+            let empty = self.counts.iter().take_while(|&&count| count == 0).count();
+            if empty > 0 {
+                let span = 1 << (empty as u32 * SLOT_BITS);
+                self.now = (self.now / span + 1) * span - 1;
+                if self.now >= target {
+                    self.now = target;
+                    break;
+                }
+            }
+            self.now += 1;
+            // The slots of the upper levels this tick starts go down a level or more.
+            for level in (1..LEVELS).rev() {
+                let shift = level as u32 * SLOT_BITS;
+                if self.now & ((1 << shift) - 1) != 0 {
+                    continue;
+                }
+                let slot = (self.now >> shift) as usize % SLOTS;
+                let items = std::mem::take(&mut self.levels[level][slot]);
+                self.counts[level] -= items.len();
+                for (tick, item) in items {
+                    self.insert(tick, item);
+                }
+            }
+            let slot = self.now as usize % SLOTS;
+            let items = std::mem::take(&mut self.levels[0][slot]);
+            self.counts[0] -= items.len();
+            for (tick, item) in items {
+                if tick <= self.now {
+                    expired.push(item);
+                    self.len -= 1;
+                } else {
+                    self.insert(tick, item);
+                }
+            }
+        }
+        expired
+    }
+
+    /// Puts `item` in the lowest level whose slots still tell `tick` apart from now.
+    fn insert(&mut self, tick: u64, item: T) {
+        let differing = (tick ^ self.now) | (SLOTS as u64 - 1);
+        let level = ((63 - differing.leading_zeros()) / SLOT_BITS) as usize;
+        let level = level.min(LEVELS - 1);
+        let slot = (tick >> (level as u32 * SLOT_BITS)) as usize % SLOTS;
+        self.levels[level][slot].push((tick, item));
+        self.counts[level] += 1;
+    }
+}
+
+/// Retransmission timeout, from the smoothed round trip time and its variation as in
+/// RFC 6298. Receivers acknowledge parts when asked by a Sync, so a Sync interval is
+/// added to the round trip.
+#[derive(Debug, Default)]
+pub(crate) struct Timeout {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+}
+
+impl Timeout {
+    /// A round trip took `rtt`.
+    pub fn sampled(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let deviation = srtt.abs_diff(rtt);
+                self.rttvar = (self.rttvar * 3 + deviation) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+    }
+
+    /// Smoothed round trip time, zero until one was measured.
+    pub fn srtt(&self) -> Duration {
+        self.srtt.unwrap_or_default()
+    }
+
+    /// How long to wait for the Ack of a part sent after `retries` retransmissions.
+    pub fn after(&self, retries: u32) -> Duration {
+        let base = match self.srtt {
+            Some(srtt) => SYNC_INTERVAL + srtt + self.rttvar * 4,
+            None => INITIAL_TIMEOUT,
+        };
+        base.saturating_mul(1 << retries.min(16))
+            .min(MAX_BACKOFF.max(base))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn items_expire_at_their_deadline_in_order() {
+        let start = Instant::now();
+        let mut wheel = TimingWheel::new(start);
+        // One per level, and one past the last.
+        let delays = [5, 300, 20_000, 2_000_000, 20_000_000];
+        for (index, millis) in delays.iter().enumerate().rev() {
+            wheel.schedule(start + Duration::from_millis(*millis), index);
+        }
+        let mut expired = Vec::new();
+        for millis in delays {
+            let at = start + Duration::from_millis(millis);
+            assert!(wheel.expire(at - TICK).is_empty(), "{millis}ms");
+            expired.extend(wheel.expire(at));
+        }
+        assert_eq!(expired, (0..delays.len()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn past_deadlines_expire_at_the_next_call() {
+        let start = Instant::now();
+        let mut wheel = TimingWheel::new(start);
+        wheel.expire(start + Duration::from_millis(100));
+        wheel.schedule(start, 'a');
+        wheel.schedule(start + Duration::from_millis(150), 'b');
+        assert_eq!(wheel.expire(start + Duration::from_millis(101)), vec!['a']);
+        assert_eq!(wheel.expire(start + Duration::from_secs(60)), vec!['b']);
+    }
+
+    #[test]
+    fn timeouts_follow_the_round_trips_and_back_off() {
+        let mut timeout = Timeout::default();
+        assert_eq!(timeout.after(0), INITIAL_TIMEOUT);
+        assert_eq!(timeout.after(1), 2 * INITIAL_TIMEOUT);
+        assert_eq!(timeout.after(10), MAX_BACKOFF);
+
+        timeout.sampled(Duration::from_millis(40));
+        assert_eq!(timeout.srtt(), Duration::from_millis(40));
+        assert_eq!(timeout.after(0), SYNC_INTERVAL + Duration::from_millis(120));
+        for _ in 0..50 {
+            timeout.sampled(Duration::from_millis(40));
+        }
+        assert!(timeout.after(0) < SYNC_INTERVAL + Duration::from_millis(45));
+        assert_eq!(timeout.after(30), MAX_BACKOFF);
+    }
+}
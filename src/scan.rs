@@ -0,0 +1,208 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, Read, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use thiserror::Error;
+
+/// Size of the chunks streamed to the scanner.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Error, Debug)]
+pub enum ScanError {
+    #[error("I/O error while scanning: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("The scanner failed: {0}")]
+    Failed(String),
+}
+
+/// Outcome of a scan.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Clean,
+    /// The scanner flagged the file, with what it found.
+    Infected(String),
+}
+
+/// Where received files are sent for scanning before they are published.
+#[derive(Debug, Clone)]
+pub enum Scanner {
+    /// A clamd daemon listening on this unix socket, fed through its INSTREAM command.
+    Clamd(PathBuf),
+    /// A shell command reading the file on its stdin. Exit status 0 means clean and 1
+    /// means infected, like `clamscan -`; anything else is a failure.
+    Command(String),
+}
+
+impl Scanner {
+    pub fn scan(&self, path: &Path) -> Result<Verdict, ScanError> {
+        let file = File::open(path)?;
+        match self {
+            Scanner::Clamd(socket) => scan_clamd(socket, file),
+            Scanner::Command(command) => scan_command(command, file),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn scan_clamd(socket: &Path, mut file: File) -> Result<Verdict, ScanError> {
+    let mut stream = std::os::unix::net::UnixStream::connect(socket)?;
+    stream.write_all(b"zINSTREAM\0")?;
+    let mut chunk = vec![0; CHUNK_SIZE];
+    loop {
+        let read = file.read(&mut chunk)?;
+        stream.write_all(&(read as u32).to_be_bytes())?;
+        if read == 0 {
+            break;
+        }
+        stream.write_all(&chunk[..read])?;
+    }
+
+    // Replies to z-prefixed commands end with a NUL byte.
+    let mut reply = Vec::new();
+    io::BufReader::new(stream).read_until(b'\0', &mut reply)?;
+    let reply = String::from_utf8_lossy(&reply);
+    // "stream: OK", "stream: <signature> FOUND" or "<reason> ERROR".
+    let reply = reply.trim_end_matches(['\0', '\n']);
+    let status = reply.strip_prefix("stream: ").unwrap_or(reply);
+    if status == "OK" {
+        Ok(Verdict::Clean)
+    } else if let Some(signature) = status.strip_suffix(" FOUND") {
+        Ok(Verdict::Infected(signature.to_string()))
+    } else {
+        Err(ScanError::Failed(reply.to_string()))
+    }
+}
+
+#[cfg(not(unix))]
+fn scan_clamd(_socket: &Path, _file: File) -> Result<Verdict, ScanError> {
+    Err(ScanError::Failed(
+        "clamd sockets are only supported on unix".to_string(),
+    ))
+}
+
+fn scan_command(command: &str, mut file: File) -> Result<Verdict, ScanError> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("Scanner stdin is piped");
+    let feeder = std::thread::spawn(move || -> io::Result<()> {
+        match io::copy(&mut file, &mut stdin) {
+            // The scanner may stop reading as soon as it found something.
+            Err(err) if err.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+            result => result.map(|_| ()),
+        }
+    });
+    let output = child.wait_with_output()?;
+    feeder.join().expect("Scanner feeder panicked")?;
+
+    let report = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    match output.status.code() {
+        Some(0) => Ok(Verdict::Clean),
+        Some(1) => Ok(Verdict::Infected(report)),
+        _ => Err(ScanError::Failed(format!("{}: {report}", output.status))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn commands_give_their_verdict_by_exit_status() {
+        let scanner = Scanner::Command(
+            "if grep -q EVIL; then echo Evil.Test.Signature; exit 1; fi".to_string(),
+        );
+        let dir = TempDir::new("scan");
+        let clean = dir.write("clean", b"holiday pictures");
+        let infected = dir.write("infected", b"something EVIL inside");
+        assert_eq!(scanner.scan(&clean).unwrap(), Verdict::Clean);
+        assert_eq!(
+            scanner.scan(&infected).unwrap(),
+            Verdict::Infected("Evil.Test.Signature".to_string())
+        );
+        let broken = Scanner::Command("cat >/dev/null; echo no database; exit 2".to_string());
+        match broken.scan(&clean) {
+            Err(ScanError::Failed(why)) => assert!(why.ends_with(": no database"), "{why}"),
+            other => panic!("expected the scan to fail, got {other:?}"),
+        }
+        assert!(matches!(
+            scanner.scan(&clean.with_extension("missing")),
+            Err(ScanError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn scanners_may_stop_reading_early() {
+        let dir = TempDir::new("scan-large");
+        let large = dir.write("large", vec![0; 4 * 1024 * 1024]);
+        let scanner =
+            Scanner::Command("head -c 1 >/dev/null; echo Found.Early; exit 1".to_string());
+        assert_eq!(
+            scanner.scan(&large).unwrap(),
+            Verdict::Infected("Found.Early".to_string())
+        );
+    }
+
+    /// Answers one INSTREAM scan like clamd, with `reply`, and returns the bytes streamed.
+    #[cfg(unix)]
+    fn clamd(socket: &Path, reply: &'static str) -> std::thread::JoinHandle<Vec<u8>> {
+        let _ = std::fs::remove_file(socket);
+        let listener = std::os::unix::net::UnixListener::bind(socket).unwrap();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut command = [0; 10];
+            stream.read_exact(&mut command).unwrap();
+            assert_eq!(&command, b"zINSTREAM\0");
+            let mut streamed = Vec::new();
+            loop {
+                let mut size = [0; 4];
+                stream.read_exact(&mut size).unwrap();
+                let size = u32::from_be_bytes(size) as usize;
+                if size == 0 {
+                    break;
+                }
+                let start = streamed.len();
+                streamed.resize(start + size, 0);
+                stream.read_exact(&mut streamed[start..]).unwrap();
+            }
+            stream.write_all(reply.as_bytes()).unwrap();
+            streamed
+        })
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn clamd_gets_the_file_in_chunks() {
+        let dir = TempDir::new("clamd");
+        let socket = dir.join("clamd.sock");
+        let content: Vec<u8> = (0..3 * CHUNK_SIZE as u32 + 5).map(|i| i as u8).collect();
+        let file = dir.write("file", &content);
+        for (reply, verdict) in [
+            ("stream: OK\0", Some(Verdict::Clean)),
+            (
+                "stream: Eicar-Signature FOUND\0",
+                Some(Verdict::Infected("Eicar-Signature".to_string())),
+            ),
+            ("INSTREAM size limit exceeded. ERROR\0", None),
+        ] {
+            let daemon = clamd(&socket, reply);
+            match (Scanner::Clamd(socket.clone()).scan(&file), verdict) {
+                (Ok(found), Some(verdict)) => assert_eq!(found, verdict),
+                (Err(ScanError::Failed(why)), None) => {
+                    assert_eq!(why, "INSTREAM size limit exceeded. ERROR")
+                }
+                (other, _) => panic!("{reply:?} gave {other:?}"),
+            }
+            assert_eq!(daemon.join().unwrap(), content);
+        }
+    }
+}
@@ -1,171 +1,2062 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet},
     fs::File,
-    io::Read,
-    net::UdpSocket,
+    io::{self, ErrorKind, Read, Seek, SeekFrom},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    ops::Range,
+    path::Path,
     sync::{
-        mpsc::{Receiver, Sender},
-        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
-use tracing::{error, info, warn};
+use thiserror::Error;
+use tracing::{debug, error, info, info_span, warn};
 
 use crate::{
-    protocol::{BufferError, Message},
-    BUF_CAPACITY, MTU, PART_SIZE,
+    activity::{Activity, Direction, Session},
+    batch,
+    congestion::Congestion,
+    crypto::{self, SessionKey},
+    dedup,
+    delta::{self, DeltaFile},
+    dscp::Dscp,
+    merkle::{self, Digester},
+    multipath::Paths,
+    pool::BufferPool,
+    protocol::{
+        BlockSum, BufferError, Capabilities, GroupMember, HopStats, ManifestEntry, Message, Mirror,
+        MAX_BLOCK_SUMS, MAX_CHUNK_DIGESTS,
+    },
+    rendezvous::{Meeting, Route},
+    resume::SendCheckpoint,
+    session_id,
+    sim::{impair, Impairments},
+    socket::Socket,
+    spawn,
+    state::{Phase, SenderAction, SenderState, MAX_RETRIES},
+    stats::TransferStats,
+    trace::Tracer,
+    transport::{bind_udp, TcpTransport, Transport, MAX_BATCH},
+    Progress, ProgressCallback, Quota, RateCap, BUF_CAPACITY, MAX_TEXT, MTU, PART_SIZE,
 };
 
-fn read_to_end(file: File, channel: Sender<Vec<u8>>) {
-    let mut file = file;
-    loop {
-        let mut buf: Vec<u8> = vec![0; BUF_CAPACITY];
-        // NOTE: We might not fill the buffer fully
-        // We should keep track of the actual length of the buffer and shrink the buffer initial capacity if we always read less
-        match file.read(&mut buf) {
-            Ok(read) => {
-                if read == 0 {
-                    info!("Reached EOF.");
-                    break;
+pub(crate) const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+pub(crate) const SYNC_INTERVAL: Duration = Duration::from_millis(200);
+/// Packet buffers kept for reuse once acknowledged, about 6MiB worth.
+const POOL_SIZE: usize = 4096;
+/// Requests kept in flight while exchanging with the receiver ahead of a transfer.
+const REQUEST_WINDOW: usize = 32;
+/// Longest the pacer lets parts go back to back.
+const BURST: Duration = Duration::from_millis(1);
+/// How often a paused transfer looks whether it was resumed.
+const PAUSE_POLL: Duration = Duration::from_millis(100);
+/// First buffer the file reader fills, in whole parts.
+const MIN_READ_BUF: usize = 256 * PART_SIZE;
+
+#[derive(Error, Debug)]
+pub enum SendError {
+    #[error("No answer from the receiver after {0} attempts.")]
+    NoAnswer(u32),
+
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("The transfer would exceed the quota of {0} bytes")]
+    Quota(u64),
+
+    #[error("The receiver aborted the transfer: {0}")]
+    Aborted(String),
+
+    #[error("The receiver cannot take the transfer: {0}")]
+    Incompatible(String),
+
+    #[error("The transfer was cancelled")]
+    Cancelled,
+}
+
+/// Sending side of a transfer.
+///
+/// ```no_run
+/// let sender = sanic::Sender::new("192.168.1.20:6666").retries(3);
+/// sender.send("archive.tar".as_ref())?;
+/// # Ok::<(), sanic::SendError>(())
+/// ```
+#[derive(Clone)]
+pub struct Sender {
+    addr: String,
+    bind: String,
+    connect_timeout: Duration,
+    retries: u32,
+    part_retries: u32,
+    tcp_fallback: Option<Duration>,
+    key: Option<SessionKey>,
+    transport: Option<Arc<dyn Transport>>,
+    progress: Option<ProgressCallback>,
+    hop_stats: Option<HopStatsCallback>,
+    stats: Option<StatsCallback>,
+    tracer: Option<Tracer>,
+    chaos: Option<Impairments>,
+    quota: Option<Quota>,
+    cap: Option<RateCap>,
+    stop: Option<Arc<AtomicBool>>,
+    activity: Option<Activity>,
+    streams: usize,
+    paths: Vec<String>,
+    meeting: Option<Meeting>,
+    relay_fallback: Option<Duration>,
+    send_buffer: Option<usize>,
+    recv_buffer: Option<usize>,
+    dscp: Option<Dscp>,
+    congestion: Congestion,
+    pacing: bool,
+    delta: bool,
+    dedup: bool,
+    verify: bool,
+    token: Option<String>,
+    resume: bool,
+}
+
+type HopStatsCallback = Arc<dyn Fn(&HopStats) + Send + Sync>;
+type StatsCallback = Arc<dyn Fn(&TransferStats) + Send + Sync>;
+
+impl Sender {
+    pub fn new(addr: impl Into<String>) -> Self {
+        Sender {
+            addr: addr.into(),
+            bind: "0.0.0.0:6667".to_string(),
+            connect_timeout: Duration::from_secs(30),
+            retries: 8,
+            part_retries: MAX_RETRIES,
+            tcp_fallback: Some(Duration::from_secs(5)),
+            key: None,
+            transport: None,
+            progress: None,
+            hop_stats: None,
+            stats: None,
+            tracer: None,
+            chaos: None,
+            quota: None,
+            cap: None,
+            stop: None,
+            activity: None,
+            streams: 1,
+            paths: Vec::new(),
+            meeting: None,
+            relay_fallback: None,
+            send_buffer: None,
+            recv_buffer: None,
+            dscp: None,
+            congestion: Congestion::default(),
+            pacing: true,
+            delta: false,
+            dedup: false,
+            verify: false,
+            token: None,
+            resume: false,
+        }
+    }
+
+    /// Address of the receiver.
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// Local address the socket is bound to.
+    pub fn bind(mut self, addr: impl Into<String>) -> Self {
+        self.bind = addr.into();
+        self
+    }
+
+    /// Give up if the receiver has not accepted the transfer after this long.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Number of times the Send handshake is retried before giving up.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Number of times a part is sent again without being acknowledged, each time after
+    /// twice as long, before giving up on a receiver that went away.
+    pub fn part_retries(mut self, retries: u32) -> Self {
+        self.part_retries = retries;
+        self
+    }
+
+    /// Switch to TCP when the receiver did not answer over UDP after this long, as some
+    /// networks drop UDP entirely. `None` disables the fallback.
+    pub fn tcp_fallback(mut self, after: Option<Duration>) -> Self {
+        self.tcp_fallback = after;
+        self
+    }
+
+    /// Send through `transport` instead of a UDP socket bound to the `bind` address.
+    pub fn transport(mut self, transport: impl Transport + 'static) -> Self {
+        self.transport = Some(Arc::new(transport));
+        self
+    }
+
+    /// Stop once `limit` bytes went on the wire, retransmissions included, over every
+    /// transfer of this sender and its clones. Files larger than what is left are refused
+    /// before connecting.
+    pub fn max_bytes(mut self, limit: u64) -> Self {
+        self.quota = Some(Quota::new(limit));
+        self
+    }
+
+    /// Stay under `cap` on top of what the receiver allows. It can change while a transfer
+    /// runs.
+    pub fn rate_cap(mut self, cap: RateCap) -> Self {
+        self.cap = Some(cap);
+        self
+    }
+
+    /// Abort the transfer running once `stop` is set, telling the receiver.
+    pub fn stop_on(mut self, stop: Arc<AtomicBool>) -> Self {
+        self.stop = Some(stop);
+        self
+    }
+
+    /// Lists the transfers running in `activity`, where they can be paused, resumed and
+    /// cancelled.
+    pub fn activity(mut self, activity: Activity) -> Self {
+        self.activity = Some(activity);
+        self
+    }
+
+    /// Stripe the parts over `streams` UDP sockets, each with its own source port, so
+    /// multi-queue NICs and ECMP paths spread the load. Only the first one handles the
+    /// handshake. Ignored over TCP or a custom transport.
+    pub fn streams(mut self, streams: usize) -> Self {
+        self.streams = streams.max(1);
+        self
+    }
+
+    /// Also send from `addr`, the local address of another interface, so the transfer
+    /// uses both links and survives one of them going down. Parts are spread over the
+    /// paths by how well each one delivers, and the acknowledgements come back over the
+    /// best one. Ignored over TCP or a custom transport.
+    pub fn path(mut self, addr: impl Into<String>) -> Self {
+        self.paths.push(addr.into());
+        self
+    }
+
+    /// Treat the address given to [`Sender::new`] as a rendezvous server instead, and send
+    /// to the receiver that registers `code` there, wherever it is. Waits up to the
+    /// connect timeout for it. Both sides punch through their NATs, so the transfer goes
+    /// straight between them, but there is no TCP fallback and the extra streams and paths
+    /// only get through NATs that let any port of ours in. The transfer is encrypted with
+    /// a key derived from the code, in place of the one given to [`Sender::key`].
+    ///
+    /// See [`rendezvous::new_code`](crate::rendezvous::new_code) to make one up.
+    pub fn rendezvous(mut self, code: impl Into<String>) -> Self {
+        self.meeting = Some(Meeting::new(code));
+        self
+    }
+
+    /// When meeting the receiver at a rendezvous server, have the server relay the transfer
+    /// if the receiver did not answer directly after this long, as happens when a NAT on
+    /// the way gives every destination its own port. The server has to allow it, and caps
+    /// the rate.
+    pub fn relay_fallback(mut self, after: Option<Duration>) -> Self {
+        self.relay_fallback = after;
+        self
+    }
+
+    /// Encrypt the transfer with a key shared with the receiver.
+    pub fn key(mut self, key: SessionKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// Called every time the receiver acknowledges new parts.
+    pub fn on_progress(mut self, callback: impl Fn(Progress) + Send + Sync + 'static) -> Self {
+        self.progress = Some(Arc::new(callback));
+        self
+    }
+
+    /// Called with the statistics forwarding hops between us and the receiver report, about
+    /// once a second each. They are not authenticated, even in encrypted transfers.
+    pub fn on_hop_stats(mut self, callback: impl Fn(&HopStats) + Send + Sync + 'static) -> Self {
+        self.hop_stats = Some(Arc::new(callback));
+        self
+    }
+
+    /// Called with the statistics of each transfer once it ended, whether it succeeded or
+    /// not, as long as the receiver accepted it.
+    pub fn on_stats(mut self, callback: impl Fn(&TransferStats) + Send + Sync + 'static) -> Self {
+        self.stats = Some(Arc::new(callback));
+        self
+    }
+
+    /// Records every message of the transfers in `tracer`, see [`crate::trace`].
+    pub fn trace_packets(mut self, tracer: Tracer) -> Self {
+        self.tracer = Some(tracer);
+        self
+    }
+
+    /// Puts the datagrams we send through `impairments`, to see how transfers hold up on
+    /// a bad link, see [`Impairments`].
+    pub fn chaos(mut self, impairments: Impairments) -> Self {
+        self.chaos = Some(impairments);
+        self
+    }
+
+    /// Kernel send buffer of each UDP socket, in bytes. Capped by net.core.wmem_max on
+    /// Linux.
+    pub fn send_buffer(mut self, bytes: usize) -> Self {
+        self.send_buffer = Some(bytes);
+        self
+    }
+
+    /// Kernel receive buffer of each UDP socket, in bytes. Capped by net.core.rmem_max on
+    /// Linux.
+    pub fn recv_buffer(mut self, bytes: usize) -> Self {
+        self.recv_buffer = Some(bytes);
+        self
+    }
+
+    /// Marks the datagrams we send with `dscp`, for QoS setups to classify the transfers,
+    /// see [`crate::dscp`].
+    pub fn dscp(mut self, dscp: Dscp) -> Self {
+        self.dscp = Some(dscp);
+        self
+    }
+
+    /// How fast to go when neither the receiver nor the path cap the rate, see
+    /// [`crate::congestion`].
+    pub fn congestion(mut self, congestion: Congestion) -> Self {
+        self.congestion = congestion;
+        self
+    }
+
+    /// Whether to spread the parts out evenly at the rate the receiver takes them, on by
+    /// default. Without it, parts go in batches as fast as the rate limit allows, which
+    /// only suits loopback benchmarks: shallow switch buffers drop the bursts.
+    pub fn pacing(mut self, pacing: bool) -> Self {
+        self.pacing = pacing;
+        self
+    }
+
+    /// Only send what the receiver's copy of a file lacks, when it has an older one. Group
+    /// members always go whole.
+    pub fn delta(mut self, delta: bool) -> Self {
+        self.delta = delta;
+        self
+    }
+
+    /// Cut files into chunks where their content says so, and skip the chunks the
+    /// receiver kept from previous transfers, see [`crate::Receiver::chunk_cache`]. Files
+    /// that go as a delta do not.
+    pub fn dedup(mut self, dedup: bool) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    /// Send the digests of the blocks of parts along with them, so the receiver checks each
+    /// block as it completes and only has the corrupt ones sent again.
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Present `token` to receivers that only take transfers from the senders with it, see
+    /// [`crate::access::Access`]. It goes in the clear unless the transfer is encrypted.
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Save how many parts of each file the receiver acknowledged as it goes, in the
+    /// temporary directory, so that once restarted we go on from there rather than start
+    /// over. Files that go as a delta, deduplicated, verified or encrypted start over.
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    /// Sends `bytes` of synthetic data for a speed test, which the receiver throws away.
+    pub(crate) fn send_synthetic(&self, bytes: u64) -> Result<(), SendError> {
+        let progress = self.progress.clone();
+        self.transfer(Payload::Synthetic(bytes), "speedtest", None, None, progress)
+    }
+
+    /// Sends `file` and returns once every part has been acknowledged by the receiver.
+    pub fn send(&self, file: &Path) -> Result<(), SendError> {
+        let progress = self.progress.clone();
+        self.transfer(Payload::File(file), &file_name(file), None, None, progress)
+    }
+
+    /// Sends `text`, up to [`MAX_TEXT`] bytes, straight from memory: the receiver shows it
+    /// rather than storing it, see [`crate::Receiver::on_text`]. Receivers that do not know
+    /// about text messages store it as `message.txt`.
+    pub fn send_text(&self, text: &str) -> Result<(), SendError> {
+        if text.len() > MAX_TEXT {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("text messages are at most {MAX_TEXT} bytes"),
+            )
+            .into());
+        }
+        let progress = self.progress.clone();
+        self.transfer(Payload::Text(text), "message.txt", None, None, progress)
+    }
+
+    /// Sends the bytes of `file` in `range`, up to its end, which the receiver writes in
+    /// place into its copy of the file.
+    pub fn send_range(&self, file: &Path, range: Range<u64>) -> Result<(), SendError> {
+        let progress = self.progress.clone();
+        self.transfer(
+            Payload::File(file),
+            &file_name(file),
+            None,
+            Some(range),
+            progress,
+        )
+    }
+
+    /// Sends `files` as the transfer group `name`: the receiver publishes them all at once
+    /// after the last one arrived, or none of them if any transfer fails.
+    ///
+    /// Progress is reported over the whole group.
+    pub fn send_group(&self, name: &str, files: &[impl AsRef<Path>]) -> Result<(), SendError> {
+        let count = u32::try_from(files.len())
+            .map_err(|_| std::io::Error::new(ErrorKind::InvalidInput, "Too many files"))?;
+        let mut sizes = Vec::with_capacity(files.len());
+        for file in files {
+            let size = std::fs::metadata(file.as_ref())?.len();
+            sizes.push(size.div_ceil(PART_SIZE as u64) as u32);
+        }
+        let parts_total: u32 = sizes.iter().sum();
+
+        let mut parts_before = 0;
+        for (index, (file, parts)) in files.iter().zip(sizes).enumerate() {
+            let group = GroupMember {
+                name: name.to_string(),
+                index: index as u32,
+                count,
+            };
+            let progress = self.progress.clone().map(|callback| -> ProgressCallback {
+                Arc::new(move |progress: Progress| {
+                    callback(Progress {
+                        parts_done: parts_before + progress.parts_done,
+                        parts_total,
+                    })
+                })
+            });
+            info!(group = name, index, "Sending group member.");
+            let file = file.as_ref();
+            self.transfer(
+                Payload::File(file),
+                &file_name(file),
+                Some(group),
+                None,
+                progress,
+            )?;
+            parts_before += parts;
+        }
+
+        Ok(())
+    }
+
+    /// Sends the directory tree under `root`: announces its files with a manifest, then
+    /// sends the ones the receiver does not already have, each in a transfer of its own.
+    ///
+    /// Progress is reported over the files sent.
+    pub fn send_batch(&self, root: &Path) -> Result<(), SendError> {
+        self.send_tree(root, None)
+    }
+
+    /// Makes the directory of the receiver `mirror` names a copy of the tree under `root`,
+    /// sending only the files it does not have the same of, and removing the ones `root`
+    /// does not have if asked to.
+    pub fn mirror(&self, root: &Path, mirror: &Mirror) -> Result<(), SendError> {
+        self.send_tree(root, Some(mirror))
+    }
+
+    fn send_tree(&self, root: &Path, mirror: Option<&Mirror>) -> Result<(), SendError> {
+        let files = batch::walk(root)?;
+        let entries: Vec<ManifestEntry> = files.iter().map(|(_, entry)| entry.clone()).collect();
+        let have = self.announce(&entries, mirror)?;
+        let missing: Vec<_> = files
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| !have.contains(&(*index as u32)))
+            .map(|(_, file)| file)
+            .collect();
+        info!(
+            files = files.len(),
+            missing = missing.len(),
+            "Receiver took the manifest."
+        );
+        let parts_total: u32 = missing
+            .iter()
+            .map(|(_, entry)| entry.size.div_ceil(PART_SIZE as u64) as u32)
+            .sum();
+
+        let mut parts_before = 0;
+        for (file, entry) in missing {
+            let progress = self.progress.clone().map(|callback| -> ProgressCallback {
+                Arc::new(move |progress: Progress| {
+                    callback(Progress {
+                        parts_done: parts_before + progress.parts_done,
+                        parts_total,
+                    })
+                })
+            });
+            self.transfer(Payload::File(file), &entry.path, None, None, progress)?;
+            parts_before += entry.size.div_ceil(PART_SIZE as u64) as u32;
+        }
+
+        Ok(())
+    }
+
+    fn transfer(
+        &self,
+        payload: Payload,
+        filename: &str,
+        group: Option<GroupMember>,
+        range: Option<Range<u64>>,
+        progress: Option<ProgressCallback>,
+    ) -> Result<(), SendError> {
+        let (handle, reread, len, delta, dedup, source) = match payload {
+            Payload::File(file) => {
+                // Ranges go in place, there is nothing for them to be a delta against.
+                let mut staged = match self.delta && group.is_none() && range.is_none() {
+                    true => self.diff(file, filename)?,
+                    false => None,
+                };
+                let delta = staged.is_some();
+                let dedup = !delta && self.dedup && range.is_none();
+                if dedup {
+                    staged = Some(self.dedupe(file)?);
+                }
+                let handle = match &staged {
+                    Some(staged) => staged.file.try_clone()?,
+                    None => File::open(file)?,
+                };
+                // With a cursor of its own, for parts a restarted receiver lost.
+                let reread = File::open(staged.as_ref().map_or(file, DeltaFile::path))?;
+                let len = handle.metadata()?.len();
+                (Some(handle), Some(reread), len, delta, dedup, Some(file))
+            }
+            // Receivers do not take these back after a restart.
+            Payload::Text(text) => (None, None, text.len() as u64, false, false, None),
+            Payload::Synthetic(bytes) => (None, None, bytes, false, false, None),
+        };
+        let range = match range {
+            Some(range) if range.start > range.end.min(len) => {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "range {}..{} is not within the {len} bytes of the file",
+                        range.start, range.end
+                    ),
+                )
+                .into())
+            }
+            Some(range) => Some(range.start..range.end.min(len)),
+            None => None,
+        };
+        let size = range.as_ref().map_or(len, |range| range.end - range.start);
+        let nb_parts = size.div_ceil(PART_SIZE as u64) as u32;
+        let reread = reread.map(|file| {
+            Arc::new(Reread {
+                file: Mutex::new(file),
+                start: range.as_ref().map_or(0, |range| range.start),
+                size,
+            })
+        });
+        if let Some(quota) = self.quota.as_ref().filter(|quota| size > quota.remaining()) {
+            return Err(SendError::Quota(quota.limit()));
+        }
+
+        let peer: SocketAddr = self.addr.to_socket_addrs()?.next().ok_or_else(|| {
+            std::io::Error::new(ErrorKind::InvalidInput, "Receiver address did not resolve")
+        })?;
+        let filename = filename.to_string();
+        let id = session_id()?;
+        let session = info_span!("session", id, %peer, filename);
+        let _session = session.enter();
+        // Deltas and chunks depend on what the receiver has by then, and a restarted sender
+        // would reuse the nonces of its first attempt.
+        let resumable = self.resume
+            && !delta
+            && !dedup
+            && !self.verify
+            && group.is_none()
+            && self.key.is_none()
+            && self.meeting.is_none();
+        let mut checkpoint = match source.filter(|_| resumable) {
+            Some(file) => {
+                let sent = range.clone().unwrap_or(0..len);
+                Some(SendCheckpoint::new(peer, file, sent, nb_parts)?)
+            }
+            None => None,
+        };
+        let presumed = checkpoint.as_mut().map_or(0, SendCheckpoint::load);
+        if presumed > 0 {
+            info!(parts = presumed, "Going on from a previous attempt.");
+        }
+        let request = Message::Send {
+            filename: filename.clone(),
+            parts: nb_parts,
+            group,
+            delta,
+            dedup,
+            verify: self.verify,
+            token: self.token.clone(),
+            offset: range.as_ref().map(|range| range.start),
+            text: matches!(payload, Payload::Text(_)),
+            synthetic: matches!(payload, Payload::Synthetic(_)),
+            size: Some(size),
+        };
+        let start = Instant::now();
+        let mut state = SenderState::new(nb_parts);
+        state.presume(presumed);
+        state.control(self.congestion);
+        state.max_retries(self.part_retries);
+        if let Some(cap) = &self.cap {
+            state.cap(cap.clone());
+        }
+        let state = Arc::new(Mutex::new(state));
+        let (socket, extra) = self.connect(peer, &request, &state)?;
+        let calls = socket.calls();
+        if let Some(reason) = state.lock().expect("Could not lock state").aborted() {
+            return Err(SendError::Aborted(reason.to_string()));
+        }
+        let capabilities = state
+            .lock()
+            .expect("Could not lock state")
+            .capabilities()
+            .cloned();
+        if let Some(reason) = capabilities
+            .as_ref()
+            .and_then(|capabilities| incompatibility(capabilities, socket.sealed(), self.verify))
+        {
+            abort(&socket, &state, reason.clone());
+            return Err(SendError::Incompatible(reason));
+        }
+        info!(parts = nb_parts, "Receiver accepted the transfer.");
+        debug!(?capabilities, "Receiver capabilities.");
+        let tracked = self
+            .activity
+            .as_ref()
+            .map(|activity| activity.start(Direction::Send, &id, Some(peer), &filename, nb_parts));
+        let progress = match (progress, &tracked) {
+            (progress, None) => progress,
+            (progress, Some(tracked)) => {
+                let listed = Arc::clone(tracked);
+                Some(Arc::new(move |update: Progress| {
+                    listed.progress(update.parts_done);
+                    if let Some(progress) = &progress {
+                        progress(update);
+                    }
+                }) as ProgressCallback)
+            }
+        };
+
+        let mut sockets = vec![socket.try_clone()?];
+        sockets.extend(extra);
+        let paths = (sockets.len() > 1).then(|| Arc::new(Mutex::new(Paths::new(sockets.len()))));
+
+        let finished = Arc::new(AtomicBool::new(false));
+        let (source, reader) = match (handle, payload) {
+            // The parts presumed acknowledged are skipped.
+            (Some(handle), _) if presumed > 0 => {
+                let sent = range.clone().unwrap_or(0..len);
+                let skipped = sent.start + u64::from(presumed) * PART_SIZE as u64;
+                open_source(handle, Some(skipped.min(sent.end)..sent.end))
+            }
+            (Some(handle), _) => open_source(handle, range),
+            (None, Payload::Text(text)) => text_source(text),
+            (None, Payload::Synthetic(bytes)) => synthetic_source(bytes),
+            (None, Payload::File(_)) => unreachable!("Files are opened"),
+        };
+        let pool = BufferPool::new(POOL_SIZE);
+        let buffers = pool.clone();
+        let sender = {
+            let parts = PartSender {
+                sockets: clone_all(&sockets)?,
+                paths: paths.clone(),
+                state: state.clone(),
+                quota: self.quota.clone(),
+                stop: self.stop.clone(),
+                listed: tracked.as_ref().map(|tracked| Arc::clone(tracked)),
+                pool: pool.clone(),
+                part_id: presumed,
+                pacer: Pacer::new(),
+                pacing: self.pacing,
+                digester: self.verify.then(Digester::default),
+            };
+            spawn(move || handle_send(parts, source))
+        };
+        let saved = checkpoint.as_ref().map(|saved| saved.path().to_path_buf());
+        let sync = {
+            let sockets = clone_all(&sockets)?;
+            let paths = paths.clone();
+            let state = state.clone();
+            let finished = finished.clone();
+            let listed = tracked.as_ref().map(|tracked| Arc::clone(tracked));
+            spawn(move || handle_sync(sockets, paths, state, finished, listed, checkpoint))
+        };
+        let answering = Answering {
+            progress,
+            hop_stats: self.hop_stats.clone(),
+            quota: self.quota.clone(),
+            pool,
+            reread,
+        };
+        // The receiver answers over whichever path we last synced on.
+        let mut acks = Vec::new();
+        for (index, path_socket) in sockets.into_iter().enumerate().skip(1) {
+            let path = paths.clone().map(|paths| (index, paths));
+            let state = state.clone();
+            let answering = answering.clone();
+            acks.push(spawn(move || {
+                handle_ack_and_loss(path_socket, path, state, answering)
+            }));
+        }
+
+        let acked = handle_ack_and_loss(
+            socket,
+            paths.clone().map(|paths| (0, paths)),
+            state.clone(),
+            answering,
+        );
+        // The first error any thread ran into ends the transfer.
+        let mut failure = acked.err();
+        // Keep syncing while the other paths are still acknowledging.
+        for thread in acks {
+            let result = thread
+                .join()
+                .unwrap_or_else(|_| Err(std::io::Error::other("a sender thread panicked")));
+            if let Err(err) = result {
+                failure.get_or_insert(err);
+            }
+        }
+        finished.store(true, Ordering::Relaxed);
+
+        if let Some(reader) = reader {
+            if reader.join().is_err() {
+                error!("The file reader thread panicked.");
+            }
+        }
+        for thread in [sender, sync] {
+            let result = thread
+                .join()
+                .unwrap_or_else(|_| Err(std::io::Error::other("a sender thread panicked")));
+            if let Err(err) = result {
+                failure.get_or_insert(err);
+            }
+        }
+        if let Some(paths) = &paths {
+            let paths = paths.lock().expect("Could not lock paths");
+            for (path, stats) in paths.stats().iter().enumerate() {
+                info!(
+                    path,
+                    sent = stats.sent,
+                    acked = stats.acked,
+                    lost = stats.lost,
+                    srtt = ?stats.srtt,
+                    down = stats.down,
+                    "Path statistics."
+                );
+            }
+        }
+        if let Some(callback) = &self.stats {
+            let state = state.lock().expect("Could not lock state");
+            let counts = state.counts();
+            let (send_calls, recv_calls) = calls.get();
+            let (buffers_allocated, buffers_reused) = buffers.counts();
+            callback(&TransferStats {
+                filename,
+                bytes: size,
+                parts: nb_parts,
+                elapsed: start.elapsed(),
+                parts_sent: counts.parts_sent,
+                retransmissions: counts.retransmissions,
+                reported_lost: counts.reported_lost,
+                duplicate_acks: counts.duplicate_acks,
+                rtt: state.rtt(),
+                send_calls,
+                recv_calls,
+                buffers_allocated,
+                buffers_reused,
+            });
+        }
+        if let Some(err) = failure {
+            return Err(err.into());
+        }
+
+        let (aborted, unanswered) = {
+            let state = state.lock().expect("Could not lock state");
+            (state.aborted().map(str::to_string), state.unanswered())
+        };
+        // Gave up on a receiver that went away.
+        if let Some(attempts) = unanswered {
+            return Err(SendError::NoAnswer(attempts));
+        }
+        match aborted {
+            None => {
+                if let Some(saved) = saved {
+                    if let Err(err) = std::fs::remove_file(&saved) {
+                        if err.kind() != ErrorKind::NotFound {
+                            warn!(path = %saved.display(), error = %err, "Could not remove resume state.");
+                        }
+                    }
                 }
-                channel.send(buf).expect("Failed to send buffer to reader.");
+                Ok(())
             }
-            Err(err) => {
-                error!(error = ?err, "Error when reading file.");
-                panic!();
+            Some(_) if self.quota.as_ref().is_some_and(Quota::exceeded) => Err(SendError::Quota(
+                self.quota.as_ref().map_or(0, Quota::limit),
+            )),
+            Some(_) if tracked.is_some_and(|tracked| tracked.is_cancelled()) => {
+                Err(SendError::Cancelled)
             }
+            Some(reason) => Err(SendError::Aborted(reason)),
         }
     }
 }
 
-fn make_parts_packet(data: &[u8], part_id: u32, packet: &mut [u8]) -> Result<(), BufferError> {
-    if data.len() > PART_SIZE {
-        return Err(BufferError::DoesNotFit);
+impl Sender {
+    /// Socket to exchange with the receiver ahead of a transfer.
+    fn control_socket(&self) -> Result<Socket, SendError> {
+        if self.meeting.is_some() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "batches and deltas cannot be sent by code",
+            )
+            .into());
+        }
+        let peer: SocketAddr = self.addr.to_socket_addrs()?.next().ok_or_else(|| {
+            std::io::Error::new(ErrorKind::InvalidInput, "Receiver address did not resolve")
+        })?;
+        let transport: Arc<dyn Transport> = match &self.transport {
+            Some(transport) => transport.clone(),
+            None => bind_udp(&self.bind)?,
+        };
+        let socket = Socket::new(
+            impair(transport, self.chaos, 0),
+            self.key.as_ref(),
+            crypto::SPACE_SENDER,
+        )?
+        .trace(self.tracer.clone());
+        socket.connect(peer);
+        Ok(socket)
+    }
+
+    /// Sends the Manifest of a batch of `entries`, and returns the indices of the ones the
+    /// receiver already has.
+    fn announce(
+        &self,
+        entries: &[ManifestEntry],
+        mirror: Option<&Mirror>,
+    ) -> Result<BTreeSet<u32>, SendError> {
+        let manifests = batch::manifests(entries, mirror, self.token.as_deref())
+            .into_iter()
+            .map(|manifest| {
+                let Message::Manifest { first, .. } = manifest else {
+                    unreachable!("Batches are announced with manifests");
+                };
+                (first, manifest)
+            });
+        self.ask_have(&self.control_socket()?, manifests)
+    }
+
+    /// Cuts `file` into chunks, asks the receiver which of them it kept, and writes the
+    /// delta that sends the others.
+    fn dedupe(&self, file: &Path) -> Result<DeltaFile, SendError> {
+        let chunks = dedup::chunks(File::open(file)?)?;
+        let requests = chunks
+            .chunks(MAX_CHUNK_DIGESTS)
+            .enumerate()
+            .map(|(i, chunks)| {
+                let first = (i * MAX_CHUNK_DIGESTS) as u32;
+                let request = Message::Chunks {
+                    first,
+                    token: self.token.clone(),
+                    digests: chunks.iter().map(|chunk| chunk.sha256).collect(),
+                };
+                (first, request)
+            });
+        let cached = self.ask_have(&self.control_socket()?, requests)?;
+        let delta = DeltaFile::create()?;
+        let encoded = delta::encode_chunks(&chunks, &cached, File::open(file)?, &delta.file)?;
+        info!(
+            chunks = chunks.len(),
+            cached = encoded.copied,
+            literal = encoded.literal,
+            "Sending a chunked delta."
+        );
+        (&delta.file).rewind()?;
+        Ok(delta)
+    }
+
+    /// Sends `requests`, keeping a window of them in flight, until the receiver answered
+    /// each with the Have of the same first index, and returns all the ids it had.
+    fn ask_have(
+        &self,
+        socket: &Socket,
+        requests: impl IntoIterator<Item = (u32, Message)>,
+    ) -> Result<BTreeSet<u32>, SendError> {
+        let mut pending: BTreeMap<u32, Vec<u8>> = requests
+            .into_iter()
+            .map(|(first, request)| (first, request.serialize()))
+            .collect();
+        let mut have = BTreeSet::new();
+        let mut start = Instant::now();
+        let mut backoff = INITIAL_BACKOFF;
+        let mut attempts = 0;
+        while !pending.is_empty() {
+            let remaining = self.remaining(start, attempts)?;
+            attempts += 1;
+            let window: Vec<u32> = pending.keys().take(REQUEST_WINDOW).copied().collect();
+            for first in &window {
+                socket.send(&pending[first])?;
+            }
+            let before = pending.len();
+            let deadline = Instant::now() + backoff.min(remaining);
+            receive_until(socket, deadline, |message| {
+                if let Message::Have { first, ids } = message {
+                    if pending.remove(&first).is_some() {
+                        have.extend(ids);
+                    }
+                }
+                Ok(window.iter().all(|first| !pending.contains_key(first)))
+            })?;
+            if pending.len() < before {
+                start = Instant::now();
+                attempts = 0;
+                backoff = INITIAL_BACKOFF;
+            } else {
+                backoff *= 2;
+            }
+        }
+        Ok(have)
+    }
+
+    /// Fetches the checksums of the receiver's copy of `filename`, and writes the delta
+    /// rebuilding `file` from it. None if the receiver has no copy, or one that shares no
+    /// block with `file`.
+    fn diff(&self, file: &Path, filename: &str) -> Result<Option<DeltaFile>, SendError> {
+        let (block_size, sums) = self.signatures(filename)?;
+        if sums.is_empty() {
+            info!("The receiver has no copy of the file, sending it whole.");
+            return Ok(None);
+        }
+        let delta = DeltaFile::create()?;
+        let source = io::BufReader::new(File::open(file)?);
+        let encoded = delta::encode(&sums, block_size, source, &delta.file)?;
+        if encoded.copied == 0 {
+            info!("The receiver's copy shares nothing with the file, sending it whole.");
+            return Ok(None);
+        }
+        info!(
+            copied = encoded.copied,
+            literal = encoded.literal,
+            "Sending a delta."
+        );
+        (&delta.file).rewind()?;
+        Ok(Some(delta))
+    }
+
+    /// Fetches the block size and the block checksums of the receiver's copy of
+    /// `filename`, keeping a window of Signatures in flight.
+    fn signatures(&self, filename: &str) -> Result<(u32, Vec<BlockSum>), SendError> {
+        let socket = self.control_socket()?;
+        let mut sums: Vec<BlockSum> = Vec::new();
+        // Answers that overtook the ones before them, by first block.
+        let mut early: BTreeMap<u32, Vec<BlockSum>> = BTreeMap::new();
+        let mut expected: Option<(u32, u32)> = None;
+        let mut start = Instant::now();
+        let mut backoff = INITIAL_BACKOFF;
+        let mut attempts = 0;
+        loop {
+            let total = expected.map(|(total, _)| total as usize);
+            if total.is_some_and(|total| sums.len() >= total) {
+                break;
+            }
+            let remaining = self.remaining(start, attempts)?;
+            attempts += 1;
+            // A single one until we know how many blocks there are.
+            let window = match total {
+                Some(_) => REQUEST_WINDOW,
+                None => 1,
+            };
+            for i in 0..window {
+                let first = sums.len() + i * MAX_BLOCK_SUMS;
+                if total.is_some_and(|total| first >= total) {
+                    break;
+                }
+                let mut request = Message::Signatures {
+                    filename: filename.to_string(),
+                    first: first as u32,
+                    token: self.token.clone(),
+                }
+                .serialize();
+                request.resize(MTU, 0);
+                socket.send(&request)?;
+            }
+            let before = sums.len();
+            let deadline = Instant::now() + backoff.min(remaining);
+            receive_until(&socket, deadline, |message| {
+                let Message::Blocks {
+                    first,
+                    total,
+                    block_size,
+                    sums: blocks,
+                } = message
+                else {
+                    return Ok(false);
+                };
+                if total > 0
+                    && !(delta::MIN_BLOCK_SIZE..=delta::MAX_BLOCK_SIZE).contains(&block_size)
+                {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("the receiver checksums blocks of {block_size} bytes"),
+                    )
+                    .into());
+                }
+                if *expected.get_or_insert((total, block_size)) != (total, block_size) {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "the receiver's copy changed while checksumming it",
+                    )
+                    .into());
+                }
+                if first as usize >= sums.len() {
+                    early.insert(first, blocks);
+                }
+                while let Some(blocks) = early.remove(&(sums.len() as u32)) {
+                    if blocks.is_empty() {
+                        break;
+                    }
+                    sums.extend(blocks);
+                }
+                sums.truncate(total as usize);
+                Ok(sums.len() >= (total as usize).min(before + window * MAX_BLOCK_SUMS))
+            })?;
+            if sums.len() > before {
+                // Making progress, however long the whole file takes.
+                start = Instant::now();
+                attempts = 0;
+                backoff = INITIAL_BACKOFF;
+            } else {
+                backoff *= 2;
+            }
+        }
+        let (_, block_size) = expected.unwrap_or_default();
+        Ok((block_size, sums))
     }
 
-    packet[0] = 2;
+    /// Time left to wait for the receiver after `attempts` attempts since `start`.
+    fn remaining(&self, start: Instant, attempts: u32) -> Result<Duration, SendError> {
+        match self.connect_timeout.checked_sub(start.elapsed()) {
+            Some(remaining) if !remaining.is_zero() && attempts <= self.retries => Ok(remaining),
+            _ => Err(SendError::NoAnswer(attempts)),
+        }
+    }
 
-    let pid = part_id.to_be_bytes();
-    packet[1] = pid[0];
-    packet[2] = pid[1];
-    packet[3] = pid[2];
-    packet[4] = pid[3];
+    /// Performs the handshake over UDP, then over TCP or through the rendezvous server if
+    /// the receiver stayed silent, and returns the session socket along with the extra
+    /// streams and paths the parts are spread over. `addr` is the receiver, or the
+    /// rendezvous server we meet it at.
+    fn connect(
+        &self,
+        addr: SocketAddr,
+        request: &Message,
+        state: &Mutex<SenderState>,
+    ) -> Result<(Socket, Vec<Socket>), SendError> {
+        let start = Instant::now();
+        let relay_fallback = self.relay_fallback.filter(|_| self.meeting.is_some());
+        let transport: Arc<dyn Transport> = match &self.transport {
+            Some(transport) => transport.clone(),
+            None => bind_udp(&self.bind)?,
+        };
+        let transport = impair(transport, self.chaos, 0);
+        let (route, key) = match &self.meeting {
+            Some(meeting) => {
+                let (route, key) = meeting.route(&*transport, addr, true, self.connect_timeout)?;
+                (route, Some(key))
+            }
+            None => (Route::Direct(addr), self.key.clone()),
+        };
+        let socket = Socket::new(transport.clone(), key.as_ref(), crypto::SPACE_SENDER)?
+            .trace(self.tracer.clone());
+        socket.size_buffers(self.send_buffer, self.recv_buffer);
+        socket.mark(self.dscp);
+        let peer = match route {
+            Route::Direct(peer) => peer,
+            Route::Relayed {
+                relay,
+                bytes_per_sec,
+            } => {
+                let remaining = self.connect_timeout.saturating_sub(start.elapsed());
+                let relayed = (relay, bytes_per_sec);
+                return self.connect_relayed(socket, relayed, request, state, remaining);
+            }
+        };
+        socket.connect(peer);
+
+        // The receiver's NAT would not let the connection in.
+        let fallback = self
+            .tcp_fallback
+            .filter(|_| self.transport.is_none() && self.meeting.is_none());
+        let udp_timeout = fallback
+            .or(relay_fallback)
+            .map_or(self.connect_timeout, |after| {
+                after.min(self.connect_timeout)
+            });
+        match handshake(&socket, request, state, udp_timeout, self.retries) {
+            Err(SendError::NoAnswer(_)) if fallback.is_some() || relay_fallback.is_some() => {}
+            Err(SendError::NoAnswer(attempts)) if self.meeting.is_some() => {
+                // Its answers would not open with a key derived from another code.
+                warn!("The receiver did not answer, check that it typed the same code.");
+                return Err(SendError::NoAnswer(attempts));
+            }
+            Err(err) => return Err(err),
+            Ok(()) => {
+                let extra = match &self.transport {
+                    Some(_) => Vec::new(),
+                    None => {
+                        let mut extra = open_streams(&socket, peer, self.streams - 1, self.chaos)?;
+                        extra.extend(open_paths(
+                            &socket,
+                            peer,
+                            &self.paths,
+                            self.chaos,
+                            self.streams,
+                        )?);
+                        for socket in &extra {
+                            socket.size_buffers(self.send_buffer, self.recv_buffer);
+                            socket.mark(self.dscp);
+                        }
+                        extra
+                    }
+                };
+                return Ok((socket, extra));
+            }
+        }
+
+        let remaining = self.connect_timeout.saturating_sub(start.elapsed());
+        if remaining.is_zero() {
+            return Err(SendError::NoAnswer(self.retries + 1));
+        }
+        if let (Some(_), Some(meeting)) = (relay_fallback, &self.meeting) {
+            warn!("No answer from the receiver, asking the rendezvous server to relay.");
+            let bytes_per_sec = meeting.relay(&*transport, addr, remaining)?;
+            let remaining = self.connect_timeout.saturating_sub(start.elapsed());
+            let relayed = (addr, bytes_per_sec);
+            return self.connect_relayed(socket, relayed, request, state, remaining);
+        }
+        warn!("No answer over UDP, falling back to TCP.");
+        let stream = TcpStream::connect_timeout(&peer, remaining)?;
+        let tcp = socket.with_transport(Arc::new(TcpTransport::new(stream)?));
+        tcp.mark(self.dscp);
+        tcp.connect(peer);
+        handshake(&tcp, request, state, remaining, self.retries)?;
+        if self.streams > 1 || !self.paths.is_empty() {
+            warn!("The TCP fallback sends over a single stream.");
+        }
+        Ok((tcp, Vec::new()))
+    }
 
-    for i in 0..data.len() {
-        packet[5 + i] = data[i];
+    /// Performs the handshake through the rendezvous server at `relay`, which forwards our
+    /// datagrams to the receiver at up to `bytes_per_sec`.
+    fn connect_relayed(
+        &self,
+        socket: Socket,
+        (relay, bytes_per_sec): (SocketAddr, u64),
+        request: &Message,
+        state: &Mutex<SenderState>,
+        timeout: Duration,
+    ) -> Result<(Socket, Vec<Socket>), SendError> {
+        // Leave room for the sealing overhead and the answers, which the relay counts too.
+        state
+            .lock()
+            .expect("Could not lock state")
+            .limit_path(Some(bytes_per_sec - bytes_per_sec / 10));
+        socket.connect(relay);
+        handshake(&socket, request, state, timeout, self.retries)?;
+        if self.streams > 1 || !self.paths.is_empty() {
+            warn!("Relayed transfers go over a single stream.");
+        }
+        Ok((socket, Vec::new()))
     }
+}
+
+/// Opens `count` more UDP sockets towards `peer` for the same session, bound to the same
+/// address as `socket` but each on a port of its own.
+fn open_streams(
+    socket: &Socket,
+    peer: SocketAddr,
+    count: usize,
+    chaos: Option<Impairments>,
+) -> std::io::Result<Vec<Socket>> {
+    let mut bind = socket.local_addr()?;
+    bind.set_port(0);
+    (1..=count)
+        .map(|stream| {
+            let transport = bind_udp(&bind.to_string())?;
+            let stream = socket.with_transport(impair(transport, chaos, stream as u64));
+            stream.connect(peer);
+            Ok(stream)
+        })
+        .collect()
+}
+
+/// Opens a UDP socket towards `peer` from each of `binds`, the local addresses of other
+/// interfaces, and announces it to the receiver with a Join. The paths come after the
+/// `streams` of the session.
+fn open_paths(
+    socket: &Socket,
+    peer: SocketAddr,
+    binds: &[String],
+    chaos: Option<Impairments>,
+    streams: usize,
+) -> io::Result<Vec<Socket>> {
+    binds
+        .iter()
+        .enumerate()
+        .map(|(i, bind)| {
+            let transport = impair(bind_udp(bind)?, chaos, (streams + i) as u64);
+            let path = socket.with_transport(transport);
+            path.connect(peer);
+            // The sync thread announces it again, the link may only be down for now.
+            if let Err(err) = path.send(&Message::Join.serialize()) {
+                warn!(bind, error = ?err, "Could not join from another path.");
+            }
+            Ok(path)
+        })
+        .collect()
+}
+
+fn clone_all(sockets: &[Socket]) -> io::Result<Vec<Socket>> {
+    sockets.iter().map(Socket::try_clone).collect()
+}
 
+/// Leaves path `index` out after `err`, and hands the error back once no path is left.
+fn drop_path(paths: &Mutex<Paths>, index: usize, err: io::Error) -> io::Result<()> {
+    let mut paths = paths.lock().expect("Could not lock paths");
+    paths.down(index);
+    if !paths.any_up() {
+        return Err(err);
+    }
+    warn!(path = index, error = ?err, "Path failed, leaving it out.");
     Ok(())
 }
 
-fn handle_ack_and_loss(
-    socket: UdpSocket,
-    parts_waiting_ack: Arc<Mutex<Vec<u32>>>,
-    packet_in_flight: Arc<Mutex<HashMap<u32, Vec<u8>>>>,
-) {
+/// Sends `request` until the receiver answers with an Accept, doubling the wait between
+/// attempts, and gives up after `retries` retries or once `connect_timeout` has elapsed.
+/// Hands the messages the receiver sends until `deadline` to `take`, until it returns
+/// true. An Abort fails instead.
+fn receive_until(
+    socket: &Socket,
+    deadline: Instant,
+    mut take: impl FnMut(Message) -> Result<bool, SendError>,
+) -> Result<(), SendError> {
     let mut buf: Vec<u8> = vec![0; MTU];
     loop {
+        let wait = deadline.saturating_duration_since(Instant::now());
+        if wait.is_zero() {
+            return Ok(());
+        }
+        socket.set_read_timeout(Some(wait))?;
         match socket.recv(&mut buf) {
-            Ok(size) => {
-                let data = &buf[..size];
-                match Message::parse(data) {
-                    Ok(Message::Ack { ids }) => {
-                        for id in ids {
-                            let mut acks_guard = parts_waiting_ack
-                                .lock()
-                                .expect("Could not lock on acks array");
-                            if let Ok(id_pos) = acks_guard.binary_search(&id) {
-                                acks_guard.remove(id_pos);
-                            } else {
-                                warn!(id, "received a ack with an id we don't have in our array.");
+            Ok(size) => match Message::parse(&buf[..size]) {
+                Ok(Message::Abort { reason }) => return Err(SendError::Aborted(reason)),
+                Ok(message) => {
+                    if take(message)? {
+                        return Ok(());
+                    }
+                }
+                Err(err) => warn!(error = ?err, "Could not parse packet."),
+            },
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Ok(())
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// What a transfer sends.
+#[derive(Clone, Copy)]
+enum Payload<'a> {
+    File(&'a Path),
+    /// A text message, sent from memory.
+    Text(&'a str),
+    /// As many bytes of a fixed pattern, for a speed test.
+    Synthetic(u64),
+}
+
+/// Name `file` is sent as.
+fn file_name(file: &Path) -> String {
+    file.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+fn handshake(
+    socket: &Socket,
+    request: &Message,
+    state: &Mutex<SenderState>,
+    connect_timeout: Duration,
+    retries: u32,
+) -> Result<(), SendError> {
+    let start = Instant::now();
+    let packet = request.serialize();
+    let mut backoff = INITIAL_BACKOFF;
+    let mut buf: Vec<u8> = vec![0; MTU];
+    let mut attempts = 0;
+    state.lock().expect("Could not lock state").start();
+    while attempts <= retries {
+        let remaining = match connect_timeout.checked_sub(start.elapsed()) {
+            Some(remaining) if !remaining.is_zero() => remaining,
+            _ => break,
+        };
+        attempts += 1;
+        socket.send(&packet)?;
+        state
+            .lock()
+            .expect("Could not lock state")
+            .asked(Instant::now());
+
+        let deadline = Instant::now() + backoff.min(remaining);
+        loop {
+            let wait = deadline.saturating_duration_since(Instant::now());
+            if wait.is_zero() {
+                break;
+            }
+            socket.set_read_timeout(Some(wait))?;
+            match socket.recv(&mut buf) {
+                Ok(size) => match Message::parse(&buf[..size]) {
+                    Ok(msg) => {
+                        let mut state = state.lock().expect("Could not lock state");
+                        if matches!(msg, Message::Accept { .. }) {
+                            state.answered(Instant::now());
+                        }
+                        for action in state.on_message(msg) {
+                            if let SenderAction::Unexpected(msg) = action {
+                                warn!(message = ?msg, "Received unexpected message.");
                             }
                         }
+                        if state.phase() != Phase::Handshaking {
+                            socket.set_read_timeout(None)?;
+                            return Ok(());
+                        }
+                    }
+                    Err(err) => warn!(error = ?err, "Could not parse packet."),
+                },
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    break
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        info!(attempts, "No answer from the receiver, retrying.");
+        backoff *= 2;
+    }
+
+    Err(SendError::NoAnswer(attempts))
+}
+
+/// Reads `file` into `channel`, ending with the error that stopped the reading, if any.
+/// Buffers start at [`MIN_READ_BUF`] and double while the reads fill them, up to
+/// [`BUF_CAPACITY`], so small files and ranges do not allocate for large ones.
+fn read_to_end(file: impl Read, channel: mpsc::Sender<std::io::Result<Vec<u8>>>) {
+    let mut file = file;
+    // Every buffer but the last one must hold a whole number of parts, or the part ids
+    // would no longer map to `id * PART_SIZE` offsets on the receiver.
+    let max = BUF_CAPACITY - BUF_CAPACITY % PART_SIZE;
+    let mut capacity = MIN_READ_BUF.min(max);
+    loop {
+        let mut buf: Vec<u8> = vec![0; capacity];
+        let mut filled = 0;
+        while filled < capacity {
+            match file.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(read) => filled += read,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    error!(error = ?err, "Error when reading file.");
+                    let _ = channel.send(Err(err));
+                    return;
+                }
+            }
+        }
+        if filled == 0 {
+            info!("Reached EOF.");
+            break;
+        }
+        // Only what was read goes on, and the next buffer is sized after it.
+        buf.truncate(filled);
+        capacity = match filled == capacity {
+            true => (capacity * 2).min(max),
+            false => filled.next_multiple_of(PART_SIZE).max(MIN_READ_BUF),
+        };
+        // The transfer was aborted, nobody wants the rest of the file.
+        if channel.send(Ok(buf)).is_err() {
+            break;
+        }
+    }
+}
+
+/// Writes Part `part_id` holding `data` to `packet`, as [`Message::Part`] would serialize
+/// it, replacing what the buffer held. Any recycled buffer does, whatever its length.
+pub(crate) fn make_parts_packet(
+    data: &[u8],
+    part_id: u32,
+    packet: &mut Vec<u8>,
+) -> Result<(), BufferError> {
+    if data.len() > PART_SIZE {
+        return Err(BufferError::DoesNotFit);
+    }
+    packet.clear();
+    packet.push(2);
+    packet.extend_from_slice(&part_id.to_be_bytes());
+    packet.extend_from_slice(data);
+    Ok(())
+}
+
+/// Where parts are read again when a restarted receiver lost them.
+struct Reread {
+    file: Mutex<File>,
+    /// Offset of part 0 in the file, and bytes from there on that are sent.
+    start: u64,
+    size: u64,
+}
+
+impl Reread {
+    /// Writes the packet of part `id` to `packet`.
+    fn part(&self, id: u32, packet: &mut Vec<u8>) -> io::Result<()> {
+        let offset = u64::from(id) * PART_SIZE as u64;
+        if offset >= self.size {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("part {id} is past the end of the file"),
+            ));
+        }
+        let mut data = vec![0; (self.size - offset).min(PART_SIZE as u64) as usize];
+        let mut file = self.file.lock().expect("Could not lock file");
+        file.seek(SeekFrom::Start(self.start + offset))?;
+        file.read_exact(&mut data)?;
+        make_parts_packet(&data, id, packet).map_err(io::Error::other)
+    }
+}
+
+/// What the threads taking the answers of the receiver act on.
+#[derive(Clone)]
+struct Answering {
+    progress: Option<ProgressCallback>,
+    hop_stats: Option<HopStatsCallback>,
+    quota: Option<Quota>,
+    pool: BufferPool,
+    /// None when the payload cannot be read again.
+    reread: Option<Arc<Reread>>,
+}
+
+/// Takes the answers of the receiver coming over `socket`, which is path `path.0` of a
+/// multipath transfer if `path` is set.
+fn handle_ack_and_loss(
+    socket: Socket,
+    path: Option<(usize, Arc<Mutex<Paths>>)>,
+    state: Arc<Mutex<SenderState>>,
+    answering: Answering,
+) -> std::io::Result<()> {
+    let Answering {
+        progress,
+        hop_stats,
+        quota,
+        pool,
+        reread,
+    } = answering;
+    let mut buf: Vec<u8> = vec![0; MTU];
+    // Only the whole transfer fails once no other path is left.
+    let give_up = |err| {
+        match &path {
+            Some((index, paths)) => drop_path(paths, *index, err),
+            None => Err(err),
+        }
+        .map_err(|err| fail(&socket, &state, err))
+    };
+    // Wake up regularly to notice an abort from the other threads.
+    if let Err(err) = socket.set_read_timeout(Some(SYNC_INTERVAL)) {
+        return give_up(err);
+    }
+    while state.lock().expect("Could not lock state").phase() != Phase::Done {
+        let mut actions = match socket.recv(&mut buf) {
+            Ok(size) => match Message::parse(&buf[..size]) {
+                Ok(msg) => {
+                    if let Some((_, paths)) = &path {
+                        let mut paths = paths.lock().expect("Could not lock paths");
+                        match &msg {
+                            Message::Ack { ids } => paths.acked(ids, Instant::now()),
+                            Message::Loss { ids } => paths.lost(ids),
+                            _ => {}
+                        }
                     }
-                    Ok(Message::Loss { ids }) => {
-                        for id in ids {
-                            if let Some(packet) = packet_in_flight
-                                .lock()
-                                .expect("Could not lock packet in flight")
-                                .get(&id)
-                            {
-                                socket
-                                    .send(packet)
-                                    .expect("Failed to send packet to client");
-                            } else {
-                                warn!(id, "Client lost a packet that is not in flight.");
+                    let (actions, released, covered) = {
+                        let mut state = state.lock().expect("Could not lock state");
+                        let answer = matches!(msg, Message::Ack { .. } | Message::Loss { .. });
+                        // Received is not an answer to a Sync, but acknowledges parts.
+                        let covered = match (&msg, &path) {
+                            (Message::Received { below, ranges }, Some(_)) => {
+                                state.covered(*below, ranges)
                             }
+                            _ => Vec::new(),
+                        };
+                        let actions = state.on_message(msg);
+                        // After the message, so congestion control counts the parts it
+                        // acknowledges.
+                        if answer {
+                            state.answered(Instant::now());
+                        }
+                        (actions, state.recycle(), covered)
+                    };
+                    if let Some((_, paths)) = &path {
+                        if !covered.is_empty() {
+                            let mut paths = paths.lock().expect("Could not lock paths");
+                            paths.acked(&covered, Instant::now());
                         }
                     }
-                    Ok(msg) => {
-                        warn!(message = ?msg, "Received unexpected message.");
+                    pool.put_all(released);
+                    actions
+                }
+                Err(err) => {
+                    warn!(error = ?err, "Could not parse packet.");
+                    Vec::new()
+                }
+            },
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                Vec::new()
+            }
+            Err(err) => return give_up(err),
+        };
+        // Parts reported lost or past their retransmission timeout, whether or not
+        // anything came.
+        let due = state
+            .lock()
+            .expect("Could not lock state")
+            .due(Instant::now());
+        match due {
+            Ok(resends) => actions.extend(resends),
+            Err(reason) => {
+                abort(&socket, &state, reason);
+                break;
+            }
+        }
+        for action in actions {
+            match action {
+                SenderAction::Resend(packet) => {
+                    if let Some(quota) = &quota {
+                        // Only the part data counts, like for the first send.
+                        let data = packet.len().saturating_sub(MTU - PART_SIZE);
+                        if !quota.consume(data as u64) {
+                            abort(&socket, &state, quota.reason());
+                            break;
+                        }
+                    }
+                    if let Err(err) = socket.send(&packet) {
+                        return give_up(err);
+                    }
+                }
+                SenderAction::Progress(update) => {
+                    if let Some(progress) = &progress {
+                        progress(update);
                     }
-                    Err(err) => {
-                        warn!(error = ?err, "Could not parse packet.");
+                }
+                SenderAction::RateLimit(Some(bytes_per_sec)) => {
+                    info!(bytes_per_sec, "Receiver capped the rate.");
+                }
+                SenderAction::RateLimit(None) => info!("Receiver lifted the rate cap."),
+                SenderAction::Paused(true) => info!("Receiver paused the transfer."),
+                SenderAction::Paused(false) => info!("Receiver resumed the transfer."),
+                SenderAction::HopStats(stats) => {
+                    debug!(?stats, "Hop statistics.");
+                    if let Some(hop_stats) = &hop_stats {
+                        hop_stats(&stats);
+                    }
+                }
+                SenderAction::Reread(ids) => {
+                    let Some(reread) = &reread else {
+                        let reason = "the receiver lost parts we cannot read again".to_string();
+                        abort(&socket, &state, reason);
+                        break;
+                    };
+                    info!(
+                        parts = ids.len(),
+                        "Receiver lost parts it had, sending them again."
+                    );
+                    for id in ids {
+                        let mut packet = pool.get();
+                        if let Err(err) = reread.part(id, &mut packet) {
+                            return Err(fail(&socket, &state, err));
+                        }
+                        let mut state = state.lock().expect("Could not lock state");
+                        // Acknowledged meanwhile otherwise.
+                        if !state.track_again(id, packet, Instant::now()) {
+                            continue;
+                        }
+                        let packet = state.packet(id).expect("Tracked parts are in flight");
+                        if let Err(err) = socket.send(packet) {
+                            drop(state);
+                            return give_up(err);
+                        }
                     }
                 }
+                SenderAction::Aborted(reason) => {
+                    warn!(reason, "Receiver aborted the transfer.");
+                }
+                SenderAction::Unexpected(msg) => {
+                    warn!(message = ?msg, "Received unexpected message.");
+                }
+            }
+        }
+    }
+    if state
+        .lock()
+        .expect("Could not lock state")
+        .aborted()
+        .is_none()
+    {
+        info!("All parts were acknowledged.");
+    }
+    Ok(())
+}
+
+/// Gives up on the transfer and tells the receiver why.
+fn abort(socket: &Socket, state: &Mutex<SenderState>, reason: String) {
+    warn!(reason, "Aborting the transfer.");
+    state
+        .lock()
+        .expect("Could not lock state")
+        .abort(reason.clone());
+    if let Err(err) = socket.send(&Message::Abort { reason }.serialize()) {
+        warn!(error = ?err, "Could not tell the receiver about the abort.");
+    }
+}
+
+/// Why the receiver cannot take our transfer, going by the `capabilities` it accepted with.
+fn incompatibility(capabilities: &Capabilities, sealed: bool, verify: bool) -> Option<String> {
+    if (capabilities.max_part_size as usize) < PART_SIZE {
+        return Some(format!(
+            "parts of {PART_SIZE} bytes are over the {} the receiver takes",
+            capabilities.max_part_size
+        ));
+    }
+    if sealed
+        && !capabilities
+            .encryption
+            .iter()
+            .any(|name| name == crypto::CIPHER)
+    {
+        return Some(format!("the receiver cannot open {}", crypto::CIPHER));
+    }
+    // It would never see a whole block to verify and acknowledge.
+    if verify
+        && capabilities
+            .max_in_flight
+            .is_some_and(|max| max < merkle::BLOCK_PARTS)
+    {
+        return Some(format!(
+            "verified blocks of {} parts are over the parts in flight the receiver takes",
+            merkle::BLOCK_PARTS
+        ));
+    }
+    None
+}
+
+/// Gives up on the transfer after a local error, so the other threads stop too, and
+/// returns the error.
+fn fail(socket: &Socket, state: &Mutex<SenderState>, err: std::io::Error) -> std::io::Error {
+    error!(error = ?err, "Transfer failed.");
+    abort(socket, state, format!("sender failed: {err}"));
+    err
+}
+
+/// Where the sender thread takes the file contents from.
+pub(crate) enum Source {
+    /// Buffers filled by the reader thread, until it drops the channel at EOF.
+    Read(mpsc::Receiver<std::io::Result<Vec<u8>>>),
+    /// The whole file, mapped in memory.
+    #[cfg(all(target_os = "linux", feature = "mmap"))]
+    Mapped(crate::mmap::Mapping),
+}
+
+/// `text` for the sender thread, all of it in a single buffer.
+fn text_source(text: &str) -> (Source, Option<std::thread::JoinHandle<()>>) {
+    let (chunk_tx, chunk_rx) = mpsc::channel();
+    if !text.is_empty() {
+        let _ = chunk_tx.send(Ok(text.as_bytes().to_vec()));
+    }
+    (Source::Read(chunk_rx), None)
+}
+
+/// `bytes` of synthetic data for the sender thread, from a reader thread like a file.
+fn synthetic_source(bytes: u64) -> (Source, Option<std::thread::JoinHandle<()>>) {
+    let (chunk_tx, chunk_rx) = mpsc::channel();
+    let reader = spawn(move || read_to_end(io::repeat(0xa5).take(bytes), chunk_tx));
+    (Source::Read(chunk_rx), Some(reader))
+}
+
+/// Opens the file for the sender thread, mapped in memory when possible, or through a
+/// reader thread otherwise, reading ahead through io_uring with the `uring` feature. Only
+/// the bytes in `range` are read, if it is given.
+pub(crate) fn open_source(
+    file: File,
+    range: Option<Range<u64>>,
+) -> (Source, Option<std::thread::JoinHandle<()>>) {
+    #[cfg(all(target_os = "linux", feature = "mmap"))]
+    if range.is_none() {
+        match crate::mmap::Mapping::new(&file) {
+            Ok(mapping) => return (Source::Mapped(mapping), None),
+            Err(err) => warn!(error = ?err, "Could not map the file, reading it instead."),
+        }
+    }
+    let (chunk_tx, chunk_rx) = mpsc::channel();
+    let reader = spawn(move || {
+        #[cfg(all(target_os = "linux", feature = "uring"))]
+        if crate::uring::available() {
+            return crate::uring::read_ahead(file, range, chunk_tx);
+        }
+        let mut file = file;
+        match range {
+            Some(range) => match file.seek(SeekFrom::Start(range.start)) {
+                Ok(_) => read_to_end(file.take(range.end - range.start), chunk_tx),
+                Err(err) => {
+                    let _ = chunk_tx.send(Err(err));
+                }
+            },
+            None => read_to_end(file, chunk_tx),
+        }
+    });
+    (Source::Read(chunk_rx), Some(reader))
+}
+
+fn handle_send(mut parts: PartSender, source: Source) -> std::io::Result<()> {
+    match source {
+        Source::Read(channel) => {
+            while let Ok(data) = channel.recv() {
+                let data = data.map_err(|err| fail(&parts.sockets[0], &parts.state, err))?;
+                if !parts.send(&data)? {
+                    return Ok(());
+                }
             }
-            Err(err) => {
-                error!(error = ?err, "Could not receive from udp socket.");
-                panic!();
+        }
+        #[cfg(all(target_os = "linux", feature = "mmap"))]
+        Source::Mapped(mapping) => {
+            if !parts.send(&mapping)? {
+                return Ok(());
             }
         }
     }
+    if let Some(digester) = &mut parts.digester {
+        let last = digester.finish();
+        parts.digested(last.into_iter().collect(), 0);
+    }
+    parts.state.lock().expect("Could not lock state").sent_all();
+    info!(parts = parts.part_id, "All parts sent.");
+    if let Some(root) = parts.digester.as_ref().map(Digester::root) {
+        info!(root = merkle::hex(&root), "Digested every block.");
+    }
+    Ok(())
 }
 
-fn handle_send(
-    socket: UdpSocket,
-    channel: Receiver<Vec<u8>>,
-    parts_waiting_ack: Arc<Mutex<Vec<u32>>>,
-    packet_in_flight: Arc<Mutex<HashMap<u32, Vec<u8>>>>,
-) {
-    let mut part_id: u32 = 0;
-    // TODO: add CRC16
-    loop {
-        match channel.recv() {
-            Ok(data) => {
-                // MTU - 1 (message ID) - 4 (part id)
-                for chunk in data.chunks(PART_SIZE) {
-                    let mut packet_data: Vec<u8> = Vec::with_capacity(MTU);
-                    make_parts_packet(chunk, part_id, &mut packet_data).expect("Chunk too big!");
-                    socket
-                        .send(&packet_data)
-                        .expect("Could not send part to client.");
-                    parts_waiting_ack
-                        .lock()
-                        .expect("Could not lock ack array.")
-                        .push(part_id);
-                    packet_in_flight
-                        .lock()
-                        .expect("Could not lock parts in flight.")
-                        .insert(part_id, packet_data);
-                    part_id += 1;
-                }
-            }
-            Err(err) => {
-                error!(error = ?err, "Error when receiving from channel");
-                break;
+/// Cuts the file contents into parts and puts them on the wire, in batches.
+struct PartSender {
+    /// Sockets the batches are spread over, the session socket first.
+    sockets: Vec<Socket>,
+    /// How well each socket delivers, when there are several.
+    paths: Option<Arc<Mutex<Paths>>>,
+    state: Arc<Mutex<SenderState>>,
+    quota: Option<Quota>,
+    /// Set to abort the transfer.
+    stop: Option<Arc<AtomicBool>>,
+    /// Where the operator pauses or cancels the transfer.
+    listed: Option<Arc<Session>>,
+    pool: BufferPool,
+    /// Id of the next part.
+    part_id: u32,
+    pacer: Pacer,
+    /// Whether parts are spread out at the pacing rate, or only kept under the rate limit.
+    pacing: bool,
+    /// Digests the blocks of a verified transfer.
+    digester: Option<Digester>,
+}
+
+impl PartSender {
+    /// Sends `data`, which starts with the next part, and returns false once the transfer
+    /// was aborted.
+    fn send(&mut self, data: &[u8]) -> std::io::Result<bool> {
+        // TODO: add CRC16
+        let mut rest = data;
+        while !rest.is_empty() {
+            if let Some(listed) = &self.listed {
+                if listed.is_cancelled() {
+                    let reason = "the sender's operator cancelled the transfer".to_string();
+                    abort(&self.sockets[0], &self.state, reason);
+                    return Ok(false);
+                }
+                if listed.is_paused() {
+                    std::thread::sleep(PAUSE_POLL);
+                    continue;
+                }
+            }
+            let (rate, burst) = {
+                let state = self.state.lock().expect("Could not lock state");
+                // Aborted while holding back, paused or with a full window.
+                if state.phase() == Phase::Done {
+                    info!("Transfer aborted, stop sending.");
+                    return Ok(false);
+                }
+                let (rate, burst) = if self.pacing {
+                    let rate = state.pacing_rate();
+                    (Some(rate), Pacer::burst(rate))
+                } else {
+                    (state.rate_limit(), MAX_BATCH)
+                };
+                (rate, state.room().map_or(burst, |room| burst.min(room)))
+            };
+            if burst == 0 {
+                // The receiver takes no more parts in flight until it acknowledges some.
+                std::thread::sleep(BURST);
+                continue;
             }
+            let (parts, tail) = rest.split_at(rest.len().min(PART_SIZE * burst));
+            rest = tail;
+            let mut batch: Vec<Vec<u8>> = Vec::with_capacity(burst);
+            let mut digests = Vec::new();
+            // MTU - 1 (message ID) - 4 (part id)
+            for chunk in parts.chunks(PART_SIZE) {
+                digests.extend(
+                    self.digester
+                        .as_mut()
+                        .and_then(|digester| digester.part(chunk)),
+                );
+                let mut packet_data = self.pool.get();
+                make_parts_packet(chunk, self.part_id + batch.len() as u32, &mut packet_data)
+                    .expect("Chunk too big!");
+                batch.push(packet_data);
+            }
+            if let Some(quota) = &self.quota {
+                if !quota.consume(parts.len() as u64) {
+                    abort(&self.sockets[0], &self.state, quota.reason());
+                    return Ok(false);
+                }
+            }
+            if self
+                .stop
+                .as_ref()
+                .is_some_and(|stop| stop.load(Ordering::Relaxed))
+            {
+                abort(
+                    &self.sockets[0],
+                    &self.state,
+                    "the sender stopped the transfer".to_string(),
+                );
+                return Ok(false);
+            }
+            self.pacer.pace(batch.iter().map(Vec::len).sum(), rate);
+
+            let mut state = self.state.lock().expect("Could not lock state");
+            if state.phase() == Phase::Done {
+                info!("Transfer aborted, stop sending.");
+                return Ok(false);
+            }
+            let first = self.part_id;
+            let now = Instant::now();
+            for packet_data in batch {
+                state.track(self.part_id, packet_data, now);
+                self.part_id += 1;
+            }
+            // Sent under the lock, so an Ack cannot recycle a packet before it is out.
+            let packets: Vec<&[u8]> = (first..self.part_id)
+                .filter_map(|id| state.packet(id))
+                .collect();
+            // A path that fails hands the batch over to the others.
+            loop {
+                let Some(path) = self.pick() else {
+                    drop(state);
+                    let err = io::Error::other("every path failed");
+                    return Err(fail(&self.sockets[0], &self.state, err));
+                };
+                let Err(err) = self.sockets[path].send_batch(&packets) else {
+                    if let Some(paths) = &self.paths {
+                        let mut paths = paths.lock().expect("Could not lock paths");
+                        paths.sent(path, first..self.part_id, Instant::now());
+                    }
+                    break;
+                };
+                let dropped = match &self.paths {
+                    Some(paths) => drop_path(paths, path, err),
+                    None => Err(err),
+                };
+                if let Err(err) = dropped {
+                    drop(state);
+                    return Err(fail(&self.sockets[path], &self.state, err));
+                }
+            }
+            drop(state);
+            self.digested(digests, self.pick().unwrap_or(0));
+        }
+        Ok(true)
+    }
+
+    /// Hands the `digests` of the blocks just sent to the state, which repeats them with its
+    /// Syncs, and sends them a first time over `path`.
+    fn digested(&self, digests: Vec<(u32, [u8; 32])>, path: usize) {
+        let Some(&(first, _)) = digests.first() else {
+            return;
+        };
+        let mut state = self.state.lock().expect("Could not lock state");
+        for &(block, digest) in &digests {
+            state.digested(block, digest);
+        }
+        drop(state);
+        let digests = digests.into_iter().map(|(_, digest)| digest).collect();
+        let message = Message::Digests { first, digests };
+        // Lost or not, the next Sync repeats it.
+        if let Err(err) = self.sockets[path].send(&message.serialize()) {
+            debug!(error = ?err, "Could not send the digests.");
+        }
+    }
+
+    /// Socket for the next batch, or None once every path is down.
+    fn pick(&self) -> Option<usize> {
+        match &self.paths {
+            Some(paths) => paths.lock().expect("Could not lock paths").pick(),
+            None => Some(0),
         }
     }
 }
 
-fn handle_sync(socket: UdpSocket, parts_waiting_ack: Arc<Mutex<Vec<u32>>>) {
-    loop {
-        std::thread::sleep(Duration::from_millis(200));
-        let len = parts_waiting_ack
-            .lock()
-            .expect("Could not lock ack array")
-            .len();
-        let mut ids: Vec<u32> = Vec::with_capacity(len);
-        {
-            let _guard = parts_waiting_ack.lock().expect("Could not lock ack array");
-            for i in _guard.iter() {
-                ids.push(i.clone());
+/// Spaces out packets so they leave at no more than the rate cap, on average.
+pub(crate) struct Pacer {
+    next: Instant,
+}
+
+impl Pacer {
+    pub fn new() -> Self {
+        Pacer {
+            next: Instant::now(),
+        }
+    }
+
+    /// Parts to send back to back at `bytes_per_sec`: as many as leave in [`BURST`], as
+    /// shorter sleeps would mostly oversleep.
+    pub fn burst(bytes_per_sec: u64) -> usize {
+        let parts = bytes_per_sec as f64 * BURST.as_secs_f64() / PART_SIZE as f64;
+        (parts as usize).clamp(1, MAX_BATCH)
+    }
+
+    /// Waits until `len` more bytes may be sent.
+    pub fn pace(&mut self, len: usize, bytes_per_sec: Option<u64>) {
+        let now = Instant::now();
+        let Some(bytes_per_sec) = bytes_per_sec else {
+            self.next = now;
+            return;
+        };
+        if self.next > now {
+            std::thread::sleep(self.next - now);
+        }
+        // Do not save up more than a burst of credit while idle, or the next bursts would
+        // ignore the cap. A burst of it makes up for oversleeping.
+        let earliest = now.checked_sub(BURST).unwrap_or(now);
+        self.next =
+            self.next.max(earliest) + Duration::from_secs_f64(len as f64 / bytes_per_sec as f64);
+    }
+}
+
+fn handle_sync(
+    sockets: Vec<Socket>,
+    paths: Option<Arc<Mutex<Paths>>>,
+    state: Arc<Mutex<SenderState>>,
+    finished: Arc<AtomicBool>,
+    listed: Option<Arc<Session>>,
+    mut checkpoint: Option<SendCheckpoint>,
+) -> std::io::Result<()> {
+    let join = Message::Join.serialize();
+    while !finished.load(Ordering::Relaxed) {
+        std::thread::sleep(SYNC_INTERVAL);
+        let (syncs, acked_below) = {
+            let mut state = state.lock().expect("Could not lock state");
+            if let Some(listed) = &listed {
+                listed.lost(state.counts().reported_lost);
+            }
+            (state.sync(Instant::now()), state.acked_below())
+        };
+        if let Some(checkpoint) = checkpoint.as_mut().filter(|saved| saved.due()) {
+            if let Err(err) = checkpoint.save(acked_below) {
+                warn!(path = %checkpoint.path().display(), error = %err, "Could not save resume state.");
             }
         }
-        let sync_msg = Message::Sync { ids: ids };
-        socket
-            .send(&sync_msg.serialize())
-            .expect("Could not sync message.");
+        // Ask over the path that delivers best, the receiver answers there.
+        let best = match &paths {
+            Some(paths) => {
+                let mut paths = paths.lock().expect("Could not lock paths");
+                paths.expire(Instant::now());
+                match paths.best() {
+                    Some(best) => best,
+                    // The part sender fails the transfer.
+                    None => continue,
+                }
+            }
+            None => 0,
+        };
+        let sent = syncs
+            .iter()
+            .try_for_each(|sync_msg| sockets[best].send(&sync_msg.serialize()).map(drop));
+        if let Err(err) = sent {
+            match &paths {
+                Some(paths) => drop_path(paths, best, err),
+                None => Err(err),
+            }
+            .map_err(|err| fail(&sockets[best], &state, err))?;
+        } else if !syncs.is_empty() {
+            state
+                .lock()
+                .expect("Could not lock state")
+                .asked(Instant::now());
+        }
+        // Joins get lost too, and a path may come back.
+        for path in &sockets[1..] {
+            if let Err(err) = path.send(&join) {
+                debug!(error = ?err, "Could not join from another path.");
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks(len: usize) -> Vec<usize> {
+        let (chunk_tx, chunk_rx) = mpsc::channel();
+        read_to_end(io::repeat(1).take(len as u64), chunk_tx);
+        chunk_rx.iter().map(|chunk| chunk.unwrap().len()).collect()
+    }
+
+    #[test]
+    fn read_buffers_grow_with_the_file() {
+        assert_eq!(chunks(0), Vec::<usize>::new());
+        assert_eq!(chunks(1000), vec![1000]);
+        let max = BUF_CAPACITY - BUF_CAPACITY % PART_SIZE;
+        let sizes = chunks(3 * max);
+        assert_eq!(sizes[..2], [MIN_READ_BUF, 2 * MIN_READ_BUF]);
+        assert!(sizes
+            .iter()
+            .all(|size| size % PART_SIZE == 0 && *size <= max));
+        assert_eq!(sizes.iter().sum::<usize>(), 3 * max);
     }
 }
@@ -0,0 +1,396 @@
+//! Several transfers at once on a single port. A dispatcher hands the datagrams of each
+//! sender to a lane of its own, and every lane is served by a worker running one session
+//! after the other like a lone receiver does. The rate cap is split between the sessions
+//! running, by the weight of their sender.
+
+use std::{
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    access::Cidr,
+    client::RateCap,
+    crypto,
+    dscp::Dscp,
+    transport::{Buffer, Transport, MAX_BATCH},
+    MTU,
+};
+
+/// A sender keeps its lane this long after its last datagram, so the next file of a batch
+/// goes to the worker that took the manifest.
+const STICKY: Duration = Duration::from_secs(5);
+/// How often the dispatcher and idle lanes look whether the receiver stopped.
+const POLL: Duration = Duration::from_millis(200);
+
+type Inbox = mpsc::Sender<(Vec<u8>, SocketAddr)>;
+
+/// How much of the rate cap the senders of a range get, against the others' 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Weight {
+    pub range: Cidr,
+    pub weight: u32,
+}
+
+/// Takes `<range>=<weight>`.
+impl FromStr for Weight {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (range, weight) = s
+            .split_once('=')
+            .ok_or_else(|| format!("{s} is not a range and a weight like 10.0.0.0/8=3"))?;
+        let weight = weight
+            .parse()
+            .ok()
+            .filter(|weight| *weight > 0)
+            .ok_or_else(|| format!("{s} does not have a positive weight"))?;
+        Ok(Weight {
+            range: range.parse()?,
+            weight,
+        })
+    }
+}
+
+/// What the dispatcher knows of a lane.
+struct Slot {
+    inbox: Inbox,
+    /// Sender whose datagrams go to the lane.
+    peer: Option<IpAddr>,
+    /// Last datagram of `peer`.
+    seen: Instant,
+    /// Weight of the session running on the lane, if one is.
+    running: Option<u32>,
+}
+
+pub(crate) struct Scheduler {
+    transport: Arc<dyn Transport>,
+    slots: Mutex<Vec<Slot>>,
+    /// Datagrams of the senders no lane was free for.
+    overflow: Inbox,
+    weights: Vec<Weight>,
+    cap: RateCap,
+    stopped: AtomicBool,
+}
+
+impl Scheduler {
+    /// Splits `transport` into `lanes` lanes, and the overflow one the senders no lane is
+    /// free for end up in.
+    pub fn new(
+        transport: Arc<dyn Transport>,
+        lanes: usize,
+        weights: Vec<Weight>,
+        cap: RateCap,
+    ) -> (Arc<Self>, Vec<Arc<Lane>>, Arc<Lane>) {
+        let (inboxes, receivers): (Vec<_>, Vec<_>) = (0..lanes).map(|_| mpsc::channel()).unzip();
+        let (overflow, turned_away) = mpsc::channel();
+        let scheduler = Arc::new(Scheduler {
+            transport,
+            slots: Mutex::new(
+                inboxes
+                    .into_iter()
+                    .map(|inbox| Slot {
+                        inbox,
+                        peer: None,
+                        seen: Instant::now(),
+                        running: None,
+                    })
+                    .collect(),
+            ),
+            overflow,
+            weights,
+            cap,
+            stopped: AtomicBool::new(false),
+        });
+        let lane = |index, datagrams| {
+            Arc::new(Lane {
+                scheduler: scheduler.clone(),
+                index,
+                datagrams: Mutex::new(datagrams),
+                read_timeout: Mutex::new(None),
+            })
+        };
+        let lanes = receivers
+            .into_iter()
+            .enumerate()
+            .map(|(index, datagrams)| lane(Some(index), datagrams))
+            .collect();
+        let overflow = lane(None, turned_away);
+        (scheduler, lanes, overflow)
+    }
+
+    /// Hands the datagrams we receive to their lanes, until stopped.
+    pub fn dispatch(&self) -> io::Result<()> {
+        self.transport.set_read_timeout(Some(POLL))?;
+        let mut bufs = vec![vec![0; MTU + crypto::OVERHEAD]; MAX_BATCH];
+        while !self.stopped.load(Ordering::Relaxed) {
+            let received = match self.transport.recv_datagrams(&mut bufs) {
+                Ok(received) => received,
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    continue
+                }
+                Err(err) => {
+                    self.stop();
+                    return Err(err);
+                }
+            };
+            let mut slots = self.slots.lock().expect("Could not lock lanes");
+            for (buf, (size, from)) in bufs.iter().zip(received) {
+                let inbox = self.route(&mut slots, from.ip());
+                // A lane gone is just another lost datagram.
+                let _ = inbox.send((buf[..size].to_vec(), from));
+            }
+        }
+        Ok(())
+    }
+
+    /// Lane the datagrams of `peer` go to: the one it already has, or one nobody uses.
+    fn route<'a>(&'a self, slots: &'a mut [Slot], peer: IpAddr) -> &'a Inbox {
+        let now = Instant::now();
+        let lane = slots
+            .iter()
+            .position(|slot| slot.peer == Some(peer))
+            .or_else(|| {
+                slots.iter().position(|slot| {
+                    slot.running.is_none() && (slot.peer.is_none() || now - slot.seen > STICKY)
+                })
+            });
+        match lane {
+            Some(lane) => {
+                let slot = &mut slots[lane];
+                slot.peer = Some(peer);
+                slot.seen = now;
+                &slot.inbox
+            }
+            None => &self.overflow,
+        }
+    }
+
+    /// Ends the dispatching, and the lanes with it.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    fn weight(&self, peer: IpAddr) -> u32 {
+        self.weights
+            .iter()
+            .find(|weight| weight.range.contains(peer))
+            .map_or(1, |weight| weight.weight)
+    }
+}
+
+/// Transport of a worker: the datagrams of the sender its lane is taken by, and sends
+/// through the shared one.
+pub(crate) struct Lane {
+    scheduler: Arc<Scheduler>,
+    /// None for the overflow lane.
+    index: Option<usize>,
+    datagrams: Mutex<mpsc::Receiver<(Vec<u8>, SocketAddr)>>,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl Lane {
+    pub fn is_first(&self) -> bool {
+        self.index == Some(0)
+    }
+
+    /// A session with `peer` runs on the lane, it keeps it until [`Lane::end`].
+    pub fn start(&self, peer: IpAddr) {
+        let Some(index) = self.index else { return };
+        let weight = self.scheduler.weight(peer);
+        let mut slots = self.scheduler.slots.lock().expect("Could not lock lanes");
+        let slot = &mut slots[index];
+        slot.peer = Some(peer);
+        slot.seen = Instant::now();
+        slot.running = Some(weight);
+    }
+
+    pub fn end(&self) {
+        let Some(index) = self.index else { return };
+        let mut slots = self.scheduler.slots.lock().expect("Could not lock lanes");
+        slots[index].running = None;
+        slots[index].seen = Instant::now();
+    }
+
+    /// Share of the rate cap the session running on the lane gets.
+    pub fn share(&self) -> Option<u64> {
+        let cap = self.scheduler.cap.get()?;
+        let slots = self.scheduler.slots.lock().expect("Could not lock lanes");
+        let own = self
+            .index
+            .and_then(|index| slots[index].running)
+            .unwrap_or(1);
+        let total: u32 = slots.iter().filter_map(|slot| slot.running).sum();
+        let share = cap as u128 * u128::from(own) / u128::from(total.max(own));
+        Some((share as u64).max(1))
+    }
+
+    fn next(
+        &self,
+        datagrams: &mpsc::Receiver<(Vec<u8>, SocketAddr)>,
+        deadline: Option<Instant>,
+    ) -> io::Result<(Vec<u8>, SocketAddr)> {
+        loop {
+            if self.scheduler.stopped.load(Ordering::Relaxed) {
+                return Err(ErrorKind::NotConnected.into());
+            }
+            let wait = deadline.map_or(POLL, |deadline| {
+                deadline.saturating_duration_since(Instant::now()).min(POLL)
+            });
+            match datagrams.recv_timeout(wait) {
+                Ok(datagram) => return Ok(datagram),
+                Err(RecvTimeoutError::Timeout)
+                    if deadline.is_some_and(|deadline| Instant::now() >= deadline) =>
+                {
+                    return Err(ErrorKind::WouldBlock.into())
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return Err(ErrorKind::NotConnected.into()),
+            }
+        }
+    }
+}
+
+impl Transport for Lane {
+    fn send_datagram(&self, datagram: &[u8], peer: SocketAddr) -> io::Result<usize> {
+        self.scheduler.transport.send_datagram(datagram, peer)
+    }
+
+    fn recv_datagram(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let timeout = *self.read_timeout.lock().expect("Could not lock timeout");
+        let datagrams = self.datagrams.lock().expect("Could not lock datagrams");
+        let (datagram, from) = self.next(&datagrams, timeout.map(|t| Instant::now() + t))?;
+        let size = datagram.len().min(buf.len());
+        buf[..size].copy_from_slice(&datagram[..size]);
+        Ok((size, from))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        if timeout == Some(Duration::ZERO) {
+            return Err(io::Error::from(ErrorKind::InvalidInput));
+        }
+        *self.read_timeout.lock().expect("Could not lock timeout") = timeout;
+        Ok(())
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.scheduler.transport.local_addr()
+    }
+
+    fn send_datagrams(&self, datagrams: &[&[u8]], peer: SocketAddr) -> io::Result<usize> {
+        self.scheduler.transport.send_datagrams(datagrams, peer)
+    }
+
+    fn recv_datagrams(&self, bufs: &mut [Vec<u8>]) -> io::Result<Vec<(usize, SocketAddr)>> {
+        let timeout = *self.read_timeout.lock().expect("Could not lock timeout");
+        let datagrams = self.datagrams.lock().expect("Could not lock datagrams");
+        let mut received = Vec::new();
+        for buf in bufs.iter_mut() {
+            let (datagram, from) = match received.is_empty() {
+                true => self.next(&datagrams, timeout.map(|t| Instant::now() + t))?,
+                false => match datagrams.try_recv() {
+                    Ok(datagram) => datagram,
+                    Err(_) => break,
+                },
+            };
+            let size = datagram.len().min(buf.len());
+            buf[..size].copy_from_slice(&datagram[..size]);
+            received.push((size, from));
+        }
+        Ok(received)
+    }
+
+    fn buffer(&self, buffer: Buffer) -> io::Result<usize> {
+        self.scheduler.transport.buffer(buffer)
+    }
+
+    fn set_buffer(&self, buffer: Buffer, bytes: usize) -> io::Result<usize> {
+        self.scheduler.transport.set_buffer(buffer, bytes)
+    }
+
+    fn set_dscp(&self, dscp: Dscp) -> io::Result<()> {
+        self.scheduler.transport.set_dscp(dscp)
+    }
+
+    fn bind_another(&self) -> io::Result<Arc<dyn Transport>> {
+        self.scheduler.transport.bind_another()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Link;
+
+    fn recv(lane: &Lane) -> Option<SocketAddr> {
+        let mut buf = [0; MTU];
+        lane.recv_datagram(&mut buf).ok().map(|(_, from)| from)
+    }
+
+    #[test]
+    fn senders_keep_their_lane() {
+        let link = Link::new();
+        let receiver = link.receiver_addr();
+        let transport = Arc::new(link.receiver_end());
+        let (scheduler, lanes, overflow) =
+            Scheduler::new(transport, 2, Vec::new(), RateCap::default());
+        std::thread::spawn({
+            let scheduler = scheduler.clone();
+            move || scheduler.dispatch()
+        });
+        for lane in lanes.iter().chain([&overflow]) {
+            lane.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        }
+
+        let a = link.endpoint("127.0.0.2:1000");
+        let striped = link.endpoint("127.0.0.2:1001");
+        let b = link.endpoint("127.0.0.3:1000");
+        let c = link.endpoint("127.0.0.4:1000");
+        a.send_datagram(b"a", receiver).unwrap();
+        assert_eq!(recv(&lanes[0]), Some(a.local_addr().unwrap()));
+        lanes[0].start(a.local_addr().unwrap().ip());
+        b.send_datagram(b"b", receiver).unwrap();
+        assert_eq!(recv(&lanes[1]), Some(b.local_addr().unwrap()));
+        lanes[1].start(b.local_addr().unwrap().ip());
+        // The other ports of a sender go to its lane too.
+        striped.send_datagram(b"a", receiver).unwrap();
+        assert_eq!(recv(&lanes[0]), Some(striped.local_addr().unwrap()));
+
+        c.send_datagram(b"c", receiver).unwrap();
+        assert_eq!(recv(&overflow), Some(c.local_addr().unwrap()));
+        // A lane whose session ended stays with its sender for a while.
+        lanes[1].end();
+        c.send_datagram(b"c", receiver).unwrap();
+        assert_eq!(recv(&overflow), Some(c.local_addr().unwrap()));
+
+        scheduler.stop();
+        assert!(recv(&lanes[0]).is_none());
+    }
+
+    #[test]
+    fn the_cap_is_shared_by_weight() {
+        let transport = Arc::new(Link::new().receiver_end());
+        let weights = vec!["10.0.0.0/8=3".parse().unwrap()];
+        let (_, lanes, _) = Scheduler::new(transport, 2, weights, RateCap::new(Some(800)));
+        lanes[0].start("192.168.1.1".parse().unwrap());
+        assert_eq!(lanes[0].share(), Some(800));
+        lanes[1].start("10.1.1.1".parse().unwrap());
+        assert_eq!(lanes[0].share(), Some(200));
+        assert_eq!(lanes[1].share(), Some(600));
+        lanes[1].end();
+        assert_eq!(lanes[0].share(), Some(800));
+        assert!("10.0.0.0/8=0".parse::<Weight>().is_err());
+        assert!("10.0.0.0/8".parse::<Weight>().is_err());
+    }
+}
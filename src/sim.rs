@@ -0,0 +1,641 @@
+//! Network impairments for testing: a transport that loses, duplicates, delays and
+//! reorders the datagrams it sends, and holds them to a bandwidth, like a bad link would.
+//!
+//! Every decision comes from a generator seeded by [`Impairments::seed`], so a run that
+//! sends the same datagrams in the same order sees the same losses.
+//!
+//! ```
+//! use sanic::{transport::MemoryNetwork, ImpairedTransport, Impairments};
+//!
+//! let network = MemoryNetwork::new();
+//! let lossy = Impairments { loss: 0.2, seed: 7, ..Impairments::default() };
+//! let transport = ImpairedTransport::new(network.bind("127.0.0.1:0".parse()?)?, lossy);
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! Impairments also parse from a list like `loss=5%,reorder=1%,latency=20ms`, to put a
+//! real socket through them with `--chaos`.
+
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    fmt, io,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use tracing::warn;
+
+use crate::{
+    dscp::Dscp,
+    transport::{Buffer, Transport},
+};
+
+/// How much longer than the others a reordered datagram is held, so the next ones
+/// overtake it.
+const REORDER_DELAY: Duration = Duration::from_millis(5);
+/// Datagrams waiting for the link past which new ones are dropped, like a router's buffer.
+const QUEUE_LIMIT: usize = 1024;
+
+/// What happens to the datagrams sent over an [`ImpairedTransport`].
+///
+/// Parses from comma separated `name=value` pairs: `loss`, `reorder` and `duplicate` as a
+/// share like `5%` or `0.05`, `latency` and `jitter` in `ms` or `s`, `bandwidth` in bytes
+/// per second with an optional K, M or G suffix, and `seed`. A random seed is picked when
+/// none is given, and displayed with the rest so the run can be repeated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Impairments {
+    /// Share of the datagrams lost, from 0 to 1.
+    pub loss: f64,
+    /// Share of the datagrams held back long enough for the next ones to overtake them.
+    pub reorder: f64,
+    /// Share of the datagrams delivered twice.
+    pub duplicate: f64,
+    /// Delay of every datagram.
+    pub latency: Duration,
+    /// Up to this much is added to the latency of each datagram, at random.
+    pub jitter: Duration,
+    /// Bytes per second the link carries, `None` for no limit.
+    pub bandwidth: Option<u64>,
+    pub seed: u64,
+}
+
+impl Default for Impairments {
+    fn default() -> Self {
+        Impairments {
+            loss: 0.0,
+            reorder: 0.0,
+            duplicate: 0.0,
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            bandwidth: None,
+            seed: 0,
+        }
+    }
+}
+
+impl FromStr for Impairments {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, String> {
+        let mut impairments = Impairments::default();
+        let mut seed = None;
+        for pair in spec
+            .split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+        {
+            let (name, value) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected name=value, got '{pair}'"))?;
+            let value = value.trim();
+            match name.trim() {
+                "loss" => impairments.loss = share(value)?,
+                "reorder" => impairments.reorder = share(value)?,
+                "duplicate" => impairments.duplicate = share(value)?,
+                "latency" => impairments.latency = duration(value)?,
+                "jitter" => impairments.jitter = duration(value)?,
+                "bandwidth" => impairments.bandwidth = Some(bandwidth(value)?),
+                "seed" => {
+                    seed = Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("invalid seed '{value}'"))?,
+                    )
+                }
+                name => return Err(format!("unknown impairment '{name}'")),
+            }
+        }
+        impairments.seed = match seed {
+            Some(seed) => seed,
+            None => {
+                let mut seed = [0; 8];
+                crate::crypto::random_bytes(&mut seed).map_err(|err| err.to_string())?;
+                u64::from_be_bytes(seed)
+            }
+        };
+        Ok(impairments)
+    }
+}
+
+impl fmt::Display for Impairments {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let shares = [
+            ("loss", self.loss),
+            ("reorder", self.reorder),
+            ("duplicate", self.duplicate),
+        ];
+        for (name, share) in shares.into_iter().filter(|(_, share)| *share > 0.0) {
+            write!(f, "{name}={}%,", share * 100.0)?;
+        }
+        let durations = [("latency", self.latency), ("jitter", self.jitter)];
+        for (name, duration) in durations.into_iter().filter(|(_, d)| !d.is_zero()) {
+            write!(f, "{name}={}ms,", duration.as_millis())?;
+        }
+        if let Some(bandwidth) = self.bandwidth {
+            write!(f, "bandwidth={bandwidth},")?;
+        }
+        write!(f, "seed={}", self.seed)
+    }
+}
+
+/// `5%` or `0.05`.
+fn share(value: &str) -> Result<f64, String> {
+    let share = match value.strip_suffix('%') {
+        Some(percent) => percent.trim().parse::<f64>().map(|percent| percent / 100.0),
+        None => value.parse(),
+    };
+    share
+        .ok()
+        .filter(|share| (0.0..=1.0).contains(share))
+        .ok_or_else(|| format!("invalid share '{value}', expected 0% to 100%"))
+}
+
+/// `20ms` or `1s`.
+fn duration(value: &str) -> Result<Duration, String> {
+    let parsed = match value.strip_suffix("ms") {
+        Some(millis) => millis.trim().parse().map(Duration::from_millis),
+        None => value
+            .strip_suffix('s')
+            .unwrap_or(value)
+            .trim()
+            .parse()
+            .map(Duration::from_secs),
+    };
+    parsed.map_err(|_| format!("invalid duration '{value}', expected like 20ms or 1s"))
+}
+
+/// `1500`, `10K`, `2M` or `1G` bytes per second.
+fn bandwidth(value: &str) -> Result<u64, String> {
+    let (digits, multiplier) = match value.char_indices().last() {
+        Some((at, 'k' | 'K')) => (&value[..at], 1_000),
+        Some((at, 'm' | 'M')) => (&value[..at], 1_000_000),
+        Some((at, 'g' | 'G')) => (&value[..at], 1_000_000_000),
+        _ => (value, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|value| value.checked_mul(multiplier))
+        .filter(|&bandwidth| bandwidth > 0)
+        .ok_or_else(|| format!("invalid bandwidth '{value}'"))
+}
+
+/// `transport` put through `impairments`, if any. Sessions over several sockets pass each
+/// a different `stream`, so they do not all lose the same datagrams.
+pub(crate) fn impair(
+    transport: Arc<dyn Transport>,
+    impairments: Option<Impairments>,
+    stream: u64,
+) -> Arc<dyn Transport> {
+    match impairments {
+        Some(impairments) => {
+            let seed = impairments.seed.wrapping_add(stream);
+            Arc::new(ImpairedTransport::new(
+                transport,
+                Impairments {
+                    seed,
+                    ..impairments
+                },
+            ))
+        }
+        None => transport,
+    }
+}
+
+/// Transport sending through `T` with [`Impairments`]. Receiving is left untouched, wrap
+/// both ends to impair both directions.
+pub struct ImpairedTransport<T: Transport> {
+    inner: Arc<T>,
+    link: Arc<Link>,
+}
+
+struct Link {
+    impairments: Impairments,
+    state: Mutex<LinkState>,
+    /// Signaled when a datagram is queued, or the transport dropped.
+    changed: Condvar,
+}
+
+struct LinkState {
+    rng: SplitMix64,
+    queue: BinaryHeap<Reverse<Scheduled>>,
+    /// When the link is done sending what is queued, with a bandwidth.
+    free_at: Instant,
+    /// Keeps datagrams due at the same instant in the order they were sent.
+    sent: u64,
+    closed: bool,
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Scheduled {
+    due: Instant,
+    sent: u64,
+    peer: SocketAddr,
+    datagram: Vec<u8>,
+}
+
+impl<T: Transport + 'static> ImpairedTransport<T> {
+    pub fn new(inner: T, impairments: Impairments) -> Self {
+        let inner = Arc::new(inner);
+        let link = Arc::new(Link {
+            impairments,
+            state: Mutex::new(LinkState {
+                rng: SplitMix64(impairments.seed),
+                queue: BinaryHeap::new(),
+                free_at: Instant::now(),
+                sent: 0,
+                closed: false,
+            }),
+            changed: Condvar::new(),
+        });
+        {
+            let (inner, link) = (inner.clone(), link.clone());
+            std::thread::spawn(move || deliver(&*inner, &link));
+        }
+        ImpairedTransport { inner, link }
+    }
+}
+
+impl<T: Transport> Transport for ImpairedTransport<T> {
+    fn send_datagram(&self, datagram: &[u8], peer: SocketAddr) -> io::Result<usize> {
+        let impairments = &self.link.impairments;
+        let mut state = self.link.state.lock().expect("Could not lock link");
+        let now = Instant::now();
+        if state.rng.chance(impairments.loss) {
+            return Ok(datagram.len());
+        }
+        let copies = 1 + usize::from(state.rng.chance(impairments.duplicate));
+        for _ in 0..copies {
+            // A full buffer drops the datagram before it takes up the link.
+            if state.queue.len() >= QUEUE_LIMIT {
+                continue;
+            }
+            let mut due = now;
+            if let Some(bandwidth) = impairments.bandwidth {
+                let on_the_wire = datagram.len() as f64 / bandwidth.max(1) as f64;
+                state.free_at = state.free_at.max(now) + Duration::from_secs_f64(on_the_wire);
+                due = state.free_at;
+            }
+            due += impairments.latency + impairments.jitter.mul_f64(state.rng.next_f64());
+            if state.rng.chance(impairments.reorder) {
+                due += REORDER_DELAY + impairments.jitter;
+            }
+            // Nothing to wait for, and nothing queued it could overtake.
+            if due <= now && state.queue.is_empty() {
+                self.inner.send_datagram(datagram, peer)?;
+                continue;
+            }
+            state.sent += 1;
+            let sent = state.sent;
+            state.queue.push(Reverse(Scheduled {
+                due,
+                sent,
+                peer,
+                datagram: datagram.to_vec(),
+            }));
+            self.link.changed.notify_one();
+        }
+        Ok(datagram.len())
+    }
+
+    fn recv_datagram(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.inner.recv_datagram(buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn recv_datagrams(&self, bufs: &mut [Vec<u8>]) -> io::Result<Vec<(usize, SocketAddr)>> {
+        self.inner.recv_datagrams(bufs)
+    }
+
+    fn buffer(&self, buffer: Buffer) -> io::Result<usize> {
+        self.inner.buffer(buffer)
+    }
+
+    fn set_buffer(&self, buffer: Buffer, bytes: usize) -> io::Result<usize> {
+        self.inner.set_buffer(buffer, bytes)
+    }
+
+    fn set_dscp(&self, dscp: Dscp) -> io::Result<()> {
+        self.inner.set_dscp(dscp)
+    }
+
+    /// The other transport goes without the impairments.
+    fn bind_another(&self) -> io::Result<Arc<dyn Transport>> {
+        self.inner.bind_another()
+    }
+}
+
+impl<T: Transport> Drop for ImpairedTransport<T> {
+    fn drop(&mut self) {
+        self.link.state.lock().expect("Could not lock link").closed = true;
+        self.link.changed.notify_one();
+    }
+}
+
+/// Sends the queued datagrams through `inner` once they are due, until the transport is
+/// dropped.
+fn deliver(inner: &dyn Transport, link: &Link) {
+    let mut state = link.state.lock().expect("Could not lock link");
+    while !state.closed {
+        let now = Instant::now();
+        let wait = match state.queue.peek() {
+            Some(Reverse(next)) if next.due <= now => {
+                let Reverse(next) = state.queue.pop().expect("Peeked a datagram");
+                if let Err(err) = inner.send_datagram(&next.datagram, next.peer) {
+                    warn!(error = ?err, "Could not deliver a delayed datagram.");
+                }
+                continue;
+            }
+            Some(Reverse(next)) => next.due - now,
+            None => Duration::from_secs(3600),
+        };
+        state = link
+            .changed
+            .wait_timeout(state, wait)
+            .expect("Could not lock link")
+            .0;
+    }
+}
+
+/// Small, fast and seedable generator, see <https://prng.di.unimi.it/splitmix64.c>.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in [0, 1).
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.next_f64() < probability
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{Link, TempDir},
+        transport::MemoryNetwork,
+        Receiver, Sender,
+    };
+    use std::io::ErrorKind;
+
+    fn received(transport: &dyn Transport) -> Vec<Vec<u8>> {
+        transport
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let mut buf = [0; 64];
+        let mut datagrams = Vec::new();
+        loop {
+            match transport.recv_datagram(&mut buf) {
+                Ok((size, _)) => datagrams.push(buf[..size].to_vec()),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return datagrams,
+                Err(err) => panic!("{err}"),
+            }
+        }
+    }
+
+    fn send_all(impairments: Impairments, count: u16) -> Vec<Vec<u8>> {
+        let network = MemoryNetwork::new();
+        let to = network.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let from = ImpairedTransport::new(
+            network.bind("127.0.0.1:0".parse().unwrap()).unwrap(),
+            impairments,
+        );
+        for i in 0..count {
+            from.send_datagram(&i.to_be_bytes(), to.local_addr().unwrap())
+                .unwrap();
+        }
+        received(&to)
+    }
+
+    #[test]
+    fn impairments_parse_from_a_list() {
+        let parsed: Impairments =
+            "loss=5%, reorder=0.01,duplicate=50%,latency=20ms,jitter=1s,bandwidth=2M,seed=42"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            parsed,
+            Impairments {
+                loss: 0.05,
+                reorder: 0.01,
+                duplicate: 0.5,
+                latency: Duration::from_millis(20),
+                jitter: Duration::from_secs(1),
+                bandwidth: Some(2_000_000),
+                seed: 42,
+            }
+        );
+        assert_eq!(
+            parsed.to_string(),
+            "loss=5%,reorder=1%,duplicate=50%,latency=20ms,jitter=1000ms,bandwidth=2000000,seed=42"
+        );
+        let seeded: Impairments = "loss=1%".parse().unwrap();
+        assert_eq!(seeded.to_string().parse::<Impairments>(), Ok(seeded));
+
+        assert!("loss=150%".parse::<Impairments>().is_err());
+        assert!("latency=fast".parse::<Impairments>().is_err());
+        assert!("drop=5%".parse::<Impairments>().is_err());
+        assert!("loss".parse::<Impairments>().is_err());
+    }
+
+    #[test]
+    fn losses_follow_the_seed() {
+        let lossy = Impairments {
+            loss: 0.2,
+            seed: 42,
+            ..Impairments::default()
+        };
+        let first = send_all(lossy, 1000);
+        assert!((750..850).contains(&first.len()), "{}", first.len());
+        assert_eq!(send_all(lossy, 1000), first);
+        let other = send_all(Impairments { seed: 43, ..lossy }, 1000);
+        assert_ne!(other, first);
+    }
+
+    #[test]
+    fn datagrams_get_duplicated_and_reordered() {
+        let all = send_all(
+            Impairments {
+                duplicate: 1.0,
+                ..Impairments::default()
+            },
+            10,
+        );
+        assert_eq!(all.len(), 20);
+
+        let reordered = send_all(
+            Impairments {
+                reorder: 0.3,
+                seed: 1,
+                ..Impairments::default()
+            },
+            100,
+        );
+        assert_eq!(reordered.len(), 100);
+        let mut sorted = reordered.clone();
+        sorted.sort();
+        assert_ne!(reordered, sorted);
+        assert_eq!(
+            sorted.concat(),
+            (0..100u16).flat_map(u16::to_be_bytes).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn datagrams_are_delayed_and_held_to_the_bandwidth() {
+        let start = Instant::now();
+        let late = send_all(
+            Impairments {
+                latency: Duration::from_millis(30),
+                ..Impairments::default()
+            },
+            1,
+        );
+        assert_eq!(late.len(), 1);
+
+        // 20 datagrams of 2 bytes at 200 bytes per second take 200ms.
+        let start_slow = Instant::now();
+        let slow = Impairments {
+            bandwidth: Some(200),
+            ..Impairments::default()
+        };
+        let network = MemoryNetwork::new();
+        let to = network.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let from =
+            ImpairedTransport::new(network.bind("127.0.0.1:0".parse().unwrap()).unwrap(), slow);
+        for i in 0..20u16 {
+            from.send_datagram(&i.to_be_bytes(), to.local_addr().unwrap())
+                .unwrap();
+        }
+        to.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let mut buf = [0; 2];
+        for _ in 0..20 {
+            to.recv_datagram(&mut buf).unwrap();
+        }
+        assert!(start_slow.elapsed() >= Duration::from_millis(190));
+        assert!(start.elapsed() >= Duration::from_millis(30));
+    }
+
+    #[test]
+    fn full_links_drop_datagrams_without_holding_the_link() {
+        // 2 bytes at 20KB/s: a full queue drains in about 100ms.
+        let network = MemoryNetwork::new();
+        let to = network.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let from = ImpairedTransport::new(
+            network.bind("127.0.0.1:0".parse().unwrap()).unwrap(),
+            Impairments {
+                bandwidth: Some(20_000),
+                ..Impairments::default()
+            },
+        );
+        let peer = to.local_addr().unwrap();
+        for i in 0..10 * QUEUE_LIMIT {
+            from.send_datagram(&(i as u16).to_be_bytes(), peer).unwrap();
+        }
+        to.set_read_timeout(Some(Duration::from_millis(300)))
+            .unwrap();
+        let mut buf = [0; 2];
+        while to.recv_datagram(&mut buf).is_ok() {}
+        let sent = Instant::now();
+        from.send_datagram(b"ok", peer).unwrap();
+        to.recv_datagram(&mut buf).unwrap();
+        assert_eq!(&buf, b"ok");
+        assert!(sent.elapsed() < Duration::from_millis(100));
+    }
+
+    /// Sends a file of `size` bytes through links with `impairments` both ways, and
+    /// returns what the receiver wrote.
+    fn transfer(name: &str, size: usize, impairments: Impairments) -> (Vec<u8>, Vec<u8>) {
+        let dir = TempDir::new(&format!("sim-{name}"));
+        let output = dir.dir("out");
+        let mut data = vec![0; size];
+        let mut rng = SplitMix64(size as u64);
+        data.iter_mut()
+            .for_each(|byte| *byte = rng.next_u64() as u8);
+        let file = dir.write(name, &data);
+
+        let link = Link::new();
+        let receiver = Receiver::new()
+            .transport(ImpairedTransport::new(
+                link.receiver_end(),
+                Impairments {
+                    seed: impairments.seed + 1,
+                    ..impairments
+                },
+            ))
+            .output(&output)
+            .once(true)
+            .idle_timeout(Duration::from_secs(10));
+        let receiving = std::thread::spawn(move || receiver.receive());
+        Sender::new(link.receiver_addr().to_string())
+            .transport(ImpairedTransport::new(
+                link.endpoint("127.0.0.1:0"),
+                impairments,
+            ))
+            .tcp_fallback(None)
+            .retries(20)
+            .send(&file)
+            .unwrap();
+        receiving.join().unwrap().unwrap();
+
+        let written = std::fs::read(output.join(name)).unwrap();
+        (data, written)
+    }
+
+    #[test]
+    fn transfers_survive_bad_links() {
+        for (loss, seed) in [(0.1, 10), (0.2, 20), (0.3, 30)] {
+            let (sent, received) = transfer(
+                &format!("loss-{seed}"),
+                500_000,
+                Impairments {
+                    loss,
+                    reorder: 0.05,
+                    duplicate: 0.02,
+                    latency: Duration::from_millis(2),
+                    jitter: Duration::from_millis(3),
+                    bandwidth: None,
+                    seed,
+                },
+            );
+            assert!(sent == received, "{loss} loss corrupted the file");
+        }
+    }
+
+    #[test]
+    fn transfers_survive_a_slow_link() {
+        let (sent, received) = transfer(
+            "slow",
+            200_000,
+            Impairments {
+                loss: 0.1,
+                bandwidth: Some(2_000_000),
+                latency: Duration::from_millis(10),
+                seed: 5,
+                ..Impairments::default()
+            },
+        );
+        assert!(sent == received);
+    }
+}
@@ -0,0 +1,280 @@
+//! Where a receiver puts the files it takes instead of its output directory, see
+//! [`Receiver::sink`](crate::Receiver::sink): a file, memory, another process, or object
+//! storage. Sinks that only take bytes in order get them so, the parts arriving ahead held
+//! back until the ones before came.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, ErrorKind, IoSlice, Write},
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::{Arc, Mutex},
+};
+
+use crate::reassembly::WriteAt;
+
+/// Takes the bytes of a received file.
+pub trait Sink: Send {
+    /// Writes `bufs` back to back, starting at `offset` in the file.
+    fn write_at(&mut self, bufs: &[IoSlice], offset: u64) -> io::Result<()>;
+
+    /// Whether every write has to start where the previous one ended.
+    fn is_sequential(&self) -> bool {
+        false
+    }
+
+    /// The whole file was written, `len` bytes of it.
+    fn finish(&mut self, len: u64) -> io::Result<()>;
+
+    /// The transfer failed, what was written is of no use.
+    fn abort(&mut self) {}
+}
+
+impl Sink for File {
+    fn write_at(&mut self, bufs: &[IoSlice], offset: u64) -> io::Result<()> {
+        self.write_all_at(&mut bufs.to_vec(), offset)
+    }
+
+    fn finish(&mut self, len: u64) -> io::Result<()> {
+        self.set_len(len)?;
+        self.sync_data()
+    }
+}
+
+/// Keeps the file in memory. Clones share it.
+#[derive(Debug, Clone, Default)]
+pub struct Memory(Arc<Mutex<Vec<u8>>>);
+
+impl Memory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// What was written so far.
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.lock().expect("Could not lock memory sink").clone()
+    }
+}
+
+impl Sink for Memory {
+    fn write_at(&mut self, bufs: &[IoSlice], offset: u64) -> io::Result<()> {
+        let mut contents = self.0.lock().expect("Could not lock memory sink");
+        let mut at = usize::try_from(offset).map_err(|_| ErrorKind::OutOfMemory)?;
+        for buf in bufs {
+            let end = at + buf.len();
+            if contents.len() < end {
+                contents.resize(end, 0);
+            }
+            contents[at..end].copy_from_slice(buf);
+            at = end;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, len: u64) -> io::Result<()> {
+        let len = usize::try_from(len).map_err(|_| ErrorKind::OutOfMemory)?;
+        self.0
+            .lock()
+            .expect("Could not lock memory sink")
+            .resize(len, 0);
+        Ok(())
+    }
+}
+
+/// Streams the file to a writer, like a socket or a pipe.
+#[derive(Debug)]
+pub struct Pipe<W>(W);
+
+impl<W: Write + Send> Pipe<W> {
+    pub fn new(out: W) -> Self {
+        Pipe(out)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.0
+    }
+}
+
+impl<W: Write + Send> Sink for Pipe<W> {
+    fn write_at(&mut self, bufs: &[IoSlice], _offset: u64) -> io::Result<()> {
+        bufs.iter().try_for_each(|buf| self.0.write_all(buf))
+    }
+
+    fn is_sequential(&self) -> bool {
+        true
+    }
+
+    fn finish(&mut self, _len: u64) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Streams the file to the stdin of a shell command, run in the current directory with
+/// the name of the file in `SANIC_FILENAME` and its size in `SANIC_SIZE`, empty if it is
+/// not known. The command has to exit successfully once stdin is closed.
+#[derive(Debug)]
+pub struct Process {
+    child: Child,
+    stdin: Option<ChildStdin>,
+}
+
+impl Process {
+    pub fn spawn(command: &str, filename: &str, size: Option<u64>) -> io::Result<Self> {
+        let mut child = Command::new("sh")
+            .args(["-c", command])
+            .env("SANIC_FILENAME", filename)
+            .env(
+                "SANIC_SIZE",
+                size.map(|size| size.to_string()).unwrap_or_default(),
+            )
+            .stdin(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take();
+        Ok(Process { child, stdin })
+    }
+}
+
+impl Sink for Process {
+    fn write_at(&mut self, bufs: &[IoSlice], _offset: u64) -> io::Result<()> {
+        let stdin = self.stdin.as_mut().ok_or(ErrorKind::BrokenPipe)?;
+        bufs.iter().try_for_each(|buf| stdin.write_all(buf))
+    }
+
+    fn is_sequential(&self) -> bool {
+        true
+    }
+
+    fn finish(&mut self, _len: u64) -> io::Result<()> {
+        drop(self.stdin.take());
+        let status = self.child.wait()?;
+        if !status.success() {
+            return Err(io::Error::other(format!(
+                "the pipe command exited with {status}"
+            )));
+        }
+        Ok(())
+    }
+
+    fn abort(&mut self) {
+        drop(self.stdin.take());
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Puts the parts of the reassembler in a sink, in order for the sequential ones.
+pub(crate) struct Ordered {
+    sink: Box<dyn Sink>,
+    /// Where the next write of a sequential sink starts.
+    next: u64,
+    /// Runs written ahead of `next`, by where they start.
+    held: BTreeMap<u64, Vec<u8>>,
+}
+
+impl Ordered {
+    pub fn new(sink: Box<dyn Sink>) -> Self {
+        Ordered {
+            sink,
+            next: 0,
+            held: BTreeMap::new(),
+        }
+    }
+
+    /// Finishes the file, which must have come whole.
+    pub fn finish(&mut self, len: u64) -> io::Result<()> {
+        if !self.held.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("bytes from {} on never came", self.next),
+            ));
+        }
+        self.sink.finish(len)
+    }
+
+    pub fn abort(&mut self) {
+        self.sink.abort();
+    }
+}
+
+impl WriteAt for Ordered {
+    fn write_all_at(&mut self, bufs: &mut [IoSlice], offset: u64) -> io::Result<()> {
+        let len: usize = bufs.iter().map(|buf| buf.len()).sum();
+        if len == 0 {
+            return Ok(());
+        }
+        if !self.sink.is_sequential() {
+            return self.sink.write_at(bufs, offset);
+        }
+        if offset != self.next {
+            let run = bufs.iter().flat_map(|buf| buf.iter().copied()).collect();
+            self.held.insert(offset, run);
+            return Ok(());
+        }
+        self.sink.write_at(bufs, offset)?;
+        self.next += len as u64;
+        while let Some(run) = self.held.remove(&self.next) {
+            self.sink.write_at(&[IoSlice::new(&run)], self.next)?;
+            self.next += run.len() as u64;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sequential sink appending what it is given.
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<u8>>>);
+
+    impl Sink for Recorder {
+        fn write_at(&mut self, bufs: &[IoSlice], offset: u64) -> io::Result<()> {
+            let mut written = self.0.lock().unwrap();
+            assert_eq!(written.len() as u64, offset);
+            bufs.iter().for_each(|buf| written.extend_from_slice(buf));
+            Ok(())
+        }
+
+        fn is_sequential(&self) -> bool {
+            true
+        }
+
+        fn finish(&mut self, _len: u64) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn write(ordered: &mut Ordered, offset: u64, data: &[u8]) {
+        ordered
+            .write_all_at(&mut [IoSlice::new(data)], offset)
+            .unwrap();
+    }
+
+    #[test]
+    fn sequential_sinks_get_the_bytes_in_order() {
+        let recorder = Recorder::default();
+        let mut ordered = Ordered::new(Box::new(recorder.clone()));
+        write(&mut ordered, 4, b"efgh");
+        write(&mut ordered, 8, b"ij");
+        assert!(recorder.0.lock().unwrap().is_empty());
+        write(&mut ordered, 0, b"abcd");
+        ordered.finish(10).unwrap();
+        assert_eq!(*recorder.0.lock().unwrap(), b"abcdefghij");
+
+        let mut ordered = Ordered::new(Box::new(Recorder::default()));
+        write(&mut ordered, 4, b"efgh");
+        assert!(ordered.finish(8).is_err());
+    }
+
+    #[test]
+    fn memory_takes_the_bytes_in_any_order() {
+        let memory = Memory::new();
+        let mut ordered = Ordered::new(Box::new(memory.clone()));
+        write(&mut ordered, 6, b"ghij");
+        write(&mut ordered, 0, b"abc");
+        write(&mut ordered, 3, b"def");
+        ordered.finish(8).unwrap();
+        assert_eq!(memory.contents(), b"abcdefgh");
+    }
+}
@@ -0,0 +1,70 @@
+//! Kernel buffer sizes of sockets, SO_SNDBUF and SO_RCVBUF on Linux.
+
+use std::{io, mem, os::fd::AsRawFd};
+
+use crate::transport::Buffer;
+
+fn option(buffer: Buffer) -> libc::c_int {
+    match buffer {
+        Buffer::Send => libc::SO_SNDBUF,
+        Buffer::Recv => libc::SO_RCVBUF,
+    }
+}
+
+/// Size of `buffer`, as asked for. The kernel reports twice as much, the other half going
+/// to its bookkeeping.
+pub(crate) fn get(socket: &impl AsRawFd, buffer: Buffer) -> io::Result<usize> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: `value` and `len` are valid for the duration of the call.
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            option(buffer),
+            &mut value as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value as usize / 2)
+}
+
+/// Asks for `bytes` of `buffer` and returns the size granted, which the kernel caps with
+/// net.core.wmem_max and net.core.rmem_max.
+pub(crate) fn set(socket: &impl AsRawFd, buffer: Buffer, bytes: usize) -> io::Result<usize> {
+    let value = bytes.min(libc::c_int::MAX as usize) as libc::c_int;
+    // SAFETY: `value` is valid for the duration of the call.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            option(buffer),
+            &value as *const _ as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    get(socket, buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+
+    #[test]
+    fn buffers_are_resized() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        for buffer in [Buffer::Send, Buffer::Recv] {
+            // Small enough for the default limits.
+            let granted = set(&socket, buffer, 64 * 1024).unwrap();
+            assert_eq!(granted, 64 * 1024);
+            assert_eq!(get(&socket, buffer).unwrap(), granted);
+        }
+    }
+}
@@ -0,0 +1,403 @@
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tracing::{debug, info, warn};
+
+use crate::{
+    crypto::{self, Cipher, SessionKey},
+    dscp::Dscp,
+    stats::IoCalls,
+    trace::{Direction, Tracer},
+    transport::{Buffer, Transport},
+    MTU,
+};
+
+/// Session view of a transport: talks to a single peer and transparently seals and opens
+/// datagrams when the transfer is encrypted.
+pub(crate) struct Socket {
+    transport: Arc<dyn Transport>,
+    /// Shared by all the clones, datagrams from anybody else are dropped while it is set.
+    peer: Arc<Mutex<Option<SocketAddr>>>,
+    /// Also take datagrams from the other ports of the peer, which stripes its parts over
+    /// several sockets.
+    any_port: bool,
+    /// Other addresses the peer joined from, which we take datagrams from too.
+    paths: Arc<Mutex<Vec<SocketAddr>>>,
+    cipher: Option<Arc<Cipher>>,
+    /// Sequence space of the control messages sent through this socket.
+    space: u8,
+    counter: Arc<AtomicU32>,
+    /// Shared by the clones and the other transports of the session.
+    calls: Arc<IoCalls>,
+    tracer: Option<Tracer>,
+}
+
+impl Socket {
+    /// Fails when there is a `key` but sanic was built without the `crypto` feature.
+    pub fn new(
+        transport: Arc<dyn Transport>,
+        key: Option<&SessionKey>,
+        space: u8,
+    ) -> io::Result<Self> {
+        Ok(Socket {
+            transport,
+            peer: Arc::new(Mutex::new(None)),
+            any_port: false,
+            paths: Arc::new(Mutex::new(Vec::new())),
+            cipher: key.map(Cipher::new).transpose()?.map(Arc::new),
+            space,
+            counter: Arc::new(AtomicU32::new(0)),
+            calls: Arc::new(IoCalls::default()),
+            tracer: None,
+        })
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(Socket {
+            transport: self.transport.clone(),
+            peer: self.peer.clone(),
+            any_port: self.any_port,
+            paths: self.paths.clone(),
+            cipher: self.cipher.clone(),
+            space: self.space,
+            counter: self.counter.clone(),
+            calls: self.calls.clone(),
+            tracer: self.tracer.clone(),
+        })
+    }
+
+    /// Same session over another transport. The sequence counter is shared, so the
+    /// nonces never repeat across both.
+    pub fn with_transport(&self, transport: Arc<dyn Transport>) -> Self {
+        Socket {
+            transport,
+            peer: Arc::new(Mutex::new(None)),
+            any_port: self.any_port,
+            paths: Arc::new(Mutex::new(Vec::new())),
+            cipher: self.cipher.clone(),
+            space: self.space,
+            counter: self.counter.clone(),
+            calls: self.calls.clone(),
+            tracer: self.tracer.clone(),
+        }
+    }
+
+    pub fn transport(&self) -> Arc<dyn Transport> {
+        self.transport.clone()
+    }
+
+    /// Takes datagrams from any port of the peer's host, not only the connected one.
+    pub fn any_port(mut self) -> Self {
+        self.any_port = true;
+        self
+    }
+
+    /// Records the messages we send and receive in `tracer`.
+    pub fn trace(mut self, tracer: Option<Tracer>) -> Self {
+        self.tracer = tracer;
+        self
+    }
+
+    pub fn connect(&self, peer: SocketAddr) {
+        *self.peer.lock().expect("Could not lock peer") = Some(peer);
+        self.paths.lock().expect("Could not lock paths").clear();
+    }
+
+    pub fn disconnect(&self) {
+        *self.peer.lock().expect("Could not lock peer") = None;
+        self.paths.lock().expect("Could not lock paths").clear();
+    }
+
+    fn join(&self, path: SocketAddr) {
+        self.paths.lock().expect("Could not lock paths").push(path);
+    }
+
+    /// Sends to `path`, one the peer joined from, keeping the current one as a path.
+    fn switch(&self, path: SocketAddr) {
+        let mut peer = self.peer.lock().expect("Could not lock peer");
+        let mut paths = self.paths.lock().expect("Could not lock paths");
+        if let Some(previous) = peer.replace(path) {
+            if !paths.contains(&previous) {
+                paths.push(previous);
+            }
+        }
+    }
+
+    /// Whether the datagrams are sealed with a session key.
+    pub fn sealed(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    pub fn peer(&self) -> Option<SocketAddr> {
+        *self.peer.lock().expect("Could not lock peer")
+    }
+
+    /// Whether a datagram from `from` belongs to the session.
+    fn accepts(&self, peer: Option<SocketAddr>, from: SocketAddr) -> bool {
+        match peer {
+            None => true,
+            Some(peer) if peer == from => true,
+            Some(peer) if self.any_port && peer.ip() == from.ip() => true,
+            Some(_) => self
+                .paths
+                .lock()
+                .expect("Could not lock paths")
+                .contains(&from),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.transport.local_addr()
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.transport.set_read_timeout(timeout)
+    }
+
+    pub fn buffer(&self, buffer: Buffer) -> io::Result<usize> {
+        self.transport.buffer(buffer)
+    }
+
+    pub fn set_buffer(&self, buffer: Buffer, bytes: usize) -> io::Result<usize> {
+        self.transport.set_buffer(buffer, bytes)
+    }
+
+    /// Marks what we send with `dscp`, and warns when the transport cannot.
+    pub fn mark(&self, dscp: Option<Dscp>) {
+        let Some(dscp) = dscp else { return };
+        match self.transport.set_dscp(dscp) {
+            Ok(()) => debug!(%dscp, "Marked the socket."),
+            Err(err) => warn!(%dscp, error = ?err, "Could not mark the socket."),
+        }
+    }
+
+    /// Asks for `send` and `recv` bytes of kernel buffers, and warns when the transport has
+    /// none or the kernel granted less.
+    pub fn size_buffers(&self, send: Option<usize>, recv: Option<usize>) {
+        let sizes = [(Buffer::Send, send), (Buffer::Recv, recv)];
+        for (buffer, bytes) in sizes {
+            let Some(bytes) = bytes else { continue };
+            match self.set_buffer(buffer, bytes) {
+                Ok(granted) if granted < bytes => warn!(
+                    ?buffer,
+                    bytes,
+                    granted,
+                    "The kernel capped the socket buffer, see net.core.wmem_max and rmem_max."
+                ),
+                Ok(granted) => debug!(?buffer, granted, "Sized the socket buffer."),
+                Err(err) => warn!(?buffer, error = ?err, "Could not size the socket buffer."),
+            }
+        }
+    }
+
+    /// Sends and receives of the session so far, see [`IoCalls`].
+    pub fn calls(&self) -> Arc<IoCalls> {
+        self.calls.clone()
+    }
+
+    pub fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        let peer = self
+            .peer()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+        self.send_to(packet, peer)
+    }
+
+    /// Sends to `to` rather than to our peer, such as to answer a probe.
+    pub fn send_to(&self, packet: &[u8], to: SocketAddr) -> io::Result<usize> {
+        self.calls.sent();
+        if let Some(tracer) = &self.tracer {
+            tracer.record(Direction::Sent, to, packet);
+        }
+        match &self.cipher {
+            Some(cipher) => self
+                .transport
+                .send_datagram(&self.seal(cipher, packet)?, to),
+            None => self.transport.send_datagram(packet, to),
+        }
+    }
+
+    /// Sends several packets to our peer with as few syscalls as the transport allows.
+    pub fn send_batch(&self, packets: &[impl AsRef<[u8]>]) -> io::Result<usize> {
+        let peer = self
+            .peer()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+        let sealed: Vec<Vec<u8>>;
+        let datagrams: Vec<&[u8]> = match &self.cipher {
+            Some(cipher) => {
+                sealed = packets
+                    .iter()
+                    .map(|packet| self.seal(cipher, packet.as_ref()))
+                    .collect::<io::Result<_>>()?;
+                sealed.iter().map(Vec::as_slice).collect()
+            }
+            None => packets.iter().map(AsRef::as_ref).collect(),
+        };
+        self.calls.sent();
+        if let Some(tracer) = &self.tracer {
+            for packet in packets {
+                tracer.record(Direction::Sent, peer, packet.as_ref());
+            }
+        }
+        self.transport.send_datagrams(&datagrams, peer)
+    }
+
+    /// Receives the next packets from our peer, blocking for the first one only. Packet `i`
+    /// is returned in `bufs[i]` and the sizes tell how many there are.
+    pub fn recv_batch(&self, bufs: &mut [Vec<u8>]) -> io::Result<Vec<usize>> {
+        let mut sealed: Vec<Vec<u8>> = match &self.cipher {
+            Some(_) => vec![vec![0; MTU + crypto::OVERHEAD]; bufs.len()],
+            None => Vec::new(),
+        };
+        loop {
+            self.calls.received();
+            let received = match &self.cipher {
+                Some(_) => self.transport.recv_datagrams(&mut sealed)?,
+                None => self.transport.recv_datagrams(bufs)?,
+            };
+            let mut peer = self.peer();
+            let mut sizes = Vec::with_capacity(received.len());
+            for (i, (size, from)) in received.into_iter().enumerate() {
+                let known = self.accepts(peer, from);
+                // Keep the packets we return at the front.
+                let size = match &self.cipher {
+                    None => {
+                        bufs.swap(sizes.len(), i);
+                        size
+                    }
+                    // Only hop statistics are in the clear, and only from known addresses.
+                    Some(_) if is_hop_stats(&sealed[i][..size]) => {
+                        let buf = &mut bufs[sizes.len()];
+                        if !known || size > buf.len() {
+                            continue;
+                        }
+                        buf[..size].copy_from_slice(&sealed[i][..size]);
+                        sizes.push(size);
+                        continue;
+                    }
+                    Some(cipher) => {
+                        let buf = &mut bufs[sizes.len()];
+                        match cipher.open(&sealed[i][..size]) {
+                            Ok(packet) if packet.len() <= buf.len() => {
+                                buf[..packet.len()].copy_from_slice(&packet);
+                                packet.len()
+                            }
+                            Ok(_) => {
+                                warn!(%from, "Dropping oversized datagram.");
+                                continue;
+                            }
+                            Err(err) => {
+                                warn!(%from, error = ?err, "Dropping datagram.");
+                                continue;
+                            }
+                        }
+                    }
+                };
+                match (bufs[sizes.len()].first(), peer) {
+                    // A Join adds the path it came from, and is not returned.
+                    (Some(&9), Some(_)) => {
+                        if !known {
+                            info!(%from, "Sender joined from another path.");
+                            self.join(from);
+                        }
+                        continue;
+                    }
+                    _ if !known => {
+                        debug!(%from, "Dropping datagram from another peer.");
+                        continue;
+                    }
+                    // The sender asks over its best path, so we answer there. A Send is
+                    // the sender restarted, maybe on another port.
+                    (Some(&0 | &3), Some(current)) if current != from => {
+                        debug!(%from, "Answering on another path.");
+                        self.switch(from);
+                        peer = Some(from);
+                    }
+                    _ => {}
+                }
+                if let Some(tracer) = &self.tracer {
+                    tracer.record(Direction::Received, from, &bufs[sizes.len()][..size]);
+                }
+                sizes.push(size);
+            }
+            if !sizes.is_empty() {
+                return Ok(sizes);
+            }
+        }
+    }
+
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.recv_from(buf).map(|(size, _)| size)
+    }
+
+    /// Receives the next datagram from our peer, silently dropping the ones that come from
+    /// somewhere else or fail authentication.
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (size, from) = self.recv_opened(buf)?;
+        if let Some(tracer) = &self.tracer {
+            tracer.record(Direction::Received, from, &buf[..size]);
+        }
+        Ok((size, from))
+    }
+
+    fn recv_opened(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut sealed: Vec<u8> = match &self.cipher {
+            Some(_) => vec![0; MTU + crypto::OVERHEAD],
+            None => Vec::new(),
+        };
+        loop {
+            self.calls.received();
+            let (size, from) = match &self.cipher {
+                Some(_) => self.transport.recv_datagram(&mut sealed)?,
+                None => self.transport.recv_datagram(buf)?,
+            };
+            if !self.accepts(self.peer(), from) {
+                debug!(%from, "Dropping datagram from another peer.");
+                continue;
+            }
+            let Some(cipher) = &self.cipher else {
+                return Ok((size, from));
+            };
+            if is_hop_stats(&sealed[..size]) && size <= buf.len() {
+                buf[..size].copy_from_slice(&sealed[..size]);
+                return Ok((size, from));
+            }
+            match cipher.open(&sealed[..size]) {
+                Ok(packet) if packet.len() <= buf.len() => {
+                    buf[..packet.len()].copy_from_slice(&packet);
+                    return Ok((packet.len(), from));
+                }
+                Ok(_) => warn!(%from, "Dropping oversized datagram."),
+                Err(err) => warn!(%from, error = ?err, "Dropping datagram."),
+            }
+        }
+    }
+
+    fn seal(&self, cipher: &Cipher, packet: &[u8]) -> io::Result<Vec<u8>> {
+        // Parts are sealed under their own id, see crypto::SPACE_PARTS.
+        if packet.first() == Some(&2) && packet.len() >= 5 {
+            let id = u32::from_be_bytes(packet[1..5].try_into().unwrap());
+            return Ok(cipher.seal(crypto::SPACE_PARTS, id, packet));
+        }
+        let seq = self.counter.fetch_add(1, Ordering::Relaxed);
+        if seq == u32::MAX {
+            return Err(io::Error::other(
+                "Control sequence numbers exhausted for this transfer.",
+            ));
+        }
+        Ok(cipher.seal(self.space, seq, packet))
+    }
+}
+
+/// Forwarding hops inject their statistics in the clear, as they do not hold the session
+/// key. They cannot be authenticated, so they are only ever reported, never acted upon.
+/// Sealed datagrams start with their sequence space, which never collides with their type.
+fn is_hop_stats(datagram: &[u8]) -> bool {
+    datagram.first() == Some(&7)
+}
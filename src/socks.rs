@@ -0,0 +1,455 @@
+//! SOCKS5 proxies (RFC 1928), for networks that only let traffic out through one. A
+//! [`Socks5Transport`] asks the proxy for a UDP association over TCP, then sends every
+//! datagram to the relay the proxy gave, behind a header naming the peer, and takes the
+//! ones the relay forwards back the same way. The association lasts as long as the TCP
+//! connection, which the transport holds.
+//!
+//! Receivers behind a proxy are reached at its relay, if it forwards the datagrams of
+//! hosts we did not send to first, as some only do for the ones we did.
+
+use std::{
+    cell::RefCell,
+    fmt,
+    io::{self, ErrorKind, Read, Write},
+    net::{
+        IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, TcpStream,
+        ToSocketAddrs, UdpSocket,
+    },
+    str::FromStr,
+    time::Duration,
+};
+
+use tracing::{debug, info};
+
+use crate::transport::Transport;
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const USERNAME_PASSWORD: u8 = 2;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const UDP_ASSOCIATE: u8 = 3;
+const ATYP_IPV4: u8 = 1;
+const ATYP_IPV6: u8 = 4;
+const DEFAULT_PORT: u16 = 1080;
+/// How long the proxy has to answer while setting the association up.
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest datagram the relay can forward, header included.
+const MAX_DATAGRAM: usize = 65536;
+
+thread_local! {
+    /// Where datagrams land before their header is stripped.
+    static SCRATCH: RefCell<Vec<u8>> = RefCell::new(vec![0; MAX_DATAGRAM]);
+}
+
+/// A proxy, from a `socks5://[user:password@]host[:port]` URL.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Proxy {
+    host: String,
+    port: u16,
+    credentials: Option<(String, String)>,
+}
+
+impl fmt::Display for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.host.contains(':') {
+            true => write!(f, "[{}]:{}", self.host, self.port),
+            false => write!(f, "{}:{}", self.host, self.port),
+        }
+    }
+}
+
+// Keeps the password out of the logs.
+impl fmt::Debug for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Proxy({self})")
+    }
+}
+
+/// Reads a `socks5://` URL, as the command line takes it.
+impl FromStr for Proxy {
+    type Err = String;
+
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        let rest = url
+            .strip_prefix("socks5://")
+            .ok_or_else(|| format!("'{url}' is not a socks5:// URL"))?;
+        let rest = rest.trim_end_matches('/');
+        let (credentials, authority) = match rest.rsplit_once('@') {
+            Some((credentials, authority)) => {
+                let (user, password) = credentials.split_once(':').unwrap_or((credentials, ""));
+                (Some((user.to_string(), password.to_string())), authority)
+            }
+            None => (None, rest),
+        };
+        if let Some((user, password)) = &credentials {
+            if user.is_empty() || user.len() > 255 || password.len() > 255 {
+                return Err(format!(
+                    "'{url}' has a user or password SOCKS5 cannot carry"
+                ));
+            }
+        }
+        // "[::1]:1080", "[::1]", "proxy:1080" or "proxy".
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, port) = bracketed
+                    .split_once(']')
+                    .ok_or_else(|| format!("'{url}' has an unclosed ["))?;
+                (host, port.strip_prefix(':'))
+            }
+            None => match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port
+                .parse()
+                .map_err(|_| format!("'{port}' is not a port"))?,
+            None => DEFAULT_PORT,
+        };
+        if host.is_empty() {
+            return Err(format!("'{url}' has no host"));
+        }
+        Ok(Proxy {
+            host: host.to_string(),
+            port,
+            credentials,
+        })
+    }
+}
+
+/// Datagrams through the UDP relay of a SOCKS5 proxy.
+#[derive(Debug)]
+pub struct Socks5Transport {
+    /// The association ends when it closes.
+    _control: TcpStream,
+    socket: UdpSocket,
+    relay: SocketAddr,
+}
+
+impl Socks5Transport {
+    /// Sets up a UDP association with `proxy`.
+    pub fn connect(proxy: &Proxy) -> io::Result<Self> {
+        let addr = (proxy.host.as_str(), proxy.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::other(format!("{proxy} does not resolve")))?;
+        let mut control = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+        control.set_read_timeout(Some(TIMEOUT))?;
+        control.set_write_timeout(Some(TIMEOUT))?;
+        authenticate(&mut control, proxy.credentials.as_ref())?;
+
+        let socket = match addr {
+            SocketAddr::V4(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?,
+            SocketAddr::V6(_) => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))?,
+        };
+        // We cannot tell the address the proxy sees us sending from, zeroes say so.
+        let mut request = vec![VERSION, UDP_ASSOCIATE, 0];
+        encode_addr(&mut request, SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+        control.write_all(&request)?;
+        let mut reply = [0; 3];
+        control.read_exact(&mut reply)?;
+        if reply[0] != VERSION {
+            return Err(io::Error::other("the proxy does not speak SOCKS5"));
+        }
+        if reply[1] != 0 {
+            return Err(io::Error::other(format!(
+                "the proxy refused the UDP association: {}",
+                refusal(reply[1])
+            )));
+        }
+        let mut relay = read_addr(&mut control)?;
+        // Relays bound to every address are reached at the proxy's.
+        if relay.ip().is_unspecified() {
+            relay.set_ip(addr.ip());
+        }
+        info!(%proxy, %relay, "Associated with the SOCKS5 proxy.");
+        Ok(Socks5Transport {
+            _control: control,
+            socket,
+            relay,
+        })
+    }
+
+    /// The relay of the proxy, where peers reach us.
+    pub fn relay(&self) -> SocketAddr {
+        self.relay
+    }
+}
+
+impl Transport for Socks5Transport {
+    fn send_datagram(&self, datagram: &[u8], peer: SocketAddr) -> io::Result<usize> {
+        // The header of an IPv6 peer is the longest.
+        let mut wrapped = Vec::with_capacity(22 + datagram.len());
+        // Reserved, then the fragment number: none.
+        wrapped.extend([0, 0, 0]);
+        encode_addr(&mut wrapped, peer);
+        wrapped.extend(datagram);
+        self.socket.send_to(&wrapped, self.relay)?;
+        Ok(datagram.len())
+    }
+
+    fn recv_datagram(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        SCRATCH.with(|scratch| {
+            let mut scratch = scratch.borrow_mut();
+            loop {
+                let (len, from) = self.socket.recv_from(&mut scratch)?;
+                if from != self.relay {
+                    debug!(%from, "Dropping a datagram that did not come through the relay.");
+                    continue;
+                }
+                let Some((peer, header)) = decode_header(&scratch[..len]) else {
+                    debug!("Dropping a relayed datagram we cannot unwrap.");
+                    continue;
+                };
+                let payload = &scratch[header..len];
+                let len = payload.len().min(buf.len());
+                buf[..len].copy_from_slice(&payload[..len]);
+                return Ok((len, peer));
+            }
+        })
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.relay)
+    }
+}
+
+/// Picks an authentication method with the proxy, and goes through it.
+fn authenticate(control: &mut TcpStream, credentials: Option<&(String, String)>) -> io::Result<()> {
+    let greeting: &[u8] = match credentials {
+        Some(_) => &[VERSION, 2, NO_AUTHENTICATION, USERNAME_PASSWORD],
+        None => &[VERSION, 1, NO_AUTHENTICATION],
+    };
+    control.write_all(greeting)?;
+    let mut chosen = [0; 2];
+    control.read_exact(&mut chosen)?;
+    match (chosen, credentials) {
+        ([VERSION, NO_AUTHENTICATION], _) => Ok(()),
+        // RFC 1929.
+        ([VERSION, USERNAME_PASSWORD], Some((user, password))) => {
+            let mut request = vec![1, user.len() as u8];
+            request.extend(user.as_bytes());
+            request.push(password.len() as u8);
+            request.extend(password.as_bytes());
+            control.write_all(&request)?;
+            let mut status = [0; 2];
+            control.read_exact(&mut status)?;
+            match status[1] {
+                0 => Ok(()),
+                _ => Err(io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    "the proxy refused our user and password",
+                )),
+            }
+        }
+        ([VERSION, NO_ACCEPTABLE_METHOD], None) => Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            "the proxy wants credentials, give them in the URL",
+        )),
+        ([VERSION, _], _) => Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            "the proxy takes none of our authentication methods",
+        )),
+        _ => Err(io::Error::other("the proxy does not speak SOCKS5")),
+    }
+}
+
+fn encode_addr(out: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            out.push(ATYP_IPV4);
+            out.extend(ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            out.push(ATYP_IPV6);
+            out.extend(ip.octets());
+        }
+    }
+    out.extend(addr.port().to_be_bytes());
+}
+
+/// Reads the address that ends the answer to a request.
+fn read_addr(control: &mut TcpStream) -> io::Result<SocketAddr> {
+    let mut atyp = [0; 1];
+    control.read_exact(&mut atyp)?;
+    let ip = match atyp[0] {
+        ATYP_IPV4 => {
+            let mut ip = [0; 4];
+            control.read_exact(&mut ip)?;
+            IpAddr::from(ip)
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0; 16];
+            control.read_exact(&mut ip)?;
+            IpAddr::from(ip)
+        }
+        _ => {
+            return Err(io::Error::other(
+                "the proxy named its relay, not an address",
+            ))
+        }
+    };
+    let mut port = [0; 2];
+    control.read_exact(&mut port)?;
+    Ok(SocketAddr::new(ip, u16::from_be_bytes(port)))
+}
+
+/// Peer of a relayed datagram and the length of its header. None for fragments, which
+/// we do not reassemble, and datagrams from a named host.
+fn decode_header(datagram: &[u8]) -> Option<(SocketAddr, usize)> {
+    let [0, 0, 0, atyp, rest @ ..] = datagram else {
+        return None;
+    };
+    match *atyp {
+        ATYP_IPV4 if rest.len() >= 6 => {
+            let ip: [u8; 4] = rest[..4].try_into().ok()?;
+            let port = u16::from_be_bytes([rest[4], rest[5]]);
+            Some((SocketAddrV4::new(ip.into(), port).into(), 4 + 6))
+        }
+        ATYP_IPV6 if rest.len() >= 18 => {
+            let ip: [u8; 16] = rest[..16].try_into().ok()?;
+            let port = u16::from_be_bytes([rest[16], rest[17]]);
+            Some((SocketAddrV6::new(ip.into(), port, 0, 0).into(), 4 + 18))
+        }
+        _ => None,
+    }
+}
+
+/// Why the proxy refused a request, by reply code.
+fn refusal(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "not allowed by its rules",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown reason",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::{testing::TempDir, Receiver, Sender};
+
+    #[test]
+    fn proxy_urls_parse() {
+        let proxy = "socks5://proxy.corp:1081".parse::<Proxy>().unwrap();
+        assert_eq!((proxy.host.as_str(), proxy.port), ("proxy.corp", 1081));
+        assert_eq!(proxy.credentials, None);
+        let proxy = "socks5://me:s3cr:t@[::1]".parse::<Proxy>().unwrap();
+        assert_eq!((proxy.host.as_str(), proxy.port), ("::1", DEFAULT_PORT));
+        assert_eq!(
+            proxy.credentials,
+            Some(("me".to_string(), "s3cr:t".to_string()))
+        );
+        assert_eq!(proxy.to_string(), "[::1]:1080");
+        assert!(!format!("{proxy:?}").contains("s3cr"));
+        assert!("http://proxy:1080".parse::<Proxy>().is_err());
+        assert!("socks5://:1080".parse::<Proxy>().is_err());
+        assert!("socks5://proxy:port".parse::<Proxy>().is_err());
+    }
+
+    #[test]
+    fn headers_round_trip() {
+        for peer in ["192.168.1.20:6666", "[2001:db8::1]:6666"] {
+            let peer: SocketAddr = peer.parse().unwrap();
+            let mut datagram = vec![0, 0, 0];
+            encode_addr(&mut datagram, peer);
+            let header = datagram.len();
+            datagram.extend(b"payload");
+            assert_eq!(decode_header(&datagram), Some((peer, header)));
+        }
+        // Fragments are dropped.
+        assert_eq!(
+            decode_header(&[0, 0, 1, ATYP_IPV4, 1, 2, 3, 4, 0, 80]),
+            None
+        );
+        assert_eq!(decode_header(&[0, 0, 0, ATYP_IPV4, 1, 2]), None);
+    }
+
+    /// SOCKS5 proxy taking `user` and `password`, relaying the datagrams of one
+    /// association. Returns its address.
+    fn proxy() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (mut control, _) = listener.accept().unwrap();
+            let mut greeting = [0; 4];
+            control.read_exact(&mut greeting).unwrap();
+            assert_eq!(greeting, [VERSION, 2, NO_AUTHENTICATION, USERNAME_PASSWORD]);
+            control.write_all(&[VERSION, USERNAME_PASSWORD]).unwrap();
+            let mut credentials = [0; 15];
+            control.read_exact(&mut credentials).unwrap();
+            assert_eq!(&credentials, b"\x01\x04user\x08password");
+            control.write_all(&[1, 0]).unwrap();
+            let mut request = [0; 10];
+            control.read_exact(&mut request).unwrap();
+            assert_eq!(request[..4], [VERSION, UDP_ASSOCIATE, 0, ATYP_IPV4]);
+            let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let mut reply = vec![VERSION, 0, 0];
+            // Bound to every address, as many relays are.
+            let port = relay.local_addr().unwrap().port();
+            encode_addr(&mut reply, SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)));
+            control.write_all(&reply).unwrap();
+
+            let mut client = None;
+            let mut buf = vec![0; MAX_DATAGRAM];
+            loop {
+                let (len, from) = relay.recv_from(&mut buf).unwrap();
+                match decode_header(&buf[..len]) {
+                    Some((peer, header)) if client.is_none_or(|client| client == from) => {
+                        client = Some(from);
+                        relay.send_to(&buf[header..len], peer).unwrap();
+                    }
+                    _ => {
+                        let mut wrapped = vec![0, 0, 0];
+                        encode_addr(&mut wrapped, from);
+                        wrapped.extend(&buf[..len]);
+                        relay.send_to(&wrapped, client.unwrap()).unwrap();
+                    }
+                }
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn files_go_through_the_proxy() {
+        let dir = TempDir::new("socks");
+        let content: Vec<u8> = (0..300_000u32).map(|i| (i % 253) as u8).collect();
+        let source = dir.write("source.bin", &content);
+        let received = dir.join("received.bin");
+
+        let receiver_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver_addr = receiver_socket.local_addr().unwrap();
+        let receiving = {
+            let receiver = Receiver::new()
+                .transport(receiver_socket)
+                .output(&received)
+                .once(true);
+            std::thread::spawn(move || receiver.receive())
+        };
+        let proxy = format!("socks5://user:password@{}", proxy())
+            .parse::<Proxy>()
+            .unwrap();
+        let transport = Socks5Transport::connect(&proxy).unwrap();
+        assert_eq!(transport.relay().ip(), Ipv4Addr::LOCALHOST);
+        Sender::new(receiver_addr.to_string())
+            .transport(transport)
+            .send(&source)
+            .unwrap();
+        receiving.join().unwrap().unwrap();
+        assert_eq!(std::fs::read(&received).unwrap(), content);
+    }
+}
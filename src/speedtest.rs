@@ -0,0 +1,291 @@
+//! Speed tests: synthetic data sent to a receiver taking them for a few seconds, then asked
+//! back from it for as long, over the same protocol as transfers. Tells what a path delivers
+//! before committing to a long transfer over it. Speed tests are not encrypted.
+//!
+//! ```no_run
+//! let report = sanic::speedtest::SpeedTest::new("192.168.1.20:6666").run()?;
+//! println!("{} bytes/s up", report.upload.bytes_per_sec());
+//! # Ok::<(), sanic::speedtest::SpeedTestError>(())
+//! ```
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use thiserror::Error;
+
+use crate::{
+    activity::Activity,
+    stats::{Percentiles, TransferStats},
+    transport::Transport,
+    ReceiveError, Receiver, SendError, Sender, PART_SIZE,
+};
+
+/// First transfer of each direction, before we know what the path takes.
+const FIRST_ROUND: u64 = 1024 * 1024;
+/// Largest transfer of a speed test, which receivers take no more parts than for.
+pub(crate) const MAX_ROUND: u64 = 4 * 1024 * 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum SpeedTestError {
+    #[error("Upload failed: {0}")]
+    Upload(#[from] SendError),
+    #[error("Download failed: {0}")]
+    Download(#[from] ReceiveError),
+}
+
+/// Speed test against a receiver that takes them, see [`Receiver::speedtest`].
+pub struct SpeedTest {
+    addr: String,
+    duration: Duration,
+    connect_timeout: Duration,
+    transport: Option<Arc<dyn Transport>>,
+}
+
+/// What went through one way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Throughput {
+    /// Bytes the receiving end got.
+    pub bytes: u64,
+    pub elapsed: Duration,
+    /// Parts the receiving end got.
+    pub parts: u64,
+    /// Parts the receiving end reported lost, counting every time.
+    pub parts_lost: u64,
+}
+
+impl Throughput {
+    pub fn bytes_per_sec(&self) -> u64 {
+        match self.elapsed.as_secs_f64() {
+            secs if secs > 0.0 => (self.bytes as f64 / secs) as u64,
+            _ => 0,
+        }
+    }
+
+    /// Share of the parts lost on the way, from 0 to 1.
+    pub fn loss(&self) -> f64 {
+        match self.parts + self.parts_lost {
+            0 => 0.0,
+            sent => self.parts_lost as f64 / sent as f64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Report {
+    pub upload: Throughput,
+    pub download: Throughput,
+    /// Round trip times of the longest upload.
+    pub rtt: Option<Percentiles>,
+}
+
+impl SpeedTest {
+    pub fn new(addr: impl Into<String>) -> Self {
+        SpeedTest {
+            addr: addr.into(),
+            duration: Duration::from_secs(10),
+            connect_timeout: Duration::from_secs(30),
+            transport: None,
+        }
+    }
+
+    /// How long each way is tested.
+    pub fn duration(mut self, duration: Duration) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    /// Give up on a receiver that has not answered after this long.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Test over `transport` instead of UDP sockets.
+    pub fn transport(mut self, transport: impl Transport + 'static) -> Self {
+        self.transport = Some(Arc::new(transport));
+        self
+    }
+
+    /// Uploads, then downloads, for the duration each.
+    pub fn run(&self) -> Result<Report, SpeedTestError> {
+        let (upload, rtt) = self.upload()?;
+        let download = self.download()?;
+        Ok(Report {
+            upload,
+            download,
+            rtt,
+        })
+    }
+
+    /// Sends synthetic data until the time is up, in transfers sized to last what is left
+    /// of it at the rate of the previous one. The last one is stopped halfway.
+    fn upload(&self) -> Result<(Throughput, Option<Percentiles>), SendError> {
+        let start = Instant::now();
+        let deadline = start + self.duration;
+        let stop = Arc::new(AtomicBool::new(false));
+        {
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(deadline.saturating_duration_since(Instant::now()));
+                stop.store(true, Ordering::Relaxed);
+            });
+        }
+        let mut total = Throughput::default();
+        let mut rtt: Option<Percentiles> = None;
+        let mut size = FIRST_ROUND;
+        while !stop.load(Ordering::Relaxed) {
+            let activity = Activity::default();
+            let stats = Arc::new(Mutex::new(None));
+            let mut sender = Sender::new(self.addr.clone())
+                .connect_timeout(self.connect_timeout)
+                .activity(activity.clone())
+                .stop_on(stop.clone())
+                .on_stats({
+                    let stats = stats.clone();
+                    move |transfer: &TransferStats| {
+                        *stats.lock().expect("Could not lock stats") = transfer.rtt
+                    }
+                });
+            if let Some(transport) = &self.transport {
+                sender = sender.transport(transport.clone());
+            }
+            let round = Instant::now();
+            let sent = sender.send_synthetic(size);
+            let got = add(&mut total, &activity, size);
+            if let Some(round_rtt) = stats.lock().expect("Could not lock stats").take() {
+                if rtt.is_none_or(|rtt| round_rtt.samples > rtt.samples) {
+                    rtt = Some(round_rtt);
+                }
+            }
+            match sent {
+                Ok(()) => size = next_round(got, round.elapsed(), deadline),
+                Err(_) if stop.load(Ordering::Relaxed) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        total.elapsed = start.elapsed();
+        Ok((total, rtt))
+    }
+
+    /// Asks for synthetic data until the time is up, the same way as it is uploaded.
+    fn download(&self) -> Result<Throughput, ReceiveError> {
+        let start = Instant::now();
+        let deadline = start + self.duration;
+        let mut total = Throughput::default();
+        let mut size = FIRST_ROUND;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            let activity = Activity::default();
+            let mut receiver = Receiver::new()
+                .idle_timeout(self.connect_timeout)
+                .max_duration(left)
+                .activity(activity.clone());
+            if let Some(transport) = &self.transport {
+                receiver = receiver.transport(transport.clone());
+            }
+            let round = Instant::now();
+            let received = receiver.get_synthetic(&self.addr, size);
+            let got = add(&mut total, &activity, size);
+            match received {
+                Ok(()) => size = next_round(got, round.elapsed(), deadline),
+                Err(ReceiveError::TooLong(_)) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        total.elapsed = start.elapsed();
+        Ok(total)
+    }
+}
+
+/// Adds what the transfer `activity` listed got through to `total`, and returns its bytes.
+fn add(total: &mut Throughput, activity: &Activity, size: u64) -> u64 {
+    let Some(ended) = activity.history().into_iter().next() else {
+        return 0;
+    };
+    let bytes = (u64::from(ended.parts_done) * PART_SIZE as u64).min(size);
+    total.bytes += bytes;
+    total.parts += u64::from(ended.parts_done);
+    total.parts_lost += ended.parts_lost;
+    bytes
+}
+
+/// Size of the next transfer, to go on until `deadline` at the rate `bytes` went in `took`.
+fn next_round(bytes: u64, took: Duration, deadline: Instant) -> u64 {
+    let left = deadline.saturating_duration_since(Instant::now());
+    let rate = bytes as f64 / took.as_secs_f64().max(0.001);
+    ((rate * left.as_secs_f64()) as u64).clamp(FIRST_ROUND, MAX_ROUND)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{protocol::Message, testing::Link, MTU};
+
+    #[test]
+    fn both_ways_are_measured() {
+        let link = Link::new();
+        link.receive(Receiver::new().speedtest(true));
+        let report = SpeedTest::new(link.receiver_addr().to_string())
+            .duration(Duration::from_millis(800))
+            .transport(link.endpoint("127.0.0.2:0"))
+            .run()
+            .unwrap();
+        for way in [report.upload, report.download] {
+            assert!(way.parts > 0, "{report:?}");
+            assert!(way.bytes_per_sec() > 0);
+            assert!(way.elapsed >= Duration::from_millis(800));
+        }
+        assert!(report.rtt.is_some());
+    }
+
+    #[test]
+    fn speed_tests_are_sent_aside() {
+        let link = Link::new();
+        link.receive(Receiver::new().speedtest(true));
+        let peer = link.endpoint("127.0.0.2:0");
+        let ask = |message: Message| {
+            let mut packet = message.serialize();
+            packet.resize(MTU, 0);
+            peer.send_datagram(&packet, link.receiver_addr()).unwrap();
+        };
+        let mut buf = [0; MTU];
+        ask(Message::SpeedTest { bytes: MAX_ROUND });
+        // Nobody takes the test, which comes from a port of its own.
+        let (_, from) = peer.recv_datagram(&mut buf).unwrap();
+        assert_ne!(from, link.receiver_addr());
+
+        // Meanwhile the receiver answers, but does not start another for the same host.
+        ask(Message::SpeedTest { bytes: MAX_ROUND });
+        ask(Message::Probe);
+        loop {
+            let (size, from) = peer.recv_datagram(&mut buf).unwrap();
+            if from == link.receiver_addr() {
+                let answer = Message::parse(&buf[..size]).unwrap();
+                assert!(matches!(answer, Message::Presence { .. }), "{answer:?}");
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn receivers_not_taking_speed_tests_refuse_them() {
+        let link = Link::new();
+        link.receive(Receiver::new());
+        let err = SpeedTest::new(link.receiver_addr().to_string())
+            .transport(link.endpoint("127.0.0.2:0"))
+            .run()
+            .unwrap_err();
+        assert!(
+            matches!(err, SpeedTestError::Upload(SendError::Aborted(_))),
+            "{err}"
+        );
+    }
+}
@@ -0,0 +1,2160 @@
+//! Transfer logic of both ends as plain state machines.
+//!
+//! They consume the messages coming from the peer and answer with the actions to take,
+//! without touching the network, the file or the clock. The threads in `server` and
+//! `client` feed them and carry out the actions.
+//!
+//! Those are threads rather than tasks of an async runtime on purpose. A receiver runs at
+//! most [`Receiver::max_sessions`](crate::Receiver::max_sessions) transfers at once and
+//! turns the other senders away, so its threads are bounded however many peers come, and
+//! most of them block on the file, which tokio would hand to its blocking pool anyway. A
+//! second driver of these machines on an async runtime would have to follow everything
+//! the threaded one does, resumes, sinks, hooks and paths, for hardly fewer threads.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
+    ops::Range,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    bloom::BloomFilter,
+    congestion::{Congestion, Controller, Delivery},
+    merkle::{self, Check, Verifier},
+    protocol::{
+        Capabilities, GroupMember, HopStats, Message, MAX_DIGESTS, MAX_RECEIVED_RANGES,
+        MAX_RESYNC_RANGES,
+    },
+    retransmit::{Timeout, TimingWheel},
+    server::SYNC_INTERVAL,
+    stats::{Percentiles, RoundTrips, SenderCounts},
+    Progress, RateCap, MTU,
+};
+
+/// False positive rate of the duplicate pre-filter, hits are double checked anyway.
+const DUPLICATE_FILTER_FP_RATE: f64 = 0.01;
+/// Number of ids that fit in a single Sync datagram.
+pub(crate) const SYNC_IDS_PER_PACKET: usize = (MTU - 1 - 4) / 4;
+/// Most Sync datagrams sent at once, the next ones go on from where they stopped.
+const MAX_SYNC_PACKETS: usize = 16;
+/// Parts the receiver acknowledges unasked at once, or as soon as the first of them
+/// arrived `ACK_DELAY` ago.
+const ACK_EVERY: usize = 1024;
+const ACK_DELAY: Duration = Duration::from_millis(20);
+/// Times a part is sent again without being acknowledged before the transfer is given up.
+pub(crate) const MAX_RETRIES: u32 = 10;
+/// Syncs a receiver that took a transfer back answers with a Resync, in case some get lost.
+const RESYNC_ANSWERS: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Phase {
+    /// No transfer yet.
+    Idle,
+    /// The sender is waiting for the receiver to accept its Send.
+    Handshaking,
+    Transferring,
+    /// The sender has put every part on the wire, or the receiver has received them all.
+    Finishing,
+    /// Every part was acknowledged, or either end gave up on the transfer.
+    Done,
+}
+
+/// Transfer a sender asked for.
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) struct Offer {
+    pub filename: String,
+    pub parts: u32,
+    pub group: Option<GroupMember>,
+    /// The parts make up a delta against our copy of the file.
+    pub delta: bool,
+    /// The parts make up a delta against the chunks we keep.
+    pub dedup: bool,
+    /// The blocks of parts come with digests we check them against.
+    pub verify: bool,
+    /// Token the sender presented.
+    pub token: Option<String>,
+    /// Byte of our copy of the file the parts go at, for a range of it.
+    pub offset: Option<u64>,
+    /// The parts make up a text message to show rather than a file.
+    pub text: bool,
+    /// The parts are synthetic data of a speed test, to throw away.
+    pub synthetic: bool,
+    /// Bytes the parts make up, when the sender said.
+    pub size: Option<u64>,
+}
+
+/// Leaves the token out of the logs.
+impl fmt::Debug for Offer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Offer")
+            .field("filename", &self.filename)
+            .field("parts", &self.parts)
+            .field("group", &self.group)
+            .field("delta", &self.delta)
+            .field("dedup", &self.dedup)
+            .field("verify", &self.verify)
+            .field("token", &self.token.as_ref().map(|_| "…"))
+            .field("offset", &self.offset)
+            .field("text", &self.text)
+            .field("synthetic", &self.synthetic)
+            .field("size", &self.size)
+            .finish()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum SenderAction {
+    /// Put this packet back on the wire.
+    Resend(Vec<u8>),
+    Progress(Progress),
+    /// The receiver changed the rate cap.
+    RateLimit(Option<u64>),
+    /// The receiver paused the transfer, or resumed it.
+    Paused(bool),
+    HopStats(HopStats),
+    /// The receiver lost these parts after acknowledging them, or does not have the ones
+    /// a restarted sender presumed: read them again, and hand them to `track_again`.
+    Reread(Vec<u32>),
+    /// The receiver gave up on the transfer.
+    Aborted(String),
+    Unexpected(Message),
+}
+
+/// Part on the wire, waiting for its Ack.
+#[derive(Debug)]
+struct InFlight {
+    packet: Vec<u8>,
+    /// Times it was sent again.
+    retries: u32,
+    /// Deadlines it missed in a row, since the receiver last reported it lost.
+    timeouts: u32,
+    /// When the receiver last reported it lost.
+    reported: Option<Instant>,
+    /// When it last went out.
+    sent: Instant,
+}
+
+/// Sending end: tracks the parts on the wire until the receiver acknowledges them, and
+/// sends them again when it reports them lost or their retransmission timeout expires.
+#[derive(Debug)]
+pub(crate) struct SenderState {
+    phase: Phase,
+    nb_parts: u32,
+    acked: u32,
+    waiting_ack: BTreeSet<u32>,
+    in_flight: HashMap<u32, InFlight>,
+    /// Every part below was handed to `track`.
+    tracked: u32,
+    /// Deadlines of the parts in flight, along with the retries they were set at, so that
+    /// the ones of parts acknowledged or sent again since are told apart. Started with the
+    /// first part.
+    deadlines: Option<TimingWheel<(u32, u32)>>,
+    timeout: Timeout,
+    max_retries: u32,
+    /// Parts the receiver reported lost since the last `due`.
+    lost: Vec<u32>,
+    /// First part the next Sync asks about, when there are more than it takes.
+    sync_from: u32,
+    /// Attempts at the part that went unacknowledged, when we gave up on the transfer.
+    unanswered: Option<u32>,
+    /// Packets nobody needs anymore, for the caller to reuse.
+    released: Vec<Vec<u8>>,
+    /// Bytes per second the receiver allows us to send.
+    rate_limit: Option<u64>,
+    /// Bytes per second the path to the receiver allows, such as through a relay.
+    path_limit: Option<u64>,
+    /// Bytes per second we hold ourselves to.
+    cap: Option<RateCap>,
+    /// The receiver asked us to hold the parts we did not send yet.
+    paused: bool,
+    aborted: Option<String>,
+    counts: SenderCounts,
+    /// Between the Sends and Syncs we ask and their answers.
+    round_trips: RoundTrips,
+    /// Picks the rate when the transfer is not unlimited.
+    controller: Option<Controller>,
+    delivery: Delivery,
+    /// What the receiver said it takes, None until it accepted or if it did not say.
+    capabilities: Option<Capabilities>,
+    /// Digests of the verified blocks with parts still waiting for an Ack.
+    digests: BTreeMap<u32, [u8; 32]>,
+}
+
+impl SenderState {
+    pub fn new(nb_parts: u32) -> Self {
+        SenderState {
+            phase: Phase::Idle,
+            nb_parts,
+            acked: 0,
+            waiting_ack: BTreeSet::new(),
+            in_flight: HashMap::new(),
+            tracked: 0,
+            deadlines: None,
+            timeout: Timeout::default(),
+            max_retries: MAX_RETRIES,
+            lost: Vec::new(),
+            sync_from: 0,
+            unanswered: None,
+            released: Vec::new(),
+            rate_limit: None,
+            path_limit: None,
+            cap: None,
+            paused: false,
+            aborted: None,
+            counts: SenderCounts::default(),
+            round_trips: RoundTrips::default(),
+            controller: None,
+            delivery: Delivery::default(),
+            capabilities: None,
+            digests: BTreeMap::new(),
+        }
+    }
+
+    pub fn control(&mut self, congestion: Congestion) {
+        self.controller = Controller::new(congestion);
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    /// Rate to stay under, the lowest of the receiver's cap, the path's, ours and the one
+    /// congestion control picked.
+    pub fn rate_limit(&self) -> Option<u64> {
+        let congestion = self.controller.as_ref().map(Controller::rate);
+        let cap = self.cap.as_ref().and_then(RateCap::get);
+        [self.rate_limit, self.path_limit, cap, congestion]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    /// Rate to spread the parts out at: the rate limit when there is one, a bit more than
+    /// the receiver acknowledges otherwise.
+    pub fn pacing_rate(&self) -> u64 {
+        self.rate_limit()
+            .unwrap_or_else(|| self.delivery.pacing_rate())
+    }
+
+    pub fn limit_path(&mut self, bytes_per_sec: Option<u64>) {
+        self.path_limit = bytes_per_sec;
+    }
+
+    pub fn cap(&mut self, cap: RateCap) {
+        self.cap = Some(cap);
+    }
+
+    /// Give up on the transfer once a part timed out `retries` times in a row, the receiver
+    /// saying nothing of it.
+    pub fn max_retries(&mut self, retries: u32) {
+        self.max_retries = retries;
+    }
+
+    pub fn counts(&self) -> &SenderCounts {
+        &self.counts
+    }
+
+    pub fn rtt(&self) -> Option<Percentiles> {
+        self.round_trips.percentiles()
+    }
+
+    /// A Send or a round of Syncs went out at `now`.
+    pub fn asked(&mut self, now: Instant) {
+        self.round_trips.asked(now);
+        if let Some(controller) = &mut self.controller {
+            controller.asked(now);
+        }
+    }
+
+    /// An Accept, Ack or Loss came back at `now`.
+    pub fn answered(&mut self, now: Instant) {
+        if let Some(rtt) = self.round_trips.answered(now) {
+            self.timeout.sampled(rtt);
+        }
+        self.delivery.answered(now);
+        if let Some(controller) = &mut self.controller {
+            controller.answered(now);
+        }
+    }
+
+    pub fn capabilities(&self) -> Option<&Capabilities> {
+        self.capabilities.as_ref()
+    }
+
+    /// Parts we may still send before the receiver acknowledges some, None without a limit.
+    /// None at all while the receiver paused the transfer.
+    pub fn room(&self) -> Option<usize> {
+        if self.paused {
+            return Some(0);
+        }
+        let max = self.capabilities.as_ref()?.max_in_flight?;
+        // At least one, or the transfer could never start.
+        Some((max.max(1) as usize).saturating_sub(self.waiting_ack.len()))
+    }
+
+    /// Why the transfer was aborted, if it was.
+    pub fn aborted(&self) -> Option<&str> {
+        self.aborted.as_deref()
+    }
+
+    /// Times the part that never got acknowledged was sent, when that is why we gave up.
+    pub fn unanswered(&self) -> Option<u32> {
+        self.unanswered
+    }
+
+    /// Gives up on the transfer: nothing is left to send or to wait for.
+    pub fn abort(&mut self, reason: String) {
+        self.phase = Phase::Done;
+        self.waiting_ack.clear();
+        self.released
+            .extend(self.in_flight.drain().map(|(_, part)| part.packet));
+        self.deadlines = None;
+        self.lost.clear();
+        self.aborted = Some(reason);
+    }
+
+    /// The Send request went out.
+    pub fn start(&mut self) {
+        if self.phase == Phase::Idle {
+            self.phase = Phase::Handshaking;
+        }
+    }
+
+    /// `packet` for part `id` is about to go on the wire at `now`. Tracking it before
+    /// sending it means its Ack can never beat us to it.
+    pub fn track(&mut self, id: u32, packet: Vec<u8>, now: Instant) {
+        self.counts.parts_sent += 1;
+        self.tracked = self.tracked.max(id + 1);
+        self.waiting_ack.insert(id);
+        self.in_flight.insert(
+            id,
+            InFlight {
+                packet,
+                retries: 0,
+                timeouts: 0,
+                reported: None,
+                sent: now,
+            },
+        );
+        let deadline = now + self.timeout.after(0);
+        self.deadlines
+            .get_or_insert_with(|| TimingWheel::new(now))
+            .schedule(deadline, (id, 0));
+    }
+
+    /// Like `track`, for a part read again after a Resync. False if the receiver
+    /// acknowledged it meanwhile, and there is nothing to send.
+    pub fn track_again(&mut self, id: u32, packet: Vec<u8>, now: Instant) -> bool {
+        if !self.waiting_ack.contains(&id) || self.in_flight.contains_key(&id) {
+            return false;
+        }
+        self.track(id, packet, now);
+        true
+    }
+
+    /// A restarted sender takes every part below `acked_below` as acknowledged in a previous
+    /// attempt. They wait for the receiver to confirm them, the ones it reports lost are read
+    /// again.
+    pub fn presume(&mut self, acked_below: u32) {
+        let acked_below = acked_below.min(self.nb_parts);
+        self.waiting_ack.extend(0..acked_below);
+        self.tracked = self.tracked.max(acked_below);
+    }
+
+    /// Every part below was acknowledged.
+    pub fn acked_below(&self) -> u32 {
+        self.waiting_ack.first().copied().unwrap_or(self.tracked)
+    }
+
+    /// `block` was put on the wire in full, its parts are verified against `digest`.
+    pub fn digested(&mut self, block: u32, digest: [u8; 32]) {
+        self.digests.insert(block, digest);
+    }
+
+    /// Packet of part `id`, while it waits for its Ack.
+    pub fn packet(&self, id: u32) -> Option<&[u8]> {
+        self.in_flight.get(&id).map(|part| part.packet.as_slice())
+    }
+
+    /// Hands back the packets of the parts acknowledged since the last call.
+    pub fn recycle(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.released)
+    }
+
+    /// Every part was handed to `track`.
+    pub fn sent_all(&mut self) {
+        if self.phase == Phase::Transferring {
+            self.phase = Phase::Finishing;
+        }
+    }
+
+    /// Sync messages asking about the parts still waiting for an Ack at `now`, and the
+    /// digests of their blocks again, in case those got lost. Parts sent less than a round
+    /// trip ago cannot be acknowledged yet, and the ones reported lost recently are dealt
+    /// with, so they are left out. Past `MAX_SYNC_PACKETS`, the rest wait for the next Syncs.
+    pub fn sync(&mut self, now: Instant) -> Vec<Message> {
+        let srtt = self.timeout.srtt();
+        let settled = |id: &&u32| {
+            self.in_flight.get(id).is_none_or(|part| {
+                now.saturating_duration_since(part.sent) >= srtt
+                    && part.reported.is_none_or(|reported| {
+                        now.saturating_duration_since(reported) >= srtt + SYNC_INTERVAL
+                    })
+            })
+        };
+        let ids: Vec<u32> = self
+            .waiting_ack
+            .range(self.sync_from..)
+            .chain(self.waiting_ack.range(..self.sync_from))
+            .filter(settled)
+            .take(SYNC_IDS_PER_PACKET * MAX_SYNC_PACKETS)
+            .copied()
+            .collect();
+        self.sync_from = match ids.last() {
+            Some(&last) if ids.len() == SYNC_IDS_PER_PACKET * MAX_SYNC_PACKETS => last + 1,
+            _ => 0,
+        };
+        let mut messages: Vec<Message> = ids
+            .chunks(SYNC_IDS_PER_PACKET)
+            .map(|chunk| Message::Sync {
+                ids: chunk.to_vec(),
+            })
+            .collect();
+        // With every part acknowledged unasked, the receiver still answers, which times
+        // round trips and brings its rate limit and pauses.
+        if messages.is_empty() && matches!(self.phase, Phase::Transferring | Phase::Finishing) {
+            messages.push(Message::Sync { ids: Vec::new() });
+        }
+        let mut blocks = self.digests.iter().peekable();
+        while let Some((&first, digest)) = blocks.next() {
+            let mut digests = vec![*digest];
+            while digests.len() < MAX_DIGESTS {
+                match blocks.next_if(|(&block, _)| block == first + digests.len() as u32) {
+                    Some((_, digest)) => digests.push(*digest),
+                    None => break,
+                }
+            }
+            messages.push(Message::Digests { first, digests });
+        }
+        messages
+    }
+
+    /// Parts to send again at `now`: the ones reported lost, and the ones whose
+    /// retransmission timeout expired. Parts reported lost that were never on the wire since
+    /// a restart are to be read again. Err with the reason to abort once a part timed out
+    /// `max_retries` times in a row.
+    pub fn due(&mut self, now: Instant) -> Result<Vec<SenderAction>, String> {
+        // Whether each part timed out, rather than the receiver reporting it lost.
+        let mut due: BTreeMap<u32, bool> = BTreeMap::new();
+        let mut reread = BTreeSet::new();
+        // A part sent again less than a round trip ago may not have arrived when the
+        // receiver saw it missing.
+        let srtt = self.timeout.srtt();
+        for id in std::mem::take(&mut self.lost) {
+            match self.in_flight.get(&id) {
+                Some(part) if now.saturating_duration_since(part.sent) >= srtt => {
+                    due.insert(id, false);
+                }
+                Some(_) => {}
+                None if self.waiting_ack.contains(&id) => {
+                    reread.insert(id);
+                }
+                None => {}
+            }
+        }
+        if let Some(deadlines) = &mut self.deadlines {
+            for (id, retries) in deadlines.expire(now) {
+                // Acknowledged or sent again since.
+                if self
+                    .in_flight
+                    .get(&id)
+                    .is_some_and(|part| part.retries == retries)
+                {
+                    due.entry(id).or_insert(true);
+                }
+            }
+        }
+
+        let mut resends = Vec::with_capacity(due.len());
+        for (id, timed_out) in due {
+            let part = self
+                .in_flight
+                .get_mut(&id)
+                .expect("Due parts are in flight");
+            if !timed_out {
+                // The receiver is still there.
+                part.timeouts = 0;
+                part.reported = Some(now);
+            } else if part.timeouts >= self.max_retries {
+                self.unanswered = Some(part.timeouts + 1);
+                return Err(format!(
+                    "part {id} was not acknowledged after {} sends",
+                    part.timeouts + 1
+                ));
+            } else {
+                part.timeouts += 1;
+            }
+            part.retries += 1;
+            part.sent = now;
+            resends.push(SenderAction::Resend(part.packet.clone()));
+            let deadline = now + self.timeout.after(part.timeouts);
+            if let Some(deadlines) = &mut self.deadlines {
+                deadlines.schedule(deadline, (id, part.retries));
+            }
+        }
+        self.counts.retransmissions += resends.len() as u64;
+        self.counts.parts_sent += resends.len() as u64;
+        if !reread.is_empty() {
+            self.counts.retransmissions += reread.len() as u64;
+            resends.push(SenderAction::Reread(reread.into_iter().collect()));
+        }
+        Ok(resends)
+    }
+
+    /// Parts still waiting for an Ack that a Received from the receiver covers.
+    pub fn covered(&self, below: u32, ranges: &[Range<u32>]) -> Vec<u32> {
+        let mut ids: Vec<u32> = self.waiting_ack.range(..below).copied().collect();
+        for range in ranges
+            .iter()
+            .filter(|range| range.start.max(below) < range.end)
+        {
+            ids.extend(self.waiting_ack.range(range.start.max(below)..range.end));
+        }
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+
+    /// The receiver acknowledged `ids`.
+    fn acked(&mut self, ids: Vec<u32>) -> Vec<SenderAction> {
+        self.delivery.acked(ids.len());
+        if let Some(controller) = &mut self.controller {
+            controller.acked(ids.len());
+        }
+        for id in ids {
+            // Acks for parts we already forgot about are duplicates.
+            if self.waiting_ack.remove(&id) {
+                self.released
+                    .extend(self.in_flight.remove(&id).map(|part| part.packet));
+                self.acked += 1;
+            } else {
+                self.counts.duplicate_acks += 1;
+            }
+        }
+        let waiting = &self.waiting_ack;
+        self.digests.retain(|&block, _| {
+            let parts = merkle::parts_of(block, self.nb_parts);
+            waiting.range(parts).next().is_some()
+        });
+        if self.acked >= self.nb_parts {
+            self.phase = Phase::Done;
+        }
+        vec![SenderAction::Progress(Progress {
+            parts_done: self.acked,
+            parts_total: self.nb_parts,
+        })]
+    }
+
+    /// The receiver restarted, and of the parts `first..end` only has the ones in `ranges`.
+    /// Parts on the wire it does not have go again with the next `due`, the ones it lost
+    /// after acknowledging them wait for the caller to read them again.
+    fn resynced(&mut self, first: u32, end: u32, ranges: &[Range<u32>]) -> Vec<SenderAction> {
+        let end = end.min(self.tracked);
+        let mut missing = Vec::new();
+        let mut covered = Vec::new();
+        let mut at = first;
+        for range in ranges {
+            missing.extend(at..range.start.min(end));
+            let (start, stop) = (range.start.max(at), range.end.min(end));
+            if start < stop {
+                covered.extend(self.waiting_ack.range(start..stop));
+            }
+            at = at.max(range.end);
+        }
+        missing.extend(at..end);
+        let mut reread = Vec::new();
+        for id in missing {
+            if self.in_flight.contains_key(&id) {
+                self.lost.push(id);
+            } else if self.waiting_ack.insert(id) {
+                // Already waiting to be read again otherwise.
+                reread.push(id);
+            }
+        }
+        self.acked -= reread.len() as u32;
+        self.counts.retransmissions += reread.len() as u64;
+        let mut actions = self.acked(covered);
+        if !reread.is_empty() {
+            actions.push(SenderAction::Reread(reread));
+        }
+        actions
+    }
+
+    pub fn on_message(&mut self, message: Message) -> Vec<SenderAction> {
+        match (self.phase, message) {
+            (Phase::Handshaking, Message::Accept { capabilities }) => {
+                self.capabilities = capabilities;
+                self.phase = if self.nb_parts == 0 {
+                    Phase::Done
+                } else {
+                    Phase::Transferring
+                };
+                Vec::new()
+            }
+            // Our first Accept got duplicated on the way.
+            (_, Message::Accept { .. }) => Vec::new(),
+            (Phase::Transferring | Phase::Finishing, Message::Ack { ids }) => self.acked(ids),
+            (Phase::Transferring | Phase::Finishing, Message::Received { below, ranges }) => {
+                let ids = self.covered(below, &ranges);
+                self.acked(ids)
+            }
+            (Phase::Transferring | Phase::Finishing, Message::Resync { first, end, ranges }) => {
+                self.resynced(first, end, &ranges)
+            }
+            // Sent again by the next `due`.
+            (Phase::Transferring | Phase::Finishing, Message::Loss { ids }) => {
+                self.counts.reported_lost += ids.len() as u64;
+                if let Some(controller) = &mut self.controller {
+                    controller.lost();
+                }
+                self.lost.extend(ids);
+                Vec::new()
+            }
+            // Repeated with every Sync answer, only changes are worth reporting.
+            (_, Message::RateLimit { bytes_per_sec }) => {
+                let rate_limit = Some(bytes_per_sec).filter(|rate| *rate > 0);
+                if rate_limit == self.rate_limit {
+                    return Vec::new();
+                }
+                self.rate_limit = rate_limit;
+                vec![SenderAction::RateLimit(rate_limit)]
+            }
+            // Also repeated while paused.
+            (_, Message::Pause { paused }) => {
+                if paused == self.paused {
+                    return Vec::new();
+                }
+                self.paused = paused;
+                vec![SenderAction::Paused(paused)]
+            }
+            (_, Message::HopStats(stats)) => vec![SenderAction::HopStats(stats)],
+            // The receiver opening its NAT for us, our Sends are what opens ours.
+            (_, Message::Punch) => Vec::new(),
+            (
+                Phase::Handshaking | Phase::Transferring | Phase::Finishing,
+                Message::Abort { reason },
+            ) => {
+                self.abort(reason.clone());
+                vec![SenderAction::Aborted(reason)]
+            }
+            (_, message) => vec![SenderAction::Unexpected(message)],
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum ReceiverAction {
+    /// A sender asked for a new transfer.
+    Start(Offer),
+    /// The sender asked for the next transfer right after the current one.
+    Next(Offer),
+    /// The sender did not get our Accept, send it again.
+    Accept {
+        capabilities: Option<Capabilities>,
+    },
+    Write {
+        id: u32,
+        data: Vec<u8>,
+    },
+    Progress(Progress),
+    Sync {
+        ack: Vec<u32>,
+        loss: Vec<u32>,
+    },
+    /// The parts of `block` did not match its digest, they are to be sent again.
+    Corrupt(u32),
+    /// We took the transfer back after a restart, tell the sender what we have.
+    Resync(Vec<Message>),
+    /// Every part was received, and verified if the sender gave digests.
+    Complete,
+    /// The sender gave up on the transfer.
+    Aborted(String),
+    Unexpected(Message),
+}
+
+/// Receiving end: tracks which parts arrived, once each.
+#[derive(Debug)]
+pub(crate) struct ReceiverState {
+    phase: Phase,
+    nb_parts: u32,
+    received: BTreeSet<u32>,
+    /// Every part below arrived.
+    below: u32,
+    /// Parts that arrived since the last Received, and when the first of them was noticed.
+    fresh: Vec<u32>,
+    fresh_since: Option<Instant>,
+    /// Sized once the offer is accepted or resumed, see [`ReceiverState::prepare`].
+    seen: Option<BloomFilter>,
+    /// The sender gives the digests of the blocks of parts.
+    verify: bool,
+    /// Checks the blocks of parts, when the sender gives their digests.
+    verifier: Option<Verifier>,
+    /// The writer has every part on disk.
+    stored: bool,
+    /// The transfer was taken back after a restart, rather than accepted.
+    resumed: bool,
+    /// Syncs still to answer with a Resync.
+    resyncs: u32,
+    /// What our Accept told the sender.
+    capabilities: Option<Capabilities>,
+}
+
+impl Default for ReceiverState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReceiverState {
+    pub fn new() -> Self {
+        ReceiverState {
+            phase: Phase::Idle,
+            nb_parts: 0,
+            received: BTreeSet::new(),
+            below: 0,
+            fresh: Vec::new(),
+            fresh_since: None,
+            seen: None,
+            verify: false,
+            verifier: None,
+            stored: false,
+            resumed: false,
+            resyncs: 0,
+            capabilities: None,
+        }
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    /// We accepted the transfer, telling the sender it may use our `capabilities`.
+    pub fn accepted(&mut self, capabilities: Capabilities) {
+        self.capabilities = Some(capabilities);
+        self.prepare();
+    }
+
+    /// Takes back a transfer of `nb_parts` parts we received before a restart, of which
+    /// the ones in `stored` made it to disk. The next Syncs are answered with a Resync.
+    pub fn resume(&mut self, nb_parts: u32, stored: impl IntoIterator<Item = u32>) {
+        self.reset();
+        self.begin(nb_parts, false);
+        self.prepare();
+        let seen = self
+            .seen
+            .as_mut()
+            .expect("Prepared transfers have a filter");
+        for id in stored.into_iter().filter(|&id| id < nb_parts) {
+            seen.insert(id);
+            self.received.insert(id);
+        }
+        while self.received.contains(&self.below) {
+            self.below += 1;
+        }
+        self.resumed = true;
+        self.resyncs = RESYNC_ANSWERS;
+    }
+
+    pub fn resumed(&self) -> bool {
+        self.resumed
+    }
+
+    /// Parts received so far.
+    pub fn received(&self) -> impl Iterator<Item = u32> + '_ {
+        self.received.iter().copied()
+    }
+
+    /// Resync messages listing the parts we have, as many as it takes.
+    fn resync(&self) -> Vec<Message> {
+        let mut messages = Vec::new();
+        let mut first = 0;
+        let mut ranges: Vec<Range<u32>> = Vec::new();
+        for &id in &self.received {
+            if let Some(range) = ranges.last_mut().filter(|range| range.end == id) {
+                range.end += 1;
+                continue;
+            }
+            if ranges.len() == MAX_RESYNC_RANGES {
+                // The parts between the last range and this one are missing.
+                messages.push(Message::Resync {
+                    first,
+                    end: id,
+                    ranges: std::mem::take(&mut ranges),
+                });
+                first = id;
+            }
+            ranges.push(id..id + 1);
+        }
+        messages.push(Message::Resync {
+            first,
+            end: self.nb_parts,
+            ranges,
+        });
+        messages
+    }
+
+    /// Root of the verified transfer, once every block checked out.
+    pub fn root(&self) -> Option<[u8; 32]> {
+        self.verifier.as_ref()?.root()
+    }
+
+    /// Forgets the current transfer and waits for the next Send.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// The writer has every part on disk, which completes the transfer if it was verified.
+    pub fn stored(&mut self) -> Option<ReceiverAction> {
+        self.stored = true;
+        self.complete()
+    }
+
+    /// Whether the Ack of part `id` waits for the transfer to complete. Holding back the
+    /// last part keeps the sender from finishing before the parts are stored and verified.
+    fn held(&self, id: u32) -> bool {
+        self.phase == Phase::Transferring && id + 1 == self.nb_parts
+    }
+
+    /// The Received to send at `now`, once enough parts arrived since the last one or the
+    /// first of them waited long enough, and right away when the transfer is complete.
+    /// Parts of verified transfers are only acknowledged by Sync answers, once their block
+    /// checked out.
+    pub fn report(&mut self, now: Instant) -> Option<Message> {
+        if self.fresh.is_empty() {
+            return None;
+        }
+        let since = *self.fresh_since.get_or_insert(now);
+        if self.fresh.len() < ACK_EVERY
+            && now.saturating_duration_since(since) < ACK_DELAY
+            && self.phase != Phase::Finishing
+        {
+            return None;
+        }
+        self.fresh_since = None;
+        let mut fresh = std::mem::take(&mut self.fresh);
+        fresh.sort_unstable();
+        // Up to the part held back.
+        let below = match self.phase {
+            Phase::Transferring => self.below.min(self.nb_parts - 1),
+            _ => self.below,
+        };
+        let mut ranges: Vec<Range<u32>> = Vec::new();
+        for id in fresh
+            .into_iter()
+            .filter(|&id| id >= below && !self.held(id))
+        {
+            if let Some(range) = ranges.last_mut().filter(|range| range.end == id) {
+                range.end += 1;
+            } else if ranges.len() < MAX_RECEIVED_RANGES {
+                ranges.push(id..id + 1);
+            } else {
+                // The rest are acknowledged by the next ones, or by Sync answers.
+                break;
+            }
+        }
+        Some(Message::Received { below, ranges })
+    }
+
+    pub fn on_message(&mut self, message: Message) -> Vec<ReceiverAction> {
+        if self.phase == Phase::Transferring {
+            self.prepare();
+        }
+        match (self.phase, message) {
+            (
+                Phase::Idle,
+                Message::Send {
+                    filename,
+                    parts,
+                    group,
+                    delta,
+                    dedup,
+                    verify,
+                    token,
+                    offset,
+                    text,
+                    synthetic,
+                    size,
+                },
+            ) => {
+                let mut actions = vec![ReceiverAction::Start(Offer {
+                    filename,
+                    parts,
+                    group,
+                    delta,
+                    dedup,
+                    verify,
+                    token,
+                    offset,
+                    text,
+                    synthetic,
+                    size,
+                })];
+                actions.extend(self.begin(parts, verify));
+                actions
+            }
+            (Phase::Transferring, Message::Send { .. }) => vec![ReceiverAction::Accept {
+                capabilities: self.capabilities.clone(),
+            }],
+            // Once we have everything, a Send is the sender starting its next transfer.
+            (
+                Phase::Finishing,
+                Message::Send {
+                    filename,
+                    parts,
+                    group,
+                    delta,
+                    dedup,
+                    verify,
+                    token,
+                    offset,
+                    text,
+                    synthetic,
+                    size,
+                },
+            ) => {
+                self.reset();
+                let mut actions = vec![ReceiverAction::Next(Offer {
+                    filename,
+                    parts,
+                    group,
+                    delta,
+                    dedup,
+                    verify,
+                    token,
+                    offset,
+                    text,
+                    synthetic,
+                    size,
+                })];
+                actions.extend(self.begin(parts, verify));
+                actions
+            }
+            (Phase::Transferring, Message::Part { id, data }) => {
+                if id >= self.nb_parts {
+                    return vec![ReceiverAction::Unexpected(Message::Part { id, data })];
+                }
+                // The sender retransmits parts whose Sync raced with the part itself.
+                if self.seen.as_mut().is_some_and(|seen| seen.insert(id))
+                    && self.received.contains(&id)
+                {
+                    return Vec::new();
+                }
+                self.received.insert(id);
+                if self.verifier.is_none() {
+                    self.fresh.push(id);
+                    while self.received.contains(&self.below) {
+                        self.below += 1;
+                    }
+                }
+                let check = self
+                    .verifier
+                    .as_mut()
+                    .map(|verifier| verifier.part(id, &data));
+                let mut actions = vec![
+                    ReceiverAction::Write { id, data },
+                    ReceiverAction::Progress(Progress {
+                        parts_done: self.received.len() as u32,
+                        parts_total: self.nb_parts,
+                    }),
+                ];
+                if let Some(check) = check {
+                    actions.extend(self.checked(merkle::block_of(id), check));
+                }
+                actions.extend(self.complete());
+                actions
+            }
+            // Late duplicates of parts we already have.
+            (Phase::Finishing, Message::Part { .. }) => Vec::new(),
+            (Phase::Transferring, Message::Digests { first, digests })
+                if self.verifier.is_some() =>
+            {
+                let mut actions = Vec::new();
+                for (block, digest) in (first..).zip(digests) {
+                    let check = self
+                        .verifier
+                        .as_mut()
+                        .map_or(Check::Pending, |verifier| verifier.expect(block, digest));
+                    actions.extend(self.checked(block, check));
+                }
+                actions.extend(self.complete());
+                actions
+            }
+            // Repeated by the sender until the parts of their blocks are acknowledged.
+            (Phase::Finishing, Message::Digests { .. }) => Vec::new(),
+            (Phase::Transferring | Phase::Finishing, Message::Sync { ids }) => {
+                // Due to the lack of ordering guarantees in UDP, a part reported lost here
+                // might still arrive right after the Sync. Parts of blocks not verified yet
+                // are neither, nor is the part held back.
+                let verified = |id: &u32| {
+                    self.verifier
+                        .as_ref()
+                        .is_none_or(|verifier| verifier.covers(*id))
+                        && !self.held(*id)
+                };
+                let (ack, loss) = ids
+                    .into_iter()
+                    .filter(|id| verified(id) || !self.received.contains(id))
+                    .partition(|id| self.received.contains(id));
+                let mut actions = vec![ReceiverAction::Sync { ack, loss }];
+                if self.resyncs > 0 {
+                    self.resyncs -= 1;
+                    actions.push(ReceiverAction::Resync(self.resync()));
+                }
+                actions
+            }
+            (Phase::Transferring, Message::Abort { reason }) => {
+                self.phase = Phase::Done;
+                vec![ReceiverAction::Aborted(reason)]
+            }
+            (_, message) => vec![ReceiverAction::Unexpected(message)],
+        }
+    }
+
+    /// Drops the parts of `block` if `check` found them corrupt, so they are reported lost.
+    fn checked(&mut self, block: u32, check: Check) -> Option<ReceiverAction> {
+        if check != Check::Corrupt {
+            return None;
+        }
+        for id in merkle::parts_of(block, self.nb_parts) {
+            self.received.remove(&id);
+        }
+        Some(ReceiverAction::Corrupt(block))
+    }
+
+    fn complete(&mut self) -> Option<ReceiverAction> {
+        let verified = self.verifier.as_ref().is_none_or(Verifier::is_complete);
+        if self.phase != Phase::Transferring
+            || (self.received.len() as u32) < self.nb_parts
+            || !verified
+            || !self.stored
+        {
+            return None;
+        }
+        self.phase = Phase::Finishing;
+        // The part held back goes with the next Received.
+        if self.verifier.is_none() {
+            self.fresh.push(self.nb_parts - 1);
+        }
+        Some(ReceiverAction::Complete)
+    }
+
+    fn begin(&mut self, nb_parts: u32, verify: bool) -> Option<ReceiverAction> {
+        self.nb_parts = nb_parts;
+        self.verify = verify;
+        self.seen = None;
+        self.verifier = None;
+        if nb_parts == 0 {
+            self.phase = Phase::Finishing;
+            return Some(ReceiverAction::Complete);
+        }
+        self.phase = Phase::Transferring;
+        None
+    }
+
+    /// Sets up the tracking of the parts. Offers come unauthenticated, so this waits for
+    /// ours to pass the access, size and quota checks, rather than sizing anything after
+    /// the part count of any datagram.
+    fn prepare(&mut self) {
+        if self.seen.is_none() {
+            self.seen = Some(BloomFilter::new(self.nb_parts, DUPLICATE_FILTER_FP_RATE));
+            self.verifier = self.verify.then(|| Verifier::new(self.nb_parts));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn send(parts: u32) -> Message {
+        Message::Send {
+            filename: "file".to_string(),
+            parts,
+            group: None,
+            delta: false,
+            dedup: false,
+            verify: false,
+            token: None,
+            offset: None,
+            text: false,
+            synthetic: false,
+            size: None,
+        }
+    }
+
+    fn part(id: u32) -> Message {
+        Message::Part {
+            id,
+            data: vec![id as u8],
+        }
+    }
+
+    fn written(actions: &[ReceiverAction]) -> Vec<u32> {
+        actions
+            .iter()
+            .filter_map(|action| match action {
+                ReceiverAction::Write { id, .. } => Some(*id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn accepted_sender(nb_parts: u32) -> SenderState {
+        let mut state = SenderState::new(nb_parts);
+        state.start();
+        assert!(state
+            .on_message(Message::Accept { capabilities: None })
+            .is_empty());
+        for id in 0..nb_parts {
+            state.track(id, vec![2, id as u8], Instant::now());
+        }
+        state.sent_all();
+        state
+    }
+
+    fn capabilities(max_in_flight: Option<u32>) -> Capabilities {
+        Capabilities {
+            max_part_size: 1,
+            max_in_flight,
+            compression: Vec::new(),
+            encryption: Vec::new(),
+            free_bytes: None,
+        }
+    }
+
+    fn receiving(nb_parts: u32) -> ReceiverState {
+        let mut state = ReceiverState::new();
+        state.on_message(send(nb_parts));
+        state
+    }
+
+    #[test]
+    fn receiver_starts_on_send() {
+        let mut state = ReceiverState::new();
+        let actions = state.on_message(send(3));
+        assert_eq!(
+            actions,
+            vec![ReceiverAction::Start(Offer {
+                filename: "file".to_string(),
+                parts: 3,
+                group: None,
+                delta: false,
+                dedup: false,
+                verify: false,
+                token: None,
+                offset: None,
+                text: false,
+                synthetic: false,
+                size: None,
+            })]
+        );
+        assert_eq!(state.phase(), Phase::Transferring);
+    }
+
+    #[test]
+    fn receiver_sizes_nothing_before_accepting() {
+        let mut state = ReceiverState::new();
+        state.on_message(send(u32::MAX));
+        assert_eq!(state.phase(), Phase::Transferring);
+        assert!(state.seen.is_none());
+        state.reset();
+        assert!(state.seen.is_none());
+
+        state.on_message(send(3));
+        state.accepted(capabilities(None));
+        assert!(state.seen.is_some());
+        assert_eq!(written(&state.on_message(part(0))), vec![0]);
+        assert!(state.on_message(part(0)).is_empty());
+    }
+
+    #[test]
+    fn receiver_ignores_parts_before_send() {
+        let mut state = ReceiverState::new();
+        let actions = state.on_message(part(0));
+        assert!(matches!(actions[..], [ReceiverAction::Unexpected(_)]));
+        assert_eq!(state.phase(), Phase::Idle);
+    }
+
+    #[test]
+    fn receiver_answers_handshake_retries() {
+        let mut state = receiving(3);
+        state.accepted(capabilities(Some(8)));
+        assert_eq!(
+            state.on_message(send(3)),
+            vec![ReceiverAction::Accept {
+                capabilities: Some(capabilities(Some(8)))
+            }]
+        );
+        assert_eq!(state.phase(), Phase::Transferring);
+    }
+
+    #[test]
+    fn receiver_completes_in_order() {
+        let mut state = receiving(3);
+        let mut ids = Vec::new();
+        for id in 0..3 {
+            ids.extend(written(&state.on_message(part(id))));
+        }
+        assert_eq!(ids, vec![0, 1, 2]);
+        assert_eq!(state.phase(), Phase::Transferring);
+        assert_eq!(state.stored(), Some(ReceiverAction::Complete));
+        assert_eq!(state.phase(), Phase::Finishing);
+    }
+
+    #[test]
+    fn receiver_completes_out_of_order() {
+        let mut state = receiving(4);
+        for id in [3, 1, 0, 2] {
+            let actions = state.on_message(part(id));
+            assert_eq!(written(&actions), vec![id]);
+            assert!(!actions.contains(&ReceiverAction::Complete));
+        }
+        assert_eq!(state.stored(), Some(ReceiverAction::Complete));
+        assert_eq!(state.stored(), None);
+        assert_eq!(state.phase(), Phase::Finishing);
+    }
+
+    #[test]
+    fn receiver_holds_the_last_ack_until_stored() {
+        let mut state = receiving(2);
+        for id in [1, 0] {
+            state.on_message(part(id));
+        }
+        let sync = || Message::Sync { ids: vec![0, 1] };
+        assert_eq!(
+            state.on_message(sync()),
+            vec![ReceiverAction::Sync {
+                ack: vec![0],
+                loss: vec![],
+            }]
+        );
+        let now = Instant::now();
+        assert_eq!(state.report(now), None);
+        assert_eq!(
+            state.report(now + ACK_DELAY),
+            Some(Message::Received {
+                below: 1,
+                ranges: vec![],
+            })
+        );
+        assert_eq!(state.stored(), Some(ReceiverAction::Complete));
+        assert_eq!(
+            state.report(now + ACK_DELAY),
+            Some(Message::Received {
+                below: 2,
+                ranges: vec![],
+            })
+        );
+        assert_eq!(
+            state.on_message(sync()),
+            vec![ReceiverAction::Sync {
+                ack: vec![0, 1],
+                loss: vec![],
+            }]
+        );
+    }
+
+    #[test]
+    fn receiver_writes_duplicates_once() {
+        let mut state = receiving(3);
+        assert_eq!(written(&state.on_message(part(1))), vec![1]);
+        assert!(state.on_message(part(1)).is_empty());
+        assert_eq!(written(&state.on_message(part(0))), vec![0]);
+        assert!(state.on_message(part(1)).is_empty());
+        assert_eq!(state.phase(), Phase::Transferring);
+        assert_eq!(written(&state.on_message(part(2))), vec![2]);
+        assert!(state.on_message(part(2)).is_empty());
+        state.stored();
+        assert_eq!(state.phase(), Phase::Finishing);
+    }
+
+    #[test]
+    fn receiver_progress_counts_unique_parts() {
+        let mut state = receiving(2);
+        state.on_message(part(0));
+        state.on_message(part(0));
+        let actions = state.on_message(part(1));
+        assert!(actions.contains(&ReceiverAction::Progress(Progress {
+            parts_done: 2,
+            parts_total: 2,
+        })));
+    }
+
+    #[test]
+    fn receiver_rejects_parts_out_of_range() {
+        let mut state = receiving(2);
+        let actions = state.on_message(part(2));
+        assert!(matches!(actions[..], [ReceiverAction::Unexpected(_)]));
+    }
+
+    #[test]
+    fn receiver_reports_losses() {
+        let mut state = receiving(4);
+        state.on_message(part(0));
+        state.on_message(part(2));
+        let actions = state.on_message(Message::Sync {
+            ids: vec![0, 1, 2, 3],
+        });
+        assert_eq!(
+            actions,
+            vec![ReceiverAction::Sync {
+                ack: vec![0, 2],
+                loss: vec![1, 3],
+            }]
+        );
+    }
+
+    /// Digests of the blocks of a transfer of `part`s.
+    fn digests(nb_parts: u32) -> Message {
+        let mut digester = merkle::Digester::default();
+        let mut digests: Vec<[u8; 32]> = (0..nb_parts)
+            .filter_map(|id| digester.part(&[id as u8]))
+            .map(|(_, digest)| digest)
+            .collect();
+        digests.extend(digester.finish().map(|(_, digest)| digest));
+        Message::Digests { first: 0, digests }
+    }
+
+    fn verifying(nb_parts: u32) -> ReceiverState {
+        let mut state = ReceiverState::new();
+        state.on_message(Message::Send {
+            filename: "file".to_string(),
+            parts: nb_parts,
+            group: None,
+            delta: false,
+            dedup: false,
+            verify: true,
+            token: None,
+            offset: None,
+            text: false,
+            synthetic: false,
+            size: None,
+        });
+        state
+    }
+
+    #[test]
+    fn receiver_acks_verified_blocks_only() {
+        let nb_parts = merkle::BLOCK_PARTS + 1;
+        let mut state = verifying(nb_parts);
+        for id in 0..nb_parts {
+            assert!(!state
+                .on_message(part(id))
+                .contains(&ReceiverAction::Complete));
+        }
+        let sync = || Message::Sync {
+            ids: vec![0, nb_parts - 1],
+        };
+        assert_eq!(
+            state.on_message(sync()),
+            vec![ReceiverAction::Sync {
+                ack: vec![],
+                loss: vec![],
+            }]
+        );
+        // Stored, but not verified yet.
+        assert_eq!(state.stored(), None);
+        assert!(state
+            .on_message(digests(nb_parts))
+            .contains(&ReceiverAction::Complete));
+        assert_eq!(
+            state.on_message(sync()),
+            vec![ReceiverAction::Sync {
+                ack: vec![0, nb_parts - 1],
+                loss: vec![],
+            }]
+        );
+        assert!(state.root().is_some());
+    }
+
+    #[test]
+    fn receiver_reports_corrupt_blocks_lost() {
+        let nb_parts = 2 * merkle::BLOCK_PARTS;
+        let mut state = verifying(nb_parts);
+        state.on_message(digests(nb_parts));
+        for id in 1..nb_parts {
+            state.on_message(part(id));
+        }
+        let corrupt = Message::Part {
+            id: 0,
+            data: vec![0xff],
+        };
+        assert!(state
+            .on_message(corrupt)
+            .contains(&ReceiverAction::Corrupt(0)));
+        let actions = state.on_message(Message::Sync {
+            ids: vec![0, 1, merkle::BLOCK_PARTS],
+        });
+        assert_eq!(
+            actions,
+            vec![ReceiverAction::Sync {
+                ack: vec![merkle::BLOCK_PARTS],
+                loss: vec![0, 1],
+            }]
+        );
+        // The block comes again, and its digest with it.
+        for id in merkle::parts_of(0, nb_parts) {
+            state.on_message(part(id));
+        }
+        state.stored();
+        assert!(state
+            .on_message(digests(nb_parts))
+            .contains(&ReceiverAction::Complete));
+    }
+
+    #[test]
+    fn receiver_reports_what_arrived_unasked() {
+        let mut state = receiving(10);
+        let now = Instant::now();
+        assert_eq!(state.report(now), None);
+        for id in [0, 1, 4, 5, 7] {
+            state.on_message(part(id));
+        }
+        assert_eq!(state.report(now), None);
+        assert_eq!(
+            state.report(now + ACK_DELAY),
+            Some(Message::Received {
+                below: 2,
+                ranges: vec![4..6, 7..8],
+            })
+        );
+        assert_eq!(state.report(now + ACK_DELAY * 2), None);
+        // Filling the gap moves the cumulative bound past the parts reported before.
+        for id in [2, 3] {
+            state.on_message(part(id));
+        }
+        assert_eq!(state.report(now + ACK_DELAY * 2), None);
+        assert_eq!(
+            state.report(now + ACK_DELAY * 3),
+            Some(Message::Received {
+                below: 6,
+                ranges: vec![],
+            })
+        );
+    }
+
+    #[test]
+    fn receiver_reports_every_few_parts_and_on_completion() {
+        let nb_parts = ACK_EVERY as u32 + 1;
+        let mut state = receiving(nb_parts);
+        let now = Instant::now();
+        for id in 0..ACK_EVERY as u32 {
+            state.on_message(part(id));
+        }
+        assert_eq!(
+            state.report(now),
+            Some(Message::Received {
+                below: ACK_EVERY as u32,
+                ranges: vec![],
+            })
+        );
+        state.on_message(part(nb_parts - 1));
+        assert_eq!(state.report(now + ACK_DELAY), None);
+        state.stored();
+        assert_eq!(
+            state.report(now),
+            Some(Message::Received {
+                below: nb_parts,
+                ranges: vec![],
+            })
+        );
+    }
+
+    #[test]
+    fn receiver_leaves_verified_parts_to_syncs() {
+        let mut state = verifying(2);
+        state.on_message(part(0));
+        assert_eq!(state.report(Instant::now() + ACK_DELAY), None);
+    }
+
+    #[test]
+    fn receiver_acks_retransmitted_parts() {
+        let mut state = receiving(2);
+        state.on_message(part(0));
+        state.on_message(Message::Sync { ids: vec![1] });
+        state.on_message(part(1));
+        state.stored();
+        let actions = state.on_message(Message::Sync { ids: vec![0, 1] });
+        assert_eq!(
+            actions,
+            vec![ReceiverAction::Sync {
+                ack: vec![0, 1],
+                loss: vec![],
+            }]
+        );
+    }
+
+    #[test]
+    fn receiver_keeps_answering_syncs_when_finishing() {
+        let mut state = receiving(1);
+        state.on_message(part(0));
+        state.stored();
+        let actions = state.on_message(Message::Sync { ids: vec![0] });
+        assert_eq!(
+            actions,
+            vec![ReceiverAction::Sync {
+                ack: vec![0],
+                loss: vec![],
+            }]
+        );
+    }
+
+    #[test]
+    fn receiver_completes_empty_transfers() {
+        let mut state = ReceiverState::new();
+        let actions = state.on_message(send(0));
+        assert_eq!(actions.last(), Some(&ReceiverAction::Complete));
+        assert_eq!(state.phase(), Phase::Finishing);
+    }
+
+    #[test]
+    fn receiver_resumes_with_what_it_stored() {
+        let mut state = ReceiverState::new();
+        state.resume(5, [0, 1, 3, 9]);
+        assert!(state.resumed());
+        assert!(state.on_message(part(1)).is_empty());
+        let resync = || Message::Resync {
+            first: 0,
+            end: 5,
+            ranges: vec![0..2, 3..4],
+        };
+        for _ in 0..RESYNC_ANSWERS {
+            assert_eq!(
+                state.on_message(Message::Sync { ids: vec![2, 3] }),
+                vec![
+                    ReceiverAction::Sync {
+                        ack: vec![3],
+                        loss: vec![2],
+                    },
+                    ReceiverAction::Resync(vec![resync()]),
+                ]
+            );
+        }
+        assert_eq!(state.on_message(Message::Sync { ids: vec![] }).len(), 1);
+        assert_eq!(written(&state.on_message(part(2))), vec![2]);
+        assert_eq!(written(&state.on_message(part(4))), vec![4]);
+        assert_eq!(state.stored(), Some(ReceiverAction::Complete));
+    }
+
+    #[test]
+    fn receiver_splits_long_resyncs() {
+        let mut state = ReceiverState::new();
+        let nb_parts = 4 * MAX_RESYNC_RANGES as u32;
+        state.resume(nb_parts, (0..nb_parts).step_by(2));
+        let [ReceiverAction::Sync { .. }, ReceiverAction::Resync(messages)] =
+            &state.on_message(Message::Sync { ids: vec![] })[..]
+        else {
+            panic!("No Resync");
+        };
+        let mut listed = Vec::new();
+        let mut next = 0;
+        for message in messages {
+            let Message::Resync { first, end, ranges } = message else {
+                panic!("{message:?}");
+            };
+            assert_eq!(*first, next);
+            assert!(ranges.len() <= MAX_RESYNC_RANGES);
+            listed.extend(ranges.iter().flat_map(|range| range.clone()));
+            next = *end;
+        }
+        assert_eq!(messages.len(), 2);
+        assert_eq!(next, nb_parts);
+        assert_eq!(listed, (0..nb_parts).step_by(2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn receiver_chains_next_transfer() {
+        let mut state = receiving(1);
+        state.on_message(part(0));
+        state.stored();
+        let actions = state.on_message(send(2));
+        assert!(matches!(
+            actions[..],
+            [ReceiverAction::Next(Offer { parts: 2, .. })]
+        ));
+        assert_eq!(state.phase(), Phase::Transferring);
+        assert_eq!(written(&state.on_message(part(0))), vec![0]);
+    }
+
+    #[test]
+    fn receiver_stops_on_abort() {
+        let mut state = receiving(2);
+        state.on_message(part(0));
+        let actions = state.on_message(Message::Abort {
+            reason: "quota".to_string(),
+        });
+        assert_eq!(actions, vec![ReceiverAction::Aborted("quota".to_string())]);
+        assert_eq!(state.phase(), Phase::Done);
+    }
+
+    #[test]
+    fn sender_handshake() {
+        let mut state = SenderState::new(2);
+        assert_eq!(state.phase(), Phase::Idle);
+        state.start();
+        assert_eq!(state.phase(), Phase::Handshaking);
+        state.on_message(Message::Accept { capabilities: None });
+        assert_eq!(state.phase(), Phase::Transferring);
+        // A duplicated Accept changes nothing.
+        assert!(state
+            .on_message(Message::Accept { capabilities: None })
+            .is_empty());
+        assert_eq!(state.phase(), Phase::Transferring);
+    }
+
+    #[test]
+    fn sender_keeps_to_the_parts_in_flight_the_receiver_takes() {
+        let mut state = SenderState::new(3);
+        state.start();
+        assert_eq!(state.room(), None);
+        state.on_message(Message::Accept {
+            capabilities: Some(capabilities(Some(2))),
+        });
+        assert_eq!(state.room(), Some(2));
+        state.track(0, vec![2, 0], Instant::now());
+        state.track(1, vec![2, 1], Instant::now());
+        assert_eq!(state.room(), Some(0));
+        state.on_message(Message::Ack { ids: vec![0] });
+        assert_eq!(state.room(), Some(1));
+    }
+
+    #[test]
+    fn sender_holds_its_parts_while_paused() {
+        let mut state = accepted_sender(3);
+        assert_eq!(state.room(), None);
+        let actions = state.on_message(Message::Pause { paused: true });
+        assert_eq!(actions, vec![SenderAction::Paused(true)]);
+        assert_eq!(state.room(), Some(0));
+        assert!(state.on_message(Message::Pause { paused: true }).is_empty());
+        let actions = state.on_message(Message::Pause { paused: false });
+        assert_eq!(actions, vec![SenderAction::Paused(false)]);
+        assert_eq!(state.room(), None);
+    }
+
+    #[test]
+    fn sender_ignores_punches() {
+        let mut state = SenderState::new(1);
+        state.start();
+        assert!(state.on_message(Message::Punch).is_empty());
+        assert_eq!(state.phase(), Phase::Handshaking);
+    }
+
+    #[test]
+    fn sender_ignores_acks_before_accept() {
+        let mut state = SenderState::new(1);
+        state.start();
+        let actions = state.on_message(Message::Ack { ids: vec![0] });
+        assert!(matches!(actions[..], [SenderAction::Unexpected(_)]));
+        assert_eq!(state.phase(), Phase::Handshaking);
+    }
+
+    #[test]
+    fn sender_empty_transfer_is_done_on_accept() {
+        let mut state = SenderState::new(0);
+        state.start();
+        state.on_message(Message::Accept { capabilities: None });
+        assert_eq!(state.phase(), Phase::Done);
+    }
+
+    #[test]
+    fn sender_done_once_everything_is_acked() {
+        let mut state = accepted_sender(3);
+        assert_eq!(state.phase(), Phase::Finishing);
+        state.on_message(Message::Ack { ids: vec![2, 0] });
+        assert_eq!(state.phase(), Phase::Finishing);
+        let actions = state.on_message(Message::Ack { ids: vec![1] });
+        assert_eq!(
+            actions,
+            vec![SenderAction::Progress(Progress {
+                parts_done: 3,
+                parts_total: 3,
+            })]
+        );
+        assert_eq!(state.phase(), Phase::Done);
+    }
+
+    #[test]
+    fn sender_counts_duplicate_acks_once() {
+        let mut state = accepted_sender(2);
+        state.on_message(Message::Ack { ids: vec![0] });
+        let actions = state.on_message(Message::Ack { ids: vec![0, 0] });
+        assert_eq!(
+            actions,
+            vec![SenderAction::Progress(Progress {
+                parts_done: 1,
+                parts_total: 2,
+            })]
+        );
+        assert_eq!(state.phase(), Phase::Finishing);
+    }
+
+    #[test]
+    fn sender_resends_lost_parts() {
+        let mut state = accepted_sender(3);
+        assert!(state
+            .on_message(Message::Loss { ids: vec![2, 1] })
+            .is_empty());
+        assert_eq!(
+            state.due(Instant::now()).unwrap(),
+            vec![
+                SenderAction::Resend(vec![2, 1]),
+                SenderAction::Resend(vec![2, 2])
+            ]
+        );
+    }
+
+    #[test]
+    fn sender_leaves_lost_parts_resent_within_a_round_trip() {
+        let mut state = accepted_sender(2);
+        let now = Instant::now();
+        state.asked(now);
+        state.answered(now + Duration::from_millis(50));
+        state.on_message(Message::Loss { ids: vec![0] });
+        let resent = now + Duration::from_millis(60);
+        assert_eq!(state.due(resent).unwrap().len(), 1);
+        // The Loss raced with the part sent again.
+        state.on_message(Message::Loss { ids: vec![0] });
+        assert!(state
+            .due(resent + Duration::from_millis(10))
+            .unwrap()
+            .is_empty());
+        state.on_message(Message::Loss { ids: vec![0] });
+        assert_eq!(
+            state.due(resent + Duration::from_millis(60)).unwrap(),
+            vec![SenderAction::Resend(vec![2, 0])]
+        );
+    }
+
+    #[test]
+    fn sender_resends_parts_past_their_timeout_with_backoff() {
+        let mut state = accepted_sender(2);
+        let now = Instant::now();
+        state.on_message(Message::Ack { ids: vec![1] });
+        assert!(state
+            .due(now + Duration::from_millis(900))
+            .unwrap()
+            .is_empty());
+        // The first round trip is not known yet, parts wait a second. Deadlines are
+        // rounded up to the next millisecond.
+        let first = now + Duration::from_millis(1001);
+        assert_eq!(
+            state.due(first).unwrap(),
+            vec![SenderAction::Resend(vec![2, 0])]
+        );
+        assert!(state
+            .due(first + Duration::from_millis(1900))
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            state
+                .due(first + Duration::from_millis(2001))
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(state.counts().retransmissions, 2);
+    }
+
+    #[test]
+    fn sender_gives_up_on_parts_never_acknowledged() {
+        let mut state = accepted_sender(1);
+        state.max_retries(2);
+        let mut now = Instant::now();
+        for _ in 0..2 {
+            now += Duration::from_secs(60);
+            assert_eq!(state.due(now).unwrap().len(), 1);
+        }
+        now += Duration::from_secs(60);
+        assert_eq!(
+            state.due(now).unwrap_err(),
+            "part 0 was not acknowledged after 3 sends"
+        );
+        assert_eq!(state.unanswered(), Some(3));
+    }
+
+    #[test]
+    fn sender_repeats_digests_until_their_blocks_are_acked() {
+        let nb_parts = 3 * merkle::BLOCK_PARTS;
+        let mut state = accepted_sender(nb_parts);
+        for block in [0, 1, 2] {
+            state.digested(block, [block as u8; 32]);
+        }
+        state.on_message(Message::Ack {
+            ids: merkle::parts_of(1, nb_parts).collect(),
+        });
+        let digests: Vec<Message> = state
+            .sync(Instant::now())
+            .into_iter()
+            .filter(|message| matches!(message, Message::Digests { .. }))
+            .collect();
+        assert_eq!(
+            digests,
+            [
+                Message::Digests {
+                    first: 0,
+                    digests: vec![[0; 32]],
+                },
+                Message::Digests {
+                    first: 2,
+                    digests: vec![[2; 32]],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn sender_takes_received_as_acks() {
+        let mut state = accepted_sender(6);
+        state.on_message(Message::Ack { ids: vec![1] });
+        let actions = state.on_message(Message::Received {
+            below: 3,
+            ranges: vec![4..5, Range { start: 9, end: 2 }, 5..100],
+        });
+        assert_eq!(
+            actions,
+            vec![SenderAction::Progress(Progress {
+                parts_done: 5,
+                parts_total: 6,
+            })]
+        );
+        assert_eq!(state.counts().duplicate_acks, 0);
+        assert!(
+            matches!(&state.sync(Instant::now())[..], [Message::Sync { ids }] if *ids == vec![3])
+        );
+        state.on_message(Message::Received {
+            below: 6,
+            ranges: vec![],
+        });
+        assert_eq!(state.phase(), Phase::Done);
+    }
+
+    #[test]
+    fn sender_sends_again_what_a_restarted_receiver_lost() {
+        let mut state = accepted_sender(6);
+        state.on_message(Message::Ack {
+            ids: vec![0, 1, 2, 3],
+        });
+        let resync = || Message::Resync {
+            first: 0,
+            end: 6,
+            ranges: vec![0..1, 4..5],
+        };
+        assert_eq!(
+            state.on_message(resync()),
+            vec![
+                SenderAction::Progress(Progress {
+                    parts_done: 2,
+                    parts_total: 6,
+                }),
+                SenderAction::Reread(vec![1, 2, 3]),
+            ]
+        );
+        // Part 5 was on the wire, and goes again.
+        assert_eq!(
+            state.due(Instant::now()).unwrap(),
+            vec![SenderAction::Resend(vec![2, 5])]
+        );
+        assert!(state.track_again(1, vec![2, 1], Instant::now()));
+        assert!(!state.track_again(4, vec![2, 4], Instant::now()));
+        // Repeated, it leaves the parts being read again be.
+        assert_eq!(
+            state.on_message(resync()),
+            vec![SenderAction::Progress(Progress {
+                parts_done: 2,
+                parts_total: 6,
+            })]
+        );
+        state.on_message(Message::Ack {
+            ids: vec![1, 2, 3, 5],
+        });
+        assert_eq!(state.phase(), Phase::Done);
+    }
+
+    #[test]
+    fn restarted_sender_reads_again_what_the_receiver_does_not_have() {
+        let mut state = SenderState::new(5);
+        state.presume(3);
+        state.start();
+        state.on_message(Message::Accept { capabilities: None });
+        for id in 3..5 {
+            state.track(id, vec![2, id as u8], Instant::now());
+        }
+        state.sent_all();
+        assert_eq!(state.acked_below(), 0);
+        let ids = |messages: Vec<Message>| match &messages[..] {
+            [Message::Sync { ids }] => ids.clone(),
+            other => panic!("unexpected {other:?}"),
+        };
+        assert_eq!(ids(state.sync(Instant::now())), vec![0, 1, 2, 3, 4]);
+
+        state.on_message(Message::Received {
+            below: 2,
+            ranges: Vec::new(),
+        });
+        assert_eq!(state.acked_below(), 2);
+        state.on_message(Message::Loss { ids: vec![2, 4] });
+        assert_eq!(
+            state.due(Instant::now()).unwrap(),
+            vec![
+                SenderAction::Resend(vec![2, 4]),
+                SenderAction::Reread(vec![2]),
+            ]
+        );
+        assert!(state.track_again(2, vec![2, 2], Instant::now()));
+        state.on_message(Message::Ack { ids: vec![2, 3, 4] });
+        assert_eq!(state.acked_below(), 5);
+        assert_eq!(state.phase(), Phase::Done);
+    }
+
+    #[test]
+    fn sender_does_not_resend_acked_parts() {
+        let mut state = accepted_sender(2);
+        state.on_message(Message::Ack { ids: vec![1] });
+        // The Loss was sent before the part arrived, and the Ack overtook it.
+        state.on_message(Message::Loss { ids: vec![1] });
+        assert!(state.due(Instant::now()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn sender_counts_what_happened() {
+        let mut state = accepted_sender(3);
+        let now = Instant::now();
+        state.asked(now);
+        state.on_message(Message::Loss { ids: vec![1, 2] });
+        state.due(now).unwrap();
+        state.answered(now + Duration::from_millis(20));
+        state.on_message(Message::Ack { ids: vec![1, 1] });
+        state.on_message(Message::Loss { ids: vec![1] });
+        state.due(now + Duration::from_millis(40)).unwrap();
+        assert_eq!(
+            state.counts(),
+            &SenderCounts {
+                parts_sent: 5,
+                retransmissions: 2,
+                reported_lost: 3,
+                duplicate_acks: 1,
+            }
+        );
+        assert_eq!(state.rtt().unwrap().max, Duration::from_millis(20));
+    }
+
+    #[test]
+    fn sender_syncs_unacked_parts() {
+        let mut state = accepted_sender(4);
+        state.on_message(Message::Ack { ids: vec![0, 2] });
+        let syncs = state.sync(Instant::now());
+        assert_eq!(syncs.len(), 1);
+        assert!(matches!(&syncs[0], Message::Sync { ids } if *ids == vec![1, 3]));
+    }
+
+    #[test]
+    fn sender_splits_large_syncs() {
+        let nb_parts = SYNC_IDS_PER_PACKET as u32 * 2 + 1;
+        let mut state = accepted_sender(nb_parts);
+        let syncs = state.sync(Instant::now());
+        assert_eq!(syncs.len(), 3);
+        for sync in syncs {
+            assert!(sync.serialize().len() <= MTU);
+        }
+    }
+
+    #[test]
+    fn sender_caps_syncs_and_goes_on_from_where_they_stopped() {
+        let cap = (SYNC_IDS_PER_PACKET * MAX_SYNC_PACKETS) as u32;
+        let mut state = accepted_sender(cap + 10);
+        let asked = |syncs: Vec<Message>| -> Vec<u32> {
+            syncs
+                .into_iter()
+                .flat_map(|sync| match sync {
+                    Message::Sync { ids } => ids,
+                    _ => Vec::new(),
+                })
+                .collect()
+        };
+        let first = asked(state.sync(Instant::now()));
+        assert_eq!(first, (0..cap).collect::<Vec<_>>());
+        let second = asked(state.sync(Instant::now()));
+        assert_eq!(second[..10], (cap..cap + 10).collect::<Vec<_>>());
+        assert_eq!(second[10..], (0..cap - 10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn sender_leaves_recent_and_reported_parts_out_of_syncs() {
+        let mut state = accepted_sender(3);
+        let now = Instant::now();
+        state.asked(now);
+        state.answered(now + Duration::from_millis(50));
+        state.on_message(Message::Loss { ids: vec![1] });
+        let resent = now + Duration::from_millis(60);
+        state.due(resent).unwrap();
+        state.track(3, vec![2, 3], resent);
+        let asked = |state: &mut SenderState, at| match &state.sync(at)[..] {
+            [Message::Sync { ids }] => ids.clone(),
+            syncs => panic!("{syncs:?}"),
+        };
+        assert_eq!(asked(&mut state, resent), vec![0, 2]);
+        let later = resent + Duration::from_millis(100);
+        assert_eq!(asked(&mut state, later), vec![0, 2, 3]);
+        let later = resent + SYNC_INTERVAL + Duration::from_millis(50);
+        assert_eq!(asked(&mut state, later), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn sender_follows_rate_limit() {
+        let mut state = accepted_sender(1);
+        assert_eq!(state.rate_limit(), None);
+        let actions = state.on_message(Message::RateLimit {
+            bytes_per_sec: 1000,
+        });
+        assert_eq!(actions, vec![SenderAction::RateLimit(Some(1000))]);
+        assert_eq!(state.rate_limit(), Some(1000));
+        // Repeated caps are not reported again.
+        assert!(state
+            .on_message(Message::RateLimit {
+                bytes_per_sec: 1000,
+            })
+            .is_empty());
+        let actions = state.on_message(Message::RateLimit { bytes_per_sec: 0 });
+        assert_eq!(actions, vec![SenderAction::RateLimit(None)]);
+        assert_eq!(state.rate_limit(), None);
+    }
+
+    #[test]
+    fn background_senders_pace_themselves() {
+        let mut state = accepted_sender(1);
+        state.control(Congestion::Background);
+        let rate = state.rate_limit().unwrap();
+        state.on_message(Message::RateLimit {
+            bytes_per_sec: 1000,
+        });
+        assert_eq!(state.rate_limit(), Some(1000));
+        state.on_message(Message::RateLimit { bytes_per_sec: 0 });
+        assert_eq!(state.rate_limit(), Some(rate));
+        state.control(Congestion::Unlimited);
+        assert_eq!(state.rate_limit(), None);
+    }
+
+    #[test]
+    fn sender_stays_under_the_path_limit() {
+        let mut state = accepted_sender(1);
+        state.limit_path(Some(5000));
+        assert_eq!(state.rate_limit(), Some(5000));
+        state.on_message(Message::RateLimit {
+            bytes_per_sec: 1000,
+        });
+        assert_eq!(state.rate_limit(), Some(1000));
+        state.on_message(Message::RateLimit {
+            bytes_per_sec: 9000,
+        });
+        assert_eq!(state.rate_limit(), Some(5000));
+        // Lifting the receiver's cap keeps the path's.
+        state.on_message(Message::RateLimit { bytes_per_sec: 0 });
+        assert_eq!(state.rate_limit(), Some(5000));
+    }
+
+    #[test]
+    fn sender_follows_its_own_cap() {
+        let mut state = accepted_sender(1);
+        let cap = RateCap::new(Some(3000));
+        state.cap(cap.clone());
+        assert_eq!(state.rate_limit(), Some(3000));
+        cap.set(Some(2000));
+        assert_eq!(state.rate_limit(), Some(2000));
+        state.on_message(Message::RateLimit {
+            bytes_per_sec: 1000,
+        });
+        assert_eq!(state.rate_limit(), Some(1000));
+        cap.set(None);
+        state.on_message(Message::RateLimit { bytes_per_sec: 0 });
+        assert_eq!(state.rate_limit(), None);
+    }
+
+    #[test]
+    fn sender_reports_hop_stats_in_any_phase() {
+        let stats = HopStats {
+            hop: "relay".to_string(),
+            to_receiver: 10,
+            to_sender: 2,
+            bytes: 14_000,
+        };
+        let mut state = SenderState::new(1);
+        state.start();
+        assert_eq!(
+            state.on_message(Message::HopStats(stats.clone())),
+            vec![SenderAction::HopStats(stats.clone())]
+        );
+        assert_eq!(state.phase(), Phase::Handshaking);
+        let mut state = accepted_sender(1);
+        assert_eq!(
+            state.on_message(Message::HopStats(stats.clone())),
+            vec![SenderAction::HopStats(stats)]
+        );
+    }
+
+    #[test]
+    fn sender_acks_can_beat_sent_all() {
+        let mut state = SenderState::new(1);
+        state.start();
+        state.on_message(Message::Accept { capabilities: None });
+        state.track(0, vec![2, 0], Instant::now());
+        state.on_message(Message::Ack { ids: vec![0] });
+        state.sent_all();
+        assert_eq!(state.phase(), Phase::Done);
+    }
+
+    #[test]
+    fn sender_aborted_by_receiver() {
+        let mut state = SenderState::new(2);
+        state.start();
+        let actions = state.on_message(Message::Abort {
+            reason: "quota".to_string(),
+        });
+        assert_eq!(actions, vec![SenderAction::Aborted("quota".to_string())]);
+        assert_eq!(state.phase(), Phase::Done);
+        assert_eq!(state.aborted(), Some("quota"));
+    }
+
+    #[test]
+    fn sender_abort_forgets_parts_in_flight() {
+        let mut state = accepted_sender(3);
+        state.abort("quota".to_string());
+        assert_eq!(state.phase(), Phase::Done);
+        assert!(state.sync(Instant::now()).is_empty());
+    }
+
+    #[test]
+    fn sender_ignores_abort_once_done() {
+        let mut state = accepted_sender(1);
+        state.on_message(Message::Ack { ids: vec![0] });
+        let actions = state.on_message(Message::Abort {
+            reason: "late".to_string(),
+        });
+        assert!(matches!(actions[..], [SenderAction::Unexpected(_)]));
+        assert_eq!(state.aborted(), None);
+    }
+
+    #[test]
+    fn sender_recycles_acked_packets() {
+        let mut state = accepted_sender(3);
+        assert!(state.recycle().is_empty());
+        state.on_message(Message::Ack { ids: vec![0, 2, 2] });
+        let mut released = state.recycle();
+        released.sort();
+        assert_eq!(released, vec![vec![2, 0], vec![2, 2]]);
+        assert!(state.recycle().is_empty());
+        assert_eq!(state.packet(1), Some(&[2, 1][..]));
+        assert_eq!(state.packet(0), None);
+    }
+}
@@ -0,0 +1,293 @@
+//! What happened during a transfer, collected as it runs and summed up once it ends, to
+//! tell why it was slow.
+
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Summary of a transfer, as seen from the sender.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransferStats {
+    pub filename: String,
+    pub bytes: u64,
+    pub parts: u32,
+    /// From the Send request to the last Ack.
+    pub elapsed: Duration,
+    /// Parts put on the wire, retransmissions included.
+    pub parts_sent: u64,
+    pub retransmissions: u64,
+    /// Parts the receiver reported lost.
+    pub reported_lost: u64,
+    /// Acks for parts that were already acknowledged.
+    pub duplicate_acks: u64,
+    /// Round trips between a Sync and its answer, and of the handshake.
+    pub rtt: Option<Percentiles>,
+    /// Calls into the transport to send, each a single syscall with batching transports.
+    pub send_calls: u64,
+    /// Calls into the transport to receive, timed out ones included.
+    pub recv_calls: u64,
+    /// Packet buffers allocated, and reused from earlier parts instead.
+    pub buffers_allocated: u64,
+    pub buffers_reused: u64,
+}
+
+/// Distribution of round trip times.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Percentiles {
+    pub samples: usize,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl Percentiles {
+    /// Percentiles of `samples`, by the nearest rank.
+    pub fn of(mut samples: Vec<Duration>) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+        samples.sort_unstable();
+        let rank = |percent: usize| samples[(samples.len() * percent).div_ceil(100).max(1) - 1];
+        Some(Percentiles {
+            samples: samples.len(),
+            p50: rank(50),
+            p90: rank(90),
+            p99: rank(99),
+            max: samples[samples.len() - 1],
+        })
+    }
+}
+
+impl TransferStats {
+    /// Bytes of the file per second.
+    pub fn throughput(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// Share of the parts put on the wire that had to be sent again.
+    pub fn loss_rate(&self) -> f64 {
+        if self.parts_sent == 0 {
+            return 0.0;
+        }
+        self.retransmissions as f64 / self.parts_sent as f64
+    }
+
+    /// One line JSON object, durations in milliseconds.
+    pub fn to_json(&self) -> String {
+        let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        let rtt = match &self.rtt {
+            None => "null".to_string(),
+            Some(rtt) => format!(
+                "{{\"samples\":{},\"p50_ms\":{:.3},\"p90_ms\":{:.3},\"p99_ms\":{:.3},\"max_ms\":{:.3}}}",
+                rtt.samples,
+                millis(rtt.p50),
+                millis(rtt.p90),
+                millis(rtt.p99),
+                millis(rtt.max)
+            ),
+        };
+        format!(
+            "{{\"filename\":{},\"bytes\":{},\"parts\":{},\"elapsed_ms\":{:.3},\
+             \"throughput_bytes_per_sec\":{:.0},\"parts_sent\":{},\"retransmissions\":{},\
+             \"reported_lost\":{},\"duplicate_acks\":{},\"loss_rate\":{:.6},\"rtt\":{},\
+             \"send_calls\":{},\"recv_calls\":{},\"buffers_allocated\":{},\
+             \"buffers_reused\":{}}}",
+            json_string(&self.filename),
+            self.bytes,
+            self.parts,
+            millis(self.elapsed),
+            self.throughput(),
+            self.parts_sent,
+            self.retransmissions,
+            self.reported_lost,
+            self.duplicate_acks,
+            self.loss_rate(),
+            rtt,
+            self.send_calls,
+            self.recv_calls,
+            self.buffers_allocated,
+            self.buffers_reused
+        )
+    }
+}
+
+/// Table for humans.
+impl fmt::Display for TransferStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rows = [
+            ("File", self.filename.clone()),
+            (
+                "Size",
+                format!("{} bytes in {} parts", self.bytes, self.parts),
+            ),
+            ("Elapsed", format!("{:.3?}", self.elapsed)),
+            (
+                "Throughput",
+                format!("{:.2} MiB/s", self.throughput() / (1024.0 * 1024.0)),
+            ),
+            ("Parts sent", self.parts_sent.to_string()),
+            ("Retransmissions", self.retransmissions.to_string()),
+            ("Reported lost", self.reported_lost.to_string()),
+            ("Duplicate acks", self.duplicate_acks.to_string()),
+            ("Loss rate", format!("{:.2}%", self.loss_rate() * 100.0)),
+            (
+                "RTT p50/p90/p99/max",
+                match &self.rtt {
+                    None => "-".to_string(),
+                    Some(rtt) => format!(
+                        "{:.1?} / {:.1?} / {:.1?} / {:.1?} ({} samples)",
+                        rtt.p50, rtt.p90, rtt.p99, rtt.max, rtt.samples
+                    ),
+                },
+            ),
+            (
+                "Send / receive calls",
+                format!("{} / {}", self.send_calls, self.recv_calls),
+            ),
+            (
+                "Buffers allocated / reused",
+                format!("{} / {}", self.buffers_allocated, self.buffers_reused),
+            ),
+        ];
+        let width = rows.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+        for (name, value) in rows {
+            writeln!(f, "{name:<width$}  {value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// `s` as a JSON string, quoted and escaped.
+pub fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// What the sender's state machine counts, see [`TransferStats`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct SenderCounts {
+    pub parts_sent: u64,
+    pub retransmissions: u64,
+    pub reported_lost: u64,
+    pub duplicate_acks: u64,
+}
+
+/// Calls a socket and its clones made into the transport.
+#[derive(Debug, Default)]
+pub(crate) struct IoCalls {
+    sends: AtomicU64,
+    recvs: AtomicU64,
+}
+
+impl IoCalls {
+    pub fn sent(&self) {
+        self.sends.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn received(&self) {
+        self.recvs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Sends and receives so far.
+    pub fn get(&self) -> (u64, u64) {
+        (
+            self.sends.load(Ordering::Relaxed),
+            self.recvs.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Round trips between a request and the first answer after it.
+#[derive(Debug, Default)]
+pub(crate) struct RoundTrips {
+    asked: Option<Instant>,
+    samples: Vec<Duration>,
+}
+
+impl RoundTrips {
+    /// A request went out at `now`. An earlier one still unanswered is forgotten, as its
+    /// answer could not be told apart from this one's.
+    pub fn asked(&mut self, now: Instant) {
+        self.asked = Some(now);
+    }
+
+    /// The answer came at `now`. Returns the round trip, unless it was already answered.
+    pub fn answered(&mut self, now: Instant) -> Option<Duration> {
+        let rtt = now.saturating_duration_since(self.asked.take()?);
+        self.samples.push(rtt);
+        Some(rtt)
+    }
+
+    pub fn percentiles(&self) -> Option<Percentiles> {
+        Percentiles::of(self.samples.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_take_the_nearest_rank() {
+        let samples = (1..=200).map(Duration::from_millis).collect();
+        let rtt = Percentiles::of(samples).unwrap();
+        assert_eq!(rtt.samples, 200);
+        assert_eq!(rtt.p50, Duration::from_millis(100));
+        assert_eq!(rtt.p90, Duration::from_millis(180));
+        assert_eq!(rtt.p99, Duration::from_millis(198));
+        assert_eq!(rtt.max, Duration::from_millis(200));
+        let single = Percentiles::of(vec![Duration::from_millis(7)]).unwrap();
+        assert_eq!(single.p50, Duration::from_millis(7));
+        assert_eq!(single.p99, Duration::from_millis(7));
+        assert_eq!(Percentiles::of(Vec::new()), None);
+
+        let mut trips = RoundTrips::default();
+        let now = Instant::now();
+        trips.answered(now);
+        trips.asked(now);
+        trips.asked(now + Duration::from_millis(10));
+        trips.answered(now + Duration::from_millis(15));
+        trips.answered(now + Duration::from_millis(40));
+        assert_eq!(trips.percentiles().unwrap().max, Duration::from_millis(5));
+    }
+
+    #[test]
+    fn stats_render_as_json() {
+        let stats = TransferStats {
+            filename: "a \"b\"\n".to_string(),
+            bytes: 2_000_000,
+            parts: 1346,
+            elapsed: Duration::from_secs(2),
+            parts_sent: 1400,
+            retransmissions: 54,
+            reported_lost: 60,
+            duplicate_acks: 3,
+            rtt: None,
+            send_calls: 100,
+            recv_calls: 200,
+            buffers_allocated: 1024,
+            buffers_reused: 376,
+        };
+        assert_eq!(stats.throughput(), 1_000_000.0);
+        let json = stats.to_json();
+        assert!(json.starts_with("{\"filename\":\"a \\\"b\\\"\\u000a\",\"bytes\":2000000,"));
+        assert!(json.contains("\"loss_rate\":0.038571,\"rtt\":null,"));
+        assert!(json.ends_with("\"buffers_reused\":376}"));
+        assert!(stats
+            .to_string()
+            .contains("Loss rate                   3.86%"));
+    }
+}
@@ -0,0 +1,155 @@
+//! Running as a systemd service: the UDP socket passed with socket activation (see
+//! sd_listen_fds(3)), and the readiness, status and watchdog notifications of
+//! `Type=notify` services (see sd_notify(3)). `contrib/systemd` has units using them.
+
+use std::{
+    env,
+    io::{self, ErrorKind},
+    net::UdpSocket,
+    os::{
+        fd::{FromRawFd, RawFd},
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram},
+    },
+    sync::Arc,
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use tracing::{info, warn};
+
+/// First descriptor systemd passes, SD_LISTEN_FDS_START.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// The UDP socket systemd passed us, if it started us for one, as set with
+/// `ListenDatagram=` in the socket unit. The variables telling about it are removed, so
+/// that the commands we run do not take it for theirs: call it before starting threads.
+pub fn listen_socket() -> io::Result<Option<UdpSocket>> {
+    let ours = env::var("LISTEN_PID").is_ok_and(|pid| pid == std::process::id().to_string());
+    let fds = env::var("LISTEN_FDS").ok();
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(name);
+    }
+    let (true, Some(fds)) = (ours, fds) else {
+        return Ok(None);
+    };
+    match fds.parse::<u32>() {
+        Ok(0) => Ok(None),
+        // SAFETY: systemd hands the descriptor over to us, nothing else owns it.
+        Ok(1) => adopt(unsafe { UdpSocket::from_raw_fd(LISTEN_FDS_START) }).map(Some),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("systemd passed {fds} sockets, sanic listens on a single datagram one"),
+        )),
+    }
+}
+
+/// Checks `socket` is a bound one, and keeps it from leaking into the commands we run.
+fn adopt(socket: UdpSocket) -> io::Result<UdpSocket> {
+    // Passed descriptors are inherited on exec, their duplicates are not.
+    let socket = socket.try_clone()?;
+    let addr = socket.local_addr()?;
+    info!(%addr, "Listening on the socket systemd passed.");
+    Ok(socket)
+}
+
+/// Tells systemd how the service is doing, through `NOTIFY_SOCKET`.
+#[derive(Debug)]
+pub struct Notifier {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+}
+
+impl Notifier {
+    /// Notifies the service manager that started us, if it wants to hear from us.
+    pub fn from_env() -> io::Result<Option<Self>> {
+        match env::var("NOTIFY_SOCKET") {
+            Ok(path) if !path.is_empty() => Notifier::connect(&path).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Notifies on the unix socket at `path`, in the abstract namespace if it starts
+    /// with `@`.
+    pub fn connect(path: &str) -> io::Result<Self> {
+        let addr = match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(path)?,
+        };
+        Ok(Notifier {
+            socket: UnixDatagram::unbound()?,
+            addr,
+        })
+    }
+
+    /// Sends `state`, newline separated assignments like `READY=1` or `STATUS=...`.
+    pub fn notify(&self, state: &str) -> io::Result<()> {
+        self.socket.send_to_addr(state.as_bytes(), &self.addr)?;
+        Ok(())
+    }
+
+    /// Keeps the watchdog of the service fed, if it has one (`WatchdogSec=`), from a
+    /// thread pinging it twice per period for as long as we run.
+    pub fn keep_alive(self: &Arc<Self>) -> Option<JoinHandle<()>> {
+        let ours =
+            env::var("WATCHDOG_PID").map_or(true, |pid| pid == std::process::id().to_string());
+        let usec = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+        if !ours || usec == 0 {
+            return None;
+        }
+        let period = Duration::from_micros(usec) / 2;
+        let notifier = self.clone();
+        Some(std::thread::spawn(move || loop {
+            if let Err(err) = notifier.notify("WATCHDOG=1") {
+                warn!(error = %err, "Could not feed the systemd watchdog.");
+            }
+            std::thread::sleep(period);
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::{AsRawFd, IntoRawFd};
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn notifications_reach_the_service_manager() {
+        let dir = TempDir::new("notify");
+        let path = dir.join("notify");
+        let manager = UnixDatagram::bind(&path).unwrap();
+        let notifier = Notifier::connect(path.to_str().unwrap()).unwrap();
+        notifier.notify("READY=1\nSTATUS=Listening").unwrap();
+        let mut buf = [0; 64];
+        let len = manager.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1\nSTATUS=Listening");
+
+        let name = format!("sanic-notify-{}", std::process::id());
+        let manager =
+            UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(&name).unwrap()).unwrap();
+        let notifier = Notifier::connect(&format!("@{name}")).unwrap();
+        notifier.notify("STOPPING=1").unwrap();
+        let len = manager.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"STOPPING=1");
+    }
+
+    #[test]
+    fn passed_sockets_are_not_inherited() {
+        let passed = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = passed.local_addr().unwrap();
+        let fd = passed.into_raw_fd();
+        // SAFETY: the descriptor was just given up.
+        let socket = adopt(unsafe { UdpSocket::from_raw_fd(fd) }).unwrap();
+        assert_eq!(socket.local_addr().unwrap(), addr);
+        let fd = socket.as_raw_fd();
+        let info = std::fs::read_to_string(format!("/proc/self/fdinfo/{fd}")).unwrap();
+        let flags = info
+            .lines()
+            .find_map(|line| line.strip_prefix("flags:"))
+            .unwrap();
+        // O_CLOEXEC
+        assert_ne!(u32::from_str_radix(flags.trim(), 8).unwrap() & 0o2000000, 0);
+    }
+}
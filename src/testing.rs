@@ -0,0 +1,54 @@
+//! Fixtures shared by the tests: a scratch directory, and a receiver with its senders on
+//! a [`MemoryNetwork`].
+
+use std::{net::SocketAddr, thread::JoinHandle, time::Duration};
+
+pub(crate) use crate::tempdir::TempDir;
+use crate::{
+    transport::{MemoryNetwork, MemoryTransport, Transport},
+    ReceiveError, Receiver, Sender,
+};
+
+/// A [`MemoryNetwork`] with a receiver end at [`Link::receiver_addr`], for transfers that
+/// never touch a socket.
+#[derive(Default)]
+pub(crate) struct Link {
+    pub network: MemoryNetwork,
+}
+
+impl Link {
+    pub fn new() -> Self {
+        Link::default()
+    }
+
+    /// Where the receiver, or what stands in for it, listens.
+    pub fn receiver_addr(&self) -> SocketAddr {
+        "127.0.0.1:6666".parse().unwrap()
+    }
+
+    /// Transport of the receiver end, free again once dropped.
+    pub fn receiver_end(&self) -> MemoryTransport {
+        self.network.bind(self.receiver_addr()).unwrap()
+    }
+
+    /// Runs `receiver` on the receiver end, in a thread.
+    pub fn receive(&self, receiver: Receiver) -> JoinHandle<Result<(), ReceiveError>> {
+        let receiver = receiver.transport(self.receiver_end());
+        std::thread::spawn(move || receiver.receive())
+    }
+
+    /// A sender to the receiver end, from an address of its own.
+    pub fn sender(&self) -> Sender {
+        Sender::new(self.receiver_addr().to_string()).transport(self.endpoint("127.0.0.2:0"))
+    }
+
+    /// Endpoint at `addr`, port 0 picking one, for tests speaking the protocol themselves.
+    /// Reads give up after 5 seconds.
+    pub fn endpoint(&self, addr: &str) -> MemoryTransport {
+        let endpoint = self.network.bind(addr.parse().unwrap()).unwrap();
+        endpoint
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        endpoint
+    }
+}
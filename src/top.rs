@@ -0,0 +1,340 @@
+//! `sanic top`: a live table of the transfers of a running receiver or queue, polled over
+//! its control socket, with keys to pause, resume and cancel them.
+
+use std::{
+    collections::HashMap,
+    io::{self, IsTerminal, Read, Write},
+    path::Path,
+    process::{Command, Stdio},
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+use sanic::activity::Snapshot;
+
+use crate::{control, human_bytes, CliError};
+
+const BAR_WIDTH: usize = 20;
+
+pub fn top(socket: &Path, interval: Duration) -> Result<(), CliError> {
+    let keys = Keys::listen();
+    let mut table = Table::default();
+    let mut status = String::new();
+    let mut next_poll = Instant::now();
+    loop {
+        if Instant::now() >= next_poll {
+            let answers = control::run(socket, &["list-sessions".to_string()])?;
+            let sessions = answers
+                .first()
+                .and_then(|answer| Snapshot::parse_list(answer))
+                .ok_or_else(|| io::Error::other("the control socket answered nonsense"))?;
+            table.update(sessions);
+            next_poll = Instant::now() + interval;
+        }
+        draw(socket, &table, &status, keys.is_some())?;
+        let Some(keys) = &keys else {
+            std::thread::sleep(interval);
+            continue;
+        };
+        let key = match keys
+            .events
+            .recv_timeout(next_poll.saturating_duration_since(Instant::now()))
+        {
+            Ok(key) => key,
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            // Stdin closed.
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+        match key {
+            Key::Quit => return Ok(()),
+            Key::Up => table.selected = table.selected.saturating_sub(1),
+            Key::Down => {
+                table.selected = (table.selected + 1).min(table.rows.len().saturating_sub(1))
+            }
+            Key::Pause | Key::Cancel => {
+                let Some(row) = table.rows.get(table.selected) else {
+                    continue;
+                };
+                let verb = match key {
+                    Key::Cancel => "cancel",
+                    _ if row.session.paused => "resume",
+                    _ => "pause",
+                };
+                let command = format!("{verb} {}", row.session.id);
+                status = match control::run(socket, &[command]) {
+                    Ok(_) => format!("{verb}: {}", row.session.id),
+                    Err(err) => format!("{verb} {}: {err}", row.session.id),
+                };
+                // Show the change right away.
+                next_poll = Instant::now();
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Table {
+    rows: Vec<Row>,
+    selected: usize,
+    polled: Option<Instant>,
+}
+
+struct Row {
+    session: Snapshot,
+    /// Bytes per second since the previous poll, the average on the first.
+    rate: u64,
+}
+
+impl Table {
+    fn update(&mut self, sessions: Vec<Snapshot>) {
+        let now = Instant::now();
+        let since = self.polled.map(|polled| now - polled);
+        let before: HashMap<String, u64> = self
+            .rows
+            .drain(..)
+            .map(|row| (row.session.id.clone(), row.session.bytes()))
+            .collect();
+        self.rows = sessions
+            .into_iter()
+            .map(|session| {
+                let rate = match (since, before.get(&session.id)) {
+                    (Some(since), Some(&bytes)) if !since.is_zero() => {
+                        (session.bytes().saturating_sub(bytes) as f64 / since.as_secs_f64()) as u64
+                    }
+                    _ => session.rate(),
+                };
+                Row { session, rate }
+            })
+            .collect();
+        self.selected = self.selected.min(self.rows.len().saturating_sub(1));
+        self.polled = Some(now);
+    }
+}
+
+fn draw(socket: &Path, table: &Table, status: &str, interactive: bool) -> io::Result<()> {
+    let mut screen = String::from("\x1b[H\x1b[2J");
+    screen.push_str(&format!(
+        "sanic top - {} - {} session(s)\r\n\r\n",
+        socket.display(),
+        table.rows.len()
+    ));
+    screen.push_str(&format!(
+        "  {:<8}  {:<7}  {:<21}  {:<24}  {:<27}  {:>12}  {:>6}  {:>8}\r\n",
+        "ID", "DIR", "PEER", "FILE", "PROGRESS", "RATE", "LOST", "ETA"
+    ));
+    for (index, row) in table.rows.iter().enumerate() {
+        let session = &row.session;
+        let marker = if interactive && index == table.selected {
+            '>'
+        } else {
+            ' '
+        };
+        let peer = session
+            .peer
+            .map_or_else(|| "-".to_string(), |peer| peer.to_string());
+        let eta = match session.paused {
+            true => "paused".to_string(),
+            false => session.eta().map_or_else(|| "-".to_string(), clock),
+        };
+        screen.push_str(&format!(
+            "{marker} {:<8}  {:<7}  {:<21}  {:<24}  {}  {:>12}  {:>6}  {:>8}\r\n",
+            session.id,
+            session.direction,
+            peer,
+            truncate(&session.filename, 24),
+            bar(session.parts_done, session.parts_total),
+            format!("{}/s", human_bytes(row.rate)),
+            session.parts_lost,
+            eta
+        ));
+    }
+    if table.rows.is_empty() {
+        screen.push_str("  no transfer running\r\n");
+    }
+    screen.push_str("\r\n");
+    if interactive {
+        screen.push_str("j/k select  p pause/resume  c cancel  q quit");
+    }
+    if !status.is_empty() {
+        screen.push_str(&format!("    {status}"));
+    }
+    screen.push_str("\r\n");
+    let mut stdout = io::stdout().lock();
+    stdout.write_all(screen.as_bytes())?;
+    stdout.flush()
+}
+
+/// `[#####-----]  50%`, 27 characters wide.
+fn bar(done: u32, total: u32) -> String {
+    let ratio = match total {
+        0 => 1.0,
+        total => done as f64 / total as f64,
+    };
+    let filled = ((ratio * BAR_WIDTH as f64) as usize).min(BAR_WIDTH);
+    format!(
+        "[{}{}] {:>3}%",
+        "#".repeat(filled),
+        "-".repeat(BAR_WIDTH - filled),
+        (ratio * 100.0) as u32
+    )
+}
+
+/// `H:MM:SS`, or `M:SS` under an hour.
+fn clock(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs / 3600 {
+        0 => format!("{}:{:02}", secs / 60, secs % 60),
+        hours => format!("{hours}:{:02}:{:02}", secs / 60 % 60, secs % 60),
+    }
+}
+
+/// The end of `name`, which tells files apart better than its start.
+fn truncate(name: &str, width: usize) -> String {
+    let count = name.chars().count();
+    if count <= width {
+        return name.to_string();
+    }
+    let tail: String = name.chars().skip(count - (width - 1)).collect();
+    format!("~{tail}")
+}
+
+enum Key {
+    Up,
+    Down,
+    Pause,
+    Cancel,
+    Quit,
+}
+
+/// Keys pressed, read without waiting for enter while the terminal is switched out of its
+/// line mode, and switched back on drop.
+struct Keys {
+    events: mpsc::Receiver<Key>,
+    saved: String,
+}
+
+impl Keys {
+    /// None when stdin is not a terminal that `stty` can switch, the table then only shows.
+    fn listen() -> Option<Keys> {
+        if !io::stdin().is_terminal() {
+            return None;
+        }
+        let saved = stty(&["-g"])?;
+        // Ctrl-C comes as a key too, so that quitting always restores the terminal.
+        stty(&["-icanon", "-echo", "-isig", "min", "1"])?;
+        print!("\x1b[?25l");
+        let (keys, events) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut escape = Vec::new();
+            for byte in io::stdin().lock().bytes() {
+                let Ok(byte) = byte else {
+                    break;
+                };
+                // Arrows are ESC [ A and ESC [ B.
+                if byte == 0x1b || !escape.is_empty() {
+                    escape.push(byte);
+                    if escape.len() < 3 {
+                        continue;
+                    }
+                }
+                let key = match (escape.as_slice(), byte) {
+                    ([0x1b, b'[', b'A'], _) | ([], b'k') => Key::Up,
+                    ([0x1b, b'[', b'B'], _) | ([], b'j') => Key::Down,
+                    ([], b'p' | b' ') => Key::Pause,
+                    ([], b'c') => Key::Cancel,
+                    ([], b'q' | 0x03) => Key::Quit,
+                    _ => {
+                        escape.clear();
+                        continue;
+                    }
+                };
+                escape.clear();
+                if keys.send(key).is_err() {
+                    break;
+                }
+            }
+        });
+        Some(Keys {
+            events,
+            saved: saved.trim().to_string(),
+        })
+    }
+}
+
+impl Drop for Keys {
+    fn drop(&mut self) {
+        print!("\x1b[?25h");
+        let _ = io::stdout().flush();
+        stty(&[self.saved.as_str()]);
+    }
+}
+
+/// Runs `stty` on the terminal of stdin, and returns what it printed.
+fn stty(args: &[&str]) -> Option<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use sanic::{activity::Direction, PART_SIZE};
+
+    use super::*;
+
+    fn session(id: &str, parts_done: u32) -> Snapshot {
+        Snapshot {
+            id: id.to_string(),
+            direction: Direction::Receive,
+            peer: None,
+            filename: "a.bin".to_string(),
+            parts_done,
+            parts_total: 1000,
+            parts_lost: 0,
+            elapsed: Duration::from_secs(10),
+            paused: false,
+        }
+    }
+
+    #[test]
+    fn progress_fits_its_column() {
+        assert_eq!(bar(0, 10), "[--------------------]   0%");
+        assert_eq!(bar(5, 10), "[##########----------]  50%");
+        assert_eq!(bar(10, 10), "[####################] 100%");
+        assert_eq!(bar(0, 0), bar(10, 10));
+        assert_eq!(bar(1, 3).len(), 27);
+        assert_eq!(clock(Duration::from_secs(59)), "0:59");
+        assert_eq!(clock(Duration::from_secs(61 * 60 + 5)), "1:01:05");
+        assert_eq!(truncate("short.txt", 24), "short.txt");
+        assert_eq!(truncate("a-very-long-file-name.tar.gz", 12), "~name.tar.gz");
+        assert_eq!(truncate("ééééé", 4), "~ééé");
+    }
+
+    #[test]
+    fn rates_come_from_the_last_poll() {
+        let mut table = Table::default();
+        table.update(vec![session("a", 100), session("b", 0)]);
+        // The average over the session, without an earlier poll.
+        assert_eq!(table.rows[0].rate, 10 * PART_SIZE as u64);
+
+        table.selected = 1;
+        table.polled = Some(Instant::now() - Duration::from_secs(2));
+        table.update(vec![session("a", 300), session("c", 50)]);
+        let rate = table.rows[0].rate as f64 / PART_SIZE as f64;
+        assert!((99.0..=100.0).contains(&rate), "{rate} parts per second");
+        // New sessions start at their average.
+        assert_eq!(table.rows[1].rate, 5 * PART_SIZE as u64);
+
+        table.update(vec![session("a", 300)]);
+        assert_eq!(table.selected, 0);
+        table.update(Vec::new());
+        assert_eq!(table.selected, 0);
+    }
+}
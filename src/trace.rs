@@ -0,0 +1,213 @@
+//! Packet traces: every message a session sends or receives, timestamped, to diagnose
+//! protocol bugs offline with [`dump`].
+//!
+//! A trace starts with [`MAGIC`] and the start time, in microseconds since the Unix epoch.
+//! Each record then holds, in big-endian:
+//!
+//! - microseconds since the start (u64),
+//! - direction, 0 for sent and 1 for received (u8),
+//! - peer address: 4 or 6, the IP, then the port (u16),
+//! - length of the message (u16), length captured (u16), and the captured bytes.
+//!
+//! Messages are captured before sealing and after opening. Only the header of a Part is,
+//! which keeps traces of large transfers small.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, ErrorKind, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use tracing::warn;
+
+use crate::{journal::utc, protocol::Message};
+
+pub const MAGIC: &[u8; 8] = b"SANICTR1";
+/// What is kept of a Part: its type and id.
+const PART_CAPTURE: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// Handle on a trace being written, clones write to the same one. The end of the trace is
+/// written once the last clone is dropped.
+#[derive(Debug, Clone)]
+pub struct Tracer {
+    file: Arc<Mutex<BufWriter<File>>>,
+    start: Instant,
+}
+
+impl Tracer {
+    /// Starts a new trace at `path`, replacing the file if it exists.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let start = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        file.write_all(MAGIC)?;
+        file.write_u64::<BigEndian>(start.as_micros() as u64)?;
+        Ok(Tracer {
+            file: Arc::new(Mutex::new(file)),
+            start: Instant::now(),
+        })
+    }
+
+    pub(crate) fn record(&self, direction: Direction, peer: SocketAddr, packet: &[u8]) {
+        let mut record = Vec::with_capacity(32);
+        encode(&mut record, self.start.elapsed(), direction, peer, packet);
+        let mut file = self.file.lock().expect("Could not lock trace");
+        if let Err(err) = file.write_all(&record) {
+            warn!(error = ?err, "Could not write to the packet trace.");
+        }
+    }
+}
+
+fn encode(
+    record: &mut Vec<u8>,
+    at: Duration,
+    direction: Direction,
+    peer: SocketAddr,
+    packet: &[u8],
+) {
+    let captured = match packet.first() {
+        Some(&2) => &packet[..packet.len().min(PART_CAPTURE)],
+        _ => packet,
+    };
+    record.extend((at.as_micros() as u64).to_be_bytes());
+    record.push(match direction {
+        Direction::Sent => 0,
+        Direction::Received => 1,
+    });
+    match peer.ip() {
+        IpAddr::V4(ip) => {
+            record.push(4);
+            record.extend(ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            record.push(6);
+            record.extend(ip.octets());
+        }
+    }
+    record.extend(peer.port().to_be_bytes());
+    record.extend((packet.len() as u16).to_be_bytes());
+    record.extend((captured.len() as u16).to_be_bytes());
+    record.extend(captured);
+}
+
+/// Writes the trace at `path` to `out`, one line per message.
+pub fn dump(path: impl AsRef<Path>, out: &mut impl Write) -> io::Result<()> {
+    let mut trace = BufReader::new(File::open(path)?);
+    let mut magic = [0; 8];
+    trace.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "not a sanic packet trace",
+        ));
+    }
+    let start = UNIX_EPOCH + Duration::from_micros(trace.read_u64::<BigEndian>()?);
+    writeln!(out, "Trace started at {}", utc(start))?;
+    loop {
+        let at = match trace.read_u64::<BigEndian>() {
+            Ok(at) => Duration::from_micros(at),
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let arrow = match trace.read_u8()? {
+            0 => "->",
+            _ => "<-",
+        };
+        let ip = match trace.read_u8()? {
+            4 => {
+                let mut octets = [0; 4];
+                trace.read_exact(&mut octets)?;
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            _ => {
+                let mut octets = [0; 16];
+                trace.read_exact(&mut octets)?;
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+        };
+        let peer = SocketAddr::new(ip, trace.read_u16::<BigEndian>()?);
+        let len = trace.read_u16::<BigEndian>()? as usize;
+        let mut captured = vec![0; trace.read_u16::<BigEndian>()? as usize];
+        trace.read_exact(&mut captured)?;
+        writeln!(
+            out,
+            "{:>12.6} {arrow} {peer:<21} {}",
+            at.as_secs_f64(),
+            describe(&captured, len)
+        )?;
+    }
+}
+
+fn describe(captured: &[u8], len: usize) -> String {
+    match Message::parse(captured) {
+        Ok(Message::Part { id, .. }) => {
+            format!(
+                "Part {{ id: {id}, len: {} }}",
+                len.saturating_sub(PART_CAPTURE)
+            )
+        }
+        Ok(message) => format!("{message:?}"),
+        Err(err) => format!("unparsable {len} bytes: {err}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn traces_dump_one_line_per_message() {
+        let dir = TempDir::new("trace");
+        let path = dir.join("trace.bin");
+        let tracer = Tracer::create(&path).unwrap();
+        let receiver: SocketAddr = "192.168.1.20:6666".parse().unwrap();
+        let part = Message::Part {
+            id: 7,
+            data: vec![1; 1000],
+        };
+        tracer.record(Direction::Sent, receiver, &part.serialize());
+        let sync = Message::Sync { ids: vec![3, 4] };
+        tracer.record(Direction::Sent, receiver, &sync.serialize());
+        tracer.record(
+            Direction::Received,
+            "[::1]:6666".parse().unwrap(),
+            &Message::Loss { ids: vec![4] }.serialize(),
+        );
+        tracer.record(Direction::Received, receiver, &[42]);
+        drop(tracer);
+
+        let mut out = Vec::new();
+        dump(&path, &mut out).unwrap();
+        // Parts only keep their header.
+        assert!(std::fs::metadata(&path).unwrap().len() < 200);
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].starts_with("Trace started at "));
+        assert!(lines[1].ends_with(" -> 192.168.1.20:6666     Part { id: 7, len: 1000 }"));
+        assert!(lines[2].ends_with(" -> 192.168.1.20:6666     Sync { ids: [3, 4] }"));
+        assert!(lines[3].ends_with(" <- [::1]:6666            Loss { ids: [4] }"));
+        assert!(lines[4].contains(" <- 192.168.1.20:6666     unparsable 1 bytes: "));
+    }
+
+    #[test]
+    fn other_files_are_refused() {
+        let dir = TempDir::new("not-trace");
+        let path = dir.join("other.bin");
+        std::fs::write(&path, b"SANICTR0 and more").unwrap();
+        let err = dump(&path, &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
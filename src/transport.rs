@@ -0,0 +1,476 @@
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind, Read, Write},
+    net::{Ipv4Addr, SocketAddr, TcpStream, UdpSocket},
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{dscp::Dscp, MTU};
+
+/// Most datagrams sent or received in a single batch.
+pub const MAX_BATCH: usize = 64;
+
+/// Kernel buffer of a socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Buffer {
+    Send,
+    Recv,
+}
+
+/// Datagram transport the transfer engine runs on.
+///
+/// Both ends share a transport between their threads, so implementations must be usable
+/// from several threads at once, like a `UdpSocket` is.
+pub trait Transport: Send + Sync {
+    fn send_datagram(&self, datagram: &[u8], peer: SocketAddr) -> io::Result<usize>;
+
+    /// Blocks until a datagram arrives or the read timeout expires.
+    fn recv_datagram(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Sends several datagrams to the same peer, in order, and returns how many were sent.
+    fn send_datagrams(&self, datagrams: &[&[u8]], peer: SocketAddr) -> io::Result<usize> {
+        for datagram in datagrams {
+            self.send_datagram(datagram, peer)?;
+        }
+        Ok(datagrams.len())
+    }
+
+    /// Blocks until a datagram arrives or the read timeout expires, then returns the size
+    /// and sender of every datagram received in `bufs`, in order. Implementations that can
+    /// batch also take the datagrams already queued, up to `bufs.len()`.
+    fn recv_datagrams(&self, bufs: &mut [Vec<u8>]) -> io::Result<Vec<(usize, SocketAddr)>> {
+        match bufs.first_mut() {
+            Some(buf) => Ok(vec![self.recv_datagram(buf)?]),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Size of the kernel `buffer` of the socket, for transports that have one.
+    fn buffer(&self, _buffer: Buffer) -> io::Result<usize> {
+        Err(ErrorKind::Unsupported.into())
+    }
+
+    /// Asks for `bytes` of kernel `buffer` and returns the size granted, for transports
+    /// that have one.
+    fn set_buffer(&self, _buffer: Buffer, _bytes: usize) -> io::Result<usize> {
+        Err(ErrorKind::Unsupported.into())
+    }
+
+    /// Marks the datagrams sent from now on with `dscp`, for transports that can.
+    fn set_dscp(&self, _dscp: Dscp) -> io::Result<()> {
+        Err(ErrorKind::Unsupported.into())
+    }
+
+    /// Another transport on the same address but a port of its own, for transports that
+    /// can bind one.
+    fn bind_another(&self) -> io::Result<Arc<dyn Transport>> {
+        Err(ErrorKind::Unsupported.into())
+    }
+}
+
+impl Transport for UdpSocket {
+    fn send_datagram(&self, datagram: &[u8], peer: SocketAddr) -> io::Result<usize> {
+        self.send_to(datagram, peer)
+    }
+
+    fn recv_datagram(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.recv_from(buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UdpSocket::set_read_timeout(self, timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+
+    #[cfg(all(target_os = "linux", feature = "mmsg"))]
+    fn send_datagrams(&self, datagrams: &[&[u8]], peer: SocketAddr) -> io::Result<usize> {
+        crate::mmsg::send_batch(self, datagrams, peer)
+    }
+
+    #[cfg(all(target_os = "linux", feature = "mmsg"))]
+    fn recv_datagrams(&self, bufs: &mut [Vec<u8>]) -> io::Result<Vec<(usize, SocketAddr)>> {
+        crate::mmsg::recv_batch(self, bufs)
+    }
+
+    #[cfg(all(target_os = "linux", feature = "sockbuf"))]
+    fn buffer(&self, buffer: Buffer) -> io::Result<usize> {
+        crate::sockbuf::get(self, buffer)
+    }
+
+    #[cfg(all(target_os = "linux", feature = "sockbuf"))]
+    fn set_buffer(&self, buffer: Buffer, bytes: usize) -> io::Result<usize> {
+        crate::sockbuf::set(self, buffer, bytes)
+    }
+
+    #[cfg(all(target_os = "linux", feature = "dscp"))]
+    fn set_dscp(&self, dscp: Dscp) -> io::Result<()> {
+        let ipv6 = UdpSocket::local_addr(self)?.is_ipv6();
+        crate::dscp::mark(self, ipv6, dscp)
+    }
+
+    fn bind_another(&self) -> io::Result<Arc<dyn Transport>> {
+        rebind_udp(UdpSocket::local_addr(self)?)
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Arc<T> {
+    fn send_datagram(&self, datagram: &[u8], peer: SocketAddr) -> io::Result<usize> {
+        (**self).send_datagram(datagram, peer)
+    }
+
+    fn recv_datagram(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        (**self).recv_datagram(buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        (**self).local_addr()
+    }
+
+    fn send_datagrams(&self, datagrams: &[&[u8]], peer: SocketAddr) -> io::Result<usize> {
+        (**self).send_datagrams(datagrams, peer)
+    }
+
+    fn recv_datagrams(&self, bufs: &mut [Vec<u8>]) -> io::Result<Vec<(usize, SocketAddr)>> {
+        (**self).recv_datagrams(bufs)
+    }
+
+    fn buffer(&self, buffer: Buffer) -> io::Result<usize> {
+        (**self).buffer(buffer)
+    }
+
+    fn set_buffer(&self, buffer: Buffer, bytes: usize) -> io::Result<usize> {
+        (**self).set_buffer(buffer, bytes)
+    }
+
+    fn set_dscp(&self, dscp: Dscp) -> io::Result<()> {
+        (**self).set_dscp(dscp)
+    }
+
+    fn bind_another(&self) -> io::Result<Arc<dyn Transport>> {
+        (**self).bind_another()
+    }
+}
+
+/// Binds the UDP socket a transfer runs on, through io_uring when the `uring` feature is
+/// enabled and the kernel supports it, or else with segmentation offload when the `gso`
+/// feature is enabled and the kernel supports it.
+pub(crate) fn bind_udp(addr: &str) -> io::Result<Arc<dyn Transport>> {
+    #[cfg(all(target_os = "linux", feature = "uring"))]
+    if let Some(socket) = crate::uring::UringSocket::bind(addr)? {
+        return Ok(Arc::new(socket));
+    }
+    #[cfg(all(target_os = "linux", feature = "gso"))]
+    return Ok(Arc::new(crate::offload::OffloadSocket::bind(addr)?));
+    #[cfg(not(all(target_os = "linux", feature = "gso")))]
+    Ok(Arc::new(UdpSocket::bind(addr)?))
+}
+
+/// A UDP socket like [`bind_udp`] binds, on the address of `addr` and a free port.
+pub(crate) fn rebind_udp(mut addr: SocketAddr) -> io::Result<Arc<dyn Transport>> {
+    addr.set_port(0);
+    bind_udp(&addr.to_string())
+}
+
+type Inbox = mpsc::Sender<(Vec<u8>, SocketAddr)>;
+
+/// In-process network linking [`MemoryTransport`]s through channels, for tests.
+///
+/// Datagrams sent to an address nobody is bound to are silently dropped, like UDP.
+#[derive(Clone)]
+pub struct MemoryNetwork {
+    endpoints: Arc<Mutex<HashMap<SocketAddr, Inbox>>>,
+    next_port: Arc<AtomicU16>,
+}
+
+impl Default for MemoryNetwork {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        MemoryNetwork {
+            endpoints: Arc::new(Mutex::new(HashMap::new())),
+            next_port: Arc::new(AtomicU16::new(49152)),
+        }
+    }
+
+    /// Binds a new endpoint, port 0 picking a free one.
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<MemoryTransport> {
+        let mut addr = addr;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(Ipv4Addr::LOCALHOST.into());
+        }
+        let mut endpoints = self.endpoints.lock().expect("Could not lock endpoints");
+        if addr.port() == 0 {
+            while addr.port() == 0 || endpoints.contains_key(&addr) {
+                addr.set_port(self.next_port.fetch_add(1, Ordering::Relaxed).max(1024));
+            }
+        } else if endpoints.contains_key(&addr) {
+            return Err(io::Error::new(ErrorKind::AddrInUse, addr.to_string()));
+        }
+
+        let (inbox, datagrams) = mpsc::channel();
+        endpoints.insert(addr, inbox);
+        Ok(MemoryTransport {
+            network: self.clone(),
+            addr,
+            datagrams: Mutex::new(datagrams),
+            read_timeout: Mutex::new(None),
+        })
+    }
+}
+
+/// Endpoint of a [`MemoryNetwork`].
+pub struct MemoryTransport {
+    network: MemoryNetwork,
+    addr: SocketAddr,
+    datagrams: Mutex<mpsc::Receiver<(Vec<u8>, SocketAddr)>>,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl Transport for MemoryTransport {
+    fn send_datagram(&self, datagram: &[u8], peer: SocketAddr) -> io::Result<usize> {
+        let endpoints = self
+            .network
+            .endpoints
+            .lock()
+            .expect("Could not lock endpoints");
+        if let Some(inbox) = endpoints.get(&peer) {
+            // The peer being gone is just another lost datagram.
+            let _ = inbox.send((datagram.to_vec(), self.addr));
+        }
+        Ok(datagram.len())
+    }
+
+    fn recv_datagram(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let timeout = *self.read_timeout.lock().expect("Could not lock timeout");
+        let datagrams = self.datagrams.lock().expect("Could not lock datagrams");
+        let (datagram, from) = match timeout {
+            Some(timeout) => datagrams
+                .recv_timeout(timeout)
+                .map_err(|_| io::Error::from(ErrorKind::WouldBlock))?,
+            None => datagrams
+                .recv()
+                .map_err(|_| io::Error::from(ErrorKind::NotConnected))?,
+        };
+        // Like UDP, a datagram larger than the buffer is truncated.
+        let size = datagram.len().min(buf.len());
+        buf[..size].copy_from_slice(&datagram[..size]);
+        Ok((size, from))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        if timeout == Some(Duration::ZERO) {
+            return Err(io::Error::from(ErrorKind::InvalidInput));
+        }
+        *self.read_timeout.lock().expect("Could not lock timeout") = timeout;
+        Ok(())
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+
+    fn bind_another(&self) -> io::Result<Arc<dyn Transport>> {
+        let mut addr = self.addr;
+        addr.set_port(0);
+        Ok(Arc::new(self.network.bind(addr)?))
+    }
+}
+
+impl Drop for MemoryTransport {
+    fn drop(&mut self) {
+        if let Ok(mut endpoints) = self.network.endpoints.lock() {
+            endpoints.remove(&self.addr);
+        }
+    }
+}
+
+/// Datagrams framed over a TCP stream (`u16` big endian length, then the datagram), for
+/// networks that drop UDP altogether.
+pub struct TcpTransport {
+    peer: SocketAddr,
+    local: SocketAddr,
+    writer: Mutex<TcpStream>,
+    /// Bytes read from the stream that do not form a whole frame yet. Kept across calls so
+    /// a read timeout in the middle of a frame never desynchronizes the stream.
+    reader: Mutex<(TcpStream, Vec<u8>)>,
+}
+
+impl TcpTransport {
+    pub fn new(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        Ok(TcpTransport {
+            peer: stream.peer_addr()?,
+            local: stream.local_addr()?,
+            writer: Mutex::new(stream.try_clone()?),
+            reader: Mutex::new((stream, Vec::with_capacity(2 * MTU))),
+        })
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send_datagram(&self, datagram: &[u8], _peer: SocketAddr) -> io::Result<usize> {
+        let len = u16::try_from(datagram.len())
+            .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "Datagram too large"))?;
+        let mut frame = Vec::with_capacity(datagram.len() + 2);
+        frame.extend(len.to_be_bytes());
+        frame.extend(datagram);
+        self.writer
+            .lock()
+            .expect("Could not lock tcp writer")
+            .write_all(&frame)?;
+        Ok(datagram.len())
+    }
+
+    fn recv_datagram(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut guard = self.reader.lock().expect("Could not lock tcp reader");
+        let (stream, pending) = &mut *guard;
+        loop {
+            if pending.len() >= 2 {
+                let len = u16::from_be_bytes([pending[0], pending[1]]) as usize;
+                if pending.len() >= len + 2 {
+                    let size = len.min(buf.len());
+                    buf[..size].copy_from_slice(&pending[2..2 + size]);
+                    pending.drain(..len + 2);
+                    return Ok((size, self.peer));
+                }
+            }
+            let mut chunk = [0u8; 16 * 1024];
+            match stream.read(&mut chunk)? {
+                0 => return Err(io::Error::from(ErrorKind::ConnectionReset)),
+                read => pending.extend_from_slice(&chunk[..read]),
+            }
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        // Both handles share the same socket, and the reader may be blocked in a read.
+        self.writer
+            .lock()
+            .expect("Could not lock tcp writer")
+            .set_read_timeout(timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local)
+    }
+
+    #[cfg(all(target_os = "linux", feature = "dscp"))]
+    fn set_dscp(&self, dscp: Dscp) -> io::Result<()> {
+        let writer = self.writer.lock().expect("Could not lock tcp writer");
+        crate::dscp::mark(&*writer, self.local.is_ipv6(), dscp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn memory_endpoints_exchange_datagrams_in_order() {
+        let network = MemoryNetwork::new();
+        let a = network.bind(addr("127.0.0.1:6666")).unwrap();
+        let b = network.bind(addr("0.0.0.0:0")).unwrap();
+        let b_addr = b.local_addr().unwrap();
+        assert!(b_addr.ip().is_loopback() && b_addr.port() != 0);
+
+        let datagrams: [&[u8]; 3] = [b"one", b"two", b"three"];
+        assert_eq!(a.send_datagrams(&datagrams, b_addr).unwrap(), 3);
+        let mut bufs = vec![vec![0; MTU]; 2];
+        for expected in datagrams {
+            let received = b.recv_datagrams(&mut bufs).unwrap();
+            assert_eq!(received, [(expected.len(), addr("127.0.0.1:6666"))]);
+            assert_eq!(&bufs[0][..expected.len()], expected);
+        }
+        // Larger than the buffer, truncated like UDP.
+        b.send_datagram(b"truncated", addr("127.0.0.1:6666"))
+            .unwrap();
+        let mut buf = [0; 5];
+        assert_eq!(a.recv_datagram(&mut buf).unwrap(), (5, b_addr));
+        assert_eq!(&buf, b"trunc");
+    }
+
+    #[test]
+    fn memory_endpoints_behave_like_sockets() {
+        let network = MemoryNetwork::new();
+        let a = network.bind(addr("127.0.0.1:6666")).unwrap();
+        let err = network.bind(addr("127.0.0.1:6666")).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+        assert_eq!(
+            a.set_read_timeout(Some(Duration::ZERO)).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+        a.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        let mut buf = [0; MTU];
+        assert_eq!(
+            a.recv_datagram(&mut buf).unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+
+        // Nobody listens, the datagram is lost without an error.
+        let b = network.bind(addr("127.0.0.1:6667")).unwrap();
+        drop(b);
+        assert_eq!(a.send_datagram(b"lost", addr("127.0.0.1:6667")).unwrap(), 4);
+        let b = network.bind(addr("127.0.0.1:6667")).unwrap();
+        b.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        assert!(b.recv_datagram(&mut buf).is_err());
+        assert!(a.buffer(Buffer::Recv).is_err());
+    }
+
+    #[test]
+    fn tcp_frames_survive_partial_reads() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        let transport = TcpTransport::new(client).unwrap();
+        transport
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+
+        // Half a frame, then a timeout, then the rest and a whole one.
+        server.write_all(&[0, 5, b'h', b'e']).unwrap();
+        let mut buf = [0; MTU];
+        assert!(transport.recv_datagram(&mut buf).is_err());
+        server.write_all(b"llo\x00\x02hi").unwrap();
+        let peer = transport.peer_addr();
+        assert_eq!(transport.recv_datagram(&mut buf).unwrap(), (5, peer));
+        assert_eq!(&buf[..5], b"hello");
+        assert_eq!(transport.recv_datagram(&mut buf).unwrap(), (2, peer));
+        assert_eq!(&buf[..2], b"hi");
+
+        transport.send_datagram(b"back", peer).unwrap();
+        let mut frame = [0; 6];
+        server.read_exact(&mut frame).unwrap();
+        assert_eq!(&frame, b"\x00\x04back");
+        let large = vec![0; u16::MAX as usize + 1];
+        assert!(transport.send_datagram(&large, peer).is_err());
+    }
+}
@@ -0,0 +1,580 @@
+//! io_uring on Linux: batches of datagrams sent and received, runs of parts written, and
+//! files read ahead, each through a ring.
+//!
+//! The threads of a transfer stay as they are, each one waiting on a ring of its own
+//! instead of on a syscall: a batch goes to the kernel as linked entries in a single
+//! submission, and a file is read with several reads in flight so the sender never waits
+//! on the disk. Rings are never shared, as closing one interrupts the blocking syscalls of
+//! every thread that used it. Kernels and sandboxes without io_uring get the plain path.
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    fs::File,
+    io::{self, ErrorKind, IoSlice},
+    mem,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    ops::Range,
+    os::fd::AsRawFd,
+    sync::{mpsc, Arc, Mutex},
+    time::Duration,
+};
+
+use io_uring::{opcode, squeue, types, IoUring};
+use tracing::{debug, error, info};
+
+use crate::{
+    dscp::Dscp,
+    mmsg::{from_sockaddr, to_sockaddr},
+    transport::{rebind_udp, Buffer, Transport, MAX_BATCH},
+    PART_SIZE,
+};
+
+/// Entries of a ring, room for a whole batch and the timeout of a receive.
+const ENTRIES: u32 = 2 * MAX_BATCH as u32;
+/// Reads in flight ahead of the sender.
+const READ_AHEAD: usize = 4;
+/// Bytes of every read but the last, a whole number of parts.
+const READ_CHUNK: usize = 512 * PART_SIZE;
+/// Most buffers a single write takes, IOV_MAX on Linux.
+const MAX_IOVECS: usize = 1024;
+
+thread_local! {
+    /// Ring of this thread, None where io_uring is not available.
+    static RING: RefCell<Option<IoUring>> = RefCell::new(IoUring::new(ENTRIES).ok());
+}
+
+/// Runs `f` with the ring of this thread, if it has one.
+fn with_ring<T>(f: impl FnOnce(Option<&mut IoUring>) -> T) -> T {
+    RING.with(|ring| f(ring.borrow_mut().as_mut()))
+}
+
+/// Whether this thread goes through io_uring.
+pub(crate) fn available() -> bool {
+    with_ring(|ring| ring.is_some())
+}
+
+/// Queues `entries`, which the ring has room for as it is drained after every batch.
+///
+/// # Safety
+///
+/// Whatever the entries point to must stay put until [`complete`] returns.
+unsafe fn push(ring: &mut IoUring, entries: &[squeue::Entry]) {
+    ring.submission()
+        .push_multiple(entries)
+        .expect("The ring has room for a batch");
+}
+
+/// Submits the entries queued and waits for `count` of them, returning their results by
+/// user data, from 0 to `count`.
+fn complete(ring: &mut IoUring, count: usize) -> Vec<i32> {
+    let mut results = vec![0; count];
+    let mut done = 0;
+    while done < count {
+        if let Err(err) = ring.submit_and_wait(1) {
+            if !matches!(
+                err.raw_os_error(),
+                Some(libc::EINTR | libc::EAGAIN | libc::EBUSY)
+            ) {
+                // The buffers of the entries in flight are the kernel's until they complete,
+                // giving them back could have it write to freed memory.
+                error!(error = %err, "io_uring failed with entries in flight.");
+                std::process::abort();
+            }
+        }
+        for entry in ring.completion() {
+            results[entry.user_data() as usize] = entry.result();
+            done += 1;
+        }
+    }
+    results
+}
+
+/// Result of an entry as the syscall it stands for would have returned it.
+fn check(result: i32) -> io::Result<usize> {
+    match result {
+        0.. => Ok(result as usize),
+        _ => Err(io::Error::from_raw_os_error(-result)),
+    }
+}
+
+/// UDP socket sending and receiving through the ring of the calling thread, and like a
+/// plain socket from threads without one.
+pub(crate) struct UringSocket {
+    socket: UdpSocket,
+    /// Kept here too, as a ring does not wait with the timeout of the socket.
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl UringSocket {
+    /// Binds `addr`, None if io_uring is not available here.
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Option<Self>> {
+        if !available() {
+            debug!("io_uring is not available.");
+            return Ok(None);
+        }
+        Ok(Some(UringSocket {
+            socket: UdpSocket::bind(addr)?,
+            read_timeout: Mutex::new(None),
+        }))
+    }
+
+    /// Waits for a datagram in the first of `iovecs` until the read timeout, then takes
+    /// the ones already queued in the others.
+    fn receive(
+        &self,
+        ring: &mut IoUring,
+        iovecs: &mut [libc::iovec],
+    ) -> io::Result<Vec<(usize, SocketAddr)>> {
+        let timeout = *self.read_timeout.lock().expect("Could not lock timeout");
+        let mut addrs: Vec<libc::sockaddr_storage> = vec![
+            // SAFETY: sockaddr_storage is plain old data, all zeroes is a valid value.
+            unsafe { mem::zeroed() };
+            iovecs.len()
+        ];
+        let mut headers: Vec<libc::msghdr> = iovecs
+            .iter_mut()
+            .zip(addrs.iter_mut())
+            .map(|(iovec, addr)| {
+                // SAFETY: msghdr is plain old data, all zeroes is a valid value.
+                let mut header: libc::msghdr = unsafe { mem::zeroed() };
+                header.msg_name = addr as *mut _ as *mut libc::c_void;
+                header.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as u32;
+                header.msg_iov = iovec;
+                header.msg_iovlen = 1;
+                header
+            })
+            .collect();
+        let fd = types::Fd(self.socket.as_raw_fd());
+
+        let first = opcode::RecvMsg::new(fd, &mut headers[0])
+            .build()
+            .user_data(0);
+        let timespec = timeout.map(types::Timespec::from);
+        let results = match &timespec {
+            Some(timespec) => {
+                let expiry = opcode::LinkTimeout::new(timespec).build().user_data(1);
+                // SAFETY: the header, the buffers and the timespec outlive the wait.
+                unsafe { push(ring, &[first.flags(squeue::Flags::IO_LINK), expiry]) };
+                complete(ring, 2)
+            }
+            None => {
+                // SAFETY: the header and the buffers outlive the wait.
+                unsafe { push(ring, &[first]) };
+                complete(ring, 1)
+            }
+        };
+        let size = match results[0] {
+            result if result == -libc::ECANCELED && timeout.is_some() => {
+                return Err(ErrorKind::WouldBlock.into())
+            }
+            result => check(result)?,
+        };
+        let mut received = vec![(size, from_sockaddr(&addrs[0])?)];
+
+        // The first one missing cuts the chain, and the entries after it are cancelled.
+        let queued: Vec<squeue::Entry> = headers[1..]
+            .iter_mut()
+            .enumerate()
+            .map(|(i, header)| {
+                let entry = opcode::RecvMsg::new(fd, header)
+                    .flags(libc::MSG_DONTWAIT as u32)
+                    .build()
+                    .user_data(i as u64);
+                match i + 2 < iovecs.len() {
+                    true => entry.flags(squeue::Flags::IO_LINK),
+                    false => entry,
+                }
+            })
+            .collect();
+        if !queued.is_empty() {
+            // SAFETY: the headers and the buffers outlive the wait.
+            unsafe { push(ring, &queued) };
+            for (result, addr) in complete(ring, queued.len()).into_iter().zip(&addrs[1..]) {
+                match check(result) {
+                    Ok(size) => received.push((size, from_sockaddr(addr)?)),
+                    Err(_) => break,
+                }
+            }
+        }
+        Ok(received)
+    }
+
+    fn send(&self, ring: &mut IoUring, datagrams: &[&[u8]], peer: SocketAddr) -> io::Result<()> {
+        let (addr, addr_len) = to_sockaddr(peer);
+        let fd = types::Fd(self.socket.as_raw_fd());
+        for batch in datagrams.chunks(MAX_BATCH) {
+            let mut iovecs: Vec<libc::iovec> = batch
+                .iter()
+                .map(|datagram| libc::iovec {
+                    iov_base: datagram.as_ptr() as *mut libc::c_void,
+                    iov_len: datagram.len(),
+                })
+                .collect();
+            let headers: Vec<libc::msghdr> = iovecs
+                .iter_mut()
+                .map(|iovec| {
+                    // SAFETY: msghdr is plain old data, all zeroes is a valid value.
+                    let mut header: libc::msghdr = unsafe { mem::zeroed() };
+                    header.msg_name = &addr as *const _ as *mut libc::c_void;
+                    header.msg_namelen = addr_len;
+                    header.msg_iov = iovec;
+                    header.msg_iovlen = 1;
+                    header
+                })
+                .collect();
+            // Linked, so the datagrams leave in order.
+            let entries: Vec<squeue::Entry> = headers
+                .iter()
+                .enumerate()
+                .map(|(i, header)| {
+                    let entry = opcode::SendMsg::new(fd, header).build().user_data(i as u64);
+                    match i + 1 < headers.len() {
+                        true => entry.flags(squeue::Flags::IO_LINK),
+                        false => entry,
+                    }
+                })
+                .collect();
+            // SAFETY: the headers, the address and the datagrams outlive the wait.
+            unsafe { push(ring, &entries) };
+            for result in complete(ring, entries.len()) {
+                check(result)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Transport for UringSocket {
+    fn send_datagram(&self, datagram: &[u8], peer: SocketAddr) -> io::Result<usize> {
+        self.send_datagrams(&[datagram], peer)?;
+        Ok(datagram.len())
+    }
+
+    fn recv_datagram(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        with_ring(|ring| match ring {
+            Some(ring) => {
+                let mut iovec = [libc::iovec {
+                    iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                    iov_len: buf.len(),
+                }];
+                Ok(self.receive(ring, &mut iovec)?[0])
+            }
+            None => self.socket.recv_from(buf),
+        })
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)?;
+        *self.read_timeout.lock().expect("Could not lock timeout") = timeout;
+        Ok(())
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn send_datagrams(&self, datagrams: &[&[u8]], peer: SocketAddr) -> io::Result<usize> {
+        with_ring(|ring| match ring {
+            Some(ring) => self.send(ring, datagrams, peer).map(|()| datagrams.len()),
+            None => Transport::send_datagrams(&self.socket, datagrams, peer),
+        })
+    }
+
+    fn recv_datagrams(&self, bufs: &mut [Vec<u8>]) -> io::Result<Vec<(usize, SocketAddr)>> {
+        let count = bufs.len().min(MAX_BATCH);
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        with_ring(|ring| match ring {
+            Some(ring) => {
+                let mut iovecs: Vec<libc::iovec> = bufs[..count]
+                    .iter_mut()
+                    .map(|buf| libc::iovec {
+                        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                        iov_len: buf.len(),
+                    })
+                    .collect();
+                self.receive(ring, &mut iovecs)
+            }
+            None => Transport::recv_datagrams(&self.socket, bufs),
+        })
+    }
+
+    fn buffer(&self, buffer: Buffer) -> io::Result<usize> {
+        Transport::buffer(&self.socket, buffer)
+    }
+
+    fn set_buffer(&self, buffer: Buffer, bytes: usize) -> io::Result<usize> {
+        Transport::set_buffer(&self.socket, buffer, bytes)
+    }
+
+    fn set_dscp(&self, dscp: Dscp) -> io::Result<()> {
+        Transport::set_dscp(&self.socket, dscp)
+    }
+
+    fn bind_another(&self) -> io::Result<Arc<dyn Transport>> {
+        rebind_udp(self.socket.local_addr()?)
+    }
+}
+
+/// Writes `bufs` back to back at `offset`, through the ring of this thread, or with
+/// `pwritev` where there is none.
+pub(crate) fn write_all_at(file: &File, bufs: &mut [IoSlice], offset: u64) -> io::Result<()> {
+    with_ring(|ring| match ring {
+        Some(ring) => write_through(ring, file, bufs, offset),
+        None => crate::pwritev::write_all_at(file, bufs, offset),
+    })
+}
+
+fn write_through(
+    ring: &mut IoUring,
+    file: &File,
+    mut bufs: &mut [IoSlice],
+    mut offset: u64,
+) -> io::Result<()> {
+    let fd = types::Fd(file.as_raw_fd());
+    // The kernel may write only part of the buffers, write the rest until it is all out.
+    while !bufs.is_empty() {
+        let count = bufs.len().min(MAX_IOVECS);
+        // IoSlice is guaranteed to be ABI compatible with iovec on unix.
+        let entry = opcode::Writev::new(fd, bufs.as_ptr() as *const libc::iovec, count as u32)
+            .offset(offset)
+            .build()
+            .user_data(0);
+        // SAFETY: the buffers outlive the wait.
+        unsafe { push(ring, &[entry]) };
+        let written = match check(complete(ring, 1)[0]) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(written) => written,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        offset += written as u64;
+        IoSlice::advance_slices(&mut bufs, written);
+    }
+    Ok(())
+}
+
+/// A chunk of the file being read.
+struct Chunk {
+    buf: Vec<u8>,
+    offset: u64,
+    filled: usize,
+    /// The file ended before the chunk did.
+    short: bool,
+}
+
+impl Chunk {
+    fn done(&self) -> bool {
+        self.short || self.filled == self.buf.len()
+    }
+
+    /// Entry reading the rest of the chunk, under the user data `id`.
+    fn read(&mut self, fd: types::Fd, id: u64) -> squeue::Entry {
+        let rest = &mut self.buf[self.filled..];
+        opcode::Read::new(fd, rest.as_mut_ptr(), rest.len() as u32)
+            .offset(self.offset + self.filled as u64)
+            .build()
+            .user_data(id)
+    }
+}
+
+/// Sends the bytes of `file` in `range`, the whole file without one, to `channel` in
+/// order, in buffers holding a whole number of parts but the last one. Only for threads
+/// with a ring, see [`available`].
+pub(crate) fn read_ahead(
+    file: File,
+    range: Option<Range<u64>>,
+    channel: mpsc::Sender<io::Result<Vec<u8>>>,
+) {
+    let range = match range.map_or_else(|| file.metadata().map(|meta| 0..meta.len()), Ok) {
+        Ok(range) => range,
+        Err(err) => {
+            error!(error = ?err, "Error when reading file.");
+            let _ = channel.send(Err(err));
+            return;
+        }
+    };
+    with_ring(|ring| {
+        let ring = ring.expect("The thread has a ring");
+        let fd = types::Fd(file.as_raw_fd());
+        // Chunks in file order, the first one being number `first`.
+        let mut chunks: VecDeque<Chunk> = VecDeque::with_capacity(READ_AHEAD);
+        let mut first = 0;
+        let mut next = range.start;
+        let mut in_flight = 0;
+        // No more reads once the file ended, a read failed or the transfer was aborted.
+        let (mut stopped, mut failed) = (false, false);
+        loop {
+            while !stopped && chunks.len() < READ_AHEAD && next < range.end {
+                let len = (range.end - next).min(READ_CHUNK as u64) as usize;
+                let mut chunk = Chunk {
+                    buf: vec![0; len],
+                    offset: next,
+                    filled: 0,
+                    short: false,
+                };
+                let entry = chunk.read(fd, first + chunks.len() as u64);
+                // SAFETY: chunks only leave the queue once their read completed, and the
+                // heap buffer they point to does not move with them.
+                unsafe { push(ring, &[entry]) };
+                chunks.push_back(chunk);
+                in_flight += 1;
+                next += len as u64;
+            }
+            if in_flight == 0 {
+                break;
+            }
+            if let Err(err) = ring.submit_and_wait(1) {
+                if !matches!(
+                    err.raw_os_error(),
+                    Some(libc::EINTR | libc::EAGAIN | libc::EBUSY)
+                ) {
+                    error!(error = %err, "io_uring failed with entries in flight.");
+                    std::process::abort();
+                }
+            }
+            let completed: Vec<(u64, i32)> = ring
+                .completion()
+                .map(|entry| (entry.user_data(), entry.result()))
+                .collect();
+            for (id, result) in completed {
+                in_flight -= 1;
+                let chunk = &mut chunks[(id - first) as usize];
+                match check(result) {
+                    Ok(0) => chunk.short = true,
+                    Ok(read) => chunk.filled += read,
+                    Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                    Err(err) => {
+                        if !failed {
+                            error!(error = ?err, "Error when reading file.");
+                            let _ = channel.send(Err(err));
+                        }
+                        (stopped, failed) = (true, true);
+                        chunk.short = true;
+                    }
+                }
+                if !chunk.done() && !stopped {
+                    let entry = chunk.read(fd, id);
+                    // SAFETY: as above.
+                    unsafe { push(ring, &[entry]) };
+                    in_flight += 1;
+                }
+            }
+            // Once stopped, what is in flight is only waited for.
+            while !stopped && chunks.front().is_some_and(Chunk::done) {
+                let mut chunk = chunks.pop_front().expect("There is a first chunk");
+                first += 1;
+                chunk.buf.truncate(chunk.filled);
+                stopped = chunk.short;
+                // The transfer was aborted, nobody wants the rest of the file.
+                if !chunk.buf.is_empty() && channel.send(Ok(chunk.buf)).is_err() {
+                    stopped = true;
+                }
+            }
+        }
+        if !failed {
+            info!("Reached EOF.");
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    use std::time::Instant;
+
+    fn socket() -> UringSocket {
+        UringSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .expect("io_uring is available")
+    }
+
+    #[test]
+    fn batches_go_through_the_ring_in_order() {
+        let (a, b) = (socket(), socket());
+        let datagrams: Vec<Vec<u8>> = (0..100u8).map(|i| vec![i; 10 + i as usize]).collect();
+        let refs: Vec<&[u8]> = datagrams.iter().map(Vec::as_slice).collect();
+        let to = b.local_addr().unwrap();
+        assert_eq!(a.send_datagrams(&refs, to).unwrap(), 100);
+        assert_eq!(a.send_datagram(b"last", to).unwrap(), 4);
+
+        b.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut bufs = vec![vec![0; 1500]; 32];
+        let mut received = Vec::new();
+        while received.len() < 101 {
+            for (i, (size, from)) in b.recv_datagrams(&mut bufs).unwrap().into_iter().enumerate() {
+                assert_eq!(from, a.local_addr().unwrap());
+                received.push(bufs[i][..size].to_vec());
+            }
+        }
+        assert_eq!(received[..100], datagrams[..]);
+        assert_eq!(received[100], b"last");
+    }
+
+    #[test]
+    fn receives_time_out_like_sockets() {
+        let socket = socket();
+        assert!(socket.set_read_timeout(Some(Duration::ZERO)).is_err());
+        socket
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        let start = Instant::now();
+        let err = socket.recv_datagram(&mut [0; 1500]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    fn temp_file(dir: &TempDir) -> (std::path::PathBuf, File) {
+        let path = dir.join("file");
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        (path, file)
+    }
+
+    #[test]
+    fn writes_land_at_their_offset() {
+        let dir = TempDir::new("uring-write");
+        let (path, file) = temp_file(&dir);
+        let data: Vec<u8> = (0..3000u32).map(|i| (i % 251) as u8).collect();
+        let mut bufs: Vec<IoSlice> = data.chunks(1).map(IoSlice::new).collect();
+        write_all_at(&file, &mut bufs, 100).unwrap();
+        let written = std::fs::read(&path).unwrap();
+        assert_eq!(written[..100], [0; 100]);
+        assert_eq!(written[100..], data[..]);
+    }
+
+    #[test]
+    fn files_are_read_ahead_in_whole_parts() {
+        let dir = TempDir::new("uring-read");
+        let (path, file) = temp_file(&dir);
+        let len = READ_AHEAD * READ_CHUNK * 2 + 17;
+        let data: Vec<u8> = (0..len as u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        for (range, expected) in [
+            (None, &data[..]),
+            (
+                Some(PART_SIZE as u64..len as u64 - 5),
+                &data[PART_SIZE..len - 5],
+            ),
+        ] {
+            let (chunks, read) = mpsc::channel();
+            assert!(available());
+            read_ahead(file.try_clone().unwrap(), range, chunks);
+            let read: Vec<Vec<u8>> = read.into_iter().map(Result::unwrap).collect();
+            let (last, whole) = read.split_last().unwrap();
+            assert!(whole.iter().all(|chunk| chunk.len() % PART_SIZE == 0));
+            assert!(!last.is_empty());
+            assert_eq!(read.concat(), expected);
+        }
+    }
+}
@@ -0,0 +1,321 @@
+//! Watch mode: sends the files that show up or change under a directory, once they were
+//! left alone for a while, and remembers what it sent across restarts.
+//!
+//! Changes are noticed with inotify on Linux, with the `inotify` feature, and by looking
+//! at the directory every few seconds otherwise. Hidden files, like the ones downloads and
+//! editors write before renaming them, are left out.
+
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use tracing::{info, warn};
+
+use crate::Sender;
+
+/// Name of the record of what was sent, in the watched directory unless told otherwise.
+const RECORD: &str = ".sanic-sent";
+/// How long a file that could not be sent waits before the next attempt.
+const RETRY: Duration = Duration::from_secs(10);
+/// How often the directory is looked at without notifications.
+const POLL: Duration = Duration::from_secs(2);
+/// How often it is looked at anyway, for the notifications that were missed.
+const RESCAN: Duration = Duration::from_secs(60);
+
+type SentCallback = Arc<dyn Fn(&Path) + Send + Sync>;
+
+/// Sends what changes under a directory with a [`Sender`].
+///
+/// ```no_run
+/// use sanic::{watch::Watcher, Sender};
+///
+/// Watcher::new("/var/log/app", Sender::new("192.168.1.20:6666")).run()?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct Watcher {
+    dir: PathBuf,
+    sender: Sender,
+    debounce: Duration,
+    record: PathBuf,
+    sent: Option<SentCallback>,
+}
+
+impl Watcher {
+    pub fn new(dir: impl Into<PathBuf>, sender: Sender) -> Self {
+        let dir = dir.into();
+        Watcher {
+            record: dir.join(RECORD),
+            dir,
+            sender,
+            debounce: Duration::from_secs(2),
+            sent: None,
+        }
+    }
+
+    /// Only send a file once it went unchanged for `delay`, so that files still being
+    /// written are not sent halfway.
+    pub fn debounce(mut self, delay: Duration) -> Self {
+        self.debounce = delay;
+        self
+    }
+
+    /// Keep the record of what was sent at `path` instead of in the watched directory.
+    pub fn record(mut self, path: impl Into<PathBuf>) -> Self {
+        self.record = path.into();
+        self
+    }
+
+    /// Called with the path of every file sent.
+    pub fn on_sent(mut self, callback: impl Fn(&Path) + Send + Sync + 'static) -> Self {
+        self.sent = Some(Arc::new(callback));
+        self
+    }
+
+    /// Sends what changed since the last run, then what changes from then on. Transfers
+    /// that fail are tried again later, only failing to read the directory or to keep the
+    /// record stops the watch.
+    pub fn run(&self) -> io::Result<()> {
+        let mut record = Record::open(&self.record)?;
+        let mut changes = Changes::new();
+        // Files to send, as last seen, and when they are due.
+        let mut pending: HashMap<PathBuf, (Version, Instant)> = HashMap::new();
+        loop {
+            let mut found = HashMap::new();
+            scan(&self.dir, Path::new(""), &mut found, &mut changes)?;
+            let now = Instant::now();
+            pending.retain(|path, _| found.contains_key(path));
+            for (path, version) in found {
+                if record.sent.get(&path) == Some(&version) {
+                    pending.remove(&path);
+                } else if pending.get(&path).is_none_or(|(seen, _)| *seen != version) {
+                    pending.insert(path, (version, now + self.debounce));
+                }
+            }
+            let mut due: Vec<_> = pending
+                .iter()
+                .filter(|(_, (_, due))| *due <= now)
+                .map(|(path, (version, _))| (path.clone(), *version))
+                .collect();
+            due.sort();
+            for (path, version) in due {
+                let file = self.dir.join(&path);
+                match self.sender.send(&file) {
+                    Ok(()) => {
+                        info!(path = %path.display(), "Sent a file that changed.");
+                        record.mark(&path, version)?;
+                        pending.remove(&path);
+                        if let Some(sent) = &self.sent {
+                            sent(&file);
+                        }
+                    }
+                    Err(err) => {
+                        warn!(path = %path.display(), error = %err, "Could not send the file, trying again later.");
+                        pending.insert(path, (version, Instant::now() + RETRY));
+                    }
+                }
+            }
+            let wait = pending
+                .values()
+                .map(|(_, due)| due.saturating_duration_since(Instant::now()))
+                .min()
+                .unwrap_or(RESCAN);
+            changes.wait(wait.min(RESCAN));
+        }
+    }
+}
+
+/// Size and modification time a file was seen with, in nanoseconds since the epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Version {
+    len: u64,
+    modified: u128,
+}
+
+/// Files already sent, kept in an append-only file with a line per transfer:
+/// `<size>\t<modification time>\t<path>`, the last line of a path winning.
+struct Record {
+    file: File,
+    sent: HashMap<PathBuf, Version>,
+}
+
+impl Record {
+    fn open(path: &Path) -> io::Result<Self> {
+        let mut sent = HashMap::new();
+        match std::fs::read_to_string(path) {
+            Ok(contents) => {
+                for line in contents.lines() {
+                    let mut fields = line.splitn(3, '\t');
+                    let (Some(len), Some(modified), Some(name)) =
+                        (fields.next(), fields.next(), fields.next())
+                    else {
+                        continue;
+                    };
+                    if let (Ok(len), Ok(modified)) = (len.parse(), modified.parse()) {
+                        sent.insert(PathBuf::from(name), Version { len, modified });
+                    }
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Record { file, sent })
+    }
+
+    fn mark(&mut self, path: &Path, version: Version) -> io::Result<()> {
+        writeln!(
+            self.file,
+            "{}\t{}\t{}",
+            version.len,
+            version.modified,
+            path.display()
+        )?;
+        self.sent.insert(path.to_path_buf(), version);
+        Ok(())
+    }
+}
+
+/// Regular files under `dir`, by their path relative to the watched directory, which
+/// `relative` is the path of `dir` in. Directories are watched on the way.
+fn scan(
+    dir: &Path,
+    relative: &Path,
+    found: &mut HashMap<PathBuf, Version>,
+    changes: &mut Changes,
+) -> io::Result<()> {
+    changes.watch(dir);
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name.to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            // Gone since it was listed.
+            Err(_) => continue,
+        };
+        let path = relative.join(&name);
+        if metadata.is_dir() {
+            if let Err(err) = scan(&entry.path(), &path, found, changes) {
+                warn!(path = %path.display(), error = %err, "Could not look into the directory.");
+            }
+        } else if metadata.is_file() {
+            let modified = metadata
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos();
+            let len = metadata.len();
+            found.insert(path, Version { len, modified });
+        }
+    }
+    Ok(())
+}
+
+/// Wakes the watch up when something changes, with notifications when we have them.
+struct Changes {
+    #[cfg(all(target_os = "linux", feature = "inotify"))]
+    inotify: Option<crate::inotify::Inotify>,
+}
+
+impl Changes {
+    fn new() -> Self {
+        Changes {
+            #[cfg(all(target_os = "linux", feature = "inotify"))]
+            inotify: crate::inotify::Inotify::new()
+                .inspect_err(
+                    |err| warn!(error = %err, "Could not watch for changes, polling instead."),
+                )
+                .ok(),
+        }
+    }
+
+    #[cfg(all(target_os = "linux", feature = "inotify"))]
+    fn watch(&mut self, dir: &Path) {
+        if let Some(Err(err)) = self.inotify.as_ref().map(|inotify| inotify.watch(dir)) {
+            warn!(path = %dir.display(), error = %err, "Could not watch for changes, polling instead.");
+            self.inotify = None;
+        }
+    }
+
+    #[cfg(not(all(target_os = "linux", feature = "inotify")))]
+    fn watch(&mut self, _dir: &Path) {}
+
+    /// Returns once something changed, or after `timeout`.
+    fn wait(&self, timeout: Duration) {
+        #[cfg(all(target_os = "linux", feature = "inotify"))]
+        if let Some(inotify) = &self.inotify {
+            match inotify.wait(timeout) {
+                Ok(_) => return,
+                Err(err) => warn!(error = %err, "Could not wait for changes."),
+            }
+        }
+        std::thread::sleep(timeout.min(POLL));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{Link, TempDir},
+        Receiver,
+    };
+
+    #[test]
+    fn the_record_survives_restarts() {
+        let dir = TempDir::new("record");
+        let path = dir.join(RECORD);
+        let version = Version {
+            len: 12,
+            modified: 1_700_000_000_123_456_789,
+        };
+        let mut record = Record::open(&path).unwrap();
+        record.mark(Path::new("sub/a\tb.log"), version).unwrap();
+        record
+            .mark(Path::new("c.log"), Version { len: 1, ..version })
+            .unwrap();
+        record.mark(Path::new("c.log"), version).unwrap();
+        drop(record);
+        let record = Record::open(&path).unwrap();
+        assert_eq!(record.sent.len(), 2);
+        assert_eq!(record.sent[Path::new("sub/a\tb.log")], version);
+        assert_eq!(record.sent[Path::new("c.log")], version);
+    }
+
+    #[test]
+    fn changed_files_are_sent_once_settled() {
+        let root = TempDir::new("watch");
+        let (watched, output) = (root.dir("watched"), root.dir("output"));
+        root.dir("watched/sub");
+        root.write("watched/a.txt", b"first");
+        root.write("watched/.hidden", b"skipped");
+        let link = Link::new();
+        // Both run for good, like daemons.
+        link.receive(Receiver::new().output(&output));
+        let watcher = Watcher::new(&watched, link.sender()).debounce(Duration::from_millis(100));
+        std::thread::spawn(move || watcher.run());
+
+        let arrived = |name: &str, contents: &[u8]| {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while std::fs::read(output.join(name)).ok().as_deref() != Some(contents) {
+                assert!(Instant::now() < deadline, "{name} did not arrive");
+                std::thread::sleep(Duration::from_millis(20));
+            }
+        };
+        arrived("a.txt", b"first");
+        std::fs::write(watched.join("sub").join("b.txt"), b"second").unwrap();
+        arrived("b.txt", b"second");
+        std::fs::write(watched.join("a.txt"), b"first, amended").unwrap();
+        arrived("a.txt", b"first, amended");
+        assert!(!output.join(".hidden").exists());
+        let record = Record::open(&watched.join(RECORD)).unwrap();
+        assert_eq!(record.sent.len(), 2);
+    }
+}
@@ -0,0 +1,507 @@
+//! Page of a long running [`Receiver`](crate::Receiver), served over HTTP for receivers
+//! without a screen like a NAS: the transfers running with their progress, the last ones
+//! that ended, and the files received, which the browser downloads.
+//!
+//! ```no_run
+//! use sanic::{activity::Activity, Web};
+//!
+//! let activity = Activity::default();
+//! Web::new("/srv/inbox", activity.clone()).serve("127.0.0.1:8080")?;
+//! sanic::Receiver::new().output("/srv/inbox").activity(activity).receive()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use tracing::{info, warn};
+
+use crate::{
+    activity::{Activity, Snapshot},
+    journal::utc,
+};
+
+/// How long a browser has to send its request, headers included.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest request read, headers included.
+const MAX_REQUEST: u64 = 16 * 1024;
+/// How long a write of the answer may block, so that browsers gone quiet let go of their
+/// connection.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Connections answered at once, the others are turned away.
+const MAX_CONNECTIONS: usize = 64;
+/// Files listed at most, the latest first.
+const MAX_FILES: usize = 500;
+/// How deep the page looks for files under the output directory.
+const MAX_DEPTH: usize = 8;
+
+/// The page, and the files it lets browsers download.
+#[derive(Debug, Clone)]
+pub struct Web {
+    dir: PathBuf,
+    activity: Activity,
+}
+
+/// A received file, its path relative to the output directory.
+struct Listed {
+    path: String,
+    size: u64,
+    modified: SystemTime,
+}
+
+impl Web {
+    /// Shows the files under `dir` and the sessions of `activity`, which the receiver
+    /// storing its files in `dir` is given too.
+    pub fn new(dir: impl Into<PathBuf>, activity: Activity) -> Self {
+        Web {
+            dir: dir.into(),
+            activity,
+        }
+    }
+
+    /// Serves the page at `/` on `addr` from a background thread, and returns the address
+    /// it listens on.
+    pub fn serve(&self, addr: impl ToSocketAddrs) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)?;
+        let local = listener.local_addr()?;
+        info!(addr = %local, "Serving the web page.");
+        let web = self.clone();
+        let connections = Arc::new(AtomicUsize::new(0));
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        warn!(error = ?err, "Could not accept a web connection.");
+                        continue;
+                    }
+                };
+                if connections.fetch_add(1, Ordering::Relaxed) >= MAX_CONNECTIONS {
+                    connections.fetch_sub(1, Ordering::Relaxed);
+                    warn!("Turning a web connection away, too many are open.");
+                    let _ = stream.set_write_timeout(Some(REQUEST_TIMEOUT));
+                    let _ = respond(&mut stream, "503 Service Unavailable", "text/plain", "");
+                    continue;
+                }
+                let (web, connections) = (web.clone(), connections.clone());
+                // Downloads take a while, the page should not wait for them.
+                std::thread::spawn(move || {
+                    if let Err(err) = web.answer(stream) {
+                        warn!(error = ?err, "Could not answer a web request.");
+                    }
+                    connections.fetch_sub(1, Ordering::Relaxed);
+                });
+            }
+        });
+        Ok(local)
+    }
+
+    fn answer(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        let mut reader = BufReader::new(
+            Deadline {
+                stream: stream.try_clone()?,
+                at: Instant::now() + REQUEST_TIMEOUT,
+            }
+            .take(MAX_REQUEST),
+        );
+        let mut request = String::new();
+        reader.read_line(&mut request)?;
+        // The headers do not matter, but the client may wait for us to read them.
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 2 {
+            header.clear();
+        }
+        if reader.get_ref().limit() == 0 {
+            return respond(
+                &mut stream,
+                "431 Request Header Fields Too Large",
+                "text/plain",
+                "",
+            );
+        }
+
+        let mut words = request.split_whitespace();
+        let target = match (words.next(), words.next()) {
+            (Some("GET"), Some(target)) => target,
+            _ => return respond(&mut stream, "405 Method Not Allowed", "text/plain", ""),
+        };
+        // Query strings, like the ones of a refresh, are ignored.
+        let path = target.split('?').next().unwrap_or(target);
+        match path {
+            "/" => respond(
+                &mut stream,
+                "200 OK",
+                "text/html; charset=utf-8",
+                &self.page(),
+            ),
+            "/sessions" => {
+                let sessions: Vec<String> =
+                    self.activity.list().iter().map(|s| s.to_json()).collect();
+                let json = format!("[{}]", sessions.join(","));
+                respond(&mut stream, "200 OK", "application/json", &json)
+            }
+            _ => match path
+                .strip_prefix("/files/")
+                .and_then(|path| self.file(path))
+            {
+                Some(file) => download(&mut stream, &file),
+                None => respond(&mut stream, "404 Not Found", "text/plain", "Not found\n"),
+            },
+        }
+    }
+
+    /// The received file at the percent-encoded `path`, None unless it is one the page
+    /// lists.
+    fn file(&self, path: &str) -> Option<PathBuf> {
+        let path = percent_decode(path)?;
+        let mut file = self.dir.clone();
+        for name in path.split('/') {
+            if !listable(name) {
+                return None;
+            }
+            file.push(name);
+        }
+        file.is_file().then_some(file)
+    }
+
+    fn page(&self) -> String {
+        let mut page = String::from(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+             <meta http-equiv=\"refresh\" content=\"2\"><title>sanic</title>\
+             <style>body{font-family:sans-serif;margin:2em}table{border-collapse:collapse}\
+             td,th{padding:.2em .8em;text-align:left}tr:nth-child(even){background:#f2f2f2}\
+             </style></head><body>\n",
+        );
+        let running = self.activity.list();
+        let _ = writeln!(page, "<h2>Transfers ({})</h2>", running.len());
+        if running.is_empty() {
+            page.push_str("<p>No transfer running.</p>\n");
+        } else {
+            page.push_str(
+                "<table><tr><th>Id</th><th>Peer</th><th>File</th><th>Progress</th>\
+                 <th>Rate</th><th>Lost</th><th>ETA</th></tr>\n",
+            );
+            for session in &running {
+                let eta = match (session.paused, session.eta()) {
+                    (true, _) => "paused".to_string(),
+                    (false, Some(eta)) => format!("{}s", eta.as_secs()),
+                    (false, None) => "-".to_string(),
+                };
+                let _ = writeln!(
+                    page,
+                    "<tr><td>{}</td><td>{}</td><td>{}</td>\
+                     <td><progress max=\"{}\" value=\"{}\"></progress> {}%</td>\
+                     <td>{}/s</td><td>{}</td><td>{eta}</td></tr>",
+                    html_escape(&session.id),
+                    peer(session),
+                    html_escape(&session.filename),
+                    session.parts_total,
+                    session.parts_done,
+                    percent(session),
+                    human_size(session.rate()),
+                    session.parts_lost,
+                );
+            }
+            page.push_str("</table>\n");
+        }
+
+        let history = self.activity.history();
+        page.push_str("<h2>Recent</h2>\n");
+        if history.is_empty() {
+            page.push_str("<p>No transfer ended yet.</p>\n");
+        } else {
+            page.push_str(
+                "<table><tr><th>Id</th><th>Peer</th><th>File</th><th>Outcome</th>\
+                 <th>Duration</th></tr>\n",
+            );
+            for session in &history {
+                let outcome = match session.parts_done >= session.parts_total {
+                    true => "completed".to_string(),
+                    false => format!("stopped at {}%", percent(session)),
+                };
+                let _ = writeln!(
+                    page,
+                    "<tr><td>{}</td><td>{}</td><td>{}</td><td>{outcome}</td><td>{}s</td></tr>",
+                    html_escape(&session.id),
+                    peer(session),
+                    html_escape(&session.filename),
+                    session.elapsed.as_secs(),
+                );
+            }
+            page.push_str("</table>\n");
+        }
+
+        let files = self.files();
+        let _ = writeln!(page, "<h2>Files ({})</h2>", files.len());
+        if files.is_empty() {
+            page.push_str("<p>Nothing received yet.</p>\n");
+        } else {
+            page.push_str("<table><tr><th>File</th><th>Size</th><th>Received</th></tr>\n");
+            for file in &files {
+                let _ = writeln!(
+                    page,
+                    "<tr><td><a href=\"/files/{}\">{}</a></td><td>{}</td><td>{}</td></tr>",
+                    percent_encode(&file.path),
+                    html_escape(&file.path),
+                    human_size(file.size),
+                    utc(file.modified),
+                );
+            }
+            page.push_str("</table>\n");
+        }
+        page.push_str("</body></html>\n");
+        page
+    }
+
+    /// The files under the output directory, the latest first, leaving out hidden ones
+    /// and those still being received.
+    fn files(&self) -> Vec<Listed> {
+        let mut files = Vec::new();
+        let mut dirs = vec![(self.dir.clone(), String::new(), 0)];
+        while let Some((dir, prefix, depth)) = dirs.pop() {
+            let Ok(entries) = std::fs::read_dir(&dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+                    continue;
+                };
+                let Ok(metadata) = entry.metadata() else {
+                    continue;
+                };
+                if !listable(&name) {
+                    continue;
+                }
+                let path = format!("{prefix}{name}");
+                if metadata.is_dir() && depth < MAX_DEPTH {
+                    dirs.push((entry.path(), format!("{path}/"), depth + 1));
+                } else if metadata.is_file() {
+                    files.push(Listed {
+                        path,
+                        size: metadata.len(),
+                        modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                    });
+                }
+            }
+        }
+        files.sort_by_key(|file| std::cmp::Reverse(file.modified));
+        files.truncate(MAX_FILES);
+        files
+    }
+}
+
+/// Whether a file or directory with this name is shown and served: not hidden, like
+/// the directories of groups being received, nor partial.
+fn listable(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && !name.ends_with(".sanic-partial")
+        && !name.contains('\\')
+}
+
+/// A stream read until a deadline, however slowly the bytes come.
+struct Deadline {
+    stream: TcpStream,
+    at: Instant,
+}
+
+impl Read for Deadline {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.at.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buf)
+    }
+}
+
+fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+fn download(stream: &mut TcpStream, path: &Path) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("file")
+        .replace(['"', '\r', '\n'], "_");
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\n\
+         Content-Disposition: attachment; filename=\"{name}\"\r\nContent-Length: {len}\r\n\
+         Connection: close\r\n\r\n"
+    )?;
+    io::copy(&mut file, stream)?;
+    stream.flush()
+}
+
+fn peer(session: &Snapshot) -> String {
+    session
+        .peer
+        .map_or_else(|| "-".to_string(), |peer| peer.to_string())
+}
+
+fn percent(session: &Snapshot) -> u64 {
+    match session.parts_total {
+        0 => 100,
+        total => u64::from(session.parts_done) * 100 / u64::from(total),
+    }
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{bytes} B"),
+        unit => format!("{value:.1} {}", UNITS[unit]),
+    }
+}
+
+fn html_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// `path` for a URL, its slashes kept.
+fn percent_encode(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            byte => {
+                let _ = write!(encoded, "%{byte:02X}");
+            }
+        }
+    }
+    encoded
+}
+
+fn percent_decode(path: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut rest = path.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{activity::Direction, testing::TempDir};
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn page_lists_transfers_and_serves_files() {
+        let dir = TempDir::new("web");
+        dir.write("photos/a & b.jpg", b"jpeg");
+        dir.write("big.iso.sanic-partial", b"half");
+        dir.write("secret", b"no");
+        let activity = Activity::default();
+        let running = activity.start(Direction::Receive, "0a1b2c3d", None, "<big>.iso", 10);
+        running.progress(4);
+        let addr = Web::new(dir.path(), activity).serve("127.0.0.1:0").unwrap();
+
+        let page = get(addr, "/");
+        assert!(page.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(page.contains("&lt;big&gt;.iso"));
+        assert!(page.contains("<progress max=\"10\" value=\"4\"></progress> 40%"));
+        assert!(page.contains("<a href=\"/files/photos/a%20%26%20b.jpg\">photos/a &amp; b.jpg</a>"));
+        assert!(!page.contains("sanic-partial"));
+
+        drop(running);
+        assert!(get(addr, "/").contains("stopped at 40%"));
+        let download = get(addr, "/files/photos/a%20%26%20b.jpg");
+        assert!(download.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(download.ends_with("\r\n\r\njpeg"));
+        for path in [
+            "/files/../web.rs",
+            "/files/photos/..%2F..%2Fsecret",
+            "/files/big.iso.sanic-partial",
+        ] {
+            assert!(get(addr, path).starts_with("HTTP/1.1 404"), "{path}");
+        }
+    }
+
+    #[test]
+    fn requests_are_bounded() {
+        let dir = TempDir::new("web-bounds");
+        let addr = Web::new(dir.path(), Activity::default())
+            .serve("127.0.0.1:0")
+            .unwrap();
+        // Exactly as much as is read, so that nothing is left unread when the server closes.
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let request = "GET / HTTP/1.1\r\nX-Padding: ";
+        let padding = "a".repeat(MAX_REQUEST as usize - request.len() - 2);
+        write!(stream, "{request}{padding}\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 431"), "{response}");
+
+        let idle: Vec<_> = (0..MAX_CONNECTIONS)
+            .map(|_| TcpStream::connect(addr).unwrap())
+            .collect();
+        // Connections are taken in order, so the last one is the one over the limit.
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503"), "{response}");
+        drop(idle);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !get(addr, "/").starts_with("HTTP/1.1 200") {
+            assert!(Instant::now() < deadline, "connections were not let go of");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}